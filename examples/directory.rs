@@ -0,0 +1,146 @@
+//! Directory example
+//!
+//! Runs a directory node, in the style of a Tor hidden service directory, and two peers
+//! on the same mock mixnet. Alice registers her signed address record with the directory,
+//! and Bob, who only knows the directory's Nym address and Alice's peer ID, looks her up
+//! and dials her, all without leaving the mixnet.
+//!
+//! ```sh
+//! cargo run --example directory
+//! ```
+//!
+//! In a real deployment the directory runs on a well-known node, whose Nym address is
+//! shipped with the application in place of clearnet bootstrap addresses.
+
+use futures::StreamExt;
+use libp2p::core::{muxing::StreamMuxerBox, transport::Transport};
+use libp2p::swarm::{keep_alive, NetworkBehaviour, Swarm, SwarmBuilder, SwarmEvent};
+use libp2p::{identity, ping, PeerId};
+use std::error::Error;
+use std::time::Duration;
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+use rust_libp2p_nym::directory::{DirectoryClient, DirectoryServer};
+use rust_libp2p_nym::transport::NymTransport;
+
+/// the mock mixnet every node of the example uses
+const ENDPOINT: &str = "mock://directory-example";
+
+/// how long Alice's address record stays valid
+const RECORD_TTL: Duration = Duration::from_secs(3600);
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    keep_alive: keep_alive::Behaviour,
+    ping: ping::Behaviour,
+}
+
+/// new_swarm returns a swarm over the transport, once it has reported its Nym address.
+async fn new_swarm(keypair: identity::Keypair, transport: NymTransport) -> Swarm<Behaviour> {
+    let behaviour = Behaviour {
+        keep_alive: keep_alive::Behaviour::default(),
+        ping: ping::Behaviour::default(),
+    };
+    let mut swarm = SwarmBuilder::with_tokio_executor(
+        transport
+            .map(|a, _| (a.0, StreamMuxerBox::new(a.1)))
+            .boxed(),
+        behaviour,
+        PeerId::from(keypair.public()),
+    )
+    .build();
+    while !matches!(
+        swarm.select_next_some().await,
+        SwarmEvent::NewListenAddr { .. }
+    ) {}
+    swarm
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("directory=info")),
+        )
+        .init();
+
+    // the directory node answers the directory messages among its broadcasts
+    let directory_key = identity::Keypair::generate_ed25519();
+    let mut transport = NymTransport::new(ENDPOINT, directory_key.clone()).await?;
+    let directory = transport.signed_address_record(RECORD_TTL)?.recipient();
+    tokio::spawn(
+        DirectoryServer::new(transport.mixnet_connection()).run(transport.subscribe_broadcasts()),
+    );
+    let mut directory_swarm = new_swarm(directory_key, transport).await;
+    tokio::spawn(async move {
+        loop {
+            directory_swarm.select_next_some().await;
+        }
+    });
+    info!("directory at {directory}");
+
+    // Alice registers her address record
+    let alice_key = identity::Keypair::generate_ed25519();
+    let alice_peer_id = PeerId::from(alice_key.public());
+    let mut transport = NymTransport::new(ENDPOINT, alice_key.clone()).await?;
+    let record = transport.signed_address_record(RECORD_TTL)?;
+    let alice = DirectoryClient::new(transport.mixnet_connection(), record.recipient());
+    let mut alice_broadcasts = transport.subscribe_broadcasts();
+    let mut alice_swarm = new_swarm(alice_key, transport).await;
+    let handler = alice.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(payload) = alice_broadcasts.recv() => {
+                    handler.handle_payload(&payload);
+                }
+                event = alice_swarm.select_next_some() => {
+                    if let SwarmEvent::ConnectionEstablished { peer_id, .. } = event {
+                        info!("alice: connected to {peer_id}");
+                    }
+                }
+            }
+        }
+    });
+    alice.register(directory, record).await?;
+    info!("alice registered as {alice_peer_id}");
+
+    // Bob only knows the directory and Alice's peer ID
+    let bob_key = identity::Keypair::generate_ed25519();
+    let mut transport = NymTransport::new(ENDPOINT, bob_key.clone()).await?;
+    let bob = DirectoryClient::new(
+        transport.mixnet_connection(),
+        transport.signed_address_record(RECORD_TTL)?.recipient(),
+    );
+    let mut bob_broadcasts = transport.subscribe_broadcasts();
+    let handler = bob.clone();
+    tokio::spawn(async move {
+        while let Some(payload) = bob_broadcasts.recv().await {
+            handler.handle_payload(&payload);
+        }
+    });
+    let mut bob_swarm = new_swarm(bob_key, transport).await;
+    let lookup = bob.lookup(directory, alice_peer_id);
+    tokio::pin!(lookup);
+    let record = loop {
+        tokio::select! {
+            record = &mut lookup => break record?.ok_or("alice isn't in the directory")?,
+            _ = bob_swarm.select_next_some() => {}
+        }
+    };
+    let address = record.multiaddr()?;
+    info!("bob found alice at {address}");
+
+    bob_swarm.dial(address)?;
+    loop {
+        if let SwarmEvent::Behaviour(BehaviourEvent::Ping(ping::Event {
+            peer,
+            result: Ok(ping::Success::Ping { rtt }),
+        })) = bob_swarm.select_next_some().await
+        {
+            info!("bob pinged {peer} in {rtt:?}");
+            return Ok(());
+        }
+    }
+}
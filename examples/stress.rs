@@ -0,0 +1,362 @@
+//! Stress test example
+//!
+//! Spins up a number of in-process swarms, connected through the mock mixnet or through
+//! a nym-client container each, and runs a randomized workload of dials, pings and
+//! disconnects between them. Every dial and disconnect must complete and every connection
+//! must keep carrying pings within a timeout, and once everything is disconnected no
+//! connection or buffered message may be left behind, on the swarm's side or the
+//! transport's. It doubles as a soak test of the transport's state machines.
+//!
+//! ```sh
+//! cargo run --example stress -- --nodes 8 --rounds 500 --seed 42
+//! ```
+//!
+//! Add `--containers` to run a nym-client container per node instead of the mock mixnet;
+//! expect each step to take seconds rather than milliseconds.
+
+use futures::StreamExt;
+use libp2p::core::{muxing::StreamMuxerBox, transport::Transport};
+use libp2p::swarm::{keep_alive, NetworkBehaviour, SwarmBuilder, SwarmEvent};
+use libp2p::{identity, ping, Multiaddr, PeerId};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::collections::HashSet;
+use std::error::Error;
+use std::time::{Duration, Instant};
+use testcontainers::clients;
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+use rust_libp2p_nym::backend::MockMixnet;
+use rust_libp2p_nym::events::ConnectionEvent;
+use rust_libp2p_nym::test_utils::create_nym_client;
+use rust_libp2p_nym::transport::NymTransport;
+
+/// how long a step may take before the transport is considered stuck
+const STEP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// how often connected nodes ping each other
+const PING_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    keep_alive: keep_alive::Behaviour,
+    ping: ping::Behaviour,
+}
+
+struct Args {
+    nodes: usize,
+    rounds: usize,
+    seed: u64,
+    containers: bool,
+}
+
+fn parse_args() -> Result<Args, Box<dyn Error>> {
+    let mut args = Args {
+        nodes: 5,
+        rounds: 100,
+        seed: rand::random(),
+        containers: false,
+    };
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        let mut value = || argv.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--nodes" => args.nodes = value()?.parse()?,
+            "--rounds" => args.rounds = value()?.parse()?,
+            "--seed" => args.seed = value()?.parse()?,
+            "--containers" => args.containers = true,
+            _ => return Err(format!("unknown argument {arg}").into()),
+        }
+    }
+    if args.nodes < 2 {
+        return Err("at least 2 nodes are needed".into());
+    }
+    Ok(args)
+}
+
+/// Command is what the workload asks of a node.
+enum Command {
+    Dial(Multiaddr),
+    Disconnect(PeerId),
+    Report(oneshot::Sender<Report>),
+}
+
+/// Report is what a node has left open.
+#[derive(Debug, PartialEq)]
+struct Report {
+    /// connections the swarm knows about, established or pending
+    swarm_connections: u32,
+    /// connections the transport opened and didn't close yet
+    transport_connections: i64,
+    /// bytes held by the transport's buffers
+    memory_used: usize,
+}
+
+impl Report {
+    fn is_clean(&self) -> bool {
+        self.swarm_connections == 0 && self.transport_connections == 0 && self.memory_used == 0
+    }
+}
+
+/// NodeEvent is what a node reports to the workload.
+#[derive(Debug, PartialEq)]
+enum NodeEvent {
+    Connected(PeerId),
+    Disconnected(PeerId),
+    Pinged(PeerId),
+    DialFailed(String),
+}
+
+struct Node {
+    peer_id: PeerId,
+    address: Multiaddr,
+    commands_tx: UnboundedSender<Command>,
+}
+
+/// spawn_node runs a swarm over the transport in its own task, which reports its events
+/// tagged with the node's index.
+async fn spawn_node(
+    index: usize,
+    keypair: identity::Keypair,
+    transport: NymTransport,
+    events_tx: UnboundedSender<(usize, NodeEvent)>,
+) -> Node {
+    let peer_id = PeerId::from(keypair.public());
+    let memory_budget = transport.memory_budget();
+    let mut connection_events = transport.mixnet_connection().connection_events();
+    let behaviour = Behaviour {
+        keep_alive: keep_alive::Behaviour::default(),
+        ping: ping::Behaviour::new(ping::Config::new().with_interval(PING_INTERVAL)),
+    };
+    let mut swarm = SwarmBuilder::with_tokio_executor(
+        transport
+            .map(|a, _| (a.0, StreamMuxerBox::new(a.1)))
+            .boxed(),
+        behaviour,
+        peer_id,
+    )
+    .build();
+
+    // the transport reports its Nym address as soon as the swarm polls it
+    let address = loop {
+        if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+            break address;
+        }
+    };
+
+    let (commands_tx, mut commands_rx) = unbounded_channel();
+    tokio::spawn(async move {
+        let mut transport_connections = 0i64;
+        loop {
+            tokio::select! {
+                command = commands_rx.recv() => match command {
+                    Some(Command::Dial(address)) => {
+                        if let Err(e) = swarm.dial(address) {
+                            events_tx.send((index, NodeEvent::DialFailed(e.to_string()))).ok();
+                        }
+                    }
+                    Some(Command::Disconnect(peer_id)) => {
+                        swarm.disconnect_peer_id(peer_id).ok();
+                    }
+                    Some(Command::Report(report_tx)) => {
+                        let info = swarm.network_info();
+                        report_tx
+                            .send(Report {
+                                swarm_connections: info.connection_counters().num_connections(),
+                                transport_connections,
+                                memory_used: memory_budget.used(),
+                            })
+                            .ok();
+                    }
+                    None => return,
+                },
+                Some(event) = connection_events.next() => match event {
+                    ConnectionEvent::Opened { .. } => transport_connections += 1,
+                    ConnectionEvent::Closed { .. } => transport_connections -= 1,
+                    _ => {}
+                },
+                event = swarm.select_next_some() => {
+                    let event = match event {
+                        SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                            NodeEvent::Connected(peer_id)
+                        }
+                        SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                            NodeEvent::Disconnected(peer_id)
+                        }
+                        SwarmEvent::OutgoingConnectionError { error, .. } => {
+                            NodeEvent::DialFailed(error.to_string())
+                        }
+                        SwarmEvent::Behaviour(BehaviourEvent::Ping(ping::Event {
+                            peer,
+                            result: Ok(ping::Success::Ping { .. }),
+                        })) => NodeEvent::Pinged(peer),
+                        _ => continue,
+                    };
+                    events_tx.send((index, event)).ok();
+                }
+            }
+        }
+    });
+
+    Node {
+        peer_id,
+        address,
+        commands_tx,
+    }
+}
+
+/// drain discards the events so far, which don't count towards the next step.
+fn drain(events_rx: &mut UnboundedReceiver<(usize, NodeEvent)>) {
+    while events_rx.try_recv().is_ok() {}
+}
+
+/// expect waits for all of the events, in any order, ignoring unrelated ones. It fails if
+/// a dial fails, or if they don't all arrive within the step timeout, which means a
+/// connection is stuck.
+async fn expect(
+    events_rx: &mut UnboundedReceiver<(usize, NodeEvent)>,
+    mut expected: Vec<(usize, NodeEvent)>,
+) -> Result<(), Box<dyn Error>> {
+    let deadline = tokio::time::Instant::now() + STEP_TIMEOUT;
+    while !expected.is_empty() {
+        let event = tokio::time::timeout_at(deadline, events_rx.recv())
+            .await
+            .map_err(|_| format!("stuck waiting for {expected:?}"))?
+            .ok_or("a node stopped")?;
+        if let (index, NodeEvent::DialFailed(error)) = &event {
+            return Err(format!("node {index} failed to dial: {error}").into());
+        }
+        expected.retain(|e| *e != event);
+    }
+    Ok(())
+}
+
+/// report asks every node what it has left open.
+async fn report(nodes: &[Node]) -> Result<Vec<Report>, Box<dyn Error>> {
+    let mut reports = vec![];
+    for node in nodes {
+        let (report_tx, report_rx) = oneshot::channel();
+        node.commands_tx.send(Command::Report(report_tx))?;
+        reports.push(report_rx.await?);
+    }
+    Ok(reports)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("stress=info")),
+        )
+        .init();
+    let args = parse_args()?;
+    info!(
+        "running {} rounds on {} nodes with seed {}",
+        args.rounds, args.nodes, args.seed
+    );
+    let mut rng = StdRng::seed_from_u64(args.seed);
+
+    let docker_client = clients::Cli::default();
+    let mut containers = vec![];
+    let mixnet = MockMixnet::new();
+    let (events_tx, mut events_rx) = unbounded_channel();
+    let mut nodes = vec![];
+    for index in 0..args.nodes {
+        let keypair = identity::Keypair::generate_ed25519();
+        let transport = if args.containers {
+            let nym_id = format!("stress-{}-{}", args.seed, index);
+            let (container, uri) = create_nym_client(&docker_client, &nym_id);
+            containers.push(container);
+            NymTransport::new(&uri, keypair.clone()).await?
+        } else {
+            NymTransport::new_with_backend(mixnet.new_backend(), keypair.clone())?
+        };
+        nodes.push(spawn_node(index, keypair, transport, events_tx.clone()).await);
+    }
+
+    let started_at = Instant::now();
+    // pairs of connected nodes, the dialer first
+    let mut connected: HashSet<(usize, usize)> = HashSet::new();
+    let (mut dials, mut pings, mut disconnects) = (0, 0, 0);
+    for round in 0..args.rounds {
+        let a = rng.gen_range(0..nodes.len());
+        let b = (a + rng.gen_range(1..nodes.len())) % nodes.len();
+        let pair = *connected
+            .iter()
+            .find(|&&pair| pair == (a, b) || pair == (b, a))
+            .unwrap_or(&(a, b));
+        let (dialer, listener) = pair;
+        drain(&mut events_rx);
+        let events = if !connected.contains(&pair) {
+            dials += 1;
+            connected.insert(pair);
+            nodes[dialer]
+                .commands_tx
+                .send(Command::Dial(nodes[listener].address.clone()))?;
+            vec![
+                (dialer, NodeEvent::Connected(nodes[listener].peer_id)),
+                (listener, NodeEvent::Connected(nodes[dialer].peer_id)),
+            ]
+        } else if rng.gen_bool(0.5) {
+            pings += 1;
+            vec![
+                (a, NodeEvent::Pinged(nodes[b].peer_id)),
+                (b, NodeEvent::Pinged(nodes[a].peer_id)),
+            ]
+        } else {
+            disconnects += 1;
+            connected.remove(&pair);
+            // either end may close it
+            nodes[a]
+                .commands_tx
+                .send(Command::Disconnect(nodes[b].peer_id))?;
+            vec![
+                (a, NodeEvent::Disconnected(nodes[b].peer_id)),
+                (b, NodeEvent::Disconnected(nodes[a].peer_id)),
+            ]
+        };
+        expect(&mut events_rx, events)
+            .await
+            .map_err(|e| format!("round {round}: {e}"))?;
+    }
+
+    // close everything that's left, after which nothing may be left open
+    for (dialer, listener) in connected.drain() {
+        drain(&mut events_rx);
+        nodes[dialer]
+            .commands_tx
+            .send(Command::Disconnect(nodes[listener].peer_id))?;
+        expect(
+            &mut events_rx,
+            vec![
+                (dialer, NodeEvent::Disconnected(nodes[listener].peer_id)),
+                (listener, NodeEvent::Disconnected(nodes[dialer].peer_id)),
+            ],
+        )
+        .await?;
+    }
+    let deadline = Instant::now() + STEP_TIMEOUT;
+    loop {
+        let reports = report(&nodes).await?;
+        if reports.iter().all(Report::is_clean) {
+            break;
+        }
+        if Instant::now() > deadline {
+            return Err(format!("nodes left state behind: {reports:?}").into());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    info!(
+        "{} dials, {} ping checks and {} disconnects in {:?}, nothing left behind",
+        dials,
+        pings,
+        disconnects,
+        started_at.elapsed()
+    );
+    Ok(())
+}
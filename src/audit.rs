@@ -0,0 +1,195 @@
+use libp2p::core::PeerId;
+use nym_sphinx::addressing::clients::Recipient;
+use parking_lot::Mutex;
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+use crate::error::Error;
+
+/// ConnectionDirection is whether a connection attempt was made by us or the remote peer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionDirection {
+    Inbound,
+    Outbound,
+}
+
+/// ConnectionOutcome is how a connection attempt ended.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionOutcome {
+    Established,
+    /// the attempt failed with the given error
+    Failed(String),
+    /// the connection request was dropped by the inbound filter
+    Filtered,
+}
+
+/// AuditRecord describes a single connection attempt.
+#[derive(Clone, Debug)]
+pub struct AuditRecord {
+    pub direction: ConnectionDirection,
+    /// the remote peer, if known; for inbound attempts, this is whoever the request claims
+    pub peer_id: Option<PeerId>,
+    /// the remote Nym address, if known
+    pub address: Option<Recipient>,
+    pub outcome: ConnectionOutcome,
+    pub started_at: SystemTime,
+    pub finished_at: SystemTime,
+}
+
+impl fmt::Display for AuditRecord {
+    /// formats the record as a single line of space-separated fields.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            ConnectionDirection::Inbound => "inbound",
+            ConnectionDirection::Outbound => "outbound",
+        };
+        let peer_id = self
+            .peer_id
+            .map(|peer_id| peer_id.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let address = self
+            .address
+            .map(|address| address.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let outcome = match &self.outcome {
+            ConnectionOutcome::Established => "established".to_string(),
+            ConnectionOutcome::Failed(e) => format!("failed: {}", e),
+            ConnectionOutcome::Filtered => "filtered".to_string(),
+        };
+        write!(
+            f,
+            "{} {} {} peer={} address={} outcome={}",
+            unix_timestamp(self.started_at),
+            unix_timestamp(self.finished_at),
+            direction,
+            peer_id,
+            address,
+            outcome,
+        )
+    }
+}
+
+/// unix_timestamp formats a time as seconds since the unix epoch, with microseconds.
+fn unix_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!(
+        "{}.{:06}",
+        since_epoch.as_secs(),
+        since_epoch.subsec_micros()
+    )
+}
+
+/// AuditSink receives a record of every inbound and outbound connection attempt,
+/// eg. for operators of public services who need forensic records.
+/// Records are only ever appended.
+/// Any `FnMut(&AuditRecord) -> Result<(), Error>` closure is a sink.
+pub trait AuditSink: Send + 'static {
+    fn record(&mut self, record: &AuditRecord) -> Result<(), Error>;
+}
+
+impl<F> AuditSink for F
+where
+    F: FnMut(&AuditRecord) -> Result<(), Error> + Send + 'static,
+{
+    fn record(&mut self, record: &AuditRecord) -> Result<(), Error> {
+        self(record)
+    }
+}
+
+/// FileAuditSink appends records to a file, one line per record.
+pub struct FileAuditSink {
+    file: File,
+}
+
+impl FileAuditSink {
+    /// open opens the file at the given path for appending, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(Error::AuditLogError)?;
+        Ok(FileAuditSink { file })
+    }
+}
+
+impl AuditSink for FileAuditSink {
+    fn record(&mut self, record: &AuditRecord) -> Result<(), Error> {
+        // a single write per record, so concurrent writers don't interleave lines
+        self.file
+            .write_all(format!("{}\n", record).as_bytes())
+            .map_err(Error::AuditLogError)
+    }
+}
+
+/// AuditLog is a shared handle to the transport's audit sink, so that dials can record
+/// their outcome after the transport has returned.
+#[derive(Clone)]
+pub(crate) struct AuditLog(Arc<Mutex<Box<dyn AuditSink>>>);
+
+impl AuditLog {
+    pub(crate) fn new<S: AuditSink>(sink: S) -> Self {
+        AuditLog(Arc::new(Mutex::new(Box::new(sink))))
+    }
+
+    pub(crate) fn record(
+        &self,
+        direction: ConnectionDirection,
+        peer_id: Option<PeerId>,
+        address: Option<Recipient>,
+        outcome: ConnectionOutcome,
+        started_at: SystemTime,
+    ) {
+        let record = AuditRecord {
+            direction,
+            peer_id,
+            address,
+            outcome,
+            started_at,
+            finished_at: SystemTime::now(),
+        };
+        if let Err(e) = self.0.lock().record(&record) {
+            warn!("failed to write audit record {}: {:?}", record, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::mock::random_recipient;
+
+    #[test]
+    fn test_file_audit_sink_appends() {
+        let path = std::env::temp_dir().join(format!("audit-{}.log", PeerId::random()));
+        let peer_id = PeerId::random();
+        let address = random_recipient();
+
+        for _ in 0..2 {
+            let log = AuditLog::new(FileAuditSink::open(&path).unwrap());
+            log.record(
+                ConnectionDirection::Inbound,
+                Some(peer_id),
+                Some(address),
+                ConnectionOutcome::Established,
+                SystemTime::now(),
+            );
+        }
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(&format!(
+            "inbound peer={} address={} outcome=established",
+            peer_id, address
+        )));
+    }
+}
@@ -0,0 +1,346 @@
+use async_trait::async_trait;
+use futures::future::FutureExt;
+use nym_sphinx::addressing::clients::Recipient;
+use std::future::Future;
+use tokio::{
+    sync::mpsc::UnboundedSender,
+    time::{sleep_until, Duration, Instant},
+};
+use tracing::{info, warn};
+
+use super::{BackendFactory, MixnetBackend, MixnetInfo, PacketSize, SendOutcome, SenderTag};
+use crate::error::Error;
+
+/// DEFAULT_MAX_SEND_FAILURES is the number of consecutive failed sends after which
+/// the current gateway is considered to be down.
+pub const DEFAULT_MAX_SEND_FAILURES: usize = 3;
+
+/// FailoverBackend wraps backends connected to different gateways and automatically
+/// fails over to the next gateway when the current one has an outage, instead of
+/// requiring a manual restart.
+/// An outage is detected when the backend disconnects, when sends keep failing, or
+/// (optionally) when no inbound traffic has been received for a while.
+/// Since a Nym address includes the gateway, failing over changes our address; the
+/// transport reports this as a new listen address, and the old one as expired.
+pub struct FailoverBackend<B: MixnetBackend> {
+    /// creates a backend connected to each of the gateways, in order of preference
+    gateways: Vec<BackendFactory<B>>,
+
+    /// index into gateways of the one we're currently connected to
+    current: usize,
+    backend: B,
+
+    /// how long we can go without inbound traffic before failing over, if set
+    inbound_timeout: Option<Duration>,
+    last_inbound: Instant,
+
+    max_send_failures: usize,
+    send_failures: usize,
+
+    /// where send outcomes are reported, if the transport asked for them; passed on to
+    /// the backend of every gateway we fail over to
+    outcomes_tx: Option<UnboundedSender<(u64, SendOutcome)>>,
+}
+
+impl<B: MixnetBackend> FailoverBackend<B> {
+    /// connect connects to the first of the given gateways that's reachable.
+    /// Each gateway is given as a function creating a backend connected to it, eg.
+    /// `SdkBackend::connect_with_gateway` with the gateway's identity key.
+    pub async fn connect(gateways: Vec<BackendFactory<B>>) -> Result<Self, Error> {
+        if gateways.is_empty() {
+            return Err(Error::NoGateways);
+        }
+
+        let mut last_err = Error::NoGateways;
+        for current in 0..gateways.len() {
+            match (gateways[current])().await {
+                Ok(backend) => {
+                    return Ok(FailoverBackend {
+                        gateways,
+                        current,
+                        backend,
+                        inbound_timeout: None,
+                        last_inbound: Instant::now(),
+                        max_send_failures: DEFAULT_MAX_SEND_FAILURES,
+                        send_failures: 0,
+                        outcomes_tx: None,
+                    });
+                }
+                Err(e) => {
+                    warn!("failed to connect to gateway {}: {:?}", current, e);
+                    last_err = e;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    /// gateway boxes a function creating a backend connected to a gateway, for use with
+    /// FailoverBackend::connect.
+    pub fn gateway<F, Fut>(connect: F) -> BackendFactory<B>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<B, Error>> + Send + 'static,
+    {
+        Box::new(move || connect().boxed())
+    }
+
+    /// with_inbound_timeout fails over when nothing was received for the given duration.
+    /// Only use this if some inbound traffic is expected regularly, eg. keepalives.
+    pub fn with_inbound_timeout(mut self, timeout: Duration) -> Self {
+        self.inbound_timeout = Some(timeout);
+        self
+    }
+
+    /// with_max_send_failures sets how many consecutive failed sends are tolerated
+    /// before failing over.
+    pub fn with_max_send_failures(mut self, max_send_failures: usize) -> Self {
+        self.max_send_failures = max_send_failures.max(1);
+        self
+    }
+
+    /// current_gateway returns the index of the gateway we're connected to.
+    pub fn current_gateway(&self) -> usize {
+        self.current
+    }
+}
+
+#[async_trait]
+impl<B: MixnetBackend> MixnetBackend for FailoverBackend<B> {
+    fn self_address(&self) -> Recipient {
+        self.backend.self_address()
+    }
+
+    fn info(&self) -> MixnetInfo {
+        self.backend.info()
+    }
+
+    fn packet_payload_len(&self, packet_size: PacketSize) -> Option<usize> {
+        self.backend.packet_payload_len(packet_size)
+    }
+
+    async fn send(&mut self, recipient: Recipient, message: Vec<u8>) -> Result<(), Error> {
+        let res = self.backend.send(recipient, message).await;
+        match res {
+            Ok(()) => self.send_failures = 0,
+            Err(_) => self.send_failures += 1,
+        }
+        res
+    }
+
+    async fn send_with_packet_size(
+        &mut self,
+        recipient: Recipient,
+        message: Vec<u8>,
+        packet_size: PacketSize,
+    ) -> Result<(), Error> {
+        let res = self
+            .backend
+            .send_with_packet_size(recipient, message, packet_size)
+            .await;
+        match res {
+            Ok(()) => self.send_failures = 0,
+            // not an outage
+            Err(Error::UnsupportedPacketSize(_)) => {}
+            Err(_) => self.send_failures += 1,
+        }
+        res
+    }
+
+    async fn send_tracked(
+        &mut self,
+        recipient: Recipient,
+        message: Vec<u8>,
+        packet_size: PacketSize,
+        id: u64,
+    ) -> Result<(), Error> {
+        let res = self
+            .backend
+            .send_tracked(recipient, message, packet_size, id)
+            .await;
+        match res {
+            Ok(()) => self.send_failures = 0,
+            Err(Error::UnsupportedPacketSize(_)) => {}
+            Err(_) => self.send_failures += 1,
+        }
+        res
+    }
+
+    fn reports_send_outcomes(&self) -> bool {
+        self.backend.reports_send_outcomes()
+    }
+
+    fn report_send_outcomes(&mut self, outcomes_tx: UnboundedSender<(u64, SendOutcome)>) {
+        self.outcomes_tx = Some(outcomes_tx.clone());
+        self.backend.report_send_outcomes(outcomes_tx)
+    }
+
+    async fn send_anonymous(
+        &mut self,
+        recipient: Recipient,
+        message: Vec<u8>,
+        reply_surbs: u32,
+    ) -> Result<(), Error> {
+        let res = self
+            .backend
+            .send_anonymous(recipient, message, reply_surbs)
+            .await;
+        match res {
+            Ok(()) => self.send_failures = 0,
+            Err(Error::Unimplemented) => {}
+            Err(_) => self.send_failures += 1,
+        }
+        res
+    }
+
+    /// the reply SURBs are kept by the Nym client, so replies to senders that reached us
+    /// through a gateway we failed over from can't be sent.
+    async fn send_reply(&mut self, sender_tag: SenderTag, message: Vec<u8>) -> Result<(), Error> {
+        let res = self.backend.send_reply(sender_tag, message).await;
+        match res {
+            Ok(()) => self.send_failures = 0,
+            Err(Error::Unimplemented) => {}
+            Err(_) => self.send_failures += 1,
+        }
+        res
+    }
+
+    async fn recv(&mut self) -> Result<Vec<u8>, Error> {
+        self.recv_with_sender_tag()
+            .await
+            .map(|(message, _)| message)
+    }
+
+    async fn recv_with_sender_tag(&mut self) -> Result<(Vec<u8>, Option<SenderTag>), Error> {
+        if self.send_failures >= self.max_send_failures {
+            warn!("gateway {} keeps failing to send", self.current);
+            return Err(Error::MixnetDisconnected);
+        }
+
+        let timeout = self.inbound_timeout.map(|t| self.last_inbound + t);
+        let res = tokio::select! {
+            res = self.backend.recv_with_sender_tag() => res,
+            _ = async {
+                match timeout {
+                    Some(deadline) => sleep_until(deadline).await,
+                    None => futures::future::pending().await,
+                }
+            } => {
+                warn!("no inbound traffic from gateway {}", self.current);
+                return Err(Error::MixnetDisconnected);
+            }
+        };
+
+        if res.is_ok() {
+            self.last_inbound = Instant::now();
+        }
+        res
+    }
+
+    /// reconnect connects to the next gateway that's reachable, trying the one that
+    /// just failed last.
+    async fn reconnect(&mut self) -> Result<(), Error> {
+        let mut last_err = Error::NoGateways;
+        for offset in 1..=self.gateways.len() {
+            let index = (self.current + offset) % self.gateways.len();
+            match (self.gateways[index])().await {
+                Ok(mut backend) => {
+                    info!("failed over from gateway {} to {}", self.current, index);
+                    if let Some(outcomes_tx) = &self.outcomes_tx {
+                        // all gateways are reached the same way, so they all report
+                        // outcomes or none do
+                        backend.report_send_outcomes(outcomes_tx.clone());
+                    }
+                    self.backend = backend;
+                    self.current = index;
+                    self.last_inbound = Instant::now();
+                    self.send_failures = 0;
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("failed to connect to gateway {}: {:?}", index, e);
+                    last_err = e;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    async fn suspend(&mut self) -> Result<(), Error> {
+        self.backend.suspend().await
+    }
+
+    /// resume resumes the current gateway's backend; the time spent suspended doesn't
+    /// count towards the inbound timeout.
+    async fn resume(&mut self) -> Result<(), Error> {
+        self.backend.resume().await?;
+        self.last_inbound = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::MockMixnet;
+
+    fn mock_gateway(mixnet: &MockMixnet) -> BackendFactory<crate::backend::MockBackend> {
+        let mixnet = mixnet.clone();
+        FailoverBackend::gateway(move || {
+            let mixnet = mixnet.clone();
+            async move { Ok(mixnet.new_backend()) }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_failover_on_disconnect() {
+        let mixnet = MockMixnet::new();
+        let mut backend =
+            FailoverBackend::connect(vec![mock_gateway(&mixnet), mock_gateway(&mixnet)])
+                .await
+                .unwrap();
+        assert_eq!(backend.current_gateway(), 0);
+        let old_address = backend.self_address();
+
+        mixnet.disconnect(&old_address);
+        assert!(matches!(
+            backend.recv().await,
+            Err(Error::MixnetDisconnected)
+        ));
+        backend.reconnect().await.unwrap();
+        assert_eq!(backend.current_gateway(), 1);
+        assert_ne!(backend.self_address(), old_address);
+
+        // the new address is reachable
+        let mut other = mixnet.new_backend();
+        other
+            .send(backend.self_address(), b"hello".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(backend.recv().await.unwrap(), b"hello".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_failover_skips_unreachable_gateway() {
+        let mixnet = MockMixnet::new();
+        let unreachable = FailoverBackend::gateway(|| async { Err(Error::MixnetDisconnected) });
+        let backend = FailoverBackend::connect(vec![unreachable, mock_gateway(&mixnet)])
+            .await
+            .unwrap();
+        assert_eq!(backend.current_gateway(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failover_on_inbound_timeout() {
+        let mixnet = MockMixnet::new();
+        let mut backend = FailoverBackend::connect(vec![mock_gateway(&mixnet)])
+            .await
+            .unwrap()
+            .with_inbound_timeout(Duration::from_millis(50));
+
+        assert!(matches!(
+            backend.recv().await,
+            Err(Error::MixnetDisconnected)
+        ));
+    }
+}
@@ -0,0 +1,431 @@
+use async_trait::async_trait;
+use libp2p::core::identity::ed25519;
+use nym_sphinx::addressing::clients::Recipient;
+use parking_lot::Mutex;
+use rand_core::{OsRng, RngCore};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::Instant;
+
+use super::{MixnetBackend, PacketSize, SendOutcome, SenderTag};
+use crate::error::Error;
+
+const RECIPIENT_LENGTH: usize = Recipient::LEN;
+
+/// NAMED_MIXNETS are the mock mixnets of `mock://` endpoints, by name.
+static NAMED_MIXNETS: OnceLock<Mutex<HashMap<String, MockMixnet>>> = OnceLock::new();
+
+/// MockMixnet is an in-memory stand-in for the Nym mixnet, for running the transport
+/// without a Nym client. All backends created from the same MockMixnet can reach each other.
+#[derive(Clone, Default)]
+pub struct MockMixnet {
+    /// recipient bytes -> channel of messages sent to that recipient, along with the
+    /// sender tag of anonymous ones
+    recipients: Arc<Mutex<HashMap<[u8; RECIPIENT_LENGTH], UnboundedSender<MockMessage>>>>,
+    /// sender tag -> address of the backend that sent anonymous messages with it. Unlike
+    /// on the real mixnet, replies don't use up the reply SURBs the sender included.
+    sender_tags: Arc<Mutex<HashMap<SenderTag, Recipient>>>,
+    /// (sender, recipient) -> conditions of the link between them
+    links: Arc<Mutex<HashMap<LinkKey, Link>>>,
+
+    /// whether backends report send outcomes, like a Nym client that tracks them
+    send_outcomes: bool,
+    /// number of upcoming messages that are lost before reaching the gateway
+    gateway_losses: Arc<AtomicUsize>,
+    /// the sphinx packet payload backends report, if any
+    packet_payload_len: Option<usize>,
+}
+
+type MockMessage = (Vec<u8>, Option<SenderTag>);
+
+type LinkKey = ([u8; RECIPIENT_LENGTH], [u8; RECIPIENT_LENGTH]);
+
+/// LinkConditions are how messages from one backend of a [`MockMixnet`] to another are
+/// delivered.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkConditions {
+    /// how long messages take to arrive; they're still delivered in the order they were sent
+    pub latency: Duration,
+    /// the probability of a message being lost, from 0 to 1
+    pub loss: f64,
+}
+
+/// Link is a link with non-default conditions.
+struct Link {
+    conditions: LinkConditions,
+    /// queue of the messages held back for the link's latency, if it has one
+    delayed_tx: Option<UnboundedSender<(Instant, MockMessage)>>,
+}
+
+impl MockMixnet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// named returns the process-wide mock mixnet with the given name, creating it if
+    /// needed. Transports created from the `mock://<name>` endpoint use it, so they can
+    /// reach each other.
+    pub fn named(name: &str) -> Self {
+        NAMED_MIXNETS
+            .get_or_init(Default::default)
+            .lock()
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// with_send_outcomes makes the backends created from now on report what became of
+    /// the messages they send, and return self.
+    pub fn with_send_outcomes(mut self) -> Self {
+        self.send_outcomes = true;
+        self
+    }
+
+    /// with_packet_payload_len makes the backends created from now on report that `len`
+    /// bytes of plaintext fit in a sphinx packet, whatever its size, and return self.
+    pub fn with_packet_payload_len(mut self, len: usize) -> Self {
+        self.packet_payload_len = Some(len);
+        self
+    }
+
+    /// lose_before_gateway drops the next `count` messages sent on this mixnet, as if
+    /// they never reached the sender's gateway.
+    pub fn lose_before_gateway(&self, count: usize) {
+        self.gateway_losses.fetch_add(count, Ordering::SeqCst);
+    }
+
+    /// set_link makes messages sent from the backend with address `from` to the one with
+    /// address `to` arrive under the given conditions, instead of right away. Links only
+    /// apply in one direction. It must be called within a tokio runtime.
+    pub fn set_link(&self, from: &Recipient, to: &Recipient, conditions: LinkConditions) {
+        let to = to.to_bytes();
+        let delayed_tx = (!conditions.latency.is_zero()).then(|| {
+            let (delayed_tx, mut delayed_rx) = unbounded_channel::<(Instant, MockMessage)>();
+            let recipients = self.recipients.clone();
+            tokio::spawn(async move {
+                while let Some((deliver_at, message)) = delayed_rx.recv().await {
+                    tokio::time::sleep_until(deliver_at).await;
+                    if let Some(inbound_tx) = recipients.lock().get(&to) {
+                        inbound_tx.send(message).ok();
+                    }
+                }
+            });
+            delayed_tx
+        });
+        self.links.lock().insert(
+            (from.to_bytes(), to),
+            Link {
+                conditions,
+                delayed_tx,
+            },
+        );
+    }
+
+    /// disconnect drops the connection of the backend with the given address,
+    /// simulating a Nym client going away. The backend can reconnect afterwards.
+    pub fn disconnect(&self, address: &Recipient) {
+        self.recipients.lock().remove(&address.to_bytes());
+    }
+
+    /// new_backend returns a backend with a new, random Nym address on this mixnet.
+    pub fn new_backend(&self) -> MockBackend {
+        let self_address = random_recipient();
+        let (inbound_tx, inbound_rx) = unbounded_channel();
+        self.recipients
+            .lock()
+            .insert(self_address.to_bytes(), inbound_tx);
+
+        let mut sender_tag = [0u8; 16];
+        OsRng.fill_bytes(&mut sender_tag);
+        MockBackend {
+            self_address,
+            sender_tag: SenderTag(sender_tag),
+            mixnet: self.clone(),
+            inbound_rx,
+            outcomes_tx: None,
+        }
+    }
+}
+
+/// MockBackend is a client of a [`MockMixnet`].
+pub struct MockBackend {
+    self_address: Recipient,
+    /// the tag our anonymous messages arrive with
+    sender_tag: SenderTag,
+    mixnet: MockMixnet,
+    inbound_rx: UnboundedReceiver<MockMessage>,
+    outcomes_tx: Option<UnboundedSender<(u64, SendOutcome)>>,
+}
+
+impl MockBackend {
+    /// take_gateway_loss returns true if the next message is lost before the gateway.
+    fn take_gateway_loss(&self) -> bool {
+        self.mixnet
+            .gateway_losses
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |losses| {
+                losses.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// deliver passes a message to the backend with the given address, if there's one;
+    /// like on the real mixnet, messages to unknown recipients are silently lost.
+    fn deliver(&self, recipient: &Recipient, message: Vec<u8>, sender_tag: Option<SenderTag>) {
+        let key = (self.self_address.to_bytes(), recipient.to_bytes());
+        if let Some(link) = self.mixnet.links.lock().get(&key) {
+            if link.conditions.loss > 0.0 && rand::random::<f64>() < link.conditions.loss {
+                return;
+            }
+            if let Some(delayed_tx) = &link.delayed_tx {
+                let deliver_at = Instant::now() + link.conditions.latency;
+                delayed_tx.send((deliver_at, (message, sender_tag))).ok();
+                return;
+            }
+        }
+        if let Some(inbound_tx) = self.mixnet.recipients.lock().get(&recipient.to_bytes()) {
+            inbound_tx.send((message, sender_tag)).ok();
+        }
+    }
+}
+
+#[async_trait]
+impl MixnetBackend for MockBackend {
+    fn self_address(&self) -> Recipient {
+        self.self_address
+    }
+
+    async fn send(&mut self, recipient: Recipient, message: Vec<u8>) -> Result<(), Error> {
+        if !self.take_gateway_loss() {
+            self.deliver(&recipient, message, None);
+        }
+        Ok(())
+    }
+
+    async fn send_anonymous(
+        &mut self,
+        recipient: Recipient,
+        message: Vec<u8>,
+        _reply_surbs: u32,
+    ) -> Result<(), Error> {
+        self.mixnet
+            .sender_tags
+            .lock()
+            .insert(self.sender_tag, self.self_address);
+        if !self.take_gateway_loss() {
+            self.deliver(&recipient, message, Some(self.sender_tag));
+        }
+        Ok(())
+    }
+
+    /// replying to a tag no message arrived with fails, as the Nym client has no reply
+    /// SURBs for it.
+    async fn send_reply(&mut self, sender_tag: SenderTag, message: Vec<u8>) -> Result<(), Error> {
+        let Some(recipient) = self.mixnet.sender_tags.lock().get(&sender_tag).copied() else {
+            return Err(Error::SurbsExhausted);
+        };
+        if !self.take_gateway_loss() {
+            self.deliver(&recipient, message, None);
+        }
+        Ok(())
+    }
+
+    fn packet_payload_len(&self, _packet_size: PacketSize) -> Option<usize> {
+        self.mixnet.packet_payload_len
+    }
+
+    /// the mock mixnet doesn't use sphinx packets, so any packet size is fine.
+    async fn send_with_packet_size(
+        &mut self,
+        recipient: Recipient,
+        message: Vec<u8>,
+        _packet_size: PacketSize,
+    ) -> Result<(), Error> {
+        self.send(recipient, message).await
+    }
+
+    async fn send_tracked(
+        &mut self,
+        recipient: Recipient,
+        message: Vec<u8>,
+        _packet_size: PacketSize,
+        id: u64,
+    ) -> Result<(), Error> {
+        let outcome = if self.take_gateway_loss() {
+            SendOutcome::LostBeforeGateway
+        } else {
+            self.deliver(&recipient, message, None);
+            SendOutcome::HandedToGateway
+        };
+        if let Some(outcomes_tx) = &self.outcomes_tx {
+            outcomes_tx.send((id, outcome)).ok();
+        }
+        Ok(())
+    }
+
+    fn reports_send_outcomes(&self) -> bool {
+        self.mixnet.send_outcomes
+    }
+
+    fn report_send_outcomes(&mut self, outcomes_tx: UnboundedSender<(u64, SendOutcome)>) {
+        self.outcomes_tx = Some(outcomes_tx);
+    }
+
+    async fn recv(&mut self) -> Result<Vec<u8>, Error> {
+        self.recv_with_sender_tag()
+            .await
+            .map(|(message, _)| message)
+    }
+
+    async fn recv_with_sender_tag(&mut self) -> Result<(Vec<u8>, Option<SenderTag>), Error> {
+        self.inbound_rx
+            .recv()
+            .await
+            .ok_or(Error::MixnetDisconnected)
+    }
+
+    async fn reconnect(&mut self) -> Result<(), Error> {
+        let (inbound_tx, inbound_rx) = unbounded_channel();
+        self.mixnet
+            .recipients
+            .lock()
+            .insert(self.self_address.to_bytes(), inbound_tx);
+        self.inbound_rx = inbound_rx;
+        Ok(())
+    }
+}
+
+/// random_recipient returns a well-formed Nym address that doesn't belong to any real client.
+pub(crate) fn random_recipient() -> Recipient {
+    let mut bytes = [0u8; RECIPIENT_LENGTH];
+    bytes[..32].copy_from_slice(&ed25519::Keypair::generate().public().encode());
+    OsRng.fill_bytes(&mut bytes[32..64]);
+    bytes[64..].copy_from_slice(&ed25519::Keypair::generate().public().encode());
+    Recipient::try_from_bytes(bytes).expect("recipient bytes must be valid")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn test_mock_backend_send_recv() {
+        let mixnet = MockMixnet::new();
+        let mut alice = mixnet.new_backend();
+        let mut bob = mixnet.new_backend();
+        assert_ne!(alice.self_address(), bob.self_address());
+
+        alice
+            .send(bob.self_address(), b"hello".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(bob.recv().await.unwrap(), b"hello".to_vec());
+
+        // sending to an unknown address doesn't fail
+        alice
+            .send(random_recipient(), b"lost".to_vec())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mock_backend_anonymous_reply() {
+        let mixnet = MockMixnet::new();
+        let mut alice = mixnet.new_backend();
+        let mut bob = mixnet.new_backend();
+
+        alice
+            .send_anonymous(bob.self_address(), b"hello".to_vec(), 10)
+            .await
+            .unwrap();
+        let (message, sender_tag) = bob.recv_with_sender_tag().await.unwrap();
+        assert_eq!(message, b"hello".to_vec());
+        let sender_tag = sender_tag.unwrap();
+
+        bob.send_reply(sender_tag, b"hi".to_vec()).await.unwrap();
+        assert_eq!(
+            alice.recv_with_sender_tag().await.unwrap(),
+            (b"hi".to_vec(), None)
+        );
+
+        // there are no reply SURBs for a tag no message arrived with
+        assert!(matches!(
+            bob.send_reply(SenderTag([0; 16]), b"lost".to_vec()).await,
+            Err(Error::SurbsExhausted)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mock_mixnet_link_conditions() {
+        let mixnet = MockMixnet::new();
+        let mut alice = mixnet.new_backend();
+        let mut bob = mixnet.new_backend();
+        let mut carol = mixnet.new_backend();
+        let latency = Duration::from_millis(100);
+        let slow = LinkConditions {
+            latency,
+            ..Default::default()
+        };
+        let lossy = LinkConditions {
+            loss: 1.0,
+            ..Default::default()
+        };
+        mixnet.set_link(&alice.self_address(), &bob.self_address(), slow);
+        mixnet.set_link(&alice.self_address(), &carol.self_address(), lossy);
+
+        let sent_at = Instant::now();
+        for i in 0..3u8 {
+            alice.send(bob.self_address(), vec![i]).await.unwrap();
+            alice.send(carol.self_address(), vec![i]).await.unwrap();
+        }
+        for i in 0..3u8 {
+            assert_eq!(bob.recv().await.unwrap(), vec![i]);
+        }
+        assert!(sent_at.elapsed() >= latency);
+        assert!(carol.recv().now_or_never().is_none());
+
+        // links only apply in one direction
+        bob.send(alice.self_address(), b"hi".to_vec())
+            .await
+            .unwrap();
+        carol
+            .send(alice.self_address(), b"hi".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            alice.recv().now_or_never().unwrap().unwrap(),
+            b"hi".to_vec()
+        );
+        assert_eq!(
+            alice.recv().now_or_never().unwrap().unwrap(),
+            b"hi".to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_backend_reconnect() {
+        let mixnet = MockMixnet::new();
+        let mut alice = mixnet.new_backend();
+        let mut bob = mixnet.new_backend();
+
+        mixnet.disconnect(&bob.self_address());
+        assert!(matches!(
+            bob.recv().await.unwrap_err(),
+            Error::MixnetDisconnected
+        ));
+
+        bob.reconnect().await.unwrap();
+        alice
+            .send(bob.self_address(), b"hello".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(bob.recv().await.unwrap(), b"hello".to_vec());
+    }
+}
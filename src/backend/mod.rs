@@ -0,0 +1,271 @@
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use nym_sphinx::addressing::clients::Recipient;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::error::Error;
+
+pub mod failover;
+pub mod mock;
+#[cfg(feature = "sdk")]
+pub mod sdk;
+pub mod websocket;
+
+pub use failover::FailoverBackend;
+pub use mock::{LinkConditions, MockBackend, MockMixnet};
+#[cfg(feature = "sdk")]
+pub use sdk::{SdkBackend, SdkClientConfig};
+pub use websocket::WebsocketBackend;
+
+/// MixnetBackend abstracts how we reach the Nym mixnet: sending bytes to a recipient and
+/// receiving the bytes that other clients sent to us.
+/// The transport only talks to the mixnet through this trait, so supporting a new way of
+/// running a Nym client only requires a new implementation of it.
+#[async_trait]
+pub trait MixnetBackend: Send + 'static {
+    /// returns our own Nym address.
+    fn self_address(&self) -> Recipient;
+
+    /// sends the given bytes to the recipient over the mixnet.
+    async fn send(&mut self, recipient: Recipient, message: Vec<u8>) -> Result<(), Error>;
+
+    /// sends the given bytes to the recipient using sphinx packets of the given size.
+    /// backends that can't choose the packet size per message only support
+    /// `PacketSize::Default`, and return `Error::UnsupportedPacketSize` otherwise.
+    async fn send_with_packet_size(
+        &mut self,
+        recipient: Recipient,
+        message: Vec<u8>,
+        packet_size: PacketSize,
+    ) -> Result<(), Error> {
+        match packet_size {
+            PacketSize::Default => self.send(recipient, message).await,
+            packet_size => Err(Error::UnsupportedPacketSize(packet_size)),
+        }
+    }
+
+    /// sends like send_with_packet_size, tagging the message with an id that its outcome
+    /// is reported with, see report_send_outcomes. backends that don't report outcomes
+    /// ignore the id.
+    async fn send_tracked(
+        &mut self,
+        recipient: Recipient,
+        message: Vec<u8>,
+        packet_size: PacketSize,
+        _id: u64,
+    ) -> Result<(), Error> {
+        self.send_with_packet_size(recipient, message, packet_size)
+            .await
+    }
+
+    /// returns whether the backend can report what became of each message sent with
+    /// send_tracked. by default backends can't; neither the websocket nor the sdk backend
+    /// can, since the Nym client doesn't tell them. the mixnet task only tracks outcomes,
+    /// and retransmits messages lost before the gateway, for backends that can.
+    fn reports_send_outcomes(&self) -> bool {
+        false
+    }
+
+    /// asks the backend to report what became of each message sent with send_tracked
+    /// on the given channel, along with the message's id. it's only called if
+    /// reports_send_outcomes returns true.
+    fn report_send_outcomes(&mut self, _outcomes_tx: UnboundedSender<(u64, SendOutcome)>) {}
+
+    /// sends the given bytes to the recipient along with `reply_surbs` reply SURBs,
+    /// without revealing our Nym address: the recipient can only answer with
+    /// send_reply, to the sender tag the message arrives with.
+    /// backends that can't send anonymously return `Error::Unimplemented`.
+    async fn send_anonymous(
+        &mut self,
+        _recipient: Recipient,
+        _message: Vec<u8>,
+        _reply_surbs: u32,
+    ) -> Result<(), Error> {
+        Err(Error::Unimplemented)
+    }
+
+    /// sends the given bytes to the anonymous sender with the given tag, using the reply
+    /// SURBs it sent us. backends that can't reply return `Error::Unimplemented`.
+    async fn send_reply(&mut self, _sender_tag: SenderTag, _message: Vec<u8>) -> Result<(), Error> {
+        Err(Error::Unimplemented)
+    }
+
+    /// waits for the next message received from the mixnet.
+    /// this is raced against outbound messages, so it must be cancel-safe.
+    /// if the backend lost its connection to the mixnet, this returns
+    /// `Error::MixnetDisconnected` (or a websocket error for websocket backends).
+    async fn recv(&mut self) -> Result<Vec<u8>, Error>;
+
+    /// waits for the next message like recv, and also returns the sender tag to reply to
+    /// if it was sent anonymously. backends that can't reply never return a tag.
+    async fn recv_with_sender_tag(&mut self) -> Result<(Vec<u8>, Option<SenderTag>), Error> {
+        Ok((self.recv().await?, None))
+    }
+
+    /// re-establishes the connection to the mixnet after recv reported a disconnect,
+    /// and subscribes to inbound messages again.
+    /// backends that can't reconnect return `Error::Unimplemented`.
+    async fn reconnect(&mut self) -> Result<(), Error> {
+        Err(Error::Unimplemented)
+    }
+
+    /// lets go of the connection to the mixnet while the transport is paused, eg. while a
+    /// mobile app is in the background, without losing our identity. recv and send aren't
+    /// called until resume. by default the connection is kept, and only goes unused.
+    async fn suspend(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// re-establishes the connection after suspend, and finds out our Nym address again,
+    /// in case it changed meanwhile. if this fails, the backend is reconnected instead.
+    async fn resume(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// returns how many bytes of plaintext fit in one sphinx packet of the given size, as
+    /// the Nym client reports it. backends that can't ask the client return None, and the
+    /// packet size's [nominal payload](PacketSize::nominal_payload_len) is assumed; the
+    /// Nym client doesn't tell the websocket or sdk backend, so they always do.
+    fn packet_payload_len(&self, _packet_size: PacketSize) -> Option<usize> {
+        None
+    }
+
+    /// returns what the backend knows about its connection to the mixnet.
+    /// by default that's only the gateway, which is part of our Nym address.
+    fn info(&self) -> MixnetInfo {
+        MixnetInfo {
+            gateway: Some(self.self_address().gateway().to_base58_string()),
+            ..MixnetInfo::default()
+        }
+    }
+}
+
+/// SenderTag identifies the sender of an anonymous message to the Nym client it arrived
+/// at, which keeps the reply SURBs the sender included, without revealing its Nym address.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct SenderTag(pub [u8; 16]);
+
+/// SendOutcome is what became of a message after the backend accepted it, as far as the
+/// Nym client can tell.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SendOutcome {
+    /// all of its packets were handed to the gateway, so it's on its way through the mixnet
+    HandedToGateway,
+    /// some of its packets never reached the gateway, eg. because the client's connection
+    /// to it dropped; since the message can't arrive, sending it again can't duplicate it
+    LostBeforeGateway,
+}
+
+/// MixnetInfo describes how a backend is connected to the mixnet, so that transport issues
+/// can be correlated with conditions on the mixnet side, eg. a gateway having problems or
+/// a topology change. Fields the backend can't tell are None.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MixnetInfo {
+    /// identity key of the gateway our Nym address is registered with
+    pub gateway: Option<String>,
+    /// the current mixnet topology epoch, as reported by the Nym client
+    pub topology_epoch: Option<u64>,
+    /// version of the Nym client
+    pub client_version: Option<String>,
+}
+
+/// PacketSize is the size of the sphinx packets a message is split into.
+///
+/// Every packet is padded to the full size, so the choice is a trade-off:
+/// - regular packets (about 2KB of payload) waste little bandwidth on small messages, such
+///   as pings or gossip, and are what almost all mixnet traffic uses, so they blend in best.
+/// - extended packets (8, 16 or 32KB) split large messages into fewer packets, which means
+///   less per-packet overhead and fewer chances of a message being delayed by one slow
+///   packet, but small messages are padded a lot and fewer clients use them, which makes
+///   the traffic stand out more.
+///
+/// Mostly small messages should use regular packets; bulk transfers may benefit from
+/// extended ones.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum PacketSize {
+    /// whatever the Nym client is configured to use; this is regular packets unless the
+    /// client was configured otherwise.
+    #[default]
+    Default,
+    Regular,
+    Extended8,
+    Extended16,
+    Extended32,
+}
+
+impl PacketSize {
+    /// nominal_payload_len returns how many bytes of plaintext fit in one packet of this
+    /// size under the mixnet's packet parameters at the time of writing, counting default
+    /// packets as regular ones. The Nym network may change its parameters, so the size
+    /// reported by a backend that can ask the Nym client, or set with
+    /// [`NymTransport::with_packet_payload_len`](crate::transport::NymTransport::with_packet_payload_len),
+    /// takes precedence.
+    pub fn nominal_payload_len(&self) -> usize {
+        match self {
+            PacketSize::Default | PacketSize::Regular => 2 * 1024,
+            PacketSize::Extended8 => 8 * 1024,
+            PacketSize::Extended16 => 16 * 1024,
+            PacketSize::Extended32 => 32 * 1024,
+        }
+    }
+}
+
+/// BackendFactory creates a new, connected backend, eg. one using a fresh Nym identity or
+/// a different gateway.
+pub type BackendFactory<B> = Box<dyn Fn() -> BoxFuture<'static, Result<B, Error>> + Send>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::mock::random_recipient;
+
+    struct DefaultOnlyBackend(Vec<Vec<u8>>);
+
+    #[async_trait]
+    impl MixnetBackend for DefaultOnlyBackend {
+        fn self_address(&self) -> Recipient {
+            random_recipient()
+        }
+
+        async fn send(&mut self, _recipient: Recipient, message: Vec<u8>) -> Result<(), Error> {
+            self.0.push(message);
+            Ok(())
+        }
+
+        async fn recv(&mut self) -> Result<Vec<u8>, Error> {
+            futures::future::pending().await
+        }
+    }
+
+    #[test]
+    fn test_default_info() {
+        let backend = DefaultOnlyBackend(vec![]);
+        let info = backend.info();
+        assert!(info.gateway.is_some());
+        assert_eq!(info.topology_epoch, None);
+        assert_eq!(info.client_version, None);
+        assert!(!backend.reports_send_outcomes());
+
+        assert_eq!(backend.packet_payload_len(PacketSize::Regular), None);
+        assert_eq!(
+            PacketSize::Default.nominal_payload_len(),
+            PacketSize::Regular.nominal_payload_len()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_with_packet_size_default_impl() {
+        let mut backend = DefaultOnlyBackend(vec![]);
+        backend
+            .send_with_packet_size(random_recipient(), vec![1], PacketSize::Default)
+            .await
+            .unwrap();
+        assert!(matches!(
+            backend
+                .send_with_packet_size(random_recipient(), vec![2], PacketSize::Extended32)
+                .await,
+            Err(Error::UnsupportedPacketSize(PacketSize::Extended32))
+        ));
+        assert_eq!(backend.0, vec![vec![1]]);
+    }
+}
@@ -0,0 +1,157 @@
+use async_trait::async_trait;
+use nym_sdk::mixnet::{self, IncludedSurbs, MixnetClient, MixnetClientBuilder};
+use nym_sphinx::addressing::clients::Recipient;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use super::MixnetBackend;
+use crate::error::Error;
+
+/// SdkClientConfig sizes the buffers of the in-process Nym client and the traffic it
+/// generates on its own, for devices with little memory or bandwidth to spare. The
+/// default leaves the nym-sdk's defaults alone.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SdkClientConfig {
+    /// disables the loop cover traffic the client sends to hide when it's sending real
+    /// messages. This weakens our anonymity, so only do it if the device can't afford it.
+    pub disable_cover_traffic: bool,
+    /// the average delay between loop cover packets; longer delays send less of them
+    pub cover_traffic_delay: Option<Duration>,
+    /// the most reply SURBs the client keeps for each sender that sent it some
+    pub max_reply_surbs: Option<usize>,
+}
+
+impl SdkClientConfig {
+    fn apply(&self, config: &mut mixnet::Config) {
+        let debug = &mut config.debug_config;
+        if self.disable_cover_traffic {
+            debug.cover_traffic.disable_loop_cover_traffic_stream = true;
+        }
+        if let Some(delay) = self.cover_traffic_delay {
+            debug.cover_traffic.loop_cover_traffic_average_delay = delay;
+        }
+        if let Some(max) = self.max_reply_surbs {
+            debug.reply_surbs.maximum_reply_surb_storage_threshold = max;
+            debug.reply_surbs.minimum_reply_surb_storage_threshold = debug
+                .reply_surbs
+                .minimum_reply_surb_storage_threshold
+                .min(max);
+        }
+    }
+}
+
+/// SdkBackend runs a Nym client in-process using the nym-sdk, so no external
+/// nym-client is required.
+pub struct SdkBackend {
+    client: Option<MixnetClient>,
+    /// the gateway and config to reconnect with
+    gateway: Option<String>,
+    config: SdkClientConfig,
+
+    /// messages received from the client but not yet returned by recv
+    received: VecDeque<Vec<u8>>,
+}
+
+impl SdkBackend {
+    /// connect_new starts a new in-process Nym client with an ephemeral identity.
+    pub async fn connect_new() -> Result<Self, Error> {
+        Self::connect_with_config(None, SdkClientConfig::default()).await
+    }
+
+    /// connect_with_gateway starts a new in-process Nym client with an ephemeral identity,
+    /// registered with the gateway with the given identity key.
+    /// Combined with `FailoverBackend`, this allows failing over to backup gateways.
+    pub async fn connect_with_gateway(gateway: String) -> Result<Self, Error> {
+        Self::connect_with_config(Some(gateway), SdkClientConfig::default()).await
+    }
+
+    /// connect_with_config starts a new in-process Nym client with an ephemeral identity,
+    /// registered with the given gateway if any, and with its buffers and cover traffic
+    /// set by the config. Reconnecting keeps both.
+    pub async fn connect_with_config(
+        gateway: Option<String>,
+        config: SdkClientConfig,
+    ) -> Result<Self, Error> {
+        let client = connect(gateway.clone(), &config).await?;
+        Ok(SdkBackend {
+            gateway,
+            config,
+            ..Self::new(client)
+        })
+    }
+
+    /// new wraps an already connected nym-sdk client.
+    pub fn new(client: MixnetClient) -> Self {
+        SdkBackend {
+            client: Some(client),
+            gateway: None,
+            config: SdkClientConfig::default(),
+            received: VecDeque::new(),
+        }
+    }
+
+    fn client(&self) -> &MixnetClient {
+        self.client
+            .as_ref()
+            .expect("client is only taken while reconnecting")
+    }
+}
+
+#[async_trait]
+impl MixnetBackend for SdkBackend {
+    fn self_address(&self) -> Recipient {
+        *self.client().nym_address()
+    }
+
+    async fn send(&mut self, recipient: Recipient, message: Vec<u8>) -> Result<(), Error> {
+        self.client()
+            .send_bytes(recipient, message, IncludedSurbs::ExposeSelfAddress)
+            .await;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            if let Some(message) = self.received.pop_front() {
+                return Ok(message);
+            }
+
+            let Some(client) = self.client.as_mut() else {
+                return Err(Error::MixnetDisconnected);
+            };
+            let Some(messages) = client.wait_for_messages().await else {
+                return Err(Error::MixnetDisconnected);
+            };
+            self.received
+                .extend(messages.into_iter().map(|msg| msg.message));
+        }
+    }
+
+    /// reconnect shuts down the old client and subscribes to inbound messages on a new one.
+    /// note that ephemeral clients get a new Nym address when reconnecting.
+    async fn reconnect(&mut self) -> Result<(), Error> {
+        if let Some(client) = self.client.take() {
+            client.disconnect().await;
+        }
+        self.client = Some(connect(self.gateway.clone(), &self.config).await?);
+        self.received.clear();
+        Ok(())
+    }
+}
+
+/// connect starts a new in-process Nym client with an ephemeral identity.
+async fn connect(gateway: Option<String>, config: &SdkClientConfig) -> Result<MixnetClient, Error> {
+    if gateway.is_none() && *config == SdkClientConfig::default() {
+        return MixnetClient::connect_new()
+            .await
+            .map_err(|e| Error::NymMessageError(e.to_string()));
+    }
+    let mut sdk_config = mixnet::Config::new(gateway, None);
+    config.apply(&mut sdk_config);
+    MixnetClientBuilder::new(Some(sdk_config), None)
+        .await
+        .map_err(|e| Error::NymMessageError(e.to_string()))?
+        .connect_to_mixnet()
+        .await
+        .map_err(|e| Error::NymMessageError(e.to_string()))
+}
@@ -0,0 +1,527 @@
+use async_trait::async_trait;
+use futures::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use nym_sphinx::addressing::clients::Recipient;
+use nym_sphinx::anonymous_replies::requests::AnonymousSenderTag;
+use nym_websocket::{requests::ClientRequest, responses::ServerResponse};
+use parking_lot::Mutex;
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+use tokio::{
+    net::TcpStream,
+    sync::mpsc::{channel, error::SendError, Sender},
+};
+use tokio_tungstenite::{
+    connect_async, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream,
+};
+use tracing::{debug, warn};
+
+use super::{MixnetBackend, SenderTag};
+use crate::error::Error;
+use crate::fragment::{fragment, FRAGMENT_HEADER_LEN};
+
+/// WsConnection is a websocket connection to a Nym client.
+pub(crate) type WsConnection = WebSocketStream<MaybeTlsStream<TcpStream>>;
+type WsSink = SplitSink<WsConnection, Message>;
+type WsStream = SplitStream<WsConnection>;
+type Outbound = (Recipient, Vec<u8>);
+
+/// WORKER_QUEUE_LEN is how many messages may wait for a sender worker, beyond which send
+/// waits for the worker to catch up.
+const WORKER_QUEUE_LEN: usize = 4;
+
+/// WebsocketBackend talks to an external nym-client over its websockets endpoint.
+pub struct WebsocketBackend {
+    /// the Nym client's websockets endpoint; kept for reconnecting
+    uri: String,
+    sender_workers: usize,
+
+    /// the largest websocket frame we write, if capped
+    max_frame_size: Option<usize>,
+
+    self_address: Recipient,
+
+    /// inbound messages are only read from the first websocket connection
+    stream: WsStream,
+
+    /// write side of the first websocket connection; used directly when
+    /// there's only one sender worker
+    sink: WsSink,
+
+    /// the sender workers when more than one websocket connection is used
+    workers: Vec<SenderWorker>,
+}
+
+impl WebsocketBackend {
+    /// connect opens `sender_workers` websocket connections to the Nym client at `uri`.
+    /// Outbound messages are spread across the connections, but all messages for the same
+    /// recipient are written by the same connection, so the relative order of messages on
+    /// a connection is preserved.
+    pub async fn connect(uri: &String, sender_workers: usize) -> Result<Self, Error> {
+        Self::connect_inner(uri, sender_workers, None).await
+    }
+
+    /// connect_with_max_frame_size is like connect, for Nym clients behind proxies that
+    /// cap the size of websocket frames. Messages that don't fit in a frame of
+    /// `max_frame_size` bytes are split up and sent as several mixnet messages, which
+    /// the receiving transport puts back together. Peers should use the same cap, since
+    /// it also limits what their Nym client can deliver to ours.
+    pub async fn connect_with_max_frame_size(
+        uri: &String,
+        sender_workers: usize,
+        max_frame_size: usize,
+    ) -> Result<Self, Error> {
+        Self::connect_inner(uri, sender_workers, Some(max_frame_size)).await
+    }
+
+    /// from_connection returns a backend with a single websocket connection, `ws_stream`,
+    /// to the Nym client at `uri`, whose address is already known to be `self_address`.
+    pub(crate) fn from_connection(
+        uri: &str,
+        ws_stream: WsConnection,
+        self_address: Recipient,
+    ) -> Self {
+        let (sink, stream) = ws_stream.split();
+        WebsocketBackend {
+            uri: uri.to_string(),
+            sender_workers: 1,
+            max_frame_size: None,
+            self_address,
+            stream,
+            sink,
+            workers: vec![],
+        }
+    }
+
+    async fn connect_inner(
+        uri: &String,
+        sender_workers: usize,
+        max_frame_size: Option<usize>,
+    ) -> Result<Self, Error> {
+        let (mut ws_stream, _) = connect_async(uri)
+            .await
+            .map_err(Error::WebsocketStreamError)?;
+
+        let self_address = get_self_address(&mut ws_stream).await?;
+        if let Some(max_frame_size) = max_frame_size {
+            if max_frame_size <= send_request_overhead(self_address) + FRAGMENT_HEADER_LEN {
+                return Err(Error::InvalidConfig("max frame size is too small"));
+            }
+        }
+        let (sink, stream) = ws_stream.split();
+
+        // every additional worker opens its own websocket connection.
+        let mut workers = vec![];
+        for _ in 1..sender_workers {
+            workers.push(SenderWorker::spawn(uri, max_frame_size).await?);
+        }
+
+        Ok(WebsocketBackend {
+            uri: uri.clone(),
+            sender_workers,
+            max_frame_size,
+            self_address,
+            stream,
+            sink,
+            workers,
+        })
+    }
+
+    /// restart_worker replaces the sender worker at the given index, whose connection
+    /// failed, with one on a new connection. The new worker writes what the old one
+    /// couldn't, followed by `outbound`. If no new connection can be opened, the error is
+    /// returned, which the transport takes as a disconnect.
+    async fn restart_worker(&mut self, index: usize, outbound: Outbound) -> Result<(), Error> {
+        let worker = SenderWorker::spawn(&self.uri, self.max_frame_size).await?;
+        let old = std::mem::replace(&mut self.workers[index], worker);
+        let failure = old.failure.lock().take();
+        let unwritten = match failure {
+            Some(WorkerFailure { error, unwritten }) => {
+                debug!(
+                    "restarted sender worker, rewriting {} messages: {:?}",
+                    unwritten.len(),
+                    error
+                );
+                unwritten
+            }
+            None => vec![],
+        };
+        for outbound in unwritten.into_iter().chain([outbound]) {
+            self.workers[index]
+                .tx
+                .send(outbound)
+                .await
+                .map_err(|_| Error::MixnetDisconnected)?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MixnetBackend for WebsocketBackend {
+    fn self_address(&self) -> Recipient {
+        self.self_address
+    }
+
+    /// when more than one sender worker is used, this returns once the message has been
+    /// queued on its worker, waiting while the worker is behind. A worker whose connection
+    /// failed is restarted first.
+    async fn send(&mut self, recipient: Recipient, message: Vec<u8>) -> Result<(), Error> {
+        if self.workers.is_empty() {
+            return write_bytes(&mut self.sink, recipient, &message, self.max_frame_size).await;
+        }
+
+        // the first connection is also a worker, so it's included in the worker count
+        let index = worker_index(&recipient, self.workers.len() + 1);
+        if index == 0 {
+            return write_bytes(&mut self.sink, recipient, &message, self.max_frame_size).await;
+        }
+
+        match self.workers[index - 1].tx.send((recipient, message)).await {
+            Ok(()) => Ok(()),
+            Err(SendError(outbound)) => self.restart_worker(index - 1, outbound).await,
+        }
+    }
+
+    /// anonymous messages and replies are always written by the first connection.
+    async fn send_anonymous(
+        &mut self,
+        recipient: Recipient,
+        message: Vec<u8>,
+        reply_surbs: u32,
+    ) -> Result<(), Error> {
+        let request = |message| ClientRequest::SendAnonymous {
+            recipient,
+            message,
+            reply_surbs,
+            connection_id: None,
+        };
+        write_requests(&mut self.sink, &message, self.max_frame_size, request).await
+    }
+
+    async fn send_reply(&mut self, sender_tag: SenderTag, message: Vec<u8>) -> Result<(), Error> {
+        let request = |message| ClientRequest::Reply {
+            sender_tag: AnonymousSenderTag::from_bytes(sender_tag.0),
+            message,
+            connection_id: None,
+        };
+        write_requests(&mut self.sink, &message, self.max_frame_size, request).await
+    }
+
+    async fn recv(&mut self) -> Result<Vec<u8>, Error> {
+        self.recv_with_sender_tag()
+            .await
+            .map(|(message, _)| message)
+    }
+
+    async fn recv_with_sender_tag(&mut self) -> Result<(Vec<u8>, Option<SenderTag>), Error> {
+        let Some(res) = self.stream.next().await else {
+            return Err(Error::WebsocketStreamReadNone);
+        };
+
+        let msg = res.map_err(Error::WebsocketStreamError)?;
+        match parse_nym_message(msg)? {
+            ServerResponse::Received(msg) => Ok((
+                msg.message,
+                msg.sender_tag.map(|tag| SenderTag(tag.to_bytes())),
+            )),
+            ServerResponse::Error(e) => Err(Error::NymMessageError(e.to_string())),
+            _ => Err(Error::UnexpectedNymMessage),
+        }
+    }
+
+    /// reconnect opens new websocket connections to the Nym client, replacing the old ones.
+    /// the old sender workers stop once their channels are dropped, and what the ones whose
+    /// connection failed couldn't write is written by the new ones.
+    async fn reconnect(&mut self) -> Result<(), Error> {
+        let backend =
+            Self::connect_inner(&self.uri, self.sender_workers, self.max_frame_size).await?;
+        if backend.self_address != self.self_address {
+            warn!(
+                "Nym address changed after reconnecting: {} -> {}",
+                self.self_address, backend.self_address
+            );
+        }
+        let unwritten: Vec<Outbound> = self
+            .workers
+            .iter()
+            .filter_map(|worker| worker.failure.lock().take())
+            .flat_map(|failure| failure.unwritten)
+            .collect();
+        *self = backend;
+        for (recipient, message) in unwritten {
+            self.send(recipient, message).await?;
+        }
+        Ok(())
+    }
+
+    /// suspend closes the websocket connections to the Nym client; resume opens new ones.
+    async fn suspend(&mut self) -> Result<(), Error> {
+        self.workers.clear();
+        self.sink.close().await.map_err(Error::WebsocketStreamError)
+    }
+
+    async fn resume(&mut self) -> Result<(), Error> {
+        self.reconnect().await
+    }
+}
+
+/// SenderWorker is a task writing messages to its own websocket connection to the Nym
+/// client. It stops once the connection fails, leaving behind why, along with the messages
+/// it couldn't write.
+struct SenderWorker {
+    tx: Sender<Outbound>,
+    failure: Arc<Mutex<Option<WorkerFailure>>>,
+}
+
+struct WorkerFailure {
+    error: Error,
+    /// the message that failed to be written, if any, and those queued behind it
+    unwritten: Vec<Outbound>,
+}
+
+impl SenderWorker {
+    /// spawn opens a websocket connection to the Nym client at `uri`, and starts a task
+    /// writing every message sent to the worker to it.
+    async fn spawn(uri: &str, max_frame_size: Option<usize>) -> Result<Self, Error> {
+        let (ws_stream, _) = connect_async(uri)
+            .await
+            .map_err(Error::WebsocketStreamError)?;
+        let (mut sink, mut stream) = ws_stream.split();
+        let (tx, mut rx) = channel::<Outbound>(WORKER_QUEUE_LEN);
+        let failure = Arc::new(Mutex::new(None));
+        let worker_failure = failure.clone();
+        tokio::task::spawn(async move {
+            let (error, mut unwritten) = loop {
+                tokio::select! {
+                    outbound = rx.recv() => {
+                        // the backend was dropped or replaced
+                        let Some((recipient, message)) = outbound else {
+                            return;
+                        };
+                        let res = write_bytes(&mut sink, recipient, &message, max_frame_size);
+                        if let Err(e) = res.await {
+                            break (e, vec![(recipient, message)]);
+                        }
+                    }
+                    // anything the Nym client writes to a send-only connection is discarded
+                    res = stream.next() => match res {
+                        Some(Ok(_)) => {}
+                        Some(Err(e)) => break (Error::WebsocketStreamError(e), vec![]),
+                        None => break (Error::WebsocketStreamReadNone, vec![]),
+                    },
+                }
+            };
+            warn!(
+                "sender worker lost its connection to the Nym client: {:?}",
+                error
+            );
+            // held until the queue is drained, so a send failing on the closed queue finds
+            // the failure once it gets the lock
+            let mut failure = worker_failure.lock();
+            rx.close();
+            while let Ok(outbound) = rx.try_recv() {
+                unwritten.push(outbound);
+            }
+            *failure = Some(WorkerFailure { error, unwritten });
+        });
+        Ok(SenderWorker { tx, failure })
+    }
+}
+
+/// worker_index returns the index of the worker responsible for the given recipient.
+fn worker_index(recipient: &Recipient, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    recipient.to_bytes().hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
+/// send_request_overhead returns how many bytes a send request adds to the message.
+fn send_request_overhead(recipient: Recipient) -> usize {
+    request_overhead(|message| send_request(recipient, message))
+}
+
+/// request_overhead returns how many bytes a request made by the given function adds to
+/// the message.
+fn request_overhead(request: impl Fn(Vec<u8>) -> ClientRequest) -> usize {
+    request(vec![]).serialize().len()
+}
+
+fn send_request(recipient: Recipient, message: Vec<u8>) -> ClientRequest {
+    ClientRequest::Send {
+        recipient,
+        message,
+        connection_id: None,
+    }
+}
+
+/// write_bytes writes a message to the Nym client, split up into several send requests
+/// if it doesn't fit in a frame of `max_frame_size` bytes.
+async fn write_bytes(
+    ws_sink: &mut WsSink,
+    recipient: Recipient,
+    message: &[u8],
+    max_frame_size: Option<usize>,
+) -> Result<(), Error> {
+    let request = |message| send_request(recipient, message);
+    write_requests(ws_sink, message, max_frame_size, request).await?;
+
+    debug!(
+        "wrote message to mixnet: recipient: {:?}",
+        recipient.to_string()
+    );
+    Ok(())
+}
+
+/// write_requests writes a message to the Nym client in requests made by the given
+/// function, several of them if it doesn't fit in a frame of `max_frame_size` bytes.
+async fn write_requests(
+    ws_sink: &mut WsSink,
+    message: &[u8],
+    max_frame_size: Option<usize>,
+    request: impl Fn(Vec<u8>) -> ClientRequest,
+) -> Result<(), Error> {
+    let fragments = match max_frame_size {
+        Some(max_frame_size) => fragment(
+            message,
+            max_frame_size.saturating_sub(request_overhead(&request)),
+        )?,
+        None => vec![message.to_vec()],
+    };
+
+    for fragment in fragments {
+        ws_sink
+            .send(Message::Binary(request(fragment).serialize()))
+            .await
+            .map_err(Error::WebsocketStreamError)?;
+    }
+    Ok(())
+}
+
+/// open_connection opens a websocket connection to the Nym client at `uri`.
+pub(crate) async fn open_connection(uri: &str) -> Result<WsConnection, Error> {
+    let (ws_stream, _) = connect_async(uri)
+        .await
+        .map_err(Error::WebsocketStreamError)?;
+    Ok(ws_stream)
+}
+
+/// get_self_address asks the Nym client for its Nym address.
+pub(crate) async fn get_self_address(ws_stream: &mut WsConnection) -> Result<Recipient, Error> {
+    let self_address_request = ClientRequest::SelfAddress.serialize();
+    ws_stream
+        .send(Message::Binary(self_address_request))
+        .await
+        .map_err(Error::WebsocketStreamError)?;
+
+    // loop until we receive the SelfAddress respone, since the next message might not
+    // necessarily be the SelfAddress response.
+    while let Some(raw_message) = ws_stream.next().await {
+        let raw_message = raw_message.map_err(Error::WebsocketStreamError)?;
+        let response = parse_nym_message(raw_message)?;
+        return match response {
+            ServerResponse::SelfAddress(recipient) => Ok(*recipient),
+            ServerResponse::Error(e) => Err(Error::NymMessageError(e.to_string())),
+            _ => continue,
+        };
+    }
+    Err(Error::RecvError)
+}
+
+fn parse_nym_message(msg: Message) -> Result<ServerResponse, Error> {
+    match msg {
+        Message::Text(str) => ServerResponse::deserialize(&str.into_bytes())
+            .map_err(|e| Error::NymMessageError(e.to_string())),
+        Message::Binary(bytes) => {
+            ServerResponse::deserialize(&bytes).map_err(|e| Error::NymMessageError(e.to_string()))
+        }
+        _ => Err(Error::UnknownNymMessage),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use nym_sphinx::addressing::clients::Recipient;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+    use tokio_tungstenite::accept_async;
+
+    use super::*;
+    use crate::backend::mock::random_recipient;
+
+    /// serve_nym_client answers the websocket connection like a Nym client with the given
+    /// address, passing on the messages sent through it along with the connection's index.
+    /// Connections other than the first are closed after the first message.
+    async fn serve_nym_client(
+        tcp: TcpStream,
+        index: usize,
+        self_address: Recipient,
+        sent_tx: UnboundedSender<(usize, Vec<u8>)>,
+    ) {
+        let mut ws_stream = accept_async(tcp).await.unwrap();
+        while let Some(Ok(Message::Binary(bytes))) = ws_stream.next().await {
+            match ClientRequest::deserialize(&bytes).unwrap() {
+                ClientRequest::SelfAddress => {
+                    let response = ServerResponse::SelfAddress(Box::new(self_address));
+                    let response = Message::Binary(response.serialize());
+                    ws_stream.send(response).await.unwrap();
+                }
+                ClientRequest::Send { message, .. } => {
+                    sent_tx.send((index, message)).unwrap();
+                    if index > 0 {
+                        return;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_worker_index_affinity() {
+        let recipient = Recipient::try_from_base58_string("D1rrpsysCGCYXy9saP8y3kmNpGtJZUXN9SvFoUcqAsM9.9Ssso1ea5NfkbMASdiseDSjTN1fSWda5SgEVjdSN4CvV@GJqd3ZxpXWSNxTfx7B1pPtswpetH4LnJdFeLeuY5KUuN").unwrap();
+        let index = worker_index(&recipient, 4);
+        assert!(index < 4);
+        for _ in 0..10 {
+            assert_eq!(worker_index(&recipient, 4), index);
+        }
+        assert_eq!(worker_index(&recipient, 1), 0);
+    }
+
+    #[tokio::test]
+    async fn test_sender_worker_restart() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("ws://{}", listener.local_addr().unwrap());
+        let (sent_tx, mut sent_rx) = unbounded_channel();
+        let self_address = random_recipient();
+        tokio::spawn(async move {
+            for index in 0.. {
+                let (tcp, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve_nym_client(tcp, index, self_address, sent_tx.clone()));
+            }
+        });
+
+        let mut backend = WebsocketBackend::connect(&uri, 2).await.unwrap();
+        assert_eq!(backend.self_address(), self_address);
+        let recipient = std::iter::repeat_with(random_recipient)
+            .find(|recipient| worker_index(recipient, 2) == 1)
+            .unwrap();
+
+        backend.send(recipient, vec![1]).await.unwrap();
+        assert_eq!(sent_rx.recv().await.unwrap(), (1, vec![1]));
+        // the Nym client closed the worker's connection
+        while backend.workers[0].failure.lock().is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        // so the next message is written by a worker on a new connection
+        backend.send(recipient, vec![2]).await.unwrap();
+        assert_eq!(sent_rx.recv().await.unwrap(), (2, vec![2]));
+    }
+}
@@ -0,0 +1,43 @@
+//! nym-doctor checks what stands in the way of reaching the mixnet through a Nym client,
+//! and prints a report with a hint for each check that failed:
+//!
+//! ```sh
+//! cargo run --bin nym-doctor -- [--timeout <seconds>] <endpoint>
+//! ```
+//!
+//! The endpoint is a URI like the one given to `NymTransport::new`, eg.
+//! `ws://127.0.0.1:1977`. Each check may take up to `--timeout` seconds, 30 by default.
+//! The exit status is 1 if a check failed. Don't point it at a Nym client a node is using,
+//! since it reads the messages the client receives.
+
+use rust_libp2p_nym::doctor::diagnose;
+use std::{error::Error, process::ExitCode};
+use tokio::time::Duration;
+
+const USAGE: &str = "usage: nym-doctor [--timeout <seconds>] <endpoint>";
+
+#[tokio::main]
+async fn main() -> Result<ExitCode, Box<dyn Error>> {
+    let mut timeout = Duration::from_secs(30);
+    let mut endpoint = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--timeout" => {
+                let secs = args.next().ok_or(USAGE)?;
+                timeout = Duration::from_secs(secs.parse().map_err(|_| USAGE)?);
+            }
+            _ if endpoint.is_none() => endpoint = Some(arg),
+            _ => return Err(USAGE.into()),
+        }
+    }
+    let endpoint = endpoint.ok_or(USAGE)?;
+
+    let report = diagnose(endpoint.as_str(), timeout).await?;
+    print!("{report}");
+    Ok(if report.is_healthy() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
@@ -0,0 +1,166 @@
+//! nym-replay feeds the inbound messages of a captured message journal back through a
+//! NymTransport running on a mock mixnet, so the connection and substream state
+//! transitions they caused can be reproduced and debugged without a live mixnet.
+//!
+//! Record a journal with `NymTransport::with_journal()`, then run:
+//!
+//! ```sh
+//! cargo run --bin nym-replay -- [--realtime] <journal file>...
+//! ```
+//!
+//! Pass rotated files oldest first, eg. `journal.2 journal.1 journal`. With `--realtime`,
+//! messages are replayed with the delays they were originally received with. Set
+//! `RUST_LOG` to change what's logged; the transport's own logs are at debug level.
+//!
+//! Outbound messages, and inbound ones recorded without their data, are skipped. Messages
+//! the transport sends in response go to the mock mixnet and are dropped.
+
+use futures::{future::poll_fn, AsyncReadExt};
+use libp2p::core::{
+    identity::Keypair,
+    muxing::{StreamMuxer, StreamMuxerExt},
+    transport::{Transport, TransportEvent},
+    PeerId,
+};
+use rust_libp2p_nym::backend::{mock::MockMixnet, MixnetBackend};
+use rust_libp2p_nym::journal::{JournalDirection, JournalReader, JournalRecord};
+use rust_libp2p_nym::transport::NymTransport;
+use std::{error::Error, pin::Pin, task::Poll};
+use tokio::time::{sleep, Duration};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+/// how long the transport is given to process the last replayed message.
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new("nym_replay=info,rust_libp2p_nym=debug")),
+        )
+        .init();
+
+    let mut realtime = false;
+    let mut paths = vec![];
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--realtime" => realtime = true,
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        return Err("usage: nym-replay [--realtime] <journal file>...".into());
+    }
+
+    let mut records: Vec<JournalRecord> = vec![];
+    for path in &paths {
+        for record in JournalReader::open(path)? {
+            records.push(record?);
+        }
+    }
+
+    let mixnet = MockMixnet::new();
+    let backend = mixnet.new_backend();
+    let address = backend.self_address();
+    let transport = NymTransport::new_with_backend(backend, Keypair::generate_ed25519())?;
+    tokio::spawn(drive_transport(transport));
+    // stands in for all the remote peers in the journal
+    let mut replayer = mixnet.new_backend();
+
+    let (mut replayed, mut skipped) = (0, 0);
+    let mut last_timestamp = None;
+    for (i, record) in records.into_iter().enumerate() {
+        if record.direction != JournalDirection::Inbound {
+            skipped += 1;
+            continue;
+        }
+        if !record.is_complete() {
+            warn!("record {}: only the header was recorded, skipping", i);
+            skipped += 1;
+            continue;
+        }
+
+        if let (true, Some(last)) = (realtime, last_timestamp) {
+            sleep(Duration::from_micros(record.timestamp.saturating_sub(last))).await;
+        }
+        last_timestamp = Some(record.timestamp);
+
+        match record.parse() {
+            Ok(msg) => info!("record {}: {:?}, {} bytes", i, msg.kind(), record.len),
+            Err(e) => info!("record {}: failed to parse: {:?}", i, e),
+        }
+        replayer.send(address, record.bytes).await?;
+        replayed += 1;
+    }
+
+    sleep(SETTLE_TIME).await;
+    info!("replayed {} messages, skipped {}", replayed, skipped);
+    Ok(())
+}
+
+/// drive_transport polls the transport, logging its events and driving the connections
+/// it establishes.
+async fn drive_transport(mut transport: NymTransport) {
+    loop {
+        match poll_fn(|cx| Pin::new(&mut transport).poll(cx)).await {
+            TransportEvent::Incoming { upgrade, .. } => {
+                tokio::spawn(async move {
+                    match upgrade.await {
+                        Ok((peer_id, connection)) => {
+                            info!("connection established with {}", peer_id);
+                            drive_connection(peer_id, connection).await;
+                        }
+                        Err(e) => info!("inbound connection failed: {:?}", e),
+                    }
+                });
+            }
+            event => info!("transport event: {:?}", event),
+        }
+    }
+}
+
+/// drive_connection accepts the substreams opened on a connection and reads them to the
+/// end, until the connection fails.
+async fn drive_connection<M>(peer_id: PeerId, mut connection: M)
+where
+    M: StreamMuxer + Unpin,
+    M::Substream: futures::AsyncRead + Send + Unpin + 'static,
+    M::Error: std::fmt::Debug,
+{
+    let mut substreams = 0;
+    loop {
+        let res = poll_fn(|cx| {
+            if let Poll::Ready(res) = connection.poll_inbound_unpin(cx) {
+                return Poll::Ready(res.map(Some));
+            }
+            connection.poll_unpin(cx).map(|res| res.map(|_| None))
+        })
+        .await;
+        let mut substream = match res {
+            Ok(Some(substream)) => substream,
+            Ok(None) => continue,
+            Err(e) => {
+                info!("connection with {} closed: {:?}", peer_id, e);
+                return;
+            }
+        };
+
+        substreams += 1;
+        let index = substreams;
+        info!("substream {} opened by {}", index, peer_id);
+        tokio::spawn(async move {
+            let mut data = vec![];
+            match substream.read_to_end(&mut data).await {
+                Ok(len) => info!("substream {} closed after {} bytes", index, len),
+                Err(e) => info!(
+                    "substream {} failed after {} bytes: {:?}",
+                    index,
+                    data.len(),
+                    e
+                ),
+            }
+        });
+    }
+}
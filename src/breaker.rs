@@ -0,0 +1,340 @@
+use parking_lot::Mutex;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::error::Error;
+
+/// CircuitBreakerConfig sets when the circuit breaker trips and how the Nym client is
+/// probed while it's open, see
+/// [`NymTransport::with_circuit_breaker`](crate::transport::NymTransport::with_circuit_breaker).
+#[derive(Clone, Debug, PartialEq)]
+pub struct CircuitBreakerConfig {
+    /// how many messages in a row the Nym client may refuse, or lose before the gateway,
+    /// before the breaker trips
+    pub max_send_failures: u32,
+    /// how long we may go without receiving anything after sending a message, if that's
+    /// checked. Nodes that send without expecting anything back should leave it unset.
+    pub max_inbound_silence: Option<Duration>,
+    /// how often a message is sent to our own address while the breaker is open
+    pub probe_interval: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            max_send_failures: 5,
+            max_inbound_silence: Some(Duration::from_secs(60)),
+            probe_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+impl CircuitBreakerConfig {
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if self.max_send_failures == 0 {
+            return Err(Error::InvalidConfig(
+                "circuit breaker send failure threshold must not be zero",
+            ));
+        }
+        if self.max_inbound_silence == Some(Duration::ZERO) {
+            return Err(Error::InvalidConfig(
+                "circuit breaker inbound silence threshold must not be zero",
+            ));
+        }
+        if self.probe_interval.is_zero() {
+            return Err(Error::InvalidConfig(
+                "circuit breaker probe interval must not be zero",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// BreakerState is whether the mixnet is considered to be in service.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BreakerState {
+    /// messages go through as usual
+    Closed,
+    /// the mixnet appears to be down: dials fail right away, retransmissions are paused
+    /// and the Nym client is probed until it's back
+    Open,
+}
+
+/// TripReason is why the circuit breaker tripped.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TripReason {
+    /// this many messages in a row were refused by the Nym client or lost before the
+    /// gateway
+    SendFailures(u32),
+    /// nothing was received for this long after sending a message
+    InboundSilence(Duration),
+}
+
+impl fmt::Display for TripReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TripReason::SendFailures(failures) => {
+                write!(f, "{} consecutive send failures", failures)
+            }
+            TripReason::InboundSilence(silence) => {
+                write!(f, "nothing received for {:?}", silence)
+            }
+        }
+    }
+}
+
+/// BreakerEvent is a change of the circuit breaker's state.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BreakerEvent {
+    /// the breaker opened
+    Tripped(TripReason),
+    /// a message arrived while the breaker was open, so it closed again after the outage
+    /// lasted this long
+    Recovered { outage: Duration },
+}
+
+/// CircuitBreaker reports whether the mixnet is considered to be in service, and sends
+/// its state changes to subscribers. It's shared by the mixnet task and the transports
+/// using it, and can be kept after the transport is moved into a swarm.
+#[derive(Clone, Debug, Default)]
+pub struct CircuitBreaker {
+    inner: Arc<BreakerInner>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerInner {
+    open: AtomicBool,
+    subscribers: Mutex<Vec<UnboundedSender<BreakerEvent>>>,
+}
+
+impl CircuitBreaker {
+    /// state returns whether the breaker is currently open.
+    pub fn state(&self) -> BreakerState {
+        if self.is_open() {
+            BreakerState::Open
+        } else {
+            BreakerState::Closed
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.inner.open.load(Ordering::SeqCst)
+    }
+
+    /// subscribe returns a channel of the breaker's state changes from now on.
+    pub fn subscribe(&self) -> UnboundedReceiver<BreakerEvent> {
+        let (events_tx, events_rx) = unbounded_channel();
+        self.inner.subscribers.lock().push(events_tx);
+        events_rx
+    }
+
+    /// set_open changes the state, and sends the event to the subscribers if it did change.
+    fn set_open(&self, open: bool, event: BreakerEvent) {
+        if self.inner.open.swap(open, Ordering::SeqCst) == open {
+            return;
+        }
+        self.inner
+            .subscribers
+            .lock()
+            .retain(|events_tx| events_tx.send(event.clone()).is_ok());
+    }
+}
+
+/// OutageDetector trips the circuit breaker on consecutive send failures or inbound
+/// silence, and decides when the mixnet task probes the Nym client while it's open.
+#[derive(Debug, Default)]
+pub(crate) struct OutageDetector {
+    breaker: CircuitBreaker,
+    /// messages refused or lost before the gateway since the last one that went through
+    send_failures: u32,
+    /// when we first sent a message after the last one was received
+    awaiting_inbound_since: Option<Instant>,
+    /// when the breaker tripped, while it's open
+    opened_at: Option<Instant>,
+    next_probe_at: Option<Instant>,
+}
+
+impl OutageDetector {
+    pub(crate) fn new(breaker: CircuitBreaker) -> Self {
+        OutageDetector {
+            breaker,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn is_open(&self) -> bool {
+        self.opened_at.is_some()
+    }
+
+    /// record_sent counts a message that the Nym client took, or handed to the gateway
+    /// if it reports that.
+    pub(crate) fn record_sent(&mut self) {
+        self.send_failures = 0;
+        self.awaiting_inbound_since.get_or_insert_with(Instant::now);
+    }
+
+    /// record_send_failure counts a message that the Nym client refused or lost before the
+    /// gateway, and trips the breaker once there were too many in a row.
+    pub(crate) fn record_send_failure(&mut self, config: Option<&CircuitBreakerConfig>) {
+        self.send_failures = self.send_failures.saturating_add(1);
+        let Some(config) = config else {
+            return;
+        };
+        if self.send_failures >= config.max_send_failures {
+            self.trip(TripReason::SendFailures(self.send_failures));
+        }
+    }
+
+    /// record_received notes that a message arrived, and returns true if that closed the
+    /// breaker.
+    pub(crate) fn record_received(&mut self) -> bool {
+        self.send_failures = 0;
+        self.awaiting_inbound_since = None;
+        self.close()
+    }
+
+    /// silence_deadline returns when the breaker trips unless something arrives, if it's
+    /// closed and inbound silence is checked.
+    pub(crate) fn silence_deadline(
+        &self,
+        config: Option<&CircuitBreakerConfig>,
+    ) -> Option<Instant> {
+        if self.is_open() {
+            return None;
+        }
+        let max_silence = config?.max_inbound_silence?;
+        Some(self.awaiting_inbound_since? + max_silence)
+    }
+
+    /// check_silence trips the breaker if nothing arrived for too long after sending.
+    pub(crate) fn check_silence(&mut self, config: Option<&CircuitBreakerConfig>) {
+        let Some(deadline) = self.silence_deadline(config) else {
+            return;
+        };
+        if Instant::now() >= deadline {
+            let since = self.awaiting_inbound_since.unwrap_or(deadline);
+            self.trip(TripReason::InboundSilence(since.elapsed()));
+        }
+    }
+
+    /// resumed restarts the wait for inbound messages after the transport was paused, so
+    /// the time spent paused isn't taken for silence.
+    pub(crate) fn resumed(&mut self) {
+        if self.awaiting_inbound_since.is_some() {
+            self.awaiting_inbound_since = Some(Instant::now());
+        }
+    }
+
+    /// next_probe_at returns when the Nym client is to be probed next, while the breaker
+    /// is open.
+    pub(crate) fn next_probe_at(&self) -> Option<Instant> {
+        self.next_probe_at
+    }
+
+    /// probe_sent schedules the next probe.
+    pub(crate) fn probe_sent(&mut self, config: Option<&CircuitBreakerConfig>) {
+        let probe_interval = config
+            .map(|config| config.probe_interval)
+            .unwrap_or_else(|| CircuitBreakerConfig::default().probe_interval);
+        self.next_probe_at = Some(Instant::now() + probe_interval);
+    }
+
+    /// close closes the breaker, eg. once it was turned off, and returns true if it was
+    /// open.
+    pub(crate) fn close(&mut self) -> bool {
+        let Some(opened_at) = self.opened_at.take() else {
+            return false;
+        };
+        self.next_probe_at = None;
+        info!("the mixnet is back after {:?}", opened_at.elapsed());
+        self.breaker.set_open(
+            false,
+            BreakerEvent::Recovered {
+                outage: opened_at.elapsed(),
+            },
+        );
+        true
+    }
+
+    fn trip(&mut self, reason: TripReason) {
+        if self.is_open() {
+            return;
+        }
+        self.opened_at = Some(Instant::now());
+        // the first probe goes out right away
+        self.next_probe_at = Some(Instant::now());
+        self.send_failures = 0;
+        self.awaiting_inbound_since = None;
+        warn!("the mixnet appears to be down: {}", reason);
+        self.breaker.set_open(true, BreakerEvent::Tripped(reason));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_outage_detector() {
+        let config = CircuitBreakerConfig {
+            max_send_failures: 2,
+            max_inbound_silence: Some(Duration::from_millis(50)),
+            probe_interval: Duration::from_secs(1),
+        };
+        let breaker = CircuitBreaker::default();
+        let mut events_rx = breaker.subscribe();
+        let mut detector = OutageDetector::new(breaker.clone());
+
+        // a message going through resets the count
+        detector.record_send_failure(Some(&config));
+        detector.record_sent();
+        detector.record_send_failure(Some(&config));
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        detector.record_send_failure(Some(&config));
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(
+            events_rx.try_recv().unwrap(),
+            BreakerEvent::Tripped(TripReason::SendFailures(2))
+        );
+        assert!(detector.next_probe_at().is_some());
+        assert!(detector.silence_deadline(Some(&config)).is_none());
+
+        assert!(detector.record_received());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(matches!(
+            events_rx.try_recv().unwrap(),
+            BreakerEvent::Recovered { .. }
+        ));
+        assert!(detector.next_probe_at().is_none());
+        assert!(!detector.record_received());
+
+        // silence only counts once something was sent
+        assert!(detector.silence_deadline(Some(&config)).is_none());
+        detector.record_sent();
+        detector.check_silence(Some(&config));
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        detector.check_silence(Some(&config));
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(matches!(
+            events_rx.try_recv().unwrap(),
+            BreakerEvent::Tripped(TripReason::InboundSilence(_))
+        ));
+
+        // without a config, failures are counted but never trip it
+        assert!(detector.close());
+        for _ in 0..10 {
+            detector.record_send_failure(None);
+        }
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+}
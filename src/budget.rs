@@ -0,0 +1,290 @@
+use parking_lot::Mutex;
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Waker},
+};
+
+/// PRESSURE_PERCENT is how full the budget is, in percent of its limit, when backpressure
+/// starts: substream writes and reading from the mixnet pause until it's below again.
+const PRESSURE_PERCENT: usize = 80;
+
+/// BufferKind is a kind of buffer whose memory counts towards the budget.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum BufferKind {
+    /// fragments of messages that haven't all arrived yet
+    Reassembly,
+    /// data received on substreams that the application hasn't read yet
+    Substream,
+    /// data written to substreams that hasn't been written to the mixnet yet
+    Outbound,
+}
+
+impl BufferKind {
+    const COUNT: usize = 3;
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// MemoryBudget caps the memory held by the transport's buffers, see
+/// [`NymTransport::with_memory_budget`](crate::transport::NymTransport::with_memory_budget),
+/// and reports how much they currently hold. It can be cloned and kept around after the
+/// transport is moved into a swarm.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBudget {
+    inner: Arc<BudgetInner>,
+}
+
+#[derive(Debug, Default)]
+struct BudgetInner {
+    /// 0 if there's no limit
+    limit: AtomicUsize,
+    used: [AtomicUsize; BufferKind::COUNT],
+    shed: AtomicU64,
+    shed_cover: AtomicU64,
+    shed_low_priority: AtomicU64,
+    /// tasks waiting for the budget to be below the pressure threshold
+    waiters: Mutex<Vec<Waker>>,
+}
+
+impl MemoryBudget {
+    /// limit returns the most memory the buffers may hold, if there's a limit.
+    pub fn limit(&self) -> Option<usize> {
+        match self.inner.limit.load(Ordering::SeqCst) {
+            0 => None,
+            limit => Some(limit),
+        }
+    }
+
+    /// used returns the memory all buffers currently hold, in bytes.
+    pub fn used(&self) -> usize {
+        self.inner
+            .used
+            .iter()
+            .map(|used| used.load(Ordering::SeqCst))
+            .sum()
+    }
+
+    /// used_by returns the memory the given kind of buffer currently holds, in bytes.
+    pub fn used_by(&self, kind: BufferKind) -> usize {
+        self.inner.used[kind.index()].load(Ordering::SeqCst)
+    }
+
+    /// shed returns how many partially received messages were dropped because the budget
+    /// was exhausted.
+    pub fn shed(&self) -> u64 {
+        self.inner.shed.load(Ordering::Relaxed)
+    }
+
+    /// shed_cover returns how many outbound probes, ie. latency pings and echoes, were
+    /// dropped because the budget was under pressure.
+    pub fn shed_cover(&self) -> u64 {
+        self.inner.shed_cover.load(Ordering::Relaxed)
+    }
+
+    /// shed_low_priority returns how many outbound messages of low priority substreams
+    /// were dropped because the budget was exhausted.
+    pub fn shed_low_priority(&self) -> u64 {
+        self.inner.shed_low_priority.load(Ordering::Relaxed)
+    }
+
+    /// is_under_pressure returns true if the buffers hold enough memory that writes and
+    /// reading from the mixnet are paused.
+    pub fn is_under_pressure(&self) -> bool {
+        match self.limit() {
+            Some(limit) => self.used() >= limit / 100 * PRESSURE_PERCENT,
+            None => false,
+        }
+    }
+
+    /// is_exhausted returns true if the buffers hold all the memory the limit allows.
+    pub fn is_exhausted(&self) -> bool {
+        matches!(self.limit(), Some(limit) if self.used() >= limit)
+    }
+
+    pub(crate) fn set_limit(&self, limit: Option<usize>) {
+        self.inner.limit.store(limit.unwrap_or(0), Ordering::SeqCst);
+        self.wake_waiters();
+    }
+
+    /// reserve counts the bytes towards the budget until the returned reservation is
+    /// dropped, whether or not that exceeds the limit. It's for memory that's already
+    /// been allocated, so it can't be refused anymore.
+    pub(crate) fn reserve(&self, kind: BufferKind, bytes: usize) -> Reservation {
+        self.inner.used[kind.index()].fetch_add(bytes, Ordering::SeqCst);
+        Reservation {
+            budget: Some(self.clone()),
+            kind,
+            bytes,
+        }
+    }
+
+    /// try_reserve is like reserve, but returns None if the bytes would exceed the limit.
+    pub(crate) fn try_reserve(&self, kind: BufferKind, bytes: usize) -> Option<Reservation> {
+        if !self.has_room(bytes) {
+            return None;
+        }
+        Some(self.reserve(kind, bytes))
+    }
+
+    pub(crate) fn record_shed(&self) {
+        self.inner.shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_shed_cover(&self) {
+        self.inner.shed_cover.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_shed_low_priority(&self) {
+        self.inner.shed_low_priority.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// poll_relieved is ready once the budget isn't under pressure.
+    pub(crate) fn poll_relieved(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if !self.is_under_pressure() {
+            return Poll::Ready(());
+        }
+        {
+            let mut waiters = self.inner.waiters.lock();
+            if !waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+                waiters.push(cx.waker().clone());
+            }
+        }
+        // memory might have been released before the waker was registered
+        if !self.is_under_pressure() {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+
+    fn has_room(&self, bytes: usize) -> bool {
+        match self.limit() {
+            Some(limit) => self.used() + bytes <= limit,
+            None => true,
+        }
+    }
+
+    fn release(&self, kind: BufferKind, bytes: usize) {
+        self.inner.used[kind.index()].fetch_sub(bytes, Ordering::SeqCst);
+        if !self.is_under_pressure() {
+            self.wake_waiters();
+        }
+    }
+
+    fn wake_waiters(&self) {
+        let waiters = std::mem::take(&mut *self.inner.waiters.lock());
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+}
+
+/// Reservation is memory counted towards a budget, until it's dropped. The default one
+/// doesn't count towards any budget.
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    budget: Option<MemoryBudget>,
+    kind: BufferKind,
+    bytes: usize,
+}
+
+impl Default for Reservation {
+    fn default() -> Self {
+        Reservation {
+            budget: None,
+            kind: BufferKind::Substream,
+            bytes: 0,
+        }
+    }
+}
+
+impl Reservation {
+    /// resize counts the given number of bytes instead, whether or not that exceeds the
+    /// limit.
+    pub(crate) fn resize(&mut self, bytes: usize) {
+        let Some(budget) = &self.budget else {
+            return;
+        };
+        if bytes > self.bytes {
+            budget.inner.used[self.kind.index()].fetch_add(bytes - self.bytes, Ordering::SeqCst);
+        } else {
+            budget.release(self.kind, self.bytes - bytes);
+        }
+        self.bytes = bytes;
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// try_grow counts the given number of extra bytes, and returns false if that would
+    /// exceed the limit.
+    pub(crate) fn try_grow(&mut self, bytes: usize) -> bool {
+        if let Some(budget) = &self.budget {
+            if !budget.has_room(bytes) {
+                return false;
+            }
+        }
+        self.resize(self.bytes + bytes);
+        true
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.release(self.kind, self.bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::task::noop_waker;
+
+    use super::*;
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = MemoryBudget::default();
+        budget.set_limit(Some(1000));
+
+        let mut reassembly = budget.try_reserve(BufferKind::Reassembly, 500).unwrap();
+        let outbound = budget.reserve(BufferKind::Outbound, 200);
+        assert_eq!(budget.used(), 700);
+        assert_eq!(budget.used_by(BufferKind::Reassembly), 500);
+        assert!(!budget.is_under_pressure());
+        assert!(budget.try_reserve(BufferKind::Reassembly, 301).is_none());
+
+        assert!(reassembly.try_grow(100));
+        assert!(budget.is_under_pressure());
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert_eq!(budget.poll_relieved(&mut cx), Poll::Pending);
+        assert!(!budget.is_exhausted());
+        assert!(!reassembly.try_grow(201));
+        assert!(reassembly.try_grow(200));
+        assert!(budget.is_exhausted());
+        reassembly.resize(600);
+
+        drop(outbound);
+        assert_eq!(budget.used(), 600);
+        assert!(budget.inner.waiters.lock().is_empty());
+        assert_eq!(budget.poll_relieved(&mut cx), Poll::Ready(()));
+
+        reassembly.resize(0);
+        assert_eq!(budget.used(), 0);
+        drop(reassembly);
+        assert_eq!(budget.used(), 0);
+
+        // without a limit, usage is still counted
+        budget.set_limit(None);
+        let _substream = budget.reserve(BufferKind::Substream, 5000);
+        assert_eq!(budget.used(), 5000);
+        assert!(!budget.is_under_pressure());
+    }
+}
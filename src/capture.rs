@@ -0,0 +1,451 @@
+use libp2p::core::PeerId;
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    fs::{File, OpenOptions},
+    io::{BufReader, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tracing::warn;
+
+use crate::error::Error;
+pub use crate::journal::JournalDirection;
+pub use crate::message::MessageKind;
+use crate::message::{parse_message_data, ConnectionId, InboundMessage, Message};
+use crate::stats::unix_micros;
+
+/// CAPTURE_MAGIC starts every capture file, followed by the format version and the snap
+/// length, both big-endian.
+const CAPTURE_MAGIC: [u8; 4] = *b"NYMC";
+const CAPTURE_VERSION: u16 = 1;
+
+/// CapturedMessage is a message of a connection kept by the debug capture, see
+/// [`TransportStats::set_message_capture`](crate::stats::TransportStats::set_message_capture).
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapturedMessage {
+    pub direction: JournalDirection,
+    /// microseconds since the unix epoch
+    pub timestamp: u64,
+    pub kind: MessageKind,
+    /// the message as written to or received from the mixnet
+    pub bytes: Vec<u8>,
+}
+
+/// MessageCapture keeps the latest messages of each connection in a ring buffer, while
+/// it's enabled. It's shared by the mixnet task, which sees the messages, and the
+/// transports using it, which know the connections.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MessageCapture {
+    inner: Arc<CaptureInner>,
+}
+
+#[derive(Debug, Default)]
+struct CaptureInner {
+    /// messages kept per connection; 0 while disabled
+    capacity: AtomicUsize,
+    state: Mutex<CaptureState>,
+    /// whether messages are written to a capture file
+    writing: AtomicBool,
+    file: Mutex<Option<CaptureFile>>,
+}
+
+#[derive(Debug, Default)]
+struct CaptureState {
+    peers: HashMap<ConnectionId, PeerId>,
+    buffers: HashMap<ConnectionId, VecDeque<CapturedMessage>>,
+}
+
+impl MessageCapture {
+    /// set_capacity keeps up to `capacity` messages per connection from now on, or
+    /// disables the capture and discards the messages kept so far if None.
+    pub(crate) fn set_capacity(&self, capacity: Option<usize>) {
+        let capacity = capacity.unwrap_or(0);
+        self.inner.capacity.store(capacity, Ordering::SeqCst);
+        let mut state = self.inner.state.lock();
+        state.buffers.retain(|_, buffer| {
+            buffer.drain(..buffer.len().saturating_sub(capacity));
+            !buffer.is_empty()
+        });
+    }
+
+    /// set_file writes the messages of the registered connections to the capture file from
+    /// now on, or stops writing them if None.
+    pub(crate) fn set_file(&self, file: Option<CaptureFile>) {
+        let mut current = self.inner.file.lock();
+        self.inner.writing.store(file.is_some(), Ordering::SeqCst);
+        *current = file;
+    }
+
+    /// register_connection captures the messages of the connection from now on, under the
+    /// given peer.
+    pub(crate) fn register_connection(&self, id: &ConnectionId, peer_id: PeerId) {
+        self.inner.state.lock().peers.insert(id.clone(), peer_id);
+    }
+
+    /// unregister_connection stops capturing the messages of the connection, and discards
+    /// those kept.
+    pub(crate) fn unregister_connection(&self, id: &ConnectionId) {
+        let mut state = self.inner.state.lock();
+        state.peers.remove(id);
+        state.buffers.remove(id);
+    }
+
+    /// record keeps the message if the capture is enabled and it belongs to a registered
+    /// connection, dropping the connection's oldest one if its buffer is full, and writes
+    /// it to the capture file if there's one.
+    pub(crate) fn record(&self, direction: JournalDirection, message: &Message, bytes: &[u8]) {
+        let capacity = self.inner.capacity.load(Ordering::SeqCst);
+        let writing = self.inner.writing.load(Ordering::SeqCst);
+        if capacity == 0 && !writing {
+            return;
+        }
+        let Some(id) = message.connection_id() else {
+            return;
+        };
+        let mut state = self.inner.state.lock();
+        let Some(peer_id) = state.peers.get(id).copied() else {
+            return;
+        };
+        let timestamp = unix_micros();
+        if writing {
+            if let Some(file) = self.inner.file.lock().as_mut() {
+                file.write(direction, timestamp, &peer_id, bytes);
+            }
+        }
+        if capacity == 0 {
+            return;
+        }
+        let buffer = state.buffers.entry(id.clone()).or_default();
+        if buffer.len() >= capacity {
+            buffer.pop_front();
+        }
+        buffer.push_back(CapturedMessage {
+            direction,
+            timestamp,
+            kind: message.kind(),
+            bytes: bytes.to_vec(),
+        });
+    }
+
+    /// captured returns the messages kept for the connections to the peer, oldest first.
+    pub(crate) fn captured(&self, peer_id: &PeerId) -> Vec<CapturedMessage> {
+        let state = self.inner.state.lock();
+        let mut messages: Vec<CapturedMessage> = state
+            .buffers
+            .iter()
+            .filter(|(id, _)| state.peers.get(*id) == Some(peer_id))
+            .flat_map(|(_, buffer)| buffer.iter().cloned())
+            .collect();
+        messages.sort_by_key(|message| message.timestamp);
+        messages
+    }
+}
+
+/// CaptureFile is a file the messages of connections are written to, for offline analysis
+/// of the protocol, see
+/// [`TransportStats::set_capture_file`](crate::stats::TransportStats::set_capture_file).
+///
+/// Like a pcap file, it starts with a header: the magic bytes `NYMC`, the format version
+/// as a u16 and the snap length as a u32, 0 if messages are kept whole. Each record then
+/// holds the timestamp in microseconds since the unix epoch as a u64, the direction as a
+/// byte (0 for inbound, 1 for outbound), the length of the remote peer's ID as a byte and
+/// the ID, the length of the message as a u32, and the length of what was kept of it as a
+/// u32 followed by those bytes. Integers are big-endian. Read it with [`CaptureReader`].
+pub struct CaptureFile {
+    path: PathBuf,
+    file: File,
+    snap_len: u32,
+}
+
+impl fmt::Debug for CaptureFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("CaptureFile").field(&self.path).finish()
+    }
+}
+
+impl CaptureFile {
+    /// create creates the capture file, replacing any file at the path. Of each message,
+    /// at most `snap_len` bytes are kept if set, eg. just enough for the headers.
+    pub fn create<P: AsRef<Path>>(path: P, snap_len: Option<u32>) -> Result<Self, Error> {
+        if snap_len == Some(0) {
+            return Err(Error::InvalidConfig("capture snap length must not be zero"));
+        }
+        let snap_len = snap_len.unwrap_or(0);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .map_err(Error::CaptureFileError)?;
+        let mut header = CAPTURE_MAGIC.to_vec();
+        header.extend_from_slice(&CAPTURE_VERSION.to_be_bytes());
+        header.extend_from_slice(&snap_len.to_be_bytes());
+        file.write_all(&header).map_err(Error::CaptureFileError)?;
+        Ok(CaptureFile {
+            path: path.as_ref().to_path_buf(),
+            file,
+            snap_len,
+        })
+    }
+
+    /// write appends a record of the message. Failures are logged rather than returned,
+    /// so the capture never gets in the way of the traffic itself.
+    fn write(
+        &mut self,
+        direction: JournalDirection,
+        timestamp: u64,
+        peer_id: &PeerId,
+        bytes: &[u8],
+    ) {
+        let kept = match self.snap_len {
+            0 => bytes.len(),
+            snap_len => bytes.len().min(snap_len as usize),
+        };
+        let peer_id = peer_id.to_bytes();
+        let mut record = Vec::with_capacity(18 + peer_id.len() + kept);
+        record.extend_from_slice(&timestamp.to_be_bytes());
+        record.push(match direction {
+            JournalDirection::Inbound => 0u8,
+            JournalDirection::Outbound => 1u8,
+        });
+        record.push(peer_id.len() as u8);
+        record.extend_from_slice(&peer_id);
+        record.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        record.extend_from_slice(&(kept as u32).to_be_bytes());
+        record.extend_from_slice(&bytes[..kept]);
+        // a single write per record, so a crash doesn't leave half a record behind
+        if let Err(e) = self.file.write_all(&record) {
+            warn!("failed to write capture record: {:?}", e);
+        }
+    }
+}
+
+/// CaptureRecord is a message read back from a capture file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaptureRecord {
+    /// microseconds since the unix epoch
+    pub timestamp: u64,
+    pub direction: JournalDirection,
+    pub peer_id: PeerId,
+    /// the length of the message, which is more than `bytes` if it was cut at the snap
+    /// length
+    pub len: u32,
+    pub bytes: Vec<u8>,
+}
+
+impl CaptureRecord {
+    /// is_complete returns true if the whole message was kept.
+    pub fn is_complete(&self) -> bool {
+        self.bytes.len() == self.len as usize
+    }
+
+    /// kind returns the type of the message, if it's a known one.
+    pub fn kind(&self) -> Option<MessageKind> {
+        self.bytes
+            .first()
+            .copied()
+            .and_then(MessageKind::from_type_byte)
+    }
+
+    /// parse decodes the message, as the transport would on receiving it. Messages cut at
+    /// the snap length may fail to parse.
+    pub fn parse(&self) -> Result<InboundMessage, Error> {
+        parse_message_data(&self.bytes)
+    }
+
+    /// read_from reads the next record, or returns None at the end of the file.
+    fn read_from<R: Read>(reader: &mut R) -> Result<Option<Self>, Error> {
+        let mut header = [0u8; 10];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(Error::CaptureFileError(e)),
+        }
+        let timestamp = u64::from_be_bytes(header[0..8].try_into().unwrap());
+        let direction = match header[8] {
+            0 => JournalDirection::Inbound,
+            1 => JournalDirection::Outbound,
+            _ => return Err(Error::InvalidCaptureFile),
+        };
+        let mut peer_id = vec![0u8; header[9] as usize];
+        reader
+            .read_exact(&mut peer_id)
+            .map_err(Error::CaptureFileError)?;
+        let peer_id = PeerId::from_bytes(&peer_id).map_err(|_| Error::InvalidCaptureFile)?;
+
+        let mut lengths = [0u8; 8];
+        reader
+            .read_exact(&mut lengths)
+            .map_err(Error::CaptureFileError)?;
+        let len = u32::from_be_bytes(lengths[0..4].try_into().unwrap());
+        let kept = u32::from_be_bytes(lengths[4..8].try_into().unwrap());
+        if kept > len {
+            return Err(Error::InvalidCaptureFile);
+        }
+        let mut bytes = vec![0u8; kept as usize];
+        reader
+            .read_exact(&mut bytes)
+            .map_err(Error::CaptureFileError)?;
+
+        Ok(Some(CaptureRecord {
+            timestamp,
+            direction,
+            peer_id,
+            len,
+            bytes,
+        }))
+    }
+}
+
+/// CaptureReader reads the records of a capture file, oldest first.
+pub struct CaptureReader {
+    reader: BufReader<File>,
+    snap_len: Option<u32>,
+}
+
+impl CaptureReader {
+    /// open checks the header of the capture file, which must be of a version this reader
+    /// understands.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = File::open(path).map_err(Error::CaptureFileError)?;
+        let mut reader = BufReader::new(file);
+        let mut header = [0u8; 10];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| Error::InvalidCaptureFile)?;
+        if header[0..4] != CAPTURE_MAGIC
+            || u16::from_be_bytes(header[4..6].try_into().unwrap()) != CAPTURE_VERSION
+        {
+            return Err(Error::InvalidCaptureFile);
+        }
+        let snap_len = u32::from_be_bytes(header[6..10].try_into().unwrap());
+        Ok(CaptureReader {
+            reader,
+            snap_len: (snap_len != 0).then_some(snap_len),
+        })
+    }
+
+    /// snap_len returns how many bytes of each message were kept at most, if limited.
+    pub fn snap_len(&self) -> Option<u32> {
+        self.snap_len
+    }
+}
+
+impl Iterator for CaptureReader {
+    type Item = Result<CaptureRecord, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        CaptureRecord::read_from(&mut self.reader).transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::{SubstreamId, SubstreamMessage, TransportMessage};
+
+    #[test]
+    fn test_message_capture() {
+        let capture = MessageCapture::default();
+        let peer_id = PeerId::random();
+        let id = ConnectionId::generate();
+        let substream_id = SubstreamId::generate();
+        let message_on = |id: &ConnectionId, nonce| {
+            Message::TransportMessage(TransportMessage {
+                nonce,
+                id: id.clone(),
+                message: SubstreamMessage::new_with_data(substream_id.clone(), vec![1, 2, 3]),
+            })
+        };
+        let message = |nonce| message_on(&id, nonce);
+        capture.register_connection(&id, peer_id);
+
+        // nothing is kept while disabled
+        capture.record(JournalDirection::Inbound, &message(1), &[1]);
+        assert!(capture.captured(&peer_id).is_empty());
+
+        capture.set_capacity(Some(2));
+        for nonce in 1..=3 {
+            let message = message(nonce);
+            capture.record(JournalDirection::Outbound, &message, &message.to_bytes());
+        }
+        let captured = capture.captured(&peer_id);
+        assert_eq!(captured.len(), 2);
+        assert_eq!(captured[0].bytes, message(2).to_bytes());
+        assert_eq!(captured[1].kind, MessageKind::Transport);
+
+        // messages of unknown connections aren't kept
+        let other = message_on(&ConnectionId::generate(), 1);
+        capture.record(JournalDirection::Inbound, &other, &other.to_bytes());
+        assert_eq!(capture.captured(&peer_id).len(), 2);
+
+        capture.set_capacity(Some(1));
+        assert_eq!(capture.captured(&peer_id), captured[1..]);
+        capture.set_capacity(None);
+        assert!(capture.captured(&peer_id).is_empty());
+    }
+
+    #[test]
+    fn test_capture_file() {
+        let dir = std::env::temp_dir().join(format!("capture-{}", unix_micros()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("messages.capture");
+        let capture = MessageCapture::default();
+        let peer_id = PeerId::random();
+        let id = ConnectionId::generate();
+        capture.register_connection(&id, peer_id);
+        let message = |data: Vec<u8>| {
+            Message::TransportMessage(TransportMessage {
+                nonce: 1,
+                id: id.clone(),
+                message: SubstreamMessage::new_with_data(SubstreamId::generate(), data),
+            })
+        };
+
+        // the file is written even though no messages are kept in memory
+        capture.set_file(Some(CaptureFile::create(&path, None).unwrap()));
+        let short = message(vec![1, 2, 3]);
+        capture.record(JournalDirection::Outbound, &short, &short.to_bytes());
+        let long = message(vec![7u8; 500]);
+        capture.record(JournalDirection::Inbound, &long, &long.to_bytes());
+        capture.set_file(None);
+        capture.record(JournalDirection::Inbound, &short, &short.to_bytes());
+        assert!(capture.captured(&peer_id).is_empty());
+
+        let reader = CaptureReader::open(&path).unwrap();
+        assert_eq!(reader.snap_len(), None);
+        let records: Vec<CaptureRecord> = reader.map(|record| record.unwrap()).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, JournalDirection::Outbound);
+        assert_eq!(records[0].peer_id, peer_id);
+        assert_eq!(records[0].bytes, short.to_bytes());
+        assert_eq!(records[0].kind(), Some(MessageKind::Transport));
+        assert!(records[1].is_complete());
+        assert!(records[1].parse().is_ok());
+        assert!(records[0].timestamp <= records[1].timestamp);
+
+        // with a snap length, only the start of each message is kept
+        capture.set_file(Some(CaptureFile::create(&path, Some(100)).unwrap()));
+        capture.record(JournalDirection::Inbound, &long, &long.to_bytes());
+        let mut reader = CaptureReader::open(&path).unwrap();
+        assert_eq!(reader.snap_len(), Some(100));
+        let record = reader.next().unwrap().unwrap();
+        assert_eq!(record.bytes.len(), 100);
+        assert_eq!(record.len as usize, long.to_bytes().len());
+        assert!(!record.is_complete());
+        assert!(reader.next().is_none());
+
+        CaptureFile::create(&path, Some(0)).unwrap_err();
+        std::fs::write(&path, b"not a capture").unwrap();
+        assert!(matches!(
+            CaptureReader::open(&path),
+            Err(Error::InvalidCaptureFile)
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,767 @@
+use libp2p::core::PeerId;
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::{
+    sync::watch,
+    time::{Duration, Instant},
+};
+
+use crate::backend::PacketSize;
+use crate::error::Error;
+use crate::framing::Framing;
+use crate::DEFAULT_HANDSHAKE_TIMEOUT_SECS;
+
+/// CONFIG_ENV_PREFIX starts the names of the environment variables that override the
+/// settings of a configuration file: `NYM_TRANSPORT_MEMORY_BUDGET=1048576` overrides
+/// `memory_budget`.
+pub const CONFIG_ENV_PREFIX: &str = "NYM_TRANSPORT_";
+
+/// RuntimeConfig is the part of a NymTransport's configuration that can be changed while
+/// it's running, through [`NymTransport::update_config`](crate::transport::NymTransport::update_config)
+/// or a [`ConfigHandle`]. Changes apply to connection attempts from then on; established
+/// connections are left alone.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimeConfig {
+    /// how long a dial waits for the remote peer to respond
+    pub handshake_timeout: Duration,
+
+    /// maximum number of inbound connection requests accepted per second, if set;
+    /// short bursts of up to this many requests are allowed
+    pub max_inbound_connections_per_sec: Option<u32>,
+
+    /// if set, only connection requests from these peers are accepted
+    pub allow_list: Option<HashSet<PeerId>>,
+
+    /// connection requests from these peers are rejected
+    pub deny_list: HashSet<PeerId>,
+
+    /// maximum number of substreams open on a connection, if set; opens by the remote peer
+    /// beyond it are reset with [`ResetCode::TooManyStreams`](crate::substream::ResetCode::TooManyStreams)
+    pub max_substreams_per_connection: Option<usize>,
+
+    /// maximum number of substreams the remote peer may open on a connection per second,
+    /// if set; short bursts of up to this many opens are allowed, and opens beyond them
+    /// are reset like those beyond `max_substreams_per_connection`
+    pub max_substream_opens_per_sec: Option<u32>,
+
+    /// if set, peer IDs, Nym addresses and message contents are left out of the logs
+    pub redact_logs: bool,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            handshake_timeout: Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT_SECS),
+            max_inbound_connections_per_sec: None,
+            allow_list: None,
+            deny_list: HashSet::new(),
+            max_substreams_per_connection: None,
+            max_substream_opens_per_sec: None,
+            redact_logs: false,
+        }
+    }
+}
+
+impl RuntimeConfig {
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if self.handshake_timeout.is_zero() {
+            return Err(Error::InvalidConfig("handshake_timeout must not be zero"));
+        }
+        if self.max_inbound_connections_per_sec == Some(0) {
+            return Err(Error::InvalidConfig(
+                "max_inbound_connections_per_sec must not be zero",
+            ));
+        }
+        if self.max_substreams_per_connection == Some(0) {
+            return Err(Error::InvalidConfig(
+                "max_substreams_per_connection must not be zero",
+            ));
+        }
+        if self.max_substream_opens_per_sec == Some(0) {
+            return Err(Error::InvalidConfig(
+                "max_substream_opens_per_sec must not be zero",
+            ));
+        }
+        Ok(())
+    }
+
+    /// is_allowed returns true if connection requests from the given peer are accepted by
+    /// the allow and deny lists.
+    pub(crate) fn is_allowed(&self, peer_id: &PeerId) -> bool {
+        if self.deny_list.contains(peer_id) {
+            return false;
+        }
+        match &self.allow_list {
+            Some(allow_list) => allow_list.contains(peer_id),
+            None => true,
+        }
+    }
+}
+
+/// NymTransportConfig holds the settings of a NymTransport that can be kept in a TOML file,
+/// so operators can tune a node without recompiling it; see
+/// [`NymTransport::with_config`](crate::transport::NymTransport::with_config).
+/// Every setting is optional, and those left out keep the transport's defaults.
+/// Durations are in milliseconds. For example:
+///
+/// ```toml
+/// handshake_timeout_ms = 10000
+/// deny_list = ["12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN"]
+/// packet_size = "extended16"
+/// memory_budget = 67108864
+/// latency_probe_interval_ms = 30000
+/// journal_path = "/var/lib/node/journal"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NymTransportConfig {
+    /// see [`RuntimeConfig::handshake_timeout`]
+    pub handshake_timeout_ms: Option<u64>,
+    /// see [`RuntimeConfig::max_inbound_connections_per_sec`]
+    pub max_inbound_connections_per_sec: Option<u32>,
+    /// peer IDs; see [`RuntimeConfig::allow_list`]
+    pub allow_list: Option<Vec<String>>,
+    /// peer IDs; see [`RuntimeConfig::deny_list`]
+    pub deny_list: Option<Vec<String>>,
+    /// see [`RuntimeConfig::max_substreams_per_connection`]
+    pub max_substreams_per_connection: Option<usize>,
+    /// see [`RuntimeConfig::max_substream_opens_per_sec`]
+    pub max_substream_opens_per_sec: Option<u32>,
+    /// see [`RuntimeConfig::redact_logs`]
+    pub redact_logs: Option<bool>,
+
+    /// how long [`NymTransport::new_with_config`](crate::transport::NymTransport::new_with_config)
+    /// waits to connect to the mixnet endpoint, if limited
+    pub connect_timeout_ms: Option<u64>,
+    /// how long dials wait for the mixnet while it's reconnecting, if at all
+    pub dial_queue_timeout_ms: Option<u64>,
+    /// the most data a substream write sends in one message, if limited
+    pub max_message_size: Option<usize>,
+    /// if set, dials that don't choose otherwise are anonymous, with this many reply SURBs
+    /// sent along with every message
+    pub reply_surbs: Option<u32>,
+    /// whether to offer the latency extension to peers
+    pub latency_extension: bool,
+    /// how far a peer's clock may be off before it's reported, if clocks are checked
+    pub clock_skew_threshold_ms: Option<u64>,
+    /// whether to correct message ages for clock skew
+    pub clock_skew_correction: bool,
+    /// whether to offer compact connection IDs to peers
+    pub compact_connection_ids: bool,
+    /// whether to announce this crate's name and version to peers, or the given user
+    /// agent instead if it's set
+    pub user_agent: bool,
+    pub custom_user_agent: Option<String>,
+    /// whether to reject inbound messages of unknown types rather than skip them
+    pub strict_message_types: bool,
+    /// one of "default", "regular", "extended8", "extended16" or "extended32"
+    pub packet_size: Option<String>,
+    /// bytes of plaintext per sphinx packet, if not asked of the Nym client. Neither the
+    /// websocket nor the sdk backend can ask it, so in production this is always what
+    /// fragmentation goes by; if it's unset, the nominal payload of the packet size is
+    /// assumed, and a warning is logged.
+    pub packet_payload_len: Option<usize>,
+    /// sphinx packets a message may need before it's split into fragments
+    pub max_message_packets: Option<usize>,
+    /// the framing version messages are written in, 1 by default
+    pub framing: Option<u8>,
+    /// how long the rest of a fragmented message is waited for, 30 seconds by default,
+    /// and how many may be partially received at once, 64 by default
+    pub reassembly_timeout_ms: Option<u64>,
+    pub max_partial_messages: Option<usize>,
+    pub inbound_bytes_per_min: Option<u64>,
+    pub outbound_bytes_per_min: Option<u64>,
+    /// the low watermark defaults to half the high one
+    pub inbound_high_watermark: Option<usize>,
+    pub inbound_low_watermark: Option<usize>,
+    /// inbound messages passed on together, if batched, and how long they may be held
+    /// back, 5 milliseconds by default
+    pub inbound_batch_size: Option<usize>,
+    pub inbound_batch_delay_ms: Option<u64>,
+    /// substream writes that may wait to be written to the mixnet, 1024 by default
+    pub outbound_capacity: Option<usize>,
+    /// in bytes
+    pub memory_budget: Option<usize>,
+    pub latency_probe_interval_ms: Option<u64>,
+    /// how often the stats of each connection are reported, if at all
+    pub connection_stats_interval_ms: Option<u64>,
+    /// how often keepalives are sent, if at all, and how many in a row may go
+    /// unanswered before a connection is dropped, 3 by default
+    pub keepalive_interval_ms: Option<u64>,
+    pub keepalive_max_missed: Option<u32>,
+    pub reachability_probe_interval_ms: Option<u64>,
+    pub upgrade_timeout_ms: Option<u64>,
+    /// tasks verifying inbound connection requests, and requests that may wait for one
+    pub handshake_workers: Option<usize>,
+    pub max_queued_handshakes: Option<usize>,
+    /// dials whose handshakes may be in flight at once, 64 by default, and dials that may
+    /// wait for one of them to finish, 256 by default
+    pub max_concurrent_dials: Option<usize>,
+    pub max_queued_dials: Option<usize>,
+
+    /// whether to trip the circuit breaker when the mixnet appears to be down; the other
+    /// settings default to those of [`CircuitBreakerConfig::default`](crate::breaker::CircuitBreakerConfig)
+    pub circuit_breaker: bool,
+    pub breaker_max_send_failures: Option<u32>,
+    pub breaker_max_inbound_silence_ms: Option<u64>,
+    pub breaker_probe_interval_ms: Option<u64>,
+
+    /// whether to keep connections to pinned peers, re-dialing them with the given backoff
+    pub peer_pinning: bool,
+    pub redial_initial_backoff_ms: Option<u64>,
+    pub redial_max_backoff_ms: Option<u64>,
+
+    /// whether to answer echo requests, at up to the given rate
+    pub echo_responder: bool,
+    pub echo_max_replies_per_sec: Option<u32>,
+
+    /// file the audit log is appended to, if any
+    pub audit_log_path: Option<PathBuf>,
+
+    /// path of the message journal, if any; the other settings default to those of
+    /// [`JournalConfig::new`](crate::journal::JournalConfig::new)
+    pub journal_path: Option<PathBuf>,
+    pub journal_max_file_size: Option<u64>,
+    pub journal_max_files: Option<usize>,
+    pub journal_headers_only: Option<bool>,
+}
+
+impl NymTransportConfig {
+    /// with_connect_timeout sets how long to wait for the connection to the mixnet
+    /// endpoint, and returns self.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout_ms = Some(timeout.as_millis() as u64);
+        self
+    }
+
+    /// with_dial_timeout sets how long a dial waits for the remote peer to respond, see
+    /// [`RuntimeConfig::handshake_timeout`], and returns self.
+    pub fn with_dial_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout_ms = Some(timeout.as_millis() as u64);
+        self
+    }
+
+    /// with_max_message_size sets the most data a substream write sends in one message,
+    /// and returns self.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = Some(max_message_size);
+        self
+    }
+
+    /// with_outbound_capacity sets how many substream writes may wait to be written to
+    /// the mixnet before further writes wait, and returns self.
+    pub fn with_outbound_capacity(mut self, capacity: usize) -> Result<Self, Error> {
+        check_outbound_capacity(capacity)?;
+        self.outbound_capacity = Some(capacity);
+        Ok(self)
+    }
+
+    /// with_keepalive sets how often keepalives are sent, and how many in a row may go
+    /// unanswered before a connection is dropped, and returns self.
+    pub fn with_keepalive(mut self, interval: Duration, max_missed: u32) -> Self {
+        self.keepalive_interval_ms = Some(interval.as_millis() as u64);
+        self.keepalive_max_missed = Some(max_missed);
+        self
+    }
+
+    /// with_anonymous_dials makes dials anonymous unless their dial options say
+    /// otherwise, with `reply_surbs` reply SURBs sent along with every message, and
+    /// returns self.
+    pub fn with_anonymous_dials(mut self, reply_surbs: u32) -> Self {
+        self.reply_surbs = Some(reply_surbs);
+        self
+    }
+
+    /// with_inbound_watermarks sets the capacity of the backlog of inbound messages, see
+    /// [`NymTransport::with_inbound_watermarks`](crate::transport::NymTransport::with_inbound_watermarks),
+    /// and returns self.
+    pub fn with_inbound_watermarks(mut self, high: usize, low: usize) -> Result<Self, Error> {
+        check_inbound_watermarks(high, low)?;
+        self.inbound_high_watermark = Some(high);
+        self.inbound_low_watermark = Some(low);
+        Ok(self)
+    }
+
+    /// with_handshake_workers sets how many tasks verify inbound connection requests, and
+    /// how many requests may wait for one, see
+    /// [`NymTransport::with_handshake_workers`](crate::transport::NymTransport::with_handshake_workers),
+    /// and returns self.
+    pub fn with_handshake_workers(
+        mut self,
+        workers: usize,
+        max_queued: usize,
+    ) -> Result<Self, Error> {
+        check_handshake_workers(workers)?;
+        self.handshake_workers = Some(workers);
+        self.max_queued_handshakes = Some(max_queued);
+        Ok(self)
+    }
+
+    /// with_max_concurrent_dials sets how many dials may handshake at once, and how many
+    /// may wait for one of them to finish, see
+    /// [`NymTransport::with_max_concurrent_dials`](crate::transport::NymTransport::with_max_concurrent_dials),
+    /// and returns self.
+    pub fn with_max_concurrent_dials(
+        mut self,
+        max_concurrent: usize,
+        max_queued: usize,
+    ) -> Result<Self, Error> {
+        check_max_concurrent_dials(max_concurrent)?;
+        self.max_concurrent_dials = Some(max_concurrent);
+        self.max_queued_dials = Some(max_queued);
+        Ok(self)
+    }
+
+    /// from_file reads the configuration from a TOML file. Environment variables named
+    /// after a setting with the [`CONFIG_ENV_PREFIX`], in upper case, override it.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let toml = std::fs::read_to_string(path).map_err(Error::ConfigFileError)?;
+        Self::from_toml(&toml, std::env::vars())
+    }
+
+    /// from_toml parses the configuration, with the settings overridden by the variables
+    /// in `env` that start with the CONFIG_ENV_PREFIX.
+    pub(crate) fn from_toml(
+        toml: &str,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, Error> {
+        let mut table: toml::Table = toml
+            .parse()
+            .map_err(|e: toml::de::Error| Error::InvalidConfigFile(e.to_string()))?;
+        for (name, value) in env {
+            if let Some(key) = name.strip_prefix(CONFIG_ENV_PREFIX) {
+                table.insert(key.to_lowercase(), parse_env_value(&value));
+            }
+        }
+        toml::Value::Table(table)
+            .try_into()
+            .map_err(|e: toml::de::Error| Error::InvalidConfigFile(e.to_string()))
+    }
+
+    /// apply_runtime_config overrides the runtime configuration with the settings that
+    /// are set.
+    pub(crate) fn apply_runtime_config(&self, config: &mut RuntimeConfig) -> Result<(), Error> {
+        if let Some(timeout) = millis(self.handshake_timeout_ms)? {
+            config.handshake_timeout = timeout;
+        }
+        if self.max_inbound_connections_per_sec.is_some() {
+            config.max_inbound_connections_per_sec = self.max_inbound_connections_per_sec;
+        }
+        if let Some(allow_list) = &self.allow_list {
+            config.allow_list = Some(parse_peer_ids(allow_list)?);
+        }
+        if let Some(deny_list) = &self.deny_list {
+            config.deny_list = parse_peer_ids(deny_list)?;
+        }
+        if self.max_substreams_per_connection.is_some() {
+            config.max_substreams_per_connection = self.max_substreams_per_connection;
+        }
+        if self.max_substream_opens_per_sec.is_some() {
+            config.max_substream_opens_per_sec = self.max_substream_opens_per_sec;
+        }
+        if let Some(redact_logs) = self.redact_logs {
+            config.redact_logs = redact_logs;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn packet_size(&self) -> Result<Option<PacketSize>, Error> {
+        let Some(packet_size) = &self.packet_size else {
+            return Ok(None);
+        };
+        let packet_size = match packet_size.as_str() {
+            "default" => PacketSize::Default,
+            "regular" => PacketSize::Regular,
+            "extended8" => PacketSize::Extended8,
+            "extended16" => PacketSize::Extended16,
+            "extended32" => PacketSize::Extended32,
+            other => {
+                return Err(Error::InvalidConfigFile(format!(
+                    "unknown packet size {:?}",
+                    other
+                )))
+            }
+        };
+        Ok(Some(packet_size))
+    }
+
+    pub(crate) fn framing(&self) -> Result<Option<Framing>, Error> {
+        match self.framing {
+            None => Ok(None),
+            Some(1) => Ok(Some(Framing::V1)),
+            Some(2) => Ok(Some(Framing::V2)),
+            Some(other) => Err(Error::InvalidConfigFile(format!(
+                "unknown framing version {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// millis returns the duration of a setting in milliseconds, if it's set.
+pub(crate) fn millis(ms: Option<u64>) -> Result<Option<Duration>, Error> {
+    match ms {
+        Some(0) => Err(Error::InvalidConfig("durations must not be zero")),
+        ms => Ok(ms.map(Duration::from_millis)),
+    }
+}
+
+/// check_outbound_capacity rejects an outbound capacity no write could ever fit in.
+pub(crate) fn check_outbound_capacity(capacity: usize) -> Result<(), Error> {
+    if capacity == 0 {
+        return Err(Error::InvalidConfig("outbound capacity must not be zero"));
+    }
+    Ok(())
+}
+
+/// check_inbound_watermarks rejects watermarks reading could never resume between.
+pub(crate) fn check_inbound_watermarks(high: usize, low: usize) -> Result<(), Error> {
+    if high == 0 || low >= high {
+        return Err(Error::InvalidConfig(
+            "inbound high watermark must be positive and above the low watermark",
+        ));
+    }
+    Ok(())
+}
+
+/// check_handshake_workers rejects a handshake pool without workers.
+pub(crate) fn check_handshake_workers(workers: usize) -> Result<(), Error> {
+    if workers == 0 {
+        return Err(Error::InvalidConfig("handshake workers must not be zero"));
+    }
+    Ok(())
+}
+
+/// check_max_concurrent_dials rejects a dial limit no dial could ever go under.
+pub(crate) fn check_max_concurrent_dials(max_concurrent: usize) -> Result<(), Error> {
+    if max_concurrent == 0 {
+        return Err(Error::InvalidConfig(
+            "max concurrent dials must not be zero",
+        ));
+    }
+    Ok(())
+}
+
+fn parse_peer_ids(peer_ids: &[String]) -> Result<HashSet<PeerId>, Error> {
+    peer_ids
+        .iter()
+        .map(|peer_id| {
+            PeerId::from_str(peer_id)
+                .map_err(|_| Error::InvalidConfigFile(format!("invalid peer ID {:?}", peer_id)))
+        })
+        .collect()
+}
+
+/// parse_env_value parses an environment variable as a TOML value, eg. a number, a
+/// boolean or an array, or as a plain string if it isn't one.
+fn parse_env_value(value: &str) -> toml::Value {
+    format!("value = {}", value)
+        .parse::<toml::Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
+/// ConfigHandle updates the runtime configuration of a NymTransport. It can be cloned and
+/// kept around after the transport is moved into a swarm.
+#[derive(Clone, Debug)]
+pub struct ConfigHandle {
+    config_tx: Arc<watch::Sender<RuntimeConfig>>,
+}
+
+impl ConfigHandle {
+    pub(crate) fn new(config: RuntimeConfig) -> (Self, watch::Receiver<RuntimeConfig>) {
+        let (config_tx, config_rx) = watch::channel(config);
+        (
+            ConfigHandle {
+                config_tx: Arc::new(config_tx),
+            },
+            config_rx,
+        )
+    }
+
+    /// config returns the current runtime configuration.
+    pub fn config(&self) -> RuntimeConfig {
+        self.config_tx.borrow().clone()
+    }
+
+    /// update replaces the runtime configuration, if the new one is valid.
+    pub fn update(&self, config: RuntimeConfig) -> Result<(), Error> {
+        config.validate()?;
+        self.config_tx.send_replace(config);
+        Ok(())
+    }
+
+    /// modify changes the runtime configuration in place, if the result is valid.
+    pub fn modify<F: FnOnce(&mut RuntimeConfig)>(&self, modify: F) -> Result<(), Error> {
+        let mut config = self.config();
+        modify(&mut config);
+        self.update(config)
+    }
+}
+
+/// RateLimiter is a token bucket allowing a number of events per second, in bursts of
+/// up to that many events.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new() -> Self {
+        RateLimiter {
+            tokens: f64::MAX,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// try_acquire returns true if an event is allowed at the given rate, and takes a
+    /// token for it. The rate may change between calls.
+    pub(crate) fn try_acquire(&mut self, per_sec: Option<u32>) -> bool {
+        let Some(per_sec) = per_sec else {
+            return true;
+        };
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        let burst = per_sec as f64;
+        self.tokens = (self.tokens + elapsed * burst).min(burst);
+        self.last_refill = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// BandwidthLimiter is a token bucket of bytes, refilled at a rate given per minute, with
+/// room for one second's worth of bytes. A message may take more bytes than are left,
+/// after which traffic waits until the bucket has refilled, so it's slowed down to the
+/// rate rather than cut off.
+#[derive(Debug)]
+pub(crate) struct BandwidthLimiter {
+    /// negative while paying off a message larger than what was left
+    available: f64,
+    last_refill: Instant,
+}
+
+impl BandwidthLimiter {
+    pub(crate) fn new() -> Self {
+        BandwidthLimiter {
+            available: f64::MAX,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, bytes_per_min: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        let per_sec = bytes_per_min as f64 / 60.0;
+        self.available = (self.available + elapsed * per_sec).min(per_sec);
+        self.last_refill = now;
+    }
+
+    /// ready_at returns when traffic is allowed again at the given rate, or None if it's
+    /// allowed now. The rate may change between calls.
+    pub(crate) fn ready_at(&mut self, bytes_per_min: Option<u64>) -> Option<Instant> {
+        let bytes_per_min = bytes_per_min?;
+        self.refill(bytes_per_min);
+        if self.available >= 0.0 {
+            return None;
+        }
+        let per_sec = bytes_per_min as f64 / 60.0;
+        Some(self.last_refill + Duration::from_secs_f64(-self.available / per_sec))
+    }
+
+    /// consume takes the given number of bytes from the bucket.
+    pub(crate) fn consume(&mut self, bytes: usize, bytes_per_min: Option<u64>) {
+        let Some(bytes_per_min) = bytes_per_min else {
+            return;
+        };
+        self.refill(bytes_per_min);
+        self.available -= bytes as f64;
+    }
+}
+
+/// Redacted formats a value for the logs, unless log redaction is enabled.
+pub(crate) struct Redacted<'a, T>(pub(crate) &'a T, pub(crate) bool);
+
+impl<T: fmt::Debug> fmt::Debug for Redacted<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.1 {
+            return f.write_str("<redacted>");
+        }
+        self.0.fmt(f)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Redacted<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.1 {
+            return f.write_str("<redacted>");
+        }
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_config_handle_rejects_invalid_config() {
+        let (handle, config_rx) = ConfigHandle::new(RuntimeConfig::default());
+        handle
+            .modify(|config| config.handshake_timeout = Duration::ZERO)
+            .unwrap_err();
+        assert_eq!(*config_rx.borrow(), RuntimeConfig::default());
+
+        handle
+            .modify(|config| config.max_inbound_connections_per_sec = Some(10))
+            .unwrap();
+        assert!(config_rx.has_changed().unwrap());
+        assert_eq!(config_rx.borrow().max_inbound_connections_per_sec, Some(10));
+
+        handle
+            .modify(|config| config.max_substreams_per_connection = Some(0))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_config_allow_and_deny_lists() {
+        let peer_id = PeerId::random();
+        let other_peer_id = PeerId::random();
+        let mut config = RuntimeConfig::default();
+        assert!(config.is_allowed(&peer_id));
+
+        config.allow_list = Some(HashSet::from([peer_id]));
+        assert!(config.is_allowed(&peer_id));
+        assert!(!config.is_allowed(&other_peer_id));
+
+        // the deny list wins
+        config.deny_list.insert(peer_id);
+        assert!(!config.is_allowed(&peer_id));
+    }
+
+    #[test]
+    fn test_transport_config_builder() {
+        let config = NymTransportConfig::default()
+            .with_dial_timeout(Duration::from_millis(1500))
+            .with_keepalive(Duration::from_secs(30), 4)
+            .with_max_message_size(1024)
+            .with_outbound_capacity(64)
+            .unwrap()
+            .with_handshake_workers(2, 16)
+            .unwrap()
+            .with_max_concurrent_dials(8, 32)
+            .unwrap();
+        assert_eq!(config.handshake_timeout_ms, Some(1500));
+        assert_eq!(config.keepalive_interval_ms, Some(30_000));
+        assert_eq!(config.keepalive_max_missed, Some(4));
+        assert_eq!(config.max_message_size, Some(1024));
+        assert_eq!(config.outbound_capacity, Some(64));
+        assert_eq!(config.handshake_workers, Some(2));
+        assert_eq!(config.max_queued_handshakes, Some(16));
+        assert_eq!(config.max_concurrent_dials, Some(8));
+        assert_eq!(config.max_queued_dials, Some(32));
+        assert_eq!(config.connect_timeout_ms, None);
+
+        let config = NymTransportConfig::default();
+        assert!(config.clone().with_outbound_capacity(0).is_err());
+        assert!(config.clone().with_inbound_watermarks(10, 10).is_err());
+        assert!(config.clone().with_handshake_workers(0, 16).is_err());
+        assert!(config.with_max_concurrent_dials(0, 32).is_err());
+    }
+
+    #[test]
+    fn test_transport_config_from_toml() {
+        let peer_id = PeerId::random();
+        let toml = format!(
+            r#"
+            handshake_timeout_ms = 10000
+            deny_list = ["{}"]
+            packet_size = "extended16"
+            memory_budget = 1000
+            max_substreams_per_connection = 64
+            journal_path = "/tmp/journal"
+            "#,
+            peer_id
+        );
+        let env = [
+            ("NYM_TRANSPORT_MEMORY_BUDGET", "2000"),
+            ("NYM_TRANSPORT_REDACT_LOGS", "true"),
+            ("NYM_TRANSPORT_AUDIT_LOG_PATH", "/tmp/audit log"),
+            ("UNRELATED", "1"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let config = NymTransportConfig::from_toml(&toml, env).unwrap();
+        assert_eq!(config.memory_budget, Some(2000));
+        assert_eq!(config.packet_size().unwrap(), Some(PacketSize::Extended16));
+        assert_eq!(config.audit_log_path, Some(PathBuf::from("/tmp/audit log")));
+        assert_eq!(config.journal_path, Some(PathBuf::from("/tmp/journal")));
+
+        let mut runtime_config = RuntimeConfig::default();
+        config.apply_runtime_config(&mut runtime_config).unwrap();
+        assert_eq!(runtime_config.handshake_timeout, Duration::from_secs(10));
+        assert_eq!(runtime_config.deny_list, HashSet::from([peer_id]));
+        assert!(runtime_config.redact_logs);
+        assert_eq!(runtime_config.allow_list, None);
+        assert_eq!(runtime_config.max_substreams_per_connection, Some(64));
+        assert_eq!(runtime_config.max_substream_opens_per_sec, None);
+
+        // unknown settings and invalid values are rejected
+        NymTransportConfig::from_toml("handshake_timeout = 5", []).unwrap_err();
+        NymTransportConfig::from_toml("memory_budget = \"lots\"", []).unwrap_err();
+        let config = NymTransportConfig::from_toml("allow_list = [\"nope\"]", []).unwrap();
+        config
+            .apply_runtime_config(&mut RuntimeConfig::default())
+            .unwrap_err();
+        let config = NymTransportConfig::from_toml("", []).unwrap();
+        assert_eq!(config, NymTransportConfig::default());
+        assert_eq!(config.packet_size().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new();
+        assert!(limiter.try_acquire(None));
+        for _ in 0..3 {
+            assert!(limiter.try_acquire(Some(3)));
+        }
+        assert!(!limiter.try_acquire(Some(3)));
+
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(limiter.try_acquire(Some(3)));
+        assert!(!limiter.try_acquire(Some(3)));
+    }
+
+    #[tokio::test]
+    async fn test_bandwidth_limiter() {
+        // 1000 bytes per second
+        let rate = Some(60_000);
+        let mut limiter = BandwidthLimiter::new();
+        assert_eq!(limiter.ready_at(None), None);
+        assert_eq!(limiter.ready_at(rate), None);
+
+        // a large message is let through, and paid off afterwards
+        limiter.consume(1100, rate);
+        let ready_at = limiter.ready_at(rate).unwrap();
+        assert!(ready_at <= Instant::now() + Duration::from_millis(100));
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(limiter.ready_at(rate), None);
+
+        // without a cap, nothing is throttled
+        limiter.consume(1000, rate);
+        assert_eq!(limiter.ready_at(None), None);
+    }
+}
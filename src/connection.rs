@@ -1,33 +1,241 @@
 use libp2p::core::{muxing::StreamMuxerEvent, PeerId, StreamMuxer};
 use nym_sphinx::addressing::clients::Recipient;
+use parking_lot::RwLock;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
     pin::Pin,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
     task::{Context, Poll, Waker},
+    time::Duration,
 };
-use tokio::sync::{
-    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-    oneshot,
+use tokio::{
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot, Semaphore,
+    },
+    time::Instant,
 };
-use tracing::debug;
+use tracing::{debug, warn};
 
+use crate::backend::PacketSize;
+use crate::budget::{BufferKind, MemoryBudget, Reservation};
+use crate::config::RateLimiter;
+use crate::dial::DialOptions;
 use crate::error::Error;
+use crate::events::{ConnectionEvent, ConnectionEventSender, NegotiatedFeature};
 use crate::message::{
-    ConnectionId, Message, OutboundMessage, SubstreamId, SubstreamMessage, SubstreamMessageType,
-    TransportMessage,
+    ConnectionId, Message, MessageAge, MessagePriority, OutboundMessage, ResetCode, Route,
+    ShutdownReason, SubstreamId, SubstreamMessage, SubstreamMessageType, TransportMessage,
+    PROTOCOL_VERSION,
 };
+use crate::mixnet::OutboundSender;
+use crate::spec::extension;
+use crate::stats::{ConnectionTraffic, TransportStats};
 use crate::substream::Substream;
 
+/// how far ahead of its receive time the send time of inbound data may be, after clock
+/// correction, before it's taken to be wrong.
+const MAX_SENT_AT_LEAD: Duration = Duration::from_secs(60);
+
+/// how many wrong send times in a row downgrade the latency extension.
+const MAX_IMPLAUSIBLE_SENT_AT: u32 = 3;
+
+/// SharedRecipient is the remote Nym address of a connection. It's shared between the
+/// connection, its substreams and the transport, so that the transport can redirect
+/// traffic when the remote peer migrates to a new address. Along with the address, it
+/// holds the remote peer's reference to the connection, if the connection uses the compact
+/// ID extension, and how the connection's messages are routed. The listener of an anonymous
+/// connection doesn't know the dialer's address, so its own stands in for it.
+#[derive(Clone, Debug)]
+pub(crate) struct SharedRecipient {
+    recipient: Arc<RwLock<Recipient>>,
+    connection_ref: Option<u64>,
+    route: Route,
+}
+
+impl SharedRecipient {
+    pub(crate) fn new(recipient: Recipient) -> Self {
+        SharedRecipient {
+            recipient: Arc::new(RwLock::new(recipient)),
+            connection_ref: None,
+            route: Route::Direct,
+        }
+    }
+
+    pub(crate) fn get(&self) -> Recipient {
+        *self.recipient.read()
+    }
+
+    pub(crate) fn set(&self, recipient: Recipient) {
+        *self.recipient.write() = recipient;
+    }
+
+    pub(crate) fn connection_ref(&self) -> Option<u64> {
+        self.connection_ref
+    }
+
+    pub(crate) fn route(&self) -> Route {
+        self.route
+    }
+}
+
+/// ConnectionHandle is the transport's side of an established connection.
+pub(crate) struct ConnectionHandle {
+    pub(crate) peer_id: PeerId,
+
+    /// sends messages received from the mixnet to the corresponding Connection
+    pub(crate) inbound_tx: UnboundedSender<SubstreamMessage>,
+
+    pub(crate) remote_recipient: SharedRecipient,
+
+    /// the extensions the connection uses
+    pub(crate) extensions: u8,
+
+    /// epoch of the last address update received from the remote peer
+    pub(crate) remote_address_epoch: u64,
+
+    pub(crate) established_at: Instant,
+
+    /// set once a protocol is negotiated on any of the connection's substreams
+    pub(crate) negotiated: Arc<AtomicBool>,
+
+    /// latency probes sent on the connection, and the ones answered
+    pub(crate) probes_sent: u64,
+    pub(crate) probes_answered: u64,
+    pub(crate) pending_probes: PendingPings,
+
+    /// keepalive pings sent since the remote peer last answered one
+    pub(crate) unanswered_keepalives: u32,
+    pub(crate) pending_keepalives: PendingPings,
+
+    /// set when the connection is dropped because keepalives went unanswered
+    pub(crate) keepalive_expired: Arc<AtomicBool>,
+
+    /// the substream data of the connection since its stats were last reported, when
+    /// they were, and the retransmits received by then
+    pub(crate) traffic: ConnectionTraffic,
+    pub(crate) stats_reported_at: Instant,
+    pub(crate) reported_retransmits: u64,
+}
+
+impl ConnectionHandle {
+    /// deliver passes a message received from the mixnet on to the connection.
+    pub(crate) fn deliver(&self, message: &SubstreamMessage) -> Result<(), Error> {
+        if let SubstreamMessageType::Data(data) = &message.message_type {
+            self.traffic.record_received(data.len());
+        }
+        self.inbound_tx
+            .send(message.clone())
+            .map_err(|e| Error::InboundSendError(e.to_string()))
+    }
+}
+
+/// MAX_PENDING_PINGS is how many pings of each kind are remembered until they're answered;
+/// older ones are taken as lost.
+const MAX_PENDING_PINGS: usize = 16;
+
+/// PendingPings are the send times of the pings of one kind, eg. latency probes, that
+/// weren't answered yet. Pongs echo the send time of their ping, which tells the kinds
+/// apart.
+#[derive(Debug, Default)]
+pub(crate) struct PendingPings(VecDeque<u64>);
+
+impl PendingPings {
+    pub(crate) fn push(&mut self, sent_at: u64) {
+        if self.0.len() == MAX_PENDING_PINGS {
+            self.0.pop_front();
+        }
+        self.0.push_back(sent_at);
+    }
+
+    /// answer returns whether a pong echoing `ping_sent_at` answers one of the pings,
+    /// which is forgotten then.
+    pub(crate) fn answer(&mut self, ping_sent_at: u64) -> bool {
+        let Some(index) = self.0.iter().position(|sent_at| *sent_at == ping_sent_at) else {
+            return false;
+        };
+        self.0.remove(index);
+        true
+    }
+}
+
+/// NegotiatedParams are the parameters a connection runs with, for debugging interop
+/// problems between peers.
+/// Apart from the extensions, nothing is negotiated yet: every peer uses the same
+/// parameters, so they only tell which protocol version and features a connection has.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NegotiatedParams {
+    pub protocol_version: u8,
+    /// whether payloads are compressed
+    pub compression: bool,
+    /// whether messages are delivered to substreams in the order they were sent
+    pub ordered_delivery: bool,
+    /// whether messages lost in the mixnet are sent again
+    pub retransmission: bool,
+    /// whether payloads are encrypted end-to-end, beyond the mixnet's own encryption
+    pub encryption: bool,
+    /// how many messages can be sent before the remote peer acknowledges them, if limited
+    pub flow_control_window: Option<usize>,
+    /// whether substream data is stamped with its send time, see
+    /// [`NymTransport::with_latency_extension`](crate::transport::NymTransport::with_latency_extension)
+    pub latency_extension: bool,
+    /// whether messages refer to the connection by a short reference instead of its ID,
+    /// see [`NymTransport::with_compact_connection_ids`](crate::transport::NymTransport::with_compact_connection_ids)
+    pub compact_connection_ids: bool,
+    /// the name and version of the remote peer's implementation, if it announced them,
+    /// see [`NymTransport::with_user_agent`](crate::transport::NymTransport::with_user_agent)
+    pub remote_user_agent: Option<String>,
+}
+
+impl Default for NegotiatedParams {
+    fn default() -> Self {
+        NegotiatedParams {
+            protocol_version: PROTOCOL_VERSION,
+            compression: false,
+            ordered_delivery: true,
+            retransmission: false,
+            encryption: false,
+            flow_control_window: None,
+            latency_extension: false,
+            compact_connection_ids: false,
+            remote_user_agent: None,
+        }
+    }
+}
+
+impl fmt::Display for NegotiatedParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let window = self
+            .flow_control_window
+            .map(|window| window.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        write!(
+            f,
+            "version={} compression={} ordered={} retransmission={} encryption={} window={} \
+             latency={} compact_ids={} agent={}",
+            self.protocol_version,
+            self.compression,
+            self.ordered_delivery,
+            self.retransmission,
+            self.encryption,
+            window,
+            self.latency_extension,
+            self.compact_connection_ids,
+            self.remote_user_agent.as_deref().unwrap_or("-"),
+        )
+    }
+}
+
 /// Connection represents the result of a connection setup process.
 /// It implements `StreamMuxer` and thus has stream multiplexing built in.
 #[derive(Debug)]
 pub struct Connection {
     pub(crate) peer_id: PeerId,
-    pub(crate) remote_recipient: Recipient,
+    pub(crate) remote_recipient: SharedRecipient,
     pub(crate) id: ConnectionId,
 
     /// receive inbound messages from the `InnerConnection`
@@ -38,15 +246,25 @@ pub struct Connection {
     pending_substreams: HashSet<SubstreamId>,
 
     /// substream ID -> substream's inbound_tx channel
-    substream_inbound_txs: HashMap<SubstreamId, UnboundedSender<Vec<u8>>>,
+    substream_inbound_txs:
+        HashMap<SubstreamId, UnboundedSender<(Vec<u8>, Option<MessageAge>, Reservation)>>,
 
-    /// substream ID -> substream's close_tx channel
-    substream_close_txs: HashMap<SubstreamId, oneshot::Sender<()>>,
+    /// substream ID -> substream's close_tx channel; a reset sends its code
+    substream_close_txs: HashMap<SubstreamId, oneshot::Sender<Option<ResetCode>>>,
+
+    /// limits on the substreams the remote peer opens; opens beyond them are reset
+    max_substreams: Option<usize>,
+    max_substream_opens_per_sec: Option<u32>,
+    substream_open_limiter: RateLimiter,
+
+    /// whether substream messages of unknown types fail the connection rather than being
+    /// skipped
+    strict_message_types: bool,
 
     /// send messages to the mixnet
     /// used for sending `SubstreamMessageType::OpenRequest` messages
     /// also passed to each substream so they can write to the mixnet
-    pub(crate) mixnet_outbound_tx: UnboundedSender<OutboundMessage>,
+    pub(crate) mixnet_outbound_tx: OutboundSender,
 
     /// inbound substream open requests; used in poll_inbound
     inbound_open_tx: UnboundedSender<Substream>,
@@ -61,6 +279,66 @@ pub struct Connection {
     pub(crate) message_nonce: Arc<AtomicU64>,
 
     waker: Option<Waker>,
+
+    /// where substreams record their traffic, if anywhere
+    stats: Option<TransportStats>,
+
+    /// the substream data of the connection since the transport last reported it
+    pub(crate) traffic: ConnectionTraffic,
+
+    /// what the data buffered by substreams counts towards
+    memory_budget: MemoryBudget,
+
+    /// the most data a substream write sends in one message, if limited
+    max_message_size: Option<usize>,
+
+    /// bounds the substream writes waiting to be written to the mixnet, if set
+    outbound_capacity: Option<Arc<Semaphore>>,
+
+    negotiated: NegotiatedParams,
+
+    /// priority and packet size of the connection's substreams, as set by the dial options
+    substream_priority: MessagePriority,
+    substream_packet_size: Option<PacketSize>,
+
+    /// opaque data the dialer attached to the connection request, if any
+    handshake_payload: Option<Vec<u8>>,
+
+    /// offset of the remote peer's clock that message ages are corrected by, if any
+    clock_offset_micros: Option<i64>,
+
+    /// whether substreams stamp the data written to them with its send time; shared with
+    /// them, so it can be turned off if the latency extension is downgraded
+    stamp_sent_at: Arc<AtomicBool>,
+
+    /// inbound send times in a row that can't be right, see check_sent_at
+    implausible_sent_at: u32,
+
+    /// where downgrades of the connection's features are reported, if anywhere
+    events: Option<ConnectionEventSender>,
+
+    /// set once a protocol is negotiated on any substream, ie. the connection has
+    /// finished upgrading
+    pub(crate) upgraded: Arc<AtomicBool>,
+
+    /// set by the transport if it dropped the connection because the remote peer stopped
+    /// answering keepalives
+    pub(crate) keepalive_expired: Arc<AtomicBool>,
+
+    /// tells the transport the connection was dropped, and why, if set
+    closed_tx: Option<UnboundedSender<(ConnectionId, ShutdownReason)>>,
+
+    /// what the remote peer is told once the connection is dropped
+    close_reason: ShutdownReason,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(closed_tx) = self.closed_tx.take() {
+            // the transport might be gone already, which is fine
+            closed_tx.send((self.id.clone(), self.close_reason)).ok();
+        }
+    }
 }
 
 impl Connection {
@@ -69,19 +347,23 @@ impl Connection {
         remote_recipient: Recipient,
         id: ConnectionId,
         inbound_rx: UnboundedReceiver<SubstreamMessage>,
-        mixnet_outbound_tx: UnboundedSender<OutboundMessage>,
+        mixnet_outbound_tx: OutboundSender,
     ) -> Self {
         let (inbound_open_tx, inbound_open_rx) = unbounded_channel();
         let (close_tx, close_rx) = unbounded_channel();
 
         Connection {
             peer_id,
-            remote_recipient,
+            remote_recipient: SharedRecipient::new(remote_recipient),
             id,
             inbound_rx,
             pending_substreams: HashSet::new(),
             substream_inbound_txs: HashMap::new(),
             substream_close_txs: HashMap::new(),
+            max_substreams: None,
+            max_substream_opens_per_sec: None,
+            substream_open_limiter: RateLimiter::new(),
+            strict_message_types: false,
             mixnet_outbound_tx,
             inbound_open_tx,
             inbound_open_rx,
@@ -89,27 +371,183 @@ impl Connection {
             close_rx,
             message_nonce: Arc::new(AtomicU64::new(1)),
             waker: None,
+            stats: None,
+            traffic: ConnectionTraffic::default(),
+            memory_budget: MemoryBudget::default(),
+            max_message_size: None,
+            outbound_capacity: None,
+            negotiated: NegotiatedParams::default(),
+            substream_priority: MessagePriority::default(),
+            substream_packet_size: None,
+            handshake_payload: None,
+            clock_offset_micros: None,
+            stamp_sent_at: Arc::new(AtomicBool::new(false)),
+            implausible_sent_at: 0,
+            events: None,
+            upgraded: Arc::new(AtomicBool::new(false)),
+            keepalive_expired: Arc::new(AtomicBool::new(false)),
+            closed_tx: None,
+            close_reason: ShutdownReason::Shutdown,
         }
     }
 
+    /// with_close_notify sends the connection's ID and close reason to the given channel
+    /// once it's dropped.
+    pub(crate) fn with_close_notify(
+        mut self,
+        closed_tx: UnboundedSender<(ConnectionId, ShutdownReason)>,
+    ) -> Self {
+        self.closed_tx = Some(closed_tx);
+        self
+    }
+
+    /// set_close_reason sets the reason the remote peer is told once the connection is
+    /// dropped, [`ShutdownReason::Shutdown`] by default. It's reported in the remote
+    /// peer's [`ConnectionEvent::Closed`](crate::events::ConnectionEvent::Closed) event.
+    pub fn set_close_reason(&mut self, reason: ShutdownReason) {
+        self.close_reason = reason;
+    }
+
+    /// with_dial_options applies the substream options of the dial the connection
+    /// resulted from.
+    pub(crate) fn with_dial_options(mut self, options: &DialOptions) -> Self {
+        self.substream_priority = options.priority;
+        self.substream_packet_size = options.packet_size;
+        self
+    }
+
+    /// with_handshake_payload sets the payload the dialer attached to the connection
+    /// request.
+    pub(crate) fn with_handshake_payload(mut self, payload: Option<Vec<u8>>) -> Self {
+        self.handshake_payload = payload;
+        self
+    }
+
+    /// handshake_payload returns the opaque data the dialer attached to the connection
+    /// request with [`DialOptions::with_handshake_payload`], if we're the listener.
+    pub fn handshake_payload(&self) -> Option<&[u8]> {
+        self.handshake_payload.as_deref()
+    }
+
+    /// with_clock_offset corrects the send times of inbound data by the given offset of the
+    /// remote peer's clock, in microseconds.
+    pub(crate) fn with_clock_offset(mut self, offset_micros: Option<i64>) -> Self {
+        self.clock_offset_micros = offset_micros;
+        self
+    }
+
+    /// with_extensions sets the extensions negotiated in the handshake.
+    pub(crate) fn with_extensions(mut self, extensions: u8) -> Self {
+        self.negotiated.latency_extension = extensions & extension::LATENCY != 0;
+        self.negotiated.compact_connection_ids = extensions & extension::COMPACT_ID != 0;
+        self.stamp_sent_at
+            .store(self.negotiated.latency_extension, Ordering::Relaxed);
+        self
+    }
+
+    /// with_events reports downgrades of the connection's features to the given sender.
+    pub(crate) fn with_events(mut self, events: ConnectionEventSender) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// with_remote_user_agent sets the user agent the remote peer announced in the
+    /// handshake.
+    pub(crate) fn with_remote_user_agent(mut self, user_agent: Option<String>) -> Self {
+        self.negotiated.remote_user_agent = user_agent;
+        self
+    }
+
+    /// with_remote_connection_ref sends the connection's messages with the remote peer's
+    /// reference to it instead of its ID, if it has one. It's only called before any
+    /// substreams are opened, which copy the reference.
+    pub(crate) fn with_remote_connection_ref(mut self, connection_ref: Option<u64>) -> Self {
+        self.remote_recipient.connection_ref = connection_ref;
+        self
+    }
+
+    /// with_route sends the connection's messages the given way, eg. as replies to an
+    /// anonymous dialer. Like with_remote_connection_ref, it's only called before any
+    /// substreams are opened.
+    pub(crate) fn with_route(mut self, route: Route) -> Self {
+        self.remote_recipient.route = route;
+        self
+    }
+
+    /// negotiated returns the parameters the connection runs with.
+    pub fn negotiated(&self) -> &NegotiatedParams {
+        &self.negotiated
+    }
+
+    /// with_stats records the traffic of the connection's substreams in the given stats.
+    pub(crate) fn with_stats(mut self, stats: TransportStats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// with_substream_limits resets substreams the remote peer opens while the connection
+    /// has the given number of substreams open, or faster than the given rate.
+    pub(crate) fn with_substream_limits(
+        mut self,
+        max_substreams: Option<usize>,
+        max_opens_per_sec: Option<u32>,
+    ) -> Self {
+        self.max_substreams = max_substreams;
+        self.max_substream_opens_per_sec = max_opens_per_sec;
+        self
+    }
+
+    /// with_strict_message_types fails the connection with a protocol error when the
+    /// remote peer sends a substream message of a type we don't know, if `strict`, rather
+    /// than skipping it.
+    pub(crate) fn with_strict_message_types(mut self, strict: bool) -> Self {
+        self.strict_message_types = strict;
+        self
+    }
+
+    /// with_memory_budget counts the data buffered by the connection's substreams towards
+    /// the given budget, and applies backpressure to writes while it's under pressure.
+    pub(crate) fn with_memory_budget(mut self, memory_budget: MemoryBudget) -> Self {
+        self.memory_budget = memory_budget;
+        self
+    }
+
+    /// with_max_message_size limits how much data a write to the connection's substreams
+    /// sends in one message.
+    pub(crate) fn with_max_message_size(mut self, max_message_size: Option<usize>) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// with_outbound_capacity makes writes to the connection's substreams wait for one of
+    /// the capacity's permits, which is held until the data is written to the mixnet.
+    pub(crate) fn with_outbound_capacity(mut self, capacity: Arc<Semaphore>) -> Self {
+        self.outbound_capacity = Some(capacity);
+        self
+    }
+
     fn new_outbound_substream(&mut self) -> Result<Substream, Error> {
         let substream_id = SubstreamId::generate();
         let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
 
         // send the substream open request that requests to open a substream with the given ID
         self.mixnet_outbound_tx
-            .send(OutboundMessage {
-                recipient: self.remote_recipient,
-                message: Message::TransportMessage(TransportMessage {
-                    nonce,
-                    id: self.id.clone(),
-                    message: SubstreamMessage {
-                        substream_id: substream_id.clone(),
-                        message_type: SubstreamMessageType::OpenRequest,
-                    },
-                }),
-            })
-            .map_err(|e| Error::OutboundSendError(e.to_string()))?;
+            .send(
+                OutboundMessage::new(
+                    Message::TransportMessage(TransportMessage {
+                        nonce,
+                        id: self.id.clone(),
+                        message: SubstreamMessage::new(
+                            substream_id.clone(),
+                            SubstreamMessageType::OpenRequest,
+                        ),
+                    }),
+                    self.remote_recipient.get(),
+                )
+                .with_connection_ref(self.remote_recipient.connection_ref())
+                .with_route(self.remote_recipient.route()),
+            )
+            .map_err(Error::from)?;
 
         // track pending outbound substreams
         // TODO we should probably lock this? storing map values should be atomic
@@ -127,8 +565,8 @@ impl Connection {
             return Err(Error::SubstreamIdExists(id));
         }
 
-        let (inbound_tx, inbound_rx) = unbounded_channel::<Vec<u8>>();
-        let (close_tx, close_rx) = oneshot::channel::<()>();
+        let (inbound_tx, inbound_rx) = unbounded_channel();
+        let (close_tx, close_rx) = oneshot::channel::<Option<ResetCode>>();
         self.substream_inbound_txs.insert(id.clone(), inbound_tx);
         self.substream_close_txs.insert(id.clone(), close_tx);
 
@@ -136,15 +574,80 @@ impl Connection {
             waker.wake();
         }
 
-        Ok(Substream::new(
-            self.remote_recipient,
+        let substream = Substream::new(
+            self.remote_recipient.clone(),
             self.id.clone(),
             id,
             inbound_rx,
             self.mixnet_outbound_tx.clone(),
             close_rx,
             self.message_nonce.clone(),
-        ))
+        )
+        .with_negotiated_flag(self.upgraded.clone())
+        .with_sent_at_stamps(self.stamp_sent_at.clone())
+        .with_memory_budget(self.memory_budget.clone())
+        .with_max_message_size(self.max_message_size)
+        .with_outbound_capacity(self.outbound_capacity.clone())
+        .with_traffic(self.traffic.clone());
+        substream.set_priority(self.substream_priority);
+        if let Some(packet_size) = self.substream_packet_size {
+            substream.set_packet_size(packet_size);
+        }
+        Ok(match &self.stats {
+            Some(stats) => substream.with_stats(stats.clone(), self.peer_id),
+            None => substream,
+        })
+    }
+
+    /// check_sent_at drops the send time from the age of inbound data if the latency
+    /// extension was downgraded, or if the send time is too far ahead of the receive time
+    /// to be right. Too many of those in a row downgrade the extension.
+    fn check_sent_at(&mut self, mut age: MessageAge) -> MessageAge {
+        let Some(sent_at) = age.sent_at else {
+            return age;
+        };
+        if !self.negotiated.latency_extension {
+            age.sent_at = None;
+            return age;
+        }
+        match sent_at.duration_since(age.received_at) {
+            Ok(lead) if lead > MAX_SENT_AT_LEAD => {
+                age.sent_at = None;
+                self.implausible_sent_at += 1;
+                if self.implausible_sent_at >= MAX_IMPLAUSIBLE_SENT_AT {
+                    let reason = format!(
+                        "{} send times in a row ahead of their receive times by over {:?}",
+                        self.implausible_sent_at, MAX_SENT_AT_LEAD
+                    );
+                    self.downgrade(NegotiatedFeature::LatencyExtension, reason);
+                }
+            }
+            _ => self.implausible_sent_at = 0,
+        }
+        age
+    }
+
+    /// downgrade turns the given feature off for the rest of the connection, rather than
+    /// closing it, and reports that. The remote peer isn't told; it parses messages with
+    /// and without the feature either way.
+    fn downgrade(&mut self, feature: NegotiatedFeature, reason: String) {
+        match feature {
+            NegotiatedFeature::LatencyExtension => {
+                self.negotiated.latency_extension = false;
+                self.stamp_sent_at.store(false, Ordering::Relaxed);
+            }
+        }
+        warn!(
+            "downgrading {:?} on connection {:?} with {}: {}",
+            feature, self.id, self.peer_id, reason
+        );
+        if let Some(events) = &self.events {
+            events.send(ConnectionEvent::Downgraded {
+                peer_id: self.peer_id,
+                feature,
+                reason,
+            });
+        }
     }
 
     fn handle_close(&mut self, substream_id: SubstreamId) -> Result<(), Error> {
@@ -154,13 +657,73 @@ impl Connection {
 
         // notify substream that it's closed
         let close_tx = self.substream_close_txs.remove(&substream_id);
-        close_tx.unwrap().send(()).unwrap();
+        close_tx.unwrap().send(None).unwrap();
 
         // notify poll_close that the substream is closed
         self.close_tx
             .send(substream_id)
             .map_err(|e| Error::InboundSendError(e.to_string()))
     }
+
+    /// accepts_substream returns false if the remote peer may not open another substream,
+    /// because the connection has as many open as it allows or they're opened too fast.
+    fn accepts_substream(&mut self) -> bool {
+        if let Some(max_substreams) = self.max_substreams {
+            // substreams the application dropped without the remote peer closing them
+            // don't count
+            let open = self
+                .substream_inbound_txs
+                .values()
+                .filter(|inbound_tx| !inbound_tx.is_closed())
+                .count();
+            if open >= max_substreams {
+                return false;
+            }
+        }
+        self.substream_open_limiter
+            .try_acquire(self.max_substream_opens_per_sec)
+    }
+
+    /// reset_substream declines the remote peer's request to open the substream.
+    fn reset_substream(&mut self, substream_id: SubstreamId, code: ResetCode) -> Result<(), Error> {
+        debug!("resetting substream {:?}: {}", &substream_id, code);
+        if let Some(stats) = &self.stats {
+            stats.record_substream_reset();
+        }
+        let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
+        self.mixnet_outbound_tx
+            .send(
+                OutboundMessage::new(
+                    Message::TransportMessage(TransportMessage {
+                        nonce,
+                        id: self.id.clone(),
+                        message: SubstreamMessage::new(
+                            substream_id,
+                            SubstreamMessageType::Reset(code),
+                        ),
+                    }),
+                    self.remote_recipient.get(),
+                )
+                .with_connection_ref(self.remote_recipient.connection_ref())
+                .with_route(self.remote_recipient.route()),
+            )
+            .map_err(Error::from)
+    }
+
+    /// handle_reset closes a substream the remote peer declined to open. Unlike a close,
+    /// it isn't reported to poll_close.
+    fn handle_reset(&mut self, substream_id: SubstreamId, code: ResetCode) {
+        debug!(
+            "substream {:?} reset by the remote peer: {}",
+            &substream_id, code
+        );
+        self.pending_substreams.remove(&substream_id);
+        self.substream_inbound_txs.remove(&substream_id);
+        if let Some(close_tx) = self.substream_close_txs.remove(&substream_id) {
+            // the substream might have been dropped already
+            close_tx.send(Some(code)).ok();
+        }
+    }
 }
 
 impl StreamMuxer for Connection {
@@ -197,27 +760,57 @@ impl StreamMuxer for Connection {
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
-        while let Poll::Ready(Some(msg)) = self.inbound_rx.poll_recv(cx) {
+        loop {
+            let msg = match self.inbound_rx.poll_recv(cx) {
+                Poll::Ready(Some(msg)) => msg,
+                // the transport dropped the connection, eg. because it stalled while upgrading
+                Poll::Ready(None) if self.keepalive_expired.load(Ordering::SeqCst) => {
+                    return Poll::Ready(Err(Error::KeepaliveTimeout))
+                }
+                Poll::Ready(None) => return Poll::Ready(Err(Error::ConnectionDropped)),
+                Poll::Pending => break,
+            };
+            let age = match (msg.age(), self.clock_offset_micros) {
+                (Some(age), Some(offset_micros)) => Some(age.corrected(offset_micros)),
+                (age, _) => age,
+            };
+            let age = age.map(|age| self.check_sent_at(age));
             match msg.message_type {
                 SubstreamMessageType::OpenRequest => {
+                    if !self.accepts_substream() {
+                        self.reset_substream(msg.substream_id, ResetCode::TooManyStreams)?;
+                        continue;
+                    }
+
                     // create a new substream with the given ID
-                    let substream = self.new_substream(msg.substream_id.clone())?;
+                    let substream = match self.new_substream(msg.substream_id.clone()) {
+                        Ok(substream) => substream,
+                        Err(e) => {
+                            // the remote peer reused the ID of an open substream
+                            self.close_reason = ShutdownReason::ProtocolError;
+                            return Poll::Ready(Err(e));
+                        }
+                    };
                     let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
 
                     // send the response to the remote peer
                     self.mixnet_outbound_tx
-                        .send(OutboundMessage {
-                            recipient: self.remote_recipient,
-                            message: Message::TransportMessage(TransportMessage {
-                                nonce,
-                                id: self.id.clone(),
-                                message: SubstreamMessage {
-                                    substream_id: msg.substream_id.clone(),
-                                    message_type: SubstreamMessageType::OpenResponse,
-                                },
-                            }),
-                        })
-                        .map_err(|e| Error::OutboundSendError(e.to_string()))?;
+                        .send(
+                            OutboundMessage::new(
+                                Message::TransportMessage(TransportMessage {
+                                    nonce,
+                                    id: self.id.clone(),
+                                    message: SubstreamMessage::new(
+                                        msg.substream_id.clone(),
+                                        SubstreamMessageType::OpenResponse,
+                                    ),
+                                }),
+                                self.remote_recipient.get(),
+                            )
+                            .with_connection_ref(self.remote_recipient.connection_ref())
+                            .with_route(self.remote_recipient.route()),
+                        )
+                        .map_err(Error::from)?;
                     debug!("wrote OpenResponse for substream: {:?}", &msg.substream_id);
 
                     // send the substream to our own channel to be returned in poll_inbound
@@ -236,18 +829,51 @@ impl StreamMuxer for Connection {
                     }
                 }
                 SubstreamMessageType::Close => {
-                    self.handle_close(msg.substream_id)?;
+                    match self.handle_close(msg.substream_id) {
+                        // the substream might have been reset, but the remote peer wrote
+                        // to it and closed it before learning that
+                        Err(Error::SubstreamIdDoesNotExist(substream_id)) => {
+                            debug!(
+                                "SubstreamMessageType::Close for unknown substream: {:?}",
+                                &substream_id
+                            );
+                        }
+                        res => res?,
+                    }
+                }
+                SubstreamMessageType::Reset(code) => {
+                    self.handle_reset(msg.substream_id, code);
+                }
+                SubstreamMessageType::Unknown(op) => {
+                    if let Some(stats) = &self.stats {
+                        stats.record_unknown_message_type();
+                    }
+                    if self.strict_message_types {
+                        warn!("rejecting substream message of unknown type {}", op);
+                        self.close_reason = ShutdownReason::ProtocolError;
+                        return Poll::Ready(Err(Error::InvalidSubstreamMessageType));
+                    }
+                    // eg. from a newer peer; it doesn't concern the substreams we know of
+                    debug!("skipping substream message of unknown type {}", op);
                 }
                 SubstreamMessageType::Data(data) => {
                     debug!("SubstreamMessageType::Data: {:?}", &data);
-                    let inbound_tx = self
-                        .substream_inbound_txs
-                        .get_mut(&msg.substream_id)
-                        .expect("must have a substream channel for substream");
+                    let Some(inbound_tx) = self.substream_inbound_txs.get_mut(&msg.substream_id)
+                    else {
+                        // as with closes, the substream might have been reset
+                        debug!(
+                            "SubstreamMessageType::Data for unknown substream: {:?}",
+                            &msg.substream_id
+                        );
+                        continue;
+                    };
 
                     // NOTE: this ignores channel closed errors, which is fine because the substream
                     // might have been closed/dropped
-                    inbound_tx.send(data).ok();
+                    let reservation = self
+                        .memory_budget
+                        .reserve(BufferKind::Substream, data.len());
+                    inbound_tx.send((data, age, reservation)).ok();
                 }
             }
         }
@@ -260,30 +886,40 @@ impl StreamMuxer for Connection {
 /// PendingConnection represents a connection that's been initiated, but not completed.
 pub(crate) struct PendingConnection {
     pub(crate) remote_recipient: Recipient,
-    pub(crate) connection_tx: oneshot::Sender<Connection>,
+    /// how the connection's messages are routed, once it's established
+    pub(crate) route: Route,
+    /// receives the connection, or the reason the listener denied it
+    pub(crate) connection_tx: oneshot::Sender<Result<Connection, Error>>,
 }
 
 impl PendingConnection {
     pub(crate) fn new(
         remote_recipient: Recipient,
-        connection_tx: oneshot::Sender<Connection>,
+        connection_tx: oneshot::Sender<Result<Connection, Error>>,
     ) -> Self {
         PendingConnection {
             remote_recipient,
+            route: Route::Direct,
             connection_tx,
         }
     }
+
+    pub(crate) fn with_route(mut self, route: Route) -> Self {
+        self.route = route;
+        self
+    }
 }
 
 #[cfg(test)]
 mod test {
     use futures::future::poll_fn;
     use futures::{AsyncReadExt, AsyncWriteExt, FutureExt};
+    use std::time::{SystemTime, UNIX_EPOCH};
     use testcontainers::clients;
 
     use super::*;
     use crate::message::InboundMessage;
-    use crate::mixnet::initialize_mixnet;
+    use crate::mixnet::{initialize_mixnet, outbound_channel};
     use crate::test_utils::create_nym_client;
 
     async fn inbound_receive_and_send(
@@ -419,4 +1055,105 @@ mod test {
         )
         .await;
     }
+
+    #[tokio::test]
+    async fn test_connection_downgrades_latency_extension() {
+        let (inbound_tx, inbound_rx) = unbounded_channel::<SubstreamMessage>();
+        let (outbound_tx, mut outbound_rx) = outbound_channel();
+        let events = ConnectionEventSender::default();
+        let mut events_rx = events.subscribe();
+        let peer_id = PeerId::random();
+        let mut connection = Connection::new(
+            peer_id,
+            crate::backend::mock::random_recipient(),
+            ConnectionId::generate(),
+            inbound_rx,
+            outbound_tx,
+        )
+        .with_extensions(extension::LATENCY)
+        .with_events(events);
+        let mut substream = connection.new_outbound_substream().unwrap();
+        // the open request
+        outbound_rx.recv().await.unwrap();
+
+        let substream_id = substream.substream_id.clone();
+        let inbound_data = |sent_at: SystemTime| {
+            let mut data = SubstreamMessage::new_with_data(substream_id.clone(), b"hello".to_vec());
+            data.sent_at = Some(sent_at.duration_since(UNIX_EPOCH).unwrap().as_micros() as u64);
+            data.received_at = Some(SystemTime::now());
+            data
+        };
+        let mut buf = [0u8; 5];
+
+        // a plausible send time is kept
+        inbound_tx.send(inbound_data(SystemTime::now())).unwrap();
+        poll_fn(|cx| Pin::new(&mut connection).poll(cx)).now_or_never();
+        substream.read_exact(&mut buf).await.unwrap();
+        assert!(substream.last_read_age().unwrap().sent_at.is_some());
+
+        // send times from the future are dropped, and enough of them in a row turn the
+        // extension off
+        let future = SystemTime::now() + Duration::from_secs(3600);
+        for _ in 0..MAX_IMPLAUSIBLE_SENT_AT {
+            assert!(connection.negotiated().latency_extension);
+            inbound_tx.send(inbound_data(future)).unwrap();
+            poll_fn(|cx| Pin::new(&mut connection).poll(cx)).now_or_never();
+            substream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(substream.last_read_age().unwrap().sent_at, None);
+        }
+        assert!(!connection.negotiated().latency_extension);
+        assert!(matches!(
+            events_rx.try_recv().unwrap(),
+            ConnectionEvent::Downgraded {
+                peer_id: downgraded,
+                feature: NegotiatedFeature::LatencyExtension,
+                ..
+            } if downgraded == peer_id
+        ));
+
+        // the connection carries on without it, in both directions
+        inbound_tx.send(inbound_data(SystemTime::now())).unwrap();
+        poll_fn(|cx| Pin::new(&mut connection).poll(cx)).now_or_never();
+        substream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(substream.last_read_age().unwrap().sent_at, None);
+        substream.write_all(b"hello").await.unwrap();
+        let Message::TransportMessage(msg) = outbound_rx.recv().await.unwrap().message else {
+            panic!("expected a transport message");
+        };
+        assert_eq!(msg.message.sent_at, None);
+    }
+
+    #[test]
+    fn test_connection_unknown_substream_message_type() {
+        for strict in [false, true] {
+            let (inbound_tx, inbound_rx) = unbounded_channel::<SubstreamMessage>();
+            let (outbound_tx, _outbound_rx) = outbound_channel();
+            let stats = TransportStats::default();
+            let mut connection = Connection::new(
+                PeerId::random(),
+                crate::backend::mock::random_recipient(),
+                ConnectionId::generate(),
+                inbound_rx,
+                outbound_tx,
+            )
+            .with_stats(stats.clone())
+            .with_strict_message_types(strict);
+
+            inbound_tx
+                .send(SubstreamMessage::new(
+                    SubstreamId::generate(),
+                    SubstreamMessageType::Unknown(0x7f),
+                ))
+                .unwrap();
+            let res = poll_fn(|cx| Pin::new(&mut connection).poll(cx)).now_or_never();
+            assert_eq!(stats.unknown_message_types(), 1);
+            if strict {
+                assert!(matches!(res, Some(Err(Error::InvalidSubstreamMessageType))));
+                assert_eq!(connection.close_reason, ShutdownReason::ProtocolError);
+            } else {
+                // skipped, and the connection carries on
+                assert!(res.is_none());
+            }
+        }
+    }
 }
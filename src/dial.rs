@@ -0,0 +1,347 @@
+use futures::{
+    future::{self, BoxFuture},
+    task::noop_waker,
+    FutureExt,
+};
+use libp2p::core::{multiaddr::Protocol, Multiaddr};
+use parking_lot::RwLock;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::sync::{mpsc::UnboundedSender, watch, OwnedSemaphorePermit, Semaphore};
+
+use crate::backend::PacketSize;
+use crate::error::Error;
+use crate::message::MessagePriority;
+
+/// MAX_HANDSHAKE_PAYLOAD_LEN is the longest handshake payload a dial can carry.
+pub const MAX_HANDSHAKE_PAYLOAD_LEN: usize = u16::MAX as usize;
+
+/// DialOptions are Nym-specific options for dialing a peer. libp2p's `DialOpts` can't carry
+/// them to the transport, so they're set per address through a [`DialOptionsHandle`] and
+/// picked up when the swarm dials that address.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DialOptions {
+    /// priority of the connection request and of the connection's substreams
+    pub priority: MessagePriority,
+    /// packet size of the connection request and of the connection's substreams, if not
+    /// the transport's
+    pub packet_size: Option<PacketSize>,
+    /// opaque bytes sent along with the connection request, available to the listener
+    /// through `Connection::handshake_payload`
+    pub handshake_payload: Option<Vec<u8>>,
+    /// if set, the dial is anonymous: our Nym address is left out of the connection
+    /// request, and every message on the connection carries this many reply SURBs for the
+    /// listener to answer with instead
+    pub reply_surbs: Option<u32>,
+    /// if set, dials report the phases they go through to it
+    pub progress: Option<DialProgress>,
+}
+
+impl DialOptions {
+    /// with_priority sets the priority of the connection request and the connection's
+    /// substreams, and returns self.
+    pub fn with_priority(mut self, priority: MessagePriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// with_packet_size sets the packet size of the connection request and the
+    /// connection's substreams, and returns self.
+    pub fn with_packet_size(mut self, packet_size: PacketSize) -> Self {
+        self.packet_size = Some(packet_size);
+        self
+    }
+
+    /// with_handshake_payload attaches the payload to the connection request, and returns
+    /// self. Listeners from before handshake payloads existed reject requests carrying
+    /// one.
+    pub fn with_handshake_payload(mut self, payload: Vec<u8>) -> Result<Self, Error> {
+        if payload.len() > MAX_HANDSHAKE_PAYLOAD_LEN {
+            return Err(Error::InvalidConfig("handshake payload is too long"));
+        }
+        self.handshake_payload = Some(payload);
+        Ok(self)
+    }
+
+    /// with_anonymous makes the dial anonymous, hiding our Nym address from the listener
+    /// behind `reply_surbs` reply SURBs sent along with every message, and returns self.
+    /// Our peer ID is still revealed by the handshake.
+    pub fn with_anonymous(mut self, reply_surbs: u32) -> Self {
+        self.reply_surbs = Some(reply_surbs);
+        self
+    }
+
+    /// with_progress makes dials report the phases they go through to `progress`, and
+    /// returns self.
+    pub fn with_progress(mut self, progress: DialProgress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// report tells the dial's progress receiver, if any, that it reached the phase.
+    pub(crate) fn report(&self, phase: DialPhase) {
+        if let Some(progress) = &self.progress {
+            progress.phase_tx.send_replace(phase);
+        }
+    }
+}
+
+/// DialPhase is how far a dial got. A handshake takes a round trip through the mixnet, so
+/// a dial may spend seconds awaiting the response.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DialPhase {
+    /// waiting for a dial slot or for the connection to the mixnet, eg. while it's
+    /// reconnecting
+    #[default]
+    Queued,
+    /// the connection request is queued to be written to the mixnet
+    SentRequest,
+    /// the connection request was handed to the Nym client, and the listener's response
+    /// is awaited
+    AwaitingResponse,
+    /// the listener accepted, and the connection is being set up
+    Upgrading,
+    /// the connection was established
+    Established,
+    /// the dial failed, with the error its future returned
+    Failed,
+}
+
+/// DialProgress reports the [`DialPhase`]s of dials to the receiver returned along with
+/// it, eg. to show a status while connecting over the mixnet. Set it with
+/// [`DialOptions::with_progress`]; if several dials use it, the receiver sees the phases
+/// of the latest. Dropping the dial future cancels the dial.
+#[derive(Clone, Debug)]
+pub struct DialProgress {
+    phase_tx: Arc<watch::Sender<DialPhase>>,
+}
+
+impl DialProgress {
+    pub fn new() -> (Self, watch::Receiver<DialPhase>) {
+        let (phase_tx, phase_rx) = watch::channel(DialPhase::default());
+        let progress = DialProgress {
+            phase_tx: Arc::new(phase_tx),
+        };
+        (progress, phase_rx)
+    }
+}
+
+// options are compared by whether they report to the same receiver
+impl PartialEq for DialProgress {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.phase_tx, &other.phase_tx)
+    }
+}
+
+/// DialOptionsHandle sets the [`DialOptions`] used when dialing an address. It can be
+/// cloned and kept around after the transport is moved into a swarm.
+#[derive(Clone, Debug, Default)]
+pub struct DialOptionsHandle {
+    options: Arc<RwLock<HashMap<Multiaddr, DialOptions>>>,
+}
+
+impl DialOptionsHandle {
+    /// set uses the options for dials to the address from now on, until they're removed.
+    /// If the address doesn't end in a /p2p/ component, they're also used for dials to it
+    /// with one, as the swarm appends the peer ID when dialing a known peer.
+    pub fn set(&self, address: Multiaddr, options: DialOptions) {
+        self.options.write().insert(address, options);
+    }
+
+    /// remove stops using options for dials to the address, and returns them.
+    pub fn remove(&self, address: &Multiaddr) -> Option<DialOptions> {
+        self.options.write().remove(address)
+    }
+
+    /// get returns the options for a dial to the address, if any.
+    pub(crate) fn get(&self, address: &Multiaddr) -> Option<DialOptions> {
+        let options = self.options.read();
+        if let Some(found) = options.get(address) {
+            return Some(found.clone());
+        }
+        without_peer_id(address).and_then(|address| options.get(&address).cloned())
+    }
+}
+
+/// PreconnectHandle asks the transport to [preconnect](crate::transport::NymTransport::preconnect)
+/// to an address. It can be cloned and kept around after the transport is moved into a swarm.
+#[derive(Clone, Debug)]
+pub struct PreconnectHandle {
+    preconnect_tx: UnboundedSender<Multiaddr>,
+}
+
+impl PreconnectHandle {
+    pub(crate) fn new(preconnect_tx: UnboundedSender<Multiaddr>) -> Self {
+        Self { preconnect_tx }
+    }
+
+    /// preconnect performs the handshake with the peer at the address the next time the
+    /// transport is polled, and keeps the connection for the next dial to that address.
+    pub fn preconnect(&self, address: Multiaddr) -> Result<(), Error> {
+        self.preconnect_tx
+            .send(address)
+            .map_err(|e| Error::OutboundSendError(e.to_string()))
+    }
+}
+
+/// DialSlots caps the dials whose handshakes are in flight at once, so that a burst of
+/// dials doesn't flood the Nym client's queues. Dials beyond the cap wait for a slot in
+/// the order they were made, and fail with [`Error::DialQueueFull`] if too many already
+/// wait.
+#[derive(Clone, Debug)]
+pub(crate) struct DialSlots {
+    slots: Arc<Semaphore>,
+    /// dials waiting for a slot; shared with the transport's stats
+    queued: Arc<AtomicUsize>,
+    max_queued: usize,
+}
+
+impl DialSlots {
+    pub(crate) fn new(max_concurrent: usize, max_queued: usize, queued: Arc<AtomicUsize>) -> Self {
+        DialSlots {
+            slots: Arc::new(Semaphore::new(max_concurrent)),
+            queued,
+            max_queued,
+        }
+    }
+
+    /// join returns the ticket of a new dial to its slot, or fails if it would have to
+    /// wait behind the most dials allowed to. A dial that has to wait takes its place in
+    /// line right away, so dials get their slots in the order they joined, whichever
+    /// order their tickets are waited on in.
+    pub(crate) fn join(&self) -> Result<DialTicket, Error> {
+        // the semaphore hands released permits to waiting dials first, so this doesn't
+        // jump the queue
+        if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+            return Ok(DialTicket {
+                slot: future::ready(permit).boxed(),
+                queued: None,
+            });
+        }
+        self.queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                (queued < self.max_queued).then_some(queued + 1)
+            })
+            .map_err(|_| Error::DialQueueFull)?;
+        // the semaphore is never closed
+        let mut slot = self
+            .slots
+            .clone()
+            .acquire_owned()
+            .map(|permit| permit.unwrap())
+            .boxed();
+        // the semaphore only puts the acquisition in line once it's polled
+        let waker = noop_waker();
+        if let Poll::Ready(permit) = slot.poll_unpin(&mut Context::from_waker(&waker)) {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return Ok(DialTicket {
+                slot: future::ready(permit).boxed(),
+                queued: None,
+            });
+        }
+        Ok(DialTicket {
+            slot,
+            queued: Some(self.queued.clone()),
+        })
+    }
+}
+
+/// DialTicket is a dial's place in line for a slot. Dropping it leaves the line.
+pub(crate) struct DialTicket {
+    slot: BoxFuture<'static, OwnedSemaphorePermit>,
+    /// the count of waiting dials this one is part of, if it has to wait
+    queued: Option<Arc<AtomicUsize>>,
+}
+
+impl DialTicket {
+    /// wait returns the dial's slot once it's free, which is held until it's dropped.
+    pub(crate) async fn wait(mut self) -> OwnedSemaphorePermit {
+        (&mut self.slot).await
+    }
+}
+
+impl Drop for DialTicket {
+    fn drop(&mut self) {
+        if let Some(queued) = self.queued.take() {
+            queued.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// without_peer_id returns the address without its trailing /p2p/ component, or None if it
+/// doesn't end in one.
+pub(crate) fn without_peer_id(address: &Multiaddr) -> Option<Multiaddr> {
+    let mut address = address.clone();
+    match address.pop() {
+        Some(Protocol::P2p(_)) => Some(address),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::FutureExt;
+    use libp2p::core::PeerId;
+
+    use super::*;
+    use crate::backend::mock::random_recipient;
+    use crate::transport::nym_address_to_multiaddress;
+
+    #[test]
+    fn test_dial_options_lookup() {
+        let handle = DialOptionsHandle::default();
+        let address = nym_address_to_multiaddress(random_recipient()).unwrap();
+        let options = DialOptions::default().with_priority(MessagePriority::High);
+        handle.set(address.clone(), options.clone());
+
+        assert_eq!(handle.get(&address), Some(options.clone()));
+        let with_peer_id = address.clone().with(Protocol::P2p(PeerId::random().into()));
+        assert_eq!(handle.get(&with_peer_id), Some(options.clone()));
+        let other = nym_address_to_multiaddress(random_recipient()).unwrap();
+        assert_eq!(handle.get(&other), None);
+
+        assert_eq!(handle.remove(&address), Some(options));
+        assert_eq!(handle.get(&with_peer_id), None);
+
+        assert!(DialOptions::default()
+            .with_handshake_payload(vec![0; MAX_HANDSHAKE_PAYLOAD_LEN + 1])
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dial_slots() {
+        let queued = Arc::new(AtomicUsize::new(0));
+        let slots = DialSlots::new(1, 2, queued.clone());
+        let first = slots.join().unwrap().wait().await;
+        let second = slots.join().unwrap();
+        let third = slots.join().unwrap();
+        assert_eq!(queued.load(Ordering::SeqCst), 2);
+        assert!(matches!(slots.join(), Err(Error::DialQueueFull)));
+
+        // the waiting dials get the slot in the order they joined once the first is done,
+        // even if the later one waits first
+        let mut third = Box::pin(third.wait());
+        assert!((&mut third).now_or_never().is_none());
+        let mut second = Box::pin(second.wait());
+        assert!((&mut second).now_or_never().is_none());
+        drop(first);
+        assert!((&mut third).now_or_never().is_none());
+        let second = second.await;
+        assert_eq!(queued.load(Ordering::SeqCst), 1);
+        drop(second);
+        let _third = third.await;
+        assert_eq!(queued.load(Ordering::SeqCst), 0);
+
+        // a dial dropped while waiting leaves the line
+        let third = slots.join().unwrap();
+        assert_eq!(queued.load(Ordering::SeqCst), 1);
+        drop(third);
+        assert_eq!(queued.load(Ordering::SeqCst), 0);
+    }
+}
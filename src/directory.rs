@@ -0,0 +1,618 @@
+use libp2p::core::PeerId;
+use nym_sphinx::addressing::clients::Recipient;
+use parking_lot::Mutex;
+use rand_core::{OsRng, RngCore};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::{mpsc::UnboundedReceiver, oneshot};
+use tracing::debug;
+
+use crate::error::Error;
+use crate::mixnet::MixnetConnection;
+use crate::record::SignedNymAddressRecord;
+use crate::stats::unix_micros;
+
+/// DIRECTORY_PREFIX starts every directory message, so they can share the broadcast
+/// channel with other payloads.
+pub const DIRECTORY_PREFIX: &[u8] = b"libp2p-nym-directory/1";
+
+/// DEFAULT_MAX_RECORDS is how many records a directory keeps by default.
+const DEFAULT_MAX_RECORDS: usize = 10_000;
+
+/// DEFAULT_REQUEST_TIMEOUT is how long a directory client waits for an answer by default;
+/// a request and its answer each take a trip through the mixnet.
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+const NONCE_LEN: usize = 8; // length of u64
+
+/// directory message types, after the prefix
+mod directory_op {
+    pub(super) const REGISTER: u8 = 0;
+    pub(super) const REGISTERED: u8 = 1;
+    pub(super) const LOOKUP: u8 = 2;
+    pub(super) const FOUND: u8 = 3;
+}
+
+/// DirectoryMessage is exchanged between directory nodes and their clients as the payload
+/// of a broadcast, see [`MixnetConnection::broadcast`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum DirectoryMessage {
+    /// asks the directory to keep the record until it expires; answered at the record's
+    /// address
+    Register {
+        nonce: u64,
+        record: SignedNymAddressRecord,
+    },
+    /// whether the directory kept the record
+    Registered { nonce: u64, accepted: bool },
+    /// asks the directory for the record of the peer, to be answered at `reply_to`
+    Lookup {
+        nonce: u64,
+        peer_id: PeerId,
+        reply_to: Recipient,
+    },
+    /// the record of the peer that was looked up, if the directory has one
+    Found {
+        nonce: u64,
+        record: Option<SignedNymAddressRecord>,
+    },
+}
+
+impl DirectoryMessage {
+    pub fn nonce(&self) -> u64 {
+        match self {
+            DirectoryMessage::Register { nonce, .. }
+            | DirectoryMessage::Registered { nonce, .. }
+            | DirectoryMessage::Lookup { nonce, .. }
+            | DirectoryMessage::Found { nonce, .. } => *nonce,
+        }
+    }
+
+    /// to_bytes encodes the message: the prefix, a type byte, the nonce, and then the
+    /// record, the acceptance byte, the address to reply to and the peer ID, or a found
+    /// byte and the record if found, depending on the type.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = DIRECTORY_PREFIX.to_vec();
+        match self {
+            DirectoryMessage::Register { nonce, record } => {
+                bytes.push(directory_op::REGISTER);
+                bytes.extend_from_slice(&nonce.to_be_bytes());
+                bytes.extend_from_slice(&record.to_bytes());
+            }
+            DirectoryMessage::Registered { nonce, accepted } => {
+                bytes.push(directory_op::REGISTERED);
+                bytes.extend_from_slice(&nonce.to_be_bytes());
+                bytes.push(u8::from(*accepted));
+            }
+            DirectoryMessage::Lookup {
+                nonce,
+                peer_id,
+                reply_to,
+            } => {
+                bytes.push(directory_op::LOOKUP);
+                bytes.extend_from_slice(&nonce.to_be_bytes());
+                bytes.extend_from_slice(&reply_to.to_bytes());
+                bytes.extend_from_slice(&peer_id.to_bytes());
+            }
+            DirectoryMessage::Found { nonce, record } => {
+                bytes.push(directory_op::FOUND);
+                bytes.extend_from_slice(&nonce.to_be_bytes());
+                match record {
+                    Some(record) => {
+                        bytes.push(1);
+                        bytes.extend_from_slice(&record.to_bytes());
+                    }
+                    None => bytes.push(0),
+                }
+            }
+        }
+        bytes
+    }
+
+    /// try_from_bytes decodes a message encoded with `to_bytes`. It returns None if the
+    /// payload isn't a directory message at all, and records still have to be verified
+    /// before they're trusted.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Option<Self>, Error> {
+        let Some(bytes) = bytes.strip_prefix(DIRECTORY_PREFIX) else {
+            return Ok(None);
+        };
+        if bytes.len() < 1 + NONCE_LEN {
+            return Err(Error::InvalidDirectoryMessage);
+        }
+        let nonce = u64::from_be_bytes(
+            bytes[1..1 + NONCE_LEN]
+                .try_into()
+                .map_err(|_| Error::InvalidDirectoryMessage)?,
+        );
+        let body = &bytes[1 + NONCE_LEN..];
+        let message = match bytes[0] {
+            directory_op::REGISTER => DirectoryMessage::Register {
+                nonce,
+                record: SignedNymAddressRecord::try_from_bytes(body)?,
+            },
+            directory_op::REGISTERED => match body {
+                [accepted] => DirectoryMessage::Registered {
+                    nonce,
+                    accepted: *accepted != 0,
+                },
+                _ => return Err(Error::InvalidDirectoryMessage),
+            },
+            directory_op::LOOKUP => {
+                if body.len() < Recipient::LEN {
+                    return Err(Error::InvalidDirectoryMessage);
+                }
+                let mut recipient_bytes = [0u8; Recipient::LEN];
+                recipient_bytes.copy_from_slice(&body[..Recipient::LEN]);
+                DirectoryMessage::Lookup {
+                    nonce,
+                    reply_to: Recipient::try_from_bytes(recipient_bytes)
+                        .map_err(Error::InvalidRecipientBytes)?,
+                    peer_id: PeerId::from_bytes(&body[Recipient::LEN..])
+                        .map_err(Error::InvalidPeerIdBytes)?,
+                }
+            }
+            directory_op::FOUND => match body.split_first() {
+                Some((0, [])) => DirectoryMessage::Found {
+                    nonce,
+                    record: None,
+                },
+                Some((1, record)) => DirectoryMessage::Found {
+                    nonce,
+                    record: Some(SignedNymAddressRecord::try_from_bytes(record)?),
+                },
+                _ => return Err(Error::InvalidDirectoryMessage),
+            },
+            _ => return Err(Error::InvalidDirectoryMessage),
+        };
+        Ok(Some(message))
+    }
+}
+
+/// DirectoryStore keeps the latest verified address record of each peer until it expires.
+#[derive(Debug)]
+pub struct DirectoryStore {
+    records: HashMap<PeerId, SignedNymAddressRecord>,
+    max_records: usize,
+}
+
+impl Default for DirectoryStore {
+    fn default() -> Self {
+        DirectoryStore::new(DEFAULT_MAX_RECORDS)
+    }
+}
+
+impl DirectoryStore {
+    pub fn new(max_records: usize) -> Self {
+        DirectoryStore {
+            records: HashMap::new(),
+            max_records: max_records.max(1),
+        }
+    }
+
+    /// insert keeps the record if it verifies, unless we have a record of the peer that
+    /// expires later. When the store is full, the record that expires soonest makes room.
+    pub fn insert(&mut self, record: SignedNymAddressRecord) -> Result<(), Error> {
+        record.verify()?;
+        if let Some(existing) = self.records.get(&record.peer_id()) {
+            if existing.expiry() > record.expiry() {
+                // an older record can't replace a newer one, eg. when it's replayed
+                return Ok(());
+            }
+        } else if self.records.len() >= self.max_records {
+            self.expire();
+            if self.records.len() >= self.max_records {
+                let soonest = self
+                    .records
+                    .values()
+                    .min_by_key(|record| record.expiry())
+                    .map(|record| record.peer_id());
+                if let Some(peer_id) = soonest {
+                    self.records.remove(&peer_id);
+                }
+            }
+        }
+        self.records.insert(record.peer_id(), record);
+        Ok(())
+    }
+
+    /// get returns the record of the peer, if we have one that hasn't expired.
+    pub fn get(&mut self, peer_id: &PeerId) -> Option<SignedNymAddressRecord> {
+        let now = unix_micros() / 1_000_000;
+        match self.records.get(peer_id) {
+            Some(record) if record.expiry() > now => Some(record.clone()),
+            Some(_) => {
+                self.records.remove(peer_id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// expire forgets the records that expired.
+    pub fn expire(&mut self) {
+        let now = unix_micros() / 1_000_000;
+        self.records.retain(|_, record| record.expiry() > now);
+    }
+}
+
+/// DirectoryServer runs a directory on a well-known node: peers register their signed
+/// address records with it and look each other up, over the mixnet, so a network of Nym
+/// nodes can find each other without a clearnet bootstrap.
+pub struct DirectoryServer {
+    store: DirectoryStore,
+    connection: MixnetConnection,
+}
+
+impl DirectoryServer {
+    /// new returns a directory answering through the connection, eg. the one of the
+    /// node's transport from
+    /// [`NymTransport::mixnet_connection`](crate::transport::NymTransport::mixnet_connection).
+    pub fn new(connection: MixnetConnection) -> Self {
+        DirectoryServer {
+            store: DirectoryStore::default(),
+            connection,
+        }
+    }
+
+    /// with_max_records sets how many records the directory keeps, and returns self.
+    pub fn with_max_records(mut self, max_records: usize) -> Self {
+        self.store = DirectoryStore::new(max_records);
+        self
+    }
+
+    pub fn store(&self) -> &DirectoryStore {
+        &self.store
+    }
+
+    /// handle_payload answers the directory message in a broadcast payload, and returns
+    /// false if the payload isn't one, so it can be handled otherwise.
+    pub fn handle_payload(&mut self, payload: &[u8]) -> bool {
+        let message = match DirectoryMessage::try_from_bytes(payload) {
+            Ok(Some(message)) => message,
+            Ok(None) => return false,
+            Err(e) => {
+                debug!("dropping invalid directory message: {:?}", e);
+                return true;
+            }
+        };
+        let (recipient, answer) = match message {
+            DirectoryMessage::Register { nonce, record } => {
+                let recipient = record.recipient();
+                let accepted = match self.store.insert(record) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        debug!("declining directory record: {:?}", e);
+                        false
+                    }
+                };
+                (recipient, DirectoryMessage::Registered { nonce, accepted })
+            }
+            DirectoryMessage::Lookup {
+                nonce,
+                peer_id,
+                reply_to,
+            } => {
+                let record = self.store.get(&peer_id);
+                (reply_to, DirectoryMessage::Found { nonce, record })
+            }
+            // answers are for clients
+            DirectoryMessage::Registered { .. } | DirectoryMessage::Found { .. } => {
+                return true;
+            }
+        };
+        if let Err(e) = self
+            .connection
+            .broadcast(vec![recipient], answer.to_bytes())
+        {
+            debug!("failed to answer directory request: {:?}", e);
+        }
+        true
+    }
+
+    /// run answers the directory messages among the broadcasts, eg. those of the node's
+    /// transport from [`NymTransport::subscribe_broadcasts`], until the channel closes.
+    ///
+    /// [`NymTransport::subscribe_broadcasts`]: crate::transport::NymTransport::subscribe_broadcasts
+    pub async fn run(mut self, mut broadcasts: UnboundedReceiver<Vec<u8>>) {
+        while let Some(payload) = broadcasts.recv().await {
+            self.handle_payload(&payload);
+        }
+    }
+}
+
+/// DirectoryClient registers our address record with directory nodes and looks up the
+/// records of other peers. Answers arrive as broadcasts, which have to be passed to
+/// [`DirectoryClient::handle_payload`]. It can be cloned, and the clones share the
+/// requests waiting for an answer.
+#[derive(Clone)]
+pub struct DirectoryClient {
+    connection: MixnetConnection,
+    /// our Nym address, where lookups are answered
+    reply_to: Recipient,
+    timeout: Duration,
+    /// nonce of each request waiting for an answer -> where to send it
+    pending: Arc<Mutex<HashMap<u64, oneshot::Sender<DirectoryMessage>>>>,
+}
+
+impl DirectoryClient {
+    /// new returns a client sending requests through the connection, with lookups
+    /// answered at our Nym address `reply_to`.
+    pub fn new(connection: MixnetConnection, reply_to: Recipient) -> Self {
+        DirectoryClient {
+            connection,
+            reply_to,
+            timeout: DEFAULT_REQUEST_TIMEOUT,
+            pending: Arc::default(),
+        }
+    }
+
+    /// with_timeout sets how long to wait for a directory to answer, and returns self.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// register asks the directory to keep our address record. The answer goes to the
+    /// address in the record.
+    pub async fn register(
+        &self,
+        directory: Recipient,
+        record: SignedNymAddressRecord,
+    ) -> Result<(), Error> {
+        let nonce = OsRng.next_u64();
+        match self
+            .request(directory, DirectoryMessage::Register { nonce, record })
+            .await?
+        {
+            DirectoryMessage::Registered { accepted: true, .. } => Ok(()),
+            DirectoryMessage::Registered {
+                accepted: false, ..
+            } => Err(Error::DirectoryRecordRejected),
+            _ => Err(Error::InvalidDirectoryMessage),
+        }
+    }
+
+    /// lookup asks the directory for the address record of the peer. A record it returns
+    /// is only trusted if it verifies and is the peer's.
+    pub async fn lookup(
+        &self,
+        directory: Recipient,
+        peer_id: PeerId,
+    ) -> Result<Option<SignedNymAddressRecord>, Error> {
+        let nonce = OsRng.next_u64();
+        let lookup = DirectoryMessage::Lookup {
+            nonce,
+            peer_id,
+            reply_to: self.reply_to,
+        };
+        match self.request(directory, lookup).await? {
+            DirectoryMessage::Found { record: None, .. } => Ok(None),
+            DirectoryMessage::Found {
+                record: Some(record),
+                ..
+            } => {
+                if record.peer_id() != peer_id {
+                    return Err(Error::InvalidAddressRecordSignature);
+                }
+                record.verify()?;
+                Ok(Some(record))
+            }
+            _ => Err(Error::InvalidDirectoryMessage),
+        }
+    }
+
+    /// handle_payload passes on the answer in a broadcast payload to the request waiting
+    /// for it, and returns false if the payload isn't a directory message, so it can be
+    /// handled otherwise.
+    pub fn handle_payload(&self, payload: &[u8]) -> bool {
+        let message = match DirectoryMessage::try_from_bytes(payload) {
+            Ok(Some(message)) => message,
+            Ok(None) => return false,
+            Err(e) => {
+                debug!("dropping invalid directory message: {:?}", e);
+                return true;
+            }
+        };
+        match self.pending.lock().remove(&message.nonce()) {
+            Some(answer_tx) => {
+                answer_tx.send(message).ok();
+            }
+            None => debug!("dropping unexpected directory answer"),
+        }
+        true
+    }
+
+    /// request sends the message to the directory and waits for the answer.
+    async fn request(
+        &self,
+        directory: Recipient,
+        message: DirectoryMessage,
+    ) -> Result<DirectoryMessage, Error> {
+        let nonce = message.nonce();
+        let (answer_tx, answer_rx) = oneshot::channel();
+        self.pending.lock().insert(nonce, answer_tx);
+        if let Err(e) = self
+            .connection
+            .broadcast(vec![directory], message.to_bytes())
+        {
+            self.pending.lock().remove(&nonce);
+            return Err(e);
+        }
+        match tokio::time::timeout(self.timeout, answer_rx).await {
+            Ok(answer) => answer.map_err(|_| Error::RecvError),
+            Err(_) => {
+                self.pending.lock().remove(&nonce);
+                Err(Error::DirectoryTimeout)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::future::poll_fn;
+    use libp2p::core::{identity::Keypair, Transport};
+    use std::pin::Pin;
+
+    use super::*;
+    use crate::backend::{mock::random_recipient, MockMixnet};
+    use crate::transport::NymTransport;
+
+    fn record(keypair: &Keypair, ttl: Duration) -> SignedNymAddressRecord {
+        SignedNymAddressRecord::new(keypair, random_recipient(), ttl).unwrap()
+    }
+
+    #[test]
+    fn test_directory_message_roundtrip() {
+        let keypair = Keypair::generate_ed25519();
+        let record = record(&keypair, Duration::from_secs(60));
+        for message in [
+            DirectoryMessage::Register {
+                nonce: 1,
+                record: record.clone(),
+            },
+            DirectoryMessage::Registered {
+                nonce: 2,
+                accepted: true,
+            },
+            DirectoryMessage::Lookup {
+                nonce: 3,
+                peer_id: record.peer_id(),
+                reply_to: random_recipient(),
+            },
+            DirectoryMessage::Found {
+                nonce: 4,
+                record: Some(record),
+            },
+            DirectoryMessage::Found {
+                nonce: 5,
+                record: None,
+            },
+        ] {
+            let decoded = DirectoryMessage::try_from_bytes(&message.to_bytes()).unwrap();
+            assert_eq!(decoded, Some(message));
+        }
+
+        // other broadcasts aren't directory messages
+        assert_eq!(DirectoryMessage::try_from_bytes(b"hello").unwrap(), None);
+        let mut truncated = DIRECTORY_PREFIX.to_vec();
+        truncated.push(directory_op::FOUND);
+        assert!(matches!(
+            DirectoryMessage::try_from_bytes(&truncated),
+            Err(Error::InvalidDirectoryMessage)
+        ));
+    }
+
+    #[test]
+    fn test_directory_store() {
+        let mut store = DirectoryStore::new(2);
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+
+        let newer = record(&keypair, Duration::from_secs(120));
+        store.insert(newer.clone()).unwrap();
+        // a replayed older record doesn't replace the newer one
+        store
+            .insert(record(&keypair, Duration::from_secs(60)))
+            .unwrap();
+        assert_eq!(store.get(&peer_id), Some(newer));
+
+        // expired records are declined
+        assert!(matches!(
+            store.insert(record(&Keypair::generate_ed25519(), Duration::ZERO)),
+            Err(Error::AddressRecordExpired)
+        ));
+
+        // when full, the record expiring soonest makes room
+        let other = Keypair::generate_ed25519();
+        store
+            .insert(record(&other, Duration::from_secs(30)))
+            .unwrap();
+        let another = Keypair::generate_ed25519();
+        store
+            .insert(record(&another, Duration::from_secs(90)))
+            .unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(store.get(&PeerId::from(other.public())).is_none());
+        assert!(store.get(&PeerId::from(another.public())).is_some());
+    }
+
+    /// spawn_transport polls the transport in the background, passing the broadcasts it
+    /// receives on.
+    fn spawn_transport(
+        mixnet: &MockMixnet,
+    ) -> (
+        MixnetConnection,
+        Recipient,
+        Keypair,
+        UnboundedReceiver<Vec<u8>>,
+    ) {
+        let keypair = Keypair::generate_ed25519();
+        let mut transport =
+            NymTransport::new_with_backend(mixnet.new_backend(), keypair.clone()).unwrap();
+        let broadcasts = transport.subscribe_broadcasts();
+        let connection = transport.mixnet_connection();
+        let address = transport
+            .signed_address_record(Duration::from_secs(60))
+            .unwrap()
+            .recipient();
+        tokio::spawn(async move {
+            loop {
+                poll_fn(|cx| Pin::new(&mut transport).poll(cx)).await;
+            }
+        });
+        (connection, address, keypair, broadcasts)
+    }
+
+    #[tokio::test]
+    async fn test_directory_register_and_lookup() {
+        let mixnet = MockMixnet::new();
+        let (directory_connection, directory, _, directory_broadcasts) = spawn_transport(&mixnet);
+        tokio::spawn(DirectoryServer::new(directory_connection).run(directory_broadcasts));
+
+        let mut clients = vec![];
+        for _ in 0..2 {
+            let (connection, address, keypair, mut broadcasts) = spawn_transport(&mixnet);
+            let client =
+                DirectoryClient::new(connection, address).with_timeout(Duration::from_secs(5));
+            let handler = client.clone();
+            tokio::spawn(async move {
+                while let Some(payload) = broadcasts.recv().await {
+                    assert!(handler.handle_payload(&payload));
+                }
+            });
+            clients.push((client, address, keypair));
+        }
+        let (alice, alice_address, alice_keypair) = &clients[0];
+        let (bob, _, _) = &clients[1];
+        let alice_peer_id = PeerId::from(alice_keypair.public());
+
+        assert_eq!(bob.lookup(directory, alice_peer_id).await.unwrap(), None);
+        let alice_record =
+            SignedNymAddressRecord::new(alice_keypair, *alice_address, Duration::from_secs(60))
+                .unwrap();
+        alice
+            .register(directory, alice_record.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            bob.lookup(directory, alice_peer_id).await.unwrap(),
+            Some(alice_record)
+        );
+
+        // nobody answers at a random address
+        let lost = DirectoryClient::new(alice.connection.clone(), *alice_address)
+            .with_timeout(Duration::from_millis(100));
+        assert!(matches!(
+            lost.lookup(random_recipient(), alice_peer_id).await,
+            Err(Error::DirectoryTimeout)
+        ));
+    }
+}
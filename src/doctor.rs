@@ -0,0 +1,391 @@
+//! Startup diagnostics, for finding out why a transport doesn't connect.
+//!
+//! [`diagnose`] checks, in order, that the Nym client's websocket endpoint can be reached,
+//! how long the client takes to answer a request for its Nym address, how long a message
+//! sent to our own address takes to come back through the mixnet, and how large a message
+//! makes it through. Checks that depend on one that failed are skipped. The `nym-doctor`
+//! binary runs the checks and prints the report.
+//!
+//! The checks read the messages the Nym client receives, so they shouldn't be run against
+//! a client a node is using at the same time.
+
+use nym_sphinx::addressing::clients::Recipient;
+use rand::RngCore;
+use std::{fmt, future::Future};
+use tokio::time::{timeout, timeout_at, Duration, Instant};
+
+#[cfg(feature = "sdk")]
+use crate::backend::SdkBackend;
+use crate::backend::{websocket, MixnetBackend, MockMixnet, WebsocketBackend};
+use crate::endpoint::NymEndpoint;
+use crate::error::Error;
+
+/// PAYLOAD_PROBE_SIZES are the sizes of the messages sent to find the largest message
+/// that makes it through the mixnet, in bytes.
+pub const PAYLOAD_PROBE_SIZES: [usize; 4] = [1024, 32 * 1024, 256 * 1024, 1024 * 1024];
+
+/// length of the header identifying probe messages: a random nonce and the probe's index
+const PROBE_HEADER_LEN: usize = 9;
+
+/// Check is the outcome of one of the checks of a [`DiagnosticReport`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Check<T> {
+    /// the check passed, with what it measured
+    Passed(T),
+    /// the check failed, with the reason
+    Failed(String),
+    /// the check wasn't run, with the reason
+    Skipped(&'static str),
+}
+
+impl<T> Check<T> {
+    /// is_failed returns whether the check failed; skipped checks haven't.
+    pub fn is_failed(&self) -> bool {
+        matches!(self, Check::Failed(_))
+    }
+
+    /// passed returns what the check measured, if it passed.
+    pub fn passed(&self) -> Option<&T> {
+        match self {
+            Check::Passed(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// DiagnosticReport is what [`diagnose`] found out about reaching the mixnet through an
+/// endpoint.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiagnosticReport {
+    /// the endpoint that was checked
+    pub endpoint: String,
+    /// how long opening the websocket connection to the Nym client took; skipped for
+    /// endpoints other than websockets
+    pub websocket: Check<Duration>,
+    /// how long the Nym client took to answer a request for its Nym address; skipped for
+    /// endpoints other than websockets
+    pub self_address: Check<Duration>,
+    /// our Nym address, if it's known
+    pub address: Option<Recipient>,
+    /// how long a small message sent to our own address took to come back
+    pub loopback: Check<Duration>,
+    /// the largest of [`PAYLOAD_PROBE_SIZES`] that came back when sent to our own address
+    pub max_payload: Check<usize>,
+}
+
+impl DiagnosticReport {
+    fn new(endpoint: String) -> Self {
+        DiagnosticReport {
+            endpoint,
+            websocket: Check::Skipped("not a websocket endpoint"),
+            self_address: Check::Skipped("not a websocket endpoint"),
+            address: None,
+            loopback: Check::Skipped("couldn't connect to the mixnet"),
+            max_payload: Check::Skipped("couldn't connect to the mixnet"),
+        }
+    }
+
+    /// is_healthy returns whether none of the checks failed.
+    pub fn is_healthy(&self) -> bool {
+        !(self.websocket.is_failed()
+            || self.self_address.is_failed()
+            || self.loopback.is_failed()
+            || self.max_payload.is_failed())
+    }
+}
+
+impl fmt::Display for DiagnosticReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "endpoint: {}", self.endpoint)?;
+        if let Some(address) = &self.address {
+            writeln!(f, "nym address: {address}")?;
+        }
+        write_check(
+            f,
+            "websocket",
+            &self.websocket,
+            |d| format!("connected in {d:?}"),
+            "is the nym-client running, and listening on this host and port?",
+        )?;
+        write_check(
+            f,
+            "self address",
+            &self.self_address,
+            |d| format!("answered in {d:?}"),
+            "is this the websocket endpoint of a nym-client, and of a compatible version?",
+        )?;
+        write_check(
+            f,
+            "loopback",
+            &self.loopback,
+            |d| format!("came back in {d:?}"),
+            "is the nym-client connected to its gateway? check its logs for gateway errors",
+        )?;
+        write_check(
+            f,
+            "max payload",
+            &self.max_payload,
+            |len| format!("at least {len} bytes"),
+            "is something between us and the nym-client capping the size of websocket frames?",
+        )
+    }
+}
+
+/// write_check writes a line with the outcome of a check, and a hint if it failed.
+fn write_check<T>(
+    f: &mut fmt::Formatter<'_>,
+    name: &str,
+    check: &Check<T>,
+    passed: impl Fn(&T) -> String,
+    hint: &str,
+) -> fmt::Result {
+    match check {
+        Check::Passed(value) => writeln!(f, "{name}: ok, {}", passed(value)),
+        Check::Failed(reason) => writeln!(f, "{name}: FAILED, {reason}\n  hint: {hint}"),
+        Check::Skipped(reason) => writeln!(f, "{name}: skipped, {reason}"),
+    }
+}
+
+/// diagnose runs the checks against the endpoint, eg. `"ws://127.0.0.1:1977"`, giving
+/// each of them up to `check_timeout`, and returns the report. It only fails if the
+/// endpoint can't be parsed; failed checks are part of the report.
+pub async fn diagnose<E>(endpoint: E, check_timeout: Duration) -> Result<DiagnosticReport, Error>
+where
+    E: TryInto<NymEndpoint>,
+    Error: From<E::Error>,
+{
+    let endpoint = endpoint.try_into()?;
+    let mut report = DiagnosticReport::new(endpoint.to_string());
+    match &endpoint {
+        NymEndpoint::Websocket(uri) => {
+            if let Some(mut backend) = connect_websocket(uri, check_timeout, &mut report).await {
+                check_mixnet(&mut backend, check_timeout, &mut report).await;
+            }
+        }
+        #[cfg(feature = "sdk")]
+        NymEndpoint::Sdk { gateway } => {
+            let connect = async {
+                match gateway {
+                    Some(gateway) => SdkBackend::connect_with_gateway(gateway.clone()).await,
+                    None => SdkBackend::connect_new().await,
+                }
+            };
+            match timed(check_timeout, connect).await {
+                Ok((mut backend, _)) => {
+                    check_mixnet(&mut backend, check_timeout, &mut report).await
+                }
+                Err(reason) => report.loopback = Check::Failed(reason),
+            }
+        }
+        #[cfg(not(feature = "sdk"))]
+        NymEndpoint::Sdk { .. } => {
+            report.loopback = Check::Skipped("sdk:// requires the sdk feature");
+            report.max_payload = Check::Skipped("sdk:// requires the sdk feature");
+        }
+        NymEndpoint::Mock(name) => {
+            let mut backend = MockMixnet::named(name).new_backend();
+            check_mixnet(&mut backend, check_timeout, &mut report).await;
+        }
+    }
+    Ok(report)
+}
+
+/// connect_websocket runs the websocket and self address checks, and returns a backend
+/// using the connection if both passed.
+async fn connect_websocket(
+    uri: &str,
+    check_timeout: Duration,
+    report: &mut DiagnosticReport,
+) -> Option<WebsocketBackend> {
+    report.self_address = Check::Skipped("couldn't connect to the websocket endpoint");
+    let mut ws_stream = match timed(check_timeout, websocket::open_connection(uri)).await {
+        Ok((ws_stream, elapsed)) => {
+            report.websocket = Check::Passed(elapsed);
+            ws_stream
+        }
+        Err(reason) => {
+            report.websocket = Check::Failed(reason);
+            return None;
+        }
+    };
+
+    let address = timed(check_timeout, websocket::get_self_address(&mut ws_stream)).await;
+    match address {
+        Ok((address, elapsed)) => {
+            report.self_address = Check::Passed(elapsed);
+            report.address = Some(address);
+            Some(WebsocketBackend::from_connection(uri, ws_stream, address))
+        }
+        Err(reason) => {
+            report.self_address = Check::Failed(reason);
+            None
+        }
+    }
+}
+
+/// check_mixnet runs the loopback and max payload checks through the backend.
+async fn check_mixnet<B: MixnetBackend>(
+    backend: &mut B,
+    check_timeout: Duration,
+    report: &mut DiagnosticReport,
+) {
+    let address = backend.self_address();
+    report.address = Some(address);
+    let mut nonce = [0u8; 8];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    // the loopback probe is as small as a probe gets, and isn't one of the payload probes
+    let loopback = timed(check_timeout, async {
+        send_probe(backend, address, &nonce, u8::MAX, PROBE_HEADER_LEN).await?;
+        while recv_probe(backend, &nonce).await? != u8::MAX {}
+        Ok(())
+    });
+    match loopback.await {
+        Ok(((), elapsed)) => report.loopback = Check::Passed(elapsed),
+        Err(reason) => {
+            report.loopback = Check::Failed(reason);
+            report.max_payload = Check::Skipped("the loopback check failed");
+            return;
+        }
+    }
+
+    let deadline = Instant::now() + check_timeout;
+    let send = async {
+        for (index, size) in PAYLOAD_PROBE_SIZES.iter().enumerate() {
+            send_probe(backend, address, &nonce, index as u8, *size).await?;
+        }
+        Ok::<_, Error>(())
+    };
+    match timeout_at(deadline, send).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            report.max_payload = Check::Failed(e.to_string());
+            return;
+        }
+        Err(_) => {
+            report.max_payload = Check::Failed(format!(
+                "sending the probes took longer than {check_timeout:?}"
+            ));
+            return;
+        }
+    }
+
+    // probes that haven't come back by the deadline are taken to be too large
+    let mut received = [false; PAYLOAD_PROBE_SIZES.len()];
+    while received.contains(&false) {
+        match timeout_at(deadline, recv_probe(backend, &nonce)).await {
+            Ok(Ok(index)) => {
+                if let Some(received) = received.get_mut(index as usize) {
+                    *received = true;
+                }
+            }
+            Ok(Err(e)) => {
+                report.max_payload = Check::Failed(e.to_string());
+                return;
+            }
+            Err(_) => break,
+        }
+    }
+    report.max_payload = match received.iter().rposition(|received| *received) {
+        Some(index) => Check::Passed(PAYLOAD_PROBE_SIZES[index]),
+        None => Check::Failed(format!(
+            "not even a {} byte message came back within {check_timeout:?}",
+            PAYLOAD_PROBE_SIZES[0]
+        )),
+    };
+}
+
+/// send_probe sends a probe of `len` bytes with the given index to our own address.
+async fn send_probe<B: MixnetBackend>(
+    backend: &mut B,
+    address: Recipient,
+    nonce: &[u8; 8],
+    index: u8,
+    len: usize,
+) -> Result<(), Error> {
+    let mut probe = vec![0u8; len.max(PROBE_HEADER_LEN)];
+    probe[..8].copy_from_slice(nonce);
+    probe[8] = index;
+    backend.send(address, probe).await
+}
+
+/// recv_probe waits for the next probe with the given nonce, and returns its index.
+/// Other messages are dropped.
+async fn recv_probe<B: MixnetBackend>(backend: &mut B, nonce: &[u8; 8]) -> Result<u8, Error> {
+    loop {
+        let message = backend.recv().await?;
+        if message.len() >= PROBE_HEADER_LEN && message[..8] == nonce[..] {
+            return Ok(message[8]);
+        }
+    }
+}
+
+/// timed runs the future for up to `check_timeout`, and returns its output along with
+/// how long it took, or why it failed.
+async fn timed<T>(
+    check_timeout: Duration,
+    future: impl Future<Output = Result<T, Error>>,
+) -> Result<(T, Duration), String> {
+    let started = Instant::now();
+    match timeout(check_timeout, future).await {
+        Ok(Ok(value)) => Ok((value, started.elapsed())),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("no answer within {check_timeout:?}")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::LinkConditions;
+
+    #[tokio::test]
+    async fn test_diagnose_mock_endpoint() {
+        let report = diagnose("mock://doctor", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(report.is_healthy(), "{report}");
+        assert!(report.address.is_some());
+        assert!(matches!(report.websocket, Check::Skipped(_)));
+        assert!(report.loopback.passed().is_some());
+        assert_eq!(
+            report.max_payload,
+            Check::Passed(*PAYLOAD_PROBE_SIZES.last().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_loopback_lost() {
+        let mixnet = MockMixnet::new();
+        let mut backend = mixnet.new_backend();
+        let address = backend.self_address();
+        let lossy = LinkConditions {
+            loss: 1.0,
+            ..LinkConditions::default()
+        };
+        mixnet.set_link(&address, &address, lossy);
+
+        let mut report = DiagnosticReport::new("mock://lossy".to_string());
+        check_mixnet(&mut backend, Duration::from_millis(200), &mut report).await;
+        assert!(!report.is_healthy());
+        assert!(report.loopback.is_failed());
+        assert!(matches!(report.max_payload, Check::Skipped(_)));
+        assert!(report
+            .to_string()
+            .contains("hint: is the nym-client connected"));
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_unreachable_websocket() {
+        // nothing listens on port 1
+        let report = diagnose("ws://127.0.0.1:1", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(report.websocket.is_failed());
+        assert!(matches!(report.self_address, Check::Skipped(_)));
+        assert!(matches!(report.loopback, Check::Skipped(_)));
+        assert!(report
+            .to_string()
+            .contains("hint: is the nym-client running"));
+    }
+}
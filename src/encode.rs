@@ -0,0 +1,160 @@
+use nym_sphinx::addressing::clients::Recipient;
+use std::{
+    collections::{HashMap, VecDeque},
+    future::{poll_fn, Future},
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{task::JoinHandle, time::Instant};
+use tracing::warn;
+
+use crate::error::Error;
+use crate::fragment::fragment;
+use crate::message::OutboundMessage;
+
+/// EncodedMessage is an outbound message along with the bytes written to the mixnet.
+#[derive(Debug)]
+pub(crate) struct EncodedMessage {
+    pub(crate) message: OutboundMessage,
+    pub(crate) bytes: Vec<u8>,
+    /// the fragments the bytes are written as, if messages are fragmented
+    pub(crate) fragments: Option<Result<Vec<Vec<u8>>, Error>>,
+    /// when the message was done encoding
+    pub(crate) encoded_at: Instant,
+}
+
+/// encode serializes the message and splits it into fragments of at most
+/// `fragment_threshold` bytes, if set.
+pub(crate) fn encode(
+    message: OutboundMessage,
+    fragment_threshold: Option<usize>,
+) -> EncodedMessage {
+    let bytes = message.to_bytes();
+    let fragments = fragment_threshold.map(|threshold| fragment(&bytes, threshold));
+    EncodedMessage {
+        message,
+        bytes,
+        fragments,
+        encoded_at: Instant::now(),
+    }
+}
+
+/// OffloadedEncodes encodes outbound messages on the blocking thread pool, so that large
+/// payloads don't hold up the mixnet task. Messages to the same recipient, and so those
+/// of the same connection, come out in the order they went in; others may overtake them.
+#[derive(Debug, Default)]
+pub(crate) struct OffloadedEncodes {
+    /// recipient bytes -> encodes running for that recipient, oldest first
+    running: HashMap<[u8; Recipient::LEN], VecDeque<JoinHandle<EncodedMessage>>>,
+}
+
+impl OffloadedEncodes {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.running.is_empty()
+    }
+
+    /// is_running_for returns true if messages to the recipient are being encoded, so the
+    /// next one has to wait for them.
+    pub(crate) fn is_running_for(&self, recipient: &Recipient) -> bool {
+        self.running.contains_key(&recipient.to_bytes())
+    }
+
+    /// spawn starts encoding the message on the blocking thread pool.
+    pub(crate) fn spawn(&mut self, message: OutboundMessage, fragment_threshold: Option<usize>) {
+        let recipient = message.recipient.to_bytes();
+        let handle = tokio::task::spawn_blocking(move || encode(message, fragment_threshold));
+        self.running.entry(recipient).or_default().push_back(handle);
+    }
+
+    /// next returns the next message that's done encoding and has no older message to
+    /// the same recipient still encoding. It never returns while nothing is running.
+    pub(crate) async fn next(&mut self) -> EncodedMessage {
+        poll_fn(|cx| self.poll_next(cx)).await
+    }
+
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<EncodedMessage> {
+        let mut done = None;
+        for handles in self.running.values_mut() {
+            // only the oldest of each recipient may come out
+            while let Some(handle) = handles.front_mut() {
+                match Pin::new(handle).poll(cx) {
+                    Poll::Pending => break,
+                    Poll::Ready(result) => {
+                        handles.pop_front();
+                        match result {
+                            Ok(encoded) => {
+                                done = Some(encoded);
+                                break;
+                            }
+                            Err(e) => warn!("failed to encode outbound message: {:?}", e),
+                        }
+                    }
+                }
+            }
+            if done.is_some() {
+                break;
+            }
+        }
+        self.running.retain(|_, handles| !handles.is_empty());
+        match done {
+            Some(encoded) => Poll::Ready(encoded),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::mock::random_recipient;
+    use crate::message::{ConnectionId, Message, SubstreamId, SubstreamMessage, TransportMessage};
+
+    fn data_message(recipient: Recipient, nonce: u64, len: usize) -> OutboundMessage {
+        let message = Message::TransportMessage(TransportMessage {
+            nonce,
+            message: SubstreamMessage::new_with_data(SubstreamId::generate(), vec![0; len]),
+            id: ConnectionId::generate(),
+        });
+        OutboundMessage::new(message, recipient)
+    }
+
+    fn nonce(encoded: &EncodedMessage) -> u64 {
+        match &encoded.message.message {
+            Message::TransportMessage(msg) => msg.nonce,
+            _ => panic!("expected a TransportMessage"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_offloaded_encodes() {
+        let recipient = random_recipient();
+        let other_recipient = random_recipient();
+        let mut encodes = OffloadedEncodes::default();
+        assert!(encodes.is_empty());
+
+        // a large message, then small ones after it
+        encodes.spawn(data_message(recipient, 1, 4 << 20), Some(64 << 10));
+        encodes.spawn(data_message(recipient, 2, 10), Some(64 << 10));
+        encodes.spawn(data_message(other_recipient, 1, 10), None);
+        assert!(encodes.is_running_for(&recipient));
+        assert!(encodes.is_running_for(&other_recipient));
+
+        let mut nonces = vec![];
+        for _ in 0..3 {
+            let encoded = encodes.next().await;
+            if encoded.message.recipient == recipient {
+                let nonce = nonce(&encoded);
+                nonces.push(nonce);
+                let fragments = encoded.fragments.unwrap().unwrap();
+                assert_eq!(fragments.len() > 1, nonce == 1);
+            } else {
+                assert_eq!(encoded.bytes, encoded.message.to_bytes());
+                assert!(encoded.fragments.is_none());
+            }
+        }
+        // messages to the same recipient come out in order
+        assert_eq!(nonces, vec![1, 2]);
+        assert!(encodes.is_empty());
+        assert!(!encodes.is_running_for(&recipient));
+    }
+}
@@ -0,0 +1,198 @@
+use std::{fmt, str::FromStr};
+
+use crate::error::Error;
+
+/// NymEndpoint is where a transport reaches the mixnet, parsed from a URI whose scheme
+/// selects the backend:
+///
+/// - `ws://host:port` or `wss://host:port` is an external nym-client's websocket endpoint,
+///   eg. `ws://127.0.0.1:1977`
+/// - `sdk://` runs a Nym client in-process, and `sdk://<gateway identity key>` registers it
+///   with that gateway; it requires the `sdk` feature
+/// - `mock://<name>` is an in-memory mock mixnet; transports using the same name in the
+///   same process can reach each other
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NymEndpoint {
+    /// the full websocket URI, including the scheme
+    Websocket(String),
+    Sdk {
+        gateway: Option<String>,
+    },
+    Mock(String),
+}
+
+impl NymEndpoint {
+    /// scheme returns the URI scheme of the endpoint; `ws` and `wss` are both websockets.
+    pub fn scheme(&self) -> &str {
+        match self {
+            NymEndpoint::Websocket(uri) if uri.starts_with("wss://") => "wss",
+            NymEndpoint::Websocket(_) => "ws",
+            NymEndpoint::Sdk { .. } => "sdk",
+            NymEndpoint::Mock(_) => "mock",
+        }
+    }
+}
+
+impl FromStr for NymEndpoint {
+    type Err = Error;
+
+    fn from_str(uri: &str) -> Result<Self, Error> {
+        let invalid = |reason: &str| Error::InvalidEndpoint(format!("{uri}: {reason}"));
+        let Some((scheme, rest)) = uri.split_once("://") else {
+            return Err(invalid(
+                "missing scheme, expected ws://, wss://, sdk:// or mock://",
+            ));
+        };
+        // a trailing slash is harmless, anything after it isn't
+        let rest = rest.strip_suffix('/').unwrap_or(rest);
+        match scheme.to_ascii_lowercase().as_str() {
+            "ws" | "wss" => {
+                let authority = rest.split_once('/').map(|(a, _)| a).unwrap_or(rest);
+                if authority.contains('@') {
+                    return Err(invalid("credentials aren't supported in the URI"));
+                }
+                // the colons of an IPv6 address, eg. [::1], don't start a port
+                let (host, port) = match authority.rfind(':') {
+                    Some(i) if !authority[i..].contains(']') => {
+                        (&authority[..i], Some(&authority[i + 1..]))
+                    }
+                    _ => (authority, None),
+                };
+                if host.is_empty() {
+                    return Err(invalid("missing host"));
+                }
+                if let Some(port) = port {
+                    if port.parse::<u16>().is_err() {
+                        return Err(invalid(&format!("invalid port {port:?}")));
+                    }
+                }
+                Ok(NymEndpoint::Websocket(uri.to_string()))
+            }
+            "sdk" => {
+                if rest.contains(['/', ':', '@']) {
+                    return Err(invalid(
+                        "expected nothing or a gateway identity key after sdk://",
+                    ));
+                }
+                let gateway = (!rest.is_empty()).then(|| rest.to_string());
+                Ok(NymEndpoint::Sdk { gateway })
+            }
+            "mock" => {
+                if rest.is_empty() {
+                    return Err(invalid("expected a mixnet name after mock://"));
+                }
+                if rest.contains(['/', ':', '@']) {
+                    return Err(invalid("expected only a mixnet name after mock://"));
+                }
+                Ok(NymEndpoint::Mock(rest.to_string()))
+            }
+            _ => Err(invalid(&format!(
+                "unsupported scheme {scheme:?}, expected ws, wss, sdk or mock"
+            ))),
+        }
+    }
+}
+
+impl TryFrom<&str> for NymEndpoint {
+    type Error = Error;
+
+    fn try_from(uri: &str) -> Result<Self, Error> {
+        uri.parse()
+    }
+}
+
+impl TryFrom<&String> for NymEndpoint {
+    type Error = Error;
+
+    fn try_from(uri: &String) -> Result<Self, Error> {
+        uri.parse()
+    }
+}
+
+impl TryFrom<String> for NymEndpoint {
+    type Error = Error;
+
+    fn try_from(uri: String) -> Result<Self, Error> {
+        uri.parse()
+    }
+}
+
+impl fmt::Display for NymEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NymEndpoint::Websocket(uri) => f.write_str(uri),
+            NymEndpoint::Sdk { gateway } => {
+                write!(f, "sdk://{}", gateway.as_deref().unwrap_or_default())
+            }
+            NymEndpoint::Mock(name) => write!(f, "mock://{name}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_endpoint() {
+        for uri in [
+            "ws://127.0.0.1:1977",
+            "wss://nym.example.com",
+            "ws://[::1]:1977",
+            "ws://[::1]",
+        ] {
+            assert_eq!(
+                uri.parse::<NymEndpoint>().unwrap(),
+                NymEndpoint::Websocket(uri.to_string())
+            );
+        }
+        assert_eq!(
+            "wss://nym.example.com"
+                .parse::<NymEndpoint>()
+                .unwrap()
+                .scheme(),
+            "wss"
+        );
+        assert_eq!(
+            "sdk://".parse::<NymEndpoint>().unwrap(),
+            NymEndpoint::Sdk { gateway: None }
+        );
+        assert_eq!(
+            "sdk://E3mvZTHQCdBvhfr178Swx9g4QG3kkRUun7YnToLMcMbM"
+                .parse::<NymEndpoint>()
+                .unwrap(),
+            NymEndpoint::Sdk {
+                gateway: Some("E3mvZTHQCdBvhfr178Swx9g4QG3kkRUun7YnToLMcMbM".to_string())
+            }
+        );
+        assert_eq!(
+            "mock://test/".parse::<NymEndpoint>().unwrap(),
+            NymEndpoint::Mock("test".to_string())
+        );
+
+        // the endpoint round-trips through its string form
+        for uri in ["ws://127.0.0.1:1977", "sdk://", "mock://test"] {
+            assert_eq!(uri.parse::<NymEndpoint>().unwrap().to_string(), uri);
+        }
+
+        for (uri, reason) in [
+            ("127.0.0.1:1977", "missing scheme"),
+            ("http://127.0.0.1:1977", "unsupported scheme \"http\""),
+            ("ws://", "missing host"),
+            ("ws://:1977", "missing host"),
+            ("ws://127.0.0.1:nym", "invalid port \"nym\""),
+            ("ws://user@127.0.0.1:1977", "credentials"),
+            ("sdk://gateway/path", "gateway identity key"),
+            ("mock://", "mixnet name"),
+            ("mock://a:b", "only a mixnet name"),
+        ] {
+            match uri.parse::<NymEndpoint>() {
+                Err(Error::InvalidEndpoint(error)) => {
+                    assert!(error.starts_with(uri), "{error}");
+                    assert!(error.contains(reason), "{error}");
+                }
+                result => panic!("{uri} parsed as {result:?}"),
+            }
+        }
+    }
+}
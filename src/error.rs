@@ -1,9 +1,11 @@
-use libp2p::core::multiaddr;
+use libp2p::core::{multiaddr, PeerId};
 use nym_sphinx::addressing::clients::RecipientFormattingError;
 use tokio_tungstenite::tungstenite::Error as WsError;
 
 use crate::message::SubstreamId;
 
+pub use crate::message::DenialReason;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("unimplemented")]
@@ -16,6 +18,34 @@ pub enum Error {
     WebsocketStreamError(#[from] WsError),
     #[error("websocket stream read returned None")]
     WebsocketStreamReadNone,
+    #[error("lost connection to the mixnet")]
+    MixnetDisconnected,
+    #[error("no gateway could be reached")]
+    NoGateways,
+    #[error("the mixnet backend doesn't support packet size {0:?}")]
+    UnsupportedPacketSize(crate::backend::PacketSize),
+    #[error("failed to sign address update: {0}")]
+    AddressUpdateSigningFailed(String),
+    #[error("invalid address update message bytes")]
+    InvalidAddressUpdateBytes,
+    #[error("address update has an invalid signature")]
+    InvalidAddressUpdateSignature,
+    #[error("failed to sign address record: {0}")]
+    AddressRecordSigningFailed(String),
+    #[error("invalid address record bytes")]
+    InvalidAddressRecordBytes,
+    #[error("address record has an invalid signature")]
+    InvalidAddressRecordSignature,
+    #[error("address record has expired")]
+    AddressRecordExpired,
+    #[error("no connection found for address update")]
+    NoConnectionForAddressUpdate,
+    #[error("invalid ping/pong message bytes")]
+    InvalidProbeMessageBytes,
+    #[error("no connection found for ping/pong")]
+    NoConnectionForProbe,
+    #[error("no echo reply within the handshake timeout")]
+    EchoTimeout,
     #[error("nym message error")]
     NymMessageError(String),
     #[error("unexpected message received over mixnet")]
@@ -76,4 +106,105 @@ pub enum Error {
     SendErrorTransportEvent,
     #[error("dial timed out")]
     DialTimeout(#[from] tokio::time::error::Elapsed),
+    #[error("too many dials are waiting for a dial slot")]
+    DialQueueFull,
+    #[error("too many messages are waiting to be written to the mixnet")]
+    OutboundQueueFull,
+    #[error("failed to read or write identity file")]
+    IdentityFileError(std::io::Error),
+    #[error("failed to write audit log")]
+    AuditLogError(std::io::Error),
+    #[error("failed to read or write message journal")]
+    JournalError(std::io::Error),
+    #[error("invalid message journal record")]
+    InvalidJournalRecord,
+    #[error("invalid identity file")]
+    InvalidIdentityFile,
+    #[error("unsupported key type")]
+    UnsupportedKeyType,
+    #[error("failed to set up transport: {0}")]
+    TransportSetupError(String),
+    #[error("invalid reachability message bytes")]
+    InvalidReachabilityMessageBytes,
+    #[error("no connection found for reachability request")]
+    NoConnectionForReachabilityRequest,
+    #[error("the mixnet is unavailable")]
+    MixnetUnavailable,
+    #[error("invalid config: {0}")]
+    InvalidConfig(&'static str),
+    #[error("failed to read config file")]
+    ConfigFileError(std::io::Error),
+    #[error("invalid config file: {0}")]
+    InvalidConfigFile(String),
+    #[error("inbound connection rejected: {0}")]
+    InboundConnectionRejected(DenialReason),
+    #[error("connection denied by the remote peer: {0}")]
+    ConnectionDenied(DenialReason),
+    #[error("invalid message fragment bytes")]
+    InvalidFragmentBytes,
+    #[error("message needs too many fragments for the max frame size")]
+    MessageTooLargeForFrameSize,
+    #[error("injected failure: {0}")]
+    InjectedFailure(&'static str),
+    #[error("connection dropped by the transport")]
+    ConnectionDropped,
+    #[error("listener key {0} is already used by another transport")]
+    ListenerKeyInUse(PeerId),
+    #[error("message missed its send deadline")]
+    SendDeadlineExceeded,
+    #[error("invalid compact message bytes")]
+    InvalidCompactMessageBytes,
+    #[error("no connection found for compact reference {0}")]
+    NoConnectionForCompactReference(u64),
+    #[error("the mixnet appears to be down; the circuit breaker is open")]
+    MixnetOutage,
+    #[error("the connection has no reply SURBs left")]
+    SurbsExhausted,
+    #[error("failed to read or write capture file")]
+    CaptureFileError(std::io::Error),
+    #[error("invalid capture file")]
+    InvalidCaptureFile,
+    #[error("invalid Nym endpoint {0}")]
+    InvalidEndpoint(String),
+    #[error("invalid directory message")]
+    InvalidDirectoryMessage,
+    #[error("the directory didn't answer in time")]
+    DirectoryTimeout,
+    #[error("the directory declined the address record")]
+    DirectoryRecordRejected,
+    #[error("unknown message type {0}")]
+    UnknownMessageType(u8),
+    #[error("a transport is already registered as {0}")]
+    DuplicateInstanceName(String),
+    #[error("the transport was shut down")]
+    TransportShutDown,
+    #[error("user agent is not valid UTF-8")]
+    InvalidUserAgent,
+    #[error("no data arrived within the read deadline")]
+    ReadDeadlineExceeded,
+    #[error("unsupported framing version {0}")]
+    UnsupportedFraming(u8),
+    #[error("failed to read or write substream")]
+    SubstreamIoError(std::io::Error),
+    #[error("failed to sign peer snapshot: {0}")]
+    PeerSnapshotSigningFailed(String),
+    #[error("invalid peer snapshot bytes")]
+    InvalidPeerSnapshotBytes,
+    #[error("unsupported peer snapshot version {0}")]
+    UnsupportedPeerSnapshotVersion(u8),
+    #[error("peer snapshot has an invalid signature")]
+    InvalidPeerSnapshotSignature,
+    #[error("peer snapshot is signed by untrusted peer {0}")]
+    UntrustedPeerSnapshotSigner(PeerId),
+    #[error("the remote peer stopped answering keepalives")]
+    KeepaliveTimeout,
+    #[error("timed out connecting to the mixnet endpoint")]
+    ConnectTimeout,
+}
+
+// lets constructors taking anything that converts to a NymEndpoint also take one directly
+impl From<std::convert::Infallible> for Error {
+    fn from(infallible: std::convert::Infallible) -> Self {
+        match infallible {}
+    }
 }
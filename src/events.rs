@@ -0,0 +1,160 @@
+use libp2p::core::PeerId;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::audit::{ConnectionDirection, ConnectionOutcome};
+use crate::stats::ConnectionStats;
+
+pub use crate::message::ShutdownReason;
+
+/// ConnectionEvent is a change to the connections of a transport, for applications that
+/// manage their sessions without a libp2p Swarm; see
+/// [`MixnetConnection::connection_events`](crate::mixnet::MixnetConnection::connection_events).
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionEvent {
+    /// a connection finished its handshake
+    Opened {
+        peer_id: PeerId,
+        direction: ConnectionDirection,
+    },
+    /// a connection went away
+    Closed {
+        peer_id: PeerId,
+        reason: CloseReason,
+    },
+    /// a connection attempt failed; for inbound attempts, the peer is whoever the request
+    /// claims to be from, if known
+    HandshakeFailed {
+        peer_id: Option<PeerId>,
+        direction: ConnectionDirection,
+        error: String,
+    },
+    /// the remote peer of a connection sent something it shouldn't have, eg. an address
+    /// update with an invalid signature
+    PeerMisbehaved { peer_id: PeerId, reason: String },
+    /// the remote peer's clock appears to be off from ours by more than the threshold set
+    /// with [`NymTransport::with_clock_skew_detection`](crate::transport::NymTransport::with_clock_skew_detection);
+    /// the offset is positive if its clock is ahead
+    ClockSkewed { peer_id: PeerId, offset_micros: i64 },
+    /// a connection stopped using an optional feature it negotiated, because the feature
+    /// kept failing; the connection carries on without it
+    Downgraded {
+        peer_id: PeerId,
+        feature: NegotiatedFeature,
+        reason: String,
+    },
+    /// the traffic of a connection over the last interval, if enabled with
+    /// [`NymTransport::with_connection_stats`](crate::transport::NymTransport::with_connection_stats)
+    Stats {
+        peer_id: PeerId,
+        stats: ConnectionStats,
+    },
+}
+
+/// NegotiatedFeature is an optional feature of a connection that's turned off for the rest
+/// of the connection if it keeps failing, rather than closing the connection.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NegotiatedFeature {
+    /// the send times of substream data, see
+    /// [`NymTransport::with_latency_extension`](crate::transport::NymTransport::with_latency_extension)
+    LatencyExtension,
+}
+
+/// CloseReason is why a connection went away.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CloseReason {
+    /// the application dropped the connection
+    Dropped,
+    /// the connection didn't negotiate a protocol within the upgrade timeout
+    UpgradeTimeout,
+    /// the remote peer stopped answering keepalives
+    KeepaliveTimeout,
+    /// the remote peer closed the connection, for the reason it gave
+    Remote(ShutdownReason),
+    /// the transport was shut down
+    Shutdown,
+}
+
+impl ConnectionEvent {
+    /// from_outcome returns the event for a connection attempt with the given outcome.
+    pub(crate) fn from_outcome(
+        direction: ConnectionDirection,
+        peer_id: Option<PeerId>,
+        outcome: &ConnectionOutcome,
+    ) -> Self {
+        match (outcome, peer_id) {
+            (ConnectionOutcome::Established, Some(peer_id)) => {
+                ConnectionEvent::Opened { peer_id, direction }
+            }
+            (ConnectionOutcome::Filtered, _) => ConnectionEvent::HandshakeFailed {
+                peer_id,
+                direction,
+                error: "dropped by the inbound filter".to_string(),
+            },
+            (ConnectionOutcome::Failed(error), _) => ConnectionEvent::HandshakeFailed {
+                peer_id,
+                direction,
+                error: error.clone(),
+            },
+            (ConnectionOutcome::Established, None) => ConnectionEvent::HandshakeFailed {
+                peer_id,
+                direction,
+                error: "unknown remote peer".to_string(),
+            },
+        }
+    }
+}
+
+/// ConnectionEventSender sends connection events to every subscriber. It's shared by a
+/// transport and its MixnetConnection handles.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ConnectionEventSender(Arc<Mutex<Vec<UnboundedSender<ConnectionEvent>>>>);
+
+impl ConnectionEventSender {
+    pub(crate) fn subscribe(&self) -> UnboundedReceiver<ConnectionEvent> {
+        let (events_tx, events_rx) = unbounded_channel();
+        self.0.lock().push(events_tx);
+        events_rx
+    }
+
+    /// send sends the event to the subscribers, and forgets those that went away.
+    pub(crate) fn send(&self, event: ConnectionEvent) {
+        self.0
+            .lock()
+            .retain(|events_tx| events_tx.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_connection_event_sender() {
+        let sender = ConnectionEventSender::default();
+        let mut events_rx = sender.subscribe();
+        let dropped_rx = sender.subscribe();
+        drop(dropped_rx);
+
+        let peer_id = PeerId::random();
+        let event = ConnectionEvent::from_outcome(
+            ConnectionDirection::Inbound,
+            Some(peer_id),
+            &ConnectionOutcome::Established,
+        );
+        sender.send(event.clone());
+        assert_eq!(events_rx.try_recv().unwrap(), event);
+        assert_eq!(sender.0.lock().len(), 1);
+
+        let event = ConnectionEvent::from_outcome(
+            ConnectionDirection::Outbound,
+            None,
+            &ConnectionOutcome::Failed("dial timed out".to_string()),
+        );
+        assert!(matches!(
+            event,
+            ConnectionEvent::HandshakeFailed { peer_id: None, .. }
+        ));
+    }
+}
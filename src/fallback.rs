@@ -0,0 +1,155 @@
+use futures::future::Either;
+use libp2p::core::{
+    identity::Keypair,
+    muxing::StreamMuxerBox,
+    transport::{Boxed, Transport},
+    upgrade, PeerId,
+};
+use libp2p::{noise, tcp, yamux};
+
+use crate::error::Error;
+use crate::transport::NymTransport;
+
+/// with_fallback combines the Nym transport with another transport, such as TCP or QUIC.
+/// `/nym/` addresses are always handled by the Nym transport; any other address is
+/// passed on to the fallback transport, so one swarm can talk to both mixnet-only and
+/// clearnet peers.
+pub fn with_fallback<T>(nym: NymTransport, fallback: T) -> Boxed<(PeerId, StreamMuxerBox)>
+where
+    T: Transport<Output = (PeerId, StreamMuxerBox)> + Send + Unpin + 'static,
+    T::Dial: Send + 'static,
+    T::ListenerUpgrade: Send + 'static,
+    T::Error: Send + Sync + 'static,
+{
+    nym.map(|(peer_id, conn), _| (peer_id, StreamMuxerBox::new(conn)))
+        .or_transport(fallback)
+        .map(|output, _| match output {
+            Either::Left(output) => output,
+            Either::Right(output) => output,
+        })
+        .boxed()
+}
+
+/// nym_or_tcp combines the Nym transport with a noise-authenticated, yamux-multiplexed
+/// TCP transport using the given keypair.
+pub fn nym_or_tcp(
+    nym: NymTransport,
+    keypair: &Keypair,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Error> {
+    let noise = noise::NoiseAuthenticated::xx(keypair)
+        .map_err(|e| Error::TransportSetupError(e.to_string()))?;
+    let tcp = tcp::tokio::Transport::new(tcp::Config::default().nodelay(true))
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise)
+        .multiplex(yamux::YamuxConfig::default())
+        .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)));
+    Ok(with_fallback(nym, tcp))
+}
+
+#[cfg(test)]
+mod test {
+    use futures::future::poll_fn;
+    use libp2p::core::{
+        identity::Keypair,
+        multiaddr::Protocol,
+        muxing::StreamMuxerBox,
+        transport::{Boxed, Transport, TransportEvent},
+        Multiaddr, PeerId,
+    };
+    use std::{pin::Pin, str::FromStr};
+
+    use super::nym_or_tcp;
+    use crate::backend::MockMixnet;
+    use crate::transport::NymTransport;
+
+    type BoxedTransport = Boxed<(PeerId, StreamMuxerBox)>;
+
+    /// connect dials the address from the dialer, driving both transports, and returns
+    /// the peer IDs each end sees and the local address of the listener's connection.
+    async fn connect(
+        dialer: &mut BoxedTransport,
+        listener: &mut BoxedTransport,
+        addr: Multiaddr,
+    ) -> (PeerId, PeerId, Multiaddr) {
+        let mut dial = tokio::spawn(dialer.dial(addr).unwrap());
+        let (upgrade, local_addr) = loop {
+            tokio::select! {
+                event = poll_fn(|cx| Pin::new(&mut *listener).poll(cx)) => {
+                    if let TransportEvent::Incoming { upgrade, local_addr, .. } = event {
+                        break (upgrade, local_addr);
+                    }
+                }
+                _ = poll_fn(|cx| Pin::new(&mut *dialer).poll(cx)) => {}
+            }
+        };
+        let accept = tokio::spawn(upgrade);
+        let (dialed_peer_id, _) = loop {
+            tokio::select! {
+                res = &mut dial => break res.unwrap().unwrap(),
+                _ = poll_fn(|cx| Pin::new(&mut *dialer).poll(cx)) => {}
+                _ = poll_fn(|cx| Pin::new(&mut *listener).poll(cx)) => {}
+            }
+        };
+        let (accepted_peer_id, _) = accept.await.unwrap().unwrap();
+        (dialed_peer_id, accepted_peer_id, local_addr)
+    }
+
+    #[tokio::test]
+    async fn test_nym_or_tcp_routes_by_address() {
+        let mixnet = MockMixnet::new();
+        let dialer_keypair = Keypair::generate_ed25519();
+        let listener_keypair = Keypair::generate_ed25519();
+        let dialer_peer_id = PeerId::from_public_key(&dialer_keypair.public());
+        let listener_peer_id = PeerId::from_public_key(&listener_keypair.public());
+        let nym =
+            NymTransport::new_with_backend(mixnet.new_backend(), dialer_keypair.clone()).unwrap();
+        let mut dialer = nym_or_tcp(nym, &dialer_keypair).unwrap();
+        let nym =
+            NymTransport::new_with_backend(mixnet.new_backend(), listener_keypair.clone()).unwrap();
+        let nym_addr = nym.listen_addr.clone();
+        let mut listener = nym_or_tcp(nym, &listener_keypair).unwrap();
+
+        listener
+            .listen_on(Multiaddr::from_str("/ip4/127.0.0.1/tcp/0").unwrap())
+            .unwrap();
+        let tcp_addr = loop {
+            match poll_fn(|cx| Pin::new(&mut listener).poll(cx)).await {
+                TransportEvent::NewAddress { listen_addr, .. }
+                    if listen_addr.iter().any(|p| matches!(p, Protocol::Tcp(_))) =>
+                {
+                    break listen_addr
+                }
+                _ => {}
+            }
+        };
+
+        // a TCP address reaches the listener over TCP..
+        let (dialed, accepted, local_addr) = connect(&mut dialer, &mut listener, tcp_addr).await;
+        assert_eq!(dialed, listener_peer_id);
+        assert_eq!(accepted, dialer_peer_id);
+        assert!(local_addr.iter().any(|p| matches!(p, Protocol::Tcp(_))));
+
+        // ..and a Nym address over the mixnet
+        let (dialed, accepted, local_addr) =
+            connect(&mut dialer, &mut listener, nym_addr.clone()).await;
+        assert_eq!(dialed, listener_peer_id);
+        assert_eq!(accepted, dialer_peer_id);
+        assert_eq!(local_addr, nym_addr);
+    }
+
+    #[tokio::test]
+    async fn test_nym_or_tcp_falls_back_for_tcp_addresses() {
+        let keypair = Keypair::generate_ed25519();
+        let mixnet = MockMixnet::new();
+        let nym = NymTransport::new_with_backend(mixnet.new_backend(), keypair.clone()).unwrap();
+        let mut transport = nym_or_tcp(nym, &keypair).unwrap();
+
+        // TCP addresses are handled by the TCP transport
+        transport
+            .listen_on(Multiaddr::from_str("/ip4/127.0.0.1/tcp/0").unwrap())
+            .unwrap();
+        transport
+            .dial(Multiaddr::from_str("/ip4/127.0.0.1/tcp/4001").unwrap())
+            .unwrap();
+    }
+}
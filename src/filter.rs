@@ -0,0 +1,43 @@
+use libp2p::core::PeerId;
+use nym_sphinx::addressing::clients::Recipient;
+
+pub use crate::message::{InboundMessage, MessageKind};
+
+/// MessageSender is what we know about the sender of an inbound message.
+/// Nym messages are anonymous, so this is only known for messages belonging to a
+/// connection, in which case it's taken from the connection rather than the message.
+/// For connection requests, it's whatever the request claims.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MessageSender {
+    pub peer_id: Option<PeerId>,
+    pub address: Option<Recipient>,
+}
+
+/// FilterAction is the verdict of an InboundFilter on a message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FilterAction {
+    /// handle the message as usual
+    Accept,
+    /// drop the message before it touches any connection state
+    Drop,
+    /// handle the message as usual, and count it under the given tag in the
+    /// transport's stats
+    Tag(&'static str),
+}
+
+/// InboundFilter sees every message received from the mixnet before the transport
+/// handles it, and can drop or tag it; eg. for research instrumentation, spam
+/// filtering or bridging other protocols.
+/// Any `FnMut(&MessageSender, &InboundMessage) -> FilterAction` closure is a filter.
+pub trait InboundFilter: Send + 'static {
+    fn filter(&mut self, sender: &MessageSender, message: &InboundMessage) -> FilterAction;
+}
+
+impl<F> InboundFilter for F
+where
+    F: FnMut(&MessageSender, &InboundMessage) -> FilterAction + Send + 'static,
+{
+    fn filter(&mut self, sender: &MessageSender, message: &InboundMessage) -> FilterAction {
+        self(sender, message)
+    }
+}
@@ -0,0 +1,377 @@
+use rand_core::{OsRng, RngCore};
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+use crate::budget::{BufferKind, MemoryBudget, Reservation};
+use crate::error::Error;
+use crate::spec;
+use crate::stats::ReassemblyEvictions;
+
+/// FRAGMENT_TAG starts every fragment of a message that was split up to fit a size cap.
+/// It isn't a valid message type, so fragments can't be mistaken for whole messages.
+pub(crate) const FRAGMENT_TAG: u8 = spec::fragment::TAG;
+
+/// FRAGMENT_HEADER_LEN is the length of the tag, message ID, fragment index and
+/// fragment count at the start of each fragment.
+pub(crate) const FRAGMENT_HEADER_LEN: usize = spec::fragment::PAYLOAD;
+
+/// how many partially received messages are kept by default; the least recently added to
+/// is dropped beyond this.
+pub(crate) const DEFAULT_MAX_PARTIAL_MESSAGES: usize = 64;
+
+/// how long the rest of a partially received message is waited for by default.
+pub(crate) const DEFAULT_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// fragment splits a message into fragments of at most `max_len` bytes, header included.
+/// A message that already fits is returned as is.
+pub(crate) fn fragment(message: &[u8], max_len: usize) -> Result<Vec<Vec<u8>>, Error> {
+    if message.len() <= max_len {
+        return Ok(vec![message.to_vec()]);
+    }
+    if max_len <= FRAGMENT_HEADER_LEN {
+        return Err(Error::InvalidConfig("max frame size is too small"));
+    }
+
+    let chunks: Vec<&[u8]> = message.chunks(max_len - FRAGMENT_HEADER_LEN).collect();
+    let count = u16::try_from(chunks.len()).map_err(|_| Error::MessageTooLargeForFrameSize)?;
+    let id = OsRng.next_u64();
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut bytes = Vec::with_capacity(FRAGMENT_HEADER_LEN + chunk.len());
+            bytes.push(FRAGMENT_TAG);
+            bytes.extend_from_slice(&id.to_be_bytes());
+            bytes.extend_from_slice(&(index as u16).to_be_bytes());
+            bytes.extend_from_slice(&count.to_be_bytes());
+            bytes.extend_from_slice(chunk);
+            bytes
+        })
+        .collect())
+}
+
+/// PartialMessage is a message some of whose fragments have arrived.
+struct PartialMessage {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    /// when the message is dropped if it's still incomplete
+    deadline: Instant,
+    /// when the latest fragment arrived, which decides what's evicted first
+    last_fragment_at: Instant,
+    /// bytes of the fragments received so far
+    bytes: usize,
+    /// the memory reserved for the fragments
+    reservation: Reservation,
+}
+
+/// Reassembler puts fragmented messages back together, whichever backend they arrived
+/// through. Fragments may arrive in any order.
+pub(crate) struct Reassembler {
+    pending: HashMap<u64, PartialMessage>,
+    budget: MemoryBudget,
+    /// how long a message may take to arrive, from its first fragment
+    timeout: Duration,
+    /// how many messages may be partially received at once
+    max_pending: usize,
+    evictions: ReassemblyEvictions,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Reassembler::new(MemoryBudget::default(), ReassemblyEvictions::default())
+    }
+}
+
+impl Reassembler {
+    /// new returns a reassembler whose partial messages count towards the budget. A message
+    /// whose fragments don't fit in what's left of it is dropped. Messages dropped for
+    /// taking too long, or to make room for others, are counted in `evictions`.
+    pub(crate) fn new(budget: MemoryBudget, evictions: ReassemblyEvictions) -> Self {
+        Reassembler {
+            pending: HashMap::new(),
+            budget,
+            timeout: DEFAULT_REASSEMBLY_TIMEOUT,
+            max_pending: DEFAULT_MAX_PARTIAL_MESSAGES,
+            evictions,
+        }
+    }
+
+    /// set_limits changes how long a message may take to arrive and how many may be
+    /// partially received at once. Messages already pending keep their deadline.
+    pub(crate) fn set_limits(&mut self, timeout: Duration, max_pending: usize) {
+        self.timeout = timeout;
+        self.max_pending = max_pending.max(1);
+    }
+
+    /// next_deadline returns when the next partial message times out, if any is pending.
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|partial| partial.deadline).min()
+    }
+
+    /// push returns the message once all of its fragments have arrived. Data that isn't
+    /// a fragment is returned right away.
+    pub(crate) fn push(&mut self, data: Vec<u8>) -> Result<Option<Vec<u8>>, Error> {
+        self.push_fragment(data, true)
+    }
+
+    /// push_fragment is push, where a reassembled message may itself be a fragment if
+    /// `may_nest`: the transport splits messages that need too many sphinx packets, and
+    /// the websocket backend may split those fragments again to fit its frame size cap.
+    fn push_fragment(&mut self, data: Vec<u8>, may_nest: bool) -> Result<Option<Vec<u8>>, Error> {
+        if data.first() != Some(&FRAGMENT_TAG) {
+            return Ok(Some(data));
+        }
+        if data.len() < FRAGMENT_HEADER_LEN {
+            return Err(Error::InvalidFragmentBytes);
+        }
+
+        let id = u64::from_be_bytes(data[1..9].try_into().unwrap());
+        let index = u16::from_be_bytes(data[9..11].try_into().unwrap()) as usize;
+        let count = u16::from_be_bytes(data[11..13].try_into().unwrap()) as usize;
+        if index >= count {
+            return Err(Error::InvalidFragmentBytes);
+        }
+
+        let fragment = &data[FRAGMENT_HEADER_LEN..];
+        self.expire();
+        if !self.pending.contains_key(&id) {
+            self.make_room();
+            // the whole message is reserved up front, assuming all of its fragments are the
+            // size of the first one to arrive, so messages are shed before they're half done
+            let Some(reservation) = self
+                .budget
+                .try_reserve(BufferKind::Reassembly, count * fragment.len())
+            else {
+                self.budget.record_shed();
+                return Ok(None);
+            };
+            self.pending.insert(
+                id,
+                PartialMessage {
+                    fragments: vec![None; count],
+                    received: 0,
+                    deadline: Instant::now() + self.timeout,
+                    last_fragment_at: Instant::now(),
+                    bytes: 0,
+                    reservation,
+                },
+            );
+        }
+        let partial = self.pending.get_mut(&id).expect("partial message exists");
+        if partial.fragments.len() != count {
+            return Err(Error::InvalidFragmentBytes);
+        }
+        if partial.fragments[index].is_none() {
+            partial.bytes += fragment.len();
+            let reserved = partial.reservation.bytes();
+            if partial.bytes > reserved && !partial.reservation.try_grow(partial.bytes - reserved) {
+                // the message can't be completed anymore
+                self.pending.remove(&id);
+                self.budget.record_shed();
+                return Ok(None);
+            }
+            partial.fragments[index] = Some(fragment.to_vec());
+            partial.received += 1;
+            partial.last_fragment_at = Instant::now();
+        }
+        if partial.received < count {
+            return Ok(None);
+        }
+
+        let partial = self.pending.remove(&id).expect("partial message exists");
+        let message: Vec<u8> = partial.fragments.into_iter().flatten().flatten().collect();
+        if message.first() == Some(&FRAGMENT_TAG) {
+            if !may_nest {
+                return Err(Error::InvalidFragmentBytes);
+            }
+            return self.push_fragment(message, false);
+        }
+        Ok(Some(message))
+    }
+
+    /// expire drops partial messages that are past their deadline.
+    pub(crate) fn expire(&mut self) {
+        let now = Instant::now();
+        let evictions = &self.evictions;
+        self.pending.retain(|_, partial| {
+            let keep = partial.deadline > now;
+            if !keep {
+                evictions.record_timed_out();
+            }
+            keep
+        });
+    }
+
+    /// make_room drops the partial messages least recently added to until there's room
+    /// for another.
+    fn make_room(&mut self) {
+        while self.pending.len() >= self.max_pending {
+            let Some(lru) = self
+                .pending
+                .iter()
+                .min_by_key(|(_, partial)| partial.last_fragment_at)
+                .map(|(id, _)| *id)
+            else {
+                return;
+            };
+            self.pending.remove(&lru);
+            self.evictions.record_evicted();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fragment_and_reassemble() {
+        let message: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let mut fragments = fragment(&message, 100).unwrap();
+        assert_eq!(fragments.len(), 12);
+        assert!(fragments.iter().all(|fragment| fragment.len() <= 100));
+
+        // fragments can arrive out of order, interleaved with other messages
+        fragments.reverse();
+        let mut reassembler = Reassembler::default();
+        let last = fragments.pop().unwrap();
+        for fragment in fragments {
+            assert_eq!(reassembler.push(fragment).unwrap(), None);
+        }
+        assert_eq!(
+            reassembler.push(vec![1, 2, 3]).unwrap(),
+            Some(vec![1, 2, 3])
+        );
+        assert_eq!(reassembler.push(last).unwrap(), Some(message));
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn test_reassembly_budget() {
+        let budget = MemoryBudget::default();
+        budget.set_limit(Some(1000));
+        let mut reassembler = Reassembler::new(budget.clone(), ReassemblyEvictions::default());
+
+        let first = fragment(&[1u8; 900], 500).unwrap();
+        assert_eq!(reassembler.push(first[0].clone()).unwrap(), None);
+        assert_eq!(
+            budget.used_by(BufferKind::Reassembly),
+            2 * (500 - FRAGMENT_HEADER_LEN)
+        );
+
+        // a message that doesn't fit in what's left is shed, and the first one survives
+        let second = fragment(&[2u8; 900], 500).unwrap();
+        assert_eq!(reassembler.push(second[0].clone()).unwrap(), None);
+        assert_eq!(reassembler.push(second[1].clone()).unwrap(), None);
+        assert_eq!(budget.shed(), 2);
+        assert_eq!(reassembler.pending.len(), 1);
+
+        assert_eq!(
+            reassembler.push(first[1].clone()).unwrap(),
+            Some(vec![1u8; 900])
+        );
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reassembly_limits() {
+        let budget = MemoryBudget::default();
+        let evictions = ReassemblyEvictions::default();
+        let mut reassembler = Reassembler::new(budget.clone(), evictions.clone());
+        reassembler.set_limits(Duration::from_secs(10), 2);
+        let messages: Vec<Vec<Vec<u8>>> = (0..3u8)
+            .map(|i| fragment(&[i; 300], 100).unwrap())
+            .collect();
+
+        // the message least recently added to makes room for a new one
+        assert_eq!(reassembler.push(messages[0][0].clone()).unwrap(), None);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(reassembler.push(messages[1][0].clone()).unwrap(), None);
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(reassembler.push(messages[0][1].clone()).unwrap(), None);
+        assert_eq!(reassembler.push(messages[2][0].clone()).unwrap(), None);
+        assert_eq!(evictions.get().evicted, 1);
+        assert_eq!(reassembler.pending.len(), 2);
+        let id = |fragment: &[u8]| u64::from_be_bytes(fragment[1..9].try_into().unwrap());
+        assert!(!reassembler.pending.contains_key(&id(&messages[1][0])));
+
+        // messages are dropped once past their deadline, however recently added to
+        let deadline = reassembler.next_deadline().unwrap();
+        assert_eq!(deadline, Instant::now() + Duration::from_secs(8));
+        tokio::time::advance(Duration::from_secs(8)).await;
+        reassembler.expire();
+        assert_eq!(evictions.get().timed_out, 1);
+        assert_eq!(reassembler.pending.len(), 1);
+        tokio::time::advance(Duration::from_secs(2)).await;
+        reassembler.expire();
+        assert_eq!(evictions.get().timed_out, 2);
+        assert!(reassembler.next_deadline().is_none());
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reassemble_missing_fragment() {
+        let message: Vec<u8> = (0..500u32).map(|i| i as u8).collect();
+        let fragments = fragment(&message, 100).unwrap();
+        let evictions = ReassemblyEvictions::default();
+        let mut reassembler = Reassembler::new(MemoryBudget::default(), evictions.clone());
+
+        // shuffled, duplicated and with one fragment missing, the message never completes
+        let missing = fragments.len() / 2;
+        for (i, fragment) in fragments.iter().enumerate().rev() {
+            if i != missing {
+                assert_eq!(reassembler.push(fragment.clone()).unwrap(), None);
+                assert_eq!(reassembler.push(fragment.clone()).unwrap(), None);
+            }
+        }
+        assert_eq!(reassembler.pending.len(), 1);
+
+        // it completes if the missing fragment turns up before the deadline
+        tokio::time::advance(DEFAULT_REASSEMBLY_TIMEOUT / 2).await;
+        let completed = reassembler.push(fragments[missing].clone()).unwrap();
+        assert_eq!(completed, Some(message.clone()));
+
+        // and is dropped otherwise
+        for fragment in fragment(&message, 100).unwrap().into_iter().skip(1) {
+            assert_eq!(reassembler.push(fragment).unwrap(), None);
+        }
+        tokio::time::advance(DEFAULT_REASSEMBLY_TIMEOUT).await;
+        reassembler.expire();
+        assert!(reassembler.pending.is_empty());
+        assert_eq!(evictions.get().timed_out, 1);
+    }
+
+    #[test]
+    fn test_reassemble_nested_fragments() {
+        let message: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let mut reassembler = Reassembler::default();
+        let mut reassembled = None;
+        for outer in fragment(&message, 300).unwrap() {
+            for inner in fragment(&outer, 100).unwrap() {
+                if let Some(message) = reassembler.push(inner).unwrap() {
+                    reassembled = Some(message);
+                }
+            }
+        }
+        assert_eq!(reassembled, Some(message));
+
+        // but only one level deep
+        let split = |fragments: Vec<Vec<u8>>, max_len| -> Vec<Vec<u8>> {
+            fragments
+                .iter()
+                .flat_map(|f| fragment(f, max_len).unwrap())
+                .collect()
+        };
+        let nested = split(split(fragment(&[1u8; 100], 60).unwrap(), 40), 25);
+        assert!(nested.into_iter().any(|fragment| matches!(
+            reassembler.push(fragment),
+            Err(Error::InvalidFragmentBytes)
+        )));
+    }
+
+    #[test]
+    fn test_fragment_small_message() {
+        assert_eq!(fragment(&[1, 2, 3], 100).unwrap(), vec![vec![1, 2, 3]]);
+        fragment(&[0u8; 100], FRAGMENT_HEADER_LEN).unwrap_err();
+    }
+}
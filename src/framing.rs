@@ -0,0 +1,112 @@
+//! Framing of the Nym messages the transport writes to the mixnet, and the migration from
+//! one framing to the next.
+//!
+//! A Nym message is a whole message, starting with its type byte, or a fragment of one.
+//! In the v1 framing that's all there is; the v2 framing puts a header in front naming the
+//! framing version (see [`spec::frame`]), so that later changes to the format can be told
+//! apart from earlier ones. Inbound messages are decoded in either framing, so a fleet can
+//! move to v2 without a flag day: first every node is upgraded to a version that decodes
+//! both, then nodes switch to writing v2 with
+//! [`NymTransport::with_framing`](crate::transport::NymTransport::with_framing).
+//! [`TransportStats::inbound_frames`](crate::stats::TransportStats::inbound_frames) tells
+//! when no peer writes v1 anymore.
+
+use crate::error::Error;
+use crate::spec;
+
+/// Framing is how the Nym messages written to the mixnet are framed.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum Framing {
+    /// the message as it is; the only framing peers from before v2 decode
+    #[default]
+    V1,
+    /// the message behind a header naming the framing version
+    V2,
+}
+
+impl Framing {
+    /// overhead returns how many bytes the framing adds to a message.
+    pub fn overhead(self) -> usize {
+        match self {
+            Framing::V1 => 0,
+            Framing::V2 => spec::frame::MESSAGE,
+        }
+    }
+}
+
+/// frame returns a Nym message, given in the v1 framing, in the given framing.
+pub fn frame(message: Vec<u8>, framing: Framing) -> Vec<u8> {
+    match framing {
+        Framing::V1 => message,
+        Framing::V2 => {
+            let mut bytes = Vec::with_capacity(spec::frame::MESSAGE + message.len());
+            bytes.push(spec::frame::TAG);
+            bytes.push(spec::frame::V2);
+            bytes.extend_from_slice(&message);
+            bytes
+        }
+    }
+}
+
+/// unframe returns the framing of a Nym message read from the mixnet, and the message in
+/// the v1 framing. It fails for framing versions this version doesn't know.
+pub fn unframe(mut bytes: Vec<u8>) -> Result<(Framing, Vec<u8>), Error> {
+    if bytes.first() != Some(&spec::frame::TAG) {
+        return Ok((Framing::V1, bytes));
+    }
+    match bytes.get(spec::frame::VERSION) {
+        Some(&spec::frame::V2) => Ok((Framing::V2, bytes.split_off(spec::frame::MESSAGE))),
+        Some(&version) => Err(Error::UnsupportedFraming(version)),
+        None => Err(Error::InvalidMessageBytes),
+    }
+}
+
+/// convert returns a Nym message in either framing in the given one, eg. to replay
+/// recorded traffic to peers that only decode v1.
+pub fn convert(bytes: Vec<u8>, framing: Framing) -> Result<Vec<u8>, Error> {
+    let (_, message) = unframe(bytes)?;
+    Ok(frame(message, framing))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::message::{ConnectionId, Message, MessageKind, PingMessage};
+
+    #[test]
+    fn test_framing_roundtrip_and_convert() {
+        let message = Message::Ping(PingMessage {
+            id: ConnectionId::generate(),
+            sent_at: 42,
+        })
+        .to_bytes();
+
+        for framing in [Framing::V1, Framing::V2] {
+            let framed = frame(message.clone(), framing);
+            assert_eq!(framed.len(), message.len() + framing.overhead());
+            assert_eq!(unframe(framed.clone()).unwrap(), (framing, message.clone()));
+            for to in [Framing::V1, Framing::V2] {
+                assert_eq!(
+                    convert(framed.clone(), to).unwrap(),
+                    frame(message.clone(), to)
+                );
+            }
+        }
+
+        // v1 messages start with their type byte, v2 ones with the frame tag
+        assert_eq!(message[0], MessageKind::Ping.type_byte());
+        assert_eq!(
+            frame(message.clone(), Framing::V2)[..2],
+            [spec::frame::TAG, 2]
+        );
+
+        // later framings are rejected rather than taken for v1
+        let mut future = frame(message, Framing::V2);
+        future[spec::frame::VERSION] = 3;
+        assert!(matches!(unframe(future), Err(Error::UnsupportedFraming(3))));
+        assert!(matches!(
+            unframe(vec![spec::frame::TAG]),
+            Err(Error::InvalidMessageBytes)
+        ));
+    }
+}
@@ -0,0 +1,207 @@
+use libp2p::core::PeerId;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::SystemTime,
+};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    Semaphore,
+};
+
+use crate::config::RuntimeConfig;
+use crate::error::Error;
+use crate::message::{ConnectionMessage, DenialReason};
+
+/// VerifiedRequest is an inbound connection request the handshake workers are done with.
+#[derive(Debug)]
+pub(crate) struct VerifiedRequest {
+    pub(crate) msg: ConnectionMessage,
+    /// when the request was received, for the audit log
+    pub(crate) started_at: SystemTime,
+    /// Err if the request must be declined, with
+    /// [`Error::InboundConnectionRejected`] if the dialer is to be told why
+    pub(crate) result: Result<(), Error>,
+}
+
+/// HandshakePool verifies inbound connection requests on a bounded number of worker tasks,
+/// so that a burst of handshakes doesn't hold up the messages of established connections.
+/// Requests beyond the queue limit are declined right away.
+#[derive(Debug)]
+pub(crate) struct HandshakePool {
+    /// one permit per worker
+    workers: Arc<Semaphore>,
+    /// how many requests can be in flight: one per worker, plus the queue limit
+    max_in_flight: usize,
+    /// requests queued or being verified
+    in_flight: Arc<AtomicUsize>,
+    verified_tx: UnboundedSender<VerifiedRequest>,
+    verified_rx: UnboundedReceiver<VerifiedRequest>,
+}
+
+impl HandshakePool {
+    pub(crate) fn new(workers: usize, max_queued: usize) -> Self {
+        let (verified_tx, verified_rx) = unbounded_channel();
+        let workers = workers.max(1);
+        HandshakePool {
+            workers: Arc::new(Semaphore::new(workers)),
+            max_in_flight: workers + max_queued,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            verified_tx,
+            verified_rx,
+        }
+    }
+
+    /// in_flight returns how many requests are queued or being verified.
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// submit queues the request to be verified against the configuration, and returns it
+    /// if the queue is full.
+    pub(crate) fn submit(
+        &self,
+        msg: ConnectionMessage,
+        started_at: SystemTime,
+        local_peer_id: PeerId,
+        config: RuntimeConfig,
+    ) -> Result<(), ConnectionMessage> {
+        if self.in_flight() >= self.max_in_flight {
+            return Err(msg);
+        }
+
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let workers = self.workers.clone();
+        let in_flight = self.in_flight.clone();
+        let verified_tx = self.verified_tx.clone();
+        tokio::task::spawn(async move {
+            // the semaphore is never closed
+            let _permit = workers.acquire_owned().await;
+            let result = verify_connection_request(&msg, local_peer_id, &config);
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            // the transport might be gone already, which is fine
+            verified_tx
+                .send(VerifiedRequest {
+                    msg,
+                    started_at,
+                    result,
+                })
+                .ok();
+        });
+        Ok(())
+    }
+
+    /// poll_verified returns the next request the workers are done with.
+    pub(crate) fn poll_verified(&mut self, cx: &mut Context<'_>) -> Poll<Option<VerifiedRequest>> {
+        self.verified_rx.poll_recv(cx)
+    }
+}
+
+/// verify_connection_request runs the checks of an inbound connection request that don't
+/// depend on the transport's state: that it says where to respond to, that it's meant for
+/// us, and that the allow and deny lists accept the dialer.
+fn verify_connection_request(
+    msg: &ConnectionMessage,
+    local_peer_id: PeerId,
+    config: &RuntimeConfig,
+) -> Result<(), Error> {
+    if msg.recipient.is_none() && msg.reply_tag.is_none() {
+        return Err(Error::NoneRecipientInConnectionRequest);
+    }
+
+    // the dialer meant another listener using our Nym address
+    if matches!(msg.target, Some(target) if target != local_peer_id) {
+        return Err(Error::InboundConnectionRejected(
+            DenialReason::UnknownListener,
+        ));
+    }
+
+    if !config.is_allowed(&msg.peer_id) {
+        return Err(Error::InboundConnectionRejected(DenialReason::NotAllowed));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use futures::future::poll_fn;
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::backend::mock::random_recipient;
+    use crate::message::ConnectionId;
+
+    fn connection_request(peer_id: PeerId, target: Option<PeerId>) -> ConnectionMessage {
+        ConnectionMessage {
+            peer_id,
+            id: ConnectionId::generate(),
+            recipient: Some(random_recipient()),
+            target,
+            extensions: 0,
+            handshake_payload: None,
+            timestamps: vec![],
+            connection_ref: None,
+            user_agent: None,
+            reply_tag: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_pool() {
+        let local_peer_id = PeerId::random();
+        let denied_peer_id = PeerId::random();
+        let config = RuntimeConfig {
+            deny_list: HashSet::from([denied_peer_id]),
+            ..Default::default()
+        };
+        let mut pool = HandshakePool::new(1, 2);
+
+        let accepted = connection_request(PeerId::random(), Some(local_peer_id));
+        let accepted_id = accepted.id.clone();
+        pool.submit(accepted, SystemTime::now(), local_peer_id, config.clone())
+            .unwrap();
+        let denied = connection_request(denied_peer_id, None);
+        pool.submit(denied, SystemTime::now(), local_peer_id, config.clone())
+            .unwrap();
+        let misdirected = connection_request(PeerId::random(), Some(PeerId::random()));
+        pool.submit(
+            misdirected,
+            SystemTime::now(),
+            local_peer_id,
+            config.clone(),
+        )
+        .unwrap();
+
+        // one request is being verified and two are queued, which is as many as fit
+        pool.submit(
+            connection_request(PeerId::random(), None),
+            SystemTime::now(),
+            local_peer_id,
+            config.clone(),
+        )
+        .unwrap_err();
+
+        let mut results = vec![];
+        for _ in 0..3 {
+            let verified = poll_fn(|cx| pool.poll_verified(cx)).await.unwrap();
+            results.push((verified.msg.id, verified.result));
+        }
+        assert_eq!(pool.in_flight(), 0);
+        assert!(results
+            .iter()
+            .any(|(id, result)| *id == accepted_id && result.is_ok()));
+        assert!(results.iter().any(|(_, result)| matches!(
+            result,
+            Err(Error::InboundConnectionRejected(DenialReason::NotAllowed))
+        )));
+        assert!(results.iter().any(|(_, result)| matches!(
+            result,
+            Err(Error::InboundConnectionRejected(
+                DenialReason::UnknownListener
+            ))
+        )));
+    }
+}
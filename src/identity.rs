@@ -0,0 +1,174 @@
+use libp2p::core::identity::{ed25519, secp256k1, Keypair};
+use std::{fs, io::Write, path::Path};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::error::Error;
+
+const ED25519_TAG: u8 = 0;
+const SECP256K1_TAG: u8 = 1;
+const ED25519_KEYPAIR_LEN: usize = 64;
+const SECP256K1_SECRET_LEN: usize = 32;
+
+/// KeyType is the kind of libp2p keypair to generate for a new identity.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum KeyType {
+    #[default]
+    Ed25519,
+    Secp256k1,
+}
+
+/// load_or_generate_keypair loads the libp2p keypair stored at `path`.
+/// If the file doesn't exist, a new keypair of the given type is generated and saved
+/// there, so the node keeps a stable PeerId across restarts.
+pub fn load_or_generate_keypair<P: AsRef<Path>>(
+    path: P,
+    key_type: KeyType,
+) -> Result<Keypair, Error> {
+    let path = path.as_ref();
+    if path.exists() {
+        return load_keypair(path);
+    }
+
+    let keypair = match key_type {
+        KeyType::Ed25519 => Keypair::generate_ed25519(),
+        KeyType::Secp256k1 => Keypair::generate_secp256k1(),
+    };
+    save_keypair(path, &keypair)?;
+    Ok(keypair)
+}
+
+/// load_keypair loads a keypair previously saved with [`save_keypair`].
+pub fn load_keypair<P: AsRef<Path>>(path: P) -> Result<Keypair, Error> {
+    // the file holds the secret key, so it's scrubbed from memory once decoded
+    let mut bytes = Zeroizing::new(fs::read(path).map_err(Error::IdentityFileError)?);
+    decode_keypair(&mut bytes)
+}
+
+/// decode_keypair decodes a keypair encoded by [`save_keypair`], and scrubs `bytes`
+/// whether or not they hold a valid one.
+fn decode_keypair(bytes: &mut [u8]) -> Result<Keypair, Error> {
+    let res = decode_keypair_unscrubbed(bytes);
+    bytes.zeroize();
+    res
+}
+
+fn decode_keypair_unscrubbed(bytes: &mut [u8]) -> Result<Keypair, Error> {
+    if bytes.is_empty() {
+        return Err(Error::InvalidIdentityFile);
+    }
+
+    match bytes[0] {
+        ED25519_TAG if bytes.len() == 1 + ED25519_KEYPAIR_LEN => {
+            let keypair = ed25519::Keypair::decode(&mut bytes[1..])
+                .map_err(|_| Error::InvalidIdentityFile)?;
+            Ok(Keypair::Ed25519(keypair))
+        }
+        SECP256K1_TAG if bytes.len() == 1 + SECP256K1_SECRET_LEN => {
+            let secret = secp256k1::SecretKey::from_bytes(&mut bytes[1..])
+                .map_err(|_| Error::InvalidIdentityFile)?;
+            Ok(Keypair::Secp256k1(secret.into()))
+        }
+        _ => Err(Error::InvalidIdentityFile),
+    }
+}
+
+/// save_keypair writes the keypair to `path`, readable only by the current user on unix.
+pub fn save_keypair<P: AsRef<Path>>(path: P, keypair: &Keypair) -> Result<(), Error> {
+    let mut bytes = Zeroizing::new(Vec::with_capacity(1 + ED25519_KEYPAIR_LEN));
+    match keypair {
+        Keypair::Ed25519(keypair) => {
+            bytes.push(ED25519_TAG);
+            let encoded = Zeroizing::new(keypair.encode());
+            bytes.extend_from_slice(encoded.as_slice());
+        }
+        Keypair::Secp256k1(keypair) => {
+            bytes.push(SECP256K1_TAG);
+            let secret = Zeroizing::new(keypair.secret().to_bytes());
+            bytes.extend_from_slice(secret.as_slice());
+        }
+        #[allow(unreachable_patterns)]
+        _ => return Err(Error::UnsupportedKeyType),
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options
+        .open(path)
+        .map_err(Error::IdentityFileError)?
+        .write_all(&bytes)
+        .map_err(Error::IdentityFileError)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use libp2p::core::PeerId;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}-{}", name, rand::random::<u64>()))
+    }
+
+    #[test]
+    fn test_load_or_generate_keypair() {
+        for key_type in [KeyType::Ed25519, KeyType::Secp256k1] {
+            let path = temp_path("rust-libp2p-nym-identity");
+            let keypair = load_or_generate_keypair(&path, key_type).unwrap();
+            let reloaded = load_or_generate_keypair(&path, KeyType::default()).unwrap();
+            assert_eq!(
+                PeerId::from(keypair.public()),
+                PeerId::from(reloaded.public())
+            );
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_load_invalid_keypair() {
+        let path = temp_path("rust-libp2p-nym-invalid-identity");
+        fs::write(&path, [ED25519_TAG, 1, 2, 3]).unwrap();
+        assert!(matches!(
+            load_keypair(&path).unwrap_err(),
+            Error::InvalidIdentityFile
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_decode_keypair_scrubs_bytes() {
+        let ed25519 = Keypair::generate_ed25519();
+        let Keypair::Ed25519(ed25519_keypair) = &ed25519 else {
+            unreachable!()
+        };
+        let mut bytes = [&[ED25519_TAG][..], &ed25519_keypair.encode()[..]].concat();
+        let decoded = decode_keypair(&mut bytes).unwrap();
+        assert_eq!(
+            PeerId::from(decoded.public()),
+            PeerId::from(ed25519.public())
+        );
+        assert!(bytes.iter().all(|b| *b == 0));
+
+        let secp256k1 = Keypair::generate_secp256k1();
+        let Keypair::Secp256k1(secp256k1_keypair) = &secp256k1 else {
+            unreachable!()
+        };
+        let secret = secp256k1_keypair.secret().to_bytes();
+        let mut bytes = [&[SECP256K1_TAG][..], &secret[..]].concat();
+        decode_keypair(&mut bytes).unwrap();
+        assert!(bytes.iter().all(|b| *b == 0));
+
+        // including when they don't hold a valid keypair
+        let mut bytes = [&[ED25519_TAG][..], &[0xab; 10][..]].concat();
+        assert!(matches!(
+            decode_keypair(&mut bytes),
+            Err(Error::InvalidIdentityFile)
+        ));
+        assert!(bytes.iter().all(|b| *b == 0));
+    }
+}
@@ -0,0 +1,163 @@
+//! Interoperability tests against the Go implementation of the transport, to catch changes
+//! to the wire format in `message.rs` that break compatibility between the two.
+//!
+//! The Go peer runs in a docker container, along with its own nym-client. Build its image
+//! from the Go implementation, and set `GO_LIBP2P_NYM_IMAGE` to it, preferably pinned by
+//! digest, eg. `go-libp2p-nym-interop@sha256:<digest>`, so that a rebuilt image can't
+//! change what's tested; `name:tag` works too. Without it the tests are skipped. The
+//! nym-clients run the `chainsafe/nym:1.1.12` image built by `build-docker.sh`, like the
+//! other tests that need one. The container is given:
+//! - `NYM_ID`: the id of its nym-client
+//! - `DIAL_ADDR`: the multiaddress of the Rust peer, which it dials
+//! - `INTEROP_MODE`: `handshake` to only establish the connection, or `echo` to also write
+//!   back everything it reads on each substream the Rust peer opens, until it's closed;
+//!   it reports success once such a substream is closed
+//!
+//! and prints `interop: <mode> ok` once it's done.
+//!
+//! Run with `GO_LIBP2P_NYM_IMAGE=<image> cargo test --features interop interop`.
+
+use futures::{future::poll_fn, AsyncReadExt, AsyncWriteExt};
+use libp2p::core::{
+    identity::Keypair,
+    muxing::StreamMuxerExt,
+    transport::{Transport, TransportEvent},
+    Multiaddr,
+};
+use std::pin::Pin;
+use testcontainers::{clients::Cli, core::WaitFor, images::generic::GenericImage, Container};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::time::{timeout, Duration};
+
+use crate::connection::Connection;
+use crate::test_utils::create_nym_client;
+use crate::transport::{NymTransport, Upgrade};
+
+/// GO_PEER_IMAGE_VAR names the image of the Go peer.
+const GO_PEER_IMAGE_VAR: &str = "GO_LIBP2P_NYM_IMAGE";
+
+/// how long the Go peer has to connect once it's started.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// go_peer_image returns the image of the Go peer, or None if it isn't set, in which case
+/// the tests are skipped.
+fn go_peer_image() -> Option<String> {
+    let image = std::env::var(GO_PEER_IMAGE_VAR).ok();
+    if image.is_none() {
+        eprintln!("skipping interop test: {GO_PEER_IMAGE_VAR} isn't set");
+    }
+    image
+}
+
+/// run_go_peer starts the Go peer from the given image in the given mode, dialing the
+/// address, and returns once it reports it's done. It blocks, so the Rust peer must be
+/// driven by another task.
+fn run_go_peer<'a>(
+    docker_client: &'a Cli,
+    image: &str,
+    mode: &str,
+    dial_addr: &Multiaddr,
+) -> Container<'a, GenericImage> {
+    // testcontainers joins them with a colon again, so this also takes `name@sha256:digest`
+    let (name, tag) = image.rsplit_once(':').unwrap_or((image, "latest"));
+    let go_image = GenericImage::new(name, tag)
+        .with_env_var("NYM_ID", format!("interop_go_{}", rand::random::<u64>()))
+        .with_env_var("DIAL_ADDR", dial_addr.to_string())
+        .with_env_var("INTEROP_MODE", mode)
+        .with_wait_for(WaitFor::message_on_stdout(format!("interop: {mode} ok")));
+    tokio::task::block_in_place(|| docker_client.run(go_image))
+}
+
+/// drive_transport polls the transport until it's dropped, passing on the connection
+/// requests it receives.
+async fn drive_transport(mut transport: NymTransport, upgrade_tx: UnboundedSender<Upgrade>) {
+    loop {
+        if let TransportEvent::Incoming { upgrade, .. } =
+            poll_fn(|cx| Pin::new(&mut transport).poll(cx)).await
+        {
+            upgrade_tx.send(upgrade).ok();
+        }
+    }
+}
+
+/// drive_connection polls the connection, which handles the substream messages it
+/// receives, until it fails.
+async fn drive_connection(mut connection: Connection) {
+    while poll_fn(|cx| connection.poll_unpin(cx)).await.is_ok() {}
+}
+
+/// listen starts a Rust peer on its own nym-client, and returns its address and a channel
+/// of the connection requests it receives.
+async fn listen(
+    docker_client: &Cli,
+    nym_id: &str,
+) -> (
+    Container<'_, GenericImage>,
+    Multiaddr,
+    UnboundedReceiver<Upgrade>,
+) {
+    let (container, uri) = create_nym_client(docker_client, nym_id);
+    let transport = NymTransport::new(&uri, Keypair::generate_ed25519())
+        .await
+        .unwrap();
+    let listen_addr = transport.listen_addr.clone();
+    let (upgrade_tx, upgrade_rx) = unbounded_channel();
+    tokio::spawn(drive_transport(transport, upgrade_tx));
+    (container, listen_addr, upgrade_rx)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_interop_handshake() {
+    let Some(image) = go_peer_image() else {
+        return;
+    };
+    let docker_client = Cli::default();
+    let (_container, listen_addr, mut upgrade_rx) =
+        listen(&docker_client, "test_interop_handshake").await;
+
+    let _go_container = run_go_peer(&docker_client, &image, "handshake", &listen_addr);
+
+    // the Go peer only reports success once it got our ConnectionResponse, so by now we
+    // have parsed its ConnectionRequest
+    let upgrade = timeout(CONNECT_TIMEOUT, upgrade_rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    upgrade.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_interop_echo() {
+    let Some(image) = go_peer_image() else {
+        return;
+    };
+    let docker_client = Cli::default();
+    let (_container, listen_addr, mut upgrade_rx) =
+        listen(&docker_client, "test_interop_echo").await;
+
+    let echo = tokio::spawn(async move {
+        let upgrade = timeout(CONNECT_TIMEOUT, upgrade_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let (_, mut connection) = upgrade.await.unwrap();
+        let mut substream = poll_fn(|cx| connection.poll_outbound_unpin(cx))
+            .await
+            .unwrap();
+        tokio::spawn(drive_connection(connection));
+
+        // written as several messages, so the nonces and ordering of substream data are
+        // exercised too
+        let payload: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        for chunk in payload.chunks(1024) {
+            substream.write_all(chunk).await.unwrap();
+        }
+        let mut echoed = vec![0u8; payload.len()];
+        substream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, payload);
+        substream.close().await.unwrap();
+    });
+
+    let _go_container = run_go_peer(&docker_client, &image, "echo", &listen_addr);
+    echo.await.unwrap();
+}
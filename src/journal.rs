@@ -0,0 +1,356 @@
+use nym_sphinx::addressing::clients::Recipient;
+use parking_lot::Mutex;
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::warn;
+
+use crate::error::Error;
+use crate::message::{header_len, parse_message_data, InboundMessage};
+use crate::stats::unix_micros;
+
+const RECIPIENT_LENGTH: usize = Recipient::LEN;
+
+/// JournalConfig configures the message journal. The journal is written to `path`; once
+/// it would grow beyond `max_file_size` bytes, it's moved to `path.1` (and `path.1` to
+/// `path.2` and so on), keeping at most `max_files` files in total.
+#[derive(Clone, Debug)]
+pub struct JournalConfig {
+    pub path: PathBuf,
+    pub max_file_size: u64,
+    pub max_files: usize,
+    /// if set, only message headers are recorded, without application data
+    pub headers_only: bool,
+}
+
+impl JournalConfig {
+    /// new returns a config for a journal at the given path, with files of up to 10 MiB
+    /// and up to 5 files.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        JournalConfig {
+            path: path.as_ref().to_path_buf(),
+            max_file_size: 10 * 1024 * 1024,
+            max_files: 5,
+            headers_only: false,
+        }
+    }
+}
+
+/// JournalDirection is whether a message was received from or written to the mixnet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum JournalDirection {
+    Inbound,
+    Outbound,
+}
+
+/// JournalRecord is a single wire message recorded in the journal.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JournalRecord {
+    pub direction: JournalDirection,
+    /// microseconds since the unix epoch
+    pub timestamp: u64,
+    /// the recipient of an outbound message; the mixnet doesn't tell who sent inbound ones
+    pub remote_address: Option<Recipient>,
+    /// the length of the message, which is more than `bytes` if only its header was kept
+    pub len: u32,
+    pub bytes: Vec<u8>,
+}
+
+impl JournalRecord {
+    /// is_complete returns true if the whole message was recorded, not just its header.
+    pub fn is_complete(&self) -> bool {
+        self.bytes.len() == self.len as usize
+    }
+
+    /// parse decodes the recorded message, as the transport would on receiving it.
+    /// Records with only their header kept may fail to parse.
+    pub fn parse(&self) -> Result<InboundMessage, Error> {
+        parse_message_data(&self.bytes)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(23 + RECIPIENT_LENGTH + self.bytes.len());
+        bytes.push(match self.direction {
+            JournalDirection::Inbound => 0u8,
+            JournalDirection::Outbound => 1u8,
+        });
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        match self.remote_address {
+            Some(address) => {
+                bytes.push(1u8);
+                bytes.extend_from_slice(&address.to_bytes());
+            }
+            None => bytes.push(0u8),
+        }
+        bytes.extend_from_slice(&self.len.to_be_bytes());
+        bytes.extend_from_slice(&(self.bytes.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.bytes);
+        bytes
+    }
+
+    /// read_from reads the next record, or returns None at the end of the journal.
+    fn read_from<R: Read>(reader: &mut R) -> Result<Option<Self>, Error> {
+        let mut direction = [0u8; 1];
+        match reader.read_exact(&mut direction) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(Error::JournalError(e)),
+        }
+        let direction = match direction[0] {
+            0 => JournalDirection::Inbound,
+            1 => JournalDirection::Outbound,
+            _ => return Err(Error::InvalidJournalRecord),
+        };
+
+        let mut header = [0u8; 9];
+        reader
+            .read_exact(&mut header)
+            .map_err(Error::JournalError)?;
+        let timestamp = u64::from_be_bytes(header[0..8].try_into().unwrap());
+        let remote_address = match header[8] {
+            0 => None,
+            1 => {
+                let mut address = [0u8; RECIPIENT_LENGTH];
+                reader
+                    .read_exact(&mut address)
+                    .map_err(Error::JournalError)?;
+                Some(Recipient::try_from_bytes(address).map_err(Error::InvalidRecipientBytes)?)
+            }
+            _ => return Err(Error::InvalidJournalRecord),
+        };
+
+        let mut lengths = [0u8; 8];
+        reader
+            .read_exact(&mut lengths)
+            .map_err(Error::JournalError)?;
+        let len = u32::from_be_bytes(lengths[0..4].try_into().unwrap());
+        let recorded_len = u32::from_be_bytes(lengths[4..8].try_into().unwrap());
+        if recorded_len > len {
+            return Err(Error::InvalidJournalRecord);
+        }
+        let mut bytes = vec![0u8; recorded_len as usize];
+        reader.read_exact(&mut bytes).map_err(Error::JournalError)?;
+
+        Ok(Some(JournalRecord {
+            direction,
+            timestamp,
+            remote_address,
+            len,
+            bytes,
+        }))
+    }
+}
+
+/// JournalReader reads the records of a journal file, oldest first.
+pub struct JournalReader {
+    reader: BufReader<File>,
+}
+
+impl JournalReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = File::open(path).map_err(Error::JournalError)?;
+        Ok(JournalReader {
+            reader: BufReader::new(file),
+        })
+    }
+}
+
+impl Iterator for JournalReader {
+    type Item = Result<JournalRecord, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        JournalRecord::read_from(&mut self.reader).transpose()
+    }
+}
+
+/// JournalWriter appends records to the journal file, rotating it when it's full.
+struct JournalWriter {
+    config: JournalConfig,
+    file: File,
+    size: u64,
+}
+
+impl JournalWriter {
+    fn open(config: JournalConfig) -> Result<Self, Error> {
+        if config.max_files == 0 || config.max_file_size == 0 {
+            return Err(Error::InvalidConfig(
+                "journal max_files and max_file_size must not be zero",
+            ));
+        }
+        let file = open_append(&config.path)?;
+        let size = file.metadata().map_err(Error::JournalError)?.len();
+        Ok(JournalWriter { config, file, size })
+    }
+
+    fn write(&mut self, record: &JournalRecord) -> Result<(), Error> {
+        let bytes = record.to_bytes();
+        if self.size > 0 && self.size + bytes.len() as u64 > self.config.max_file_size {
+            self.rotate()?;
+        }
+        // a single write per record, so a crash doesn't leave half a record behind
+        self.file.write_all(&bytes).map_err(Error::JournalError)?;
+        self.size += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// rotate moves each journal file one number up, dropping the oldest, and starts
+    /// a new file.
+    fn rotate(&mut self) -> Result<(), Error> {
+        let path = &self.config.path;
+        let max_files = self.config.max_files;
+        if max_files > 1 {
+            let oldest = rotated_path(path, max_files - 1);
+            if oldest.exists() {
+                fs::remove_file(&oldest).map_err(Error::JournalError)?;
+            }
+            for i in (1..max_files - 1).rev() {
+                let from = rotated_path(path, i);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(path, i + 1)).map_err(Error::JournalError)?;
+                }
+            }
+            fs::rename(path, rotated_path(path, 1)).map_err(Error::JournalError)?;
+        } else {
+            fs::remove_file(path).map_err(Error::JournalError)?;
+        }
+
+        self.file = open_append(path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File, Error> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(Error::JournalError)
+}
+
+/// rotated_path returns the path of the journal file rotated `n` times.
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{}", n));
+    PathBuf::from(path)
+}
+
+/// Journal is a shared handle to the message journal, used by the mixnet task.
+#[derive(Clone)]
+pub(crate) struct Journal(Arc<Mutex<JournalWriter>>);
+
+impl fmt::Debug for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Journal")
+            .field(&self.0.lock().config.path)
+            .finish()
+    }
+}
+
+impl Journal {
+    pub(crate) fn open(config: JournalConfig) -> Result<Self, Error> {
+        Ok(Journal(Arc::new(Mutex::new(JournalWriter::open(config)?))))
+    }
+
+    /// record appends a wire message to the journal. Failures are logged rather than
+    /// returned, so the journal never gets in the way of the traffic itself.
+    pub(crate) fn record(
+        &self,
+        direction: JournalDirection,
+        remote_address: Option<Recipient>,
+        message: &[u8],
+    ) {
+        let mut writer = self.0.lock();
+        let recorded_len = if writer.config.headers_only {
+            header_len(message)
+        } else {
+            message.len()
+        };
+        let record = JournalRecord {
+            direction,
+            timestamp: unix_micros(),
+            remote_address,
+            len: message.len() as u32,
+            bytes: message[..recorded_len].to_vec(),
+        };
+        if let Err(e) = writer.write(&record) {
+            warn!("failed to write journal record: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::mock::random_recipient;
+
+    #[test]
+    fn test_journal_rotates_and_reads_back() {
+        let dir = std::env::temp_dir().join(format!("journal-{}", unix_micros()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("messages.journal");
+        let address = random_recipient();
+
+        let journal = Journal::open(JournalConfig {
+            max_file_size: 300,
+            max_files: 2,
+            ..JournalConfig::new(&path)
+        })
+        .unwrap();
+        for i in 0..4u8 {
+            journal.record(JournalDirection::Outbound, Some(address), &[i; 100]);
+        }
+        journal.record(JournalDirection::Inbound, None, &[6u8, 4, 4, 4]);
+
+        // each outbound record takes up more than half a file
+        let records: Vec<JournalRecord> = JournalReader::open(&path)
+            .unwrap()
+            .map(|record| record.unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].bytes, vec![3u8; 100]);
+        assert_eq!(records[0].remote_address, Some(address));
+        assert_eq!(records[1].direction, JournalDirection::Inbound);
+        assert!(records[1].is_complete());
+        assert_eq!(
+            records[1].parse().unwrap().broadcast_payload(),
+            Some(&[4u8; 3][..])
+        );
+
+        let rotated: Vec<JournalRecord> = JournalReader::open(rotated_path(&path, 1))
+            .unwrap()
+            .map(|record| record.unwrap())
+            .collect();
+        assert_eq!(rotated.len(), 1);
+        assert_eq!(rotated[0].bytes, vec![2u8; 100]);
+        assert!(!rotated_path(&path, 2).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_journal_headers_only() {
+        let dir = std::env::temp_dir().join(format!("journal-headers-{}", unix_micros()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("messages.journal");
+
+        let journal = Journal::open(JournalConfig {
+            headers_only: true,
+            ..JournalConfig::new(&path)
+        })
+        .unwrap();
+        // a broadcast's header is just its type
+        journal.record(JournalDirection::Inbound, None, &[6u8, 1, 2, 3]);
+
+        let record = JournalReader::open(&path).unwrap().next().unwrap().unwrap();
+        assert_eq!(record.bytes, vec![6u8]);
+        assert_eq!(record.len, 4);
+        assert!(!record.is_complete());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,96 @@
+pub mod audit;
+pub mod backend;
+pub mod breaker;
+pub mod budget;
+pub mod capture;
+pub mod config;
 pub(crate) mod connection;
+pub mod dial;
+pub mod directory;
+pub mod doctor;
+pub(crate) mod encode;
+pub mod endpoint;
 pub mod error;
+pub mod events;
+pub mod fallback;
+pub mod filter;
+pub(crate) mod fragment;
+pub mod framing;
+pub(crate) mod handshake;
+pub mod identity;
+#[cfg(all(test, feature = "interop"))]
+mod interop;
+pub mod journal;
+pub mod lifecycle;
+pub mod listener;
 pub(crate) mod message;
-pub(crate) mod mixnet;
+pub mod metrics;
+pub mod mixnet;
+pub mod peerstore;
+pub mod pinned;
+pub(crate) mod protocol;
 pub(crate) mod queue;
+pub mod record;
+pub mod registry;
+pub mod rotation;
+pub mod shared;
+pub mod spec;
+pub mod stats;
 pub mod substream;
+#[cfg(test)]
+mod swarm_test;
 pub mod test_utils;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(not(feature = "testing"))]
+#[allow(dead_code)]
+pub(crate) mod testing;
 pub mod transport;
 
 /// The deafult timeout secs for [`transport::Upgrade`] future.
 const DEFAULT_HANDSHAKE_TIMEOUT_SECS: u64 = 5;
+
+/// The default number of websocket connections used to write outbound messages.
+const DEFAULT_SENDER_WORKERS: usize = 1;
+
+/// The default number of tasks verifying inbound connection requests.
+const DEFAULT_HANDSHAKE_WORKERS: usize = 4;
+
+/// The default number of inbound connection requests waiting for a handshake worker, beyond
+/// which they're declined.
+const DEFAULT_MAX_QUEUED_HANDSHAKES: usize = 256;
+
+/// The default number of dials whose handshakes may be in flight at once.
+const DEFAULT_MAX_CONCURRENT_DIALS: usize = 64;
+
+/// The default number of dials waiting for one of the others to finish, beyond which
+/// further dials fail.
+const DEFAULT_MAX_QUEUED_DIALS: usize = 256;
+
+/// The default length of application data above which an outbound message is encoded on
+/// the blocking thread pool rather than the mixnet task.
+const DEFAULT_ENCODE_OFFLOAD_THRESHOLD: usize = 64 * 1024;
+
+/// The default number of inbound messages waiting to be handled by the transport, at which
+/// it stops reading from the mixnet until half of them have been handled.
+const DEFAULT_INBOUND_HIGH_WATERMARK: usize = 4096;
+
+/// The default longest an inbound message is held back to be passed on to the transport
+/// with others, if inbound messages are batched.
+const DEFAULT_INBOUND_BATCH_DELAY: std::time::Duration = std::time::Duration::from_millis(5);
+
+/// The default number of substream writes that may wait to be written to the mixnet, beyond
+/// which further writes wait.
+const DEFAULT_OUTBOUND_CAPACITY: usize = 1024;
+
+/// The default number of keepalives in a row a connection's remote peer may leave
+/// unanswered before the connection is dropped, if keepalives are enabled.
+const DEFAULT_KEEPALIVE_MAX_MISSED: u32 = 3;
+
+/// The default longest each of the checks run by [`transport::NymTransport::diagnose`]
+/// may take.
+const DEFAULT_DIAGNOSTIC_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// The user agent announced in connection handshakes by
+/// [`transport::NymTransport::with_user_agent`]: this crate's name and version.
+pub const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
@@ -0,0 +1,38 @@
+use std::sync::Arc;
+use tokio::sync::watch;
+
+use crate::mixnet::MixnetOptions;
+
+/// LifecycleHandle pauses and resumes the transport's networking along with the
+/// application's lifecycle, eg. when a mobile app goes to the background and back; see
+/// [`NymTransport::pause`](crate::transport::NymTransport::pause). It can be cloned and
+/// kept around after the transport is moved into a swarm.
+#[derive(Clone, Debug)]
+pub struct LifecycleHandle {
+    mixnet_options_tx: Arc<watch::Sender<MixnetOptions>>,
+}
+
+impl LifecycleHandle {
+    pub(crate) fn new(mixnet_options_tx: Arc<watch::Sender<MixnetOptions>>) -> Self {
+        Self { mixnet_options_tx }
+    }
+
+    /// pause lets go of the connection to the mixnet and stops the transport's timers,
+    /// keeping its connections. It does nothing if already paused.
+    pub fn pause(&self) {
+        self.mixnet_options_tx
+            .send_if_modified(|options| !std::mem::replace(&mut options.paused, true));
+    }
+
+    /// resume reconnects to the mixnet and restarts the transport's timers. It does
+    /// nothing if not paused.
+    pub fn resume(&self) {
+        self.mixnet_options_tx
+            .send_if_modified(|options| std::mem::replace(&mut options.paused, false));
+    }
+
+    /// is_paused returns true between pause and resume.
+    pub fn is_paused(&self) -> bool {
+        self.mixnet_options_tx.borrow().paused
+    }
+}
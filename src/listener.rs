@@ -0,0 +1,253 @@
+use futures::StreamExt;
+use libp2p::core::PeerId;
+use nym_sphinx::addressing::clients::Recipient;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tracing::debug;
+
+use crate::error::Error;
+use crate::message::{
+    ConnectionDeniedMessage, ConnectionId, ConnectionMessage, DenialReason, Message,
+    OutboundMessage,
+};
+use crate::mixnet::{InboundStream, MixnetConnection};
+use crate::transport::Upgrade;
+
+/// Listener accepts connection requests from the low-level mixnet API, without a libp2p
+/// Swarm, much like a `TcpListener`.
+///
+/// It takes over the inbound stream: messages other than connection requests are
+/// discarded, so use [`InboundStream`] directly to handle both.
+pub struct Listener {
+    connection: MixnetConnection,
+    inbound: InboundStream,
+    peer_id: PeerId,
+}
+
+impl Listener {
+    /// new returns a listener which answers connection requests as the given peer,
+    /// eg. with the handles returned by [`open_with_backend`](crate::mixnet::open_with_backend).
+    pub fn new(connection: MixnetConnection, inbound: InboundStream, peer_id: PeerId) -> Self {
+        Listener {
+            connection,
+            inbound,
+            peer_id,
+        }
+    }
+
+    /// accept waits for the next connection request. It fails once the mixnet task has
+    /// stopped.
+    pub async fn accept(&mut self) -> Result<IncomingConnection, Error> {
+        while let Some(msg) = self.inbound.next().await {
+            let msg = match msg.0 {
+                Message::ConnectionRequest(msg) => msg,
+                msg => {
+                    debug!("listener discarding inbound {:?}", msg.kind());
+                    continue;
+                }
+            };
+            let ConnectionMessage {
+                id,
+                peer_id,
+                recipient: Some(sender),
+                target,
+                handshake_payload,
+                user_agent,
+                ..
+            } = msg
+            else {
+                debug!("listener discarding connection request without a sender");
+                continue;
+            };
+            if matches!(target, Some(target) if target != self.peer_id) {
+                debug!("listener discarding connection request for another listener key");
+                continue;
+            }
+
+            return Ok(IncomingConnection {
+                id,
+                peer_id,
+                sender,
+                connection: self.connection.clone(),
+                local_peer_id: self.peer_id,
+                handshake_payload,
+                user_agent,
+            });
+        }
+        Err(Error::RecvError)
+    }
+}
+
+/// IncomingConnection is a connection request received by a [`Listener`]. The dialer waits
+/// until it's accepted or denied; if it's dropped instead, the dial times out.
+#[derive(Debug)]
+pub struct IncomingConnection {
+    id: ConnectionId,
+    peer_id: PeerId,
+    sender: Recipient,
+    connection: MixnetConnection,
+    local_peer_id: PeerId,
+    handshake_payload: Option<Vec<u8>>,
+    user_agent: Option<String>,
+}
+
+impl IncomingConnection {
+    /// peer_id returns the peer ID the dialer claims in its request.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// sender returns the Nym address of the dialer.
+    pub fn sender(&self) -> Recipient {
+        self.sender
+    }
+
+    /// handshake_payload returns the opaque data the dialer attached to the request, if any.
+    pub fn handshake_payload(&self) -> Option<&[u8]> {
+        self.handshake_payload.as_deref()
+    }
+
+    /// user_agent returns the name and version of the dialer's implementation, if it
+    /// announced them.
+    pub fn user_agent(&self) -> Option<&str> {
+        self.user_agent.as_deref()
+    }
+
+    /// accept completes the handshake, so the dial succeeds.
+    pub fn accept(self) -> Result<(), Error> {
+        let resp = ConnectionMessage {
+            peer_id: self.local_peer_id,
+            recipient: None,
+            target: None,
+            id: self.id,
+            extensions: 0,
+            handshake_payload: None,
+            timestamps: vec![],
+            connection_ref: None,
+            user_agent: None,
+            reply_tag: None,
+        };
+        self.connection.send(OutboundMessage::new(
+            Message::ConnectionResponse(resp),
+            self.sender,
+        ))
+    }
+
+    /// deny declines the request, so the dial fails with
+    /// [`Error::ConnectionDenied`] and the given reason.
+    pub fn deny(self, reason: DenialReason) -> Result<(), Error> {
+        let denied = ConnectionDeniedMessage {
+            id: self.id,
+            reason,
+        };
+        self.connection.send(OutboundMessage::new(
+            Message::ConnectionDenied(denied),
+            self.sender,
+        ))
+    }
+}
+
+/// AcceptorMatch selects the inbound connections an [`Acceptor`] takes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AcceptorMatch {
+    /// connections from the given peer
+    Peer(PeerId),
+    /// connections whose handshake payload starts with the given bytes
+    HandshakePayloadPrefix(Vec<u8>),
+}
+
+impl AcceptorMatch {
+    /// matches returns whether a connection request from the given peer, with the given
+    /// handshake payload, is selected.
+    pub(crate) fn matches(&self, peer_id: &PeerId, handshake_payload: Option<&[u8]>) -> bool {
+        match self {
+            AcceptorMatch::Peer(selected) => selected == peer_id,
+            AcceptorMatch::HandshakePayloadPrefix(prefix) => {
+                matches!(handshake_payload, Some(payload) if payload.starts_with(prefix))
+            }
+        }
+    }
+}
+
+/// Acceptor takes the inbound connections of a
+/// [`NymTransport`](crate::transport::NymTransport) that its [`AcceptorMatch`] selects,
+/// before they're handed to the swarm, so an application can speak a custom protocol over
+/// the same Nym address as libp2p; see
+/// [`NymTransport::acceptor`](crate::transport::NymTransport::acceptor).
+pub struct Acceptor {
+    upgrades_rx: UnboundedReceiver<Upgrade>,
+}
+
+impl Acceptor {
+    pub(crate) fn new(upgrades_rx: UnboundedReceiver<Upgrade>) -> Self {
+        Acceptor { upgrades_rx }
+    }
+
+    /// accept waits for the next connection selected for this acceptor. Awaiting the
+    /// returned upgrade completes the handshake and returns the connection, which is never
+    /// seen by the swarm; dropping it declines the request. It fails once the transport is
+    /// dropped.
+    pub async fn accept(&mut self) -> Result<Upgrade, Error> {
+        self.upgrades_rx.recv().await.ok_or(Error::RecvError)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::future::poll_fn;
+    use libp2p::core::{identity::Keypair, transport::TransportEvent, Multiaddr, Transport};
+    use std::pin::Pin;
+    use tokio::time::Duration;
+
+    use super::*;
+    use crate::backend::mock::MockMixnet;
+    use crate::mixnet::open_with_backend;
+    use crate::transport::NymTransport;
+
+    #[tokio::test]
+    async fn test_listener_accept_and_deny() {
+        let mixnet = MockMixnet::new();
+        let backend = mixnet.new_backend();
+        let listen_addr: Multiaddr = format!("/nym/{}", backend.self_address()).parse().unwrap();
+        let (connection, inbound) = open_with_backend(backend);
+        let listener_peer_id = PeerId::random();
+        let mut listener = Listener::new(connection, inbound, listener_peer_id);
+
+        let backend = mixnet.new_backend();
+        let dialer_address = backend.self_address();
+        let mut dialer = NymTransport::new_with_backend(backend, Keypair::generate_ed25519())
+            .unwrap()
+            .with_timeout(Duration::from_secs(1));
+        assert!(matches!(
+            poll_fn(|cx| Pin::new(&mut dialer).poll(cx)).await,
+            TransportEvent::NewAddress { .. }
+        ));
+
+        for deny in [false, true] {
+            // the request is sent once the dial is polled
+            let dial = tokio::spawn(dialer.dial(listen_addr.clone()).unwrap());
+            let incoming = listener.accept().await.unwrap();
+            assert_eq!(incoming.peer_id(), dialer.peer_id());
+            assert_eq!(incoming.sender(), dialer_address);
+            if deny {
+                incoming.deny(DenialReason::ConnectionLimit).unwrap();
+            } else {
+                incoming.accept().unwrap();
+            }
+
+            let res = tokio::select! {
+                res = dial => res.unwrap(),
+                event = poll_fn(|cx| Pin::new(&mut dialer).poll(cx)) => {
+                    panic!("unexpected dialer event {:?}", event)
+                }
+            };
+            match res {
+                Ok((peer_id, _)) if !deny => assert_eq!(peer_id, listener_peer_id),
+                Err(Error::ConnectionDenied(DenialReason::ConnectionLimit)) if deny => {}
+                res => panic!(
+                    "unexpected dial result {:?}",
+                    res.map(|(peer_id, _)| peer_id)
+                ),
+            }
+        }
+    }
+}
@@ -1,15 +1,41 @@
-use libp2p::core::PeerId;
+use libp2p::core::{
+    identity::{Keypair, PublicKey},
+    PeerId,
+};
 use nym_sphinx::addressing::clients::Recipient;
 use rand_core::{OsRng, RngCore};
-use std::fmt::{Debug, Formatter};
+use std::{
+    collections::VecDeque,
+    fmt::{Debug, Display, Formatter},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{oneshot, OwnedSemaphorePermit},
+    time::Instant,
+};
 
+use crate::backend::{PacketSize, SenderTag};
+use crate::budget::Reservation;
 use crate::error::Error;
+use crate::spec::{self, extension, recipient_flag, substream_op, ADDRESS_UPDATE_DOMAIN};
+use crate::stats::unix_micros;
 
-const RECIPIENT_LENGTH: usize = Recipient::LEN;
-const CONNECTION_ID_LENGTH: usize = 32;
-const SUBSTREAM_ID_LENGTH: usize = 32;
+pub(crate) use crate::spec::PROTOCOL_VERSION;
 
-const NONCE_BYTES_LEN: usize = 8; // length of u64
+const RECIPIENT_LENGTH: usize = spec::RECIPIENT_LEN;
+const CONNECTION_ID_LENGTH: usize = spec::CONNECTION_ID_LEN;
+const SUBSTREAM_ID_LENGTH: usize = spec::SUBSTREAM_ID_LEN;
+
+const NONCE_BYTES_LEN: usize = spec::NONCE_LEN;
+const EPOCH_BYTES_LEN: usize = spec::EPOCH_LEN;
+const PUBLIC_KEY_LENGTH_BYTES_LEN: usize = spec::PUBLIC_KEY_LENGTH_LEN;
+const TIMESTAMP_BYTES_LEN: usize = spec::TIMESTAMP_LEN;
+const HANDSHAKE_PAYLOAD_LENGTH_BYTES_LEN: usize = spec::HANDSHAKE_PAYLOAD_LENGTH_LEN;
+const USER_AGENT_LENGTH_BYTES_LEN: usize = spec::USER_AGENT_LENGTH_LEN;
 const MIN_CONNECTION_MESSAGE_LEN: usize = CONNECTION_ID_LENGTH + NONCE_BYTES_LEN;
 
 /// ConnectionId is a unique, randomly-generated per-connection ID that's used to
@@ -24,7 +50,7 @@ impl ConnectionId {
         ConnectionId(bytes)
     }
 
-    fn from_bytes(bytes: &[u8]) -> Self {
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Self {
         let mut id = [0u8; 32];
         id[..].copy_from_slice(&bytes[0..CONNECTION_ID_LENGTH]);
         ConnectionId(id)
@@ -68,6 +94,21 @@ pub(crate) enum Message {
     ConnectionRequest(ConnectionMessage),
     ConnectionResponse(ConnectionMessage),
     TransportMessage(TransportMessage),
+    AddressUpdate(AddressUpdateMessage),
+    Ping(PingMessage),
+    Pong(PongMessage),
+    /// application bytes sent to many recipients at once; not tied to a connection.
+    Broadcast(Vec<u8>),
+    ReachabilityRequest(ReachabilityRequestMessage),
+    DialBack(DialBackMessage),
+    ConnectionDenied(ConnectionDeniedMessage),
+    EchoRequest(EchoRequestMessage),
+    EchoReply(EchoReplyMessage),
+    Compact(CompactMessage),
+    ConnectionClose(ConnectionCloseMessage),
+    CloseAck(CloseAckMessage),
+    /// application bytes sent to a single recipient; not tied to a connection.
+    Datagram(Vec<u8>),
 }
 
 /// ConnectionMessage is exchanged to open a new connection.
@@ -78,6 +119,30 @@ pub(crate) struct ConnectionMessage {
     /// recipient is the sender's Nym address.
     /// only required if this is a ConnectionRequest.
     pub(crate) recipient: Option<Recipient>,
+    /// target is the listener key of the transport a ConnectionRequest is for, ie. its
+    /// peer ID, if the dialed address includes one. Listeners sharing a Nym client are
+    /// told apart by it.
+    pub(crate) target: Option<PeerId>,
+    /// extensions is a bitmask of the [`extension`](crate::spec::extension)s the dialer
+    /// supports, in a ConnectionRequest, or of those of them the connection uses, in a
+    /// ConnectionResponse.
+    pub(crate) extensions: u8,
+    /// handshake_payload is opaque data the dialer attaches to a ConnectionRequest.
+    pub(crate) handshake_payload: Option<Vec<u8>>,
+    /// timestamps are sent if the message lists the clock extension, in microseconds since
+    /// the unix epoch: in a ConnectionRequest when it was sent, and in a ConnectionResponse
+    /// when the request was sent and received and when the response was sent.
+    pub(crate) timestamps: Vec<u64>,
+    /// connection_ref is sent if the message lists the compact ID extension: the
+    /// reference the sender wants the connection's messages addressed to it with.
+    pub(crate) connection_ref: Option<u64>,
+    /// user_agent is sent if the message lists the user agent extension: the name and
+    /// version of the sender's implementation.
+    pub(crate) user_agent: Option<String>,
+    /// reply_tag is the sender tag an anonymous ConnectionRequest, which has no
+    /// recipient, arrived with; the connection's messages are sent as replies to it.
+    /// It's not part of the message, the Nym client hands it over along with it.
+    pub(crate) reply_tag: Option<SenderTag>,
 }
 
 /// TransportMessage is sent over a connection after establishment.
@@ -93,17 +158,670 @@ pub(crate) struct TransportMessage {
     pub(crate) id: ConnectionId,
 }
 
+/// AddressUpdateMessage is sent over a connection when the sender's Nym address changes,
+/// so the remote peer redirects traffic to the new address without tearing down the
+/// connection. It's signed with the sender's libp2p key, so that only the peer at the other
+/// end of the connection can redirect it.
+#[derive(Debug)]
+pub(crate) struct AddressUpdateMessage {
+    pub(crate) id: ConnectionId,
+    /// increments every time the sender's address changes, so that an update that
+    /// arrives late (Nym doesn't guarantee ordering) doesn't undo a newer one.
+    pub(crate) epoch: u64,
+    /// the sender's new Nym address
+    pub(crate) recipient: Recipient,
+    pub(crate) public_key: PublicKey,
+    pub(crate) signature: Vec<u8>,
+}
+
+impl AddressUpdateMessage {
+    pub(crate) fn new_signed(
+        id: ConnectionId,
+        epoch: u64,
+        recipient: Recipient,
+        keypair: &Keypair,
+    ) -> Result<Self, Error> {
+        let signature = keypair
+            .sign(&Self::signed_payload(&id, epoch, &recipient))
+            .map_err(|e| Error::AddressUpdateSigningFailed(e.to_string()))?;
+        Ok(AddressUpdateMessage {
+            id,
+            epoch,
+            recipient,
+            public_key: keypair.public(),
+            signature,
+        })
+    }
+
+    /// verify returns true if the message was signed by the given peer.
+    pub(crate) fn verify(&self, peer_id: &PeerId) -> bool {
+        PeerId::from_public_key(&self.public_key) == *peer_id
+            && self.public_key.verify(
+                &Self::signed_payload(&self.id, self.epoch, &self.recipient),
+                &self.signature,
+            )
+    }
+
+    fn signed_payload(id: &ConnectionId, epoch: u64, recipient: &Recipient) -> Vec<u8> {
+        let mut bytes = ADDRESS_UPDATE_DOMAIN.to_vec();
+        bytes.extend_from_slice(&id.0);
+        bytes.extend_from_slice(&epoch.to_be_bytes());
+        bytes.extend_from_slice(&recipient.to_bytes());
+        bytes
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let public_key = self.public_key.to_protobuf_encoding();
+        let mut bytes = self.id.0.to_vec();
+        bytes.extend_from_slice(&self.epoch.to_be_bytes());
+        bytes.extend_from_slice(&self.recipient.to_bytes());
+        bytes.extend_from_slice(&(public_key.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&public_key);
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        const PUBLIC_KEY_OFFSET: usize =
+            CONNECTION_ID_LENGTH + EPOCH_BYTES_LEN + RECIPIENT_LENGTH + PUBLIC_KEY_LENGTH_BYTES_LEN;
+        if bytes.len() < PUBLIC_KEY_OFFSET {
+            return Err(Error::InvalidAddressUpdateBytes);
+        }
+
+        let id = ConnectionId::from_bytes(&bytes[0..CONNECTION_ID_LENGTH]);
+        let mut offset = CONNECTION_ID_LENGTH;
+        let epoch = u64::from_be_bytes(
+            bytes[offset..offset + EPOCH_BYTES_LEN]
+                .try_into()
+                .map_err(|_| Error::InvalidAddressUpdateBytes)?,
+        );
+        offset += EPOCH_BYTES_LEN;
+
+        let mut recipient_bytes = [0u8; RECIPIENT_LENGTH];
+        recipient_bytes.copy_from_slice(&bytes[offset..offset + RECIPIENT_LENGTH]);
+        let recipient =
+            Recipient::try_from_bytes(recipient_bytes).map_err(Error::InvalidRecipientBytes)?;
+        offset += RECIPIENT_LENGTH;
+
+        let public_key_len = u16::from_be_bytes([bytes[offset], bytes[offset + 1]]) as usize;
+        if bytes.len() < PUBLIC_KEY_OFFSET + public_key_len {
+            return Err(Error::InvalidAddressUpdateBytes);
+        }
+        let public_key =
+            PublicKey::from_protobuf_encoding(&bytes[PUBLIC_KEY_OFFSET..][..public_key_len])
+                .map_err(|_| Error::InvalidAddressUpdateBytes)?;
+        let signature = bytes[PUBLIC_KEY_OFFSET + public_key_len..].to_vec();
+
+        Ok(AddressUpdateMessage {
+            id,
+            epoch,
+            recipient,
+            public_key,
+            signature,
+        })
+    }
+}
+
+/// PingMessage probes the latency of a connection; the remote peer answers with a
+/// PongMessage echoing the timestamp. Timestamps are microseconds since the unix epoch.
+#[derive(Debug)]
+pub(crate) struct PingMessage {
+    pub(crate) id: ConnectionId,
+    pub(crate) sent_at: u64,
+}
+
+/// PongMessage answers a PingMessage. Along with the ping's timestamp, it contains when the
+/// ping was received and when the pong was sent by the responder's clock, which allows
+/// estimating the one-way delays and clock offset.
+#[derive(Debug)]
+pub(crate) struct PongMessage {
+    pub(crate) id: ConnectionId,
+    pub(crate) ping_sent_at: u64,
+    pub(crate) ping_received_at: u64,
+    pub(crate) sent_at: u64,
+}
+
+impl PingMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.id.0.to_vec();
+        bytes.extend_from_slice(&self.sent_at.to_be_bytes());
+        bytes
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH + TIMESTAMP_BYTES_LEN {
+            return Err(Error::InvalidProbeMessageBytes);
+        }
+
+        Ok(PingMessage {
+            id: ConnectionId::from_bytes(&bytes[0..CONNECTION_ID_LENGTH]),
+            sent_at: read_timestamp(&bytes[CONNECTION_ID_LENGTH..])?,
+        })
+    }
+}
+
+impl PongMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.id.0.to_vec();
+        bytes.extend_from_slice(&self.ping_sent_at.to_be_bytes());
+        bytes.extend_from_slice(&self.ping_received_at.to_be_bytes());
+        bytes.extend_from_slice(&self.sent_at.to_be_bytes());
+        bytes
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH + 3 * TIMESTAMP_BYTES_LEN {
+            return Err(Error::InvalidProbeMessageBytes);
+        }
+
+        let timestamps = &bytes[CONNECTION_ID_LENGTH..];
+        Ok(PongMessage {
+            id: ConnectionId::from_bytes(&bytes[0..CONNECTION_ID_LENGTH]),
+            ping_sent_at: read_timestamp(timestamps)?,
+            ping_received_at: read_timestamp(&timestamps[TIMESTAMP_BYTES_LEN..])?,
+            sent_at: read_timestamp(&timestamps[2 * TIMESTAMP_BYTES_LEN..])?,
+        })
+    }
+}
+
+/// ReachabilityRequestMessage asks the remote peer of a connection to send a
+/// DialBackMessage to the given Nym address, to check that it's reachable through the
+/// mixnet by someone other than ourselves.
+#[derive(Debug)]
+pub(crate) struct ReachabilityRequestMessage {
+    pub(crate) id: ConnectionId,
+    pub(crate) nonce: u64,
+    pub(crate) address: Recipient,
+}
+
+/// DialBackMessage answers a ReachabilityRequestMessage. It's sent to the address being
+/// checked rather than over the connection, and isn't tied to a connection.
+#[derive(Debug)]
+pub(crate) struct DialBackMessage {
+    pub(crate) nonce: u64,
+}
+
+impl ReachabilityRequestMessage {
+    pub(crate) fn new(id: ConnectionId, address: Recipient) -> Self {
+        ReachabilityRequestMessage {
+            id,
+            nonce: OsRng.next_u64(),
+            address,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.id.0.to_vec();
+        bytes.extend_from_slice(&self.nonce.to_be_bytes());
+        bytes.extend_from_slice(&self.address.to_bytes());
+        bytes
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH + NONCE_BYTES_LEN + RECIPIENT_LENGTH {
+            return Err(Error::InvalidReachabilityMessageBytes);
+        }
+
+        let id = ConnectionId::from_bytes(&bytes[0..CONNECTION_ID_LENGTH]);
+        let nonce = u64::from_be_bytes(
+            bytes[CONNECTION_ID_LENGTH..CONNECTION_ID_LENGTH + NONCE_BYTES_LEN]
+                .try_into()
+                .map_err(|_| Error::InvalidReachabilityMessageBytes)?,
+        );
+        let mut address_bytes = [0u8; RECIPIENT_LENGTH];
+        address_bytes
+            .copy_from_slice(&bytes[CONNECTION_ID_LENGTH + NONCE_BYTES_LEN..][..RECIPIENT_LENGTH]);
+        let address =
+            Recipient::try_from_bytes(address_bytes).map_err(Error::InvalidRecipientBytes)?;
+        Ok(ReachabilityRequestMessage { id, nonce, address })
+    }
+}
+
+impl DialBackMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.nonce.to_be_bytes().to_vec()
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < NONCE_BYTES_LEN {
+            return Err(Error::InvalidReachabilityMessageBytes);
+        }
+
+        Ok(DialBackMessage {
+            nonce: u64::from_be_bytes(
+                bytes[0..NONCE_BYTES_LEN]
+                    .try_into()
+                    .map_err(|_| Error::InvalidReachabilityMessageBytes)?,
+            ),
+        })
+    }
+}
+
+/// EchoRequestMessage asks a transport with the echo responder enabled to answer with an
+/// EchoReplyMessage, to measure the latency to its Nym address without a connection.
+/// Timestamps are microseconds since the unix epoch.
+#[derive(Debug)]
+pub(crate) struct EchoRequestMessage {
+    pub(crate) nonce: u64,
+    pub(crate) sent_at: u64,
+    /// the Nym address to send the reply to
+    pub(crate) reply_to: Recipient,
+}
+
+/// EchoReplyMessage answers an EchoRequestMessage, with the same timestamps as a
+/// PongMessage.
+#[derive(Debug)]
+pub(crate) struct EchoReplyMessage {
+    pub(crate) nonce: u64,
+    pub(crate) request_sent_at: u64,
+    pub(crate) request_received_at: u64,
+    pub(crate) sent_at: u64,
+}
+
+impl EchoRequestMessage {
+    pub(crate) fn new(reply_to: Recipient) -> Self {
+        EchoRequestMessage {
+            nonce: OsRng.next_u64(),
+            sent_at: unix_micros(),
+            reply_to,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.nonce.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.sent_at.to_be_bytes());
+        bytes.extend_from_slice(&self.reply_to.to_bytes());
+        bytes
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < NONCE_BYTES_LEN + TIMESTAMP_BYTES_LEN + RECIPIENT_LENGTH {
+            return Err(Error::InvalidProbeMessageBytes);
+        }
+
+        let nonce = read_timestamp(bytes)?;
+        let sent_at = read_timestamp(&bytes[NONCE_BYTES_LEN..])?;
+        let mut reply_to_bytes = [0u8; RECIPIENT_LENGTH];
+        reply_to_bytes
+            .copy_from_slice(&bytes[NONCE_BYTES_LEN + TIMESTAMP_BYTES_LEN..][..RECIPIENT_LENGTH]);
+        let reply_to =
+            Recipient::try_from_bytes(reply_to_bytes).map_err(Error::InvalidRecipientBytes)?;
+        Ok(EchoRequestMessage {
+            nonce,
+            sent_at,
+            reply_to,
+        })
+    }
+}
+
+impl EchoReplyMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.nonce.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.request_sent_at.to_be_bytes());
+        bytes.extend_from_slice(&self.request_received_at.to_be_bytes());
+        bytes.extend_from_slice(&self.sent_at.to_be_bytes());
+        bytes
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < NONCE_BYTES_LEN + 3 * TIMESTAMP_BYTES_LEN {
+            return Err(Error::InvalidProbeMessageBytes);
+        }
+
+        let timestamps = &bytes[NONCE_BYTES_LEN..];
+        Ok(EchoReplyMessage {
+            nonce: read_timestamp(bytes)?,
+            request_sent_at: read_timestamp(timestamps)?,
+            request_received_at: read_timestamp(&timestamps[TIMESTAMP_BYTES_LEN..])?,
+            sent_at: read_timestamp(&timestamps[2 * TIMESTAMP_BYTES_LEN..])?,
+        })
+    }
+}
+
+/// DenialReason is why a listener declined a connection request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DenialReason {
+    /// the dialer is excluded by the listener's allow or deny list
+    NotAllowed,
+    /// the listener is receiving too many connection requests
+    RateLimited,
+    /// the listener has as many connections as it accepts
+    ConnectionLimit,
+    /// no listener with the requested listener key uses the Nym address
+    UnknownListener,
+    /// a reason this version doesn't know about
+    Other,
+}
+
+impl DenialReason {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            DenialReason::NotAllowed => 0,
+            DenialReason::RateLimited => 1,
+            DenialReason::ConnectionLimit => 2,
+            DenialReason::UnknownListener => 3,
+            DenialReason::Other => 255,
+        }
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Self {
+        match value {
+            0 => DenialReason::NotAllowed,
+            1 => DenialReason::RateLimited,
+            2 => DenialReason::ConnectionLimit,
+            3 => DenialReason::UnknownListener,
+            _ => DenialReason::Other,
+        }
+    }
+}
+
+impl Display for DenialReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DenialReason::NotAllowed => "peer not allowed",
+            DenialReason::RateLimited => "rate limited",
+            DenialReason::ConnectionLimit => "connection limit reached",
+            DenialReason::UnknownListener => "unknown listener key",
+            DenialReason::Other => "other",
+        })
+    }
+}
+
+/// ResetCode is why the remote peer reset a substream instead of accepting it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResetCode {
+    /// the connection has as many substreams as the peer accepts, or they're being opened
+    /// faster than it accepts
+    TooManyStreams,
+    /// a code this version doesn't know about
+    Other,
+}
+
+impl ResetCode {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            ResetCode::TooManyStreams => 0,
+            ResetCode::Other => 255,
+        }
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Self {
+        match value {
+            0 => ResetCode::TooManyStreams,
+            _ => ResetCode::Other,
+        }
+    }
+}
+
+impl Display for ResetCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ResetCode::TooManyStreams => "too many streams",
+            ResetCode::Other => "other",
+        })
+    }
+}
+
+/// ConnectionDeniedMessage answers a connection request the listener declined, so that
+/// the dial fails with the reason instead of timing out.
+#[derive(Debug)]
+pub(crate) struct ConnectionDeniedMessage {
+    pub(crate) id: ConnectionId,
+    pub(crate) reason: DenialReason,
+}
+
+impl ConnectionDeniedMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.id.0.to_vec();
+        bytes.push(self.reason.to_u8());
+        bytes
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH + 1 {
+            return Err(Error::ConnectionMessageBytesTooShort);
+        }
+
+        Ok(ConnectionDeniedMessage {
+            id: ConnectionId::from_bytes(&bytes[0..CONNECTION_ID_LENGTH]),
+            reason: DenialReason::from_u8(bytes[CONNECTION_ID_LENGTH]),
+        })
+    }
+}
+
+/// ShutdownReason is why a peer closed a connection, as it tells the remote peer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ShutdownReason {
+    /// the application closed the connection, eg. because it's shutting down or done
+    /// with the peer
+    Shutdown,
+    /// the connection went unused, eg. it didn't finish upgrading in time
+    Idle,
+    /// the peer isn't allowed to keep the connection anymore
+    Policy,
+    /// the peer sent something the connection couldn't handle
+    ProtocolError,
+    /// a reason this version doesn't know about
+    Other,
+}
+
+impl ShutdownReason {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            ShutdownReason::Shutdown => 0,
+            ShutdownReason::Idle => 1,
+            ShutdownReason::Policy => 2,
+            ShutdownReason::ProtocolError => 3,
+            ShutdownReason::Other => 255,
+        }
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Self {
+        match value {
+            0 => ShutdownReason::Shutdown,
+            1 => ShutdownReason::Idle,
+            2 => ShutdownReason::Policy,
+            3 => ShutdownReason::ProtocolError,
+            _ => ShutdownReason::Other,
+        }
+    }
+}
+
+impl Display for ShutdownReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ShutdownReason::Shutdown => "shutdown",
+            ShutdownReason::Idle => "idle",
+            ShutdownReason::Policy => "policy",
+            ShutdownReason::ProtocolError => "protocol error",
+            ShutdownReason::Other => "other",
+        })
+    }
+}
+
+/// ConnectionCloseMessage tells the remote peer that a connection was closed, and why, so
+/// it can drop its end right away and report the reason.
+#[derive(Debug)]
+pub(crate) struct ConnectionCloseMessage {
+    pub(crate) id: ConnectionId,
+    pub(crate) reason: ShutdownReason,
+}
+
+impl ConnectionCloseMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.id.0.to_vec();
+        bytes.push(self.reason.to_u8());
+        bytes
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH + 1 {
+            return Err(Error::ConnectionMessageBytesTooShort);
+        }
+
+        Ok(ConnectionCloseMessage {
+            id: ConnectionId::from_bytes(&bytes[0..CONNECTION_ID_LENGTH]),
+            reason: ShutdownReason::from_u8(bytes[CONNECTION_ID_LENGTH]),
+        })
+    }
+}
+
+/// CloseAckMessage acknowledges a ConnectionClose on a connection using the close ack
+/// extension, so the peer that closed it stops sending it again.
+#[derive(Debug)]
+pub(crate) struct CloseAckMessage {
+    pub(crate) id: ConnectionId,
+}
+
+impl CloseAckMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.id.0.to_vec()
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < CONNECTION_ID_LENGTH {
+            return Err(Error::ConnectionMessageBytesTooShort);
+        }
+
+        Ok(CloseAckMessage {
+            id: ConnectionId::from_bytes(&bytes[0..CONNECTION_ID_LENGTH]),
+        })
+    }
+}
+
+/// CompactMessage is a Transport, AddressUpdate, Ping or Pong message sent on a connection
+/// using the compact ID extension: the connection ID is left out, and the receiver finds
+/// the connection by the reference it picked in the handshake. The inner message is parsed
+/// with an all-zero connection ID, which the transport fills in once it resolved the
+/// reference.
+#[derive(Debug)]
+pub(crate) struct CompactMessage {
+    pub(crate) reference: u64,
+    pub(crate) message: Box<Message>,
+}
+
+impl CompactMessage {
+    fn to_bytes(&self) -> Vec<u8> {
+        compact_bytes(self.reference, &self.message)
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let (reference, len) = read_varint(bytes).ok_or(Error::InvalidCompactMessageBytes)?;
+        let message = &bytes[len..];
+        let offset = message
+            .first()
+            .copied()
+            .and_then(MessageKind::from_type_byte)
+            .and_then(compact_id_offset)
+            .filter(|&offset| offset <= message.len())
+            .ok_or(Error::InvalidCompactMessageBytes)?;
+        let mut full = message[..offset].to_vec();
+        full.extend_from_slice(&[0u8; CONNECTION_ID_LENGTH]);
+        full.extend_from_slice(&message[offset..]);
+        Ok(CompactMessage {
+            reference,
+            message: Box::new(Message::try_from_bytes(full)?),
+        })
+    }
+}
+
+/// compact_bytes returns the body of a Compact message with the given reference and inner
+/// message, which must be of a type that can be sent compact.
+fn compact_bytes(reference: u64, message: &Message) -> Vec<u8> {
+    let mut bytes = vec![];
+    write_varint(&mut bytes, reference);
+    let message_bytes = message.to_bytes();
+    let offset =
+        compact_id_offset(message.kind()).expect("only messages of a connection are sent compact");
+    bytes.extend_from_slice(&message_bytes[..offset]);
+    bytes.extend_from_slice(&message_bytes[offset + CONNECTION_ID_LENGTH..]);
+    bytes
+}
+
+/// compact_id_offset returns the offset of the connection ID in messages of the given type,
+/// if they can be sent as Compact messages.
+fn compact_id_offset(kind: MessageKind) -> Option<usize> {
+    match kind {
+        MessageKind::Transport => Some(spec::transport::CONNECTION_ID),
+        MessageKind::AddressUpdate => Some(spec::address_update::CONNECTION_ID),
+        MessageKind::Ping => Some(spec::ping::CONNECTION_ID),
+        MessageKind::Pong => Some(spec::pong::CONNECTION_ID),
+        _ => None,
+    }
+}
+
+/// write_varint appends the value as an unsigned LEB128 varint.
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+/// read_varint reads an unsigned LEB128 varint, and returns it along with its length.
+fn read_varint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, &byte) in bytes.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64).checked_shl(7 * i as u32)?;
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+fn read_timestamp(bytes: &[u8]) -> Result<u64, Error> {
+    Ok(u64::from_be_bytes(
+        bytes[0..TIMESTAMP_BYTES_LEN]
+            .try_into()
+            .map_err(|_| Error::InvalidProbeMessageBytes)?,
+    ))
+}
+
 impl Message {
     fn try_from_bytes(bytes: Vec<u8>) -> Result<Self, Error> {
         if bytes.len() < 2 {
             return Err(Error::InvalidMessageBytes);
         }
 
-        Ok(match bytes[0] {
-            0 => Message::ConnectionRequest(ConnectionMessage::try_from_bytes(&bytes[1..])?),
-            1 => Message::ConnectionResponse(ConnectionMessage::try_from_bytes(&bytes[1..])?),
-            2 => Message::TransportMessage(TransportMessage::try_from_bytes(&bytes[1..])?),
-            _ => return Err(Error::InvalidMessageBytes),
+        let Some(kind) = MessageKind::from_type_byte(bytes[0]) else {
+            return Err(Error::UnknownMessageType(bytes[0]));
+        };
+        let body = &bytes[1..];
+        Ok(match kind {
+            MessageKind::ConnectionRequest => {
+                Message::ConnectionRequest(ConnectionMessage::try_from_bytes(body)?)
+            }
+            MessageKind::ConnectionResponse => {
+                Message::ConnectionResponse(ConnectionMessage::try_from_bytes(body)?)
+            }
+            MessageKind::Transport => {
+                Message::TransportMessage(TransportMessage::try_from_bytes(body)?)
+            }
+            MessageKind::AddressUpdate => {
+                Message::AddressUpdate(AddressUpdateMessage::try_from_bytes(body)?)
+            }
+            MessageKind::Ping => Message::Ping(PingMessage::try_from_bytes(body)?),
+            MessageKind::Pong => Message::Pong(PongMessage::try_from_bytes(body)?),
+            MessageKind::Broadcast => Message::Broadcast(body.to_vec()),
+            MessageKind::ReachabilityRequest => {
+                Message::ReachabilityRequest(ReachabilityRequestMessage::try_from_bytes(body)?)
+            }
+            MessageKind::DialBack => Message::DialBack(DialBackMessage::try_from_bytes(body)?),
+            MessageKind::ConnectionDenied => {
+                Message::ConnectionDenied(ConnectionDeniedMessage::try_from_bytes(body)?)
+            }
+            MessageKind::EchoRequest => {
+                Message::EchoRequest(EchoRequestMessage::try_from_bytes(body)?)
+            }
+            MessageKind::EchoReply => Message::EchoReply(EchoReplyMessage::try_from_bytes(body)?),
+            MessageKind::Compact => Message::Compact(CompactMessage::try_from_bytes(body)?),
+            MessageKind::ConnectionClose => {
+                Message::ConnectionClose(ConnectionCloseMessage::try_from_bytes(body)?)
+            }
+            MessageKind::CloseAck => Message::CloseAck(CloseAckMessage::try_from_bytes(body)?),
+            MessageKind::Datagram => Message::Datagram(body.to_vec()),
         })
     }
 }
@@ -111,12 +829,51 @@ impl Message {
 impl ConnectionMessage {
     fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = self.id.0.to_vec();
-        match self.recipient {
-            Some(recipient) => {
-                bytes.push(1u8);
-                bytes.append(&mut recipient.to_bytes().to_vec());
+        // a target is only sent along with a recipient, or with the flag of anonymous
+        // requests, so that listeners which don't know about targets reject the request
+        // rather than misread it
+        let flag = match (self.recipient, self.target) {
+            (Some(_), Some(_)) => recipient_flag::RECIPIENT_AND_TARGET,
+            (Some(_), None) => recipient_flag::RECIPIENT,
+            (None, Some(_)) => recipient_flag::TARGET,
+            (None, None) => recipient_flag::NONE,
+        };
+        let mut flag = flag;
+        if self.extensions != 0 {
+            flag |= recipient_flag::EXTENSIONS;
+        }
+        if self.handshake_payload.is_some() {
+            flag |= recipient_flag::HANDSHAKE_PAYLOAD;
+        }
+        bytes.push(flag);
+        if self.extensions != 0 {
+            bytes.push(self.extensions);
+        }
+        if let Some(recipient) = self.recipient {
+            bytes.append(&mut recipient.to_bytes().to_vec());
+        }
+        if let Some(target) = self.target {
+            let mut target = target.to_bytes();
+            bytes.push(target.len() as u8);
+            bytes.append(&mut target);
+        }
+        if let Some(payload) = &self.handshake_payload {
+            bytes.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(payload);
+        }
+        if self.extensions & extension::CLOCK != 0 {
+            bytes.push(self.timestamps.len() as u8);
+            for timestamp in &self.timestamps {
+                bytes.extend_from_slice(&timestamp.to_be_bytes());
             }
-            None => bytes.push(0u8),
+        }
+        if self.extensions & extension::COMPACT_ID != 0 {
+            write_varint(&mut bytes, self.connection_ref.unwrap_or_default());
+        }
+        if self.extensions & extension::USER_AGENT != 0 {
+            let user_agent = self.user_agent.as_deref().unwrap_or_default().as_bytes();
+            bytes.push(user_agent.len() as u8);
+            bytes.extend_from_slice(user_agent);
         }
         bytes.append(&mut self.peer_id.to_bytes());
         bytes
@@ -128,17 +885,28 @@ impl ConnectionMessage {
         }
 
         let id = ConnectionId::from_bytes(&bytes[0..CONNECTION_ID_LENGTH]);
-        let recipient = match bytes[CONNECTION_ID_LENGTH] {
-            0u8 => None,
-            1u8 => {
-                if bytes.len() < CONNECTION_ID_LENGTH + 1 + RECIPIENT_LENGTH {
+        let flag = bytes[CONNECTION_ID_LENGTH];
+        let mut offset = CONNECTION_ID_LENGTH + 1;
+        let extensions = if flag & recipient_flag::EXTENSIONS != 0 {
+            let Some(&extensions) = bytes.get(offset) else {
+                return Err(Error::ConnectionMessageBytesTooShort);
+            };
+            offset += 1;
+            extensions
+        } else {
+            0
+        };
+        let prefix = flag & !(recipient_flag::EXTENSIONS | recipient_flag::HANDSHAKE_PAYLOAD);
+        let recipient = match prefix {
+            recipient_flag::NONE | recipient_flag::TARGET => None,
+            recipient_flag::RECIPIENT | recipient_flag::RECIPIENT_AND_TARGET => {
+                if bytes.len() < offset + RECIPIENT_LENGTH {
                     return Err(Error::ConnectionMessageBytesNoRecipient);
                 }
 
                 let mut recipient_bytes = [0u8; RECIPIENT_LENGTH];
-                recipient_bytes[..].copy_from_slice(
-                    &bytes[CONNECTION_ID_LENGTH + 1..CONNECTION_ID_LENGTH + 1 + RECIPIENT_LENGTH],
-                );
+                recipient_bytes[..].copy_from_slice(&bytes[offset..offset + RECIPIENT_LENGTH]);
+                offset += RECIPIENT_LENGTH;
                 Some(
                     Recipient::try_from_bytes(recipient_bytes)
                         .map_err(Error::InvalidRecipientBytes)?,
@@ -148,26 +916,89 @@ impl ConnectionMessage {
                 return Err(Error::InvalidRecipientPrefixByte);
             }
         };
-        let peer_id = match recipient {
-            Some(_) => {
-                if bytes.len() < CONNECTION_ID_LENGTH + RECIPIENT_LENGTH + 2 {
-                    return Err(Error::ConnectionMessageBytesNoPeerId);
-                }
-                PeerId::from_bytes(&bytes[CONNECTION_ID_LENGTH + 1 + RECIPIENT_LENGTH..])
-                    .map_err(Error::InvalidPeerIdBytes)?
-            }
-            None => {
-                if bytes.len() < CONNECTION_ID_LENGTH + 2 {
-                    return Err(Error::ConnectionMessageBytesNoPeerId);
-                }
-                PeerId::from_bytes(&bytes[CONNECTION_ID_LENGTH + 1..])
-                    .map_err(Error::InvalidPeerIdBytes)?
-            }
+        let target = if matches!(
+            prefix,
+            recipient_flag::RECIPIENT_AND_TARGET | recipient_flag::TARGET
+        ) {
+            let Some(&target_len) = bytes.get(offset) else {
+                return Err(Error::ConnectionMessageBytesNoPeerId);
+            };
+            let target_bytes = bytes
+                .get(offset + 1..offset + 1 + target_len as usize)
+                .ok_or(Error::ConnectionMessageBytesNoPeerId)?;
+            offset += 1 + target_len as usize;
+            Some(PeerId::from_bytes(target_bytes).map_err(Error::InvalidPeerIdBytes)?)
+        } else {
+            None
         };
+        let handshake_payload = if flag & recipient_flag::HANDSHAKE_PAYLOAD != 0 {
+            let len_bytes = bytes
+                .get(offset..offset + HANDSHAKE_PAYLOAD_LENGTH_BYTES_LEN)
+                .ok_or(Error::ConnectionMessageBytesTooShort)?;
+            let len = u16::from_be_bytes([len_bytes[0], len_bytes[1]]) as usize;
+            offset += HANDSHAKE_PAYLOAD_LENGTH_BYTES_LEN;
+            let payload = bytes
+                .get(offset..offset + len)
+                .ok_or(Error::ConnectionMessageBytesTooShort)?;
+            offset += len;
+            Some(payload.to_vec())
+        } else {
+            None
+        };
+        let timestamps = if extensions & extension::CLOCK != 0 {
+            let Some(&count) = bytes.get(offset) else {
+                return Err(Error::ConnectionMessageBytesTooShort);
+            };
+            offset += 1;
+            let timestamps = bytes
+                .get(offset..offset + count as usize * TIMESTAMP_BYTES_LEN)
+                .ok_or(Error::ConnectionMessageBytesTooShort)?;
+            offset += timestamps.len();
+            timestamps
+                .chunks(TIMESTAMP_BYTES_LEN)
+                .map(read_timestamp)
+                .collect::<Result<_, _>>()?
+        } else {
+            vec![]
+        };
+        let connection_ref = if extensions & extension::COMPACT_ID != 0 {
+            let (connection_ref, len) =
+                read_varint(&bytes[offset..]).ok_or(Error::ConnectionMessageBytesTooShort)?;
+            offset += len;
+            Some(connection_ref)
+        } else {
+            None
+        };
+        let user_agent = if extensions & extension::USER_AGENT != 0 {
+            let Some(&len) = bytes.get(offset) else {
+                return Err(Error::ConnectionMessageBytesTooShort);
+            };
+            offset += USER_AGENT_LENGTH_BYTES_LEN;
+            let user_agent = bytes
+                .get(offset..offset + len as usize)
+                .ok_or(Error::ConnectionMessageBytesTooShort)?;
+            offset += user_agent.len();
+            let user_agent =
+                std::str::from_utf8(user_agent).map_err(|_| Error::InvalidUserAgent)?;
+            (!user_agent.is_empty()).then(|| user_agent.to_string())
+        } else {
+            None
+        };
+        if bytes.len() < offset + 1 {
+            return Err(Error::ConnectionMessageBytesNoPeerId);
+        }
+        let peer_id = PeerId::from_bytes(&bytes[offset..]).map_err(Error::InvalidPeerIdBytes)?;
         Ok(ConnectionMessage {
             peer_id,
             recipient,
+            target,
             id,
+            extensions,
+            handshake_payload,
+            timestamps,
+            connection_ref,
+            user_agent,
+            reply_tag: None,
         })
     }
 }
@@ -223,15 +1054,21 @@ pub(crate) enum SubstreamMessageType {
     OpenResponse,
     Close,
     Data(Vec<u8>),
+    Reset(ResetCode),
+    /// a type this version doesn't know, eg. from a newer peer; it still takes up its
+    /// nonce, so it's skipped rather than failing the whole message
+    Unknown(u8),
 }
 
 impl SubstreamMessageType {
     fn to_u8(&self) -> u8 {
         match self {
-            SubstreamMessageType::OpenRequest => 0,
-            SubstreamMessageType::OpenResponse => 1,
-            SubstreamMessageType::Close => 2,
-            SubstreamMessageType::Data(_) => 3,
+            SubstreamMessageType::OpenRequest => substream_op::OPEN_REQUEST,
+            SubstreamMessageType::OpenResponse => substream_op::OPEN_RESPONSE,
+            SubstreamMessageType::Close => substream_op::CLOSE,
+            SubstreamMessageType::Data(_) => substream_op::DATA,
+            SubstreamMessageType::Reset(_) => substream_op::RESET,
+            SubstreamMessageType::Unknown(op) => *op,
         }
     }
 }
@@ -241,28 +1078,64 @@ impl SubstreamMessageType {
 pub(crate) struct SubstreamMessage {
     pub(crate) substream_id: SubstreamId,
     pub(crate) message_type: SubstreamMessageType,
+    /// when the sender wrote the data, in microseconds since the unix epoch by its clock;
+    /// only sent on connections using the latency extension.
+    pub(crate) sent_at: Option<u64>,
+    /// when we received the message from the mixnet, if it's inbound.
+    pub(crate) received_at: Option<SystemTime>,
 }
 
 impl SubstreamMessage {
-    pub(crate) fn new_with_data(substream_id: SubstreamId, message: Vec<u8>) -> Self {
+    pub(crate) fn new(substream_id: SubstreamId, message_type: SubstreamMessageType) -> Self {
         SubstreamMessage {
             substream_id,
-            message_type: SubstreamMessageType::Data(message),
+            message_type,
+            sent_at: None,
+            received_at: None,
         }
     }
 
+    pub(crate) fn new_with_data(substream_id: SubstreamId, message: Vec<u8>) -> Self {
+        Self::new(substream_id, SubstreamMessageType::Data(message))
+    }
+
     pub(crate) fn new_close(substream_id: SubstreamId) -> Self {
-        SubstreamMessage {
-            substream_id,
-            message_type: SubstreamMessageType::Close,
-        }
+        Self::new(substream_id, SubstreamMessageType::Close)
+    }
+
+    /// with_sent_at stamps data with the current time, so the receiver can tell its age.
+    pub(crate) fn with_sent_at(mut self) -> Self {
+        self.sent_at = Some(unix_micros());
+        self
+    }
+
+    /// age returns when an inbound data message was sent and received.
+    pub(crate) fn age(&self) -> Option<MessageAge> {
+        Some(MessageAge {
+            received_at: self.received_at?,
+            sent_at: self
+                .sent_at
+                .map(|sent_at| UNIX_EPOCH + Duration::from_micros(sent_at)),
+        })
     }
 
     pub(crate) fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = self.substream_id.0.clone().to_vec();
-        bytes.push(self.message_type.to_u8());
-        if let SubstreamMessageType::Data(message) = &self.message_type {
-            bytes.extend_from_slice(message);
+        match (&self.message_type, self.sent_at) {
+            (SubstreamMessageType::Data(message), Some(sent_at)) => {
+                bytes.push(substream_op::STAMPED_DATA);
+                bytes.extend_from_slice(&sent_at.to_be_bytes());
+                bytes.extend_from_slice(message);
+            }
+            (SubstreamMessageType::Data(message), None) => {
+                bytes.push(substream_op::DATA);
+                bytes.extend_from_slice(message);
+            }
+            (SubstreamMessageType::Reset(code), _) => {
+                bytes.push(substream_op::RESET);
+                bytes.push(code.to_u8());
+            }
+            (message_type, _) => bytes.push(message_type.to_u8()),
         }
         bytes
     }
@@ -273,56 +1146,520 @@ impl SubstreamMessage {
         }
 
         let substream_id = SubstreamId::from_bytes(&bytes[0..SUBSTREAM_ID_LENGTH]);
+        let mut sent_at = None;
         let message_type = match bytes[SUBSTREAM_ID_LENGTH] {
-            0 => SubstreamMessageType::OpenRequest,
-            1 => SubstreamMessageType::OpenResponse,
-            2 => SubstreamMessageType::Close,
-            3 => {
+            substream_op::OPEN_REQUEST => SubstreamMessageType::OpenRequest,
+            substream_op::OPEN_RESPONSE => SubstreamMessageType::OpenResponse,
+            substream_op::CLOSE => SubstreamMessageType::Close,
+            substream_op::DATA => {
                 if bytes.len() < SUBSTREAM_ID_LENGTH + 2 {
                     return Err(Error::InvalidSubstreamMessageBytes);
                 }
                 SubstreamMessageType::Data(bytes[SUBSTREAM_ID_LENGTH + 1..].to_vec())
             }
-            _ => return Err(Error::InvalidSubstreamMessageType),
+            substream_op::STAMPED_DATA => {
+                const DATA_OFFSET: usize = SUBSTREAM_ID_LENGTH + 1 + TIMESTAMP_BYTES_LEN;
+                if bytes.len() < DATA_OFFSET + 1 {
+                    return Err(Error::InvalidSubstreamMessageBytes);
+                }
+                sent_at = Some(u64::from_be_bytes(
+                    bytes[SUBSTREAM_ID_LENGTH + 1..DATA_OFFSET]
+                        .try_into()
+                        .map_err(|_| Error::InvalidSubstreamMessageBytes)?,
+                ));
+                SubstreamMessageType::Data(bytes[DATA_OFFSET..].to_vec())
+            }
+            substream_op::RESET => match bytes.get(SUBSTREAM_ID_LENGTH + 1) {
+                Some(&code) => SubstreamMessageType::Reset(ResetCode::from_u8(code)),
+                None => return Err(Error::InvalidSubstreamMessageBytes),
+            },
+            op => SubstreamMessageType::Unknown(op),
         };
 
+        // messages are parsed as soon as they're received from the mixnet
         Ok(SubstreamMessage {
             substream_id,
             message_type,
+            sent_at,
+            received_at: Some(SystemTime::now()),
         })
     }
 }
 
 impl Message {
+    /// is_cover returns true for probes that carry no application data and that nothing
+    /// depends on arriving: latency pings and pongs and echoes. They're the first to go
+    /// when the memory budget is under pressure.
+    pub(crate) fn is_cover(&self) -> bool {
+        matches!(
+            self,
+            Message::Ping(_) | Message::Pong(_) | Message::EchoRequest(_) | Message::EchoReply(_)
+        )
+    }
+
     pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.kind().type_byte()];
         match self {
-            Message::ConnectionRequest(msg) => {
-                let mut bytes = 0_u8.to_be_bytes().to_vec();
-                bytes.append(&mut msg.to_bytes());
-                bytes
+            Message::ConnectionRequest(msg) | Message::ConnectionResponse(msg) => {
+                bytes.append(&mut msg.to_bytes())
             }
-            Message::ConnectionResponse(msg) => {
-                let mut bytes = 1_u8.to_be_bytes().to_vec();
-                bytes.append(&mut msg.to_bytes());
-                bytes
+            Message::TransportMessage(msg) => bytes.append(&mut msg.to_bytes()),
+            Message::AddressUpdate(msg) => bytes.append(&mut msg.to_bytes()),
+            Message::Ping(msg) => bytes.append(&mut msg.to_bytes()),
+            Message::Pong(msg) => bytes.append(&mut msg.to_bytes()),
+            Message::Broadcast(payload) => bytes.extend_from_slice(payload),
+            Message::ReachabilityRequest(msg) => bytes.append(&mut msg.to_bytes()),
+            Message::DialBack(msg) => bytes.append(&mut msg.to_bytes()),
+            Message::ConnectionDenied(msg) => bytes.append(&mut msg.to_bytes()),
+            Message::EchoRequest(msg) => bytes.append(&mut msg.to_bytes()),
+            Message::EchoReply(msg) => bytes.append(&mut msg.to_bytes()),
+            Message::Compact(msg) => bytes.append(&mut msg.to_bytes()),
+            Message::ConnectionClose(msg) => bytes.append(&mut msg.to_bytes()),
+            Message::CloseAck(msg) => bytes.append(&mut msg.to_bytes()),
+            Message::Datagram(payload) => bytes.extend_from_slice(payload),
+        }
+        bytes
+    }
+}
+
+/// Route is how an outbound message reaches its recipient.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) enum Route {
+    /// to the recipient's Nym address, which the message reveals ours to
+    #[default]
+    Direct,
+    /// to the recipient's Nym address with reply SURBs, so it can answer without learning
+    /// ours
+    Anonymous { reply_surbs: u32 },
+    /// back to an anonymous sender, using the reply SURBs it sent
+    Reply(SenderTag),
+}
+
+/// MessagePriority determines the order in which queued outbound messages are written to
+/// the mixnet: higher priority messages jump ahead of lower priority ones, so that eg.
+/// pings and consensus votes aren't stuck behind bulk transfers.
+/// Note that messages on the same connection are still delivered to the application in
+/// order, so prioritizing helps most across connections and for control messages.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum MessagePriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl MessagePriority {
+    /// the number of priority levels
+    pub(crate) const COUNT: usize = 3;
+
+    pub(crate) fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Self {
+        match value {
+            0 => MessagePriority::Low,
+            1 => MessagePriority::Normal,
+            _ => MessagePriority::High,
+        }
+    }
+}
+
+/// InboundMessage represents an inbound mixnet message.
+#[derive(Debug)]
+pub struct InboundMessage(pub(crate) Message);
+
+/// MessageAge tells when an inbound message was received from the mixnet and, if the
+/// connection uses the latency extension, when the sender wrote it, so the application can
+/// discard data that spent too long in the mixnet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MessageAge {
+    pub received_at: SystemTime,
+    /// by the sender's clock, so it's off by the offset between the peers' clocks, unless
+    /// the connection corrects for clock skew
+    pub sent_at: Option<SystemTime>,
+}
+
+impl MessageAge {
+    /// age returns how long ago the message was sent, if that's known, or else received.
+    pub fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(self.sent_at.unwrap_or(self.received_at))
+            .unwrap_or_default()
+    }
+
+    /// corrected translates the send time from the sender's clock to ours, given the
+    /// offset of the sender's clock in microseconds.
+    pub(crate) fn corrected(mut self, offset_micros: i64) -> Self {
+        let offset = Duration::from_micros(offset_micros.unsigned_abs());
+        self.sent_at = self.sent_at.and_then(|sent_at| {
+            if offset_micros >= 0 {
+                sent_at.checked_sub(offset)
+            } else {
+                sent_at.checked_add(offset)
             }
-            Message::TransportMessage(msg) => {
-                let mut bytes = 2_u8.to_be_bytes().to_vec();
-                bytes.append(&mut msg.to_bytes());
-                bytes
+        });
+        self
+    }
+
+    /// transit_time returns how long the message took from the sender's application to
+    /// us, including the time spent queued on both ends, if the send time is known.
+    pub fn transit_time(&self) -> Option<Duration> {
+        let sent_at = self.sent_at?;
+        Some(self.received_at.duration_since(sent_at).unwrap_or_default())
+    }
+}
+
+/// MessageKind is the type of a message sent between transports.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum MessageKind {
+    ConnectionRequest,
+    ConnectionResponse,
+    /// data or control messages of a substream
+    Transport,
+    AddressUpdate,
+    Ping,
+    Pong,
+    Broadcast,
+    ReachabilityRequest,
+    DialBack,
+    ConnectionDenied,
+    EchoRequest,
+    EchoReply,
+    /// a message of a connection using the compact ID extension
+    Compact,
+    ConnectionClose,
+    CloseAck,
+    Datagram,
+}
+
+impl Message {
+    pub(crate) fn kind(&self) -> MessageKind {
+        match self {
+            Message::ConnectionRequest(_) => MessageKind::ConnectionRequest,
+            Message::ConnectionResponse(_) => MessageKind::ConnectionResponse,
+            Message::TransportMessage(_) => MessageKind::Transport,
+            Message::AddressUpdate(_) => MessageKind::AddressUpdate,
+            Message::Ping(_) => MessageKind::Ping,
+            Message::Pong(_) => MessageKind::Pong,
+            Message::Broadcast(_) => MessageKind::Broadcast,
+            Message::ReachabilityRequest(_) => MessageKind::ReachabilityRequest,
+            Message::DialBack(_) => MessageKind::DialBack,
+            Message::ConnectionDenied(_) => MessageKind::ConnectionDenied,
+            Message::EchoRequest(_) => MessageKind::EchoRequest,
+            Message::EchoReply(_) => MessageKind::EchoReply,
+            Message::Compact(_) => MessageKind::Compact,
+            Message::ConnectionClose(_) => MessageKind::ConnectionClose,
+            Message::CloseAck(_) => MessageKind::CloseAck,
+            Message::Datagram(_) => MessageKind::Datagram,
+        }
+    }
+
+    /// connection_id returns the ID of the connection the message belongs to, if any.
+    pub(crate) fn connection_id(&self) -> Option<&ConnectionId> {
+        match self {
+            Message::ConnectionRequest(msg) | Message::ConnectionResponse(msg) => Some(&msg.id),
+            Message::TransportMessage(msg) => Some(&msg.id),
+            Message::AddressUpdate(msg) => Some(&msg.id),
+            Message::Ping(msg) => Some(&msg.id),
+            Message::Pong(msg) => Some(&msg.id),
+            Message::ReachabilityRequest(msg) => Some(&msg.id),
+            Message::ConnectionDenied(msg) => Some(&msg.id),
+            Message::ConnectionClose(msg) => Some(&msg.id),
+            Message::CloseAck(msg) => Some(&msg.id),
+            Message::Broadcast(_)
+            | Message::DialBack(_)
+            | Message::EchoRequest(_)
+            | Message::EchoReply(_)
+            | Message::Compact(_)
+            | Message::Datagram(_) => None,
+        }
+    }
+
+    /// set_connection_id sets the ID of the connection the message belongs to, for the
+    /// messages that can be sent compact.
+    pub(crate) fn set_connection_id(&mut self, id: ConnectionId) {
+        match self {
+            Message::TransportMessage(msg) => msg.id = id,
+            Message::AddressUpdate(msg) => msg.id = id,
+            Message::Ping(msg) => msg.id = id,
+            Message::Pong(msg) => msg.id = id,
+            _ => {}
+        }
+    }
+}
+
+impl InboundMessage {
+    /// kind returns the type of the message.
+    pub fn kind(&self) -> MessageKind {
+        self.0.kind()
+    }
+
+    /// with_sender_tag records the sender tag the message arrived with in connection
+    /// requests, since anonymous ones have no other way to reply to them.
+    pub(crate) fn with_sender_tag(mut self, sender_tag: Option<SenderTag>) -> Self {
+        if let Message::ConnectionRequest(msg) = &mut self.0 {
+            msg.reply_tag = sender_tag;
+        }
+        self
+    }
+
+    /// data_payload returns the application data if this is a substream data message.
+    pub fn data_payload(&self) -> Option<&[u8]> {
+        match &self.0 {
+            Message::TransportMessage(TransportMessage {
+                message:
+                    SubstreamMessage {
+                        message_type: SubstreamMessageType::Data(data),
+                        ..
+                    },
+                ..
+            }) => Some(data),
+            _ => None,
+        }
+    }
+
+    /// age returns when the message was sent and received, if this is a substream data
+    /// message.
+    pub fn age(&self) -> Option<MessageAge> {
+        match &self.0 {
+            Message::TransportMessage(TransportMessage { message, .. })
+                if matches!(message.message_type, SubstreamMessageType::Data(_)) =>
+            {
+                message.age()
             }
+            _ => None,
+        }
+    }
+
+    /// broadcast_payload returns the payload if this is a broadcast sent with
+    /// [`MixnetConnection::broadcast`](crate::mixnet::MixnetConnection::broadcast).
+    pub fn broadcast_payload(&self) -> Option<&[u8]> {
+        match &self.0 {
+            Message::Broadcast(payload) => Some(payload),
+            _ => None,
+        }
+    }
+
+    /// datagram_payload returns the payload if this is a datagram sent with
+    /// [`NymTransport::send_datagram`](crate::transport::NymTransport::send_datagram).
+    pub fn datagram_payload(&self) -> Option<&[u8]> {
+        match &self.0 {
+            Message::Datagram(payload) => Some(payload),
+            _ => None,
         }
     }
 }
 
-/// InboundMessage represents an inbound mixnet message.
-pub(crate) struct InboundMessage(pub(crate) Message);
+/// BroadcastMessage is written to many recipients, serialized only once.
+#[derive(Debug)]
+pub(crate) struct BroadcastMessage {
+    /// the serialized message
+    pub(crate) bytes: Arc<Vec<u8>>,
+    /// recipients the message hasn't been written to yet
+    pub(crate) recipients: VecDeque<Recipient>,
+    pub(crate) priority: MessagePriority,
+    pub(crate) packet_size: Option<PacketSize>,
+    /// held until the message has been written to all of its recipients
+    pub(crate) permit: Option<OwnedSemaphorePermit>,
+}
+
+impl BroadcastMessage {
+    pub(crate) fn new(payload: Vec<u8>, recipients: Vec<Recipient>) -> Self {
+        BroadcastMessage {
+            bytes: Arc::new(Message::Broadcast(payload).to_bytes()),
+            recipients: recipients.into(),
+            priority: MessagePriority::default(),
+            packet_size: None,
+            permit: None,
+        }
+    }
+}
 
 /// OutboundMessage represents an outbound mixnet message.
 #[derive(Debug)]
-pub(crate) struct OutboundMessage {
+pub struct OutboundMessage {
     pub(crate) message: Message,
     pub(crate) recipient: Recipient,
+    pub(crate) priority: MessagePriority,
+
+    /// if not set, the transport's packet size is used
+    pub(crate) packet_size: Option<PacketSize>,
+
+    /// held until the message has been written to the mixnet, which bounds the
+    /// number of in-flight messages sent through an `OutboundSink`, or written to
+    /// substreams.
+    pub(crate) permit: Option<OwnedSemaphorePermit>,
+    /// held until the message has been written to the mixnet, so its data counts towards
+    /// the memory budget until then
+    pub(crate) reservation: Option<Reservation>,
+
+    /// if set, the message is dropped if it hasn't been written to the mixnet by then
+    pub(crate) deadline: Option<Instant>,
+    /// set if the message is dropped for missing its deadline
+    pub(crate) deadline_exceeded: Option<Arc<AtomicBool>>,
+
+    /// the remote peer's reference to the connection, if it uses the compact ID extension
+    pub(crate) connection_ref: Option<u64>,
+
+    /// how the message reaches the recipient
+    pub(crate) route: Route,
+
+    /// dropped along with the message once it's been handed to the backend, or dropped,
+    /// which tells the holder of the receiver that it left the transport
+    pub(crate) written_tx: Option<oneshot::Sender<()>>,
+
+    /// when the message was queued for the mixnet task, and when the task took it up
+    pub(crate) enqueued_at: Instant,
+    pub(crate) dequeued_at: Option<Instant>,
+}
+
+impl OutboundMessage {
+    pub(crate) fn new(message: Message, recipient: Recipient) -> Self {
+        // control messages aren't part of a connection's ordered stream, so they can
+        // always jump ahead of application data
+        let priority = match message {
+            Message::TransportMessage(_) => MessagePriority::default(),
+            _ => MessagePriority::High,
+        };
+        OutboundMessage {
+            message,
+            recipient,
+            priority,
+            packet_size: None,
+            permit: None,
+            reservation: None,
+            deadline: None,
+            deadline_exceeded: None,
+            connection_ref: None,
+            route: Route::default(),
+            written_tx: None,
+            enqueued_at: Instant::now(),
+            dequeued_at: None,
+        }
+    }
+
+    /// datagram returns a message carrying application data to the recipient outside of
+    /// any connection, like [`NymTransport::send_datagram`](crate::transport::NymTransport::send_datagram),
+    /// eg. to be sent through an [`OutboundSink`](crate::mixnet::OutboundSink). The
+    /// recipient reads it with [`InboundMessage::datagram_payload`].
+    pub fn datagram(recipient: Recipient, payload: Vec<u8>) -> Self {
+        OutboundMessage::new(Message::Datagram(payload), recipient)
+            .with_priority(MessagePriority::default())
+    }
+
+    /// with_priority sets the priority the message is written to the mixnet with, and
+    /// returns self.
+    pub fn with_priority(mut self, priority: MessagePriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// with_packet_size sets the packet size the message is sent with, if not the
+    /// transport's, and returns self.
+    pub fn with_packet_size(mut self, packet_size: Option<PacketSize>) -> Self {
+        self.packet_size = packet_size;
+        self
+    }
+
+    /// with_connection_ref sends the message as a Compact message with the given reference
+    /// to the connection, if it's of a type that can be.
+    pub(crate) fn with_connection_ref(mut self, connection_ref: Option<u64>) -> Self {
+        self.connection_ref = connection_ref;
+        self
+    }
+
+    /// with_permit holds the permit until the message has been written to the mixnet, and
+    /// returns self.
+    pub(crate) fn with_permit(mut self, permit: OwnedSemaphorePermit) -> Self {
+        self.permit = Some(permit);
+        self
+    }
+
+    /// with_route sends the message the given way rather than directly to the recipient.
+    pub(crate) fn with_route(mut self, route: Route) -> Self {
+        self.route = route;
+        self
+    }
+
+    /// with_written_notifier drops `written_tx` once the message left the transport, and
+    /// returns self.
+    pub(crate) fn with_written_notifier(mut self, written_tx: oneshot::Sender<()>) -> Self {
+        self.written_tx = Some(written_tx);
+        self
+    }
+
+    /// to_bytes returns the message as it's written to the mixnet.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        match self.connection_ref {
+            Some(reference) if compact_id_offset(self.message.kind()).is_some() => {
+                let mut bytes = vec![MessageKind::Compact.type_byte()];
+                bytes.append(&mut compact_bytes(reference, &self.message));
+                bytes
+            }
+            _ => self.message.to_bytes(),
+        }
+    }
+
+    /// payload_len returns the length of the application data the message carries, which
+    /// is what makes a message expensive to encode.
+    pub(crate) fn payload_len(&self) -> usize {
+        match &self.message {
+            Message::TransportMessage(TransportMessage {
+                message:
+                    SubstreamMessage {
+                        message_type: SubstreamMessageType::Data(data),
+                        ..
+                    },
+                ..
+            }) => data.len(),
+            _ => 0,
+        }
+    }
+
+    pub(crate) fn with_reservation(mut self, reservation: Reservation) -> Self {
+        self.reservation = Some(reservation);
+        self
+    }
+
+    /// with_deadline drops the message if it hasn't been written to the mixnet by the
+    /// deadline, and then sets `exceeded`, if given.
+    pub fn with_deadline(mut self, deadline: Instant, exceeded: Option<Arc<AtomicBool>>) -> Self {
+        self.deadline = Some(deadline);
+        self.deadline_exceeded = exceeded;
+        self
+    }
+
+    pub(crate) fn is_expired(&self) -> bool {
+        matches!(self.deadline, Some(deadline) if deadline <= Instant::now())
+    }
+
+    /// into_expired drops a message that missed its deadline, and returns what to write in
+    /// its place, if anything, see into_dropped.
+    pub(crate) fn into_expired(self) -> Option<OutboundMessage> {
+        if let Some(exceeded) = &self.deadline_exceeded {
+            exceeded.store(true, Ordering::SeqCst);
+        }
+        self.into_dropped()
+    }
+
+    /// into_dropped drops the message and returns what to write in its place, if anything.
+    /// Substream messages are replaced with a close of the substream: the remote peer
+    /// can't do without their nonce, and the substream is missing data from then on.
+    pub(crate) fn into_dropped(self) -> Option<OutboundMessage> {
+        let Message::TransportMessage(msg) = self.message else {
+            return None;
+        };
+        Some(OutboundMessage {
+            message: Message::TransportMessage(TransportMessage {
+                message: SubstreamMessage::new_close(msg.message.substream_id),
+                ..msg
+            }),
+            deadline: None,
+            deadline_exceeded: None,
+            ..self
+        })
+    }
 }
 
 pub(crate) fn parse_message_data(data: &[u8]) -> Result<InboundMessage, Error> {
@@ -332,3 +1669,343 @@ pub(crate) fn parse_message_data(data: &[u8]) -> Result<InboundMessage, Error> {
     let msg = Message::try_from_bytes(data.to_vec())?;
     Ok(InboundMessage(msg))
 }
+
+/// header_len returns how much of the given message bytes is header, ie. everything
+/// but application data: the substream data of a TransportMessage and the payload of a
+/// Broadcast or Datagram. Other messages are all header.
+pub(crate) fn header_len(data: &[u8]) -> usize {
+    match data.first().copied().and_then(MessageKind::from_type_byte) {
+        Some(MessageKind::Transport) => transport_header_len(data, 0),
+        Some(MessageKind::Broadcast) => spec::broadcast::PAYLOAD,
+        Some(MessageKind::Datagram) => spec::datagram::PAYLOAD,
+        Some(MessageKind::Compact) => {
+            let Some((_, len)) = read_varint(&data[spec::compact::REFERENCE..]) else {
+                return data.len();
+            };
+            let inner = spec::compact::REFERENCE + len;
+            match data
+                .get(inner)
+                .copied()
+                .and_then(MessageKind::from_type_byte)
+            {
+                // the inner message's fields are where they'd be without the connection ID
+                Some(MessageKind::Transport) => {
+                    transport_header_len(data, inner as isize - CONNECTION_ID_LENGTH as isize)
+                }
+                _ => data.len(),
+            }
+        }
+        _ => data.len(),
+    }
+}
+
+/// transport_header_len returns the header length of a Transport message whose fields are
+/// at the spec's offsets shifted by `shift`.
+fn transport_header_len(data: &[u8], shift: isize) -> usize {
+    let at = |offset: usize| (offset as isize + shift) as usize;
+    match data.get(at(spec::transport::SUBSTREAM_OP)) {
+        Some(&substream_op::STAMPED_DATA) => data.len().min(at(spec::transport::STAMPED_DATA)),
+        Some(&substream_op::DATA) => data.len().min(at(spec::transport::DATA)),
+        _ => data.len(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::mock::random_recipient;
+
+    #[test]
+    fn test_address_update_roundtrip_and_verify() {
+        let keypair = Keypair::generate_ed25519();
+        let msg = AddressUpdateMessage::new_signed(
+            ConnectionId::generate(),
+            7,
+            random_recipient(),
+            &keypair,
+        )
+        .unwrap();
+        let bytes = Message::AddressUpdate(msg).to_bytes();
+
+        let Message::AddressUpdate(parsed) = parse_message_data(&bytes).unwrap().0 else {
+            panic!("expected Message::AddressUpdate");
+        };
+        assert_eq!(parsed.epoch, 7);
+        assert!(parsed.verify(&PeerId::from_public_key(&keypair.public())));
+
+        // another peer can't redirect the connection
+        let other = PeerId::from_public_key(&Keypair::generate_ed25519().public());
+        assert!(!parsed.verify(&other));
+    }
+
+    #[test]
+    fn test_pong_roundtrip() {
+        let id = ConnectionId::generate();
+        let msg = PongMessage {
+            id: id.clone(),
+            ping_sent_at: 1,
+            ping_received_at: 2,
+            sent_at: 3,
+        };
+        let bytes = Message::Pong(msg).to_bytes();
+
+        let Message::Pong(parsed) = parse_message_data(&bytes).unwrap().0 else {
+            panic!("expected Message::Pong");
+        };
+        assert_eq!(parsed.id, id);
+        assert_eq!(
+            (parsed.ping_sent_at, parsed.ping_received_at, parsed.sent_at),
+            (1, 2, 3)
+        );
+    }
+
+    #[test]
+    fn test_reachability_request_roundtrip() {
+        let id = ConnectionId::generate();
+        let address = random_recipient();
+        let msg = ReachabilityRequestMessage::new(id.clone(), address);
+        let nonce = msg.nonce;
+        let bytes = Message::ReachabilityRequest(msg).to_bytes();
+
+        let Message::ReachabilityRequest(parsed) = parse_message_data(&bytes).unwrap().0 else {
+            panic!("expected Message::ReachabilityRequest");
+        };
+        assert_eq!(parsed.id, id);
+        assert_eq!(parsed.nonce, nonce);
+        assert_eq!(parsed.address, address);
+
+        let bytes = Message::DialBack(DialBackMessage { nonce }).to_bytes();
+        let Message::DialBack(parsed) = parse_message_data(&bytes).unwrap().0 else {
+            panic!("expected Message::DialBack");
+        };
+        assert_eq!(parsed.nonce, nonce);
+    }
+
+    #[test]
+    fn test_echo_roundtrip() {
+        let address = random_recipient();
+        let msg = EchoRequestMessage::new(address);
+        let (nonce, sent_at) = (msg.nonce, msg.sent_at);
+        let bytes = Message::EchoRequest(msg).to_bytes();
+
+        let Message::EchoRequest(parsed) = parse_message_data(&bytes).unwrap().0 else {
+            panic!("expected Message::EchoRequest");
+        };
+        assert_eq!((parsed.nonce, parsed.sent_at), (nonce, sent_at));
+        assert_eq!(parsed.reply_to, address);
+
+        let msg = EchoReplyMessage {
+            nonce,
+            request_sent_at: 1,
+            request_received_at: 2,
+            sent_at: 3,
+        };
+        let bytes = Message::EchoReply(msg).to_bytes();
+        let Message::EchoReply(parsed) = parse_message_data(&bytes).unwrap().0 else {
+            panic!("expected Message::EchoReply");
+        };
+        assert_eq!(parsed.nonce, nonce);
+        assert_eq!(
+            (
+                parsed.request_sent_at,
+                parsed.request_received_at,
+                parsed.sent_at
+            ),
+            (1, 2, 3)
+        );
+    }
+
+    #[test]
+    fn test_connection_denied_roundtrip() {
+        let id = ConnectionId::generate();
+        let msg = ConnectionDeniedMessage {
+            id: id.clone(),
+            reason: DenialReason::RateLimited,
+        };
+        let mut bytes = Message::ConnectionDenied(msg).to_bytes();
+
+        let Message::ConnectionDenied(parsed) = parse_message_data(&bytes).unwrap().0 else {
+            panic!("expected Message::ConnectionDenied");
+        };
+        assert_eq!(parsed.id, id);
+        assert_eq!(parsed.reason, DenialReason::RateLimited);
+
+        // reasons added by newer versions are still understood as a denial
+        *bytes.last_mut().unwrap() = 42;
+        let Message::ConnectionDenied(parsed) = parse_message_data(&bytes).unwrap().0 else {
+            panic!("expected Message::ConnectionDenied");
+        };
+        assert_eq!(parsed.reason, DenialReason::Other);
+    }
+
+    #[test]
+    fn test_stamped_data_age() {
+        let sent_at = unix_micros() - 250_000;
+        let mut data = SubstreamMessage::new_with_data(SubstreamId::generate(), b"hi".to_vec());
+        data.sent_at = Some(sent_at);
+        let msg = Message::TransportMessage(TransportMessage {
+            nonce: 1,
+            id: ConnectionId::generate(),
+            message: data,
+        });
+        let inbound = parse_message_data(&msg.to_bytes()).unwrap();
+        assert_eq!(inbound.data_payload(), Some(&b"hi"[..]));
+        let age = inbound.age().unwrap();
+        assert_eq!(
+            age.sent_at,
+            Some(UNIX_EPOCH + Duration::from_micros(sent_at))
+        );
+        assert!(age.transit_time().unwrap() >= Duration::from_millis(250));
+        assert!(age.age() >= age.transit_time().unwrap());
+
+        // without the extension, only the receive time is known
+        let msg = Message::TransportMessage(TransportMessage {
+            nonce: 1,
+            id: ConnectionId::generate(),
+            message: SubstreamMessage::new_with_data(SubstreamId::generate(), b"hi".to_vec()),
+        });
+        let age = parse_message_data(&msg.to_bytes()).unwrap().age().unwrap();
+        assert_eq!(age.sent_at, None);
+        assert_eq!(age.transit_time(), None);
+    }
+
+    #[test]
+    fn test_unknown_message_types() {
+        // an unknown substream message type still parses, so its nonce isn't lost
+        let msg = Message::TransportMessage(TransportMessage {
+            nonce: 7,
+            id: ConnectionId::generate(),
+            message: SubstreamMessage::new(
+                SubstreamId::generate(),
+                SubstreamMessageType::Unknown(0x7f),
+            ),
+        });
+        match parse_message_data(&msg.to_bytes()).unwrap().0 {
+            Message::TransportMessage(TransportMessage { nonce, message, .. }) => {
+                assert_eq!(nonce, 7);
+                assert!(matches!(
+                    message.message_type,
+                    SubstreamMessageType::Unknown(0x7f)
+                ));
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        // an unknown message type is reported as such, so it can be skipped
+        let mut bytes = Message::Broadcast(b"hello".to_vec()).to_bytes();
+        bytes[0] = 0xee;
+        assert!(MessageKind::from_type_byte(0xee).is_none());
+        assert!(matches!(
+            parse_message_data(&bytes),
+            Err(Error::UnknownMessageType(0xee))
+        ));
+    }
+
+    #[test]
+    fn test_connection_request_target_roundtrip() {
+        for target in [None, Some(PeerId::random())] {
+            for extensions in [
+                0,
+                spec::extension::LATENCY,
+                spec::extension::LATENCY | spec::extension::CLOCK,
+                spec::extension::CLOCK | spec::extension::COMPACT_ID,
+                spec::extension::COMPACT_ID | spec::extension::USER_AGENT,
+            ] {
+                let peer_id = PeerId::random();
+                let recipient = random_recipient();
+                let timestamps = if extensions & spec::extension::CLOCK != 0 {
+                    vec![1, 2, 3]
+                } else {
+                    vec![]
+                };
+                let msg = ConnectionMessage {
+                    peer_id,
+                    id: ConnectionId::generate(),
+                    recipient: Some(recipient),
+                    target,
+                    extensions,
+                    handshake_payload: Some(b"hello".to_vec()),
+                    timestamps: timestamps.clone(),
+                    connection_ref: (extensions & spec::extension::COMPACT_ID != 0)
+                        .then_some(u64::MAX),
+                    user_agent: (extensions & spec::extension::USER_AGENT != 0)
+                        .then(|| "rust-libp2p-nym/0.1.0".to_string()),
+                    reply_tag: None,
+                };
+                let bytes = Message::ConnectionRequest(msg).to_bytes();
+                let Message::ConnectionRequest(parsed) = parse_message_data(&bytes).unwrap().0
+                else {
+                    panic!("expected Message::ConnectionRequest");
+                };
+                assert_eq!(parsed.target, target);
+                assert_eq!(parsed.recipient, Some(recipient));
+                assert_eq!(parsed.peer_id, peer_id);
+                assert_eq!(parsed.extensions, extensions);
+                assert_eq!(parsed.handshake_payload.as_deref(), Some(&b"hello"[..]));
+                assert_eq!(parsed.timestamps, timestamps);
+                assert_eq!(
+                    parsed.connection_ref,
+                    (extensions & spec::extension::COMPACT_ID != 0).then_some(u64::MAX)
+                );
+                assert_eq!(
+                    parsed.user_agent.as_deref(),
+                    (extensions & spec::extension::USER_AGENT != 0)
+                        .then_some("rust-libp2p-nym/0.1.0")
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_compact_message_roundtrip() {
+        let id = ConnectionId::generate();
+        let substream_id = SubstreamId::generate();
+        let msg = Message::TransportMessage(TransportMessage {
+            nonce: 5,
+            id: id.clone(),
+            message: SubstreamMessage::new_with_data(substream_id.clone(), b"hi".to_vec()),
+        });
+        let full_len = msg.to_bytes().len();
+        let bytes = OutboundMessage::new(msg, random_recipient())
+            .with_connection_ref(Some(1000))
+            .to_bytes();
+        assert_eq!(bytes.len(), full_len + 1 + 2 - CONNECTION_ID_LENGTH);
+        assert_eq!(header_len(&bytes), bytes.len() - 2);
+
+        let Message::Compact(parsed) = parse_message_data(&bytes).unwrap().0 else {
+            panic!("expected Message::Compact");
+        };
+        assert_eq!(parsed.reference, 1000);
+        let mut msg = *parsed.message;
+        msg.set_connection_id(id.clone());
+        let Message::TransportMessage(msg) = msg else {
+            panic!("expected Message::TransportMessage");
+        };
+        assert_eq!(msg.id, id);
+        assert_eq!(msg.nonce, 5);
+        assert_eq!(msg.message.substream_id, substream_id);
+
+        // messages that aren't tied to a connection are sent as they are
+        let bytes = OutboundMessage::new(Message::Broadcast(b"hi".to_vec()), random_recipient())
+            .with_connection_ref(Some(1000))
+            .to_bytes();
+        assert_eq!(
+            parse_message_data(&bytes).unwrap().kind(),
+            MessageKind::Broadcast
+        );
+
+        // a compact message can't wrap another one, nor be cut short
+        let mut bytes = vec![MessageKind::Compact.type_byte()];
+        bytes.append(&mut compact_bytes(
+            1,
+            &Message::Ping(PingMessage {
+                id: ConnectionId::default(),
+                sent_at: 1,
+            }),
+        ));
+        assert!(parse_message_data(&bytes[..bytes.len() - 1]).is_err());
+        bytes[2] = MessageKind::Compact.type_byte();
+        assert!(parse_message_data(&bytes).is_err());
+        assert!(parse_message_data(&[MessageKind::Compact.type_byte(), 0x80]).is_err());
+    }
+}
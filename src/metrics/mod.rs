@@ -0,0 +1,414 @@
+use std::fmt::{self, Write};
+
+#[cfg(feature = "metrics")]
+pub mod prometheus;
+
+/// METRIC_PREFIX starts the name of every metric the transport exports.
+pub const METRIC_PREFIX: &str = "libp2p_nym_";
+
+/// MetricType is the kind of a metric, as in the Prometheus exposition format.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MetricType {
+    /// only ever goes up, eg. messages sent; graph its rate
+    Counter,
+    /// goes up and down, eg. memory used
+    Gauge,
+    /// samples counted into buckets, eg. durations; exported as `_bucket`, `_sum` and
+    /// `_count` series
+    Histogram,
+}
+
+impl MetricType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+            MetricType::Histogram => "histogram",
+        }
+    }
+}
+
+impl fmt::Display for MetricType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// MetricDescriptor describes a metric the transport exports: its full name, type,
+/// labels, and what it measures, along with where the value comes from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MetricDescriptor {
+    pub name: &'static str,
+    pub metric_type: MetricType,
+    /// the labels each sample of the metric has; empty if there's a single series
+    pub labels: &'static [&'static str],
+    pub help: &'static str,
+    /// the accessor the value is read from
+    pub source: &'static str,
+}
+
+/// ALL describes every metric the transport exports, so dashboards and alerts can be
+/// generated from it rather than from the code.
+pub const ALL: &[MetricDescriptor] = &[
+    MetricDescriptor {
+        name: "libp2p_nym_messages_sent_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Messages written to the mixnet, counted once however many fragments they took.",
+        source: "TransportStats::traffic().messages_sent",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_sent_bytes_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Bytes of the messages written to the mixnet.",
+        source: "TransportStats::traffic().bytes_sent",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_messages_received_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Messages read from the mixnet, counted once they're reassembled.",
+        source: "TransportStats::traffic().messages_received",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_received_bytes_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Bytes of the messages read from the mixnet.",
+        source: "TransportStats::traffic().bytes_received",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_reconnects_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Reconnects to the mixnet after the connection to it was lost.",
+        source: "TransportStats::traffic().reconnects",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_connections",
+        metric_type: MetricType::Gauge,
+        labels: &[],
+        help: "Connections the transport holds, including those still being upgraded.",
+        source: "TransportStats::active_connections()",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_dials_succeeded_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Dials that established a connection.",
+        source: "TransportStats::dials().succeeded",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_dials_failed_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Dials that failed, whatever the stage.",
+        source: "TransportStats::dials().failed",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_queued_dials",
+        metric_type: MetricType::Gauge,
+        labels: &[],
+        help: "Dials waiting for others to finish, beyond the maximum of concurrent dials.",
+        source: "TransportStats::queued_dials()",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_gateway_handed_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Messages handed to our gateway.",
+        source: "TransportStats::gateway().handed_to_gateway",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_gateway_lost_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Messages lost before reaching our gateway, including those sent again.",
+        source: "TransportStats::gateway().lost_before_gateway",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_gateway_retransmitted_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Messages sent again after being lost before our gateway.",
+        source: "TransportStats::gateway().retransmitted",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_reassembly_timeouts_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Fragmented messages dropped for not arriving within the reassembly timeout.",
+        source: "TransportStats::reassembly().timed_out",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_reassembly_evictions_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Partially received messages evicted to make room for newer ones.",
+        source: "TransportStats::reassembly().evicted",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_unknown_message_types_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Inbound messages of a type this version doesn't know, eg. from newer peers.",
+        source: "TransportStats::unknown_message_types()",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_inbound_frames_total",
+        metric_type: MetricType::Counter,
+        labels: &["framing"],
+        help: "Messages read from the mixnet, by framing version.",
+        source: "TransportStats::inbound_frames()",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_upgrade_timeouts_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Connections dropped for not negotiating a protocol within the upgrade timeout.",
+        source: "TransportStats::upgrade_timeouts()",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_handshake_failures_total",
+        metric_type: MetricType::Counter,
+        labels: &["stage"],
+        help: "Connection handshakes that failed, by the stage they failed at.",
+        source: "TransportStats::handshakes().failures(stage)",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_handshake_duration_seconds",
+        metric_type: MetricType::Histogram,
+        labels: &["outcome"],
+        help: "Duration of connection handshakes, by outcome: established or the failed stage.",
+        source: "TransportStats::handshakes()",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_send_stage_duration_seconds",
+        metric_type: MetricType::Histogram,
+        labels: &["stage"],
+        help: "Time outbound messages spent at each stage of the send path, and in total.",
+        source: "TransportStats::send_path()",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_substream_resets_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Substreams reset because a connection had too many.",
+        source: "TransportStats::substream_resets()",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_clock_skews_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Handshakes that found the peer's clock off by more than the threshold.",
+        source: "TransportStats::clock_skews()",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_connections_by_user_agent_total",
+        metric_type: MetricType::Counter,
+        labels: &["user_agent"],
+        help: "Connections established, by the user agent the peer announced in the handshake.",
+        source: "TransportStats::user_agents()",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_filter_drops_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Inbound messages dropped by the inbound filter.",
+        source: "TransportStats::filter_drops()",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_filter_tags_total",
+        metric_type: MetricType::Counter,
+        labels: &["tag"],
+        help: "Inbound messages tagged by the inbound filter.",
+        source: "TransportStats::filter_tags()",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_protocol_substreams_total",
+        metric_type: MetricType::Counter,
+        labels: &["peer_id", "protocol"],
+        help: "Substreams opened, by the libp2p protocol negotiated on them.",
+        source: "TransportStats::all_protocol_traffic()[peer_id][protocol].substreams",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_protocol_sent_bytes_total",
+        metric_type: MetricType::Counter,
+        labels: &["peer_id", "protocol"],
+        help: "Bytes written to substreams, by the libp2p protocol negotiated on them.",
+        source: "TransportStats::all_protocol_traffic()[peer_id][protocol].bytes_sent",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_protocol_received_bytes_total",
+        metric_type: MetricType::Counter,
+        labels: &["peer_id", "protocol"],
+        help: "Bytes read from substreams, by the libp2p protocol negotiated on them.",
+        source: "TransportStats::all_protocol_traffic()[peer_id][protocol].bytes_received",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_latency_rtt_seconds",
+        metric_type: MetricType::Gauge,
+        labels: &["peer_id"],
+        help: "Smoothed round-trip time of latency probes.",
+        source: "TransportStats::all_latency()[peer_id].smoothed_rtt",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_latency_outbound_delay_seconds",
+        metric_type: MetricType::Gauge,
+        labels: &["peer_id"],
+        help: "Estimated mixnet delay towards the peer in the latest latency probe.",
+        source: "TransportStats::all_latency()[peer_id].outbound_delay",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_latency_inbound_delay_seconds",
+        metric_type: MetricType::Gauge,
+        labels: &["peer_id"],
+        help: "Estimated mixnet delay from the peer in the latest latency probe.",
+        source: "TransportStats::all_latency()[peer_id].inbound_delay",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_clock_offset_seconds",
+        metric_type: MetricType::Gauge,
+        labels: &["peer_id"],
+        help: "Offset of the peer's clock from its latest handshake; positive if it's ahead.",
+        source: "TransportStats::clock_offset(peer_id)",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_connection_quality_score",
+        metric_type: MetricType::Gauge,
+        labels: &["peer_id"],
+        help: "Quality of the peer's latest rated connection, from 0 to 1.",
+        source: "TransportStats::all_quality()[peer_id].score",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_connection_loss_ratio",
+        metric_type: MetricType::Gauge,
+        labels: &["peer_id"],
+        help: "Share of unanswered latency probes on the peer's latest rated connection.",
+        source: "TransportStats::all_quality()[peer_id].loss()",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_reachable",
+        metric_type: MetricType::Gauge,
+        labels: &[],
+        help: "1 if the latest reachability probe of our Nym address succeeded, 0 if it failed.",
+        source: "TransportStats::reachability().reachable",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_reachability_failures",
+        metric_type: MetricType::Gauge,
+        labels: &[],
+        help: "Reachability probes of our Nym address that failed in a row.",
+        source: "TransportStats::reachability().consecutive_failures",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_memory_used_bytes",
+        metric_type: MetricType::Gauge,
+        labels: &["buffer"],
+        help: "Memory held by the transport's buffers, by kind of buffer.",
+        source: "MemoryBudget::used_by(buffer)",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_memory_limit_bytes",
+        metric_type: MetricType::Gauge,
+        labels: &[],
+        help: "Most memory the transport's buffers may hold, if limited.",
+        source: "MemoryBudget::limit()",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_memory_shed_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Partially received messages dropped because the memory budget was exhausted.",
+        source: "MemoryBudget::shed()",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_memory_shed_cover_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Outbound probes dropped because the memory budget was under pressure.",
+        source: "MemoryBudget::shed_cover()",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_memory_shed_low_priority_total",
+        metric_type: MetricType::Counter,
+        labels: &[],
+        help: "Low priority substream messages dropped because the memory budget was exhausted.",
+        source: "MemoryBudget::shed_low_priority()",
+    },
+    MetricDescriptor {
+        name: "libp2p_nym_circuit_breaker_open",
+        metric_type: MetricType::Gauge,
+        labels: &[],
+        help: "1 while the circuit breaker considers the mixnet down, 0 otherwise.",
+        source: "CircuitBreaker::is_open()",
+    },
+];
+
+/// find returns the descriptor of the metric with the given name, if it's exported.
+pub fn find(name: &str) -> Option<&'static MetricDescriptor> {
+    ALL.iter().find(|descriptor| descriptor.name == name)
+}
+
+/// render renders the descriptors as the HELP and TYPE lines of the Prometheus exposition
+/// format. The labels of each metric are listed at the end of its help text.
+pub fn render(descriptors: &[MetricDescriptor]) -> String {
+    let mut out = String::new();
+    for descriptor in descriptors {
+        // writing to a String can't fail
+        write!(out, "# HELP {} {}", descriptor.name, descriptor.help).ok();
+        if !descriptor.labels.is_empty() {
+            write!(out, " Labels: {}.", descriptor.labels.join(", ")).ok();
+        }
+        writeln!(out).ok();
+        writeln!(out, "# TYPE {} {}", descriptor.name, descriptor.metric_type).ok();
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_metric_descriptors() {
+        let mut names = HashSet::new();
+        for descriptor in ALL {
+            assert!(
+                names.insert(descriptor.name),
+                "duplicate {}",
+                descriptor.name
+            );
+            assert!(descriptor.name.starts_with(METRIC_PREFIX));
+            assert!(descriptor
+                .name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'));
+            // counters end in _total, and nothing else does
+            assert_eq!(
+                descriptor.name.ends_with("_total"),
+                descriptor.metric_type == MetricType::Counter,
+                "{}",
+                descriptor.name
+            );
+        }
+
+        let descriptor = find("libp2p_nym_protocol_sent_bytes_total").unwrap();
+        assert_eq!(descriptor.labels, &["peer_id", "protocol"]);
+        assert!(find("libp2p_nym_unknown").is_none());
+
+        let rendered = render(&[*descriptor, *find("libp2p_nym_reachable").unwrap()]);
+        assert_eq!(
+            rendered,
+            "# HELP libp2p_nym_protocol_sent_bytes_total Bytes written to substreams, by the \
+             libp2p protocol negotiated on them. Labels: peer_id, protocol.\n\
+             # TYPE libp2p_nym_protocol_sent_bytes_total counter\n\
+             # HELP libp2p_nym_reachable 1 if the latest reachability probe of our Nym address \
+             succeeded, 0 if it failed.\n\
+             # TYPE libp2p_nym_reachable gauge\n"
+        );
+    }
+}
@@ -0,0 +1,180 @@
+//! Export of the transport's statistics through [`prometheus_client`], the crate
+//! `libp2p-metrics` exports the swarm's metrics through, so both end up in one registry
+//! and are scraped together.
+//!
+//! The values are read from [`TransportStats`] on each scrape, so exporting them costs
+//! nothing in between. Every metric is described in [`ALL`](super::ALL); their names
+//! already start with [`METRIC_PREFIX`](super::METRIC_PREFIX), so register the collector
+//! with a registry that doesn't add a prefix of its own.
+
+use prometheus_client::collector::Collector;
+use prometheus_client::encoding::{EncodeMetric, MetricEncoder};
+use prometheus_client::metrics::counter::ConstCounter;
+use prometheus_client::metrics::gauge::ConstGauge;
+use prometheus_client::metrics::MetricType;
+use prometheus_client::registry::{Descriptor, LocalMetric, Registry};
+use prometheus_client::MaybeOwned;
+use std::borrow::Cow;
+use std::fmt;
+
+use super::find;
+use crate::stats::{DurationHistogram, HandshakeStats, TransportStats, HANDSHAKE_DURATION_BUCKETS};
+
+type Metric<'a> = (Cow<'a, Descriptor>, MaybeOwned<'a, Box<dyn LocalMetric>>);
+
+/// NymCollector collects the metrics of a transport from its stats on each scrape.
+#[derive(Debug)]
+pub struct NymCollector {
+    stats: TransportStats,
+}
+
+impl NymCollector {
+    pub fn new(stats: TransportStats) -> Self {
+        NymCollector { stats }
+    }
+
+    /// register registers a collector of the given stats with the registry, eg. the one
+    /// passed to `libp2p_metrics::Metrics::new`.
+    pub fn register(registry: &mut Registry, stats: TransportStats) {
+        registry.register_collector(Box::new(NymCollector::new(stats)));
+    }
+}
+
+impl Collector for NymCollector {
+    fn collect<'a>(&'a self) -> Box<dyn Iterator<Item = Metric<'a>> + 'a> {
+        let traffic = self.stats.traffic();
+        let dials = self.stats.dials();
+        let counters = [
+            ("libp2p_nym_messages_sent_total", traffic.messages_sent),
+            ("libp2p_nym_sent_bytes_total", traffic.bytes_sent),
+            (
+                "libp2p_nym_messages_received_total",
+                traffic.messages_received,
+            ),
+            ("libp2p_nym_received_bytes_total", traffic.bytes_received),
+            ("libp2p_nym_reconnects_total", traffic.reconnects),
+            ("libp2p_nym_dials_succeeded_total", dials.succeeded),
+            ("libp2p_nym_dials_failed_total", dials.failed),
+        ];
+
+        let mut metrics: Vec<Metric<'a>> = counters
+            .into_iter()
+            .map(|(name, value)| metric(name, ConstCounter::new(value)))
+            .collect();
+        let gauges = [
+            ("libp2p_nym_connections", self.stats.active_connections()),
+            ("libp2p_nym_queued_dials", self.stats.queued_dials() as u64),
+        ];
+        metrics.extend(
+            gauges
+                .into_iter()
+                .map(|(name, value)| metric(name, ConstGauge::new(value as i64))),
+        );
+        metrics.push(metric(
+            "libp2p_nym_handshake_duration_seconds",
+            HandshakeDurations(self.stats.handshakes()),
+        ));
+        Box::new(metrics.into_iter())
+    }
+}
+
+/// metric returns the metric with the given name, described as in [`ALL`](super::ALL).
+fn metric<'a>(name: &str, metric: impl LocalMetric + 'static) -> Metric<'a> {
+    let descriptor = find(name).expect("exported metrics are described");
+    // the encoder appends the suffix to counters itself
+    let name = descriptor
+        .name
+        .strip_suffix("_total")
+        .unwrap_or(descriptor.name);
+    (
+        Cow::Owned(Descriptor::new(name, descriptor.help, None, None, vec![])),
+        MaybeOwned::Owned(Box::new(metric)),
+    )
+}
+
+/// HandshakeDurations encodes the handshake durations as a histogram with a series per
+/// outcome: established, or the stage the handshake failed at.
+#[derive(Debug)]
+struct HandshakeDurations(HandshakeStats);
+
+impl EncodeMetric for HandshakeDurations {
+    fn encode(&self, mut encoder: MetricEncoder) -> fmt::Result {
+        encode_histogram(
+            encoder.encode_family(&[("outcome", "established")])?,
+            &self.0.established,
+        )?;
+        // in a stable order, so consecutive scrapes are easy to compare
+        let mut failed: Vec<_> = self.0.failed.iter().collect();
+        failed.sort_by_key(|(stage, _)| stage.as_str());
+        for (stage, histogram) in failed {
+            encode_histogram(
+                encoder.encode_family(&[("outcome", stage.as_str())])?,
+                histogram,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn metric_type(&self) -> MetricType {
+        MetricType::Histogram
+    }
+}
+
+/// encode_histogram encodes a histogram of durations in seconds. Its buckets add up
+/// towards the larger ones, while the encoder adds them up itself.
+fn encode_histogram(mut encoder: MetricEncoder, histogram: &DurationHistogram) -> fmt::Result {
+    let mut buckets = Vec::with_capacity(HANDSHAKE_DURATION_BUCKETS.len() + 1);
+    let mut below = 0;
+    for (bound, count) in HANDSHAKE_DURATION_BUCKETS.iter().zip(histogram.buckets) {
+        buckets.push((bound.as_secs_f64(), count - below));
+        below = count;
+    }
+    // the encoder writes f64::MAX as +Inf
+    buckets.push((f64::MAX, histogram.count - below));
+    encoder.encode_histogram::<()>(histogram.sum.as_secs_f64(), histogram.count, &buckets, None)
+}
+
+#[cfg(test)]
+mod test {
+    use prometheus_client::encoding::text::encode;
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::stats::HandshakeFailure;
+
+    #[test]
+    fn test_prometheus_collector() {
+        let stats = TransportStats::default();
+        let mut registry = Registry::default();
+        NymCollector::register(&mut registry, stats.clone());
+
+        stats.record_dial(true);
+        stats.record_dial(false);
+        stats.set_active_connections(3);
+        stats.record_handshake(None, SystemTime::now() - Duration::from_millis(300));
+        stats.record_handshake(
+            Some(HandshakeFailure::TimedOut),
+            SystemTime::now() - Duration::from_secs(90),
+        );
+
+        let mut out = String::new();
+        encode(&mut out, &registry).unwrap();
+        for line in [
+            "# TYPE libp2p_nym_messages_sent counter",
+            "libp2p_nym_messages_sent_total 0",
+            "libp2p_nym_dials_succeeded_total 1",
+            "libp2p_nym_dials_failed_total 1",
+            "# TYPE libp2p_nym_connections gauge",
+            "libp2p_nym_connections 3",
+            "libp2p_nym_queued_dials 0",
+            "# TYPE libp2p_nym_handshake_duration_seconds histogram",
+            "libp2p_nym_handshake_duration_seconds_count{outcome=\"established\"} 1",
+            "libp2p_nym_handshake_duration_seconds_bucket{le=\"0.25\",outcome=\"established\"} 0",
+            "libp2p_nym_handshake_duration_seconds_bucket{le=\"0.5\",outcome=\"established\"} 1",
+            "libp2p_nym_handshake_duration_seconds_count{outcome=\"timed_out\"} 1",
+            "libp2p_nym_handshake_duration_seconds_bucket{le=\"+Inf\",outcome=\"timed_out\"} 1",
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {line:?} in\n{out}");
+        }
+    }
+}
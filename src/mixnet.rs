@@ -1,185 +1,2588 @@
 use futures::{
-    pin_mut, select,
-    stream::{SplitSink, SplitStream},
+    future::{self, BoxFuture, FutureExt},
+    Future, Sink, Stream,
 };
-use futures::{FutureExt, SinkExt, StreamExt};
 use nym_sphinx::addressing::clients::Recipient;
-use nym_websocket::{requests::ClientRequest, responses::ServerResponse};
-use tokio::{
-    net::TcpStream,
-    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
 };
-use tokio_tungstenite::{
-    connect_async, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream,
+use tokio::{
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        watch, Notify, OwnedSemaphorePermit, Semaphore,
+    },
+    time::Instant,
 };
-use tracing::debug;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_util::sync::PollSemaphore;
+use tracing::{debug, info, trace, warn, Instrument};
 
+use crate::backend::{
+    MixnetBackend, MixnetInfo, PacketSize, SendOutcome, SenderTag, WebsocketBackend,
+};
+use crate::breaker::{CircuitBreaker, CircuitBreakerConfig, OutageDetector};
+use crate::budget::{BufferKind, MemoryBudget, Reservation};
+use crate::capture::MessageCapture;
+use crate::config::BandwidthLimiter;
+use crate::encode::{encode, EncodedMessage, OffloadedEncodes};
 use crate::error::Error;
+use crate::events::{ConnectionEvent, ConnectionEventSender};
+use crate::fragment::{
+    fragment, Reassembler, DEFAULT_MAX_PARTIAL_MESSAGES, DEFAULT_REASSEMBLY_TIMEOUT,
+};
+use crate::framing::{frame, unframe, Framing};
+use crate::journal::{Journal, JournalDirection};
 use crate::message::*;
+pub use crate::message::{InboundMessage, MessageAge, OutboundMessage};
+use crate::queue::{OutboundQueue, PendingWrite};
+use crate::rotation::{AddressEvent, AddressRotation};
+use crate::stats::{
+    GatewayOutcomes, InboundFramings, MixnetTraffic, ReassemblyEvictions, SendPathTimings,
+    UnknownMessageTypes,
+};
+use crate::testing::ErrorInjector;
+use crate::DEFAULT_ENCODE_OFFLOAD_THRESHOLD;
+
+/// how long to wait before retrying after a failed reconnection attempt; the delay
+/// doubles with every attempt that fails in a row, up to MAX_RECONNECT_DELAY.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// how often partially received messages are expired while reading from the mixnet is
+/// paused for the memory budget, so they can't hold on to it forever.
+const BUDGET_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// how many times a message that was lost before reaching the gateway is sent again.
+const MAX_GATEWAY_RETRANSMITS: u32 = 2;
+
+/// the most messages kept until the backend reports their outcome; the oldest are
+/// forgotten first, eg. if the backend never reports some.
+const MAX_PENDING_SENDS: usize = 1024;
+
+/// the default number of messages sent through a MixnetConnection's Sink implementation
+/// that can wait to be written to the mixnet.
+const DEFAULT_MAX_IN_FLIGHT: usize = 64;
+
+/// the default number of messages without a permit of their own, eg. control messages and
+/// datagrams, that can wait to be written to the mixnet.
+pub(crate) const DEFAULT_OUTBOUND_QUEUE_CAPACITY: usize = 1024;
+
+/// connect opens a connection to the Nym websockets endpoint at `uri`.
+/// It returns our Nym address, a stream of messages received from the mixnet, and a sink
+/// for messages to be written to the mixnet which allows at most `max_in_flight` messages
+/// to be queued but not yet written.
+pub async fn connect(
+    uri: &String,
+    max_in_flight: usize,
+) -> Result<(Recipient, InboundStream, OutboundSink), Error> {
+    let backend = WebsocketBackend::connect(uri, 1).await?;
+    Ok(connect_with_backend(backend, max_in_flight))
+}
+
+/// connect_with_backend is like connect, but reaches the mixnet through the given backend.
+pub fn connect_with_backend<B: MixnetBackend>(
+    backend: B,
+    max_in_flight: usize,
+) -> (Recipient, InboundStream, OutboundSink) {
+    let (recipient, inbound_rx, outbound_tx) = initialize_mixnet_with_backend(backend, None);
+    (
+        recipient,
+        InboundStream::new(inbound_rx),
+        OutboundSink::new(outbound_tx, max_in_flight),
+    )
+}
+
+/// open_with_backend starts the mixnet task for the given backend, returning a handle for
+/// writing to the mixnet and a stream of messages received from it.
+pub fn open_with_backend<B: MixnetBackend>(backend: B) -> (MixnetConnection, InboundStream) {
+    let channels = initialize_mixnet_with_rotation(backend, None, None);
+    (
+        MixnetConnection::new(
+            channels.outbound_tx,
+            channels.broadcast_tx,
+            channels.status_rx,
+        ),
+        InboundStream::new(channels.inbound_rx),
+    )
+}
+
+/// MixnetConnection is a cloneable handle for writing to the mixnet.
+///
+/// Besides [`send`](MixnetConnection::send), which never waits, it implements
+/// `Sink<OutboundMessage>` with flow control: the sink is only ready while the mixnet is
+/// connected and fewer than `max_in_flight` messages sent through it (by this handle
+/// and its clones) are still waiting to be written, so producers using eg.
+/// `SinkExt::send_all` are slowed down to the rate the mixnet accepts messages at.
+pub struct MixnetConnection {
+    outbound_tx: OutboundSender,
+    broadcast_tx: UnboundedSender<BroadcastMessage>,
+    status_rx: watch::Receiver<MixnetStatus>,
+
+    /// bounds the messages sent through the Sink which haven't been written yet;
+    /// shared between clones
+    in_flight: PollSemaphore,
+
+    /// permit acquired in poll_ready, attached to the next message in start_send
+    permit: Option<OwnedSemaphorePermit>,
+
+    /// resolves once the mixnet is connected again, while poll_ready is waiting for it
+    reconnected: Option<BoxFuture<'static, Result<(), Error>>>,
+
+    /// events about the connections of the transport using the mixnet, if any; shared
+    /// between clones
+    pub(crate) events: ConnectionEventSender,
+}
+
+impl MixnetConnection {
+    pub(crate) fn new(
+        outbound_tx: OutboundSender,
+        broadcast_tx: UnboundedSender<BroadcastMessage>,
+        status_rx: watch::Receiver<MixnetStatus>,
+    ) -> Self {
+        MixnetConnection {
+            outbound_tx,
+            broadcast_tx,
+            status_rx,
+            in_flight: PollSemaphore::new(Arc::new(Semaphore::new(DEFAULT_MAX_IN_FLIGHT))),
+            permit: None,
+            reconnected: None,
+            events: ConnectionEventSender::default(),
+        }
+    }
+
+    /// with_max_in_flight sets how many messages sent through the Sink implementation can
+    /// wait to be written to the mixnet before the sink stops being ready.
+    /// The limit is shared with clones made afterwards.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.in_flight = PollSemaphore::new(Arc::new(Semaphore::new(max_in_flight.max(1))));
+        self.permit = None;
+        self
+    }
+
+    /// send writes a message to the mixnet, eg. one built with
+    /// [`OutboundMessage::datagram`]. It fails with [`Error::OutboundQueueFull`] if too
+    /// many messages are already waiting to be written, 1024 by default; see
+    /// [`NymTransport::with_outbound_capacity`](crate::transport::NymTransport::with_outbound_capacity).
+    pub fn send(&self, message: OutboundMessage) -> Result<(), Error> {
+        self.outbound_tx.send(message).map_err(Error::from)
+    }
+
+    /// connection_events returns a stream of the connections being opened and closed by the
+    /// transport this handle was obtained from, with
+    /// [`NymTransport::mixnet_connection`](crate::transport::NymTransport::mixnet_connection),
+    /// for building session management without a libp2p Swarm. Every stream receives the
+    /// events from when it was created; handles not obtained from a transport have none.
+    pub fn connection_events(&self) -> impl Stream<Item = ConnectionEvent> + Send + Unpin {
+        UnboundedReceiverStream::new(self.events.subscribe())
+    }
+
+    /// broadcast writes the payload to all the given recipients, for pubsub-style fan-out.
+    /// The payload is serialized once and handed to the mixnet task in one go; it's then
+    /// written to one recipient at a time, taking turns with other outbound messages.
+    /// Recipients receive it as an [`InboundMessage`] with a
+    /// [`broadcast_payload`](InboundMessage::broadcast_payload).
+    pub fn broadcast(&self, recipients: Vec<Recipient>, payload: Vec<u8>) -> Result<(), Error> {
+        self.broadcast_with_priority(recipients, payload, MessagePriority::default())
+    }
+
+    /// broadcast_with_priority is like broadcast, with the given message priority.
+    pub fn broadcast_with_priority(
+        &self,
+        recipients: Vec<Recipient>,
+        payload: Vec<u8>,
+        priority: MessagePriority,
+    ) -> Result<(), Error> {
+        let mut msg = BroadcastMessage::new(payload, recipients);
+        msg.priority = priority;
+        // held until the broadcast has been written to all of its recipients
+        msg.permit = Some(self.outbound_tx.reserve()?);
+        self.broadcast_tx
+            .send(msg)
+            .map_err(|e| Error::OutboundSendError(e.to_string()))
+    }
+}
+
+impl Clone for MixnetConnection {
+    /// clones share the in-flight limit, but not a permit acquired by poll_ready.
+    fn clone(&self) -> Self {
+        MixnetConnection {
+            outbound_tx: self.outbound_tx.clone(),
+            broadcast_tx: self.broadcast_tx.clone(),
+            status_rx: self.status_rx.clone(),
+            in_flight: self.in_flight.clone(),
+            permit: None,
+            reconnected: None,
+            events: self.events.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for MixnetConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MixnetConnection")
+            .field("status", &*self.status_rx.borrow())
+            .field("available_permits", &self.in_flight.available_permits())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Sink<OutboundMessage> for MixnetConnection {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        // don't pile up messages while the mixnet is unreachable
+        if self.reconnected.is_none() && *self.status_rx.borrow() == MixnetStatus::Reconnecting {
+            let mut status_rx = self.status_rx.clone();
+            self.reconnected = Some(
+                async move {
+                    loop {
+                        if let MixnetStatus::Connected(_) = *status_rx.borrow_and_update() {
+                            return Ok(());
+                        }
+                        // the mixnet task stopped
+                        status_rx
+                            .changed()
+                            .await
+                            .map_err(|_| Error::MixnetUnavailable)?;
+                    }
+                }
+                .boxed(),
+            );
+        }
+        if let Some(reconnected) = self.reconnected.as_mut() {
+            let res = futures::ready!(reconnected.as_mut().poll(cx));
+            self.reconnected = None;
+            res?;
+        }
+
+        if self.permit.is_some() {
+            return Poll::Ready(Ok(()));
+        }
+        match self.in_flight.poll_acquire(cx) {
+            Poll::Ready(Some(permit)) => {
+                self.permit = Some(permit);
+                Poll::Ready(Ok(()))
+            }
+            // the semaphore is never closed, but don't hang forever if it is
+            Poll::Ready(None) => Poll::Ready(Err(Error::RecvError)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, mut item: OutboundMessage) -> Result<(), Error> {
+        item.permit = self.permit.take();
+        self.outbound_tx.send(item).map_err(Error::from)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// OutboundSender queues messages for the mixnet task. Every queued message holds a
+/// permit until it's been written to the mixnet, so the queue is bounded: messages that
+/// don't come with one, eg. substream writes holding a permit of the outbound capacity
+/// or messages sent through a sink, take one of the sender's capacity, and aren't queued
+/// if there's none left. Clones share the capacity.
+#[derive(Clone, Debug)]
+pub(crate) struct OutboundSender {
+    tx: UnboundedSender<OutboundMessage>,
+    capacity: Arc<Mutex<Arc<Semaphore>>>,
+}
+
+impl OutboundSender {
+    /// send queues the message, taking a permit of the capacity unless it holds one.
+    pub(crate) fn send(&self, mut message: OutboundMessage) -> Result<(), OutboundSendError> {
+        if message.permit.is_none() {
+            message.permit = Some(self.reserve()?);
+        }
+        self.tx.send(message).map_err(|_| OutboundSendError::Closed)
+    }
+
+    /// reserve takes a permit of the capacity, for a message queued some other way, eg.
+    /// a broadcast.
+    pub(crate) fn reserve(&self) -> Result<OwnedSemaphorePermit, OutboundSendError> {
+        let capacity = self.capacity.lock().clone();
+        capacity
+            .try_acquire_owned()
+            .map_err(|_| OutboundSendError::Full)
+    }
+
+    /// set_capacity sets how many messages without a permit of their own may be queued.
+    /// Messages already queued keep their permits of the previous capacity.
+    pub(crate) fn set_capacity(&self, capacity: usize) {
+        *self.capacity.lock() = Arc::new(Semaphore::new(capacity));
+    }
+}
+
+/// OutboundSendError is why an [`OutboundSender`] didn't queue a message.
+#[derive(Debug)]
+pub(crate) enum OutboundSendError {
+    /// the sender's capacity is used up
+    Full,
+    /// the mixnet task stopped
+    Closed,
+}
+
+impl fmt::Display for OutboundSendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutboundSendError::Full => write!(f, "outbound queue is full"),
+            OutboundSendError::Closed => write!(f, "channel closed"),
+        }
+    }
+}
+
+impl From<OutboundSendError> for Error {
+    fn from(e: OutboundSendError) -> Self {
+        match e {
+            OutboundSendError::Full => Error::OutboundQueueFull,
+            OutboundSendError::Closed => Error::OutboundSendError(e.to_string()),
+        }
+    }
+}
+
+/// outbound_channel returns a sender of messages to be written to the mixnet, with the
+/// default capacity, and the receiver the mixnet task takes them from.
+pub(crate) fn outbound_channel() -> (OutboundSender, UnboundedReceiver<OutboundMessage>) {
+    let (tx, rx) = unbounded_channel();
+    let sender = OutboundSender {
+        tx,
+        capacity: Arc::new(Mutex::new(Arc::new(Semaphore::new(
+            DEFAULT_OUTBOUND_QUEUE_CAPACITY,
+        )))),
+    };
+    (sender, rx)
+}
+
+/// InboundStream is a stream of messages received from the mixnet.
+pub struct InboundStream {
+    inbound_rx: UnboundedReceiver<InboundMessage>,
+}
+
+impl InboundStream {
+    pub(crate) fn new(inbound_rx: UnboundedReceiver<InboundMessage>) -> Self {
+        InboundStream { inbound_rx }
+    }
+}
+
+impl Stream for InboundStream {
+    type Item = InboundMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inbound_rx.poll_recv(cx)
+    }
+}
+
+/// InboundBacklog counts the inbound messages the mixnet task has passed on, which haven't
+/// been handled yet. Only the transport takes messages off the backlog, so only the
+/// transport sets watermarks on it.
+#[derive(Clone, Default)]
+pub(crate) struct InboundBacklog {
+    inner: Arc<(AtomicUsize, Notify)>,
+}
+
+impl InboundBacklog {
+    pub(crate) fn len(&self) -> usize {
+        self.inner.0.load(Ordering::SeqCst)
+    }
+
+    fn push(&self) {
+        self.inner.0.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// pop marks a message as handled.
+    pub(crate) fn pop(&self) {
+        self.inner.0.fetch_sub(1, Ordering::SeqCst);
+        self.inner.1.notify_one();
+    }
+
+    /// drained resolves once a message has been handled.
+    async fn drained(&self) {
+        self.inner.1.notified().await
+    }
+}
+
+/// OutboundSink is a sink of messages to be written to the mixnet.
+/// It only becomes ready once fewer than `max_in_flight` of the messages sent through
+/// it are still waiting to be written to the websocket.
+pub struct OutboundSink {
+    outbound_tx: OutboundSender,
+    in_flight: PollSemaphore,
+
+    /// permit acquired in poll_ready, attached to the next message in start_send
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl OutboundSink {
+    pub(crate) fn new(outbound_tx: OutboundSender, max_in_flight: usize) -> Self {
+        OutboundSink {
+            outbound_tx,
+            in_flight: PollSemaphore::new(Arc::new(Semaphore::new(max_in_flight.max(1)))),
+            permit: None,
+        }
+    }
+}
+
+impl Sink<OutboundMessage> for OutboundSink {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if self.permit.is_some() {
+            return Poll::Ready(Ok(()));
+        }
+
+        match self.in_flight.poll_acquire(cx) {
+            Poll::Ready(Some(permit)) => {
+                self.permit = Some(permit);
+                Poll::Ready(Ok(()))
+            }
+            // the semaphore is never closed, but don't hang forever if it is
+            Poll::Ready(None) => Poll::Ready(Err(Error::RecvError)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, mut item: OutboundMessage) -> Result<(), Error> {
+        item.permit = self.permit.take();
+        self.outbound_tx.send(item).map_err(Error::from)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
 
 /// initialize_mixnet initializes a read/write connection to a Nym websockets endpoint.
 /// It starts a task that listens for inbound messages from the endpoint and writes outbound messages to the endpoint.
 pub(crate) async fn initialize_mixnet(
     uri: &String,
     notify_inbound_tx: Option<UnboundedSender<()>>,
-) -> Result<
+) -> Result<(Recipient, UnboundedReceiver<InboundMessage>, OutboundSender), Error> {
+    let backend = WebsocketBackend::connect(uri, 1).await?;
+    Ok(initialize_mixnet_with_backend(backend, notify_inbound_tx))
+}
+
+/// initialize_mixnet_with_backend starts a task that listens for inbound messages from the
+/// given backend and writes outbound messages to it.
+pub(crate) fn initialize_mixnet_with_backend<B: MixnetBackend>(
+    backend: B,
+    notify_inbound_tx: Option<UnboundedSender<()>>,
+) -> (Recipient, UnboundedReceiver<InboundMessage>, OutboundSender) {
+    let channels = initialize_mixnet_with_rotation(backend, notify_inbound_tx, None);
     (
-        Recipient,
-        UnboundedReceiver<InboundMessage>,
-        UnboundedSender<OutboundMessage>,
-    ),
-    Error,
-> {
-    let (mut ws_stream, _) = connect_async(uri)
-        .await
-        .map_err(Error::WebsocketStreamError)?;
-
-    let recipient = get_self_address(&mut ws_stream).await?;
+        channels.self_address,
+        channels.inbound_rx,
+        channels.outbound_tx,
+    )
+}
+
+/// MixnetOptions can be changed while the mixnet task is running.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MixnetOptions {
+    /// packet size for outbound messages that don't set their own
+    pub(crate) packet_size: PacketSize,
+    /// if set, wire messages are recorded to the journal
+    pub(crate) journal: Option<Journal>,
+    /// caps on the bytes received from and written to the mixnet per minute, if set
+    pub(crate) inbound_bytes_per_min: Option<u64>,
+    pub(crate) outbound_bytes_per_min: Option<u64>,
+    /// if set, each outbound message is held back for a random delay of up to this long
+    /// before it's written
+    pub(crate) send_jitter: Option<Duration>,
+    /// high and low watermarks of the inbound backlog, if set: reading from the backends
+    /// pauses once the backlog reaches the high watermark, until it's down to the low one
+    pub(crate) inbound_watermarks: Option<(usize, usize)>,
+    /// if set, inbound messages are passed on to the transport in batches of up to this
+    /// many, none of them held back for longer than the delay
+    pub(crate) inbound_batching: Option<(usize, Duration)>,
+    /// when the circuit breaker trips, if it's enabled
+    pub(crate) circuit_breaker: Option<CircuitBreakerConfig>,
+    /// bytes of plaintext per sphinx packet, if set rather than asked of the backend
+    pub(crate) packet_payload_len: Option<usize>,
+    /// if set, messages needing more sphinx packets than this are split into fragments
+    pub(crate) max_message_packets: Option<usize>,
+    /// how the Nym messages written to the mixnet are framed; inbound ones are decoded in
+    /// any framing
+    pub(crate) framing: Framing,
+    /// if set, outbound messages carrying more application data than this are encoded on
+    /// the blocking thread pool
+    pub(crate) encode_offload_threshold: Option<usize>,
+    /// how long the rest of a fragmented message is waited for, and how many may be
+    /// partially received at once, if not the defaults
+    pub(crate) reassembly_timeout: Option<Duration>,
+    pub(crate) max_partial_messages: Option<usize>,
+    /// whether inbound messages of unknown types are rejected as errors rather than
+    /// skipped
+    pub(crate) strict_message_types: bool,
+    /// whether the backends are suspended, see `NymTransport::pause`
+    pub(crate) paused: bool,
+    /// set once the transport was shut down, after which the task stops
+    pub(crate) shutdown: bool,
+}
+
+/// MixnetStatus is whether the mixnet task can currently write to the mixnet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum MixnetStatus {
+    /// connected, with the given Nym address
+    Connected(Recipient),
+    /// the current backend lost its connection and is reconnecting
+    Reconnecting,
+}
+
+/// wait_for_connected returns our Nym address once the mixnet is connected.
+/// If it's reconnecting, this waits for up to `queue_timeout`, or fails right away if
+/// there's no timeout.
+pub(crate) async fn wait_for_connected(
+    status_rx: &mut watch::Receiver<MixnetStatus>,
+    queue_timeout: Option<Duration>,
+) -> Result<Recipient, Error> {
+    if let MixnetStatus::Connected(address) = *status_rx.borrow() {
+        return Ok(address);
+    }
+    let Some(queue_timeout) = queue_timeout else {
+        return Err(Error::MixnetUnavailable);
+    };
+
+    tokio::time::timeout(queue_timeout, async {
+        loop {
+            // the mixnet task stopped
+            status_rx
+                .changed()
+                .await
+                .map_err(|_| Error::MixnetUnavailable)?;
+            if let MixnetStatus::Connected(address) = *status_rx.borrow() {
+                return Ok(address);
+            }
+        }
+    })
+    .await
+    .map_err(|_| Error::MixnetUnavailable)?
+}
+
+/// MixnetChannels connects the transport to the mixnet task.
+pub(crate) struct MixnetChannels {
+    pub(crate) self_address: Recipient,
+    pub(crate) inbound_rx: UnboundedReceiver<InboundMessage>,
+    /// messages on inbound_rx which haven't been handled yet
+    pub(crate) inbound_backlog: InboundBacklog,
+    /// memory held by the buffers of the task and its transports
+    pub(crate) memory_budget: MemoryBudget,
+    /// recent messages of each connection, while capturing them is enabled
+    pub(crate) message_capture: MessageCapture,
+    /// what became of the messages written to the mixnet
+    pub(crate) gateway_outcomes: GatewayOutcomes,
+    /// partially received messages that were dropped
+    pub(crate) reassembly_evictions: ReassemblyEvictions,
+    /// inbound messages of unknown types
+    pub(crate) unknown_message_types: UnknownMessageTypes,
+    /// how long outbound messages took at each stage of the send path
+    pub(crate) send_path_timings: SendPathTimings,
+    /// Nym messages read from the mixnet in each framing
+    pub(crate) inbound_framings: InboundFramings,
+    /// messages and bytes written to and read from the mixnet, and reconnects
+    pub(crate) mixnet_traffic: MixnetTraffic,
+    /// whether the mixnet appears to be down
+    pub(crate) breaker: CircuitBreaker,
+    pub(crate) outbound_tx: OutboundSender,
+    pub(crate) broadcast_tx: UnboundedSender<BroadcastMessage>,
+    /// changes to our Nym address
+    pub(crate) address_rx: UnboundedReceiver<AddressEvent>,
+    pub(crate) options_tx: Arc<watch::Sender<MixnetOptions>>,
+    pub(crate) status_rx: watch::Receiver<MixnetStatus>,
+    pub(crate) info_rx: watch::Receiver<MixnetInfo>,
+    pub(crate) injector: ErrorInjector,
+}
+
+/// initialize_mixnet_with_rotation is like initialize_mixnet_with_backend, but if an
+/// `AddressRotation` is given, our Nym address is periodically replaced by a fresh one.
+/// Changes to our addresses are sent on the returned `AddressEvent` channel.
+pub(crate) fn initialize_mixnet_with_rotation<B: MixnetBackend>(
+    mut backend: B,
+    notify_inbound_tx: Option<UnboundedSender<()>>,
+    rotation: Option<AddressRotation<B>>,
+) -> MixnetChannels {
+    let recipient = backend.self_address();
+
+    // a channel of inbound messages from the mixnet..
+    // the transport reads from (listens) to the inbound_rx; reading from the backends
+    // pauses once the inbound backlog reaches its high watermark, which bounds it.
+    let (inbound_tx, inbound_rx) = unbounded_channel::<InboundMessage>();
+
+    // a channel of outbound messages to be written to the mixnet.
+    // the transport writes to outbound_tx; every message holds a permit until it's been
+    // written, which bounds it.
+    let (outbound_tx, outbound_rx) = outbound_channel();
+
+    // broadcasts are sent separately, so each only takes a single channel send. they
+    // hold a permit of outbound_tx's capacity as well.
+    let (broadcast_tx, broadcast_rx) = unbounded_channel::<BroadcastMessage>();
+
+    let (address_tx, address_rx) = unbounded_channel::<AddressEvent>();
+    let (options_tx, options_rx) = watch::channel(MixnetOptions {
+        encode_offload_threshold: Some(DEFAULT_ENCODE_OFFLOAD_THRESHOLD),
+        ..Default::default()
+    });
+    let (status_tx, status_rx) = watch::channel(MixnetStatus::Connected(recipient));
+    let (info_tx, info_rx) = watch::channel(backend.info());
+    let injector = ErrorInjector::default();
+    let inbound_backlog = InboundBacklog::default();
+    let memory_budget = MemoryBudget::default();
+    let message_capture = MessageCapture::default();
+    let gateway_outcomes = GatewayOutcomes::default();
+    let reassembly_evictions = ReassemblyEvictions::default();
+    let unknown_message_types = UnknownMessageTypes::default();
+    let send_path_timings = SendPathTimings::default();
+    let inbound_framings = InboundFramings::default();
+    let mixnet_traffic = MixnetTraffic::default();
+    let breaker = CircuitBreaker::default();
+    let (outcomes_tx, outcomes_rx) = unbounded_channel();
+    let tracks_send_outcomes = track_send_outcomes(&mut backend, &outcomes_tx);
+
+    let rotate_at = rotation.as_ref().map(|r| Instant::now() + r.interval);
+    let task = MixnetTask {
+        backends: vec![backend],
+        inbound_tx,
+        notify_inbound_tx,
+        inbound_backlog: inbound_backlog.clone(),
+        inbound_paused: false,
+        inbound_batch: Vec::new(),
+        inbound_batch_deadline: None,
+        memory_budget: memory_budget.clone(),
+        budget_paused: false,
+        suspended: false,
+        write_disconnected: false,
+        message_capture: message_capture.clone(),
+        gateway_outcomes: gateway_outcomes.clone(),
+        outcomes_tx,
+        outcomes_rx,
+        tracks_send_outcomes,
+        pending_sends: BTreeMap::new(),
+        next_send_id: 0,
+        outage: OutageDetector::new(breaker.clone()),
+        paused_retransmits: VecDeque::new(),
+        warned_nominal_payload_len: false,
+        outbound_rx,
+        broadcast_rx: Some(broadcast_rx),
+        address_tx,
+        rotation,
+        rotate_at,
+        retirements: VecDeque::new(),
+        outbound: OutboundQueue::default(),
+        encodes: OffloadedEncodes::default(),
+        options_rx,
+        status_tx,
+        info_tx,
+        reassembler: Reassembler::new(memory_budget.clone(), reassembly_evictions.clone()),
+        unknown_message_types: unknown_message_types.clone(),
+        send_path_timings: send_path_timings.clone(),
+        inbound_framings: inbound_framings.clone(),
+        mixnet_traffic: mixnet_traffic.clone(),
+        inbound_bandwidth: BandwidthLimiter::new(),
+        outbound_bandwidth: BandwidthLimiter::new(),
+        jittered_send_at: None,
+        injector: injector.clone(),
+        delayed_inbound: VecDeque::new(),
+    };
+    tokio::task::spawn(task.run().in_current_span());
+
+    MixnetChannels {
+        self_address: recipient,
+        inbound_rx,
+        inbound_backlog,
+        memory_budget,
+        message_capture,
+        gateway_outcomes,
+        reassembly_evictions,
+        unknown_message_types,
+        send_path_timings,
+        inbound_framings,
+        mixnet_traffic,
+        breaker,
+        outbound_tx,
+        broadcast_tx,
+        address_rx,
+        options_tx: Arc::new(options_tx),
+        status_rx,
+        info_rx,
+        injector,
+    }
+}
+
+/// track_send_outcomes asks the backend to report the outcome of each message sent
+/// through it, if it can, and returns whether it will.
+fn track_send_outcomes<B: MixnetBackend>(
+    backend: &mut B,
+    outcomes_tx: &UnboundedSender<(u64, SendOutcome)>,
+) -> bool {
+    if !backend.reports_send_outcomes() {
+        return false;
+    }
+    backend.report_send_outcomes(outcomes_tx.clone());
+    true
+}
+
+/// MixnetTask moves messages between the transport's channels and the backends.
+struct MixnetTask<B: MixnetBackend> {
+    /// the newest backend is last, and is the one used for sending.
+    /// older backends only stay around to receive messages until they're retired.
+    backends: Vec<B>,
+
+    inbound_tx: UnboundedSender<InboundMessage>,
+    notify_inbound_tx: Option<UnboundedSender<()>>,
+    inbound_backlog: InboundBacklog,
+    /// whether reading from the backends is paused until the inbound backlog is drained
+    inbound_paused: bool,
+    /// inbound messages held back to be passed on together, if batching is enabled, and
+    /// when they're passed on at the latest
+    inbound_batch: Vec<InboundMessage>,
+    inbound_batch_deadline: Option<Instant>,
+    memory_budget: MemoryBudget,
+    /// whether reading from the backends is paused until the memory budget is relieved
+    budget_paused: bool,
+    /// whether the backends are suspended, in which case they're neither read from nor
+    /// written to
+    suspended: bool,
+    /// whether writing to the current backend failed because it lost its connection to the
+    /// mixnet, in which case it's reconnected before anything else is done
+    write_disconnected: bool,
+    message_capture: MessageCapture,
+
+    gateway_outcomes: GatewayOutcomes,
+    /// passed to every backend, which report the outcome of each message on it
+    outcomes_tx: UnboundedSender<(u64, SendOutcome)>,
+    outcomes_rx: UnboundedReceiver<(u64, SendOutcome)>,
+    /// whether the current backend reports send outcomes; messages are only kept for
+    /// retransmission if it does
+    tracks_send_outcomes: bool,
+    /// messages waiting for their outcome, by id, so that they can be sent again if
+    /// they're lost before the gateway
+    pending_sends: BTreeMap<u64, PendingSend>,
+    next_send_id: u64,
+
+    /// trips the circuit breaker when the mixnet appears to be down
+    outage: OutageDetector,
+    /// messages lost before the gateway while the breaker was open, sent again once it
+    /// closes
+    paused_retransmits: VecDeque<PendingSend>,
+    /// whether it was logged that fragmentation assumes the nominal payload of a packet
+    warned_nominal_payload_len: bool,
+
+    outbound_rx: UnboundedReceiver<OutboundMessage>,
+    /// None once all broadcast senders are gone
+    broadcast_rx: Option<UnboundedReceiver<BroadcastMessage>>,
+    address_tx: UnboundedSender<AddressEvent>,
+
+    rotation: Option<AddressRotation<B>>,
+
+    /// when the next address rotation is due
+    rotate_at: Option<Instant>,
+
+    /// replaced addresses and when they should stop receiving messages, oldest first
+    retirements: VecDeque<(Instant, Recipient)>,
+
+    /// outbound messages waiting to be written, so that higher priority ones go first
+    outbound: OutboundQueue,
+    /// outbound messages being encoded off the mixnet task, written once they're done
+    encodes: OffloadedEncodes,
+
+    options_rx: watch::Receiver<MixnetOptions>,
+    status_tx: watch::Sender<MixnetStatus>,
+    /// what the current backend knows about its connection to the mixnet
+    info_tx: watch::Sender<MixnetInfo>,
+
+    /// puts back together messages that were split up to fit a websocket frame size cap
+    reassembler: Reassembler,
+    unknown_message_types: UnknownMessageTypes,
+    send_path_timings: SendPathTimings,
+    inbound_framings: InboundFramings,
+    mixnet_traffic: MixnetTraffic,
+
+    /// throttle traffic to the configured bandwidth caps
+    inbound_bandwidth: BandwidthLimiter,
+    outbound_bandwidth: BandwidthLimiter,
+    /// when the next outbound message is written at the earliest, if send jitter is
+    /// enabled and a message is waiting
+    jittered_send_at: Option<Instant>,
+
+    injector: ErrorInjector,
+    /// inbound messages held back by the injector, in the order they arrived, with when
+    /// they're passed on
+    delayed_inbound: VecDeque<(Instant, Result<Vec<u8>, Error>, Option<SenderTag>)>,
+}
+
+impl<B: MixnetBackend> MixnetTask<B> {
+    async fn run(mut self) {
+        loop {
+            let retire_at = self.retirements.front().map(|(at, _)| *at);
+            let delayed_inbound_at = self.delayed_inbound.front().map(|(at, ..)| *at);
+            // while over a bandwidth cap, the backends aren't read from or written to
+            let (
+                inbound_cap,
+                outbound_cap,
+                send_jitter,
+                inbound_watermarks,
+                inbound_batching,
+                paused,
+                shutdown,
+            ) = {
+                let options = self.options_rx.borrow();
+                self.reassembler.set_limits(
+                    options
+                        .reassembly_timeout
+                        .unwrap_or(DEFAULT_REASSEMBLY_TIMEOUT),
+                    options
+                        .max_partial_messages
+                        .unwrap_or(DEFAULT_MAX_PARTIAL_MESSAGES),
+                );
+                (
+                    options.inbound_bytes_per_min,
+                    options.outbound_bytes_per_min,
+                    options.send_jitter,
+                    options.inbound_watermarks,
+                    options.inbound_batching,
+                    options.paused,
+                    options.shutdown,
+                )
+            };
+            if shutdown {
+                self.shut_down().await;
+                return;
+            }
+            if paused && !self.suspended {
+                self.suspend().await;
+            } else if !paused && self.suspended && !self.resume().await {
+                return;
+            }
+            let write_disconnected = std::mem::take(&mut self.write_disconnected);
+            if write_disconnected
+                && !self.suspended
+                && !self.reconnect(self.backends.len() - 1).await
+            {
+                return;
+            }
+            let active = !self.suspended;
+            let reassembly_deadline = self.reassembler.next_deadline();
+            self.update_inbound_paused(inbound_watermarks);
+            self.update_budget_paused();
+            let inbound_ready_at = self.inbound_bandwidth.ready_at(inbound_cap);
+            let outbound_ready_at = self.outbound_bandwidth.ready_at(outbound_cap);
+            let jitter_deadline = self.jitter_deadline(send_jitter);
+            let breaker_config = self.breaker_config();
+            if breaker_config.is_none() && self.outage.close() {
+                self.resume_retransmits().await;
+            }
+            let silence_deadline = self.outage.silence_deadline(breaker_config.as_ref());
+
+            tokio::select! {
+                (res, index) = recv_any(&mut self.backends), if active && inbound_ready_at.is_none() && !self.inbound_paused && !self.budget_paused => {
+                    match res {
+                        Err(e) if is_disconnect(&e) => {
+                            warn!("lost connection to the mixnet: {:?}", e);
+                            if !self.reconnect(index).await {
+                                return;
+                            }
+                        }
+                        res => {
+                            if res.is_ok() && self.outage.record_received() {
+                                self.resume_retransmits().await;
+                            }
+                            let (res, sender_tag) = match res {
+                                Ok((data, sender_tag)) => {
+                                    self.inbound_bandwidth.consume(data.len(), inbound_cap);
+                                    (self.unframe_and_reassemble(data), sender_tag)
+                                }
+                                Err(e) => (Some(Err(e)), None),
+                            };
+                            // None while waiting for the rest of a fragmented message
+                            let Some(res) = res else {
+                                continue;
+                            };
+                            if let (Ok(data), Some(journal)) = (&res, &self.options_rx.borrow().journal) {
+                                journal.record(JournalDirection::Inbound, None, data);
+                            }
+                            if let Some(delay) = self.injector.take_inbound_delay() {
+                                self.delay_inbound(res, sender_tag, delay);
+                                continue;
+                            }
+                            self.pass_inbound(res, sender_tag, inbound_batching);
+                        }
+                    }
+                }
+                message = self.outbound_rx.recv(), if self.outbound.is_empty() => {
+                    let Some(message) = message else {
+                        // the transport and all its connections were dropped, but what
+                        // they sent before that is still written
+                        while !self.encodes.is_empty() {
+                            let encoded = self.encodes.next().await;
+                            self.write_encoded(encoded).await;
+                        }
+                        return;
+                    };
+                    self.outbound.push(message);
+                }
+                broadcast = recv_broadcast(&mut self.broadcast_rx) => match broadcast {
+                    Some(broadcast) => self.outbound.push_broadcast(broadcast),
+                    None => self.broadcast_rx = None,
+                },
+                _ = future::ready(()), if active && !self.outbound.is_empty() && outbound_ready_at.is_none() && jitter_deadline.is_none() => {
+                    self.send_next().await;
+                    self.jittered_send_at = None;
+                }
+                encoded = self.encodes.next(), if active && !self.encodes.is_empty() => {
+                    self.write_encoded(encoded).await;
+                }
+                _ = sleep_until(inbound_ready_at) => {}
+                _ = sleep_until(self.inbound_batch_deadline) => self.flush_inbound(),
+                _ = self.inbound_backlog.drained(), if self.inbound_paused => {}
+                _ = future::poll_fn(|cx| self.memory_budget.poll_relieved(cx)), if self.budget_paused => {}
+                _ = tokio::time::sleep(BUDGET_EXPIRY_INTERVAL), if self.budget_paused => {
+                    self.reassembler.expire();
+                }
+                _ = sleep_until(outbound_ready_at), if !self.outbound.is_empty() => {}
+                _ = sleep_until(jitter_deadline), if !self.outbound.is_empty() => {}
+                // messages whose fragments stopped arriving don't hold memory until more come
+                _ = sleep_until(reassembly_deadline) => self.reassembler.expire(),
+                Some((id, outcome)) = self.outcomes_rx.recv() => {
+                    self.handle_send_outcome(id, outcome).await;
+                }
+                _ = sleep_until(silence_deadline), if active => {
+                    self.outage.check_silence(breaker_config.as_ref());
+                }
+                _ = sleep_until(self.outage.next_probe_at()), if active => {
+                    self.probe(breaker_config.as_ref()).await;
+                }
+                _ = sleep_until(self.rotate_at), if active => self.rotate().await,
+                _ = sleep_until(retire_at) => self.retire(),
+                _ = sleep_until(delayed_inbound_at) => {
+                    self.release_delayed_inbound(inbound_batching);
+                }
+                Ok(()) = self.options_rx.changed() => {}
+                _ = self.injector.connection_dropped(), if active => {
+                    warn!("lost connection to the mixnet: injected failure");
+                    if !self.reconnect(self.backends.len() - 1).await {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// update_inbound_paused pauses reading from the backends once the inbound backlog
+    /// reaches the high watermark, eg. because the application stopped polling the swarm,
+    /// and resumes once it's down to the low watermark. Messages that arrive in the meantime
+    /// are left with the backend, ie. the Nym client.
+    fn update_inbound_paused(&mut self, watermarks: Option<(usize, usize)>) {
+        let backlog = self.inbound_backlog.len();
+        match watermarks {
+            Some((high, _)) if !self.inbound_paused && backlog >= high => {
+                info!(
+                    "pausing inbound messages; {} are waiting to be handled",
+                    backlog
+                );
+                self.inbound_paused = true;
+            }
+            Some((_, low)) if self.inbound_paused && backlog <= low => {
+                info!("resuming inbound messages");
+                self.inbound_paused = false;
+            }
+            None => self.inbound_paused = false,
+            _ => {}
+        }
+    }
+
+    /// update_budget_paused pauses reading from the backends while the memory budget is
+    /// under pressure, eg. because the application doesn't read its substreams, and resumes
+    /// once it's relieved. Like with the watermarks, messages that arrive in the meantime
+    /// are left with the backend.
+    fn update_budget_paused(&mut self) {
+        let under_pressure = self.memory_budget.is_under_pressure();
+        if under_pressure && !self.budget_paused {
+            info!(
+                "pausing inbound messages; buffers hold {} bytes",
+                self.memory_budget.used()
+            );
+        } else if !under_pressure && self.budget_paused {
+            info!("resuming inbound messages");
+        }
+        self.budget_paused = under_pressure;
+    }
+
+    /// batch_inbound adds an inbound message to the batch, and passes the batch on to the
+    /// transport if it's full or batching is disabled. Passing on several messages at
+    /// once wakes the transport, and in turn the swarm, once for all of them.
+    fn batch_inbound(&mut self, data: InboundMessage, batching: Option<(usize, Duration)>) {
+        self.inbound_batch.push(data);
+        match batching {
+            Some((max_messages, max_delay)) if self.inbound_batch.len() < max_messages => {
+                self.inbound_batch_deadline
+                    .get_or_insert_with(|| Instant::now() + max_delay);
+            }
+            _ => self.flush_inbound(),
+        }
+    }
+
+    /// flush_inbound passes the batched inbound messages on to the transport.
+    fn flush_inbound(&mut self) {
+        self.inbound_batch_deadline = None;
+        for data in self.inbound_batch.drain(..) {
+            if let Err(e) = deliver_inbound(
+                data,
+                &self.inbound_tx,
+                &self.inbound_backlog,
+                &self.notify_inbound_tx,
+            ) {
+                debug!("failed to pass on inbound message: {:?}", e);
+            }
+        }
+    }
+
+    /// pass_inbound parses a message received from the mixnet, and passes it on to the
+    /// transport.
+    fn pass_inbound(
+        &mut self,
+        res: Result<Vec<u8>, Error>,
+        sender_tag: Option<SenderTag>,
+        batching: Option<(usize, Duration)>,
+    ) {
+        match parse_inbound(res, sender_tag, &self.message_capture) {
+            Ok(data) => self.batch_inbound(data, batching),
+            Err(e) => {
+                let strict = self.options_rx.borrow().strict_message_types;
+                report_inbound_error(e, &self.unknown_message_types, strict);
+            }
+        }
+    }
+
+    /// delay_inbound holds back a message received from the mixnet for the given delay.
+    /// Held back messages are passed on in the order they arrived, so none overtakes one
+    /// that was held back before it.
+    fn delay_inbound(
+        &mut self,
+        res: Result<Vec<u8>, Error>,
+        sender_tag: Option<SenderTag>,
+        delay: Duration,
+    ) {
+        let mut release_at = Instant::now() + delay;
+        if let Some((last_release_at, ..)) = self.delayed_inbound.back() {
+            release_at = release_at.max(*last_release_at);
+        }
+        self.delayed_inbound
+            .push_back((release_at, res, sender_tag));
+    }
+
+    /// release_delayed_inbound passes on the held back messages whose delay is over.
+    fn release_delayed_inbound(&mut self, batching: Option<(usize, Duration)>) {
+        let now = Instant::now();
+        while matches!(self.delayed_inbound.front(), Some((at, ..)) if *at <= now) {
+            let Some((_, res, sender_tag)) = self.delayed_inbound.pop_front() else {
+                break;
+            };
+            self.pass_inbound(res, sender_tag, batching);
+        }
+    }
+
+    /// jitter_deadline returns when the next outbound message may be written, if it has to
+    /// wait: once a message is next in line, it's held back for a random delay of up to
+    /// `max_jitter`, so that when it's written doesn't give away when the application
+    /// sent it.
+    fn jitter_deadline(&mut self, max_jitter: Option<Duration>) -> Option<Instant> {
+        let Some(max_jitter) = max_jitter else {
+            self.jittered_send_at = None;
+            return None;
+        };
+        if self.outbound.is_empty() {
+            return None;
+        }
+        let send_at = *self
+            .jittered_send_at
+            .get_or_insert_with(|| Instant::now() + max_jitter.mul_f64(rand::random::<f64>()));
+        (send_at > Instant::now()).then_some(send_at)
+    }
+
+    /// send_next writes the highest priority outbound message to the mixnet.
+    /// messages that arrived while the previous one was being written are queued first,
+    /// so they can jump ahead of lower priority ones.
+    async fn send_next(&mut self) {
+        while let Ok(message) = self.outbound_rx.try_recv() {
+            self.outbound.push(message);
+        }
+        if let Some(broadcast_rx) = self.broadcast_rx.as_mut() {
+            while let Ok(broadcast) = broadcast_rx.try_recv() {
+                self.outbound.push_broadcast(broadcast);
+            }
+        }
+
+        match self.outbound.pop() {
+            Some(PendingWrite::Message(mut message)) => {
+                message.dequeued_at = Some(Instant::now());
+                if message.is_expired() {
+                    debug!("dropping outbound message that missed its send deadline");
+                    match message.into_expired() {
+                        Some(replacement) => message = replacement,
+                        None => return,
+                    }
+                }
+                let Some(message) = self.shed(message) else {
+                    return;
+                };
+                let packet_size = self.packet_size(message.packet_size);
+                let fragment_threshold = self.fragment_threshold(packet_size);
+                // a message waits for those to the same recipient that are still being
+                // encoded, so that the connection's messages stay in order
+                let offload_threshold = self.options_rx.borrow().encode_offload_threshold;
+                if self.encodes.is_running_for(&message.recipient)
+                    || matches!(offload_threshold, Some(t) if message.payload_len() > t)
+                {
+                    self.encodes.spawn(message, fragment_threshold);
+                    return;
+                }
+                self.write_encoded(encode(message, fragment_threshold))
+                    .await;
+            }
+            Some(PendingWrite::Broadcast {
+                recipient,
+                bytes,
+                packet_size,
+            }) => {
+                let packet_size = self.packet_size(packet_size);
+                let fragments = self
+                    .fragment_threshold(packet_size)
+                    .map(|threshold| fragment(&bytes, threshold));
+                let bytes = bytes.as_ref().clone();
+                self.write_bytes(recipient, Route::Direct, bytes, fragments, packet_size)
+                    .await;
+            }
+            None => {}
+        }
+    }
+
+    /// shed drops outbound messages the memory budget can't afford, degrading gracefully:
+    /// cover traffic goes once the budget is under pressure, and data of low priority
+    /// substreams once it's exhausted. It returns what to write in the message's place.
+    fn shed(&self, message: OutboundMessage) -> Option<OutboundMessage> {
+        let budget = &self.memory_budget;
+        if message.message.is_cover() && budget.is_under_pressure() {
+            debug!("dropping outbound probe, the memory budget is under pressure");
+            budget.record_shed_cover();
+            return None;
+        }
+        if message.priority == MessagePriority::Low
+            && matches!(message.message, Message::TransportMessage(_))
+            && budget.is_exhausted()
+        {
+            debug!("dropping low priority outbound message, the memory budget is exhausted");
+            budget.record_shed_low_priority();
+            return message.into_dropped();
+        }
+        Some(message)
+    }
+
+    /// write_encoded writes an encoded message to the mixnet. The message, and with it
+    /// any in-flight permit and memory reservation, is dropped once it's been handed to
+    /// the backend.
+    async fn write_encoded(&mut self, encoded: EncodedMessage) {
+        let EncodedMessage {
+            message,
+            bytes,
+            fragments,
+            encoded_at,
+        } = encoded;
+        self.message_capture
+            .record(JournalDirection::Outbound, &message.message, &bytes);
+        let packet_size = self.packet_size(message.packet_size);
+        self.write_bytes(
+            message.recipient,
+            message.route,
+            bytes,
+            fragments,
+            packet_size,
+        )
+        .await;
+
+        let written_at = Instant::now();
+        let dequeued_at = message.dequeued_at.unwrap_or(encoded_at);
+        trace!(
+            "wrote outbound {:?} message: queued for {:?}, encoded in {:?}, written in {:?}",
+            message.message.kind(),
+            dequeued_at - message.enqueued_at,
+            encoded_at - dequeued_at,
+            written_at - encoded_at,
+        );
+        self.send_path_timings
+            .record(message.enqueued_at, dequeued_at, encoded_at, written_at);
+    }
+
+    /// unframe_and_reassemble returns the message a Nym message read from the mixnet
+    /// carries, or None while waiting for the rest of a fragmented message. The websocket
+    /// backend splits what it writes to fit its frame size cap, so a frame can be split
+    /// too, as can the message inside it.
+    fn unframe_and_reassemble(&mut self, data: Vec<u8>) -> Option<Result<Vec<u8>, Error>> {
+        let data = match self.reassembler.push(data) {
+            Ok(data) => data?,
+            Err(e) => return Some(Err(e)),
+        };
+        let res = match unframe(data) {
+            Ok((framing, data)) => {
+                self.inbound_framings.record(framing);
+                self.reassembler.push(data).transpose()
+            }
+            Err(e) => Some(Err(e)),
+        };
+        if let Some(Ok(data)) = &res {
+            self.mixnet_traffic.record_received(data.len());
+        }
+        res
+    }
+
+    /// write_bytes writes the bytes of a message to the mixnet, as the fragments if it
+    /// was split into any.
+    async fn write_bytes(
+        &mut self,
+        recipient: Recipient,
+        route: Route,
+        bytes: Vec<u8>,
+        fragments: Option<Result<Vec<Vec<u8>>, Error>>,
+        packet_size: PacketSize,
+    ) {
+        let outbound_cap = self.options_rx.borrow().outbound_bytes_per_min;
+        self.outbound_bandwidth.consume(bytes.len(), outbound_cap);
+        self.mixnet_traffic.record_sent(bytes.len());
+        if let Some(journal) = &self.options_rx.borrow().journal {
+            journal.record(JournalDirection::Outbound, Some(recipient), &bytes);
+        }
+
+        let framing = self.options_rx.borrow().framing;
+        match fragments {
+            None => {
+                self.write(recipient, route, frame(bytes, framing), packet_size, 0)
+                    .await
+            }
+            Some(Ok(fragments)) => {
+                for fragment in fragments {
+                    let fragment = frame(fragment, framing);
+                    self.write(recipient, route, fragment, packet_size, 0).await;
+                }
+            }
+            Some(Err(e)) => debug!("failed to fragment outbound message: {:?}", e),
+        }
+    }
+
+    /// packet_size returns the packet size of a message, which is the transport's unless
+    /// the message sets its own.
+    fn packet_size(&self, packet_size: Option<PacketSize>) -> PacketSize {
+        packet_size.unwrap_or_else(|| self.options_rx.borrow().packet_size)
+    }
+
+    /// fragment_threshold returns the largest message written to the mixnet whole, if
+    /// messages are fragmented: as many sphinx packets as allowed, each carrying the
+    /// payload that's configured, or else reported by the backend, or else nominal.
+    fn fragment_threshold(&mut self, packet_size: PacketSize) -> Option<usize> {
+        let options = self.options_rx.borrow();
+        let max_packets = options.max_message_packets?;
+        let backend = self.backends.last().expect("there's always a backend");
+        let payload_len = match options
+            .packet_payload_len
+            .or_else(|| backend.packet_payload_len(packet_size))
+        {
+            Some(payload_len) => payload_len,
+            None => {
+                if !self.warned_nominal_payload_len {
+                    warn!(
+                        "the Nym client doesn't report the plaintext payload of a sphinx packet \
+                         and none is configured, so messages are fragmented assuming the \
+                         nominal payload of {:?} packets; set packet_payload_len if the \
+                         mixnet's packet parameters differ",
+                        packet_size
+                    );
+                    self.warned_nominal_payload_len = true;
+                }
+                packet_size.nominal_payload_len()
+            }
+        };
+        // the frame header goes in front of each fragment
+        Some(
+            max_packets
+                .saturating_mul(payload_len)
+                .saturating_sub(options.framing.overhead()),
+        )
+    }
+
+    /// write hands a message to the current backend. If the backend reports send
+    /// outcomes, the message is kept until its outcome is known; backends only report
+    /// them for messages sent directly.
+    async fn write(
+        &mut self,
+        recipient: Recipient,
+        route: Route,
+        bytes: Vec<u8>,
+        packet_size: PacketSize,
+        retransmits: u32,
+    ) {
+        let id = self.next_send_id;
+        self.next_send_id = self.next_send_id.wrapping_add(1);
+        let tracked = self.tracks_send_outcomes && route == Route::Direct;
+        let pending = tracked.then(|| PendingSend {
+            recipient,
+            bytes: bytes.clone(),
+            packet_size,
+            retransmits,
+            _reservation: self
+                .memory_budget
+                .reserve(BufferKind::Outbound, bytes.len()),
+        });
+
+        let backend = self.backends.last_mut().expect("there's always a backend");
+        let res = match route {
+            Route::Direct => {
+                backend
+                    .send_tracked(recipient, bytes, packet_size, id)
+                    .await
+            }
+            Route::Anonymous { reply_surbs } => {
+                backend.send_anonymous(recipient, bytes, reply_surbs).await
+            }
+            Route::Reply(sender_tag) => backend.send_reply(sender_tag, bytes).await,
+        };
+        if let Err(e) = res {
+            if is_disconnect(&e) {
+                warn!("lost connection to the mixnet: {:?}", e);
+                self.write_disconnected = true;
+            } else {
+                debug!("failed to write message to mixnet: {:?}", e);
+            }
+            // the Nym client didn't take it, so it can't have reached the gateway
+            self.gateway_outcomes.record_lost_before_gateway();
+            self.outage
+                .record_send_failure(self.breaker_config().as_ref());
+            return;
+        }
+        match pending {
+            Some(pending) => {
+                self.pending_sends.insert(id, pending);
+                if self.pending_sends.len() > MAX_PENDING_SENDS {
+                    self.pending_sends.pop_first();
+                }
+            }
+            // without outcomes, the Nym client taking it is all we know
+            None => self.outage.record_sent(),
+        }
+    }
+
+    /// handle_send_outcome counts the outcome of a message, and sends it again if it was
+    /// lost before the gateway: since it can't arrive, that can't duplicate it, whereas
+    /// a message lost further along the mixnet is left for the connection to recover.
+    async fn handle_send_outcome(&mut self, id: u64, outcome: SendOutcome) {
+        let pending = self.pending_sends.remove(&id);
+        match outcome {
+            SendOutcome::HandedToGateway => {
+                self.gateway_outcomes.record_handed_to_gateway();
+                self.outage.record_sent();
+            }
+            SendOutcome::LostBeforeGateway => {
+                self.gateway_outcomes.record_lost_before_gateway();
+                self.outage
+                    .record_send_failure(self.breaker_config().as_ref());
+                let Some(pending) = pending else {
+                    return;
+                };
+                if pending.retransmits >= MAX_GATEWAY_RETRANSMITS {
+                    debug!("giving up on message lost before the gateway");
+                    return;
+                }
+                if self.outage.is_open() {
+                    // sending it again now would most likely lose it again
+                    self.paused_retransmits.push_back(pending);
+                    if self.paused_retransmits.len() > MAX_PENDING_SENDS {
+                        self.paused_retransmits.pop_front();
+                    }
+                    return;
+                }
+                self.retransmit(pending).await;
+            }
+        }
+    }
 
-    // a channel of inbound messages from the mixnet..
-    // the transport reads from (listens) to the inbound_rx.
-    // TODO: this is probably a DOS vector; we should limit the size of the channel.
-    let (inbound_tx, inbound_rx) = unbounded_channel::<InboundMessage>();
+    /// retransmit sends a message lost before the gateway again.
+    async fn retransmit(&mut self, pending: PendingSend) {
+        debug!("sending message lost before the gateway again");
+        self.gateway_outcomes.record_retransmit();
+        let outbound_cap = self.options_rx.borrow().outbound_bytes_per_min;
+        self.outbound_bandwidth
+            .consume(pending.bytes.len(), outbound_cap);
+        let PendingSend {
+            recipient,
+            bytes,
+            packet_size,
+            retransmits,
+            ..
+        } = pending;
+        self.write(
+            recipient,
+            Route::Direct,
+            bytes,
+            packet_size,
+            retransmits + 1,
+        )
+        .await;
+    }
 
-    // a channel of outbound messages to be written to the mixnet.
-    // the transport writes to outbound_tx.
-    let (outbound_tx, mut outbound_rx) = unbounded_channel::<OutboundMessage>();
+    /// resume_retransmits sends the messages whose retransmission was paused while the
+    /// circuit breaker was open.
+    async fn resume_retransmits(&mut self) {
+        while let Some(pending) = self.paused_retransmits.pop_front() {
+            self.retransmit(pending).await;
+        }
+    }
+
+    /// probe sends an echo request to our own address while the circuit breaker is open;
+    /// any message arriving closes it again. The transport ignores the request unless its
+    /// echo responder is enabled, in which case the reply counts too.
+    async fn probe(&mut self, config: Option<&CircuitBreakerConfig>) {
+        self.outage.probe_sent(config);
+        let backend = self.backends.last_mut().expect("there's always a backend");
+        let address = backend.self_address();
+        let bytes = Message::EchoRequest(EchoRequestMessage::new(address)).to_bytes();
+        debug!("probing the Nym client");
+        if let Err(e) = backend.send(address, bytes).await {
+            debug!("failed to write probe to mixnet: {:?}", e);
+        }
+    }
 
-    let (mut sink, mut stream) = ws_stream.split();
+    fn breaker_config(&self) -> Option<CircuitBreakerConfig> {
+        self.options_rx.borrow().circuit_breaker.clone()
+    }
 
-    tokio::task::spawn(async move {
-        loop {
-            let t1 = check_inbound(&mut stream, &inbound_tx, &notify_inbound_tx).fuse();
-            let t2 = check_outbound(&mut sink, &mut outbound_rx).fuse();
+    /// shut_down writes what the transport sent before it was shut down, eg. the closes of
+    /// its connections, before the task stops.
+    async fn shut_down(&mut self) {
+        info!("the transport was shut down; stopping the mixnet task");
+        if self.suspended {
+            return;
+        }
+        while let Ok(message) = self.outbound_rx.try_recv() {
+            self.outbound.push(message);
+        }
+        while !self.outbound.is_empty() {
+            self.send_next().await;
+        }
+        while !self.encodes.is_empty() {
+            let encoded = self.encodes.next().await;
+            self.write_encoded(encoded).await;
+        }
+    }
 
-            pin_mut!(t1, t2);
+    /// suspend lets go of the backends' connections to the mixnet while we're paused.
+    /// messages to send wait in the queue until we resume.
+    async fn suspend(&mut self) {
+        info!("suspending the connection to the mixnet");
+        for backend in &mut self.backends {
+            if let Err(e) = backend.suspend().await {
+                debug!("failed to suspend mixnet backend: {:?}", e);
+            }
+        }
+        self.suspended = true;
+    }
 
-            select! {
-                _ = t1 => {},
-                _ = t2 => {},
+    /// resume re-establishes the backends' connections after suspend, reconnecting those
+    /// that can't resume, and announces our Nym address if it changed meanwhile.
+    /// returns false if the task should stop, like reconnect.
+    async fn resume(&mut self) -> bool {
+        info!("resuming the connection to the mixnet");
+        self.suspended = false;
+        self.outage.resumed();
+        // backwards, since backends may be removed
+        for index in (0..self.backends.len()).rev() {
+            let old_address = self.backends[index].self_address();
+            let resumed = match self.backends[index].resume().await {
+                Ok(()) => self.reconnected(index, old_address),
+                Err(e) => {
+                    warn!("failed to resume mixnet backend: {:?}", e);
+                    self.reconnect(index).await
+                }
             };
+            if !resumed {
+                return false;
+            }
         }
-    });
+        true
+    }
 
-    Ok((recipient, inbound_rx, outbound_tx))
-}
+    /// reconnect reconnects the backend at the given index after it was disconnected.
+    /// returns false if the task should stop, ie. our current backend can't reconnect.
+    async fn reconnect(&mut self, index: usize) -> bool {
+        let is_current = index == self.backends.len() - 1;
+        let old_address = self.backends[index].self_address();
 
-async fn check_inbound(
-    ws_stream: &mut SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
-    inbound_tx: &UnboundedSender<InboundMessage>,
-    notify_inbound_tx: &Option<UnboundedSender<()>>,
-) -> Result<(), Error> {
-    if let Some(res) = ws_stream.next().await {
-        if let Some(notify_tx) = notify_inbound_tx {
-            notify_tx
-                .send(())
-                .map_err(|e| Error::InboundSendError(e.to_string()))?;
+        if is_current {
+            self.status_tx.send_replace(MixnetStatus::Reconnecting);
+        }
+        if !reconnect(&mut self.backends[index]).await {
+            if is_current {
+                return false;
+            }
+            self.backends.remove(index);
+            return true;
+        }
+        self.mixnet_traffic.record_reconnect();
+        self.reconnected(index, old_address)
+    }
+
+    /// reconnected updates our status after the backend at the given index got a new
+    /// connection to the mixnet, and announces its address if it changed.
+    fn reconnected(&mut self, index: usize, old_address: Recipient) -> bool {
+        let is_current = index == self.backends.len() - 1;
+        let new_address = self.backends[index].self_address();
+        if is_current {
+            self.status_tx
+                .send_replace(MixnetStatus::Connected(new_address));
+            self.info_tx.send_replace(self.backends[index].info());
+        }
+        if new_address == old_address {
+            return true;
         }
 
-        match res {
-            Ok(msg) => return handle_inbound(msg, inbound_tx).await,
-            Err(e) => return Err(Error::WebsocketStreamError(e)),
+        if is_current {
+            // eg. the backend failed over to another gateway
+            info!("Nym address changed {} -> {}", old_address, new_address);
+            self.address_tx
+                .send(AddressEvent::Changed {
+                    old: old_address,
+                    new: new_address,
+                })
+                .ok();
+        } else {
+            // a replaced address that's only kept around during its grace period;
+            // there's no point in announcing a new one for it
+            self.backends.remove(index);
         }
+        true
     }
 
-    Err(Error::WebsocketStreamReadNone)
-}
+    /// rotate replaces our current address with a fresh one.
+    /// the old address keeps receiving messages until its grace period is over.
+    async fn rotate(&mut self) {
+        let Some(rotation) = &self.rotation else {
+            return;
+        };
+        self.rotate_at = Some(Instant::now() + rotation.interval);
 
-async fn handle_inbound(
-    msg: Message,
-    inbound_tx: &UnboundedSender<InboundMessage>,
-) -> Result<(), Error> {
-    let res = parse_nym_message(msg)?;
-    let msg_bytes = match res {
-        ServerResponse::Received(msg_bytes) => msg_bytes,
-        ServerResponse::Error(e) => return Err(Error::NymMessageError(e.to_string())),
-        _ => return Err(Error::UnexpectedNymMessage),
-    };
-    let data = parse_message_data(&msg_bytes.message)?;
-    inbound_tx
-        .send(data)
-        .map_err(|e| Error::InboundSendError(e.to_string()))?;
-    Ok(())
-}
+        let mut backend = match (rotation.new_backend)().await {
+            Ok(backend) => backend,
+            Err(e) => {
+                warn!("failed to obtain a new Nym address: {:?}", e);
+                return;
+            }
+        };
 
-async fn check_outbound(
-    ws_sink: &mut SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
-    outbound_rx: &mut UnboundedReceiver<OutboundMessage>,
-) -> Result<(), Error> {
-    match outbound_rx.recv().await {
-        Some(message) => write_bytes(ws_sink, message.recipient, &message.message.to_bytes()).await,
-        None => Err(Error::RecvError),
+        let old_address = self
+            .backends
+            .last()
+            .expect("there's always a backend")
+            .self_address();
+        let new_address = backend.self_address();
+        info!("rotating Nym address {} -> {}", old_address, new_address);
+
+        self.info_tx.send_replace(backend.info());
+        self.tracks_send_outcomes = track_send_outcomes(&mut backend, &self.outcomes_tx);
+        self.backends.push(backend);
+        self.status_tx
+            .send_replace(MixnetStatus::Connected(new_address));
+        self.retirements
+            .push_back((Instant::now() + rotation.grace_period, old_address));
+        self.address_tx.send(AddressEvent::New(new_address)).ok();
+    }
+
+    /// retire stops receiving messages on the oldest replaced address.
+    fn retire(&mut self) {
+        let Some((_, address)) = self.retirements.pop_front() else {
+            return;
+        };
+
+        info!("retiring Nym address {}", address);
+        self.backends
+            .retain(|backend| backend.self_address() != address);
+        self.address_tx.send(AddressEvent::Expired(address)).ok();
     }
 }
 
-async fn write_bytes(
-    ws_sink: &mut SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
+/// PendingSend is a message handed to a backend that reports send outcomes, kept until its
+/// outcome is known.
+struct PendingSend {
     recipient: Recipient,
-    message: &[u8],
-) -> Result<(), Error> {
-    let nym_packet = ClientRequest::Send {
-        recipient,
-        message: message.to_vec(),
-        connection_id: None,
-    };
+    bytes: Vec<u8>,
+    packet_size: PacketSize,
+    /// how many times it was sent again already
+    retransmits: u32,
+    _reservation: Reservation,
+}
 
-    ws_sink
-        .send(Message::Binary(nym_packet.serialize()))
-        .await
-        .map_err(Error::WebsocketStreamError)?;
+/// recv_any waits for a message on any of the backends, returning it and its sender tag
+/// along with the index of the backend it was received on.
+async fn recv_any<B: MixnetBackend>(
+    backends: &mut [B],
+) -> (Result<(Vec<u8>, Option<SenderTag>), Error>, usize) {
+    let recvs = backends.iter_mut().map(|b| b.recv_with_sender_tag());
+    let (res, index, _) = future::select_all(recvs).await;
+    (res, index)
+}
 
-    debug!(
-        "wrote message to mixnet: recipient: {:?}",
-        recipient.to_string()
-    );
-    Ok(())
+/// recv_broadcast waits for the next broadcast, or forever if the channel was closed.
+async fn recv_broadcast(
+    broadcast_rx: &mut Option<UnboundedReceiver<BroadcastMessage>>,
+) -> Option<BroadcastMessage> {
+    match broadcast_rx {
+        Some(broadcast_rx) => broadcast_rx.recv().await,
+        None => future::pending().await,
+    }
 }
 
-async fn get_self_address(
-    ws_stream: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
-) -> Result<Recipient, Error> {
-    let self_address_request = ClientRequest::SelfAddress.serialize();
-    ws_stream
-        .send(Message::Binary(self_address_request))
-        .await
-        .map_err(Error::WebsocketStreamError)?;
+/// sleep_until waits until the deadline, or forever if there isn't one.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => future::pending().await,
+    }
+}
 
-    // loop until we receive the SelfAddress respone, since the next message might not
-    // necessarily be the SelfAddress response.
-    while let Some(raw_message) = ws_stream.next().await {
-        let raw_message = raw_message.map_err(Error::WebsocketStreamError)?;
-        let response = parse_nym_message(raw_message)?;
-        return match response {
-            ServerResponse::SelfAddress(recipient) => Ok(*recipient),
-            ServerResponse::Error(e) => Err(Error::NymMessageError(e.to_string())),
-            _ => continue,
-        };
+/// is_disconnect returns true if the error means the backend lost its connection to the mixnet.
+fn is_disconnect(e: &Error) -> bool {
+    matches!(
+        e,
+        Error::WebsocketStreamReadNone | Error::WebsocketStreamError(_) | Error::MixnetDisconnected
+    )
+}
+
+/// reconnect retries reconnecting the backend until it succeeds, backing off
+/// exponentially so a Nym client that's down for a while isn't hammered.
+/// returns false if the backend doesn't support reconnecting.
+async fn reconnect<B: MixnetBackend>(backend: &mut B) -> bool {
+    let mut attempts = 0;
+    loop {
+        match backend.reconnect().await {
+            Ok(()) => {
+                info!("reconnected to the mixnet after {} attempts", attempts + 1);
+                return true;
+            }
+            Err(Error::Unimplemented) => {
+                warn!("mixnet backend can't reconnect; stopping");
+                return false;
+            }
+            Err(e) => {
+                let delay = reconnect_delay(attempts);
+                attempts += 1;
+                warn!(
+                    "failed to reconnect to the mixnet (attempt {}), retrying in {:?}: {:?}",
+                    attempts, delay, e
+                );
+                tokio::time::sleep(delay).await;
+            }
+        }
     }
-    Err(Error::RecvError)
 }
 
-fn parse_nym_message(msg: Message) -> Result<ServerResponse, Error> {
-    match msg {
-        Message::Text(str) => ServerResponse::deserialize(&str.into_bytes())
-            .map_err(|e| Error::NymMessageError(e.to_string())),
-        Message::Binary(bytes) => {
-            ServerResponse::deserialize(&bytes).map_err(|e| Error::NymMessageError(e.to_string()))
+/// reconnect_delay returns how long to wait after the given number of reconnection
+/// attempts failed in a row, plus the one that just failed.
+fn reconnect_delay(failed_attempts: u32) -> Duration {
+    RECONNECT_DELAY
+        .checked_mul(1 << failed_attempts.min(16))
+        .unwrap_or(MAX_RECONNECT_DELAY)
+        .min(MAX_RECONNECT_DELAY)
+}
+
+/// report_inbound_error logs why an inbound message wasn't passed on to the transport.
+/// Messages of unknown types are counted, and only logged as errors if we're strict
+/// about message types; otherwise they're skipped, so newer peers can add types.
+fn report_inbound_error(e: Error, unknown_message_types: &UnknownMessageTypes, strict: bool) {
+    match e {
+        Error::UnknownMessageType(message_type) => {
+            unknown_message_types.record();
+            if strict {
+                warn!("rejecting inbound message of unknown type {}", message_type);
+            } else {
+                debug!("skipping inbound message of unknown type {}", message_type);
+            }
         }
-        _ => Err(Error::UnknownNymMessage),
+        e => debug!("failed to handle inbound message: {:?}", e),
+    }
+}
+
+/// parse_inbound parses a message received from the mixnet, along with the sender tag it
+/// arrived with if it was sent anonymously.
+fn parse_inbound(
+    res: Result<Vec<u8>, Error>,
+    sender_tag: Option<SenderTag>,
+    message_capture: &MessageCapture,
+) -> Result<InboundMessage, Error> {
+    let bytes = res?;
+    let data = parse_message_data(&bytes)?;
+    message_capture.record(JournalDirection::Inbound, &data.0, &bytes);
+    Ok(data.with_sender_tag(sender_tag))
+}
+
+/// deliver_inbound passes a parsed inbound message on to the transport.
+fn deliver_inbound(
+    data: InboundMessage,
+    inbound_tx: &UnboundedSender<InboundMessage>,
+    inbound_backlog: &InboundBacklog,
+    notify_inbound_tx: &Option<UnboundedSender<()>>,
+) -> Result<(), Error> {
+    // counted before it's sent, so it can't be handled before it's counted
+    inbound_backlog.push();
+    if let Err(e) = inbound_tx.send(data) {
+        // it won't ever be handled
+        inbound_backlog.pop();
+        return Err(Error::InboundSendError(e.to_string()));
+    }
+
+    if let Some(notify_tx) = notify_inbound_tx {
+        notify_tx
+            .send(())
+            .map_err(|e| Error::InboundSendError(e.to_string()))?;
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
+    use futures::{future::poll_fn, stream, FutureExt, Sink, SinkExt, StreamExt};
+    use nym_sphinx::addressing::clients::Recipient;
+    use parking_lot::Mutex;
+    use std::{
+        pin::Pin,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
     use testcontainers::clients;
+    use tokio::{
+        sync::watch,
+        time::{timeout, Instant},
+    };
 
+    use crate::backend::{MixnetBackend, MockMixnet};
+    use crate::budget::BufferKind;
+    use crate::error::Error;
+    use crate::lifecycle::LifecycleHandle;
     use crate::message::{
-        self, ConnectionId, Message, SubstreamId, SubstreamMessage, SubstreamMessageType,
-        TransportMessage,
+        self, ConnectionId, Message, MessagePriority, PingMessage, SubstreamId, SubstreamMessage,
+        SubstreamMessageType, TransportMessage,
+    };
+    use crate::mixnet::{
+        connect_with_backend, deliver_inbound, initialize_mixnet, initialize_mixnet_with_rotation,
+        open_with_backend, outbound_channel, reconnect, reconnect_delay, wait_for_connected,
+        InboundBacklog, MixnetConnection, MixnetStatus, OutboundSink, MAX_GATEWAY_RETRANSMITS,
+        MAX_RECONNECT_DELAY,
     };
-    use crate::mixnet::initialize_mixnet;
     use crate::test_utils::create_nym_client;
 
+    #[tokio::test]
+    async fn test_mixnet_mock_backend() {
+        let mixnet = MockMixnet::new();
+        let (_, _, mut sender_sink) = connect_with_backend(mixnet.new_backend(), 1);
+        let (recipient_address, mut recipient_stream, _) =
+            connect_with_backend(mixnet.new_backend(), 1);
+
+        let substream_id = SubstreamId::generate();
+        let msg = Message::TransportMessage(TransportMessage {
+            nonce: 1,
+            id: ConnectionId::generate(),
+            message: SubstreamMessage::new_with_data(substream_id.clone(), b"hello".to_vec()),
+        });
+        sender_sink
+            .send(message::OutboundMessage::new(msg, recipient_address))
+            .await
+            .unwrap();
+
+        let received_msg = recipient_stream.next().await.unwrap();
+        let Message::TransportMessage(recv_msg) = received_msg.0 else {
+            panic!("expected Message::TransportMessage")
+        };
+        assert_eq!(substream_id, recv_msg.message.substream_id);
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_encode_offload() {
+        let mixnet = MockMixnet::new();
+        let (_, _, mut sender_sink) = connect_with_backend(mixnet.new_backend(), 1);
+        let (recipient_address, mut recipient_stream, _) =
+            connect_with_backend(mixnet.new_backend(), 1);
+
+        // the first message is encoded off the mixnet task, and the second waits for it
+        let id = ConnectionId::generate();
+        for (nonce, len) in [(1, 1 << 20), (2, 10)] {
+            let msg = Message::TransportMessage(TransportMessage {
+                nonce,
+                id: id.clone(),
+                message: SubstreamMessage::new_with_data(SubstreamId::generate(), vec![0; len]),
+            });
+            sender_sink
+                .send(message::OutboundMessage::new(msg, recipient_address))
+                .await
+                .unwrap();
+        }
+
+        for expected_nonce in [1, 2] {
+            let received_msg = timeout(Duration::from_secs(5), recipient_stream.next())
+                .await
+                .unwrap()
+                .unwrap();
+            let Message::TransportMessage(recv_msg) = received_msg.0 else {
+                panic!("expected Message::TransportMessage")
+            };
+            assert_eq!(recv_msg.nonce, expected_nonce);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_broadcast() {
+        let mixnet = MockMixnet::new();
+        let (sender, _) = open_with_backend(mixnet.new_backend());
+        let mut receivers = vec![];
+        for _ in 0..3 {
+            let backend = mixnet.new_backend();
+            let address = backend.self_address();
+            let (_, stream) = open_with_backend(backend);
+            receivers.push((address, stream));
+        }
+
+        sender
+            .broadcast(
+                receivers.iter().map(|(address, _)| *address).collect(),
+                b"hello".to_vec(),
+            )
+            .unwrap();
+
+        for (_, stream) in receivers.iter_mut() {
+            let msg = timeout(Duration::from_secs(1), stream.next())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(msg.broadcast_payload(), Some(&b"hello"[..]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_outbound_bandwidth_cap() {
+        let mixnet = MockMixnet::new();
+        let channels = initialize_mixnet_with_rotation(mixnet.new_backend(), None, None);
+        // 100 bytes per second
+        channels
+            .options_tx
+            .send_modify(|options| options.outbound_bytes_per_min = Some(6000));
+        let backend = mixnet.new_backend();
+        let address = backend.self_address();
+        let (_, mut stream) = open_with_backend(backend);
+
+        for _ in 0..2 {
+            let msg = Message::Broadcast(vec![0; 200]);
+            channels
+                .outbound_tx
+                .send(message::OutboundMessage::new(msg, address))
+                .unwrap();
+        }
+
+        // the first message is let through, and the second waits until it's paid off
+        timeout(Duration::from_millis(200), stream.next())
+            .await
+            .unwrap()
+            .unwrap();
+        timeout(Duration::from_millis(500), stream.next())
+            .await
+            .unwrap_err();
+        timeout(Duration::from_secs(2), stream.next())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_send_jitter() {
+        let mixnet = MockMixnet::new();
+        let channels = initialize_mixnet_with_rotation(mixnet.new_backend(), None, None);
+        channels
+            .options_tx
+            .send_modify(|options| options.send_jitter = Some(Duration::from_millis(100)));
+        let backend = mixnet.new_backend();
+        let address = backend.self_address();
+        let (_, mut stream) = open_with_backend(backend);
+
+        for i in 0..5 {
+            let msg = Message::Broadcast(vec![i]);
+            channels
+                .outbound_tx
+                .send(message::OutboundMessage::new(msg, address))
+                .unwrap();
+        }
+
+        // each message waits up to 100ms once it's next in line, and they stay in order
+        for i in 0..5 {
+            let msg = timeout(Duration::from_millis(500), stream.next())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(msg.broadcast_payload(), Some(&[i][..]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_retransmits_messages_lost_before_gateway() {
+        let mixnet = MockMixnet::new().with_send_outcomes();
+        let channels = initialize_mixnet_with_rotation(mixnet.new_backend(), None, None);
+        let backend = mixnet.new_backend();
+        let address = backend.self_address();
+        let (_, mut stream) = open_with_backend(backend);
+        let send = || {
+            channels
+                .outbound_tx
+                .send(message::OutboundMessage::new(
+                    Message::Broadcast(b"hello".to_vec()),
+                    address,
+                ))
+                .unwrap();
+        };
+
+        // lost once, then handed to the gateway when it's sent again
+        mixnet.lose_before_gateway(1);
+        send();
+        timeout(Duration::from_secs(1), stream.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let stats = channels.gateway_outcomes.get();
+        assert_eq!(stats.handed_to_gateway, 1);
+        assert_eq!(stats.lost_before_gateway, 1);
+        assert_eq!(stats.retransmitted, 1);
+
+        // lost every time, so it's given up on
+        mixnet.lose_before_gateway(MAX_GATEWAY_RETRANSMITS as usize + 1);
+        send();
+        timeout(Duration::from_millis(200), stream.next())
+            .await
+            .unwrap_err();
+        let stats = channels.gateway_outcomes.get();
+        assert_eq!(stats.handed_to_gateway, 1);
+        assert_eq!(
+            stats.lost_before_gateway,
+            2 + MAX_GATEWAY_RETRANSMITS as u64
+        );
+        assert_eq!(stats.retransmitted, 1 + MAX_GATEWAY_RETRANSMITS as u64);
+        assert_eq!(channels.memory_budget.used(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_no_retransmits_without_send_outcomes() {
+        // like the websocket and sdk backends, these don't report send outcomes
+        let mixnet = MockMixnet::new();
+        let channels = initialize_mixnet_with_rotation(mixnet.new_backend(), None, None);
+        let backend = mixnet.new_backend();
+        let address = backend.self_address();
+        let (_, mut stream) = open_with_backend(backend);
+
+        mixnet.lose_before_gateway(1);
+        channels
+            .outbound_tx
+            .send(message::OutboundMessage::new(
+                Message::Broadcast(b"hello".to_vec()),
+                address,
+            ))
+            .unwrap();
+        timeout(Duration::from_millis(200), stream.next())
+            .await
+            .unwrap_err();
+        let stats = channels.gateway_outcomes.get();
+        assert_eq!(stats.handed_to_gateway, 0);
+        assert_eq!(stats.lost_before_gateway, 0);
+        assert_eq!(stats.retransmitted, 0);
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_send_path_timings() {
+        let mixnet = MockMixnet::new();
+        let channels = initialize_mixnet_with_rotation(mixnet.new_backend(), None, None);
+        let backend = mixnet.new_backend();
+        let address = backend.self_address();
+        let (_, mut stream) = open_with_backend(backend);
+
+        // the second message is encoded off the mixnet task
+        for (nonce, len) in [(1, 5), (2, DEFAULT_ENCODE_OFFLOAD_THRESHOLD + 1)] {
+            let msg = Message::TransportMessage(TransportMessage {
+                nonce,
+                id: ConnectionId::generate(),
+                message: SubstreamMessage::new_with_data(SubstreamId::generate(), vec![0; len]),
+            });
+            channels
+                .outbound_tx
+                .send(message::OutboundMessage::new(msg, address))
+                .unwrap();
+        }
+        for _ in 0..2 {
+            timeout(Duration::from_secs(1), stream.next())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        let send_path = channels.send_path_timings.get();
+        assert_eq!(send_path.queued.count, 2);
+        assert_eq!(send_path.encoded.count, 2);
+        assert_eq!(send_path.written.count, 2);
+        assert_eq!(send_path.total.count, 2);
+        assert!(
+            send_path.total.sum
+                >= send_path.queued.sum + send_path.encoded.sum + send_path.written.sum
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_send_deadline() {
+        let mixnet = MockMixnet::new();
+        let channels = initialize_mixnet_with_rotation(mixnet.new_backend(), None, None);
+        let backend = mixnet.new_backend();
+        let address = backend.self_address();
+        let (_, mut stream) = open_with_backend(backend);
+
+        let substream_id = SubstreamId::generate();
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let stale = Instant::now();
+        let data = Message::TransportMessage(TransportMessage {
+            nonce: 1,
+            id: ConnectionId::generate(),
+            message: SubstreamMessage::new_with_data(substream_id.clone(), b"stale".to_vec()),
+        });
+        for msg in [data, Message::Broadcast(b"stale".to_vec())] {
+            channels
+                .outbound_tx
+                .send(
+                    message::OutboundMessage::new(msg, address)
+                        .with_deadline(stale, Some(exceeded.clone())),
+                )
+                .unwrap();
+        }
+        let fresh = Message::Broadcast(b"fresh".to_vec());
+        channels
+            .outbound_tx
+            .send(
+                message::OutboundMessage::new(fresh, address)
+                    .with_deadline(Instant::now() + Duration::from_secs(10), None),
+            )
+            .unwrap();
+
+        // the stale data is replaced with a close of its substream, so its nonce isn't
+        // missing, and the stale broadcast is dropped
+        let msg = timeout(Duration::from_secs(1), stream.next())
+            .await
+            .unwrap()
+            .unwrap();
+        let Message::TransportMessage(msg) = msg.0 else {
+            panic!("expected Message::TransportMessage");
+        };
+        assert_eq!(msg.nonce, 1);
+        assert_eq!(msg.message.substream_id, substream_id);
+        assert!(matches!(
+            msg.message.message_type,
+            SubstreamMessageType::Close
+        ));
+        let msg = timeout(Duration::from_secs(1), stream.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.broadcast_payload(), Some(&b"fresh"[..]));
+        assert!(exceeded.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_sheds_cover_traffic_and_low_priority_data() {
+        let mixnet = MockMixnet::new();
+        let channels = initialize_mixnet_with_rotation(mixnet.new_backend(), None, None);
+        let backend = mixnet.new_backend();
+        let address = backend.self_address();
+        let (_, mut stream) = open_with_backend(backend);
+        let budget = channels.memory_budget.clone();
+        budget.set_limit(Some(100));
+        let held = budget.reserve(BufferKind::Outbound, 100);
+
+        let ping = || {
+            Message::Ping(PingMessage {
+                id: ConnectionId::generate(),
+                sent_at: 0,
+            })
+        };
+        let substream_id = SubstreamId::generate();
+        let data = Message::TransportMessage(TransportMessage {
+            nonce: 1,
+            id: ConnectionId::generate(),
+            message: SubstreamMessage::new_with_data(substream_id.clone(), b"bulk".to_vec()),
+        });
+        let send = |message: message::OutboundMessage| channels.outbound_tx.send(message).unwrap();
+        send(message::OutboundMessage::new(ping(), address));
+        send(message::OutboundMessage::new(data, address).with_priority(MessagePriority::Low));
+        send(message::OutboundMessage::new(
+            Message::Broadcast(b"normal".to_vec()),
+            address,
+        ));
+
+        // the ping is dropped, the low priority data is replaced with a close of its
+        // substream, and normal priority data still goes out
+        let mut closed = false;
+        let mut broadcast = false;
+        for _ in 0..2 {
+            let msg = timeout(Duration::from_secs(1), stream.next())
+                .await
+                .unwrap()
+                .unwrap();
+            match msg.0 {
+                Message::TransportMessage(msg) => {
+                    assert_eq!(msg.message.substream_id, substream_id);
+                    assert!(matches!(
+                        msg.message.message_type,
+                        SubstreamMessageType::Close
+                    ));
+                    closed = true;
+                }
+                Message::Broadcast(payload) => {
+                    assert_eq!(payload, b"normal");
+                    broadcast = true;
+                }
+                msg => panic!("unexpected message {msg:?}"),
+            }
+        }
+        assert!(closed && broadcast);
+        assert_eq!(budget.shed_cover(), 1);
+        assert_eq!(budget.shed_low_priority(), 1);
+
+        // once the budget is relieved, probes go out again
+        drop(held);
+        send(message::OutboundMessage::new(ping(), address));
+        let msg = timeout(Duration::from_secs(1), stream.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(msg.0, Message::Ping(_)));
+        assert_eq!(budget.shed_cover(), 1);
+    }
+
+    #[test]
+    fn test_deliver_inbound_closed_channel() {
+        let backlog = InboundBacklog::default();
+        let (inbound_tx, inbound_rx) = tokio::sync::mpsc::unbounded_channel();
+        let msg = || message::InboundMessage(Message::Broadcast(vec![1]));
+
+        deliver_inbound(msg(), &inbound_tx, &backlog, &None).unwrap();
+        assert_eq!(backlog.len(), 1);
+
+        // a message the transport can't receive doesn't count towards the backlog
+        drop(inbound_rx);
+        assert!(matches!(
+            deliver_inbound(msg(), &inbound_tx, &backlog, &None),
+            Err(Error::InboundSendError(_))
+        ));
+        assert_eq!(backlog.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_delayed_inbound_keeps_order() {
+        let mixnet = MockMixnet::new();
+        let mut channels = initialize_mixnet_with_rotation(mixnet.new_backend(), None, None);
+        channels
+            .injector
+            .delay_inbound(3, Duration::from_millis(100));
+        let (peer, _) = open_with_backend(mixnet.new_backend());
+        for payload in [b"1", b"2", b"3", b"4"] {
+            peer.broadcast(vec![channels.self_address], payload.to_vec())
+                .unwrap();
+        }
+
+        // the message that isn't held back overtakes the others, which keep their order
+        for expected in [b"4", b"1", b"2", b"3"] {
+            let msg = timeout(Duration::from_secs(1), channels.inbound_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(msg.broadcast_payload(), Some(&expected[..]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_inbound_batching() {
+        let mixnet = MockMixnet::new();
+        let mut channels = initialize_mixnet_with_rotation(mixnet.new_backend(), None, None);
+        channels.options_tx.send_modify(|options| {
+            options.inbound_batching = Some((3, Duration::from_millis(300)));
+        });
+        let (peer, _) = open_with_backend(mixnet.new_backend());
+        let send = |payload: &[u8]| {
+            peer.broadcast(vec![channels.self_address], payload.to_vec())
+                .unwrap()
+        };
+
+        // the batch is held back until it's full..
+        send(b"1");
+        send(b"2");
+        timeout(Duration::from_millis(100), channels.inbound_rx.recv())
+            .await
+            .unwrap_err();
+        assert_eq!(channels.inbound_backlog.len(), 0);
+        send(b"3");
+        for expected in [b"1", b"2", b"3"] {
+            let msg = timeout(Duration::from_millis(100), channels.inbound_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(msg.broadcast_payload(), Some(&expected[..]));
+        }
+
+        // ..or until its first message waited for the delay
+        let started = Instant::now();
+        send(b"4");
+        let msg = timeout(Duration::from_secs(1), channels.inbound_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.broadcast_payload(), Some(&b"4"[..]));
+        assert!(started.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_pause_and_resume() {
+        let mixnet = MockMixnet::new();
+        let mut channels = initialize_mixnet_with_rotation(mixnet.new_backend(), None, None);
+        let lifecycle = LifecycleHandle::new(channels.options_tx.clone());
+        let backend = mixnet.new_backend();
+        let peer_address = backend.self_address();
+        let (peer, mut peer_stream) = open_with_backend(backend);
+
+        lifecycle.pause();
+        assert!(lifecycle.is_paused());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        channels
+            .outbound_tx
+            .send(message::OutboundMessage::new(
+                Message::Broadcast(b"to peer".to_vec()),
+                peer_address,
+            ))
+            .unwrap();
+        peer.broadcast(vec![channels.self_address], b"to us".to_vec())
+            .unwrap();
+
+        // nothing is read or written while paused
+        timeout(Duration::from_millis(200), peer_stream.next())
+            .await
+            .unwrap_err();
+        timeout(Duration::from_millis(200), channels.inbound_rx.recv())
+            .await
+            .unwrap_err();
+
+        // and what waited goes through once resumed
+        lifecycle.resume();
+        let msg = timeout(Duration::from_secs(1), peer_stream.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.broadcast_payload(), Some(&b"to peer"[..]));
+        let msg = timeout(Duration::from_secs(1), channels.inbound_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.broadcast_payload(), Some(&b"to us"[..]));
+        assert!(matches!(
+            *channels.status_rx.borrow(),
+            MixnetStatus::Connected(address) if address == channels.self_address
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_reconnects_after_disconnect() {
+        let mixnet = MockMixnet::new();
+        let (_, _, mut sender_sink) = connect_with_backend(mixnet.new_backend(), 1);
+        let (recipient_address, mut recipient_stream, _) =
+            connect_with_backend(mixnet.new_backend(), 1);
+
+        // the recipient's mixnet task should notice and reconnect
+        mixnet.disconnect(&recipient_address);
+
+        // messages sent before the recipient has reconnected are lost,
+        // so keep sending until one arrives
+        loop {
+            let msg = Message::TransportMessage(TransportMessage {
+                nonce: 1,
+                id: ConnectionId::generate(),
+                message: SubstreamMessage::new_close(SubstreamId::generate()),
+            });
+            sender_sink
+                .send(message::OutboundMessage::new(msg, recipient_address))
+                .await
+                .unwrap();
+            if timeout(Duration::from_millis(100), recipient_stream.next())
+                .await
+                .is_ok()
+            {
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_reconnects_after_write_disconnect() {
+        /// BrokenSocketBackend fails to write until it's reconnected, recording what it
+        /// writes after that.
+        #[derive(Clone)]
+        struct BrokenSocketBackend {
+            address: Recipient,
+            reconnected: Arc<AtomicBool>,
+            sent: Arc<Mutex<Vec<Vec<u8>>>>,
+        }
+
+        #[async_trait::async_trait]
+        impl MixnetBackend for BrokenSocketBackend {
+            fn self_address(&self) -> Recipient {
+                self.address
+            }
+
+            async fn send(&mut self, _: Recipient, message: Vec<u8>) -> Result<(), Error> {
+                if !self.reconnected.load(Ordering::SeqCst) {
+                    return Err(Error::MixnetDisconnected);
+                }
+                self.sent.lock().push(message);
+                Ok(())
+            }
+
+            async fn recv(&mut self) -> Result<Vec<u8>, Error> {
+                futures::future::pending().await
+            }
+
+            async fn reconnect(&mut self) -> Result<(), Error> {
+                self.reconnected.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+        }
+
+        let backend = BrokenSocketBackend {
+            address: crate::backend::mock::random_recipient(),
+            reconnected: Arc::new(AtomicBool::new(false)),
+            sent: Arc::new(Mutex::new(vec![])),
+        };
+        let (address, _, mut sink) = connect_with_backend(backend.clone(), 1);
+        let msg = || {
+            Message::TransportMessage(TransportMessage {
+                nonce: 1,
+                id: ConnectionId::generate(),
+                message: SubstreamMessage::new_close(SubstreamId::generate()),
+            })
+        };
+        sink.send(message::OutboundMessage::new(msg(), address))
+            .await
+            .unwrap();
+        while !backend.reconnected.load(Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        sink.send(message::OutboundMessage::new(msg(), address))
+            .await
+            .unwrap();
+        while backend.sent.lock().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reconnect_backs_off() {
+        /// FlakyBackend fails to reconnect a number of times, recording when it's tried.
+        struct FlakyBackend {
+            failures: usize,
+            attempts: Vec<Instant>,
+        }
+
+        #[async_trait::async_trait]
+        impl MixnetBackend for FlakyBackend {
+            fn self_address(&self) -> Recipient {
+                crate::backend::mock::random_recipient()
+            }
+
+            async fn send(&mut self, _: Recipient, _: Vec<u8>) -> Result<(), Error> {
+                Ok(())
+            }
+
+            async fn recv(&mut self) -> Result<Vec<u8>, Error> {
+                Err(Error::MixnetDisconnected)
+            }
+
+            async fn reconnect(&mut self) -> Result<(), Error> {
+                self.attempts.push(Instant::now());
+                if self.attempts.len() > self.failures {
+                    return Ok(());
+                }
+                Err(Error::MixnetDisconnected)
+            }
+        }
+
+        let mut backend = FlakyBackend {
+            failures: 8,
+            attempts: vec![],
+        };
+        assert!(reconnect(&mut backend).await);
+        let delays: Vec<_> = backend
+            .attempts
+            .windows(2)
+            .map(|attempts| (attempts[1] - attempts[0]).as_secs())
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(reconnect_delay(u32::MAX), MAX_RECONNECT_DELAY);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_connected() {
+        let address = MockMixnet::new().new_backend().self_address();
+        let (status_tx, mut status_rx) = watch::channel(MixnetStatus::Connected(address));
+        assert_eq!(
+            wait_for_connected(&mut status_rx, None).await.unwrap(),
+            address
+        );
+
+        // without queuing, fail fast while reconnecting
+        status_tx.send_replace(MixnetStatus::Reconnecting);
+        assert!(matches!(
+            wait_for_connected(&mut status_rx, None).await,
+            Err(Error::MixnetUnavailable)
+        ));
+        assert!(matches!(
+            wait_for_connected(&mut status_rx, Some(Duration::from_millis(50))).await,
+            Err(Error::MixnetUnavailable)
+        ));
+
+        // proceed once reconnected
+        let wait = tokio::spawn(async move {
+            wait_for_connected(&mut status_rx, Some(Duration::from_secs(1))).await
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        status_tx.send_replace(MixnetStatus::Connected(address));
+        assert_eq!(wait.await.unwrap().unwrap(), address);
+    }
+
+    #[tokio::test]
+    async fn test_outbound_sink_backpressure() {
+        let recipient = Recipient::try_from_base58_string("D1rrpsysCGCYXy9saP8y3kmNpGtJZUXN9SvFoUcqAsM9.9Ssso1ea5NfkbMASdiseDSjTN1fSWda5SgEVjdSN4CvV@GJqd3ZxpXWSNxTfx7B1pPtswpetH4LnJdFeLeuY5KUuN").unwrap();
+        let (outbound_tx, mut outbound_rx) = outbound_channel();
+        let mut sink = OutboundSink::new(outbound_tx, 1);
+        let new_msg = || {
+            message::OutboundMessage::new(
+                Message::TransportMessage(TransportMessage {
+                    nonce: 1,
+                    id: ConnectionId::generate(),
+                    message: SubstreamMessage::new_close(SubstreamId::generate()),
+                }),
+                recipient,
+            )
+        };
+
+        poll_fn(|cx| Pin::new(&mut sink).poll_ready(cx))
+            .await
+            .unwrap();
+        Pin::new(&mut sink).start_send(new_msg()).unwrap();
+
+        // the first message hasn't been written yet, so the sink isn't ready
+        assert!(poll_fn(|cx| Pin::new(&mut sink).poll_ready(cx))
+            .now_or_never()
+            .is_none());
+
+        // "writing" the message releases its permit
+        drop(outbound_rx.recv().await.unwrap());
+        poll_fn(|cx| Pin::new(&mut sink).poll_ready(cx))
+            .await
+            .unwrap();
+        Pin::new(&mut sink).start_send(new_msg()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_connection_sink_backpressure() {
+        let recipient = MockMixnet::new().new_backend().self_address();
+        let (outbound_tx, mut outbound_rx) = outbound_channel();
+        let (broadcast_tx, _broadcast_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (status_tx, status_rx) = watch::channel(MixnetStatus::Reconnecting);
+        let mut conn =
+            MixnetConnection::new(outbound_tx, broadcast_tx, status_rx).with_max_in_flight(1);
+        let new_msg = || {
+            message::OutboundMessage::new(
+                Message::TransportMessage(TransportMessage {
+                    nonce: 1,
+                    id: ConnectionId::generate(),
+                    message: SubstreamMessage::new_close(SubstreamId::generate()),
+                }),
+                recipient,
+            )
+        };
+
+        // not ready while the mixnet is reconnecting
+        assert!(poll_fn(|cx| Pin::new(&mut conn).poll_ready(cx))
+            .now_or_never()
+            .is_none());
+        status_tx.send_replace(MixnetStatus::Connected(recipient));
+        poll_fn(|cx| Pin::new(&mut conn).poll_ready(cx))
+            .await
+            .unwrap();
+        Pin::new(&mut conn).start_send(new_msg()).unwrap();
+
+        // the limit is shared with clones
+        let mut clone = conn.clone();
+        assert!(poll_fn(|cx| Pin::new(&mut clone).poll_ready(cx))
+            .now_or_never()
+            .is_none());
+
+        // "writing" the message releases its permit
+        drop(outbound_rx.recv().await.unwrap());
+        clone
+            .send_all(&mut stream::iter(vec![Ok(new_msg())]))
+            .await
+            .unwrap();
+        assert!(outbound_rx.recv().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_mixnet_connection_send_queue_full() {
+        let recipient = MockMixnet::new().new_backend().self_address();
+        let (outbound_tx, mut outbound_rx) = outbound_channel();
+        outbound_tx.set_capacity(2);
+        let (broadcast_tx, _broadcast_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_status_tx, status_rx) = watch::channel(MixnetStatus::Connected(recipient));
+        let conn = MixnetConnection::new(outbound_tx, broadcast_tx, status_rx);
+
+        conn.send(message::OutboundMessage::datagram(recipient, vec![1]))
+            .unwrap();
+        conn.broadcast(vec![recipient], vec![2]).unwrap();
+        assert!(matches!(
+            conn.send(message::OutboundMessage::datagram(recipient, vec![3])),
+            Err(Error::OutboundQueueFull)
+        ));
+        assert!(matches!(
+            conn.broadcast(vec![recipient], vec![4]),
+            Err(Error::OutboundQueueFull)
+        ));
+
+        // "writing" a message releases its permit
+        drop(outbound_rx.recv().await.unwrap());
+        conn.send(message::OutboundMessage::datagram(recipient, vec![5]))
+            .unwrap();
+    }
+
     #[tokio::test]
     async fn test_mixnet_poll_inbound_and_outbound() {
         let docker_client = clients::Cli::default();
@@ -196,10 +2599,7 @@ mod test {
         });
 
         // send a message to ourselves through the mixnet
-        let out_msg = message::OutboundMessage {
-            message: msg,
-            recipient: self_address,
-        };
+        let out_msg = message::OutboundMessage::new(msg, self_address);
 
         outbound_tx.send(out_msg).unwrap();
 
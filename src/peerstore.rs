@@ -0,0 +1,338 @@
+use libp2p::core::{
+    identity::{Keypair, PublicKey},
+    PeerId,
+};
+use nym_sphinx::addressing::clients::Recipient;
+use std::collections::HashMap;
+
+use crate::error::Error;
+use crate::record::SignedNymAddressRecord;
+use crate::stats::unix_micros;
+
+/// PEER_SNAPSHOT_MAGIC starts every peer snapshot, so it isn't mistaken for another file.
+const PEER_SNAPSHOT_MAGIC: &[u8] = b"libp2p-nym-peers";
+
+/// PEER_SNAPSHOT_DOMAIN is prepended to the payload signed in a PeerSnapshot, so the
+/// signature can't be confused with any other use of the libp2p key.
+const PEER_SNAPSHOT_DOMAIN: &[u8] = b"libp2p-nym-peer-snapshot";
+
+/// PEER_SNAPSHOT_VERSION is the version of the snapshots we write. Snapshots of other
+/// versions are rejected rather than misread.
+pub const PEER_SNAPSHOT_VERSION: u8 = 1;
+
+const RECIPIENT_LENGTH: usize = Recipient::LEN;
+const CREATED_AT_BYTES_LEN: usize = 8; // length of u64
+const COUNT_BYTES_LEN: usize = 4; // length of u32
+const PUBLIC_KEY_LENGTH_BYTES_LEN: usize = 2; // length of u16
+
+/// PeerStore is an address book of the Nym addresses of known peers. It can be exported
+/// as a signed snapshot, eg. to bootstrap a new node from an existing node's contacts,
+/// and snapshots from a trusted signer, such as a curated peer list, can be imported.
+#[derive(Clone, Debug, Default)]
+pub struct PeerStore {
+    peers: HashMap<PeerId, Recipient>,
+}
+
+impl PeerStore {
+    pub fn new() -> Self {
+        PeerStore::default()
+    }
+
+    /// insert sets the peer's Nym address, and returns the one it replaced, if any.
+    pub fn insert(&mut self, peer_id: PeerId, recipient: Recipient) -> Option<Recipient> {
+        self.peers.insert(peer_id, recipient)
+    }
+
+    /// insert_record sets the Nym address of the record's peer if the record verifies.
+    pub fn insert_record(&mut self, record: &SignedNymAddressRecord) -> Result<(), Error> {
+        record.verify()?;
+        self.peers.insert(record.peer_id(), record.recipient());
+        Ok(())
+    }
+
+    /// get returns the Nym address of the peer, if it's known.
+    pub fn get(&self, peer_id: &PeerId) -> Option<Recipient> {
+        self.peers.get(peer_id).copied()
+    }
+
+    /// remove forgets the peer, and returns its Nym address, if it was known.
+    pub fn remove(&mut self, peer_id: &PeerId) -> Option<Recipient> {
+        self.peers.remove(peer_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// iter returns the known peers and their Nym addresses, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &Recipient)> {
+        self.peers.iter()
+    }
+
+    /// export returns a snapshot of the known peers, signed with the keypair.
+    pub fn export(&self, keypair: &Keypair) -> Result<PeerSnapshot, Error> {
+        let mut entries: Vec<_> = self.peers.iter().map(|(p, r)| (*p, *r)).collect();
+        // the same peers make the same snapshot, whatever the order they were added in
+        entries.sort_by_key(|(peer_id, _)| peer_id.to_bytes());
+        PeerSnapshot::new(keypair, entries)
+    }
+
+    /// import adds the peers of a snapshot signed by `signer`, replacing the Nym addresses
+    /// of peers we already know, and returns how many peers it added or changed. Nothing
+    /// is imported unless the snapshot verifies.
+    pub fn import(&mut self, snapshot: &PeerSnapshot, signer: &PeerId) -> Result<usize, Error> {
+        snapshot.verify()?;
+        if snapshot.signer() != *signer {
+            return Err(Error::UntrustedPeerSnapshotSigner(snapshot.signer()));
+        }
+        let mut changed = 0;
+        for (peer_id, recipient) in &snapshot.entries {
+            if self.peers.insert(*peer_id, *recipient) != Some(*recipient) {
+                changed += 1;
+            }
+        }
+        Ok(changed)
+    }
+}
+
+/// PeerSnapshot is a versioned list of (PeerId, Nym address) pairs, signed by the node
+/// that exported it. The signature vouches for the list as a whole, not for the
+/// addresses: unlike a [`SignedNymAddressRecord`], an entry isn't signed by its peer, so
+/// only import snapshots from signers you trust.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerSnapshot {
+    pub(crate) version: u8,
+    /// seconds since the unix epoch
+    pub(crate) created_at: u64,
+    pub(crate) entries: Vec<(PeerId, Recipient)>,
+    pub(crate) public_key: PublicKey,
+    pub(crate) signature: Vec<u8>,
+}
+
+impl PeerSnapshot {
+    /// new returns a snapshot of the entries, signed with the keypair.
+    pub fn new(keypair: &Keypair, entries: Vec<(PeerId, Recipient)>) -> Result<Self, Error> {
+        let created_at = unix_micros() / 1_000_000;
+        let payload = signed_payload(PEER_SNAPSHOT_VERSION, created_at, &entries);
+        let signature = keypair
+            .sign(&payload)
+            .map_err(|e| Error::PeerSnapshotSigningFailed(e.to_string()))?;
+        Ok(PeerSnapshot {
+            version: PEER_SNAPSHOT_VERSION,
+            created_at,
+            entries,
+            public_key: keypair.public(),
+            signature,
+        })
+    }
+
+    /// verify checks that the snapshot was signed by its signer.
+    pub fn verify(&self) -> Result<(), Error> {
+        let payload = signed_payload(self.version, self.created_at, &self.entries);
+        if !self.public_key.verify(&payload, &self.signature) {
+            return Err(Error::InvalidPeerSnapshotSignature);
+        }
+        Ok(())
+    }
+
+    /// signer returns the peer that exported the snapshot.
+    pub fn signer(&self) -> PeerId {
+        PeerId::from_public_key(&self.public_key)
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// created_at returns when the snapshot was exported, in seconds since the unix epoch.
+    pub fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    pub fn entries(&self) -> &[(PeerId, Recipient)] {
+        &self.entries
+    }
+
+    /// to_bytes encodes the snapshot, eg. to write it to a file.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = PEER_SNAPSHOT_MAGIC.to_vec();
+        bytes.append(&mut encode_body(
+            self.version,
+            self.created_at,
+            &self.entries,
+        ));
+        let public_key = self.public_key.to_protobuf_encoding();
+        bytes.extend_from_slice(&(public_key.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&public_key);
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
+
+    /// try_from_bytes decodes a snapshot encoded with `to_bytes`. The snapshot still has
+    /// to be verified before it's trusted.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let bytes = bytes
+            .strip_prefix(PEER_SNAPSHOT_MAGIC)
+            .ok_or(Error::InvalidPeerSnapshotBytes)?;
+        let (&version, bytes) = bytes.split_first().ok_or(Error::InvalidPeerSnapshotBytes)?;
+        if version != PEER_SNAPSHOT_VERSION {
+            return Err(Error::UnsupportedPeerSnapshotVersion(version));
+        }
+        let mut reader = Reader(bytes);
+        let created_at = u64::from_be_bytes(reader.array::<CREATED_AT_BYTES_LEN>()?);
+        let count = u32::from_be_bytes(reader.array::<COUNT_BYTES_LEN>()?) as usize;
+
+        let mut entries = Vec::with_capacity(count.min(reader.0.len() / RECIPIENT_LENGTH));
+        for _ in 0..count {
+            let [peer_id_len] = reader.array::<1>()?;
+            let peer_id = PeerId::from_bytes(reader.take(peer_id_len as usize)?)
+                .map_err(Error::InvalidPeerIdBytes)?;
+            let recipient = Recipient::try_from_bytes(reader.array::<RECIPIENT_LENGTH>()?)
+                .map_err(Error::InvalidRecipientBytes)?;
+            entries.push((peer_id, recipient));
+        }
+
+        let public_key_len =
+            u16::from_be_bytes(reader.array::<PUBLIC_KEY_LENGTH_BYTES_LEN>()?) as usize;
+        let public_key = PublicKey::from_protobuf_encoding(reader.take(public_key_len)?)
+            .map_err(|_| Error::InvalidPeerSnapshotBytes)?;
+
+        Ok(PeerSnapshot {
+            version,
+            created_at,
+            entries,
+            public_key,
+            signature: reader.0.to_vec(),
+        })
+    }
+}
+
+/// Reader takes fields off the front of a snapshot.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < len {
+            return Err(Error::InvalidPeerSnapshotBytes);
+        }
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+}
+
+/// encode_body encodes the version, creation time and entries of a snapshot, which are
+/// what its signature covers.
+fn encode_body(version: u8, created_at: u64, entries: &[(PeerId, Recipient)]) -> Vec<u8> {
+    let mut bytes = vec![version];
+    bytes.extend_from_slice(&created_at.to_be_bytes());
+    bytes.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    for (peer_id, recipient) in entries {
+        // peer IDs are multihashes of at most 64 bytes of digest, so the length fits
+        let peer_id = peer_id.to_bytes();
+        bytes.push(peer_id.len() as u8);
+        bytes.extend_from_slice(&peer_id);
+        bytes.extend_from_slice(&recipient.to_bytes());
+    }
+    bytes
+}
+
+fn signed_payload(version: u8, created_at: u64, entries: &[(PeerId, Recipient)]) -> Vec<u8> {
+    let mut bytes = PEER_SNAPSHOT_DOMAIN.to_vec();
+    bytes.append(&mut encode_body(version, created_at, entries));
+    bytes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::mock::random_recipient;
+    use tokio::time::Duration;
+
+    #[test]
+    fn test_peer_snapshot_roundtrip() {
+        let keypair = Keypair::generate_ed25519();
+        let mut store = PeerStore::new();
+        let peers: Vec<_> = (0..3)
+            .map(|_| (PeerId::random(), random_recipient()))
+            .collect();
+        for (peer_id, recipient) in &peers {
+            store.insert(*peer_id, *recipient);
+        }
+        let record = SignedNymAddressRecord::new(
+            &Keypair::generate_ed25519(),
+            random_recipient(),
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        store.insert_record(&record).unwrap();
+
+        let snapshot = store.export(&keypair).unwrap();
+        let decoded = PeerSnapshot::try_from_bytes(&snapshot.to_bytes()).unwrap();
+        assert_eq!(decoded, snapshot);
+        assert_eq!(decoded.version(), PEER_SNAPSHOT_VERSION);
+        assert_eq!(decoded.signer(), PeerId::from(keypair.public()));
+
+        // a new node bootstraps from it
+        let mut imported = PeerStore::new();
+        let signer = PeerId::from(keypair.public());
+        assert_eq!(imported.import(&decoded, &signer).unwrap(), 4);
+        assert_eq!(imported.len(), 4);
+        for (peer_id, recipient) in &peers {
+            assert_eq!(imported.get(peer_id), Some(*recipient));
+        }
+        assert_eq!(imported.get(&record.peer_id()), Some(record.recipient()));
+
+        // importing it again changes nothing
+        assert_eq!(imported.import(&decoded, &signer).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_peer_snapshot_rejects_tampering() {
+        let keypair = Keypair::generate_ed25519();
+        let signer = PeerId::from(keypair.public());
+        let mut store = PeerStore::new();
+        store.insert(PeerId::random(), random_recipient());
+        let snapshot = store.export(&keypair).unwrap();
+
+        // a third party redirecting a peer to its own address
+        let mut forged = snapshot.clone();
+        forged.entries[0].1 = random_recipient();
+        let mut imported = PeerStore::new();
+        assert!(matches!(
+            imported.import(&forged, &signer),
+            Err(Error::InvalidPeerSnapshotSignature)
+        ));
+        assert!(imported.is_empty());
+
+        // or signing its own list
+        let other = store.export(&Keypair::generate_ed25519()).unwrap();
+        assert!(matches!(
+            imported.import(&other, &signer),
+            Err(Error::UntrustedPeerSnapshotSigner(_))
+        ));
+
+        // snapshots of other versions, or cut short, aren't read
+        let mut bytes = snapshot.to_bytes();
+        bytes[PEER_SNAPSHOT_MAGIC.len()] = PEER_SNAPSHOT_VERSION + 1;
+        assert!(matches!(
+            PeerSnapshot::try_from_bytes(&bytes),
+            Err(Error::UnsupportedPeerSnapshotVersion(_))
+        ));
+        let bytes = snapshot.to_bytes();
+        assert!(matches!(
+            PeerSnapshot::try_from_bytes(&bytes[..bytes.len() / 2]),
+            Err(Error::InvalidPeerSnapshotBytes)
+        ));
+    }
+}
@@ -0,0 +1,132 @@
+use futures::future::BoxFuture;
+use libp2p::core::{Multiaddr, PeerId};
+use parking_lot::RwLock;
+use std::{collections::HashMap, sync::Arc};
+use tokio::time::{Duration, Instant};
+
+use crate::connection::Connection;
+use crate::error::Error;
+
+/// RedialBackoff is how long the transport waits between attempts to re-dial a pinned
+/// peer: the delay starts at `initial` and doubles after each failed attempt, up to `max`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RedialBackoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for RedialBackoff {
+    fn default() -> Self {
+        RedialBackoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RedialBackoff {
+    /// delay returns how long to wait after the given number of consecutive failed attempts.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(failures.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// PinnedPeerEvent reports the transport's attempts to keep a connection to a pinned peer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PinnedPeerEvent {
+    /// the peer has no connection, so it's being dialed; `attempt` counts the dials since
+    /// the last successful one
+    Redialing {
+        peer_id: PeerId,
+        address: Multiaddr,
+        attempt: u32,
+    },
+    /// the dial succeeded, and the connection was handed to the swarm as an incoming one
+    Reconnected { peer_id: PeerId, attempt: u32 },
+    /// the dial failed; the next one starts after `retry_in`
+    RedialFailed {
+        peer_id: PeerId,
+        attempt: u32,
+        error: String,
+        retry_in: Duration,
+    },
+}
+
+/// PinnedPeers is the set of peers the transport keeps a connection to, see
+/// [`NymTransport::with_peer_pinning`](crate::transport::NymTransport::with_peer_pinning).
+/// It can be cloned and kept around after the transport is moved into a swarm.
+#[derive(Clone, Debug, Default)]
+pub struct PinnedPeers {
+    peers: Arc<RwLock<HashMap<PeerId, Multiaddr>>>,
+}
+
+impl PinnedPeers {
+    /// pin keeps a connection to the peer at the address from now on, re-dialing it
+    /// whenever it has none. Pinning a peer again replaces its address.
+    pub fn pin(&self, peer_id: PeerId, address: Multiaddr) {
+        self.peers.write().insert(peer_id, address);
+    }
+
+    /// unpin stops re-dialing the peer, and returns its address. Its current connection
+    /// is left open.
+    pub fn unpin(&self, peer_id: &PeerId) -> Option<Multiaddr> {
+        self.peers.write().remove(peer_id)
+    }
+
+    /// is_pinned returns true if the peer is pinned.
+    pub fn is_pinned(&self, peer_id: &PeerId) -> bool {
+        self.peers.read().contains_key(peer_id)
+    }
+
+    /// get returns the pinned peers and their addresses.
+    pub(crate) fn get(&self) -> Vec<(PeerId, Multiaddr)> {
+        self.peers
+            .read()
+            .iter()
+            .map(|(peer_id, address)| (*peer_id, address.clone()))
+            .collect()
+    }
+}
+
+/// Redial is the transport's state for re-dialing a pinned peer.
+pub(crate) struct Redial {
+    pub(crate) address: Multiaddr,
+    /// dials since the last successful one
+    pub(crate) attempts: u32,
+    /// when the next dial may start
+    pub(crate) next_attempt: Instant,
+    /// the dial in progress, if any
+    pub(crate) dial: Option<BoxFuture<'static, Result<(PeerId, Connection), Error>>>,
+}
+
+impl Redial {
+    pub(crate) fn new(address: Multiaddr) -> Self {
+        Redial {
+            address,
+            attempts: 0,
+            next_attempt: Instant::now(),
+            dial: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_redial_backoff() {
+        let backoff = RedialBackoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+        assert_eq!(backoff.delay(1), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2), Duration::from_millis(200));
+        assert_eq!(backoff.delay(4), Duration::from_millis(800));
+        assert_eq!(backoff.delay(5), Duration::from_secs(1));
+        assert_eq!(backoff.delay(100), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,216 @@
+use libp2p::core::PeerId;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::stats::{ProtocolTraffic, TransportStats};
+
+/// MULTISTREAM_PROTOCOL is the header exchanged at the start of a multistream-select
+/// negotiation; it isn't the negotiated protocol.
+const MULTISTREAM_PROTOCOL: &str = "/multistream/1.0.0";
+
+/// MAX_SNIFF_LEN is how many bytes at the start of a substream, in each direction,
+/// are searched for the multistream-select negotiation before giving up.
+const MAX_SNIFF_LEN: usize = 1024;
+
+/// ProtocolTracker finds out which libp2p protocol is spoken on a substream, and
+/// records the substream's traffic under it in the transport's stats.
+/// The muxer sits below the upgrade layer, so the protocol is found by following the
+/// multistream-select negotiation at the start of the substream: the negotiated protocol
+/// is the one proposed in one direction and echoed in the other. Alternatively, it can
+/// be tagged explicitly, which takes precedence.
+#[derive(Debug, Default)]
+pub(crate) struct ProtocolTracker {
+    protocol: Option<String>,
+
+    /// where to record traffic, once the protocol is known
+    stats: Option<(TransportStats, PeerId)>,
+
+    /// the start of the data sent and received, while the protocol isn't known
+    sent: Vec<u8>,
+    received: Vec<u8>,
+
+    /// traffic before the protocol was known
+    pending: ProtocolTraffic,
+
+    /// set once the protocol is known, so the transport can tell the connection has
+    /// finished upgrading
+    negotiated: Option<Arc<AtomicBool>>,
+}
+
+impl ProtocolTracker {
+    pub(crate) fn with_stats(mut self, stats: TransportStats, peer_id: PeerId) -> Self {
+        self.stats = Some((stats, peer_id));
+        self
+    }
+
+    pub(crate) fn with_negotiated_flag(mut self, negotiated: Arc<AtomicBool>) -> Self {
+        self.negotiated = Some(negotiated);
+        self
+    }
+
+    pub(crate) fn protocol(&self) -> Option<String> {
+        self.protocol.clone()
+    }
+
+    pub(crate) fn record_sent(&mut self, data: &[u8]) {
+        self.record(data, true);
+    }
+
+    pub(crate) fn record_received(&mut self, data: &[u8]) {
+        self.record(data, false);
+    }
+
+    fn record(&mut self, data: &[u8], sent: bool) {
+        let traffic = ProtocolTraffic {
+            substreams: 0,
+            bytes_sent: if sent { data.len() as u64 } else { 0 },
+            bytes_received: if sent { 0 } else { data.len() as u64 },
+        };
+
+        if let Some(protocol) = &self.protocol {
+            if let Some((stats, peer_id)) = &self.stats {
+                stats.record_protocol_traffic(*peer_id, protocol, &traffic);
+            }
+            return;
+        }
+
+        self.pending.add(&traffic);
+        if self.sent.len() >= MAX_SNIFF_LEN && self.received.len() >= MAX_SNIFF_LEN {
+            return;
+        }
+
+        let buf = if sent {
+            &mut self.sent
+        } else {
+            &mut self.received
+        };
+        let room = MAX_SNIFF_LEN.saturating_sub(buf.len());
+        buf.extend_from_slice(&data[..data.len().min(room)]);
+
+        if let Some(protocol) = negotiated_protocol(&self.sent, &self.received) {
+            self.set_protocol(protocol);
+        }
+    }
+
+    /// set_protocol tags the substream with the given protocol, overriding sniffing.
+    /// Traffic from now on is recorded under the new protocol.
+    pub(crate) fn set_protocol(&mut self, protocol: String) {
+        if self.protocol.is_none() {
+            // the first protocol gets the substream and the traffic so far
+            self.pending.substreams = 1;
+            if let Some((stats, peer_id)) = &self.stats {
+                stats.record_protocol_traffic(*peer_id, &protocol, &self.pending);
+            }
+            self.sent = vec![];
+            self.received = vec![];
+            if let Some(negotiated) = &self.negotiated {
+                negotiated.store(true, Ordering::Relaxed);
+            }
+        }
+        self.protocol = Some(protocol);
+    }
+}
+
+/// negotiated_protocol returns the protocol that was proposed in one direction and
+/// echoed in the other, if any.
+fn negotiated_protocol(sent: &[u8], received: &[u8]) -> Option<String> {
+    let received = multistream_protocols(received);
+    multistream_protocols(sent)
+        .into_iter()
+        .find(|protocol| received.contains(protocol))
+}
+
+/// multistream_protocols parses the protocols in the complete multistream-select messages
+/// at the start of data. Each message is a uvarint length followed by that many bytes,
+/// the last of which is a newline. Parsing stops at the first message that's incomplete
+/// or isn't a multistream-select message, eg. the protocol's own data.
+fn multistream_protocols(mut data: &[u8]) -> Vec<String> {
+    let mut protocols = vec![];
+    while let Some((len, rest)) = read_uvarint(data) {
+        if len == 0 || rest.len() < len || rest[len - 1] != b'\n' {
+            break;
+        }
+
+        let line = match std::str::from_utf8(&rest[..len - 1]) {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        if line.starts_with('/') && line != MULTISTREAM_PROTOCOL {
+            protocols.push(line.to_string());
+        }
+        data = &rest[len..];
+    }
+    protocols
+}
+
+fn read_uvarint(data: &[u8]) -> Option<(usize, &[u8])> {
+    let mut value = 0usize;
+    // multistream-select messages are far shorter than 2^21 bytes
+    for (i, byte) in data.iter().take(3).enumerate() {
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, &data[i + 1..]));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn multistream_message(line: &str) -> Vec<u8> {
+        let mut msg = vec![(line.len() + 1) as u8];
+        msg.extend_from_slice(line.as_bytes());
+        msg.push(b'\n');
+        msg
+    }
+
+    #[test]
+    fn test_protocol_tracker_sniffs_negotiation() {
+        let stats = TransportStats::default();
+        let peer_id = PeerId::random();
+        let mut tracker = ProtocolTracker::default().with_stats(stats.clone(), peer_id);
+
+        // the dialer proposes a protocol the listener doesn't support, then another one
+        let mut proposal = multistream_message(MULTISTREAM_PROTOCOL);
+        proposal.extend(multistream_message("/kad/1.0.0"));
+        tracker.record_sent(&proposal);
+        tracker.record_received(&multistream_message(MULTISTREAM_PROTOCOL));
+        tracker.record_received(&multistream_message("na"));
+        assert_eq!(tracker.protocol(), None);
+
+        let mut proposal = multistream_message("/ipfs/ping/1.0.0");
+        proposal.extend([0u8; 32]);
+        tracker.record_sent(&proposal);
+        tracker.record_received(&multistream_message("/ipfs/ping/1.0.0"));
+        assert_eq!(tracker.protocol(), Some("/ipfs/ping/1.0.0".to_string()));
+
+        tracker.record_received(&[0u8; 32]);
+        let traffic = stats.protocol_traffic(&peer_id);
+        assert_eq!(traffic.len(), 1);
+        let ping = &traffic["/ipfs/ping/1.0.0"];
+        assert_eq!(ping.substreams, 1);
+        assert_eq!(ping.bytes_sent, 20 + 12 + 18 + 32);
+        assert_eq!(ping.bytes_received, 20 + 4 + 18 + 32);
+    }
+
+    #[test]
+    fn test_protocol_tracker_explicit_tag() {
+        let stats = TransportStats::default();
+        let peer_id = PeerId::random();
+        let mut tracker = ProtocolTracker::default().with_stats(stats.clone(), peer_id);
+
+        tracker.record_sent(b"not multistream-select");
+        tracker.set_protocol("/meshsub/1.1.0".to_string());
+        tracker.record_received(b"hello");
+
+        let traffic = stats.protocol_traffic(&peer_id);
+        let gossipsub = &traffic["/meshsub/1.1.0"];
+        assert_eq!(gossipsub.substreams, 1);
+        assert_eq!(gossipsub.bytes_sent, 22);
+        assert_eq!(gossipsub.bytes_received, 5);
+    }
+}
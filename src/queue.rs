@@ -1,7 +1,21 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use tracing::{debug, warn};
 
-use crate::message::TransportMessage;
+use nym_sphinx::addressing::clients::Recipient;
+use std::sync::Arc;
+
+use crate::backend::PacketSize;
+use crate::message::{
+    BroadcastMessage, ConnectionId, Message, MessagePriority, OutboundMessage,
+    SubstreamMessageType, TransportMessage,
+};
+
+/// QUANTUM is how many bytes of payload each connection gets to write per turn.
+const QUANTUM: usize = 2048;
+
+/// MESSAGE_COST is what a message is charged on top of its payload, so connections
+/// sending many small messages still take turns.
+const MESSAGE_COST: usize = 64;
 
 /// MessageQueue is a queue of messages, ordered by nonce, that we've
 /// received but are not yet able to process because we're waiting for
@@ -23,6 +37,11 @@ pub(crate) struct MessageQueue {
     /// the head of the queue's nonce is always greater
     /// than the next expected nonce.
     queue: BTreeSet<TransportMessage>,
+
+    /// messages pushed, and of those, the ones whose nonce was already seen, ie. that the
+    /// remote peer sent more than once
+    pub(crate) received: u64,
+    pub(crate) duplicates: u64,
 }
 
 impl MessageQueue {
@@ -30,6 +49,8 @@ impl MessageQueue {
         MessageQueue {
             next_expected_nonce: 0,
             queue: BTreeSet::new(),
+            received: 0,
+            duplicates: 0,
         }
     }
 
@@ -53,6 +74,7 @@ impl MessageQueue {
     /// and should be processed by the caller.
     /// in that case, the internal queue's next expected nonce is incremented.
     pub(crate) fn try_push(&mut self, msg: TransportMessage) -> Option<TransportMessage> {
+        self.received += 1;
         if msg.nonce == self.next_expected_nonce {
             self.next_expected_nonce = self.next_expected_nonce.wrapping_add(1);
             Some(msg)
@@ -61,6 +83,7 @@ impl MessageQueue {
                 // this shouldn't happen normally, only if the other node
                 // is not following the protocol
                 warn!("received a message with a nonce that is too low");
+                self.duplicates += 1;
                 return None;
             }
 
@@ -68,6 +91,7 @@ impl MessageQueue {
                 // this shouldn't happen normally, only if the other node
                 // is not following the protocol
                 warn!("received a message with a duplicate nonce");
+                self.duplicates += 1;
                 return None;
             }
 
@@ -89,9 +113,170 @@ impl MessageQueue {
     }
 }
 
+/// QueuedMessage is a message waiting in an OutboundQueue.
+#[derive(Debug)]
+pub(crate) enum QueuedMessage {
+    Message(OutboundMessage),
+    Broadcast(BroadcastMessage),
+}
+
+/// PendingWrite is a single message to be written to the mixnet, popped from an OutboundQueue.
+#[derive(Debug)]
+pub(crate) enum PendingWrite {
+    Message(OutboundMessage),
+    /// one recipient of a broadcast
+    Broadcast {
+        recipient: Recipient,
+        bytes: Arc<Vec<u8>>,
+        packet_size: Option<PacketSize>,
+    },
+}
+
+/// FlowKey identifies the messages that are written in order within a priority level.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum FlowKey {
+    Connection(ConnectionId),
+    /// each broadcast takes turns on its own
+    Broadcast(u64),
+    /// messages that aren't tied to a connection
+    Other,
+}
+
+/// Flow is the queue of a single connection, broadcast, or the other messages.
+#[derive(Default)]
+struct Flow {
+    messages: VecDeque<QueuedMessage>,
+    /// bytes the flow may still write this turn
+    deficit: usize,
+}
+
+/// PriorityQueue is a deficit round robin scheduler over the flows of one priority level:
+/// each flow in turn writes up to QUANTUM bytes, so that a connection doing a bulk
+/// transfer can't hold up interactive ones.
+#[derive(Default)]
+struct PriorityQueue {
+    flows: HashMap<FlowKey, Flow>,
+    /// flows with queued messages, in the order of their turns
+    active: VecDeque<FlowKey>,
+}
+
+impl PriorityQueue {
+    fn push(&mut self, key: FlowKey, msg: QueuedMessage) {
+        let flow = self.flows.entry(key.clone()).or_insert_with(|| {
+            self.active.push_back(key);
+            Flow {
+                messages: VecDeque::new(),
+                deficit: QUANTUM,
+            }
+        });
+        flow.messages.push_back(msg);
+    }
+
+    fn pop(&mut self) -> Option<PendingWrite> {
+        loop {
+            let key = self.active.front()?.clone();
+            let flow = self.flows.get_mut(&key)?;
+            let cost = cost(flow.messages.front()?);
+            if flow.deficit < cost {
+                // the flow's turn is over; it gets more credit for its next one
+                flow.deficit += QUANTUM;
+                self.active.rotate_left(1);
+                continue;
+            }
+
+            flow.deficit -= cost;
+            let write = match flow.messages.pop_front()? {
+                QueuedMessage::Message(msg) => PendingWrite::Message(msg),
+                QueuedMessage::Broadcast(mut msg) => {
+                    let recipient = msg.recipients.pop_front()?;
+                    let write = PendingWrite::Broadcast {
+                        recipient,
+                        bytes: msg.bytes.clone(),
+                        packet_size: msg.packet_size,
+                    };
+                    if !msg.recipients.is_empty() {
+                        flow.messages.push_front(QueuedMessage::Broadcast(msg));
+                    }
+                    write
+                }
+            };
+            if flow.messages.is_empty() {
+                self.flows.remove(&key);
+                self.active.pop_front();
+            }
+            return Some(write);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.active.is_empty()
+    }
+}
+
+/// cost returns how many bytes of a flow's turn writing the message takes up.
+/// Writing a broadcast to one recipient takes up a whole turn, so a large fan-out takes
+/// turns with the other flows.
+fn cost(msg: &QueuedMessage) -> usize {
+    match msg {
+        QueuedMessage::Message(msg) => match &msg.message {
+            Message::TransportMessage(TransportMessage { message, .. }) => {
+                match &message.message_type {
+                    SubstreamMessageType::Data(data) => MESSAGE_COST + data.len(),
+                    _ => MESSAGE_COST,
+                }
+            }
+            _ => MESSAGE_COST,
+        },
+        QueuedMessage::Broadcast(msg) => QUANTUM.max(msg.bytes.len()),
+    }
+}
+
+/// OutboundQueue holds outbound messages waiting to be written to the mixnet.
+/// Messages are popped highest priority first. Within a priority level, connections
+/// take turns writing a fair share of bytes, and each connection's messages are popped
+/// in the order they were pushed.
+/// Broadcasts are written to one recipient at a time, taking turns with the other
+/// messages of the same priority, so a large fan-out doesn't hold up everything else.
+#[derive(Default)]
+pub(crate) struct OutboundQueue {
+    queues: [PriorityQueue; MessagePriority::COUNT],
+    next_broadcast_id: u64,
+}
+
+impl OutboundQueue {
+    pub(crate) fn push(&mut self, msg: OutboundMessage) {
+        let key = match msg.message.connection_id() {
+            Some(id) => FlowKey::Connection(id.clone()),
+            None => FlowKey::Other,
+        };
+        self.queues[msg.priority.to_u8() as usize].push(key, QueuedMessage::Message(msg));
+    }
+
+    pub(crate) fn push_broadcast(&mut self, msg: BroadcastMessage) {
+        if msg.recipients.is_empty() {
+            return;
+        }
+        let key = FlowKey::Broadcast(self.next_broadcast_id);
+        self.next_broadcast_id += 1;
+        self.queues[msg.priority.to_u8() as usize].push(key, QueuedMessage::Broadcast(msg));
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<PendingWrite> {
+        self.queues
+            .iter_mut()
+            .rev()
+            .find(|queue| !queue.is_empty())?
+            .pop()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.is_empty())
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use crate::message::{ConnectionId, SubstreamId, SubstreamMessage};
+    use crate::message::{ConnectionId, Message, SubstreamId, SubstreamMessage};
 
     use super::*;
 
@@ -134,7 +319,118 @@ mod test {
 
         // should just return the message and increment nonce when message nonce = next expected nonce
         let msg5 = TransportMessage::new(5, test_substream_message, connection_id);
-        assert_eq!(queue.try_push(msg5.clone()), Some(msg5));
+        assert_eq!(queue.try_push(msg5.clone()), Some(msg5.clone()));
         assert_eq!(queue.next_expected_nonce, 6);
+
+        // messages sent again are counted
+        assert_eq!(queue.try_push(msg5), None);
+        assert_eq!(queue.received, 6);
+        assert_eq!(queue.duplicates, 1);
+    }
+
+    #[test]
+    fn test_outbound_queue_priority() {
+        let mut queue = OutboundQueue::default();
+        let recipient = crate::backend::mock::random_recipient();
+        let msg = |nonce, priority| {
+            OutboundMessage::new(
+                Message::TransportMessage(TransportMessage::new(
+                    nonce,
+                    SubstreamMessage::new_close(SubstreamId::generate()),
+                    ConnectionId::generate(),
+                )),
+                recipient,
+            )
+            .with_priority(priority)
+        };
+        let nonce = |write: PendingWrite| match write {
+            PendingWrite::Message(OutboundMessage {
+                message: Message::TransportMessage(msg),
+                ..
+            }) => msg.nonce,
+            write => panic!("expected a TransportMessage, got {:?}", write),
+        };
+
+        queue.push(msg(1, MessagePriority::Low));
+        queue.push(msg(2, MessagePriority::Normal));
+        queue.push(msg(3, MessagePriority::High));
+        queue.push(msg(4, MessagePriority::Normal));
+        assert!(!queue.is_empty());
+
+        assert_eq!(nonce(queue.pop().unwrap()), 3);
+        assert_eq!(nonce(queue.pop().unwrap()), 2);
+        assert_eq!(nonce(queue.pop().unwrap()), 4);
+        assert_eq!(nonce(queue.pop().unwrap()), 1);
+        assert!(queue.pop().is_none());
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_outbound_queue_broadcast_takes_turns() {
+        let mut queue = OutboundQueue::default();
+        let recipients: Vec<_> = (0..3)
+            .map(|_| crate::backend::mock::random_recipient())
+            .collect();
+        let other = crate::backend::mock::random_recipient();
+
+        queue.push_broadcast(BroadcastMessage::new(vec![1, 2, 3], recipients.clone()));
+        queue.push(OutboundMessage::new(
+            Message::TransportMessage(TransportMessage::new(
+                1,
+                SubstreamMessage::new_close(SubstreamId::generate()),
+                ConnectionId::generate(),
+            )),
+            other,
+        ));
+
+        let recipient = |write: PendingWrite| match write {
+            PendingWrite::Message(msg) => msg.recipient,
+            PendingWrite::Broadcast { recipient, .. } => recipient,
+        };
+        assert_eq!(recipient(queue.pop().unwrap()), recipients[0]);
+        assert_eq!(recipient(queue.pop().unwrap()), other);
+        assert_eq!(recipient(queue.pop().unwrap()), recipients[1]);
+        assert_eq!(recipient(queue.pop().unwrap()), recipients[2]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_outbound_queue_connections_take_turns() {
+        let mut queue = OutboundQueue::default();
+        let recipient = crate::backend::mock::random_recipient();
+        let bulk = ConnectionId::generate();
+        let interactive = ConnectionId::generate();
+        let msg = |nonce, id: &ConnectionId, len| {
+            OutboundMessage::new(
+                Message::TransportMessage(TransportMessage::new(
+                    nonce,
+                    SubstreamMessage::new_with_data(SubstreamId::generate(), vec![0u8; len]),
+                    id.clone(),
+                )),
+                recipient,
+            )
+        };
+        let popped = |write: PendingWrite| match write {
+            PendingWrite::Message(OutboundMessage {
+                message: Message::TransportMessage(msg),
+                ..
+            }) => (msg.id, msg.nonce),
+            write => panic!("expected a TransportMessage, got {:?}", write),
+        };
+
+        for nonce in 1..=10 {
+            queue.push(msg(nonce, &bulk, 1500));
+        }
+        queue.push(msg(1, &interactive, 10));
+        queue.push(msg(2, &interactive, 10));
+
+        // the bulk transfer uses up its turn after one message
+        assert_eq!(popped(queue.pop().unwrap()), (bulk.clone(), 1));
+        assert_eq!(popped(queue.pop().unwrap()), (interactive.clone(), 1));
+        assert_eq!(popped(queue.pop().unwrap()), (interactive, 2));
+        for nonce in 2..=10 {
+            assert_eq!(popped(queue.pop().unwrap()), (bulk.clone(), nonce));
+        }
+        assert!(queue.is_empty());
     }
 }
@@ -0,0 +1,207 @@
+use libp2p::core::{
+    identity::{Keypair, PublicKey},
+    Multiaddr, PeerId,
+};
+use nym_sphinx::addressing::clients::Recipient;
+use tokio::time::Duration;
+
+use crate::error::Error;
+use crate::stats::unix_micros;
+use crate::transport::nym_address_to_multiaddress;
+
+/// ADDRESS_RECORD_DOMAIN is prepended to the payload signed in a SignedNymAddressRecord,
+/// so the signature can't be confused with any other use of the libp2p key.
+const ADDRESS_RECORD_DOMAIN: &[u8] = b"libp2p-nym-address-record";
+
+const RECIPIENT_LENGTH: usize = Recipient::LEN;
+const EXPIRY_BYTES_LEN: usize = 8; // length of u64
+const PUBLIC_KEY_LENGTH_BYTES_LEN: usize = 2; // length of u16
+
+/// SignedNymAddressRecord advertises the Nym address a peer can be dialed at, signed with
+/// the peer's libp2p key, so that higher layers (eg. a DHT, rendezvous or gossip) can pass
+/// it around without third parties being able to forge or redirect it. Records expire, so
+/// an address that was replaced can't be replayed forever.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SignedNymAddressRecord {
+    pub(crate) peer_id: PeerId,
+    pub(crate) recipient: Recipient,
+    /// seconds since the unix epoch
+    pub(crate) expiry: u64,
+    pub(crate) public_key: PublicKey,
+    pub(crate) signature: Vec<u8>,
+}
+
+impl SignedNymAddressRecord {
+    /// new returns a record of the given Nym address, signed with the keypair and valid
+    /// for `ttl` from now.
+    pub fn new(keypair: &Keypair, recipient: Recipient, ttl: Duration) -> Result<Self, Error> {
+        let peer_id = PeerId::from(keypair.public());
+        let expiry = unix_micros() / 1_000_000 + ttl.as_secs();
+        let signature = keypair
+            .sign(&signed_payload(&peer_id, &recipient, expiry))
+            .map_err(|e| Error::AddressRecordSigningFailed(e.to_string()))?;
+        Ok(SignedNymAddressRecord {
+            peer_id,
+            recipient,
+            expiry,
+            public_key: keypair.public(),
+            signature,
+        })
+    }
+
+    /// verify checks that the record was signed by its peer and hasn't expired.
+    pub fn verify(&self) -> Result<(), Error> {
+        if PeerId::from_public_key(&self.public_key) != self.peer_id
+            || !self.public_key.verify(
+                &signed_payload(&self.peer_id, &self.recipient, self.expiry),
+                &self.signature,
+            )
+        {
+            return Err(Error::InvalidAddressRecordSignature);
+        }
+        if self.expiry <= unix_micros() / 1_000_000 {
+            return Err(Error::AddressRecordExpired);
+        }
+        Ok(())
+    }
+
+    /// peer_id returns the peer the record advertises an address for.
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// recipient returns the advertised Nym address.
+    pub fn recipient(&self) -> Recipient {
+        self.recipient
+    }
+
+    /// expiry returns when the record stops being valid, in seconds since the unix epoch.
+    pub fn expiry(&self) -> u64 {
+        self.expiry
+    }
+
+    /// multiaddr returns the advertised address in a form that can be dialed.
+    pub fn multiaddr(&self) -> Result<Multiaddr, Error> {
+        nym_address_to_multiaddress(self.recipient)
+    }
+
+    /// to_bytes encodes the record for exchanging it with other peers. The peer ID isn't
+    /// included, since it's derived from the public key.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let public_key = self.public_key.to_protobuf_encoding();
+        let mut bytes = self.recipient.to_bytes().to_vec();
+        bytes.extend_from_slice(&self.expiry.to_be_bytes());
+        bytes.extend_from_slice(&(public_key.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&public_key);
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
+
+    /// try_from_bytes decodes a record encoded with `to_bytes`. The record still has to
+    /// be verified before it's trusted.
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        const PUBLIC_KEY_OFFSET: usize =
+            RECIPIENT_LENGTH + EXPIRY_BYTES_LEN + PUBLIC_KEY_LENGTH_BYTES_LEN;
+        if bytes.len() < PUBLIC_KEY_OFFSET {
+            return Err(Error::InvalidAddressRecordBytes);
+        }
+
+        let mut recipient_bytes = [0u8; RECIPIENT_LENGTH];
+        recipient_bytes.copy_from_slice(&bytes[0..RECIPIENT_LENGTH]);
+        let recipient =
+            Recipient::try_from_bytes(recipient_bytes).map_err(Error::InvalidRecipientBytes)?;
+        let mut offset = RECIPIENT_LENGTH;
+        let expiry = u64::from_be_bytes(
+            bytes[offset..offset + EXPIRY_BYTES_LEN]
+                .try_into()
+                .map_err(|_| Error::InvalidAddressRecordBytes)?,
+        );
+        offset += EXPIRY_BYTES_LEN;
+
+        let public_key_len = u16::from_be_bytes([bytes[offset], bytes[offset + 1]]) as usize;
+        if bytes.len() < PUBLIC_KEY_OFFSET + public_key_len {
+            return Err(Error::InvalidAddressRecordBytes);
+        }
+        let public_key =
+            PublicKey::from_protobuf_encoding(&bytes[PUBLIC_KEY_OFFSET..][..public_key_len])
+                .map_err(|_| Error::InvalidAddressRecordBytes)?;
+        let signature = bytes[PUBLIC_KEY_OFFSET + public_key_len..].to_vec();
+
+        Ok(SignedNymAddressRecord {
+            peer_id: PeerId::from_public_key(&public_key),
+            recipient,
+            expiry,
+            public_key,
+            signature,
+        })
+    }
+}
+
+fn signed_payload(peer_id: &PeerId, recipient: &Recipient, expiry: u64) -> Vec<u8> {
+    let mut bytes = ADDRESS_RECORD_DOMAIN.to_vec();
+    bytes.extend_from_slice(&peer_id.to_bytes());
+    bytes.extend_from_slice(&recipient.to_bytes());
+    bytes.extend_from_slice(&expiry.to_be_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::mock::random_recipient;
+
+    #[test]
+    fn test_address_record_roundtrip_and_verify() {
+        let keypair = Keypair::generate_ed25519();
+        let recipient = random_recipient();
+        let record =
+            SignedNymAddressRecord::new(&keypair, recipient, Duration::from_secs(60)).unwrap();
+        record.verify().unwrap();
+
+        let decoded = SignedNymAddressRecord::try_from_bytes(&record.to_bytes()).unwrap();
+        decoded.verify().unwrap();
+        assert_eq!(decoded, record);
+        assert_eq!(decoded.peer_id(), PeerId::from(keypair.public()));
+        assert_eq!(decoded.recipient(), recipient);
+        assert_eq!(
+            decoded.multiaddr().unwrap().to_string(),
+            format!("/nym/{}", recipient)
+        );
+    }
+
+    #[test]
+    fn test_address_record_rejects_forgery() {
+        let keypair = Keypair::generate_ed25519();
+        let record =
+            SignedNymAddressRecord::new(&keypair, random_recipient(), Duration::from_secs(60))
+                .unwrap();
+
+        // a third party redirecting the record to its own address
+        let mut forged = record.clone();
+        forged.recipient = random_recipient();
+        assert!(matches!(
+            forged.verify(),
+            Err(Error::InvalidAddressRecordSignature)
+        ));
+
+        // or claiming it for another peer
+        let mut forged = record.clone();
+        forged.peer_id = PeerId::random();
+        assert!(matches!(
+            forged.verify(),
+            Err(Error::InvalidAddressRecordSignature)
+        ));
+
+        // or extending its expiry
+        let mut forged = record;
+        forged.expiry += 60;
+        assert!(matches!(
+            forged.verify(),
+            Err(Error::InvalidAddressRecordSignature)
+        ));
+
+        let expired =
+            SignedNymAddressRecord::new(&keypair, random_recipient(), Duration::ZERO).unwrap();
+        assert!(matches!(expired.verify(), Err(Error::AddressRecordExpired)));
+    }
+}
@@ -0,0 +1,236 @@
+use libp2p::core::{identity::Keypair, PeerId};
+use nym_sphinx::addressing::clients::Recipient;
+use parking_lot::Mutex;
+use std::{collections::BTreeMap, sync::Arc};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{info, info_span, Instrument, Span};
+
+use crate::backend::MixnetBackend;
+use crate::endpoint::NymEndpoint;
+use crate::error::Error;
+use crate::transport::NymTransport;
+
+/// ShutdownHandle shuts a transport down, see
+/// [`NymTransport::shutdown`](crate::transport::NymTransport::shutdown). It can be cloned
+/// and kept around after the transport is moved into a swarm.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    shutdown_tx: UnboundedSender<()>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(shutdown_tx: UnboundedSender<()>) -> Self {
+        Self { shutdown_tx }
+    }
+
+    /// shutdown shuts the transport down the next time it's polled. It does nothing if
+    /// the transport is gone already.
+    pub fn shutdown(&self) {
+        self.shutdown_tx.send(()).ok();
+    }
+}
+
+/// InstanceInfo describes a transport in a [`TransportRegistry`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InstanceInfo {
+    pub name: String,
+    pub peer_id: PeerId,
+    /// the transport's Nym address when it was registered
+    pub nym_address: Recipient,
+}
+
+/// TransportRegistry keeps track of the transports of a process hosting several logical
+/// nodes, eg. a gateway-style application, each with its own Nym client and identity.
+/// Every transport is registered under an instance name: whatever it logs is within a
+/// `nym_transport` span carrying the name, so the logs of different nodes can be told
+/// apart or routed separately, and the registry can shut them all down together. It can
+/// be cloned, and the clones share the registered transports.
+#[derive(Clone, Debug, Default)]
+pub struct TransportRegistry {
+    instances: Arc<Mutex<BTreeMap<String, Instance>>>,
+}
+
+#[derive(Debug)]
+struct Instance {
+    info: InstanceInfo,
+    shutdown: ShutdownHandle,
+}
+
+impl TransportRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// new_transport connects a new transport to the mixnet through the endpoint, with
+    /// its own Nym client, and registers it under the name, which must not be taken.
+    pub async fn new_transport<E>(
+        &self,
+        name: &str,
+        endpoint: E,
+        keypair: Keypair,
+    ) -> Result<NymTransport, Error>
+    where
+        E: TryInto<NymEndpoint>,
+        Error: From<E::Error>,
+    {
+        self.check_name(name)?;
+        let span = instance_span(name);
+        // the transport's tasks are spawned within the span, so they log within it
+        let transport = NymTransport::new(endpoint, keypair)
+            .instrument(span.clone())
+            .await?;
+        self.register(name, transport.with_span(span))
+    }
+
+    /// new_transport_with_backend is like new_transport, with a transport reaching the
+    /// mixnet through the given backend.
+    pub fn new_transport_with_backend<B: MixnetBackend>(
+        &self,
+        name: &str,
+        backend: B,
+        keypair: Keypair,
+    ) -> Result<NymTransport, Error> {
+        self.check_name(name)?;
+        let span = instance_span(name);
+        let transport = {
+            let _entered = span.enter();
+            NymTransport::new_with_backend(backend, keypair)?
+        };
+        self.register(name, transport.with_span(span))
+    }
+
+    /// get returns the transport registered under the name, if any.
+    pub fn get(&self, name: &str) -> Option<InstanceInfo> {
+        self.instances
+            .lock()
+            .get(name)
+            .map(|instance| instance.info.clone())
+    }
+
+    /// instances returns the registered transports, ordered by name.
+    pub fn instances(&self) -> Vec<InstanceInfo> {
+        self.instances
+            .lock()
+            .values()
+            .map(|instance| instance.info.clone())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.lock().is_empty()
+    }
+
+    /// shutdown_instance shuts down the transport registered under the name and forgets
+    /// it. It returns false if there's no such transport.
+    pub fn shutdown_instance(&self, name: &str) -> bool {
+        let Some(instance) = self.instances.lock().remove(name) else {
+            return false;
+        };
+        info!("shutting down {}", name);
+        instance.shutdown.shutdown();
+        true
+    }
+
+    /// shutdown shuts down all registered transports and forgets them.
+    pub fn shutdown(&self) {
+        let instances = std::mem::take(&mut *self.instances.lock());
+        info!("shutting down {} transports", instances.len());
+        for instance in instances.into_values() {
+            instance.shutdown.shutdown();
+        }
+    }
+
+    fn check_name(&self, name: &str) -> Result<(), Error> {
+        if self.instances.lock().contains_key(name) {
+            return Err(Error::DuplicateInstanceName(name.to_string()));
+        }
+        Ok(())
+    }
+
+    fn register(&self, name: &str, transport: NymTransport) -> Result<NymTransport, Error> {
+        let mut instances = self.instances.lock();
+        // another transport may have taken the name while this one was connecting
+        if instances.contains_key(name) {
+            return Err(Error::DuplicateInstanceName(name.to_string()));
+        }
+        let info = InstanceInfo {
+            name: name.to_string(),
+            peer_id: transport.peer_id(),
+            nym_address: transport.nym_address(),
+        };
+        instances.insert(
+            name.to_string(),
+            Instance {
+                info,
+                shutdown: transport.shutdown_handle(),
+            },
+        );
+        Ok(transport)
+    }
+}
+
+fn instance_span(name: &str) -> Span {
+    info_span!("nym_transport", instance = %name)
+}
+
+#[cfg(test)]
+mod test {
+    use futures::future::poll_fn;
+    use libp2p::core::transport::{Transport, TransportEvent};
+    use std::{pin::Pin, task::Poll, time::Duration};
+
+    use super::*;
+    use crate::backend::MockMixnet;
+
+    #[tokio::test]
+    async fn test_transport_registry() {
+        let mixnet = MockMixnet::new();
+        let registry = TransportRegistry::new();
+        let mut alice = registry
+            .new_transport_with_backend("alice", mixnet.new_backend(), Keypair::generate_ed25519())
+            .unwrap();
+        let mut bob = registry
+            .new_transport_with_backend("bob", mixnet.new_backend(), Keypair::generate_ed25519())
+            .unwrap();
+        assert!(matches!(
+            registry.new_transport_with_backend(
+                "alice",
+                mixnet.new_backend(),
+                Keypair::generate_ed25519()
+            ),
+            Err(Error::DuplicateInstanceName(_))
+        ));
+
+        let names: Vec<String> = registry.instances().into_iter().map(|i| i.name).collect();
+        assert_eq!(names, vec!["alice", "bob"]);
+        let info = registry.get("alice").unwrap();
+        assert_eq!(info.nym_address, alice.nym_address());
+        assert_ne!(info.nym_address, bob.nym_address());
+
+        // both transports close their listeners once they're shut down
+        registry.shutdown();
+        assert!(registry.is_empty());
+        for transport in [&mut alice, &mut bob] {
+            let closed = tokio::time::timeout(
+                Duration::from_secs(1),
+                poll_fn(|cx| loop {
+                    match Pin::new(&mut *transport).poll(cx) {
+                        Poll::Ready(TransportEvent::ListenerClosed { .. }) => {
+                            return Poll::Ready(())
+                        }
+                        Poll::Ready(_) => continue,
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }),
+            )
+            .await;
+            assert!(closed.is_ok());
+            assert!(transport.is_shut_down());
+        }
+        assert!(!registry.shutdown_instance("alice"));
+    }
+}
@@ -0,0 +1,49 @@
+use futures::future::FutureExt;
+use nym_sphinx::addressing::clients::Recipient;
+use std::{future::Future, time::Duration};
+
+use crate::backend::{BackendFactory, MixnetBackend};
+use crate::error::Error;
+
+/// AddressRotation periodically replaces our Nym address with a fresh one, for
+/// long-running listeners that want their address to be unlinkable over time.
+/// The new address is announced as a new listen address (and thus via identify), while
+/// the old one keeps receiving messages for a grace period before it's retired.
+pub struct AddressRotation<B: MixnetBackend> {
+    /// how often a new address is obtained
+    pub(crate) interval: Duration,
+
+    /// how long a replaced address keeps receiving messages
+    pub(crate) grace_period: Duration,
+
+    /// creates a backend with a fresh Nym client identity
+    pub(crate) new_backend: BackendFactory<B>,
+}
+
+impl<B: MixnetBackend> AddressRotation<B> {
+    /// new returns an AddressRotation which calls `new_backend` every `interval` to obtain
+    /// a backend with a fresh identity, eg. `SdkBackend::connect_new`.
+    pub fn new<F, Fut>(interval: Duration, grace_period: Duration, new_backend: F) -> Self
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<B, Error>> + Send + 'static,
+    {
+        AddressRotation {
+            interval,
+            grace_period,
+            new_backend: Box::new(move || new_backend().boxed()),
+        }
+    }
+}
+
+/// AddressEvent notifies the transport of changes to our Nym addresses.
+#[derive(Clone, Debug)]
+pub(crate) enum AddressEvent {
+    /// we have a new address, which should be used from now on
+    New(Recipient),
+    /// the address no longer receives messages
+    Expired(Recipient),
+    /// our address was replaced without a grace period, eg. after a gateway failover;
+    /// the old address no longer receives messages
+    Changed { old: Recipient, new: Recipient },
+}
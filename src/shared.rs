@@ -0,0 +1,425 @@
+use libp2p::core::{identity::Keypair, PeerId};
+use nym_sphinx::addressing::clients::Recipient;
+use parking_lot::Mutex;
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    watch,
+};
+use tracing::debug;
+
+use crate::backend::{MixnetBackend, MixnetInfo};
+use crate::breaker::CircuitBreaker;
+use crate::budget::MemoryBudget;
+use crate::capture::MessageCapture;
+use crate::error::Error;
+use crate::message::{
+    BroadcastMessage, ConnectionDeniedMessage, ConnectionId, DenialReason, InboundMessage, Message,
+    OutboundMessage,
+};
+use crate::mixnet::{
+    initialize_mixnet_with_rotation, InboundBacklog, MixnetChannels, MixnetOptions, MixnetStatus,
+    OutboundSender,
+};
+use crate::rotation::AddressEvent;
+use crate::stats::{
+    GatewayOutcomes, InboundFramings, MixnetTraffic, ReassemblyEvictions, SendPathTimings,
+    UnknownMessageTypes,
+};
+use crate::testing::ErrorInjector;
+use crate::transport::NymTransport;
+
+/// SharedMixnet lets several independent swarms in one process share a single Nym client.
+/// Each swarm gets its own [`NymTransport`] from [`SharedMixnet::transport`], registered
+/// under a listener key, which is the transport's peer ID. Its listen address is the
+/// shared Nym address followed by `/p2p/<listener key>`; connection requests sent to such
+/// an address are routed to that transport, and requests for unknown keys are denied
+/// with [`DenialReason::UnknownListener`].
+///
+/// Connection requests without a listener key, broadcasts and dial-backs go to the first
+/// registered transport that's still alive. Mixnet options, such as the packet size or
+/// bandwidth caps, are shared by all transports; the last one set applies. So are the
+/// memory budget, the message capture, the gateway, reassembly and send path stats and
+/// the circuit breaker.
+pub struct SharedMixnet {
+    tenants: Arc<Mutex<Tenants>>,
+    inbound_backlog: InboundBacklog,
+    memory_budget: MemoryBudget,
+    message_capture: MessageCapture,
+    gateway_outcomes: GatewayOutcomes,
+    reassembly_evictions: ReassemblyEvictions,
+    unknown_message_types: UnknownMessageTypes,
+    send_path_timings: SendPathTimings,
+    inbound_framings: InboundFramings,
+    mixnet_traffic: MixnetTraffic,
+    breaker: CircuitBreaker,
+    outbound_tx: OutboundSender,
+    broadcast_tx: UnboundedSender<BroadcastMessage>,
+    options_tx: Arc<watch::Sender<MixnetOptions>>,
+    status_rx: watch::Receiver<MixnetStatus>,
+    info_rx: watch::Receiver<MixnetInfo>,
+    injector: ErrorInjector,
+}
+
+impl SharedMixnet {
+    /// new starts a mixnet task on the given backend, which the transports returned by
+    /// `transport` share.
+    pub fn new<B: MixnetBackend>(backend: B) -> Self {
+        let MixnetChannels {
+            self_address,
+            inbound_rx,
+            inbound_backlog,
+            memory_budget,
+            message_capture,
+            gateway_outcomes,
+            reassembly_evictions,
+            unknown_message_types,
+            send_path_timings,
+            inbound_framings,
+            mixnet_traffic,
+            breaker,
+            outbound_tx,
+            broadcast_tx,
+            address_rx,
+            options_tx,
+            status_rx,
+            info_rx,
+            injector,
+        } = initialize_mixnet_with_rotation(backend, None, None);
+
+        let tenants = Arc::new(Mutex::new(Tenants {
+            self_address,
+            tenants: vec![],
+            connections: HashMap::new(),
+        }));
+        let router = Router {
+            tenants: tenants.clone(),
+            inbound_backlog: inbound_backlog.clone(),
+            outbound_tx: outbound_tx.clone(),
+        };
+        tokio::spawn(router.run(inbound_rx, address_rx));
+
+        SharedMixnet {
+            tenants,
+            inbound_backlog,
+            memory_budget,
+            message_capture,
+            gateway_outcomes,
+            reassembly_evictions,
+            unknown_message_types,
+            send_path_timings,
+            inbound_framings,
+            mixnet_traffic,
+            breaker,
+            outbound_tx,
+            broadcast_tx,
+            options_tx,
+            status_rx,
+            info_rx,
+            injector,
+        }
+    }
+
+    /// self_address returns the Nym address of the shared client.
+    pub fn self_address(&self) -> Recipient {
+        self.tenants.lock().self_address
+    }
+
+    /// transport returns a new transport using the shared client, which accepts connection
+    /// requests for the keypair's peer ID. It fails with [`Error::ListenerKeyInUse`] if
+    /// another live transport already uses the same peer ID.
+    pub fn transport(&self, keypair: Keypair) -> Result<NymTransport, Error> {
+        let key = PeerId::from_public_key(&keypair.public());
+        let (inbound_tx, inbound_rx) = unbounded_channel();
+        let (address_tx, address_rx) = unbounded_channel();
+
+        let self_address = {
+            let mut tenants = self.tenants.lock();
+            tenants.remove_closed();
+            if tenants.tenants.iter().any(|tenant| tenant.key == key) {
+                return Err(Error::ListenerKeyInUse(key));
+            }
+            tenants.tenants.push(Tenant {
+                key,
+                inbound_tx,
+                address_tx,
+            });
+            tenants.self_address
+        };
+
+        let channels = MixnetChannels {
+            self_address,
+            inbound_rx,
+            inbound_backlog: self.inbound_backlog.clone(),
+            memory_budget: self.memory_budget.clone(),
+            message_capture: self.message_capture.clone(),
+            gateway_outcomes: self.gateway_outcomes.clone(),
+            reassembly_evictions: self.reassembly_evictions.clone(),
+            unknown_message_types: self.unknown_message_types.clone(),
+            send_path_timings: self.send_path_timings.clone(),
+            inbound_framings: self.inbound_framings.clone(),
+            mixnet_traffic: self.mixnet_traffic.clone(),
+            breaker: self.breaker.clone(),
+            outbound_tx: self.outbound_tx.clone(),
+            broadcast_tx: self.broadcast_tx.clone(),
+            address_rx,
+            options_tx: self.options_tx.clone(),
+            status_rx: self.status_rx.clone(),
+            info_rx: self.info_rx.clone(),
+            injector: self.injector.clone(),
+        };
+        let registration = TenantRegistration {
+            key,
+            tenants: self.tenants.clone(),
+        };
+        NymTransport::new_from_channels(channels, keypair, None, Some(registration))
+    }
+}
+
+/// Tenant is a transport registered with a SharedMixnet.
+struct Tenant {
+    key: PeerId,
+    inbound_tx: UnboundedSender<InboundMessage>,
+    address_tx: UnboundedSender<AddressEvent>,
+}
+
+impl Tenant {
+    fn is_closed(&self) -> bool {
+        self.inbound_tx.is_closed()
+    }
+}
+
+/// Tenants is the routing state of a SharedMixnet.
+struct Tenants {
+    /// the current address of the shared client
+    self_address: Recipient,
+    /// in the order they were registered
+    tenants: Vec<Tenant>,
+    /// the listener key of the transport each connection belongs to
+    connections: HashMap<ConnectionId, PeerId>,
+}
+
+impl Tenants {
+    /// remove_closed removes the transports which were dropped, along with their
+    /// connections.
+    fn remove_closed(&mut self) {
+        if !self.tenants.iter().any(Tenant::is_closed) {
+            return;
+        }
+        self.tenants.retain(|tenant| !tenant.is_closed());
+        let tenants = &self.tenants;
+        self.connections
+            .retain(|_, key| tenants.iter().any(|tenant| tenant.key == *key));
+    }
+
+    fn get(&self, key: &PeerId) -> Option<&Tenant> {
+        self.tenants.iter().find(|tenant| tenant.key == *key)
+    }
+
+    /// route returns the transport the message is for, if any.
+    fn route(&mut self, msg: &Message) -> Option<&Tenant> {
+        let key = match msg {
+            Message::ConnectionRequest(request) => {
+                let key = match request.target {
+                    Some(target) => target,
+                    None => self.tenants.first()?.key,
+                };
+                self.get(&key)?;
+                self.connections.insert(request.id.clone(), key);
+                key
+            }
+            Message::ConnectionDenied(denied) => self.connections.remove(&denied.id)?,
+            Message::ConnectionClose(close) => self.connections.remove(&close.id)?,
+            Message::Broadcast(_)
+            | Message::Datagram(_)
+            | Message::DialBack(_)
+            | Message::EchoRequest(_)
+            | Message::EchoReply(_) => self.tenants.first()?.key,
+            msg => *self.connections.get(msg.connection_id()?)?,
+        };
+        self.get(&key)
+    }
+}
+
+/// TenantRegistration is held by a transport using a SharedMixnet, so it can register the
+/// connections it dials.
+pub(crate) struct TenantRegistration {
+    key: PeerId,
+    tenants: Arc<Mutex<Tenants>>,
+}
+
+impl TenantRegistration {
+    /// register_connection routes the messages of the connection to the transport.
+    pub(crate) fn register_connection(&self, id: &ConnectionId) {
+        self.tenants.lock().connections.insert(id.clone(), self.key);
+    }
+
+    /// unregister_connection stops routing the messages of a connection that was never
+    /// established to the transport.
+    pub(crate) fn unregister_connection(&self, id: &ConnectionId) {
+        self.tenants.lock().connections.remove(id);
+    }
+}
+
+/// Router passes the messages and address changes of the shared client to the transports
+/// they're for.
+struct Router {
+    tenants: Arc<Mutex<Tenants>>,
+    inbound_backlog: InboundBacklog,
+    outbound_tx: OutboundSender,
+}
+
+impl Router {
+    async fn run(
+        self,
+        mut inbound_rx: UnboundedReceiver<InboundMessage>,
+        mut address_rx: UnboundedReceiver<AddressEvent>,
+    ) {
+        loop {
+            tokio::select! {
+                msg = inbound_rx.recv() => match msg {
+                    Some(msg) => self.route_inbound(msg),
+                    None => break,
+                },
+                Some(event) = address_rx.recv() => self.route_address_event(event),
+            }
+        }
+        debug!("shared mixnet router stopped");
+    }
+
+    fn route_inbound(&self, msg: InboundMessage) {
+        let mut tenants = self.tenants.lock();
+        tenants.remove_closed();
+        let Some(tenant) = tenants.route(&msg.0) else {
+            // transports pop the backlog once they've handled a message, so messages
+            // which don't reach one are handled here
+            self.inbound_backlog.pop();
+            self.deny_unknown_listener(msg.0);
+            return;
+        };
+        if let Err(e) = tenant.inbound_tx.send(msg) {
+            debug!("failed to route inbound message: {:?}", e);
+            self.inbound_backlog.pop();
+        }
+    }
+
+    /// deny_unknown_listener tells the dialer there's no listener for a connection request,
+    /// so its dial fails right away rather than timing out.
+    fn deny_unknown_listener(&self, msg: Message) {
+        let Message::ConnectionRequest(request) = msg else {
+            debug!("dropping inbound {:?} for no known listener", msg.kind());
+            return;
+        };
+        let Some(recipient) = request.recipient else {
+            return;
+        };
+        debug!("denying connection request for unknown listener key");
+        let denied = ConnectionDeniedMessage {
+            id: request.id,
+            reason: DenialReason::UnknownListener,
+        };
+        self.outbound_tx
+            .send(OutboundMessage::new(
+                Message::ConnectionDenied(denied),
+                recipient,
+            ))
+            .ok();
+    }
+
+    fn route_address_event(&self, event: AddressEvent) {
+        let mut tenants = self.tenants.lock();
+        match event {
+            AddressEvent::New(address) | AddressEvent::Changed { new: address, .. } => {
+                tenants.self_address = address
+            }
+            AddressEvent::Expired(_) => {}
+        }
+        for tenant in &tenants.tenants {
+            tenant.address_tx.send(event.clone()).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::future::poll_fn;
+    use libp2p::core::{
+        transport::{Transport, TransportEvent},
+        Multiaddr,
+    };
+    use std::{pin::Pin, str::FromStr};
+
+    use super::*;
+    use crate::backend::MockMixnet;
+    use crate::test_utils::new_mock_transport;
+
+    async fn assert_new_address_event(transport: &mut NymTransport) {
+        match poll_fn(|cx| Pin::new(&mut *transport).poll(cx)).await {
+            TransportEvent::NewAddress { listen_addr, .. } => {
+                assert_eq!(listen_addr, transport.listen_addr)
+            }
+            event => panic!("expected TransportEvent::NewAddress, got {:?}", event),
+        }
+    }
+
+    /// dial dials the address and returns the result, driving the dialer while waiting.
+    async fn dial(
+        dialer: &mut NymTransport,
+        addr: Multiaddr,
+    ) -> Result<(PeerId, crate::connection::Connection), Error> {
+        let dial = dialer.dial(addr).unwrap();
+        tokio::select! {
+            res = dial => res,
+            event = poll_fn(|cx| Pin::new(&mut *dialer).poll(cx)) => {
+                panic!("unexpected transport event {:?}", event)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shared_mixnet_routes_by_listener_key() {
+        let mixnet = MockMixnet::new();
+        let shared = SharedMixnet::new(mixnet.new_backend());
+        let keypair = Keypair::generate_ed25519();
+        let mut first = shared.transport(Keypair::generate_ed25519()).unwrap();
+        let mut second = shared.transport(keypair.clone()).unwrap();
+        assert!(matches!(
+            shared.transport(keypair),
+            Err(Error::ListenerKeyInUse(_))
+        ));
+        assert_eq!(
+            second.listen_addr.to_string(),
+            format!("/nym/{}/p2p/{}", shared.self_address(), second.peer_id())
+        );
+        assert_new_address_event(&mut first).await;
+        assert_new_address_event(&mut second).await;
+
+        let mut dialer = new_mock_transport(&mixnet);
+        assert_new_address_event(&mut dialer).await;
+
+        // each dial reaches the transport with the dialed listener key
+        for listener in [&mut second, &mut first] {
+            let addr = listener.listen_addr.clone();
+            let (dialed, accepted) = tokio::join!(dial(&mut dialer, addr), async {
+                match poll_fn(|cx| Pin::new(&mut *listener).poll(cx)).await {
+                    TransportEvent::Incoming { upgrade, .. } => upgrade.await.unwrap().0,
+                    event => panic!("expected TransportEvent::Incoming, got {:?}", event),
+                }
+            });
+            assert_eq!(dialed.unwrap().0, listener.peer_id());
+            assert_eq!(accepted, dialer.peer_id());
+        }
+
+        // requests for unknown listener keys are denied
+        let unknown = Multiaddr::from_str(&format!(
+            "/nym/{}/p2p/{}",
+            shared.self_address(),
+            PeerId::random()
+        ))
+        .unwrap();
+        assert!(matches!(
+            dial(&mut dialer, unknown).await,
+            Err(Error::ConnectionDenied(DenialReason::UnknownListener))
+        ));
+    }
+}
@@ -0,0 +1,724 @@
+//! The wire format of the messages exchanged between transports, for independent
+//! implementations to follow. The golden vectors in `spec/vectors.txt` pin it down to the
+//! byte; they're checked against this crate's encoding by the tests below.
+//!
+//! Every message is written to the mixnet as a single Nym message, starting with a type
+//! byte (see [`MessageKind::type_byte`]), followed by the fields of that type at the
+//! offsets in the module named after it. Integers are big-endian. Messages that don't
+//! fit the configured frame size, or need more sphinx packets than configured, are split
+//! into fragments (see [`fragment`]). In the v2 framing, each whole message or fragment is
+//! preceded by a [`frame`] header.
+//!
+//! - ConnectionRequest and ConnectionResponse: connection ID, a [`recipient_flag`] byte,
+//!   an [`extension`] byte if flagged, the sender's Nym address if flagged, the listener
+//!   key if flagged (a length byte and a peer ID), the handshake payload if flagged (a
+//!   length and the payload), the timestamps if the [`CLOCK`](extension::CLOCK) extension
+//!   is listed (a count byte and the timestamps), the sender's connection reference if the
+//!   [`COMPACT_ID`](extension::COMPACT_ID) extension is listed (a varint), the sender's
+//!   user agent if the [`USER_AGENT`](extension::USER_AGENT) extension is listed (a length
+//!   byte and UTF-8 text), and the sender's peer ID until the end of the message.
+//! - Transport: nonce, connection ID, substream ID, a [`substream_op`] byte, for stamped
+//!   data the send timestamp in microseconds since the unix epoch, for resets a
+//!   [`ResetCode`] byte, and for data the substream data until the end of the message.
+//! - AddressUpdate: connection ID, epoch, new Nym address, length of the public key,
+//!   protobuf-encoded libp2p public key, and the signature until the end of the message.
+//!   The signature covers [`ADDRESS_UPDATE_DOMAIN`], the connection ID, epoch and address.
+//! - Ping: connection ID and send timestamp. Pong: connection ID and the timestamps the
+//!   ping was sent and received at and the pong was sent at, all in microseconds since the
+//!   unix epoch.
+//! - Broadcast: the payload until the end of the message.
+//! - ReachabilityRequest: connection ID, nonce and the Nym address to dial back.
+//!   DialBack: the nonce of the request.
+//! - ConnectionDenied: connection ID and a [`DenialReason`] byte.
+//! - EchoRequest: nonce, send timestamp and the Nym address to reply to. EchoReply: the
+//!   nonce of the request and the timestamps it was sent and received at and the reply
+//!   was sent at, like Pong.
+//! - Compact: the receiver's reference to the connection as an unsigned LEB128 varint,
+//!   then a Transport, AddressUpdate, Ping or Pong message, type byte included, without
+//!   its connection ID. Only sent on connections using the
+//!   [`COMPACT_ID`](extension::COMPACT_ID) extension.
+//! - ConnectionClose: connection ID and a [`ShutdownReason`] byte.
+//! - CloseAck: the connection ID of the ConnectionClose it acknowledges. Only sent on
+//!   connections using the [`CLOSE_ACK`](extension::CLOSE_ACK) extension.
+//! - Datagram: the payload until the end of the message, like Broadcast. Peers from
+//!   before datagrams existed skip them as an unknown message type.
+
+use nym_sphinx::addressing::clients::Recipient;
+
+pub use crate::message::{DenialReason, MessageKind, ResetCode, ShutdownReason};
+
+/// PROTOCOL_VERSION is the version of the messages exchanged between transports.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+pub const TYPE_LEN: usize = 1;
+pub const CONNECTION_ID_LEN: usize = 32;
+pub const SUBSTREAM_ID_LEN: usize = 32;
+pub const RECIPIENT_LEN: usize = Recipient::LEN;
+pub const NONCE_LEN: usize = 8; // length of u64
+pub const EPOCH_LEN: usize = 8; // length of u64
+pub const TIMESTAMP_LEN: usize = 8; // length of u64
+pub const PUBLIC_KEY_LENGTH_LEN: usize = 2; // length of u16
+pub const HANDSHAKE_PAYLOAD_LENGTH_LEN: usize = 2; // length of u16
+pub const USER_AGENT_LENGTH_LEN: usize = 1; // length of u8
+
+/// ADDRESS_UPDATE_DOMAIN is prepended to the payload signed in an AddressUpdate.
+pub const ADDRESS_UPDATE_DOMAIN: &[u8] = b"libp2p-nym-address-update";
+
+impl MessageKind {
+    /// ALL lists every message type, in the order of their type bytes.
+    pub const ALL: [MessageKind; 16] = [
+        MessageKind::ConnectionRequest,
+        MessageKind::ConnectionResponse,
+        MessageKind::Transport,
+        MessageKind::AddressUpdate,
+        MessageKind::Ping,
+        MessageKind::Pong,
+        MessageKind::Broadcast,
+        MessageKind::ReachabilityRequest,
+        MessageKind::DialBack,
+        MessageKind::ConnectionDenied,
+        MessageKind::EchoRequest,
+        MessageKind::EchoReply,
+        MessageKind::Compact,
+        MessageKind::ConnectionClose,
+        MessageKind::CloseAck,
+        MessageKind::Datagram,
+    ];
+
+    /// type_byte returns the byte messages of this type start with.
+    pub fn type_byte(self) -> u8 {
+        match self {
+            MessageKind::ConnectionRequest => 0,
+            MessageKind::ConnectionResponse => 1,
+            MessageKind::Transport => 2,
+            MessageKind::AddressUpdate => 3,
+            MessageKind::Ping => 4,
+            MessageKind::Pong => 5,
+            MessageKind::Broadcast => 6,
+            MessageKind::ReachabilityRequest => 7,
+            MessageKind::DialBack => 8,
+            MessageKind::ConnectionDenied => 9,
+            MessageKind::EchoRequest => 10,
+            MessageKind::EchoReply => 11,
+            MessageKind::Compact => 12,
+            MessageKind::ConnectionClose => 13,
+            MessageKind::CloseAck => 14,
+            MessageKind::Datagram => 15,
+        }
+    }
+
+    /// from_type_byte returns the type of a message starting with the given byte, if any.
+    pub fn from_type_byte(byte: u8) -> Option<Self> {
+        Self::ALL.get(byte as usize).copied()
+    }
+}
+
+/// the byte after the connection ID of ConnectionRequest and ConnectionResponse messages.
+pub mod recipient_flag {
+    /// no Nym address follows; used by ConnectionResponse.
+    pub const NONE: u8 = 0;
+    /// the sender's Nym address follows.
+    pub const RECIPIENT: u8 = 1;
+    /// the sender's Nym address follows, and then the listener key being dialed.
+    pub const RECIPIENT_AND_TARGET: u8 = 2;
+    /// only the listener key being dialed follows; used by anonymous ConnectionRequests,
+    /// which are answered through reply SURBs rather than at the sender's address.
+    pub const TARGET: u8 = 3;
+    /// or'd with the flags above: an [`extension`](super::extension) byte follows the
+    /// flag. Peers that don't know about extensions reject such messages, so it's only
+    /// set by peers that enabled an extension.
+    pub const EXTENSIONS: u8 = 0x80;
+    /// or'd with the flags above: a handshake payload follows the listener key, if any.
+    /// Like extensions, it's only set if the dialer attaches a payload.
+    pub const HANDSHAKE_PAYLOAD: u8 = 0x40;
+}
+
+/// bits of the extensions byte of ConnectionRequest and ConnectionResponse messages. A
+/// request lists the extensions the dialer supports, and the response those of them the
+/// listener supports too, which the connection then uses.
+pub mod extension {
+    /// substream data is sent as [`STAMPED_DATA`](super::substream_op::STAMPED_DATA).
+    pub const LATENCY: u8 = 1;
+    /// connection messages carry timestamps, in microseconds since the unix epoch, for
+    /// estimating the offset between the peers' clocks: a request the time it was sent,
+    /// and a response the times the request was sent and received and it was sent, like
+    /// Pong.
+    pub const CLOCK: u8 = 2;
+    /// connection messages carry a reference the sender picked for the connection, and
+    /// messages after the handshake are sent as Compact messages with the receiver's
+    /// reference in place of the connection ID.
+    pub const COMPACT_ID: u8 = 4;
+    /// connection messages carry the name and version of the sender's implementation, eg.
+    /// `rust-libp2p-nym/0.1.0`, for diagnosing interop problems. A listener only sends its
+    /// own if the request carries one.
+    pub const USER_AGENT: u8 = 8;
+    /// ConnectionClose messages are acknowledged with a CloseAck, and sent again until
+    /// they are, so a lost close doesn't leave the remote peer waiting on the connection.
+    pub const CLOSE_ACK: u8 = 16;
+}
+
+/// the byte after the substream ID of Transport messages.
+pub mod substream_op {
+    pub const OPEN_REQUEST: u8 = 0;
+    pub const OPEN_RESPONSE: u8 = 1;
+    pub const CLOSE: u8 = 2;
+    /// followed by the substream data.
+    pub const DATA: u8 = 3;
+    /// followed by the send timestamp and the substream data; only sent on connections
+    /// using the [`LATENCY`](super::extension::LATENCY) extension.
+    pub const STAMPED_DATA: u8 = 4;
+    /// followed by a [`ResetCode`](super::ResetCode) byte; declines an open request.
+    pub const RESET: u8 = 5;
+}
+
+/// offsets of the fields of ConnectionRequest and ConnectionResponse messages. Fields
+/// after the recipient flag depend on it.
+pub mod connection {
+    use super::*;
+
+    pub const CONNECTION_ID: usize = TYPE_LEN;
+    pub const RECIPIENT_FLAG: usize = CONNECTION_ID + CONNECTION_ID_LEN;
+    /// only present if flagged; if it is, the fields after it are one byte later.
+    pub const EXTENSIONS: usize = RECIPIENT_FLAG + 1;
+    /// only present if flagged.
+    pub const RECIPIENT: usize = RECIPIENT_FLAG + 1;
+}
+
+/// offsets of the fields of Transport messages.
+pub mod transport {
+    use super::*;
+
+    pub const NONCE: usize = TYPE_LEN;
+    pub const CONNECTION_ID: usize = NONCE + NONCE_LEN;
+    pub const SUBSTREAM_ID: usize = CONNECTION_ID + CONNECTION_ID_LEN;
+    pub const SUBSTREAM_OP: usize = SUBSTREAM_ID + SUBSTREAM_ID_LEN;
+    pub const DATA: usize = SUBSTREAM_OP + 1;
+    /// only for stamped data.
+    pub const SENT_AT: usize = SUBSTREAM_OP + 1;
+    pub const STAMPED_DATA: usize = SENT_AT + TIMESTAMP_LEN;
+    /// only for resets.
+    pub const RESET_CODE: usize = SUBSTREAM_OP + 1;
+}
+
+/// offsets of the fields of AddressUpdate messages.
+pub mod address_update {
+    use super::*;
+
+    pub const CONNECTION_ID: usize = TYPE_LEN;
+    pub const EPOCH: usize = CONNECTION_ID + CONNECTION_ID_LEN;
+    pub const RECIPIENT: usize = EPOCH + EPOCH_LEN;
+    pub const PUBLIC_KEY_LENGTH: usize = RECIPIENT + RECIPIENT_LEN;
+    /// followed by the signature.
+    pub const PUBLIC_KEY: usize = PUBLIC_KEY_LENGTH + PUBLIC_KEY_LENGTH_LEN;
+}
+
+/// offsets of the fields of Ping messages.
+pub mod ping {
+    use super::*;
+
+    pub const CONNECTION_ID: usize = TYPE_LEN;
+    pub const SENT_AT: usize = CONNECTION_ID + CONNECTION_ID_LEN;
+}
+
+/// offsets of the fields of Pong messages.
+pub mod pong {
+    use super::*;
+
+    pub const CONNECTION_ID: usize = TYPE_LEN;
+    pub const PING_SENT_AT: usize = CONNECTION_ID + CONNECTION_ID_LEN;
+    pub const PING_RECEIVED_AT: usize = PING_SENT_AT + TIMESTAMP_LEN;
+    pub const SENT_AT: usize = PING_RECEIVED_AT + TIMESTAMP_LEN;
+}
+
+/// offsets of the fields of Broadcast messages.
+pub mod broadcast {
+    use super::*;
+
+    pub const PAYLOAD: usize = TYPE_LEN;
+}
+
+/// offsets of the fields of ReachabilityRequest messages.
+pub mod reachability_request {
+    use super::*;
+
+    pub const CONNECTION_ID: usize = TYPE_LEN;
+    pub const NONCE: usize = CONNECTION_ID + CONNECTION_ID_LEN;
+    pub const ADDRESS: usize = NONCE + NONCE_LEN;
+}
+
+/// offsets of the fields of DialBack messages.
+pub mod dial_back {
+    use super::*;
+
+    pub const NONCE: usize = TYPE_LEN;
+}
+
+/// offsets of the fields of ConnectionDenied messages.
+pub mod connection_denied {
+    use super::*;
+
+    pub const CONNECTION_ID: usize = TYPE_LEN;
+    pub const REASON: usize = CONNECTION_ID + CONNECTION_ID_LEN;
+}
+
+/// offsets of the fields of Compact messages.
+pub mod compact {
+    use super::*;
+
+    /// a varint of one to ten bytes, followed by the message without its connection ID.
+    pub const REFERENCE: usize = TYPE_LEN;
+}
+
+/// offsets of the fields of ConnectionClose messages.
+pub mod connection_close {
+    use super::*;
+
+    pub const CONNECTION_ID: usize = TYPE_LEN;
+    pub const REASON: usize = CONNECTION_ID + CONNECTION_ID_LEN;
+}
+
+/// offsets of the fields of CloseAck messages.
+pub mod close_ack {
+    use super::*;
+
+    pub const CONNECTION_ID: usize = TYPE_LEN;
+}
+
+/// offsets of the fields of Datagram messages.
+pub mod datagram {
+    use super::*;
+
+    pub const PAYLOAD: usize = TYPE_LEN;
+}
+
+/// fragments of a message split up to fit a frame size cap. The tag isn't a valid type
+/// byte, so fragments can't be mistaken for whole messages. Fragments of a message share
+/// its random message ID and may arrive in any order; the payloads concatenated in index
+/// order are the message. That message may itself be a fragment, split up again to fit a
+/// frame size cap, but only one level deep.
+pub mod fragment {
+    pub const TAG: u8 = 0xff;
+
+    pub const MESSAGE_ID: usize = 1;
+    pub const INDEX: usize = MESSAGE_ID + 8;
+    pub const COUNT: usize = INDEX + 2;
+    pub const PAYLOAD: usize = COUNT + 2;
+}
+
+/// the header of a Nym message in the v2 framing: a tag and the framing version, followed
+/// by the message as it's written in the v1 framing, ie. a whole message or a fragment.
+/// The tag is neither a valid type byte nor the fragment tag, so v2 messages can't be
+/// mistaken for v1 ones; see [`crate::framing`].
+pub mod frame {
+    pub const TAG: u8 = 0xfe;
+    /// the framing version of v2 messages
+    pub const V2: u8 = 2;
+
+    pub const VERSION: usize = 1;
+    pub const MESSAGE: usize = VERSION + 1;
+}
+
+#[cfg(test)]
+mod test {
+    use libp2p::core::{
+        identity::{ed25519, Keypair},
+        PeerId,
+    };
+
+    use super::*;
+    use crate::message::{
+        parse_message_data, AddressUpdateMessage, CloseAckMessage, CompactMessage,
+        ConnectionCloseMessage, ConnectionDeniedMessage, ConnectionId, ConnectionMessage,
+        DialBackMessage, EchoReplyMessage, EchoRequestMessage, Message, PingMessage, PongMessage,
+        ReachabilityRequestMessage, SubstreamId, SubstreamMessage, SubstreamMessageType,
+        TransportMessage,
+    };
+
+    const VECTORS: &str = include_str!("../spec/vectors.txt");
+
+    // the inputs of the golden vectors: keys are derived from fixed ed25519 seeds (32
+    // repetitions of the given byte), peer IDs are those of the keys' public keys, and
+    // the Nym address has the keys of seeds 1 and 2 as identity and gateway keys, and
+    // the x25519 base point as encryption key.
+
+    fn keypair(seed: u8) -> Keypair {
+        let secret = ed25519::SecretKey::from_bytes(&mut [seed; 32]).unwrap();
+        Keypair::Ed25519(secret.into())
+    }
+
+    fn peer_id(seed: u8) -> PeerId {
+        PeerId::from(keypair(seed).public())
+    }
+
+    fn recipient() -> Recipient {
+        let public_key = |seed| match keypair(seed).public() {
+            libp2p::core::identity::PublicKey::Ed25519(key) => key.encode(),
+            _ => unreachable!(),
+        };
+        let mut bytes = [0u8; RECIPIENT_LEN];
+        bytes[..32].copy_from_slice(&public_key(1));
+        bytes[32] = 9;
+        bytes[64..].copy_from_slice(&public_key(2));
+        Recipient::try_from_bytes(bytes).unwrap()
+    }
+
+    fn connection_id() -> ConnectionId {
+        ConnectionId::from_bytes(&[0x11; CONNECTION_ID_LEN])
+    }
+
+    fn transport(message_type: SubstreamMessageType) -> Message {
+        Message::TransportMessage(TransportMessage {
+            nonce: 1,
+            id: connection_id(),
+            message: SubstreamMessage::new(SubstreamId([0x22; SUBSTREAM_ID_LEN]), message_type),
+        })
+    }
+
+    /// vector returns the message the named golden vector encodes.
+    fn vector(name: &str) -> Message {
+        match name {
+            "connection_request" => Message::ConnectionRequest(ConnectionMessage {
+                peer_id: peer_id(7),
+                id: connection_id(),
+                recipient: Some(recipient()),
+                target: None,
+                extensions: 0,
+                handshake_payload: None,
+                timestamps: vec![],
+                connection_ref: None,
+                user_agent: None,
+                reply_tag: None,
+            }),
+            "connection_request_with_extensions" => Message::ConnectionRequest(ConnectionMessage {
+                peer_id: peer_id(7),
+                id: connection_id(),
+                recipient: Some(recipient()),
+                target: None,
+                extensions: extension::LATENCY,
+                handshake_payload: None,
+                timestamps: vec![],
+                connection_ref: None,
+                user_agent: None,
+                reply_tag: None,
+            }),
+            "connection_request_with_clock" => Message::ConnectionRequest(ConnectionMessage {
+                peer_id: peer_id(7),
+                id: connection_id(),
+                recipient: Some(recipient()),
+                target: None,
+                extensions: extension::CLOCK,
+                handshake_payload: None,
+                timestamps: vec![1_700_000_000_000_000],
+                connection_ref: None,
+                user_agent: None,
+                reply_tag: None,
+            }),
+            "connection_response_with_clock" => Message::ConnectionResponse(ConnectionMessage {
+                peer_id: peer_id(7),
+                id: connection_id(),
+                recipient: None,
+                target: None,
+                extensions: extension::CLOCK,
+                handshake_payload: None,
+                timestamps: vec![
+                    1_700_000_000_000_000,
+                    1_700_000_000_250_000,
+                    1_700_000_000_260_000,
+                ],
+                connection_ref: None,
+                user_agent: None,
+                reply_tag: None,
+            }),
+            "connection_request_with_compact_id" => Message::ConnectionRequest(ConnectionMessage {
+                peer_id: peer_id(7),
+                id: connection_id(),
+                recipient: Some(recipient()),
+                target: None,
+                extensions: extension::COMPACT_ID,
+                handshake_payload: None,
+                timestamps: vec![],
+                connection_ref: Some(300),
+                user_agent: None,
+                reply_tag: None,
+            }),
+            "connection_request_with_user_agent" => Message::ConnectionRequest(ConnectionMessage {
+                peer_id: peer_id(7),
+                id: connection_id(),
+                recipient: Some(recipient()),
+                target: None,
+                extensions: extension::USER_AGENT,
+                handshake_payload: None,
+                timestamps: vec![],
+                connection_ref: None,
+                user_agent: Some("example/1.0".to_string()),
+                reply_tag: None,
+            }),
+            "connection_request_with_payload" => Message::ConnectionRequest(ConnectionMessage {
+                peer_id: peer_id(7),
+                id: connection_id(),
+                recipient: Some(recipient()),
+                target: Some(peer_id(8)),
+                extensions: 0,
+                handshake_payload: Some(b"hello".to_vec()),
+                timestamps: vec![],
+                connection_ref: None,
+                user_agent: None,
+                reply_tag: None,
+            }),
+            "connection_request_with_target" => Message::ConnectionRequest(ConnectionMessage {
+                peer_id: peer_id(7),
+                id: connection_id(),
+                recipient: Some(recipient()),
+                target: Some(peer_id(8)),
+                extensions: 0,
+                handshake_payload: None,
+                timestamps: vec![],
+                connection_ref: None,
+                user_agent: None,
+                reply_tag: None,
+            }),
+            "anonymous_connection_request_with_target" => {
+                Message::ConnectionRequest(ConnectionMessage {
+                    peer_id: peer_id(7),
+                    id: connection_id(),
+                    recipient: None,
+                    target: Some(peer_id(8)),
+                    extensions: 0,
+                    handshake_payload: None,
+                    timestamps: vec![],
+                    connection_ref: None,
+                    user_agent: None,
+                    reply_tag: None,
+                })
+            }
+            "connection_response" => Message::ConnectionResponse(ConnectionMessage {
+                peer_id: peer_id(7),
+                id: connection_id(),
+                recipient: None,
+                target: None,
+                extensions: 0,
+                handshake_payload: None,
+                timestamps: vec![],
+                connection_ref: None,
+                user_agent: None,
+                reply_tag: None,
+            }),
+            "transport_open_request" => transport(SubstreamMessageType::OpenRequest),
+            "transport_open_response" => transport(SubstreamMessageType::OpenResponse),
+            "transport_close" => transport(SubstreamMessageType::Close),
+            "transport_data" => transport(SubstreamMessageType::Data(b"hello".to_vec())),
+            "transport_reset" => transport(SubstreamMessageType::Reset(ResetCode::TooManyStreams)),
+            "transport_stamped_data" => {
+                let mut msg = transport(SubstreamMessageType::Data(b"hello".to_vec()));
+                if let Message::TransportMessage(msg) = &mut msg {
+                    msg.message.sent_at = Some(1_700_000_000_000_000);
+                }
+                msg
+            }
+            "address_update" => Message::AddressUpdate(
+                AddressUpdateMessage::new_signed(connection_id(), 7, recipient(), &keypair(7))
+                    .unwrap(),
+            ),
+            "ping" => Message::Ping(PingMessage {
+                id: connection_id(),
+                sent_at: 1_700_000_000_000_000,
+            }),
+            "pong" => Message::Pong(PongMessage {
+                id: connection_id(),
+                ping_sent_at: 1_700_000_000_000_000,
+                ping_received_at: 1_700_000_000_250_000,
+                sent_at: 1_700_000_000_260_000,
+            }),
+            "broadcast" => Message::Broadcast(b"hello".to_vec()),
+            "reachability_request" => Message::ReachabilityRequest(ReachabilityRequestMessage {
+                id: connection_id(),
+                nonce: 42,
+                address: recipient(),
+            }),
+            "dial_back" => Message::DialBack(DialBackMessage { nonce: 42 }),
+            "connection_denied" => Message::ConnectionDenied(ConnectionDeniedMessage {
+                id: connection_id(),
+                reason: DenialReason::RateLimited,
+            }),
+            "echo_request" => Message::EchoRequest(EchoRequestMessage {
+                nonce: 42,
+                sent_at: 1_700_000_000_000_000,
+                reply_to: recipient(),
+            }),
+            "echo_reply" => Message::EchoReply(EchoReplyMessage {
+                nonce: 42,
+                request_sent_at: 1_700_000_000_000_000,
+                request_received_at: 1_700_000_000_250_000,
+                sent_at: 1_700_000_000_260_000,
+            }),
+            "compact_transport_data" => Message::Compact(CompactMessage {
+                reference: 300,
+                message: Box::new(transport(SubstreamMessageType::Data(b"hello".to_vec()))),
+            }),
+            "compact_ping" => Message::Compact(CompactMessage {
+                reference: 1,
+                message: Box::new(Message::Ping(PingMessage {
+                    id: ConnectionId::default(),
+                    sent_at: 1_700_000_000_000_000,
+                })),
+            }),
+            "connection_close" => Message::ConnectionClose(ConnectionCloseMessage {
+                id: connection_id(),
+                reason: ShutdownReason::ProtocolError,
+            }),
+            "close_ack" => Message::CloseAck(CloseAckMessage {
+                id: connection_id(),
+            }),
+            "datagram" => Message::Datagram(b"hello".to_vec()),
+            name => panic!("no inputs for golden vector {}", name),
+        }
+    }
+
+    fn vectors() -> impl Iterator<Item = (&'static str, Vec<u8>)> {
+        VECTORS
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (name, bytes) = line.split_once(' ').unwrap();
+                (name, hex::decode(bytes).unwrap())
+            })
+    }
+
+    #[test]
+    fn test_golden_vectors() {
+        let mut kinds = vec![];
+        for (name, bytes) in vectors() {
+            let msg = vector(name);
+            assert_eq!(hex::encode(msg.to_bytes()), hex::encode(&bytes), "{}", name);
+
+            let parsed = parse_message_data(&bytes).unwrap().0;
+            assert_eq!(parsed.kind(), msg.kind(), "{}", name);
+            assert_eq!(parsed.to_bytes(), bytes, "{}", name);
+            kinds.push(msg.kind());
+        }
+
+        // every message type has a vector
+        for kind in MessageKind::ALL {
+            assert!(kinds.contains(&kind), "no golden vector for {:?}", kind);
+        }
+    }
+
+    #[test]
+    fn test_type_bytes_and_offsets() {
+        for (i, kind) in MessageKind::ALL.into_iter().enumerate() {
+            assert_eq!(kind.type_byte() as usize, i);
+            assert_eq!(MessageKind::from_type_byte(kind.type_byte()), Some(kind));
+        }
+        assert_eq!(MessageKind::from_type_byte(fragment::TAG), None);
+        assert_eq!(MessageKind::from_type_byte(frame::TAG), None);
+
+        let bytes = vector("transport_data").to_bytes();
+        assert_eq!(&bytes[transport::NONCE..][..NONCE_LEN], &1u64.to_be_bytes());
+        assert_eq!(
+            &bytes[transport::CONNECTION_ID..][..CONNECTION_ID_LEN],
+            &[0x11; CONNECTION_ID_LEN]
+        );
+        assert_eq!(bytes[transport::SUBSTREAM_OP], substream_op::DATA);
+        assert_eq!(&bytes[transport::DATA..], b"hello");
+
+        let bytes = vector("transport_stamped_data").to_bytes();
+        assert_eq!(bytes[transport::SUBSTREAM_OP], substream_op::STAMPED_DATA);
+        assert_eq!(
+            &bytes[transport::SENT_AT..][..TIMESTAMP_LEN],
+            &1_700_000_000_000_000u64.to_be_bytes()
+        );
+        assert_eq!(&bytes[transport::STAMPED_DATA..], b"hello");
+
+        let bytes = vector("transport_reset").to_bytes();
+        assert_eq!(bytes[transport::SUBSTREAM_OP], substream_op::RESET);
+        assert_eq!(
+            ResetCode::from_u8(bytes[transport::RESET_CODE]),
+            ResetCode::TooManyStreams
+        );
+        assert_eq!(bytes.len(), transport::RESET_CODE + 1);
+
+        let bytes = vector("connection_request_with_extensions").to_bytes();
+        assert_eq!(
+            bytes[connection::RECIPIENT_FLAG],
+            recipient_flag::RECIPIENT | recipient_flag::EXTENSIONS
+        );
+        assert_eq!(bytes[connection::EXTENSIONS], extension::LATENCY);
+
+        let bytes = vector("connection_request_with_clock").to_bytes();
+        assert_eq!(bytes[connection::EXTENSIONS], extension::CLOCK);
+        let timestamps = connection::EXTENSIONS + 1 + RECIPIENT_LEN;
+        assert_eq!(bytes[timestamps], 1);
+        assert_eq!(
+            &bytes[timestamps + 1..][..TIMESTAMP_LEN],
+            &1_700_000_000_000_000u64.to_be_bytes()
+        );
+
+        let bytes = vector("connection_request_with_user_agent").to_bytes();
+        assert_eq!(bytes[connection::EXTENSIONS], extension::USER_AGENT);
+        let user_agent = connection::EXTENSIONS + 1 + RECIPIENT_LEN;
+        assert_eq!(bytes[user_agent], 11);
+        assert_eq!(
+            &bytes[user_agent + USER_AGENT_LENGTH_LEN..][..11],
+            b"example/1.0"
+        );
+
+        let bytes = vector("compact_transport_data").to_bytes();
+        assert_eq!(&bytes[compact::REFERENCE..][..2], &[0xac, 0x02]);
+        let inner = compact::REFERENCE + 2;
+        assert_eq!(
+            MessageKind::from_type_byte(bytes[inner]),
+            Some(MessageKind::Transport)
+        );
+        assert_eq!(
+            &bytes[inner + transport::NONCE..][..NONCE_LEN],
+            &1u64.to_be_bytes()
+        );
+        assert_eq!(
+            bytes.len(),
+            vector("transport_data").to_bytes().len() + 3 - CONNECTION_ID_LEN
+        );
+
+        let bytes = vector("connection_request_with_target").to_bytes();
+        assert_eq!(
+            bytes[connection::RECIPIENT_FLAG],
+            recipient_flag::RECIPIENT_AND_TARGET
+        );
+        assert_eq!(
+            &bytes[connection::RECIPIENT..][..RECIPIENT_LEN],
+            &recipient().to_bytes()
+        );
+        let bytes = vector("anonymous_connection_request_with_target").to_bytes();
+        assert_eq!(bytes[connection::RECIPIENT_FLAG], recipient_flag::TARGET);
+
+        let bytes = vector("address_update").to_bytes();
+        let Message::AddressUpdate(parsed) = parse_message_data(&bytes).unwrap().0 else {
+            panic!("expected Message::AddressUpdate");
+        };
+        assert!(parsed.verify(&peer_id(7)));
+        assert_eq!(
+            &bytes[address_update::EPOCH..][..EPOCH_LEN],
+            &7u64.to_be_bytes()
+        );
+
+        let bytes = vector("pong").to_bytes();
+        assert_eq!(
+            &bytes[pong::SENT_AT..][..TIMESTAMP_LEN],
+            &1_700_000_000_260_000u64.to_be_bytes()
+        );
+        let bytes = vector("connection_denied").to_bytes();
+        assert_eq!(
+            bytes[connection_denied::REASON],
+            DenialReason::RateLimited.to_u8()
+        );
+        let bytes = vector("connection_close").to_bytes();
+        assert_eq!(
+            bytes[connection_close::REASON],
+            ShutdownReason::ProtocolError.to_u8()
+        );
+        let bytes = vector("close_ack").to_bytes();
+        assert_eq!(
+            &bytes[close_ack::CONNECTION_ID..],
+            &[0x11; CONNECTION_ID_LEN]
+        );
+    }
+}
@@ -0,0 +1,1264 @@
+use libp2p::core::PeerId;
+use parking_lot::RwLock;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::Instant;
+
+use crate::capture::{CaptureFile, CapturedMessage, MessageCapture};
+use crate::error::Error;
+use crate::framing::Framing;
+use crate::message::DenialReason;
+
+/// SMOOTHING_FACTOR is the weight of a new sample in the smoothed estimates,
+/// like the smoothed RTT of TCP.
+const SMOOTHING_FACTOR: f64 = 0.125;
+
+/// REFERENCE_RTT is the smoothed RTT at which the latency of a connection halves its
+/// quality score; round trips through the mixnet usually take a few seconds.
+const REFERENCE_RTT: Duration = Duration::from_secs(2);
+
+/// SEND_STAGE_DURATION_BUCKETS are the upper bounds of the buckets the durations of the
+/// stages of the send path are counted into; they usually take micro- to milliseconds,
+/// but messages may queue for seconds behind bandwidth caps or a slow Nym client.
+pub const SEND_STAGE_DURATION_BUCKETS: [Duration; 8] = [
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+];
+
+/// MAX_USER_AGENTS is how many distinct user agents are counted; connections with peers
+/// announcing others are counted as [`OTHER_USER_AGENT`], so peers can't blow up the
+/// number of metric series.
+pub const MAX_USER_AGENTS: usize = 64;
+
+/// UNKNOWN_USER_AGENT counts the connections with peers that didn't announce a user agent.
+pub const UNKNOWN_USER_AGENT: &str = "unknown";
+
+/// OTHER_USER_AGENT counts the connections with peers announcing a user agent beyond the
+/// first MAX_USER_AGENTS.
+pub const OTHER_USER_AGENT: &str = "other";
+
+/// HANDSHAKE_DURATION_BUCKETS are the upper bounds of the buckets handshake durations are
+/// counted into; a handshake takes a round trip through the mixnet, so usually seconds.
+pub const HANDSHAKE_DURATION_BUCKETS: [Duration; 8] = [
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+    Duration::from_secs(30),
+    Duration::from_secs(60),
+];
+
+/// TransportStats is a handle to statistics collected by a NymTransport.
+/// It can be cloned and kept around after the transport is moved into a swarm.
+#[derive(Clone, Debug, Default)]
+pub struct TransportStats {
+    latency: Arc<RwLock<HashMap<PeerId, LatencyStats>>>,
+
+    /// inbound filter tag -> number of messages tagged with it
+    filter_tags: Arc<RwLock<HashMap<&'static str, u64>>>,
+    filter_drops: Arc<AtomicU64>,
+
+    /// result of the latest reachability probes of our Nym address, if any
+    reachability: Arc<RwLock<Option<Reachability>>>,
+
+    /// peer -> protocol -> traffic on substreams of that protocol
+    protocol_traffic: Arc<RwLock<HashMap<PeerId, HashMap<String, ProtocolTraffic>>>>,
+
+    /// peer -> quality of its most recently rated connection
+    quality: Arc<RwLock<HashMap<PeerId, ConnectionQuality>>>,
+
+    upgrade_timeouts: Arc<AtomicU64>,
+    substream_resets: Arc<AtomicU64>,
+
+    /// peer -> offset of its clock estimated in the handshake of its latest connection
+    clock_offsets: Arc<RwLock<HashMap<PeerId, i64>>>,
+    clock_skews: Arc<AtomicU64>,
+
+    /// remote user agent -> number of connections established with peers announcing it
+    user_agents: Arc<RwLock<HashMap<String, u64>>>,
+
+    /// how long handshakes took, by outcome
+    handshakes: Arc<RwLock<HandshakeStats>>,
+
+    /// recent messages of each connection, while capturing them is enabled
+    message_capture: MessageCapture,
+
+    /// what became of the messages written to the mixnet
+    gateway_outcomes: GatewayOutcomes,
+
+    /// partially received messages that were dropped before they were complete
+    reassembly_evictions: ReassemblyEvictions,
+
+    /// inbound messages of a type this version doesn't know
+    unknown_message_types: UnknownMessageTypes,
+
+    /// how long outbound messages took at each stage of the send path
+    send_path_timings: SendPathTimings,
+
+    /// Nym messages read from the mixnet in each framing
+    inbound_framings: InboundFramings,
+
+    /// messages and bytes written to and read from the mixnet, and reconnects to it
+    mixnet_traffic: MixnetTraffic,
+
+    /// connections the transport holds, including those not yet upgraded
+    active_connections: Arc<AtomicU64>,
+    dials_succeeded: Arc<AtomicU64>,
+    dials_failed: Arc<AtomicU64>,
+
+    /// dials waiting for a dial slot
+    queued_dials: Arc<AtomicUsize>,
+}
+
+impl TransportStats {
+    /// latency returns the latency estimates for the given peer, if it's been probed.
+    pub fn latency(&self, peer_id: &PeerId) -> Option<LatencyStats> {
+        self.latency.read().get(peer_id).cloned()
+    }
+
+    /// all_latency returns the latency estimates for every peer that's been probed.
+    pub fn all_latency(&self) -> HashMap<PeerId, LatencyStats> {
+        self.latency.read().clone()
+    }
+
+    /// quality returns the quality of the connection to the given peer, if it's been rated.
+    pub fn quality(&self, peer_id: &PeerId) -> Option<ConnectionQuality> {
+        self.quality.read().get(peer_id).cloned()
+    }
+
+    /// all_quality returns the quality of the connection to every peer that's been rated.
+    pub fn all_quality(&self) -> HashMap<PeerId, ConnectionQuality> {
+        self.quality.read().clone()
+    }
+
+    /// filter_tags returns how many inbound messages the inbound filter tagged with each tag.
+    pub fn filter_tags(&self) -> HashMap<&'static str, u64> {
+        self.filter_tags.read().clone()
+    }
+
+    /// filter_drops returns how many inbound messages the inbound filter dropped.
+    pub fn filter_drops(&self) -> u64 {
+        self.filter_drops.load(Ordering::Relaxed)
+    }
+
+    /// set_message_capture keeps the latest `capacity` messages written to or received
+    /// from each connection from now on, to inspect recent traffic when a bug is reported
+    /// by a live node, or stops capturing them and discards those kept if None. Messages
+    /// are kept while their connection is open. It's disabled by default.
+    pub fn set_message_capture(&self, capacity: Option<usize>) {
+        self.message_capture.set_capacity(capacity);
+    }
+
+    /// captured_messages returns the messages kept of the connections to the given peer,
+    /// oldest first.
+    pub fn captured_messages(&self, peer_id: &PeerId) -> Vec<CapturedMessage> {
+        self.message_capture.captured(peer_id)
+    }
+
+    /// set_capture_file writes the messages written to or received from each connection
+    /// to the capture file from now on, for offline analysis, or stops writing them if
+    /// None. Read it back with [`CaptureReader`](crate::capture::CaptureReader). Unlike the
+    /// message journal, it records the remote peer of each message, and only messages of
+    /// connections. It's independent of [`TransportStats::set_message_capture`].
+    pub fn set_capture_file(&self, file: Option<CaptureFile>) {
+        self.message_capture.set_file(file);
+    }
+
+    /// with_message_capture uses the given capture, which sees the messages of the mixnet
+    /// task.
+    pub(crate) fn with_message_capture(mut self, message_capture: MessageCapture) -> Self {
+        self.message_capture = message_capture;
+        self
+    }
+
+    pub(crate) fn message_capture(&self) -> &MessageCapture {
+        &self.message_capture
+    }
+
+    /// gateway returns how many messages written to the mixnet were handed to our gateway
+    /// or lost before reaching it. Only backends whose Nym client reports it can tell
+    /// whether a message reached the gateway; with others, only messages the client
+    /// refused are counted, as lost.
+    pub fn gateway(&self) -> GatewayStats {
+        self.gateway_outcomes.get()
+    }
+
+    /// with_gateway_outcomes uses the given counters, which the mixnet task updates.
+    pub(crate) fn with_gateway_outcomes(mut self, gateway_outcomes: GatewayOutcomes) -> Self {
+        self.gateway_outcomes = gateway_outcomes;
+        self
+    }
+
+    /// reassembly returns how many partially received fragmented messages were dropped
+    /// before their remaining fragments arrived, see `NymTransport::with_reassembly_limits`.
+    pub fn reassembly(&self) -> ReassemblyStats {
+        self.reassembly_evictions.get()
+    }
+
+    /// with_reassembly_evictions uses the given counters, which the mixnet task updates.
+    pub(crate) fn with_reassembly_evictions(
+        mut self,
+        reassembly_evictions: ReassemblyEvictions,
+    ) -> Self {
+        self.reassembly_evictions = reassembly_evictions;
+        self
+    }
+
+    /// unknown_message_types returns how many inbound messages, or substream messages on a
+    /// connection, were of a type this version doesn't know, eg. because the peer runs a
+    /// newer one. They're skipped unless the transport is strict about message types.
+    pub fn unknown_message_types(&self) -> u64 {
+        self.unknown_message_types.get()
+    }
+
+    /// with_unknown_message_types uses the given counter, which the mixnet task updates.
+    pub(crate) fn with_unknown_message_types(
+        mut self,
+        unknown_message_types: UnknownMessageTypes,
+    ) -> Self {
+        self.unknown_message_types = unknown_message_types;
+        self
+    }
+
+    pub(crate) fn record_unknown_message_type(&self) {
+        self.unknown_message_types.record();
+    }
+
+    /// send_path returns how long outbound messages took at each stage of the send path,
+    /// from being queued to being handed to the Nym client, to tell whether latency comes
+    /// from the transport's queues or from the mixnet itself.
+    pub fn send_path(&self) -> SendPathStats {
+        self.send_path_timings.get()
+    }
+
+    /// with_send_path_timings uses the given timings, which the mixnet task updates.
+    pub(crate) fn with_send_path_timings(mut self, send_path_timings: SendPathTimings) -> Self {
+        self.send_path_timings = send_path_timings;
+        self
+    }
+
+    /// inbound_frames returns how many messages were read from the mixnet in the given
+    /// framing. Once no peer writes v1 anymore, a later version can stop decoding it.
+    pub fn inbound_frames(&self, framing: Framing) -> u64 {
+        self.inbound_framings.get(framing)
+    }
+
+    /// with_inbound_framings uses the given counters, which the mixnet task updates.
+    pub(crate) fn with_inbound_framings(mut self, inbound_framings: InboundFramings) -> Self {
+        self.inbound_framings = inbound_framings;
+        self
+    }
+
+    /// traffic returns how many messages and bytes were written to and read from the
+    /// mixnet, and how often the transport reconnected to it.
+    pub fn traffic(&self) -> TrafficStats {
+        self.mixnet_traffic.get()
+    }
+
+    /// with_mixnet_traffic uses the given counters, which the mixnet task updates.
+    pub(crate) fn with_mixnet_traffic(mut self, mixnet_traffic: MixnetTraffic) -> Self {
+        self.mixnet_traffic = mixnet_traffic;
+        self
+    }
+
+    /// active_connections returns how many connections the transport holds, including
+    /// those still being upgraded.
+    pub fn active_connections(&self) -> u64 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_active_connections(&self, connections: usize) {
+        self.active_connections
+            .store(connections as u64, Ordering::Relaxed);
+    }
+
+    /// dials returns how many of our dials were established, and how many failed.
+    pub fn dials(&self) -> DialStats {
+        DialStats {
+            succeeded: self.dials_succeeded.load(Ordering::Relaxed),
+            failed: self.dials_failed.load(Ordering::Relaxed),
+        }
+    }
+
+    /// queued_dials returns how many dials are waiting for others to finish, see
+    /// [`NymTransport::with_max_concurrent_dials`](crate::transport::NymTransport::with_max_concurrent_dials).
+    pub fn queued_dials(&self) -> usize {
+        self.queued_dials.load(Ordering::SeqCst)
+    }
+
+    /// queued_dials_counter returns the count of dials waiting for a dial slot, which the
+    /// transport's dial slots update.
+    pub(crate) fn queued_dials_counter(&self) -> Arc<AtomicUsize> {
+        self.queued_dials.clone()
+    }
+
+    pub(crate) fn record_dial(&self, succeeded: bool) {
+        match succeeded {
+            true => &self.dials_succeeded,
+            false => &self.dials_failed,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// upgrade_timeouts returns how many connections were dropped for not finishing their
+    /// upgrade in time.
+    pub fn upgrade_timeouts(&self) -> u64 {
+        self.upgrade_timeouts.load(Ordering::Relaxed)
+    }
+
+    /// substream_resets returns how many substreams opened by remote peers were reset for
+    /// exceeding the substream limits.
+    pub fn substream_resets(&self) -> u64 {
+        self.substream_resets.load(Ordering::Relaxed)
+    }
+
+    /// clock_offset returns how far the peer's clock is off from ours, in microseconds,
+    /// as estimated in the handshake of its latest connection; positive if it's ahead.
+    /// Only peers that enabled clock skew detection too are estimated.
+    pub fn clock_offset(&self, peer_id: &PeerId) -> Option<i64> {
+        self.clock_offsets.read().get(peer_id).copied()
+    }
+
+    /// clock_skews returns how many handshakes found the peer's clock off by more than
+    /// the threshold.
+    pub fn clock_skews(&self) -> u64 {
+        self.clock_skews.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_clock_offset(&self, peer_id: PeerId, offset_micros: i64) {
+        self.clock_offsets.write().insert(peer_id, offset_micros);
+    }
+
+    pub(crate) fn record_clock_skew(&self) {
+        self.clock_skews.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// user_agents returns how many connections were established with peers running each
+    /// implementation, by the user agent they announced in the handshake, to tell which
+    /// implementations interop problems come from.
+    pub fn user_agents(&self) -> HashMap<String, u64> {
+        self.user_agents.read().clone()
+    }
+
+    /// record_user_agent counts a connection with a peer announcing the given user agent.
+    pub(crate) fn record_user_agent(&self, user_agent: Option<&str>) {
+        let mut user_agents = self.user_agents.write();
+        let user_agent = match user_agent {
+            None => UNKNOWN_USER_AGENT,
+            Some(user_agent)
+                if user_agents.contains_key(user_agent) || user_agents.len() < MAX_USER_AGENTS =>
+            {
+                user_agent
+            }
+            Some(_) => OTHER_USER_AGENT,
+        };
+        *user_agents.entry(user_agent.to_string()).or_default() += 1;
+    }
+
+    /// handshakes returns how many connection handshakes, inbound and outbound, were
+    /// established or failed at each stage, and how long they took.
+    pub fn handshakes(&self) -> HandshakeStats {
+        self.handshakes.read().clone()
+    }
+
+    /// record_handshake counts a handshake that started at `started_at`, and failed at
+    /// the given stage if it did.
+    pub(crate) fn record_handshake(
+        &self,
+        failure: Option<HandshakeFailure>,
+        started_at: SystemTime,
+    ) {
+        let duration = started_at.elapsed().unwrap_or_default();
+        let mut handshakes = self.handshakes.write();
+        match failure {
+            Some(failure) => handshakes.failed.entry(failure).or_default(),
+            None => &mut handshakes.established,
+        }
+        .record(duration);
+    }
+
+    /// reachability returns whether our Nym address was reachable by the latest
+    /// reachability probe, if any were sent.
+    pub fn reachability(&self) -> Option<Reachability> {
+        self.reachability.read().clone()
+    }
+
+    /// record_reachability records the result of a reachability probe, and returns true
+    /// if it changed whether we're reachable.
+    pub(crate) fn record_reachability(&self, reachable: bool) -> bool {
+        let now = SystemTime::now();
+        let mut reachability = self.reachability.write();
+        let changed = reachability.as_ref().map(|r| r.reachable) != Some(reachable);
+        let previous = reachability.take();
+        *reachability = Some(Reachability {
+            reachable,
+            last_checked: now,
+            last_reachable: if reachable {
+                Some(now)
+            } else {
+                previous.as_ref().and_then(|r| r.last_reachable)
+            },
+            consecutive_failures: match (reachable, previous) {
+                (true, _) => 0,
+                (false, Some(previous)) => previous.consecutive_failures + 1,
+                (false, None) => 1,
+            },
+        });
+        changed
+    }
+
+    /// protocol_traffic returns the traffic with the given peer broken down by the libp2p
+    /// protocol negotiated on each substream, eg. gossipsub vs kad vs ping.
+    /// Traffic on all connections with the peer is summed up.
+    pub fn protocol_traffic(&self, peer_id: &PeerId) -> HashMap<String, ProtocolTraffic> {
+        self.protocol_traffic
+            .read()
+            .get(peer_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// all_protocol_traffic returns the traffic by protocol for every peer.
+    pub fn all_protocol_traffic(&self) -> HashMap<PeerId, HashMap<String, ProtocolTraffic>> {
+        self.protocol_traffic.read().clone()
+    }
+
+    pub(crate) fn record_protocol_traffic(
+        &self,
+        peer_id: PeerId,
+        protocol: &str,
+        traffic: &ProtocolTraffic,
+    ) {
+        let mut protocol_traffic = self.protocol_traffic.write();
+        let peer_traffic = protocol_traffic.entry(peer_id).or_default();
+        match peer_traffic.get_mut(protocol) {
+            Some(total) => total.add(traffic),
+            None => {
+                peer_traffic.insert(protocol.to_string(), traffic.clone());
+            }
+        }
+    }
+
+    pub(crate) fn record_filter_tag(&self, tag: &'static str) {
+        *self.filter_tags.write().entry(tag).or_default() += 1;
+    }
+
+    pub(crate) fn record_filter_drop(&self) {
+        self.filter_drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_upgrade_timeout(&self) {
+        self.upgrade_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_substream_reset(&self) {
+        self.substream_resets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_quality(&self, peer_id: PeerId, quality: ConnectionQuality) {
+        self.quality.write().insert(peer_id, quality);
+    }
+
+    pub(crate) fn record_latency(&self, peer_id: PeerId, sample: LatencySample) {
+        self.latency
+            .write()
+            .entry(peer_id)
+            .or_insert_with(|| LatencyStats::new(&sample))
+            .update(&sample);
+    }
+}
+
+/// GatewayStats counts what became of the messages written to the mixnet, see
+/// [`TransportStats::gateway`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct GatewayStats {
+    pub handed_to_gateway: u64,
+    /// messages lost before reaching the gateway, including those that were sent again
+    pub lost_before_gateway: u64,
+    /// messages sent again after being lost before the gateway
+    pub retransmitted: u64,
+}
+
+/// GatewayOutcomes are the counters behind GatewayStats, shared by the mixnet task and
+/// the transport's stats.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct GatewayOutcomes {
+    handed_to_gateway: Arc<AtomicU64>,
+    lost_before_gateway: Arc<AtomicU64>,
+    retransmitted: Arc<AtomicU64>,
+}
+
+impl GatewayOutcomes {
+    pub(crate) fn record_handed_to_gateway(&self) {
+        self.handed_to_gateway.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_lost_before_gateway(&self) {
+        self.lost_before_gateway.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_retransmit(&self) {
+        self.retransmitted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> GatewayStats {
+        GatewayStats {
+            handed_to_gateway: self.handed_to_gateway.load(Ordering::Relaxed),
+            lost_before_gateway: self.lost_before_gateway.load(Ordering::Relaxed),
+            retransmitted: self.retransmitted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// ReassemblyStats counts the partially received messages that were dropped before their
+/// remaining fragments arrived, see [`TransportStats::reassembly`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ReassemblyStats {
+    /// messages whose fragments didn't all arrive before the reassembly timeout
+    pub timed_out: u64,
+    /// messages evicted to make room for newer ones, the least recently added to first
+    pub evicted: u64,
+}
+
+/// ReassemblyEvictions are the counters behind ReassemblyStats, shared by the mixnet task
+/// and the transport's stats.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ReassemblyEvictions {
+    timed_out: Arc<AtomicU64>,
+    evicted: Arc<AtomicU64>,
+}
+
+impl ReassemblyEvictions {
+    pub(crate) fn record_timed_out(&self) {
+        self.timed_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_evicted(&self) {
+        self.evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> ReassemblyStats {
+        ReassemblyStats {
+            timed_out: self.timed_out.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// UnknownMessageTypes counts the inbound messages of unknown types, shared by the mixnet
+/// task, the connections and the transport's stats.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct UnknownMessageTypes(Arc<AtomicU64>);
+
+impl UnknownMessageTypes {
+    pub(crate) fn record(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// InboundFramings counts the Nym messages read from the mixnet in each framing, shared by
+/// the mixnet task and the transport's stats.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct InboundFramings(Arc<[AtomicU64; 2]>);
+
+impl InboundFramings {
+    fn counter(&self, framing: Framing) -> &AtomicU64 {
+        match framing {
+            Framing::V1 => &self.0[0],
+            Framing::V2 => &self.0[1],
+        }
+    }
+
+    pub(crate) fn record(&self, framing: Framing) {
+        self.counter(framing).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self, framing: Framing) -> u64 {
+        self.counter(framing).load(Ordering::Relaxed)
+    }
+}
+
+/// TrafficStats counts the traffic between the transport and the mixnet, see
+/// [`TransportStats::traffic`]. Messages are counted whole, before they're fragmented and
+/// after they're reassembled, and so are their bytes, without framing.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TrafficStats {
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    /// how often a backend reconnected to the mixnet after it was disconnected
+    pub reconnects: u64,
+}
+
+/// MixnetTraffic are the counters behind TrafficStats, shared by the mixnet task and the
+/// transport's stats.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MixnetTraffic {
+    messages_sent: Arc<AtomicU64>,
+    bytes_sent: Arc<AtomicU64>,
+    messages_received: Arc<AtomicU64>,
+    bytes_received: Arc<AtomicU64>,
+    reconnects: Arc<AtomicU64>,
+}
+
+impl MixnetTraffic {
+    pub(crate) fn record_sent(&self, len: usize) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_received(&self, len: usize) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> TrafficStats {
+        TrafficStats {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// DialStats counts the outcomes of our dials, see [`TransportStats::dials`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DialStats {
+    pub succeeded: u64,
+    pub failed: u64,
+}
+
+/// ConnectionStats is the traffic of a connection over the last interval of
+/// [connection stats](crate::transport::NymTransport::with_connection_stats), see
+/// [`ConnectionEvent::Stats`](crate::events::ConnectionEvent::Stats). Messages and bytes
+/// are those of substream data.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConnectionStats {
+    /// how long the interval was; the first one starts when the connection is established
+    pub interval: Duration,
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub bytes_received: u64,
+    /// messages received that the remote peer had sent before
+    pub retransmits: u64,
+    /// smoothed round-trip time of the latency probes at the end of the interval, if any
+    /// were answered
+    pub smoothed_rtt: Option<Duration>,
+}
+
+/// ConnectionTraffic counts the substream data of a connection since it was last taken,
+/// shared by the connection, its substreams and the transport.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ConnectionTraffic {
+    messages_sent: Arc<AtomicU64>,
+    bytes_sent: Arc<AtomicU64>,
+    messages_received: Arc<AtomicU64>,
+    bytes_received: Arc<AtomicU64>,
+}
+
+impl ConnectionTraffic {
+    pub(crate) fn record_sent(&self, len: usize) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_received(&self, len: usize) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// take returns the traffic since it was last taken, with only the counters set.
+    pub(crate) fn take(&self) -> ConnectionStats {
+        ConnectionStats {
+            messages_sent: self.messages_sent.swap(0, Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.swap(0, Ordering::Relaxed),
+            messages_received: self.messages_received.swap(0, Ordering::Relaxed),
+            bytes_received: self.bytes_received.swap(0, Ordering::Relaxed),
+            ..Default::default()
+        }
+    }
+}
+
+/// SendPathStats breaks down how long outbound messages took to go through the send path,
+/// see [`TransportStats::send_path`]. Broadcasts and messages sent again after being lost
+/// aren't counted.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SendPathStats {
+    /// from being queued until the mixnet task took them up, which includes waiting
+    /// behind messages of higher priority and for the outbound bandwidth cap
+    pub queued: DurationHistogram,
+    /// serializing and fragmenting them, including waiting for the blocking thread pool
+    /// if encoding was offloaded
+    pub encoded: DurationHistogram,
+    /// handing them to the Nym client, eg. writing them to its websocket
+    pub written: DurationHistogram,
+    /// the whole send path, from being queued until written
+    pub total: DurationHistogram,
+}
+
+/// SendPathTimings are the histograms behind SendPathStats, shared by the mixnet task and
+/// the transport's stats.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SendPathTimings(Arc<RwLock<SendPathStats>>);
+
+impl SendPathTimings {
+    /// record counts a message that was queued at `enqueued_at`, taken up by the mixnet
+    /// task at `dequeued_at`, done encoding at `encoded_at` and written at `written_at`.
+    pub(crate) fn record(
+        &self,
+        enqueued_at: Instant,
+        dequeued_at: Instant,
+        encoded_at: Instant,
+        written_at: Instant,
+    ) {
+        let buckets = &SEND_STAGE_DURATION_BUCKETS;
+        let mut stats = self.0.write();
+        stats
+            .queued
+            .record_in(buckets, dequeued_at.saturating_duration_since(enqueued_at));
+        stats
+            .encoded
+            .record_in(buckets, encoded_at.saturating_duration_since(dequeued_at));
+        stats
+            .written
+            .record_in(buckets, written_at.saturating_duration_since(encoded_at));
+        stats
+            .total
+            .record_in(buckets, written_at.saturating_duration_since(enqueued_at));
+    }
+
+    pub(crate) fn get(&self) -> SendPathStats {
+        self.0.read().clone()
+    }
+}
+
+/// HandshakeFailure is the stage at which a connection handshake failed, which tells
+/// network problems (sending, timing out) from problems with the peer.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum HandshakeFailure {
+    /// the handshake couldn't be written to the mixnet, eg. because it's unavailable or
+    /// the circuit breaker is open
+    SendFailed,
+    /// no answer arrived within the handshake timeout
+    TimedOut,
+    /// a signature the peer sent didn't verify
+    InvalidSignature,
+    /// the listener declined the connection: its allow or deny list, inbound filter,
+    /// rate limit or connection limit
+    PolicyRejected,
+    /// the peer runs a protocol version we can't talk to, eg. it declined the connection
+    /// for a reason this version doesn't know about
+    VersionMismatch,
+    /// anything else, eg. a malformed request
+    Other,
+}
+
+impl HandshakeFailure {
+    /// ALL lists every stage.
+    pub const ALL: [HandshakeFailure; 6] = [
+        HandshakeFailure::SendFailed,
+        HandshakeFailure::TimedOut,
+        HandshakeFailure::InvalidSignature,
+        HandshakeFailure::PolicyRejected,
+        HandshakeFailure::VersionMismatch,
+        HandshakeFailure::Other,
+    ];
+
+    /// as_str returns the stage as it's labelled in metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HandshakeFailure::SendFailed => "send_failed",
+            HandshakeFailure::TimedOut => "timed_out",
+            HandshakeFailure::InvalidSignature => "invalid_signature",
+            HandshakeFailure::PolicyRejected => "policy_rejected",
+            HandshakeFailure::VersionMismatch => "version_mismatch",
+            HandshakeFailure::Other => "other",
+        }
+    }
+
+    /// from_error returns the stage a handshake that failed with the error failed at.
+    pub(crate) fn from_error(error: &Error) -> Self {
+        match error {
+            Error::OutboundSendError(_)
+            | Error::MixnetUnavailable
+            | Error::MixnetDisconnected
+            | Error::MixnetOutage
+            | Error::NoGateways
+            | Error::SendDeadlineExceeded => HandshakeFailure::SendFailed,
+            Error::DialTimeout(_) => HandshakeFailure::TimedOut,
+            Error::InvalidAddressUpdateSignature | Error::InvalidAddressRecordSignature => {
+                HandshakeFailure::InvalidSignature
+            }
+            Error::ConnectionDenied(DenialReason::Other) => HandshakeFailure::VersionMismatch,
+            Error::InboundConnectionRejected(_) | Error::ConnectionDenied(_) => {
+                HandshakeFailure::PolicyRejected
+            }
+            _ => HandshakeFailure::Other,
+        }
+    }
+}
+
+/// HandshakeStats are the handshakes of a transport by outcome, see
+/// [`TransportStats::handshakes`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HandshakeStats {
+    pub established: DurationHistogram,
+    pub failed: HashMap<HandshakeFailure, DurationHistogram>,
+}
+
+impl HandshakeStats {
+    /// failures returns how many handshakes failed at the stage.
+    pub fn failures(&self, stage: HandshakeFailure) -> u64 {
+        self.failed
+            .get(&stage)
+            .map(|histogram| histogram.count)
+            .unwrap_or_default()
+    }
+}
+
+/// DurationHistogram counts durations into buckets, like a Prometheus histogram: those of
+/// [`HANDSHAKE_DURATION_BUCKETS`] for handshakes, and of [`SEND_STAGE_DURATION_BUCKETS`]
+/// for the stages of the send path.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct DurationHistogram {
+    /// how many durations were at most each bucket's bound, so the counts add up
+    /// towards the larger buckets
+    pub buckets: [u64; HANDSHAKE_DURATION_BUCKETS.len()],
+    /// how many durations there were, including those above the largest bound
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl DurationHistogram {
+    fn record(&mut self, duration: Duration) {
+        self.record_in(&HANDSHAKE_DURATION_BUCKETS, duration);
+    }
+
+    fn record_in(
+        &mut self,
+        bounds: &[Duration; HANDSHAKE_DURATION_BUCKETS.len()],
+        duration: Duration,
+    ) {
+        for (bound, bucket) in bounds.iter().zip(self.buckets.iter_mut()) {
+            if duration <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += duration;
+    }
+}
+
+/// Reachability is whether our Nym address can be reached through the mixnet, as
+/// confirmed by connected peers sending a message to it on request.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reachability {
+    /// whether the latest probe succeeded
+    pub reachable: bool,
+    pub last_checked: SystemTime,
+    pub last_reachable: Option<SystemTime>,
+    /// number of probes that failed since the last one that succeeded
+    pub consecutive_failures: u32,
+}
+
+/// ProtocolTraffic is the traffic on substreams that negotiated a particular protocol.
+/// Bytes are substream payload bytes, not including the transport's framing.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProtocolTraffic {
+    pub substreams: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl ProtocolTraffic {
+    pub(crate) fn add(&mut self, other: &ProtocolTraffic) {
+        self.substreams += other.substreams;
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+    }
+}
+
+/// ConnectionQuality rates the mixnet path of a connection by its latency, the latency
+/// probes lost on it and the messages the remote peer had to send again, so applications
+/// can prefer better paths, eg. in gossipsub peer scoring.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionQuality {
+    /// from 0 (unusable) to 1 (no latency, loss or retransmits)
+    pub score: f64,
+    /// smoothed round-trip time of the latency probes, if any were answered
+    pub smoothed_rtt: Option<Duration>,
+    pub probes_sent: u64,
+    pub probes_answered: u64,
+    /// messages received on the connection, and of those, the ones the remote peer sent
+    /// more than once
+    pub messages_received: u64,
+    pub retransmits: u64,
+}
+
+impl ConnectionQuality {
+    pub(crate) fn new(
+        smoothed_rtt: Option<Duration>,
+        probes_sent: u64,
+        probes_answered: u64,
+        messages_received: u64,
+        retransmits: u64,
+    ) -> Self {
+        let mut quality = ConnectionQuality {
+            score: 0.0,
+            smoothed_rtt,
+            probes_sent,
+            probes_answered,
+            messages_received,
+            retransmits,
+        };
+        // each factor is between 0 and 1; an unknown RTT doesn't count against the path
+        let latency = smoothed_rtt
+            .map(|rtt| {
+                REFERENCE_RTT.as_secs_f64() / (REFERENCE_RTT.as_secs_f64() + rtt.as_secs_f64())
+            })
+            .unwrap_or(1.0);
+        quality.score = latency * (1.0 - quality.loss()) * (1.0 - quality.retransmit_rate());
+        quality
+    }
+
+    /// loss returns the fraction of latency probes that weren't answered, including any
+    /// still on their way.
+    pub fn loss(&self) -> f64 {
+        if self.probes_sent == 0 {
+            return 0.0;
+        }
+        1.0 - (self.probes_answered as f64 / self.probes_sent as f64).min(1.0)
+    }
+
+    /// retransmit_rate returns the fraction of received messages that were sent more than
+    /// once.
+    pub fn retransmit_rate(&self) -> f64 {
+        if self.messages_received == 0 {
+            return 0.0;
+        }
+        (self.retransmits as f64 / self.messages_received as f64).min(1.0)
+    }
+}
+
+/// LatencyStats holds mixnet delay estimates for a peer, obtained by timestamp-echo probes.
+/// Like NTP, the estimates assume the delay is about the same in both directions; the
+/// mixnet's random per-hop delays make individual samples noisy, so smoothed values are
+/// kept as well as the latest ones.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LatencyStats {
+    /// the latest round-trip time, excluding the time the peer took to respond
+    pub rtt: Duration,
+    pub smoothed_rtt: Duration,
+
+    /// the latest estimated delay from us to the peer
+    pub outbound_delay: Duration,
+    /// the latest estimated delay from the peer to us
+    pub inbound_delay: Duration,
+
+    /// estimated offset of the peer's clock relative to ours, in microseconds;
+    /// positive if the peer's clock is ahead of ours
+    pub clock_offset_micros: i64,
+    pub smoothed_clock_offset_micros: i64,
+
+    /// number of probes this is based on
+    pub samples: u64,
+}
+
+impl LatencyStats {
+    fn new(sample: &LatencySample) -> Self {
+        LatencyStats {
+            rtt: sample.rtt(),
+            smoothed_rtt: sample.rtt(),
+            outbound_delay: sample.outbound_delay(),
+            inbound_delay: sample.inbound_delay(),
+            clock_offset_micros: sample.clock_offset_micros(),
+            smoothed_clock_offset_micros: sample.clock_offset_micros(),
+            samples: 0,
+        }
+    }
+
+    fn update(&mut self, sample: &LatencySample) {
+        let rtt = sample.rtt();
+        let offset = sample.clock_offset_micros();
+        self.rtt = rtt;
+        self.smoothed_rtt =
+            self.smoothed_rtt.mul_f64(1.0 - SMOOTHING_FACTOR) + rtt.mul_f64(SMOOTHING_FACTOR);
+        self.outbound_delay = sample.outbound_delay();
+        self.inbound_delay = sample.inbound_delay();
+        self.clock_offset_micros = offset;
+        self.smoothed_clock_offset_micros = (self.smoothed_clock_offset_micros as f64
+            * (1.0 - SMOOTHING_FACTOR)
+            + offset as f64 * SMOOTHING_FACTOR) as i64;
+        self.samples += 1;
+    }
+}
+
+/// EchoResult is the latency to a Nym address measured by a single echo request, see
+/// [`NymTransport::echo`](crate::transport::NymTransport::echo).
+#[derive(Clone, Debug, PartialEq)]
+pub struct EchoResult {
+    /// the round-trip time, excluding the time the responder took to reply
+    pub rtt: Duration,
+    pub outbound_delay: Duration,
+    pub inbound_delay: Duration,
+    /// estimated offset of the responder's clock relative to ours, in microseconds
+    pub clock_offset_micros: i64,
+}
+
+impl EchoResult {
+    pub(crate) fn new(sample: &LatencySample) -> Self {
+        EchoResult {
+            rtt: sample.rtt(),
+            outbound_delay: sample.outbound_delay(),
+            inbound_delay: sample.inbound_delay(),
+            clock_offset_micros: sample.clock_offset_micros(),
+        }
+    }
+}
+
+/// LatencySample contains the four timestamps of a single ping/pong exchange,
+/// in microseconds since the unix epoch.
+#[derive(Clone, Debug)]
+pub(crate) struct LatencySample {
+    /// when we sent the ping, by our clock
+    pub(crate) ping_sent_at: u64,
+    /// when the peer received the ping, by its clock
+    pub(crate) ping_received_at: u64,
+    /// when the peer sent the pong, by its clock
+    pub(crate) pong_sent_at: u64,
+    /// when we received the pong, by our clock
+    pub(crate) pong_received_at: u64,
+}
+
+impl LatencySample {
+    fn rtt_micros(&self) -> i64 {
+        (self.pong_received_at as i64 - self.ping_sent_at as i64)
+            - (self.pong_sent_at as i64 - self.ping_received_at as i64)
+    }
+
+    fn rtt(&self) -> Duration {
+        Duration::from_micros(self.rtt_micros().max(0) as u64)
+    }
+
+    pub(crate) fn clock_offset_micros(&self) -> i64 {
+        ((self.ping_received_at as i64 - self.ping_sent_at as i64)
+            + (self.pong_sent_at as i64 - self.pong_received_at as i64))
+            / 2
+    }
+
+    fn outbound_delay(&self) -> Duration {
+        let delay =
+            self.ping_received_at as i64 - self.ping_sent_at as i64 - self.clock_offset_micros();
+        Duration::from_micros(delay.max(0) as u64)
+    }
+
+    fn inbound_delay(&self) -> Duration {
+        let delay =
+            self.pong_received_at as i64 - self.pong_sent_at as i64 + self.clock_offset_micros();
+        Duration::from_micros(delay.max(0) as u64)
+    }
+}
+
+/// unix_micros returns the current time in microseconds since the unix epoch.
+pub(crate) fn unix_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_latency_sample_with_clock_offset() {
+        // the peer's clock is 1s ahead; 300ms to the peer, 10ms processing, 500ms back
+        let sample = LatencySample {
+            ping_sent_at: 0,
+            ping_received_at: 1_300_000,
+            pong_sent_at: 1_310_000,
+            pong_received_at: 810_000,
+        };
+        assert_eq!(sample.rtt(), Duration::from_millis(800));
+        // with asymmetric delays the offset estimate is off by half the difference
+        assert_eq!(sample.clock_offset_micros(), 900_000);
+        assert_eq!(sample.outbound_delay(), Duration::from_millis(400));
+        assert_eq!(sample.inbound_delay(), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_transport_stats_record_latency() {
+        let stats = TransportStats::default();
+        let peer_id = PeerId::random();
+        assert!(stats.latency(&peer_id).is_none());
+
+        let sample = LatencySample {
+            ping_sent_at: 0,
+            ping_received_at: 100,
+            pong_sent_at: 100,
+            pong_received_at: 200,
+        };
+        stats.record_latency(peer_id, sample.clone());
+        stats.record_latency(peer_id, sample);
+
+        let latency = stats.latency(&peer_id).unwrap();
+        assert_eq!(latency.samples, 2);
+        assert_eq!(latency.rtt, Duration::from_micros(200));
+        assert_eq!(latency.smoothed_rtt, Duration::from_micros(200));
+        assert_eq!(latency.clock_offset_micros, 0);
+    }
+
+    #[test]
+    fn test_connection_quality_score() {
+        let perfect = ConnectionQuality::new(None, 0, 0, 0, 0);
+        assert_eq!(perfect.score, 1.0);
+
+        let quality = ConnectionQuality::new(Some(REFERENCE_RTT), 4, 3, 10, 1);
+        assert_eq!(quality.loss(), 0.25);
+        assert_eq!(quality.retransmit_rate(), 0.1);
+        assert!((quality.score - 0.5 * 0.75 * 0.9).abs() < 1e-9);
+
+        let lost = ConnectionQuality::new(Some(Duration::from_secs(1)), 2, 0, 0, 0);
+        assert_eq!(lost.score, 0.0);
+    }
+
+    #[test]
+    fn test_send_path_timings() {
+        let timings = SendPathTimings::default();
+        let stats = TransportStats::default().with_send_path_timings(timings.clone());
+        let enqueued_at = Instant::now();
+        timings.record(
+            enqueued_at,
+            enqueued_at + Duration::from_millis(20),
+            enqueued_at + Duration::from_millis(20),
+            enqueued_at + Duration::from_secs(2),
+        );
+
+        let send_path = stats.send_path();
+        assert_eq!(send_path.queued.count, 1);
+        assert_eq!(send_path.queued.sum, Duration::from_millis(20));
+        // 20ms is within the bounds from 50ms up
+        assert_eq!(send_path.queued.buckets, [0, 0, 0, 1, 1, 1, 1, 1]);
+        assert_eq!(send_path.encoded.buckets, [1; 8]);
+        assert_eq!(send_path.written.sum, Duration::from_millis(1980));
+        assert_eq!(send_path.total.buckets, [0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_transport_stats_record_user_agent() {
+        let stats = TransportStats::default();
+        stats.record_user_agent(Some("rust-libp2p-nym/0.1.0"));
+        stats.record_user_agent(Some("rust-libp2p-nym/0.1.0"));
+        stats.record_user_agent(None);
+        let user_agents = stats.user_agents();
+        assert_eq!(user_agents["rust-libp2p-nym/0.1.0"], 2);
+        assert_eq!(user_agents[UNKNOWN_USER_AGENT], 1);
+
+        for i in 0..MAX_USER_AGENTS {
+            stats.record_user_agent(Some(&format!("peer/{}", i)));
+        }
+        let user_agents = stats.user_agents();
+        assert_eq!(user_agents.len(), MAX_USER_AGENTS + 1);
+        assert_eq!(user_agents[OTHER_USER_AGENT], 2);
+        stats.record_user_agent(Some("rust-libp2p-nym/0.1.0"));
+        assert_eq!(stats.user_agents()["rust-libp2p-nym/0.1.0"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_transport_stats_record_handshake() {
+        let stats = TransportStats::default();
+        let now = SystemTime::now();
+        stats.record_handshake(None, now - Duration::from_millis(400));
+        stats.record_handshake(None, now - Duration::from_secs(3));
+        let timeout = tokio::time::timeout(Duration::ZERO, std::future::pending::<()>())
+            .await
+            .unwrap_err();
+        stats.record_handshake(
+            Some(HandshakeFailure::from_error(&Error::DialTimeout(timeout))),
+            now - Duration::from_secs(90),
+        );
+
+        let handshakes = stats.handshakes();
+        assert_eq!(handshakes.established.count, 2);
+        assert_eq!(handshakes.established.buckets, [0, 1, 1, 1, 2, 2, 2, 2]);
+        assert!(handshakes.established.sum >= Duration::from_millis(3400));
+        assert_eq!(handshakes.failures(HandshakeFailure::TimedOut), 1);
+        // it took longer than the largest bucket
+        assert_eq!(
+            handshakes.failed[&HandshakeFailure::TimedOut].buckets,
+            [0; HANDSHAKE_DURATION_BUCKETS.len()]
+        );
+        assert_eq!(handshakes.failures(HandshakeFailure::PolicyRejected), 0);
+
+        for (error, stage) in [
+            (Error::MixnetOutage, HandshakeFailure::SendFailed),
+            (
+                Error::InboundConnectionRejected(DenialReason::RateLimited),
+                HandshakeFailure::PolicyRejected,
+            ),
+            (
+                Error::ConnectionDenied(DenialReason::NotAllowed),
+                HandshakeFailure::PolicyRejected,
+            ),
+            (
+                Error::ConnectionDenied(DenialReason::Other),
+                HandshakeFailure::VersionMismatch,
+            ),
+            (
+                Error::InvalidAddressRecordSignature,
+                HandshakeFailure::InvalidSignature,
+            ),
+            (
+                Error::NoneRecipientInConnectionRequest,
+                HandshakeFailure::Other,
+            ),
+        ] {
+            assert_eq!(HandshakeFailure::from_error(&error), stage, "{error}");
+        }
+    }
+
+    #[test]
+    fn test_transport_stats_record_reachability() {
+        let stats = TransportStats::default();
+        assert!(stats.reachability().is_none());
+
+        assert!(stats.record_reachability(true));
+        assert!(!stats.record_reachability(true));
+        let last_reachable = stats.reachability().unwrap().last_reachable;
+        assert!(last_reachable.is_some());
+
+        assert!(stats.record_reachability(false));
+        assert!(!stats.record_reachability(false));
+        let reachability = stats.reachability().unwrap();
+        assert!(!reachability.reachable);
+        assert_eq!(reachability.consecutive_failures, 2);
+        assert_eq!(reachability.last_reachable, last_reachable);
+    }
+}
@@ -2,57 +2,125 @@ use futures::{
     io::{Error as IoError, ErrorKind},
     AsyncRead, AsyncWrite,
 };
-use nym_sphinx::addressing::clients::Recipient;
+use libp2p::core::PeerId;
 use parking_lot::Mutex;
 use std::{
+    future::Future,
     pin::Pin,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
         Arc,
     },
     task::{Context, Poll},
+    time::Duration,
 };
-use tokio::sync::{
-    mpsc::{UnboundedReceiver, UnboundedSender},
-    oneshot::Receiver,
+use tokio::{
+    sync::{mpsc::UnboundedReceiver, oneshot::Receiver, Semaphore},
+    time::{sleep, Instant, Sleep},
 };
+use tokio_util::sync::PollSemaphore;
 use tracing::debug;
 
+use crate::backend::PacketSize;
+use crate::budget::{BufferKind, MemoryBudget, Reservation};
+use crate::connection::SharedRecipient;
+use crate::error::Error;
 use crate::message::{
     ConnectionId, Message, OutboundMessage, SubstreamId, SubstreamMessage, TransportMessage,
 };
+pub use crate::message::{MessageAge, MessagePriority, ResetCode};
+use crate::mixnet::OutboundSender;
+use crate::protocol::ProtocolTracker;
+use crate::stats::{ConnectionTraffic, TransportStats};
 
 #[derive(Debug)]
 pub struct Substream {
-    remote_recipient: Recipient,
+    remote_recipient: SharedRecipient,
     connection_id: ConnectionId,
     pub(crate) substream_id: SubstreamId,
 
     /// inbound messages; inbound_tx is in the corresponding Connection
-    pub(crate) inbound_rx: UnboundedReceiver<Vec<u8>>,
+    pub(crate) inbound_rx: UnboundedReceiver<(Vec<u8>, Option<MessageAge>, Reservation)>,
 
     /// outbound messages; go directly to the mixnet
-    outbound_tx: UnboundedSender<OutboundMessage>,
+    outbound_tx: OutboundSender,
 
-    /// used to signal when the substream is closed
-    close_rx: Receiver<()>,
+    /// used to signal when the substream is closed, with the code if the remote peer
+    /// reset it
+    close_rx: Receiver<Option<ResetCode>>,
     closed: Mutex<bool>,
+    reset: Mutex<Option<ResetCode>>,
 
     // buffer of data that's been written to the stream,
     // but not yet read by the application.
     unread_data: Mutex<Vec<u8>>,
+    /// counts unread_data towards the memory budget
+    unread_reservation: Mutex<Reservation>,
+
+    /// what written data counts towards until it's written to the mixnet
+    memory_budget: MemoryBudget,
+
+    /// the most data a write sends in one message, if limited
+    max_message_size: Option<usize>,
+
+    /// what a write needs a permit of, held until its data is written to the mixnet, if
+    /// the writes waiting to be written are bounded
+    outbound_capacity: Option<PollSemaphore>,
 
     message_nonce: Arc<AtomicU64>,
+
+    /// whether written data is stamped with its send time, ie. the connection uses the
+    /// latency extension; shared with the connection, which turns it off if the extension
+    /// is downgraded
+    stamp_sent_at: Arc<AtomicBool>,
+
+    /// age of the message the data returned by the last read came from
+    last_read_age: Mutex<Option<MessageAge>>,
+
+    /// priority of the messages written to this substream
+    priority: AtomicU8,
+
+    /// packet size of the messages written to this substream, if not the transport's
+    packet_size: Mutex<Option<PacketSize>>,
+
+    /// how long messages written to this substream may wait to be written to the mixnet
+    send_deadline: Mutex<Option<Duration>>,
+
+    /// set once a message written to this substream missed its send deadline
+    deadline_exceeded: Arc<AtomicBool>,
+
+    /// the libp2p protocol spoken on this substream, for per-protocol stats
+    protocol: Mutex<ProtocolTracker>,
+
+    /// the data written to the connection, for its periodic stats
+    traffic: ConnectionTraffic,
+
+    /// how long a read may wait for data, and the timer of the read that's waiting
+    read_deadline: Mutex<Option<Duration>>,
+    read_timer: Mutex<Option<Pin<Box<Sleep>>>>,
+}
+
+/// ReadDeadlineExt fails reads of a substream that wait too long for data, so that
+/// request-response protocols don't need to wrap every read in `tokio::time::timeout`.
+pub trait ReadDeadlineExt {
+    /// set_read_deadline sets how long reads may wait for data from now on, or None for
+    /// no limit. A read that gets no data within the deadline fails with
+    /// [`ErrorKind::TimedOut`]; the substream stays open, and the next read waits for
+    /// the deadline again.
+    fn set_read_deadline(&self, deadline: Option<Duration>);
+
+    /// read_deadline returns how long reads may wait for data, if limited.
+    fn read_deadline(&self) -> Option<Duration>;
 }
 
 impl Substream {
     pub(crate) fn new(
-        remote_recipient: Recipient,
+        remote_recipient: SharedRecipient,
         connection_id: ConnectionId,
         substream_id: SubstreamId,
-        inbound_rx: UnboundedReceiver<Vec<u8>>,
-        outbound_tx: UnboundedSender<OutboundMessage>,
-        close_rx: Receiver<()>,
+        inbound_rx: UnboundedReceiver<(Vec<u8>, Option<MessageAge>, Reservation)>,
+        outbound_tx: OutboundSender,
+        close_rx: Receiver<Option<ResetCode>>,
         message_nonce: Arc<AtomicU64>,
     ) -> Self {
         Substream {
@@ -63,37 +131,221 @@ impl Substream {
             outbound_tx,
             close_rx,
             closed: Mutex::new(false),
+            reset: Mutex::new(None),
             unread_data: Mutex::new(vec![]),
+            unread_reservation: Mutex::new(Reservation::default()),
+            memory_budget: MemoryBudget::default(),
+            max_message_size: None,
+            outbound_capacity: None,
             message_nonce,
+            stamp_sent_at: Arc::new(AtomicBool::new(false)),
+            last_read_age: Mutex::new(None),
+            priority: AtomicU8::new(MessagePriority::default().to_u8()),
+            packet_size: Mutex::new(None),
+            send_deadline: Mutex::new(None),
+            deadline_exceeded: Arc::new(AtomicBool::new(false)),
+            protocol: Mutex::new(ProtocolTracker::default()),
+            traffic: ConnectionTraffic::default(),
+            read_deadline: Mutex::new(None),
+            read_timer: Mutex::new(None),
         }
     }
 
-    fn check_closed(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Result<(), IoError> {
-        let closed_err = IoError::new(ErrorKind::Other, "stream closed");
+    /// with_stats records the substream's traffic in the given stats, by protocol.
+    pub(crate) fn with_stats(self, stats: TransportStats, peer_id: PeerId) -> Self {
+        let protocol = self.protocol.into_inner().with_stats(stats, peer_id);
+        Substream {
+            protocol: Mutex::new(protocol),
+            ..self
+        }
+    }
+
+    /// with_negotiated_flag sets the given flag once a protocol is negotiated on the
+    /// substream.
+    pub(crate) fn with_negotiated_flag(self, negotiated: Arc<AtomicBool>) -> Self {
+        let protocol = self.protocol.into_inner().with_negotiated_flag(negotiated);
+        Substream {
+            protocol: Mutex::new(protocol),
+            ..self
+        }
+    }
+
+    /// with_sent_at_stamps stamps data written to the substream with its send time, while
+    /// the given flag is set.
+    pub(crate) fn with_sent_at_stamps(mut self, enabled: Arc<AtomicBool>) -> Self {
+        self.stamp_sent_at = enabled;
+        self
+    }
+
+    /// with_memory_budget counts data written to the substream towards the budget until
+    /// it's written to the mixnet, and pauses writes while the budget is under pressure.
+    pub(crate) fn with_memory_budget(mut self, memory_budget: MemoryBudget) -> Self {
+        self.memory_budget = memory_budget;
+        self
+    }
+
+    /// with_max_message_size limits how much data a write sends in one message; writes of
+    /// more only write that much, and the rest has to be written again.
+    pub(crate) fn with_max_message_size(mut self, max_message_size: Option<usize>) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
 
+    /// with_outbound_capacity makes writes wait for a permit of the capacity, if set, while
+    /// as many writes as it has permits haven't been written to the mixnet yet.
+    pub(crate) fn with_outbound_capacity(mut self, capacity: Option<Arc<Semaphore>>) -> Self {
+        self.outbound_capacity = capacity.map(PollSemaphore::new);
+        self
+    }
+
+    /// with_traffic counts the data written to the substream in the connection's traffic.
+    pub(crate) fn with_traffic(mut self, traffic: ConnectionTraffic) -> Self {
+        self.traffic = traffic;
+        self
+    }
+
+    /// last_read_age returns when the message that the data returned by the last read came
+    /// from was received, and sent if the connection uses the latency extension, so stale
+    /// data can be discarded. If the read returned data from several messages, it's the
+    /// age of the newest.
+    pub fn last_read_age(&self) -> Option<MessageAge> {
+        *self.last_read_age.lock()
+    }
+
+    /// set_priority sets the priority of messages written to this substream from now on,
+    /// eg. to let latency-sensitive protocols jump ahead of bulk transfers.
+    pub fn set_priority(&self, priority: MessagePriority) {
+        self.priority.store(priority.to_u8(), Ordering::Relaxed);
+    }
+
+    /// set_packet_size sets the size of the sphinx packets used for messages written to this
+    /// substream from now on, instead of the transport's packet size. See [`PacketSize`]
+    /// for the trade-off.
+    pub fn set_packet_size(&self, packet_size: PacketSize) {
+        *self.packet_size.lock() = Some(packet_size);
+    }
+
+    /// set_send_deadline sets how long messages written to this substream from now on may
+    /// wait to be written to the mixnet, or None for no limit. A message that misses its
+    /// deadline is dropped, along with the rest of the substream's data, since the remote
+    /// peer can't read past it: the substream is closed and writes fail with
+    /// [`Error::SendDeadlineExceeded`] from then on. This suits real-time data, which is
+    /// better not sent at all than sent late.
+    pub fn set_send_deadline(&self, deadline: Option<Duration>) {
+        *self.send_deadline.lock() = deadline;
+    }
+
+    fn check_deadline_exceeded(&self) -> Result<(), IoError> {
+        if self.deadline_exceeded.load(Ordering::SeqCst) {
+            return Err(IoError::new(
+                ErrorKind::TimedOut,
+                Error::SendDeadlineExceeded,
+            ));
+        }
+        Ok(())
+    }
+
+    /// set_protocol tags this substream with the libp2p protocol spoken on it, for the
+    /// per-protocol traffic stats. This is only needed if the protocol wasn't negotiated
+    /// with multistream-select, which the substream detects by itself.
+    pub fn set_protocol(&self, protocol: impl Into<String>) {
+        self.protocol.lock().set_protocol(protocol.into());
+    }
+
+    /// protocol returns the libp2p protocol spoken on this substream, if it's known.
+    pub fn protocol(&self) -> Option<String> {
+        self.protocol.lock().protocol()
+    }
+
+    /// priority returns the priority of messages written to this substream.
+    pub fn priority(&self) -> MessagePriority {
+        MessagePriority::from_u8(self.priority.load(Ordering::Relaxed))
+    }
+
+    fn check_closed(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Result<(), IoError> {
         // close_rx will return an error if the channel is closed (ie. sender was dropped),
         // or if it's empty
         let received_closed = self.close_rx.try_recv();
 
         let mut closed = self.closed.lock();
         if *closed {
-            return Err(closed_err);
+            return Err(closed_err(*self.reset.lock()));
         }
 
-        if received_closed.is_ok() {
+        if let Ok(reset) = received_closed {
             *closed = true;
-            return Err(closed_err);
+            *self.reset.lock() = reset;
+            return Err(closed_err(reset));
         }
 
         Ok(())
     }
 }
 
+impl ReadDeadlineExt for Substream {
+    fn set_read_deadline(&self, deadline: Option<Duration>) {
+        *self.read_deadline.lock() = deadline;
+        // a read that's waiting picks up the new deadline
+        *self.read_timer.lock() = None;
+    }
+
+    fn read_deadline(&self) -> Option<Duration> {
+        *self.read_deadline.lock()
+    }
+}
+
+/// closed_err returns the error reads and writes of a closed substream fail with.
+fn closed_err(reset: Option<ResetCode>) -> IoError {
+    match reset {
+        Some(code) => IoError::new(
+            ErrorKind::ConnectionReset,
+            format!("stream reset by the remote peer: {}", code),
+        ),
+        None => IoError::new(ErrorKind::Other, "stream closed"),
+    }
+}
+
 impl AsyncRead for Substream {
     fn poll_read(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut [u8],
+    ) -> Poll<Result<usize, IoError>> {
+        let res = self.as_mut().poll_read_inner(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.protocol.lock().record_received(&buf[..n]);
+        }
+        if res.is_ready() {
+            *self.read_timer.lock() = None;
+            return res;
+        }
+        self.poll_read_deadline(cx)
+    }
+}
+
+impl Substream {
+    /// poll_read_deadline fails a read that's been waiting for data for longer than the
+    /// read deadline.
+    fn poll_read_deadline(&self, cx: &mut Context<'_>) -> Poll<Result<usize, IoError>> {
+        let Some(deadline) = *self.read_deadline.lock() else {
+            return Poll::Pending;
+        };
+        let mut read_timer = self.read_timer.lock();
+        let timer = read_timer.get_or_insert_with(|| Box::pin(sleep(deadline)));
+        if timer.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        *read_timer = None;
+        Poll::Ready(Err(IoError::new(
+            ErrorKind::TimedOut,
+            Error::ReadDeadlineExceeded,
+        )))
+    }
+
+    fn poll_read_inner(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
     ) -> Poll<Result<usize, IoError>> {
         let closed_result = self.as_mut().check_closed(cx);
         if let Err(e) = closed_result {
@@ -110,18 +362,22 @@ impl AsyncRead for Substream {
             let copy_len = std::cmp::min(unread_len, buf_len);
             buf[..copy_len].copy_from_slice(&unread_data[..copy_len]);
             *unread_data = unread_data[copy_len..].to_vec();
+            self.unread_reservation.lock().resize(unread_data.len());
             copy_len
         } else {
             0
         };
 
-        if let Poll::Ready(Some(data)) = inbound_rx_data {
+        if let Poll::Ready(Some((data, age, mut reservation))) = inbound_rx_data {
+            *self.last_read_age.lock() = age;
             if filled_len == buf.len() {
                 // we've filled the buffer, so we'll have to save the rest for later
                 let mut new = vec![];
                 new.extend(unread_data.drain(..));
                 new.extend(data.iter());
                 *unread_data = new;
+                reservation.resize(unread_data.len());
+                *self.unread_reservation.lock() = reservation;
                 return Poll::Ready(Ok(filled_len));
             }
 
@@ -133,6 +389,8 @@ impl AsyncRead for Substream {
             if remaining_len < data_len {
                 unread_data.extend_from_slice(&data[remaining_len..]);
             }
+            reservation.resize(unread_data.len());
+            *self.unread_reservation.lock() = reservation;
 
             let copied = std::cmp::min(remaining_len, data_len);
             buf[filled_len..filled_len + copied].copy_from_slice(&data[..copied]);
@@ -158,35 +416,75 @@ impl AsyncWrite for Substream {
         if let Err(e) = self.as_mut().check_closed(cx) {
             return Poll::Ready(Err(e));
         }
+        self.check_deadline_exceeded()?;
+        if self.memory_budget.poll_relieved(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let permit = match self.outbound_capacity.as_mut() {
+            Some(capacity) => match capacity.poll_acquire(cx) {
+                Poll::Ready(Some(permit)) => Some(permit),
+                // the transport never closes it, but don't hang forever if it does
+                Poll::Ready(None) => {
+                    return Poll::Ready(Err(IoError::new(
+                        ErrorKind::Other,
+                        "outbound capacity closed",
+                    )))
+                }
+                Poll::Pending => return Poll::Pending,
+            },
+            None => None,
+        };
+        let buf = match self.max_message_size {
+            Some(max_message_size) if buf.len() > max_message_size => &buf[..max_message_size],
+            _ => buf,
+        };
 
         let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
 
-        self.outbound_tx
-            .send(OutboundMessage {
-                recipient: self.remote_recipient,
-                message: Message::TransportMessage(TransportMessage {
-                    nonce,
-                    id: self.connection_id.clone(),
-                    message: SubstreamMessage::new_with_data(
-                        self.substream_id.clone(),
-                        buf.to_vec(),
-                    ),
-                }),
-            })
-            .map_err(|e| {
-                IoError::new(
-                    ErrorKind::Other,
-                    format!("poll_write outbound_tx error: {}", e),
-                )
-            })?;
+        let mut data = SubstreamMessage::new_with_data(self.substream_id.clone(), buf.to_vec());
+        if self.stamp_sent_at.load(Ordering::Relaxed) {
+            data = data.with_sent_at();
+        }
+        let mut message = OutboundMessage::new(
+            Message::TransportMessage(TransportMessage {
+                nonce,
+                id: self.connection_id.clone(),
+                message: data,
+            }),
+            self.remote_recipient.get(),
+        )
+        .with_connection_ref(self.remote_recipient.connection_ref())
+        .with_route(self.remote_recipient.route())
+        .with_priority(self.priority())
+        .with_packet_size(*self.packet_size.lock())
+        .with_reservation(self.memory_budget.reserve(BufferKind::Outbound, buf.len()));
+        if let Some(deadline) = *self.send_deadline.lock() {
+            message = message.with_deadline(
+                Instant::now() + deadline,
+                Some(self.deadline_exceeded.clone()),
+            );
+        }
+        if let Some(permit) = permit {
+            message = message.with_permit(permit);
+        }
+
+        self.outbound_tx.send(message).map_err(|e| {
+            IoError::new(
+                ErrorKind::Other,
+                format!("poll_write outbound_tx error: {}", e),
+            )
+        })?;
 
+        self.protocol.lock().record_sent(buf);
+        self.traffic.record_sent(buf.len());
         Poll::Ready(Ok(buf.len()))
     }
 
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
-        if let Err(e) = self.check_closed(cx) {
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        if let Err(e) = self.as_mut().check_closed(cx) {
             return Poll::Ready(Err(e));
         }
+        self.check_deadline_exceeded()?;
 
         Poll::Ready(Ok(()))
     }
@@ -203,14 +501,20 @@ impl AsyncWrite for Substream {
 
         // send a close message to the mixnet
         self.outbound_tx
-            .send(OutboundMessage {
-                recipient: self.remote_recipient,
-                message: Message::TransportMessage(TransportMessage {
-                    nonce,
-                    id: self.connection_id.clone(),
-                    message: SubstreamMessage::new_close(self.substream_id.clone()),
-                }),
-            })
+            .send(
+                OutboundMessage::new(
+                    Message::TransportMessage(TransportMessage {
+                        nonce,
+                        id: self.connection_id.clone(),
+                        message: SubstreamMessage::new_close(self.substream_id.clone()),
+                    }),
+                    self.remote_recipient.get(),
+                )
+                .with_connection_ref(self.remote_recipient.connection_ref())
+                .with_route(self.remote_recipient.route())
+                .with_priority(self.priority())
+                .with_packet_size(*self.packet_size.lock()),
+            )
             .map_err(|e| {
                 IoError::new(
                     ErrorKind::Other,
@@ -224,20 +528,27 @@ impl AsyncWrite for Substream {
 
 #[cfg(test)]
 mod test {
-    use futures::{AsyncReadExt, AsyncWriteExt};
+    use futures::{io::ErrorKind, AsyncReadExt, AsyncWriteExt};
     use nym_sphinx::addressing::clients::Recipient;
     use std::sync::atomic::AtomicU64;
     use std::sync::Arc;
+    use std::time::Duration;
     use testcontainers::clients;
-
-    use super::Substream;
+    use tokio::sync::Semaphore;
+    use tokio::time::Instant;
+
+    use super::{ReadDeadlineExt, Substream};
+    use crate::backend::mock::random_recipient;
+    use crate::backend::PacketSize;
+    use crate::budget::Reservation;
+    use crate::connection::SharedRecipient;
     use crate::message::{ConnectionId, Message, SubstreamId, SubstreamMessage, TransportMessage};
-    use crate::mixnet::initialize_mixnet;
+    use crate::mixnet::{initialize_mixnet, outbound_channel};
     use crate::test_utils::create_nym_client;
 
     #[tokio::test]
     async fn test_substream_poll_read_unread_data() {
-        let (outbound_tx, _) = tokio::sync::mpsc::unbounded_channel();
+        let (outbound_tx, _) = outbound_channel();
         let connection_id = ConnectionId::generate();
         let substream_id = SubstreamId::generate();
 
@@ -245,7 +556,7 @@ mod test {
         let (_, close_rx) = tokio::sync::oneshot::channel();
 
         let mut substream = Substream::new(
-            Recipient::try_from_base58_string("D1rrpsysCGCYXy9saP8y3kmNpGtJZUXN9SvFoUcqAsM9.9Ssso1ea5NfkbMASdiseDSjTN1fSWda5SgEVjdSN4CvV@GJqd3ZxpXWSNxTfx7B1pPtswpetH4LnJdFeLeuY5KUuN").unwrap(),
+            SharedRecipient::new(Recipient::try_from_base58_string("D1rrpsysCGCYXy9saP8y3kmNpGtJZUXN9SvFoUcqAsM9.9Ssso1ea5NfkbMASdiseDSjTN1fSWda5SgEVjdSN4CvV@GJqd3ZxpXWSNxTfx7B1pPtswpetH4LnJdFeLeuY5KUuN").unwrap()),
             connection_id,
             substream_id,
             inbound_rx,
@@ -256,7 +567,9 @@ mod test {
 
         // test writing and reading w/ same length data
         let data = b"hello".to_vec();
-        inbound_tx.send(data.clone()).unwrap();
+        inbound_tx
+            .send((data.clone(), None, Reservation::default()))
+            .unwrap();
         let mut buf = [0u8; 5];
         let read_len = substream.read(&mut buf).await.unwrap();
         assert_eq!(read_len, data.len());
@@ -264,7 +577,9 @@ mod test {
 
         // test writing data longer than read buffer
         let data = b"nootwashere".to_vec();
-        inbound_tx.send(data.clone()).unwrap();
+        inbound_tx
+            .send((data.clone(), None, Reservation::default()))
+            .unwrap();
 
         let mut buf = [0u8; 4];
         let read_len = substream.read(&mut buf).await.unwrap();
@@ -278,7 +593,9 @@ mod test {
 
         // test read buffer larger than written data
         let data = b"nootwashere".to_vec();
-        inbound_tx.send(data.clone()).unwrap();
+        inbound_tx
+            .send((data.clone(), None, Reservation::default()))
+            .unwrap();
         let mut buf = [0u8; 16];
         let read_len = substream.read(&mut buf).await.unwrap();
         assert_eq!(read_len, data.len());
@@ -287,7 +604,9 @@ mod test {
 
         // test writing data longer than read buffer multiple times
         let data = b"nootwashere".to_vec();
-        inbound_tx.send(data.clone()).unwrap();
+        inbound_tx
+            .send((data.clone(), None, Reservation::default()))
+            .unwrap();
 
         let mut buf = [0u8; 4];
         let read_len = substream.read(&mut buf).await.unwrap();
@@ -295,7 +614,9 @@ mod test {
         assert_eq!(buf.to_vec(), b"noot".to_vec());
 
         let data = b"asdf".to_vec();
-        inbound_tx.send(data.clone()).unwrap();
+        inbound_tx
+            .send((data.clone(), None, Reservation::default()))
+            .unwrap();
 
         let mut buf = [0u8; 4];
         let read_len = substream.read(&mut buf).await.unwrap();
@@ -324,7 +645,7 @@ mod test {
         let (_, close_rx) = tokio::sync::oneshot::channel();
 
         let mut substream = Substream::new(
-            self_address,
+            SharedRecipient::new(self_address),
             connection_id,
             substream_id,
             inbound_rx,
@@ -346,6 +667,7 @@ mod test {
                     SubstreamMessage {
                         substream_id: _,
                         message_type: msg,
+                        ..
                     },
             }) => {
                 assert_eq!(nonce, 1);
@@ -353,7 +675,9 @@ mod test {
                     crate::message::SubstreamMessageType::Data(data) => {
                         assert_eq!(data, MSG_INNER);
                         // send message to substream inbound channel
-                        inbound_tx.send(data).unwrap();
+                        inbound_tx
+                            .send((data, None, Reservation::default()))
+                            .unwrap();
                     }
                     _ => panic!("unexpected message type"),
                 }
@@ -383,6 +707,7 @@ mod test {
                     SubstreamMessage {
                         substream_id: _,
                         message_type: msg,
+                        ..
                     },
             }) => match msg {
                 crate::message::SubstreamMessageType::Close => {}
@@ -392,6 +717,77 @@ mod test {
         }
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_substream_read_deadline() {
+        let (outbound_tx, _) = outbound_channel();
+        let (inbound_tx, inbound_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_close_tx, close_rx) = tokio::sync::oneshot::channel();
+        let mut substream = Substream::new(
+            SharedRecipient::new(random_recipient()),
+            ConnectionId::generate(),
+            SubstreamId::generate(),
+            inbound_rx,
+            outbound_tx,
+            close_rx,
+            Arc::new(AtomicU64::new(1)),
+        );
+        assert_eq!(substream.read_deadline(), None);
+        substream.set_read_deadline(Some(Duration::from_secs(5)));
+
+        // data arriving within the deadline is read
+        let sender = inbound_tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(4)).await;
+            sender
+                .send((b"hello".to_vec(), None, Reservation::default()))
+                .unwrap();
+        });
+        let mut buf = [0u8; 5];
+        assert_eq!(substream.read(&mut buf).await.unwrap(), 5);
+
+        // a read getting nothing fails once the deadline passes, but the substream stays
+        // usable
+        let started_at = Instant::now();
+        let e = substream.read(&mut buf).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert!(started_at.elapsed() >= Duration::from_secs(5));
+        inbound_tx
+            .send((b"again".to_vec(), None, Reservation::default()))
+            .unwrap();
+        assert_eq!(substream.read(&mut buf).await.unwrap(), 5);
+        assert_eq!(&buf, b"again");
+    }
+
+    #[tokio::test]
+    async fn test_substream_outbound_capacity() {
+        let (outbound_tx, mut outbound_rx) = outbound_channel();
+        let (_inbound_tx, inbound_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (_close_tx, close_rx) = tokio::sync::oneshot::channel();
+        let capacity = Arc::new(Semaphore::new(2));
+        let mut substream = Substream::new(
+            SharedRecipient::new(random_recipient()),
+            ConnectionId::generate(),
+            SubstreamId::generate(),
+            inbound_rx,
+            outbound_tx,
+            close_rx,
+            Arc::new(AtomicU64::new(1)),
+        )
+        .with_outbound_capacity(Some(capacity.clone()));
+
+        // two writes fit, the third waits until one of them has been written
+        substream.write_all(b"one").await.unwrap();
+        substream.write_all(b"two").await.unwrap();
+        assert!(futures::poll!(substream.write(b"three")).is_pending());
+        drop(outbound_rx.recv().await.unwrap());
+        assert_eq!(substream.write(b"three").await.unwrap(), 5);
+        assert_eq!(capacity.available_permits(), 0);
+
+        // the permits are released once the messages are
+        drop(outbound_rx);
+        assert_eq!(capacity.available_permits(), 2);
+    }
+
     #[tokio::test]
     async fn test_substream_recv_close() {
         let docker_client = clients::Cli::default();
@@ -407,7 +803,7 @@ mod test {
         let (close_tx, close_rx) = tokio::sync::oneshot::channel();
 
         let mut substream = Substream::new(
-            self_address,
+            SharedRecipient::new(self_address),
             connection_id,
             substream_id,
             inbound_rx,
@@ -417,7 +813,7 @@ mod test {
         );
 
         // close substream
-        close_tx.send(()).unwrap();
+        close_tx.send(None).unwrap();
 
         // try to read/write to closed substream; should error
         substream.write_all(MSG_INNER).await.unwrap_err();
@@ -0,0 +1,338 @@
+//! Integration tests running libp2p protocols over two in-process transports on the mock
+//! mixnet, so that regressions in the muxing and substream semantics the protocols rely on
+//! show up as protocol failures here rather than in applications.
+//!
+//! Each test connects two swarms speaking ping, identify, gossipsub and request-response,
+//! and checks the behaviour of one of the protocols.
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, StreamExt};
+use libp2p::core::{
+    identity::Keypair, muxing::StreamMuxerBox, transport::Transport, upgrade::ProtocolName,
+    Multiaddr, PeerId,
+};
+use libp2p::swarm::{keep_alive, NetworkBehaviour, Swarm, SwarmBuilder, SwarmEvent, THandlerErr};
+use libp2p::{gossipsub, identify, ping, request_response};
+use std::io;
+use std::iter;
+use tokio::time::{timeout, Duration};
+
+use crate::backend::MockMixnet;
+use crate::transport::NymTransport;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// the identify protocol version both nodes announce
+const PROTOCOL_VERSION: &str = "/rust-libp2p-nym-test/1.0.0";
+
+/// the most a request or response of the echo protocol may carry
+const MAX_ECHO_LEN: u64 = 1024;
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    keep_alive: keep_alive::Behaviour,
+    ping: ping::Behaviour,
+    identify: identify::Behaviour,
+    gossipsub: gossipsub::Behaviour,
+    echo: request_response::Behaviour<EchoCodec>,
+}
+
+type Event = SwarmEvent<BehaviourEvent, THandlerErr<Behaviour>>;
+
+/// EchoProtocol is a request-response protocol whose responses repeat the request.
+#[derive(Clone)]
+struct EchoProtocol;
+
+impl ProtocolName for EchoProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/echo/1.0.0"
+    }
+}
+
+/// EchoCodec reads a request or response until the substream is closed for writing.
+#[derive(Clone, Default)]
+struct EchoCodec;
+
+#[async_trait]
+impl request_response::Codec for EchoCodec {
+    type Protocol = EchoProtocol;
+    type Request = Vec<u8>;
+    type Response = Vec<u8>;
+
+    async fn read_request<T>(&mut self, _: &EchoProtocol, io: &mut T) -> io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_to_end(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &EchoProtocol, io: &mut T) -> io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_to_end(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &EchoProtocol,
+        io: &mut T,
+        request: Vec<u8>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &EchoProtocol,
+        io: &mut T,
+        response: Vec<u8>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&response).await
+    }
+}
+
+async fn read_to_end<T: AsyncRead + Unpin + Send>(io: &mut T) -> io::Result<Vec<u8>> {
+    let mut buf = vec![];
+    io.take(MAX_ECHO_LEN).read_to_end(&mut buf).await?;
+    Ok(buf)
+}
+
+/// new_swarm returns a swarm on a new transport of the mixnet.
+fn new_swarm(mixnet: &MockMixnet) -> Swarm<Behaviour> {
+    let keypair = Keypair::generate_ed25519();
+    let peer_id = PeerId::from(keypair.public());
+    let transport = NymTransport::new_with_backend(mixnet.new_backend(), keypair.clone())
+        .unwrap()
+        .map(|a, _| (a.0, StreamMuxerBox::new(a.1)))
+        .boxed();
+    let behaviour = Behaviour {
+        keep_alive: keep_alive::Behaviour,
+        ping: ping::Behaviour::new(ping::Config::new().with_interval(Duration::from_secs(1))),
+        identify: identify::Behaviour::new(identify::Config::new(
+            PROTOCOL_VERSION.to_string(),
+            keypair.public(),
+        )),
+        gossipsub: gossipsub::Behaviour::new(
+            gossipsub::MessageAuthenticity::Signed(keypair),
+            gossipsub::Config::default(),
+        )
+        .unwrap(),
+        echo: request_response::Behaviour::new(
+            EchoCodec,
+            iter::once((EchoProtocol, request_response::ProtocolSupport::Full)),
+            request_response::Config::default(),
+        ),
+    };
+    SwarmBuilder::with_tokio_executor(transport, behaviour, peer_id).build()
+}
+
+/// run_until drives both swarms, passing their events to `f` along with the swarm they
+/// came from, until it returns a value.
+async fn run_until<T>(
+    a: &mut Swarm<Behaviour>,
+    b: &mut Swarm<Behaviour>,
+    mut f: impl FnMut(&mut Swarm<Behaviour>, Event) -> Option<T>,
+) -> T {
+    timeout(TIMEOUT, async {
+        loop {
+            let (swarm, event) = tokio::select! {
+                event = a.select_next_some() => (&mut *a, event),
+                event = b.select_next_some() => (&mut *b, event),
+            };
+            if let Some(value) = f(swarm, event) {
+                return value;
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for the swarms")
+}
+
+/// connected_swarms returns two swarms, the first connected to the second.
+async fn connected_swarms() -> (Swarm<Behaviour>, Swarm<Behaviour>) {
+    let mixnet = MockMixnet::new();
+    let mut dialer = new_swarm(&mixnet);
+    let mut listener = new_swarm(&mixnet);
+    let listener_id = *listener.local_peer_id();
+
+    let address: Multiaddr = run_until(&mut dialer, &mut listener, |swarm, event| match event {
+        SwarmEvent::NewListenAddr { address, .. } if *swarm.local_peer_id() == listener_id => {
+            Some(address)
+        }
+        _ => None,
+    })
+    .await;
+    dialer.dial(address).unwrap();
+
+    let mut established = 0;
+    run_until(&mut dialer, &mut listener, |_, event| match event {
+        SwarmEvent::ConnectionEstablished { .. } => {
+            established += 1;
+            (established == 2).then_some(())
+        }
+        SwarmEvent::OutgoingConnectionError { error, .. } => panic!("dial failed: {error}"),
+        _ => None,
+    })
+    .await;
+    (dialer, listener)
+}
+
+#[tokio::test]
+async fn test_swarm_ping() {
+    let (mut dialer, mut listener) = connected_swarms().await;
+    let dialer_id = *dialer.local_peer_id();
+    let listener_id = *listener.local_peer_id();
+
+    // both sides ping each other over the same connection
+    let mut pinged = vec![];
+    run_until(&mut dialer, &mut listener, |swarm, event| {
+        if let SwarmEvent::Behaviour(BehaviourEvent::Ping(ping::Event { peer, result })) = event {
+            match result {
+                Ok(ping::Success::Ping { .. }) => pinged.push((*swarm.local_peer_id(), peer)),
+                Ok(ping::Success::Pong) => {}
+                Err(e) => panic!("ping failed: {e}"),
+            }
+        }
+        (pinged.contains(&(dialer_id, listener_id)) && pinged.contains(&(listener_id, dialer_id)))
+            .then_some(())
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_swarm_identify() {
+    let (mut dialer, mut listener) = connected_swarms().await;
+    let dialer_id = *dialer.local_peer_id();
+    let listener_id = *listener.local_peer_id();
+
+    let mut identified = vec![];
+    run_until(&mut dialer, &mut listener, |swarm, event| {
+        if let SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+            peer_id,
+            info,
+        })) = event
+        {
+            assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+            assert_eq!(PeerId::from(info.public_key), peer_id);
+            // both sides speak every protocol of the behaviour
+            let echo = String::from_utf8(EchoProtocol.protocol_name().to_vec()).unwrap();
+            assert!(info.protocols.contains(&echo));
+            identified.push((*swarm.local_peer_id(), peer_id));
+        }
+        (identified.contains(&(dialer_id, listener_id))
+            && identified.contains(&(listener_id, dialer_id)))
+        .then_some(())
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn test_swarm_gossipsub() {
+    let (mut dialer, mut listener) = connected_swarms().await;
+    let listener_id = *listener.local_peer_id();
+    let topic = gossipsub::IdentTopic::new("test");
+    dialer.behaviour_mut().gossipsub.subscribe(&topic).unwrap();
+    listener
+        .behaviour_mut()
+        .gossipsub
+        .subscribe(&topic)
+        .unwrap();
+
+    // the dialer publishes once it knows the listener is subscribed
+    let dialer_id = *dialer.local_peer_id();
+    let data = run_until(&mut dialer, &mut listener, |swarm, event| match event {
+        SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Subscribed {
+            peer_id,
+            ..
+        })) if peer_id == listener_id => {
+            swarm
+                .behaviour_mut()
+                .gossipsub
+                .publish(topic.clone(), b"hello".to_vec())
+                .unwrap();
+            None
+        }
+        SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message {
+            message,
+            ..
+        })) => {
+            assert_eq!(*swarm.local_peer_id(), listener_id);
+            assert_eq!(message.source, Some(dialer_id));
+            Some(message.data)
+        }
+        _ => None,
+    })
+    .await;
+    assert_eq!(data, b"hello".to_vec());
+}
+
+#[tokio::test]
+async fn test_swarm_request_response() {
+    let (mut dialer, mut listener) = connected_swarms().await;
+    let listener_id = *listener.local_peer_id();
+
+    // several requests in flight at once, each on its own substream
+    let requests: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; 100 * (i as usize + 1)]).collect();
+    let mut pending: Vec<_> = requests
+        .iter()
+        .map(|request| {
+            let id = dialer
+                .behaviour_mut()
+                .echo
+                .send_request(&listener_id, request.clone());
+            (id, request.clone())
+        })
+        .collect();
+
+    run_until(&mut dialer, &mut listener, |swarm, event| {
+        let SwarmEvent::Behaviour(BehaviourEvent::Echo(event)) = event else {
+            return None;
+        };
+        match event {
+            request_response::Event::Message {
+                message:
+                    request_response::Message::Request {
+                        request, channel, ..
+                    },
+                ..
+            } => {
+                swarm
+                    .behaviour_mut()
+                    .echo
+                    .send_response(channel, request)
+                    .unwrap();
+            }
+            request_response::Event::Message {
+                message:
+                    request_response::Message::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            } => {
+                let index = pending
+                    .iter()
+                    .position(|(id, _)| *id == request_id)
+                    .unwrap();
+                let (_, request) = pending.remove(index);
+                assert_eq!(response, request);
+            }
+            request_response::Event::OutboundFailure { error, .. } => {
+                panic!("request failed: {error}")
+            }
+            request_response::Event::InboundFailure { error, .. } => {
+                panic!("request failed: {error}")
+            }
+            request_response::Event::ResponseSent { .. } => {}
+        }
+        pending.is_empty().then_some(())
+    })
+    .await;
+}
@@ -3,6 +3,29 @@
 // This removes the requirement for having to limit test threads
 // or to build/run nym-client ourselves.
 
+use futures::{future::poll_fn, AsyncReadExt, AsyncWriteExt};
+use libp2p::core::{
+    identity::Keypair,
+    transport::{Transport, TransportError, TransportEvent},
+    Multiaddr, PeerId, StreamMuxer,
+};
+use nym_sphinx::addressing::clients::Recipient;
+use parking_lot::Mutex;
+use std::{collections::HashMap, pin::Pin, sync::Arc, task::Poll};
+use testcontainers::{clients::Cli, core::WaitFor, images::generic::GenericImage, Container};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    oneshot, Notify,
+};
+
+use crate::backend::{LinkConditions, MixnetBackend, MockBackend, MockMixnet};
+use crate::connection::Connection;
+use crate::error::Error;
+use crate::substream::Substream;
+use crate::transport::{nym_address_to_multiaddress, NymTransport, Upgrade};
+
+/// the most data a node of a [`TestNetwork`] reads from a substream at once
+const MAX_READ_LEN: usize = 64 * 1024;
 use testcontainers::{clients::Cli, core::WaitFor, images::generic::GenericImage, Container};
 
 /// Create a nym client using the same docker Cli
@@ -20,3 +43,557 @@ pub fn create_nym_client<'a>(
     let nym_uri = format!("ws://0.0.0.0:{nym_port}");
     (nym_container, nym_uri)
 }
+
+// This section runs networks of transports on the mock mixnet, for integration tests
+// with several nodes that don't need a nym-client.
+
+/// NodeBehaviour is what a node of a [`TestNetwork`] does with the data it receives.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum NodeBehaviour {
+    /// keep the data
+    #[default]
+    Sink,
+    /// keep the data and write it back on the substream it arrived on
+    Echo,
+}
+
+/// Delivery is data a node of a [`TestNetwork`] read from a substream.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Delivery {
+    /// the name of the node that opened the connection the data arrived on, or that
+    /// accepted it
+    pub from: String,
+    pub data: Vec<u8>,
+}
+
+struct NodeSpec {
+    name: String,
+    behaviour: NodeBehaviour,
+    deferred: bool,
+}
+
+/// TestNetworkBuilder declares the nodes of a [`TestNetwork`] and the links between them.
+#[derive(Default)]
+pub struct TestNetworkBuilder {
+    mixnet: MockMixnet,
+    nodes: Vec<NodeSpec>,
+    links: Vec<(String, String, LinkConditions)>,
+}
+
+impl TestNetworkBuilder {
+    /// Add a node with the given name and behaviour, started after the nodes added before
+    /// it, and return self.
+    pub fn node(mut self, name: &str, behaviour: NodeBehaviour) -> Self {
+        self.nodes.push(NodeSpec {
+            name: name.to_string(),
+            behaviour,
+            deferred: false,
+        });
+        self
+    }
+
+    /// Add a node that's only started by [`TestNetwork::start_node`], and return self. Its
+    /// Nym client exists from the start, so messages sent to it wait until it's started.
+    pub fn deferred_node(mut self, name: &str, behaviour: NodeBehaviour) -> Self {
+        self.nodes.push(NodeSpec {
+            name: name.to_string(),
+            behaviour,
+            deferred: true,
+        });
+        self
+    }
+
+    /// Make the messages between two nodes arrive under the given conditions, in both
+    /// directions, and return self.
+    pub fn link(mut self, a: &str, b: &str, conditions: LinkConditions) -> Self {
+        self.links.push((a.to_string(), b.to_string(), conditions));
+        self
+    }
+
+    /// build creates the network and starts its nodes, other than deferred ones, in the
+    /// order they were added. It must be called within a tokio runtime, and panics if a
+    /// link names an unknown node.
+    pub fn build(self) -> TestNetwork {
+        let mut network = TestNetwork {
+            mixnet: self.mixnet,
+            nodes: HashMap::new(),
+            names: HashMap::new(),
+            connections: HashMap::new(),
+        };
+        for spec in &self.nodes {
+            let keypair = Keypair::generate_ed25519();
+            let peer_id = PeerId::from(keypair.public());
+            let backend = network.mixnet.new_backend();
+            let address = nym_address_to_multiaddress(backend.self_address())
+                .expect("mock addresses are valid");
+            network.names.insert(peer_id, spec.name.clone());
+            network.nodes.insert(
+                spec.name.clone(),
+                TestNode {
+                    peer_id,
+                    address,
+                    state: NodeState {
+                        behaviour: spec.behaviour,
+                        deliveries: Default::default(),
+                        delivered: Default::default(),
+                    },
+                    unstarted: Some((backend, keypair)),
+                    dials_tx: None,
+                },
+            );
+        }
+        for (a, b, conditions) in &self.links {
+            let a = network.node(a).recipient();
+            let b = network.node(b).recipient();
+            network.mixnet.set_link(&a, &b, *conditions);
+            network.mixnet.set_link(&b, &a, *conditions);
+        }
+        for spec in self.nodes.iter().filter(|spec| !spec.deferred) {
+            network.start_node(&spec.name);
+        }
+        network
+    }
+}
+
+/// TestNetwork is a set of named transports on a mock mixnet, each run in its own task,
+/// which send each other data on substreams and record what they receive. Methods taking
+/// node names panic if there's no such node.
+pub struct TestNetwork {
+    mixnet: MockMixnet,
+    nodes: HashMap<String, TestNode>,
+    names: HashMap<PeerId, String>,
+    /// (dialer, listener) -> channel to open substreams on their connection with
+    connections: HashMap<(String, String), SubstreamOpener>,
+}
+
+/// SubstreamOpener asks the task driving a connection for a new outbound substream.
+type SubstreamOpener = UnboundedSender<oneshot::Sender<Substream>>;
+
+/// DialRequest asks a node's task to dial an address.
+type DialRequest = (Multiaddr, oneshot::Sender<Result<SubstreamOpener, Error>>);
+
+struct TestNode {
+    peer_id: PeerId,
+    address: Multiaddr,
+    state: NodeState,
+    /// the node's Nym client and keypair, until it's started
+    unstarted: Option<(MockBackend, Keypair)>,
+    /// channel to the node's task, once it's started
+    dials_tx: Option<UnboundedSender<DialRequest>>,
+}
+
+impl TestNode {
+    fn recipient(&self) -> Recipient {
+        match &self.unstarted {
+            Some((backend, _)) => backend.self_address(),
+            None => panic!("links must be set up before nodes start"),
+        }
+    }
+}
+
+/// NodeState is what a node's task shares with the network.
+#[derive(Clone)]
+struct NodeState {
+    behaviour: NodeBehaviour,
+    deliveries: Arc<Mutex<Vec<(PeerId, Vec<u8>)>>>,
+    delivered: Arc<Notify>,
+}
+
+impl TestNetwork {
+    pub fn builder() -> TestNetworkBuilder {
+        TestNetworkBuilder::default()
+    }
+
+    /// mixnet returns the mock mixnet the nodes are on, eg. to disconnect one of them.
+    pub fn mixnet(&self) -> &MockMixnet {
+        &self.mixnet
+    }
+
+    pub fn peer_id(&self, name: &str) -> PeerId {
+        self.node(name).peer_id
+    }
+
+    /// address returns the multiaddress the node listens on.
+    pub fn address(&self, name: &str) -> Multiaddr {
+        self.node(name).address.clone()
+    }
+
+    /// start_node starts a deferred node, which then handles the messages that were sent
+    /// to it in the meantime. It panics if the node was already started.
+    pub fn start_node(&mut self, name: &str) {
+        let node = self
+            .nodes
+            .get_mut(name)
+            .unwrap_or_else(|| panic!("unknown node {}", name));
+        let (backend, keypair) = node
+            .unstarted
+            .take()
+            .unwrap_or_else(|| panic!("node {} was already started", name));
+        let transport = NymTransport::new_with_backend(backend, keypair)
+            .expect("transport on the mock mixnet must be created");
+        let (dials_tx, dials_rx) = unbounded_channel();
+        tokio::spawn(run_node(transport, dials_rx, node.state.clone()));
+        node.dials_tx = Some(dials_tx);
+    }
+
+    /// connect dials `to` from `from`, unless `from` already dialed it. `from` must have
+    /// been started.
+    pub async fn connect(&mut self, from: &str, to: &str) -> Result<(), Error> {
+        let key = (from.to_string(), to.to_string());
+        if self.connections.contains_key(&key) {
+            return Ok(());
+        }
+        let address = self.address(to);
+        let dials_tx = self
+            .node(from)
+            .dials_tx
+            .as_ref()
+            .unwrap_or_else(|| panic!("node {} isn't started", from));
+        let (result_tx, result_rx) = oneshot::channel();
+        dials_tx
+            .send((address, result_tx))
+            .expect("node tasks run as long as the network");
+        let opener = result_rx.await??;
+        self.connections.insert(key, opener);
+        Ok(())
+    }
+
+    /// open_substream opens a substream on the connection from `from` to `to`, connecting
+    /// them first if needed.
+    pub async fn open_substream(&mut self, from: &str, to: &str) -> Result<Substream, Error> {
+        self.connect(from, to).await?;
+        let key = (from.to_string(), to.to_string());
+        let (substream_tx, substream_rx) = oneshot::channel();
+        if self.connections[&key].send(substream_tx).is_err() {
+            self.connections.remove(&key);
+            return Err(Error::ConnectionDropped);
+        }
+        Ok(substream_rx.await?)
+    }
+
+    /// send writes the data to `to` on a new substream from `from`.
+    pub async fn send(&mut self, from: &str, to: &str, data: &[u8]) -> Result<(), Error> {
+        let mut substream = self.open_substream(from, to).await?;
+        substream
+            .write_all(data)
+            .await
+            .map_err(Error::SubstreamIoError)
+    }
+
+    /// request writes the data to `to` on a new substream from `from`, and returns what
+    /// it writes back, eg. as an echo node.
+    pub async fn request(&mut self, from: &str, to: &str, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut substream = self.open_substream(from, to).await?;
+        substream
+            .write_all(data)
+            .await
+            .map_err(Error::SubstreamIoError)?;
+        let mut buf = vec![0u8; MAX_READ_LEN];
+        let n = substream
+            .read(&mut buf)
+            .await
+            .map_err(Error::SubstreamIoError)?;
+        buf.truncate(n);
+        Ok(buf)
+    }
+
+    /// deliveries returns the data the node received so far, in the order it was read.
+    pub fn deliveries(&self, name: &str) -> Vec<Delivery> {
+        self.node(name)
+            .state
+            .deliveries
+            .lock()
+            .iter()
+            .map(|(peer_id, data)| Delivery {
+                from: self.names[peer_id].clone(),
+                data: data.clone(),
+            })
+            .collect()
+    }
+
+    /// wait_for_deliveries waits until the node received at least `count` pieces of data,
+    /// and returns them.
+    pub async fn wait_for_deliveries(&self, name: &str, count: usize) -> Vec<Delivery> {
+        let delivered = self.node(name).state.delivered.clone();
+        loop {
+            let notified = delivered.notified();
+            let deliveries = self.deliveries(name);
+            if deliveries.len() >= count {
+                return deliveries;
+            }
+            notified.await;
+        }
+    }
+
+    fn node(&self, name: &str) -> &TestNode {
+        self.nodes
+            .get(name)
+            .unwrap_or_else(|| panic!("unknown node {}", name))
+    }
+}
+
+enum NodeInput {
+    Dial(DialRequest),
+    Event(TransportEvent<Upgrade, Error>),
+}
+
+/// run_node polls the node's transport, dialing what the network asks it to and
+/// accepting every inbound connection, until the network is dropped.
+async fn run_node(
+    mut transport: NymTransport,
+    mut dials_rx: UnboundedReceiver<DialRequest>,
+    state: NodeState,
+) {
+    loop {
+        let input = poll_fn(|cx| {
+            if let Poll::Ready(dial) = dials_rx.poll_recv(cx) {
+                return Poll::Ready(dial.map(NodeInput::Dial));
+            }
+            Pin::new(&mut transport)
+                .poll(cx)
+                .map(|event| Some(NodeInput::Event(event)))
+        })
+        .await;
+        let state = state.clone();
+        match input {
+            Some(NodeInput::Dial((address, result_tx))) => {
+                let dial = transport.dial(address);
+                tokio::spawn(async move {
+                    let res = match dial {
+                        Ok(dial) => dial.await,
+                        Err(TransportError::Other(e)) => Err(e),
+                        Err(TransportError::MultiaddrNotSupported(_)) => {
+                            Err(Error::InvalidProtocolForMultiaddr)
+                        }
+                    };
+                    match res {
+                        Ok((peer_id, conn)) => {
+                            let (opener, opens_rx) = unbounded_channel();
+                            result_tx.send(Ok(opener)).ok();
+                            drive_connection(conn, peer_id, opens_rx, state).await;
+                        }
+                        Err(e) => {
+                            result_tx.send(Err(e)).ok();
+                        }
+                    }
+                });
+            }
+            Some(NodeInput::Event(TransportEvent::Incoming { upgrade, .. })) => {
+                tokio::spawn(async move {
+                    if let Ok((peer_id, conn)) = upgrade.await {
+                        // the network only opens substreams on connections it dialed
+                        let (_, opens_rx) = unbounded_channel();
+                        drive_connection(conn, peer_id, opens_rx, state).await;
+                    }
+                });
+            }
+            Some(NodeInput::Event(_)) => {}
+            None => return,
+        }
+    }
+}
+
+/// drive_connection polls the connection until it closes, opening the substreams asked
+/// for on `opens_rx` and serving those the remote peer opens.
+async fn drive_connection(
+    mut conn: Connection,
+    peer_id: PeerId,
+    mut opens_rx: UnboundedReceiver<oneshot::Sender<Substream>>,
+    state: NodeState,
+) {
+    poll_fn(|cx| {
+        while let Poll::Ready(Some(substream_tx)) = opens_rx.poll_recv(cx) {
+            if let Poll::Ready(Ok(substream)) = Pin::new(&mut conn).poll_outbound(cx) {
+                substream_tx.send(substream).ok();
+            }
+        }
+        loop {
+            match Pin::new(&mut conn).poll(cx) {
+                Poll::Ready(Ok(_)) => continue,
+                Poll::Ready(Err(_)) => return Poll::Ready(()),
+                Poll::Pending => break,
+            }
+        }
+        while let Poll::Ready(Ok(substream)) = Pin::new(&mut conn).poll_inbound(cx) {
+            tokio::spawn(serve_substream(substream, peer_id, state.clone()));
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// serve_substream records the data read from the substream, writing it back if the node
+/// echoes, until the substream closes.
+async fn serve_substream(mut substream: Substream, peer_id: PeerId, state: NodeState) {
+    let mut buf = vec![0u8; MAX_READ_LEN];
+    loop {
+        let n = match substream.read(&mut buf).await {
+            Ok(n) if n > 0 => n,
+            _ => return,
+        };
+        state.deliveries.lock().push((peer_id, buf[..n].to_vec()));
+        state.delivered.notify_waiters();
+        if state.behaviour == NodeBehaviour::Echo && substream.write_all(&buf[..n]).await.is_err() {
+            return;
+        }
+    }
+}
+
+// This section sets up a dialer and a listener transport on the mock mixnet, for tests
+// of a single connection that poll the transports themselves.
+
+/// new_mock_transport creates a transport with a new identity on the mock mixnet.
+pub fn new_mock_transport(mixnet: &MockMixnet) -> NymTransport {
+    NymTransport::new_with_backend(mixnet.new_backend(), Keypair::generate_ed25519())
+        .expect("transport on the mock mixnet must be created")
+}
+
+/// new_mock_transports creates a dialer and a listener transport on a new mock mixnet,
+/// set up by `dialer` and `listener` respectively, and waits for both to report their
+/// listen address, so that the dialer can dial the listener.
+pub async fn new_mock_transports(
+    dialer: impl FnOnce(NymTransport) -> NymTransport,
+    listener: impl FnOnce(NymTransport) -> NymTransport,
+) -> (NymTransport, NymTransport) {
+    new_mock_transports_on(&MockMixnet::new(), dialer, listener).await
+}
+
+/// new_mock_transports_on is like [`new_mock_transports`], on the given mock mixnet, eg.
+/// one the test changes the links of or adds further transports to.
+pub async fn new_mock_transports_on(
+    mixnet: &MockMixnet,
+    dialer: impl FnOnce(NymTransport) -> NymTransport,
+    listener: impl FnOnce(NymTransport) -> NymTransport,
+) -> (NymTransport, NymTransport) {
+    let mut dialer_transport = dialer(new_mock_transport(mixnet));
+    let mut listener_transport = listener(new_mock_transport(mixnet));
+    assert_new_address_event(Pin::new(&mut dialer_transport)).await;
+    assert_new_address_event(Pin::new(&mut listener_transport)).await;
+    (dialer_transport, listener_transport)
+}
+
+/// assert_new_address_event polls the transport for its first event, and panics unless
+/// it reports the transport's listen address.
+pub async fn assert_new_address_event(mut transport: Pin<&mut NymTransport>) {
+    match poll_fn(|cx| transport.as_mut().poll(cx)).await {
+        TransportEvent::NewAddress {
+            listener_id,
+            listen_addr,
+        } => {
+            assert_eq!(listener_id, transport.listener_id);
+            assert_eq!(listen_addr, transport.listen_addr);
+        }
+        _ => panic!("expected TransportEvent::NewAddress"),
+    }
+}
+
+/// connect_mock_transports dials the listener from the dialer; both must use a mock
+/// backend and have already emitted their initial NewAddress event, eg. as created by
+/// [`new_mock_transports`].
+pub async fn connect_mock_transports(
+    dialer_transport: &mut NymTransport,
+    listener_transport: &mut NymTransport,
+) -> (Connection, Connection) {
+    let listener_multiaddr = listener_transport.listen_addr.clone();
+    let mut dial = tokio::spawn(dialer_transport.dial(listener_multiaddr).unwrap());
+    let upgrade = match poll_fn(|cx| Pin::new(&mut *listener_transport).as_mut().poll(cx)).await {
+        TransportEvent::Incoming { upgrade, .. } => upgrade,
+        res => panic!("expected TransportEvent::Incoming, got {:?}", res),
+    };
+    let (_, listener_conn) = upgrade.await.unwrap();
+    let (_, dialer_conn) = tokio::select! {
+        res = &mut dial => res.unwrap().unwrap(),
+        event = poll_fn(|cx| Pin::new(&mut *dialer_transport).as_mut().poll(cx)) => {
+            panic!("unexpected transport event {:?}", event)
+        }
+    };
+    (dialer_conn, listener_conn)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[tokio::test]
+    async fn test_network() {
+        let slow = LinkConditions {
+            latency: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let mut network = TestNetwork::builder()
+            .node("echo", NodeBehaviour::Echo)
+            .node("client", NodeBehaviour::Sink)
+            .deferred_node("late", NodeBehaviour::Sink)
+            .link("client", "echo", slow)
+            .build();
+
+        let reply = timeout(TIMEOUT, network.request("client", "echo", b"hello"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply, b"hello".to_vec());
+        assert_eq!(
+            network.deliveries("echo"),
+            vec![Delivery {
+                from: "client".to_string(),
+                data: b"hello".to_vec(),
+            }]
+        );
+        assert!(network.deliveries("client").is_empty());
+
+        // a deferred node doesn't answer until it's started
+        let dial = timeout(
+            Duration::from_millis(200),
+            network.connect("client", "late"),
+        );
+        assert!(dial.await.is_err());
+        network.start_node("late");
+        timeout(TIMEOUT, network.send("client", "late", b"hi"))
+            .await
+            .unwrap()
+            .unwrap();
+        let deliveries = timeout(TIMEOUT, network.wait_for_deliveries("late", 1))
+            .await
+            .unwrap();
+        assert_eq!(deliveries[0].from, "client");
+        assert_eq!(deliveries[0].data, b"hi".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_network_concurrent_substreams() {
+        let mut network = TestNetwork::builder()
+            .node("echo", NodeBehaviour::Echo)
+            .node("client", NodeBehaviour::Sink)
+            .build();
+
+        // the substreams share one connection, and their writes are interleaved
+        let mut substreams = vec![];
+        for _ in 0..3 {
+            let substream = timeout(TIMEOUT, network.open_substream("client", "echo"))
+                .await
+                .unwrap()
+                .unwrap();
+            substreams.push(substream);
+        }
+        for round in 0..3u8 {
+            for (i, substream) in substreams.iter_mut().enumerate() {
+                substream.write_all(&[i as u8, round]).await.unwrap();
+            }
+        }
+
+        // each gets back only what was written to it, in order
+        for (i, substream) in substreams.iter_mut().enumerate() {
+            let mut buf = [0u8; 6];
+            timeout(TIMEOUT, substream.read_exact(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let i = i as u8;
+            assert_eq!(buf, [i, 0, i, 1, i, 2]);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use parking_lot::Mutex;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::{sync::Notify, time::Duration};
+
+/// ErrorInjector makes a NymTransport fail on purpose, so applications can test their
+/// recovery logic against transport failures deterministically. It's a cloneable handle
+/// returned by [`NymTransport::error_injector`](crate::transport::NymTransport::error_injector),
+/// and can be kept around after the transport is moved into a swarm.
+/// Each injected failure happens once.
+#[derive(Clone, Default)]
+pub struct ErrorInjector {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    fail_next_dial: AtomicBool,
+    drop_connection: Notify,
+    /// how many of the next inbound messages are delayed, and by how long
+    delay_inbound: Mutex<(usize, Duration)>,
+}
+
+impl ErrorInjector {
+    /// fail_next_dial makes the next dial fail with `Error::InjectedFailure`.
+    pub fn fail_next_dial(&self) {
+        self.inner.fail_next_dial.store(true, Ordering::SeqCst);
+    }
+
+    /// drop_mixnet_connection simulates losing the connection to the mixnet, eg. the
+    /// nym-client's websocket closing. The transport reconnects as it would after a real
+    /// drop, and dials fail or wait in the meantime.
+    pub fn drop_mixnet_connection(&self) {
+        self.inner.drop_connection.notify_one();
+    }
+
+    /// delay_inbound holds back each of the next `count` messages received from the mixnet
+    /// for the given delay, so they arrive after messages received later.
+    pub fn delay_inbound(&self, count: usize, delay: Duration) {
+        *self.inner.delay_inbound.lock() = (count, delay);
+    }
+
+    /// take_dial_failure returns true if the dial being made should fail.
+    pub(crate) fn take_dial_failure(&self) -> bool {
+        self.inner.fail_next_dial.swap(false, Ordering::SeqCst)
+    }
+
+    /// connection_dropped resolves once a loss of the mixnet connection is injected.
+    pub(crate) async fn connection_dropped(&self) {
+        self.inner.drop_connection.notified().await
+    }
+
+    /// take_inbound_delay returns how long the message being received should be held back,
+    /// if at all.
+    pub(crate) fn take_inbound_delay(&self) -> Option<Duration> {
+        let mut delay_inbound = self.inner.delay_inbound.lock();
+        let (count, delay) = &mut *delay_inbound;
+        if *count == 0 {
+            return None;
+        }
+        *count -= 1;
+        Some(*delay)
+    }
+}
+
+/// How many times [`VirtualTime::settle`] yields, which is enough for a message to go
+/// through the mixnet task and both transports.
+#[cfg(any(test, feature = "testing"))]
+const SETTLE_YIELDS: usize = 32;
+
+/// VirtualTime runs the transport on tokio's paused clock, so timeout, retry and keepalive
+/// logic can be tested deterministically and without real sleeps. Every timer of the
+/// transport is a `tokio::time` timer, so it follows the paused clock.
+///
+/// While the clock is paused, it only moves when advanced, or when every task is waiting on
+/// a timer, in which case it jumps to the earliest one. The clock can only be paused on a
+/// current-thread runtime, eg. the one of `#[tokio::test]`, and it's best used with a mock
+/// mixnet: waiting on real network I/O lets the clock jump ahead.
+#[cfg(any(test, feature = "testing"))]
+pub struct VirtualTime {
+    _private: (),
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl VirtualTime {
+    /// start pauses the clock of the current runtime until the VirtualTime is dropped.
+    pub fn start() -> Self {
+        tokio::time::pause();
+        VirtualTime { _private: () }
+    }
+
+    pub fn now(&self) -> tokio::time::Instant {
+        tokio::time::Instant::now()
+    }
+
+    /// advance moves the clock forward, firing the timers that are due in the meantime,
+    /// and lets the tasks they woke run.
+    pub async fn advance(&self, duration: Duration) {
+        tokio::time::advance(duration).await;
+        self.settle().await;
+    }
+
+    /// settle lets the tasks that are ready run, without moving the clock.
+    pub async fn settle(&self) {
+        for _ in 0..SETTLE_YIELDS {
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Drop for VirtualTime {
+    fn drop(&mut self) {
+        // resuming may panic, eg. outside of the runtime, and panicking while unwinding
+        // aborts
+        if !std::thread::panicking() {
+            tokio::time::resume();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_virtual_time() {
+        let time = VirtualTime::start();
+        let start = time.now();
+        let (woken_tx, mut woken_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+            woken_tx.send(()).unwrap();
+        });
+        time.settle().await;
+
+        time.advance(Duration::from_secs(1800)).await;
+        assert!(woken_rx.try_recv().is_err());
+        time.advance(Duration::from_secs(1800)).await;
+        assert!(woken_rx.try_recv().is_ok());
+        assert_eq!(time.now() - start, Duration::from_secs(3600));
+
+        // with nothing else to do, the clock jumps to the next timer
+        tokio::time::timeout(Duration::from_secs(60), std::future::pending::<()>())
+            .await
+            .unwrap_err();
+        assert_eq!(time.now() - start, Duration::from_secs(3660));
+    }
+}
@@ -8,35 +8,107 @@ use libp2p::core::{
 use nym_sphinx::addressing::clients::Recipient;
 use std::{
     collections::HashMap,
+    path::Path,
     pin::Pin,
     str::FromStr,
+    sync::{atomic::Ordering, Arc},
     task::{Context, Poll, Waker},
+    time::SystemTime,
 };
 use tokio::{
     sync::{
-        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-        oneshot,
+        mpsc::{error::SendError, unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot, watch, Semaphore,
     },
-    time::{timeout, Duration},
+    time::{interval_at, timeout, Duration, Instant, Interval, MissedTickBehavior},
 };
 use tokio_stream::wrappers::UnboundedReceiverStream;
-use tracing::debug;
+use tracing::{debug, info, warn, Span};
 
-use crate::connection::{Connection, PendingConnection};
+use crate::audit::{AuditLog, AuditSink, ConnectionDirection, ConnectionOutcome, FileAuditSink};
+use crate::backend::{MixnetBackend, MixnetInfo, MockMixnet, PacketSize, WebsocketBackend};
+#[cfg(feature = "sdk")]
+use crate::backend::{SdkBackend, SdkClientConfig};
+use crate::breaker::{CircuitBreaker, CircuitBreakerConfig};
+use crate::budget::MemoryBudget;
+use crate::config::{
+    check_handshake_workers, check_inbound_watermarks, check_max_concurrent_dials,
+    check_outbound_capacity, millis, ConfigHandle, NymTransportConfig, RateLimiter, Redacted,
+    RuntimeConfig,
+};
+use crate::connection::{Connection, ConnectionHandle, PendingConnection, PendingPings};
+use crate::dial::{without_peer_id, DialOptionsHandle, DialPhase, DialSlots, PreconnectHandle};
+use crate::doctor::{self, DiagnosticReport};
+use crate::endpoint::NymEndpoint;
 use crate::error::Error;
+use crate::events::{CloseReason, ConnectionEvent, ShutdownReason};
+use crate::filter::{FilterAction, InboundFilter, MessageSender};
+use crate::fragment::{
+    DEFAULT_MAX_PARTIAL_MESSAGES, DEFAULT_REASSEMBLY_TIMEOUT, FRAGMENT_HEADER_LEN,
+};
+use crate::framing::Framing;
+use crate::handshake::{HandshakePool, VerifiedRequest};
+use crate::identity::{load_or_generate_keypair, KeyType};
+use crate::journal::{Journal, JournalConfig};
+use crate::lifecycle::LifecycleHandle;
+use crate::listener::{Acceptor, AcceptorMatch};
 use crate::message::{
-    ConnectionId, ConnectionMessage, InboundMessage, Message, OutboundMessage, SubstreamMessage,
+    AddressUpdateMessage, CloseAckMessage, ConnectionCloseMessage, ConnectionDeniedMessage,
+    ConnectionId, ConnectionMessage, DenialReason, DialBackMessage, EchoReplyMessage,
+    EchoRequestMessage, InboundMessage, Message, MessageKind, MessagePriority, OutboundMessage,
+    PingMessage, PongMessage, ReachabilityRequestMessage, Route, SubstreamMessage,
     TransportMessage,
 };
-use crate::mixnet::initialize_mixnet;
+use crate::mixnet::{
+    initialize_mixnet_with_rotation, wait_for_connected, InboundBacklog, MixnetChannels,
+    MixnetConnection, MixnetOptions, MixnetStatus, OutboundSender,
+};
+use crate::pinned::{PinnedPeerEvent, PinnedPeers, Redial, RedialBackoff};
 use crate::queue::MessageQueue;
-use crate::DEFAULT_HANDSHAKE_TIMEOUT_SECS;
+use crate::record::SignedNymAddressRecord;
+use crate::registry::ShutdownHandle;
+use crate::rotation::{AddressEvent, AddressRotation};
+use crate::shared::TenantRegistration;
+use crate::spec::extension;
+use crate::stats::{
+    unix_micros, ConnectionQuality, ConnectionStats, EchoResult, HandshakeFailure, LatencySample,
+    TransportStats,
+};
+use crate::testing::ErrorInjector;
+use crate::{
+    DEFAULT_DIAGNOSTIC_TIMEOUT, DEFAULT_HANDSHAKE_TIMEOUT_SECS, DEFAULT_HANDSHAKE_WORKERS,
+    DEFAULT_INBOUND_BATCH_DELAY, DEFAULT_INBOUND_HIGH_WATERMARK, DEFAULT_KEEPALIVE_MAX_MISSED,
+    DEFAULT_MAX_CONCURRENT_DIALS, DEFAULT_MAX_QUEUED_DIALS, DEFAULT_MAX_QUEUED_HANDSHAKES,
+    DEFAULT_OUTBOUND_CAPACITY, DEFAULT_SENDER_WORKERS,
+};
+
+pub use crate::connection::NegotiatedParams;
+pub use crate::dial::DialOptions;
+
+/// how long to wait for a close to be acknowledged before sending it again
+const CLOSE_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// how many times a close is sent again before giving up on its acknowledgement
+const MAX_CLOSE_RETRIES: u32 = 3;
+
+/// the longest outbound messages may be held back by send jitter
+const MAX_SEND_JITTER: Duration = Duration::from_secs(10);
 
 /// InboundTransportEvent represents an inbound event from the mixnet.
 pub enum InboundTransportEvent {
     ConnectionRequest(Upgrade),
+    HandshakeQueued,
+    HandedOff,
     ConnectionResponse,
     TransportMessage,
+    AddressUpdate,
+    Probe,
+    Broadcast,
+    Datagram,
+    Reachability,
+    ConnectionDenied,
+    ConnectionClosed,
+    Echo,
 }
 
 /// NymTransport implements the Transport trait using the Nym mixnet.
@@ -46,12 +118,14 @@ pub struct NymTransport {
     pub(crate) listen_addr: Multiaddr,
     pub(crate) listener_id: ListenerId,
 
-    /// our libp2p keypair; currently not really used
+    /// our libp2p keypair; used to sign address updates
     keypair: Keypair,
 
-    /// established connections -> channel which sends messages received from
-    /// the mixnet to the corresponding Connection
-    connections: HashMap<ConnectionId, UnboundedSender<SubstreamMessage>>,
+    /// established connections
+    connections: HashMap<ConnectionId, ConnectionHandle>,
+
+    /// increments every time our Nym address changes; sent in address updates
+    address_epoch: u64,
 
     /// outbound pending dials
     pending_dials: HashMap<ConnectionId, PendingConnection>,
@@ -62,8 +136,107 @@ pub struct NymTransport {
     /// inbound mixnet messages
     inbound_stream: UnboundedReceiverStream<InboundMessage>,
 
+    /// messages on inbound_stream which haven't been handled yet
+    inbound_backlog: InboundBacklog,
+
+    /// memory held by buffers; shared with other transports on the same Nym client
+    memory_budget: MemoryBudget,
+
     /// outbound mixnet messages
-    outbound_tx: UnboundedSender<OutboundMessage>,
+    outbound_tx: OutboundSender,
+
+    /// changes to our Nym address, if address rotation is enabled
+    address_rx: UnboundedReceiver<AddressEvent>,
+
+    /// options of the mixnet task; shared with other transports on the same Nym client
+    mixnet_options_tx: Arc<watch::Sender<MixnetOptions>>,
+
+    /// set if we share our Nym client with other transports through a
+    /// [`SharedMixnet`](crate::shared::SharedMixnet)
+    tenant: Option<TenantRegistration>,
+
+    /// whether the mixnet task is connected or reconnecting
+    mixnet_status_rx: watch::Receiver<MixnetStatus>,
+
+    /// what the mixnet backend knows about its connection, eg. its gateway
+    mixnet_info_rx: watch::Receiver<MixnetInfo>,
+
+    /// whether the mixnet appears to be down; shared with other transports on the same
+    /// Nym client
+    breaker: CircuitBreaker,
+
+    /// how long dials wait for the mixnet while it's reconnecting; if None, they fail
+    /// right away
+    dial_queue_timeout: Option<Duration>,
+
+    /// if set, dials whose options don't choose otherwise are anonymous, with this many
+    /// reply SURBs sent along with every message
+    default_reply_surbs: Option<u32>,
+
+    /// the most data a substream write sends in one message, if limited
+    max_message_size: Option<usize>,
+
+    /// bounds the substream writes waiting to be written to the mixnet
+    outbound_capacity: Arc<Semaphore>,
+
+    /// the extensions we offer in connection handshakes
+    extensions: u8,
+    /// the user agent we announce in connection handshakes, if any
+    user_agent: Option<String>,
+
+    /// how far a peer's clock may be off from ours before it's reported, if handshakes
+    /// are checked for clock skew
+    clock_skew_threshold: Option<Duration>,
+    /// whether the message ages of connections are corrected for the skew
+    clock_skew_correction: bool,
+
+    /// the connections we gave the remote peer a compact reference to, by reference
+    compact_refs: HashMap<u64, ConnectionId>,
+    /// the reference the next such connection gets
+    next_compact_ref: u64,
+
+    /// Nym-specific options for dials to particular addresses
+    dial_options: DialOptionsHandle,
+
+    /// dials started by preconnect, and the addresses they're to
+    preconnects: Vec<(Multiaddr, <NymTransport as Transport>::Dial)>,
+
+    /// preconnected connections, handed out by the next dial to their address
+    parked: HashMap<Multiaddr, (PeerId, Connection)>,
+
+    /// addresses to preconnect to, from PreconnectHandles
+    preconnect_rx: UnboundedReceiver<Multiaddr>,
+    preconnect_tx: UnboundedSender<Multiaddr>,
+
+    /// asks the transport to shut down, from ShutdownHandles
+    shutdown_rx: UnboundedReceiver<()>,
+    shutdown_tx: UnboundedSender<()>,
+    /// set once the transport was shut down
+    shut_down: bool,
+
+    /// what the transport logs is within this span, eg. one naming the instance
+    span: Span,
+
+    /// handle for writing to the mixnet outside of connections
+    mixnet_connection: MixnetConnection,
+
+    /// receives inbound broadcasts, if anyone subscribed to them
+    broadcast_tx: Option<UnboundedSender<Vec<u8>>>,
+
+    /// receives inbound datagrams, if anyone subscribed to them
+    datagram_tx: Option<UnboundedSender<Vec<u8>>>,
+
+    /// receives connection quality updates, if anyone subscribed to them
+    quality_tx: Option<UnboundedSender<(PeerId, ConnectionQuality)>>,
+
+    /// IDs of connections the application dropped
+    closed_rx: UnboundedReceiver<(ConnectionId, ShutdownReason)>,
+    closed_tx: UnboundedSender<(ConnectionId, ShutdownReason)>,
+
+    /// IDs of connections whose dial is done with: established, failed, timed out or
+    /// dropped
+    dials_done_rx: UnboundedReceiver<ConnectionId>,
+    dials_done_tx: UnboundedSender<ConnectionId>,
 
     /// inbound messages for Transport.poll()
     poll_rx: UnboundedReceiver<TransportEvent<Upgrade, Error>>,
@@ -73,499 +246,5638 @@ pub struct NymTransport {
 
     waker: Option<Waker>,
 
-    /// Timeout for the [`Upgrade`] future.
-    handshake_timeout: Duration,
+    /// runtime configuration, updated through config_handle
+    config_rx: watch::Receiver<RuntimeConfig>,
+    config_handle: ConfigHandle,
+
+    /// limits the rate of inbound connection requests
+    inbound_limiter: RateLimiter,
+
+    /// verifies inbound connection requests off the inbound path
+    handshakes: HandshakePool,
+
+    /// bounds the dials whose handshakes are in flight
+    dial_slots: DialSlots,
+
+    /// if set, every established connection is probed for latency on each tick
+    latency_probe: Option<Interval>,
+
+    /// if set, the stats of every connection are reported on each tick
+    connection_stats: Option<Interval>,
+
+    /// if set, a keepalive is sent on every connection on each tick, and connections
+    /// whose remote peer left this many in a row unanswered are dropped
+    keepalive: Option<Interval>,
+    max_missed_keepalives: u32,
+
+    /// if set, a connected peer is asked to confirm our address is reachable on each tick
+    reachability_probe: Option<Interval>,
+
+    /// how long an established connection has to negotiate a protocol on a substream
+    /// before it's dropped, if limited
+    upgrade_timeout: Option<Duration>,
+    upgrade_check: Option<Interval>,
+
+    /// peers re-dialed whenever they have no connection, if pinning is enabled
+    pinned_peers: PinnedPeers,
+    redial_backoff: RedialBackoff,
+    redial_check: Option<Interval>,
+    redials: HashMap<PeerId, Redial>,
+
+    /// if set, closes on connections using the close ack extension are sent again on each
+    /// tick until they're acknowledged
+    close_retry: Option<Interval>,
+    /// closes we sent that weren't acknowledged yet
+    unacked_closes: HashMap<ConnectionId, UnackedClose>,
+    /// closes we acknowledged, kept for as long as the remote peer may send them again
+    /// because the acknowledgement was lost
+    acked_closes: HashMap<ConnectionId, AckedClose>,
+
+    /// whether the transport was paused when last polled, so that the timers restart
+    /// once it's resumed
+    paused: bool,
+    /// receives events about re-dials, if anyone subscribed to them
+    pinned_events_tx: Option<UnboundedSender<PinnedPeerEvent>>,
+
+    /// acceptors taking the inbound connections they select, in the order they were created
+    acceptors: Vec<(AcceptorMatch, UnboundedSender<Upgrade>)>,
+
+    /// nonce of the reachability probe we're waiting for a dial-back for
+    pending_reachability: Option<u64>,
+    /// used to take turns between connected peers for reachability probes
+    reachability_probes_sent: usize,
+
+    /// limits the rate of echo replies, and their maximum rate, if the echo responder is
+    /// enabled
+    echo_responder: Option<(RateLimiter, Option<u32>)>,
+    /// nonce of each of our echo requests waiting for a reply -> where to send the result
+    pending_echoes: HashMap<u64, oneshot::Sender<EchoResult>>,
+
+    stats: TransportStats,
+
+    /// sees inbound messages before they're handled, if set
+    inbound_filter: Option<Box<dyn InboundFilter>>,
+
+    /// records connection attempts, if set
+    audit_log: Option<AuditLog>,
+
+    /// makes the transport fail on purpose, for testing
+    injector: ErrorInjector,
 }
 
 impl NymTransport {
-    /// New transport.
-    pub async fn new(uri: &String, keypair: Keypair) -> Result<Self, Error> {
-        Self::new_maybe_with_notify_inbound(uri, keypair, None, None).await
+    /// New transport which reaches the mixnet through the endpoint, eg.
+    /// `"ws://127.0.0.1:1977"`; its scheme selects the backend, see [`NymEndpoint`].
+    pub async fn new<E>(endpoint: E, keypair: Keypair) -> Result<Self, Error>
+    where
+        E: TryInto<NymEndpoint>,
+        Error: From<E::Error>,
+    {
+        Self::new_maybe_with_notify_inbound(
+            endpoint.try_into()?,
+            keypair,
+            None,
+            None,
+            DEFAULT_SENDER_WORKERS,
+        )
+        .await
     }
 
     /// New transport with a timeout.
-    pub async fn new_with_timeout(
-        uri: &String,
+    pub async fn new_with_timeout<E>(
+        endpoint: E,
         keypair: Keypair,
         timeout: Duration,
-    ) -> Result<Self, Error> {
-        Self::new_maybe_with_notify_inbound(uri, keypair, None, Some(timeout)).await
-    }
-
-    /// Add timeout to transport and return self.
-    pub fn with_timeout(mut self, timeout: Duration) -> Self {
-        self.handshake_timeout = timeout;
-        self
+    ) -> Result<Self, Error>
+    where
+        E: TryInto<NymEndpoint>,
+        Error: From<E::Error>,
+    {
+        Self::new_maybe_with_notify_inbound(
+            endpoint.try_into()?,
+            keypair,
+            None,
+            Some(timeout),
+            DEFAULT_SENDER_WORKERS,
+        )
+        .await
     }
 
-    async fn new_maybe_with_notify_inbound(
-        uri: &String,
+    /// New transport which opens `sender_workers` websocket connections to the Nym client
+    /// and spreads outbound messages across them.
+    /// Messages for the same remote recipient always use the same connection, so ordering
+    /// within a connection is preserved. Other endpoints ignore `sender_workers`.
+    pub async fn new_with_sender_workers<E>(
+        endpoint: E,
         keypair: Keypair,
-        notify_inbound_tx: Option<UnboundedSender<()>>,
-        timeout: Option<Duration>,
-    ) -> Result<Self, Error> {
-        let (self_address, inbound_rx, outbound_tx) =
-            initialize_mixnet(uri, notify_inbound_tx).await?;
-        let listen_addr = nym_address_to_multiaddress(self_address)?;
-        let listener_id = ListenerId::new();
-
-        let (poll_tx, poll_rx) = unbounded_channel::<TransportEvent<Upgrade, Error>>();
-
-        poll_tx
-            .send(TransportEvent::NewAddress {
-                listener_id,
-                listen_addr: listen_addr.clone(),
-            })
-            .map_err(|_| Error::SendErrorTransportEvent)?;
-
-        let inbound_stream = UnboundedReceiverStream::new(inbound_rx);
-        let handshake_timeout =
-            timeout.unwrap_or_else(|| Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT_SECS));
-
-        Ok(Self {
-            self_address,
-            listen_addr,
-            listener_id,
+        sender_workers: usize,
+    ) -> Result<Self, Error>
+    where
+        E: TryInto<NymEndpoint>,
+        Error: From<E::Error>,
+    {
+        Self::new_maybe_with_notify_inbound(
+            endpoint.try_into()?,
             keypair,
-            connections: HashMap::new(),
-            pending_dials: HashMap::new(),
-            message_queues: HashMap::new(),
-            inbound_stream,
-            outbound_tx,
-            poll_rx,
-            poll_tx,
-            waker: None,
-            handshake_timeout,
-        })
+            None,
+            None,
+            sender_workers.max(1),
+        )
+        .await
     }
 
-    pub(crate) fn peer_id(&self) -> PeerId {
-        PeerId::from_public_key(&self.keypair.public())
+    /// New transport using the libp2p keypair stored at `identity_path`, so the node keeps
+    /// a stable PeerId across restarts. If the file doesn't exist, a new keypair of the given
+    /// type is generated and saved there.
+    pub async fn new_with_identity_file<E, P: AsRef<Path>>(
+        endpoint: E,
+        identity_path: P,
+        key_type: KeyType,
+    ) -> Result<Self, Error>
+    where
+        E: TryInto<NymEndpoint>,
+        Error: From<E::Error>,
+    {
+        let endpoint = endpoint.try_into()?;
+        let keypair = load_or_generate_keypair(identity_path, key_type)?;
+        Self::new(endpoint, keypair).await
     }
 
-    fn handle_message_queue_on_connection_initiation(
-        &mut self,
-        id: &ConnectionId,
-    ) -> Result<(), Error> {
-        debug!("handle_message_queue_on_connection_initiation");
-        let Some(inbound_tx) = self.connections.get(id) else {
-            // this should not happen
-            return Err(Error::NoConnectionForTransportMessage);
+    /// New transport which reaches the mixnet through the endpoint, with the settings of
+    /// the configuration applied, see [`NymTransport::with_config`]. Creating it fails with
+    /// [`Error::ConnectTimeout`] if the endpoint can't be connected to within the
+    /// configuration's connect timeout.
+    pub async fn new_with_config<E>(
+        endpoint: E,
+        keypair: Keypair,
+        config: &NymTransportConfig,
+    ) -> Result<Self, Error>
+    where
+        E: TryInto<NymEndpoint>,
+        Error: From<E::Error>,
+    {
+        let endpoint = endpoint.try_into()?;
+        let connect = Self::new_maybe_with_notify_inbound(
+            endpoint,
+            keypair,
+            None,
+            None,
+            DEFAULT_SENDER_WORKERS,
+        );
+        let transport = match millis(config.connect_timeout_ms)? {
+            Some(connect_timeout) => timeout(connect_timeout, connect)
+                .await
+                .map_err(|_| Error::ConnectTimeout)??,
+            None => connect.await?,
         };
+        transport.with_config(config)
+    }
 
-        match self.message_queues.get_mut(id) {
-            Some(queue) => {
-                // update expected nonce
-                queue.set_connection_message_received();
+    /// Check what stands in the way of reaching the mixnet through the endpoint, eg.
+    /// `"ws://127.0.0.1:1977"`: whether the Nym client's websocket endpoint can be reached,
+    /// how quickly it answers a request for its address, how long a message sent to our own
+    /// address takes to come back and how large a message makes it through. Each check may
+    /// take up to 30 seconds; see [`doctor::diagnose`] to choose the timeout.
+    pub async fn diagnose<E>(endpoint: E) -> Result<DiagnosticReport, Error>
+    where
+        E: TryInto<NymEndpoint>,
+        Error: From<E::Error>,
+    {
+        doctor::diagnose(endpoint, DEFAULT_DIAGNOSTIC_TIMEOUT).await
+    }
 
-                // push pending inbound some messages in this case
-                while let Some(msg) = queue.pop() {
-                    debug!(
-                        "popped queued message with nonce {} for connection",
-                        msg.nonce
-                    );
-                    inbound_tx
-                        .send(msg.message.clone())
-                        .map_err(|e| Error::InboundSendError(e.to_string()))?;
-                }
-            }
-            None => {
-                // no queue exists for this connection, create one
-                let queue = MessageQueue::new();
-                self.message_queues.insert(id.clone(), queue);
-                let queue = self.message_queues.get_mut(id).unwrap();
-                queue.set_connection_message_received();
-            }
-        };
+    /// New transport which reaches the mixnet through the given backend.
+    pub fn new_with_backend<B: MixnetBackend>(backend: B, keypair: Keypair) -> Result<Self, Error> {
+        Self::new_from_backend(backend, keypair, None, None, None)
+    }
 
-        debug!("returning from handle_message_queue_on_connection_initiation");
-        Ok(())
+    /// New transport which runs a Nym client in-process, so no nym-client daemon is needed.
+    /// The client has an ephemeral identity, is registered with the gateway with the given
+    /// identity key if any, and has its buffers and cover traffic set by `config`. Like the
+    /// `sdk://` endpoint, but with the client's config.
+    #[cfg(feature = "sdk")]
+    pub async fn new_embedded(
+        gateway: Option<String>,
+        config: SdkClientConfig,
+        keypair: Keypair,
+    ) -> Result<Self, Error> {
+        let backend = SdkBackend::connect_with_config(gateway, config).await?;
+        Self::new_with_backend(backend, keypair)
     }
 
-    // handle_connection_response resolves the pending connection corresponding to the response
-    // (if there is one) into a Connection.
-    fn handle_connection_response(&mut self, msg: &ConnectionMessage) -> Result<(), Error> {
-        if self.connections.contains_key(&msg.id) {
-            return Err(Error::ConnectionAlreadyEstablished);
-        }
+    /// New transport which periodically replaces its Nym address with a fresh one,
+    /// obtained as configured by `rotation`.
+    /// Each new address is reported as a new listen address; replaced addresses are
+    /// reported as expired once their grace period is over.
+    pub fn new_with_address_rotation<B: MixnetBackend>(
+        backend: B,
+        keypair: Keypair,
+        rotation: AddressRotation<B>,
+    ) -> Result<Self, Error> {
+        Self::new_from_backend(backend, keypair, None, None, Some(rotation))
+    }
 
-        if let Some(pending_conn) = self.pending_dials.remove(&msg.id) {
-            // resolve connection and put into pending_conn channel
-            let (conn, conn_tx) = self.create_connection_types(
-                msg.peer_id,
-                pending_conn.remote_recipient,
-                msg.id.clone(),
-            );
+    /// Add timeout to transport and return self.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        // an invalid timeout leaves the default in place
+        if let Err(e) = self
+            .config_handle
+            .modify(|config| config.handshake_timeout = timeout)
+        {
+            debug!("ignoring handshake timeout: {:?}", e);
+        }
+        self
+    }
 
-            self.connections.insert(msg.id.clone(), conn_tx);
-            self.handle_message_queue_on_connection_initiation(&msg.id)?;
+    /// Queue dials requested while the mixnet connection is being re-established for up to
+    /// the given duration, and return self. By default, such dials fail right away with
+    /// [`Error::MixnetUnavailable`]; with queuing, they proceed once the mixnet is back,
+    /// or fail with that error if it isn't back in time. The handshake timeout only
+    /// starts once the connection request is sent.
+    pub fn with_dial_queuing(mut self, queue_timeout: Duration) -> Self {
+        self.dial_queue_timeout = Some(queue_timeout);
+        self
+    }
 
-            pending_conn
-                .connection_tx
-                .send(conn)
-                .map_err(|_| Error::ConnectionSendError)?;
+    /// Offer the latency extension to peers, and return self. On connections with peers
+    /// that enable it too, substream data is stamped with the time the sender wrote it,
+    /// so [`Substream::last_read_age`](crate::substream::Substream::last_read_age) tells
+    /// how long it took to arrive. Peers from before extensions existed reject connection
+    /// requests offering one, so only enable it if the peers you dial are up to date.
+    pub fn with_latency_extension(mut self) -> Self {
+        self.extensions |= extension::LATENCY;
+        self
+    }
 
-            if let Some(waker) = self.waker.take() {
-                waker.wake();
-            }
+    /// Check the clocks of peers in connection handshakes, and return self. The clock
+    /// extension is offered to peers; on connections with peers that enable it too, both
+    /// sides estimate the offset between their clocks from timestamps in the handshake, and
+    /// if it's over the threshold, log a warning and send a
+    /// [`ConnectionEvent::ClockSkewed`], since skew breaks anything that compares
+    /// timestamps across peers, such as message ages and address record expiry. The offsets
+    /// are kept in [`TransportStats::clock_offset`]. Like the latency extension, only enable
+    /// it if the peers you dial are up to date.
+    pub fn with_clock_skew_detection(mut self, threshold: Duration) -> Self {
+        self.extensions |= extension::CLOCK;
+        self.clock_skew_threshold = Some(threshold);
+        self
+    }
 
-            Ok(())
-        } else {
-            Err(Error::NoConnectionForResponse)
+    /// Offer compact connection IDs to peers, and return self. On connections with peers
+    /// that enable them too, each side picks a short reference for the connection in the
+    /// handshake, and messages after it carry the receiver's reference as a varint instead
+    /// of the 32-byte connection ID, which matters for small messages such as keepalives
+    /// and pings. Like the latency extension, only enable it if the peers you dial are up
+    /// to date. Transports sharing a Nym client don't offer it, since the client routes
+    /// messages to them by connection ID.
+    pub fn with_compact_connection_ids(mut self) -> Self {
+        if self.tenant.is_none() {
+            self.extensions |= extension::COMPACT_ID;
         }
+        self
     }
 
-    /// handle_connection_request handles an incoming connection request, sends back a
-    /// connection response, and finally completes the upgrade into a Connection.
-    fn handle_connection_request(&mut self, msg: &ConnectionMessage) -> Result<Connection, Error> {
-        if msg.recipient.is_none() {
-            return Err(Error::NoneRecipientInConnectionRequest);
+    /// Offer close acknowledgements to peers, and return self. On connections with peers
+    /// that enable them too, a close is acknowledged by the remote peer, and sent again up
+    /// to 3 times, 5 seconds apart, until it is. Without them, a close lost in the mixnet
+    /// leaves the remote peer waiting on the connection until its own messages go
+    /// unanswered. Like the latency extension, only enable it if the peers you dial are up
+    /// to date. Transports sharing a Nym client don't offer it, like compact IDs.
+    pub fn with_close_acks(mut self) -> Self {
+        if self.tenant.is_none() {
+            self.extensions |= extension::CLOSE_ACK;
+            let mut retry =
+                interval_at(Instant::now() + CLOSE_RETRY_INTERVAL, CLOSE_RETRY_INTERVAL);
+            retry.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            self.close_retry = Some(retry);
         }
+        self
+    }
 
-        // ensure we don't already have a conn with the same id
-        if self.connections.get(&msg.id).is_some() {
-            return Err(Error::ConnectionIDExists);
-        }
+    /// Make dials anonymous unless their [`DialOptions`] say otherwise, with `reply_surbs`
+    /// reply SURBs sent along with every message, and return self; see
+    /// [`DialOptions::with_anonymous`].
+    pub fn with_anonymous_dials(mut self, reply_surbs: u32) -> Self {
+        self.default_reply_surbs = Some(reply_surbs);
+        self
+    }
 
-        let (conn, conn_tx) =
-            self.create_connection_types(msg.peer_id, msg.recipient.unwrap(), msg.id.clone());
-        self.connections.insert(msg.id.clone(), conn_tx);
-        self.handle_message_queue_on_connection_initiation(&msg.id)?;
+    /// Limit how much data a substream write sends in one message, and return self. Writes
+    /// of more only write that much, like a short write to a socket, so callers using
+    /// `write_all` don't notice. Smaller messages need fewer sphinx packets each, so one
+    /// slow packet holds up less data. Writes aren't limited by default.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Result<Self, Error> {
+        if max_message_size == 0 {
+            return Err(Error::InvalidConfig("max message size must not be zero"));
+        }
+        self.max_message_size = Some(max_message_size);
+        Ok(self)
+    }
 
-        let resp = ConnectionMessage {
-            peer_id: self.peer_id(),
-            recipient: None,
-            id: msg.id.clone(),
-        };
+    /// Let up to `capacity` substream writes wait to be written to the mixnet, and return
+    /// self. Further writes return `Poll::Pending` until one of them has been written, so
+    /// a fast writer is slowed down to the rate the mixnet accepts messages at, rather
+    /// than queueing up data while the mixnet is slow. Control messages, eg. acks and
+    /// closes, don't count, so they can't get stuck behind data. The capacity is shared by
+    /// the connections established afterwards, and is 1024 by default.
+    ///
+    /// Up to `capacity` other messages, ie. control messages, datagrams, broadcasts and
+    /// messages sent with [`MixnetConnection::send`], may wait as well; further ones fail
+    /// with [`Error::OutboundQueueFull`].
+    pub fn with_outbound_capacity(mut self, capacity: usize) -> Result<Self, Error> {
+        check_outbound_capacity(capacity)?;
+        self.outbound_capacity = Arc::new(Semaphore::new(capacity));
+        self.outbound_tx.set_capacity(capacity);
+        Ok(self)
+    }
 
-        self.outbound_tx
-            .send(OutboundMessage {
-                message: Message::ConnectionResponse(resp),
-                recipient: msg.recipient.unwrap(),
-            })
-            .map_err(|e| Error::OutboundSendError(e.to_string()))?;
+    /// Announce this crate's name and version, [`USER_AGENT`](crate::USER_AGENT), to peers
+    /// in connection handshakes, and return self. Peers that announce theirs too answer
+    /// with it, and the user agent of the remote peer is in
+    /// [`Connection::negotiated`](crate::connection::Connection::negotiated), logged when the
+    /// connection is established and counted in [`TransportStats::user_agents`], which helps
+    /// telling which implementation interop problems come from. It's off by default, as it
+    /// tells peers which implementation we run. Like the latency extension, only enable it
+    /// if the peers you dial are up to date.
+    pub fn with_user_agent(self) -> Self {
+        self.with_custom_user_agent(crate::USER_AGENT)
+            .expect("the default user agent is valid")
+    }
 
-        if let Some(waker) = self.waker.take() {
-            waker.wake();
+    /// Announce the given user agent in connection handshakes instead of this crate's, eg.
+    /// the name and version of the application, and return self. It must not be empty or
+    /// longer than 255 bytes.
+    pub fn with_custom_user_agent(mut self, user_agent: &str) -> Result<Self, Error> {
+        if user_agent.is_empty() || user_agent.len() > u8::MAX as usize {
+            return Err(Error::InvalidConfig(
+                "user agents must be between 1 and 255 bytes long",
+            ));
         }
+        self.extensions |= extension::USER_AGENT;
+        self.user_agent = Some(user_agent.to_string());
+        Ok(self)
+    }
 
-        Ok(conn)
+    /// Correct the message ages of connections for the clock skew measured in their
+    /// handshake, and return self: the send time of
+    /// [`MessageAge`](crate::message::MessageAge)s is translated to our clock, so data from
+    /// peers with a skewed clock isn't taken as older or newer than it is. This has no
+    /// effect without [`NymTransport::with_clock_skew_detection`].
+    pub fn with_clock_skew_correction(mut self) -> Self {
+        self.clock_skew_correction = true;
+        self
     }
 
-    fn handle_transport_message(&mut self, msg: TransportMessage) -> Result<(), Error> {
-        let queue = match self.message_queues.get_mut(&msg.id) {
-            Some(queue) => queue,
-            None => {
-                // no queue exists for this connection, create one
-                let queue = MessageQueue::new();
-                self.message_queues.insert(msg.id.clone(), queue);
-                self.message_queues.get_mut(&msg.id).unwrap()
-            }
-        };
+    /// Reject inbound messages of types this version doesn't know, and return self: they're
+    /// logged as errors, and a connection on which the peer sends a substream message of
+    /// an unknown type fails with a protocol error. By default they're skipped, so peers
+    /// running a newer version can add message types; either way they're counted in
+    /// [`TransportStats::unknown_message_types`]. Strict mode is meant for tests, to catch
+    /// peers sending what they shouldn't.
+    pub fn with_strict_message_types(self) -> Self {
+        self.mixnet_options_tx
+            .send_modify(|options| options.strict_message_types = true);
+        self
+    }
 
-        queue.print_nonces();
+    /// Returns a handle for setting Nym-specific options for dials to particular addresses,
+    /// which can be kept after the transport is moved into a swarm; see [`DialOptions`].
+    pub fn dial_options_handle(&self) -> DialOptionsHandle {
+        self.dial_options.clone()
+    }
 
-        let nonce = msg.nonce;
-        let Some(msg) = queue.try_push(msg) else {
-            // don't push the message yet, it's been queued
-            debug!("message with nonce {} queued for connection", nonce);
+    /// Performs the handshake with the peer at the address ahead of time, and keeps the
+    /// connection until the address is next dialed, so that dial returns right away instead
+    /// of waiting for the handshake's round trip through the mixnet. The handshake proceeds
+    /// as the transport is polled; failures are only logged, and the later dial handshakes
+    /// as usual. The address is matched like for [`DialOptionsHandle::set`].
+    pub fn preconnect(&mut self, address: Multiaddr) -> Result<(), Error> {
+        if self.is_preconnected(&address) || self.preconnects.iter().any(|(a, _)| *a == address) {
             return Ok(());
-        };
-
-        let Some(inbound_tx) = self.connections.get(&msg.id) else {
-            return Err(Error::NoConnectionForTransportMessage);
-        };
-
-        // send original message
-        debug!(
-            "sending original message with nonce {} for connection",
-            nonce
-        );
-        inbound_tx
-            .send(msg.message.clone())
-            .map_err(|e| Error::InboundSendError(e.to_string()))?;
-
-        // try to pop queued messages and send them on inbound channel
-        while let Some(msg) = queue.pop() {
-            debug!(
-                "popped queued message with nonce {} for connection",
-                msg.nonce
-            );
-            inbound_tx
-                .send(msg.message.clone())
-                .map_err(|e| Error::InboundSendError(e.to_string()))?;
         }
-
-        if let Some(waker) = self.waker.clone().take() {
+        let dial = self.dial(address.clone()).map_err(|e| match e {
+            TransportError::MultiaddrNotSupported(_) => Error::InvalidProtocolForMultiaddr,
+            TransportError::Other(e) => e,
+        })?;
+        self.preconnects.push((address, dial));
+        if let Some(waker) = self.waker.take() {
             waker.wake();
         }
-
         Ok(())
     }
 
-    fn create_connection_types(
-        &self,
-        remote_peer_id: PeerId,
-        recipient: Recipient,
-        id: ConnectionId,
-    ) -> (Connection, UnboundedSender<SubstreamMessage>) {
-        let (inbound_tx, inbound_rx) = unbounded_channel::<SubstreamMessage>();
+    /// Returns a handle for [preconnecting](NymTransport::preconnect) to addresses, which
+    /// can be kept after the transport is moved into a swarm.
+    pub fn preconnect_handle(&self) -> PreconnectHandle {
+        PreconnectHandle::new(self.preconnect_tx.clone())
+    }
 
-        // representation of a connection; this contains channels for applications to read/write to.
-        let conn = Connection::new(
-            remote_peer_id,
-            recipient,
-            id,
-            inbound_rx,
-            self.outbound_tx.clone(),
-        );
+    /// Returns true if a preconnected connection is waiting for a dial to the address.
+    pub fn is_preconnected(&self, address: &Multiaddr) -> bool {
+        self.parked_address(address).is_some()
+    }
 
-        // inbound_tx is what we write to when receiving messages on the mixnet,
-        (conn, inbound_tx)
+    /// parked_address returns the address a connection for a dial to the given address is
+    /// parked under, if any.
+    fn parked_address(&self, address: &Multiaddr) -> Option<Multiaddr> {
+        if self.parked.contains_key(address) {
+            return Some(address.clone());
+        }
+        without_peer_id(address).filter(|address| self.parked.contains_key(address))
     }
 
-    /// handle_inbound handles an inbound message from the mixnet, received via self.inbound_stream.
+    /// poll_preconnects starts preconnecting to the addresses from PreconnectHandles, and
+    /// parks the connections of finished preconnects.
+    fn poll_preconnects(&mut self, cx: &mut Context<'_>) {
+        while let Poll::Ready(Some(address)) = self.preconnect_rx.poll_recv(cx) {
+            if let Err(e) = self.preconnect(address) {
+                debug!("failed to preconnect: {:?}", e);
+            }
+        }
+
+        let mut i = 0;
+        while i < self.preconnects.len() {
+            let Poll::Ready(res) = self.preconnects[i].1.as_mut().poll(cx) else {
+                i += 1;
+                continue;
+            };
+            let (address, _) = self.preconnects.swap_remove(i);
+            match res {
+                Ok(conn) => {
+                    debug!("preconnected to {}", Redacted(&address, self.redact_logs()));
+                    self.parked.insert(address, conn);
+                }
+                Err(e) => debug!(
+                    "failed to preconnect to {}: {:?}",
+                    Redacted(&address, self.redact_logs()),
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Set the initial runtime configuration and return self; see [`RuntimeConfig`].
+    pub fn with_runtime_config(self, config: RuntimeConfig) -> Result<Self, Error> {
+        self.config_handle.update(config)?;
+        Ok(self)
+    }
+
+    /// Apply the settings of a configuration, eg. one loaded with
+    /// [`NymTransportConfig::from_file`], and return self. Settings that aren't set are
+    /// left as they are.
+    pub fn with_config(mut self, config: &NymTransportConfig) -> Result<Self, Error> {
+        let mut runtime_config = self.config_handle.config();
+        config.apply_runtime_config(&mut runtime_config)?;
+        self = self.with_runtime_config(runtime_config)?;
+
+        if let Some(queue_timeout) = millis(config.dial_queue_timeout_ms)? {
+            self = self.with_dial_queuing(queue_timeout);
+        }
+        if let Some(max_message_size) = config.max_message_size {
+            self = self.with_max_message_size(max_message_size)?;
+        }
+        if let Some(capacity) = config.outbound_capacity {
+            self = self.with_outbound_capacity(capacity)?;
+        }
+        if let Some(reply_surbs) = config.reply_surbs {
+            self = self.with_anonymous_dials(reply_surbs);
+        }
+        if config.latency_extension {
+            self = self.with_latency_extension();
+        }
+        if let Some(threshold) = millis(config.clock_skew_threshold_ms)? {
+            self = self.with_clock_skew_detection(threshold);
+        }
+        if config.clock_skew_correction {
+            self = self.with_clock_skew_correction();
+        }
+        if config.compact_connection_ids {
+            self = self.with_compact_connection_ids();
+        }
+        if let Some(user_agent) = &config.custom_user_agent {
+            self = self.with_custom_user_agent(user_agent)?;
+        } else if config.user_agent {
+            self = self.with_user_agent();
+        }
+        if config.strict_message_types {
+            self = self.with_strict_message_types();
+        }
+        if let Some(packet_size) = config.packet_size()? {
+            self = self.with_packet_size(packet_size);
+        }
+        if let Some(len) = config.packet_payload_len {
+            self = self.with_packet_payload_len(len)?;
+        }
+        if let Some(max_packets) = config.max_message_packets {
+            self = self.with_max_message_packets(max_packets)?;
+        }
+        if let Some(framing) = config.framing()? {
+            self = self.with_framing(framing);
+        }
+        if config.reassembly_timeout_ms.is_some() || config.max_partial_messages.is_some() {
+            self = self.with_reassembly_limits(
+                millis(config.reassembly_timeout_ms)?.unwrap_or(DEFAULT_REASSEMBLY_TIMEOUT),
+                config
+                    .max_partial_messages
+                    .unwrap_or(DEFAULT_MAX_PARTIAL_MESSAGES),
+            )?;
+        }
+        if config.inbound_bytes_per_min.is_some() || config.outbound_bytes_per_min.is_some() {
+            self = self
+                .with_bandwidth_caps(config.inbound_bytes_per_min, config.outbound_bytes_per_min)?;
+        }
+        match (config.inbound_high_watermark, config.inbound_low_watermark) {
+            (Some(high), low) => {
+                self = self.with_inbound_watermarks(high, low.unwrap_or(high / 2))?;
+            }
+            (None, Some(_)) => {
+                return Err(Error::InvalidConfig(
+                    "inbound low watermark is set without a high watermark",
+                ))
+            }
+            (None, None) => {}
+        }
+        if let Some(max_messages) = config.inbound_batch_size {
+            let max_delay =
+                millis(config.inbound_batch_delay_ms)?.unwrap_or(DEFAULT_INBOUND_BATCH_DELAY);
+            self = self.with_inbound_batching(max_messages, max_delay)?;
+        }
+        if let Some(limit) = config.memory_budget {
+            self = self.with_memory_budget(limit)?;
+        }
+        if let Some(interval) = millis(config.latency_probe_interval_ms)? {
+            self = self.with_latency_probing(interval);
+        }
+        if let Some(interval) = millis(config.connection_stats_interval_ms)? {
+            self = self.with_connection_stats(interval);
+        }
+        if let Some(interval) = millis(config.keepalive_interval_ms)? {
+            let max_missed = config
+                .keepalive_max_missed
+                .unwrap_or(DEFAULT_KEEPALIVE_MAX_MISSED);
+            self = self.with_keepalive(interval, max_missed)?;
+        }
+        if let Some(interval) = millis(config.reachability_probe_interval_ms)? {
+            self = self.with_reachability_probing(interval);
+        }
+        if let Some(upgrade_timeout) = millis(config.upgrade_timeout_ms)? {
+            self = self.with_upgrade_timeout(upgrade_timeout);
+        }
+        if config.handshake_workers.is_some() || config.max_queued_handshakes.is_some() {
+            self = self.with_handshake_workers(
+                config
+                    .handshake_workers
+                    .unwrap_or(DEFAULT_HANDSHAKE_WORKERS),
+                config
+                    .max_queued_handshakes
+                    .unwrap_or(DEFAULT_MAX_QUEUED_HANDSHAKES),
+            )?;
+        }
+        if config.max_concurrent_dials.is_some() || config.max_queued_dials.is_some() {
+            self = self.with_max_concurrent_dials(
+                config
+                    .max_concurrent_dials
+                    .unwrap_or(DEFAULT_MAX_CONCURRENT_DIALS),
+                config.max_queued_dials.unwrap_or(DEFAULT_MAX_QUEUED_DIALS),
+            )?;
+        }
+        if config.circuit_breaker {
+            let mut breaker = CircuitBreakerConfig::default();
+            if let Some(max_send_failures) = config.breaker_max_send_failures {
+                breaker.max_send_failures = max_send_failures;
+            }
+            if let Some(max_inbound_silence) = millis(config.breaker_max_inbound_silence_ms)? {
+                breaker.max_inbound_silence = Some(max_inbound_silence);
+            }
+            if let Some(probe_interval) = millis(config.breaker_probe_interval_ms)? {
+                breaker.probe_interval = probe_interval;
+            }
+            self = self.with_circuit_breaker(breaker)?;
+        }
+        if config.peer_pinning {
+            let mut backoff = RedialBackoff::default();
+            if let Some(initial) = millis(config.redial_initial_backoff_ms)? {
+                backoff.initial = initial;
+            }
+            if let Some(max) = millis(config.redial_max_backoff_ms)? {
+                backoff.max = max;
+            }
+            self = self.with_peer_pinning(backoff);
+        }
+        if config.echo_responder {
+            self = self.with_echo_responder(config.echo_max_replies_per_sec);
+        }
+        if let Some(path) = &config.audit_log_path {
+            self = self.with_audit_log(FileAuditSink::open(path)?);
+        }
+        if let Some(path) = &config.journal_path {
+            let mut journal = JournalConfig::new(path);
+            if let Some(max_file_size) = config.journal_max_file_size {
+                journal.max_file_size = max_file_size;
+            }
+            if let Some(max_files) = config.journal_max_files {
+                journal.max_files = max_files;
+            }
+            if let Some(headers_only) = config.journal_headers_only {
+                journal.headers_only = headers_only;
+            }
+            self = self.with_journal(journal)?;
+        }
+        Ok(self)
+    }
+
+    /// Replace the runtime configuration (rate limits, timeouts, allow and deny lists, log
+    /// redaction) without restarting; it applies to connection attempts from then on.
+    /// To update it after the transport is moved into a swarm, use [`NymTransport::config_handle`].
+    pub fn update_config(&self, config: RuntimeConfig) -> Result<(), Error> {
+        self.config_handle.update(config)
+    }
+
+    /// Returns a handle for updating the runtime configuration, which can be kept after
+    /// the transport is moved into a swarm.
+    pub fn config_handle(&self) -> ConfigHandle {
+        self.config_handle.clone()
+    }
+
+    /// Pauses networking, eg. when the OS suspends a mobile app: the connection to the Nym
+    /// client is closed, nothing is read from or written to the mixnet, and latency and
+    /// reachability probes, upgrade timeouts and re-dials are stopped. Connections and
+    /// substreams are kept, and what's written to them waits until [`NymTransport::resume`].
+    /// If the Nym client is shared with other transports, they're paused as well.
+    pub fn pause(&self) {
+        self.lifecycle_handle().pause();
+    }
+
+    /// Resumes networking after [`NymTransport::pause`]: the connection to the Nym client
+    /// is re-established, our Nym address is fetched again and announced if it changed,
+    /// and the timers restart a full interval later.
+    pub fn resume(&self) {
+        self.lifecycle_handle().resume();
+    }
+
+    /// Returns a handle for pausing and resuming the transport, which can be kept after
+    /// the transport is moved into a swarm.
+    pub fn lifecycle_handle(&self) -> LifecycleHandle {
+        LifecycleHandle::new(self.mixnet_options_tx.clone())
+    }
+
+    /// update_paused notes whether the transport was paused or resumed since the last
+    /// poll, restarting the timers on resume, and returns whether it's paused.
+    fn update_paused(&mut self) -> bool {
+        let paused = self.mixnet_options_tx.borrow().paused;
+        if self.paused && !paused {
+            debug!("resumed; restarting timers");
+            for timer in [
+                &mut self.latency_probe,
+                &mut self.connection_stats,
+                &mut self.keepalive,
+                &mut self.reachability_probe,
+                &mut self.upgrade_check,
+                &mut self.redial_check,
+                &mut self.close_retry,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                timer.reset();
+            }
+        }
+        self.paused = paused;
+        paused
+    }
+
+    /// Set the size of the sphinx packets used for outbound messages and return self.
+    /// See [`PacketSize`] for the trade-off; individual substreams can override this with
+    /// [`Substream::set_packet_size`](crate::substream::Substream::set_packet_size).
+    /// The backend must support the packet size, otherwise sends fail.
+    pub fn with_packet_size(self, packet_size: PacketSize) -> Self {
+        self.mixnet_options_tx
+            .send_modify(|options| options.packet_size = packet_size);
+        self
+    }
+
+    /// Split messages that need more than `max_packets` sphinx packets into fragments of
+    /// at most that many packets, and return self. The Nym client only delivers a message
+    /// once all of its packets have arrived, and a message lost before the gateway is sent
+    /// again whole, so this bounds how much is held up by one slow packet or sent again.
+    /// The threshold follows the payload of a sphinx packet, see
+    /// [`NymTransport::with_packet_payload_len`]. Peers put the fragments back together
+    /// whatever their own settings. Messages aren't split by default.
+    pub fn with_max_message_packets(self, max_packets: usize) -> Result<Self, Error> {
+        if max_packets == 0 {
+            return Err(Error::InvalidConfig("max message packets must not be zero"));
+        }
+        self.mixnet_options_tx
+            .send_modify(|options| options.max_message_packets = Some(max_packets));
+        Ok(self)
+    }
+
+    /// Write messages to the mixnet in the given framing, and return self; see
+    /// [`crate::framing`]. Messages are read in any framing, but peers from before v2 only
+    /// read v1, so only switch once the peers you talk to are up to date.
+    pub fn with_framing(self, framing: Framing) -> Self {
+        self.mixnet_options_tx
+            .send_modify(|options| options.framing = framing);
+        self
+    }
+
+    /// Drop a fragmented message if its remaining fragments don't arrive within `timeout`
+    /// of its first one, and keep at most `max_partial_messages` partially received at
+    /// once, evicting the one least recently added to beyond that, and return self. This
+    /// bounds the memory held by messages whose fragments were lost. Dropped messages are
+    /// counted in [`TransportStats::reassembly`]. By default messages get 30 seconds, and
+    /// 64 are kept.
+    pub fn with_reassembly_limits(
+        self,
+        timeout: Duration,
+        max_partial_messages: usize,
+    ) -> Result<Self, Error> {
+        if timeout.is_zero() {
+            return Err(Error::InvalidConfig("reassembly timeout must not be zero"));
+        }
+        if max_partial_messages == 0 {
+            return Err(Error::InvalidConfig(
+                "max partial messages must not be zero",
+            ));
+        }
+        self.mixnet_options_tx.send_modify(|options| {
+            options.reassembly_timeout = Some(timeout);
+            options.max_partial_messages = Some(max_partial_messages);
+        });
+        Ok(self)
+    }
+
+    /// Set how many bytes of plaintext fit in one sphinx packet, whatever its size, and
+    /// return self. By default the [nominal payload](PacketSize::nominal_payload_len) of
+    /// the packet size is assumed, unless the backend reports it; the websocket and sdk
+    /// backends can't. Setting it keeps fragmentation in line with the mixnet if its packet
+    /// parameters change.
+    pub fn with_packet_payload_len(self, len: usize) -> Result<Self, Error> {
+        if len <= FRAGMENT_HEADER_LEN {
+            return Err(Error::InvalidConfig("packet payload length is too small"));
+        }
+        self.mixnet_options_tx
+            .send_modify(|options| options.packet_payload_len = Some(len));
+        Ok(self)
+    }
+
+    /// Encode outbound messages carrying more than `threshold` bytes of data on the
+    /// blocking thread pool, or every message on the mixnet task if it's None, and return
+    /// self. Encoding a large message, and splitting it into fragments, would otherwise
+    /// hold up the messages of every other connection meanwhile. A connection's messages
+    /// are still written in order. Messages carrying more than 64 KiB are offloaded by
+    /// default.
+    pub fn with_encode_offload_threshold(self, threshold: Option<usize>) -> Self {
+        self.mixnet_options_tx
+            .send_modify(|options| options.encode_offload_threshold = threshold);
+        self
+    }
+
+    /// Cap the bytes received from and written to the mixnet per minute, eg. to stay within
+    /// metered bandwidth credentials, and return self. None leaves a direction uncapped.
+    /// Traffic over a cap is slowed down to it rather than dropped: outbound messages are
+    /// queued, and inbound ones are left with the Nym client until there's room again.
+    pub fn with_bandwidth_caps(
+        self,
+        inbound_bytes_per_min: Option<u64>,
+        outbound_bytes_per_min: Option<u64>,
+    ) -> Result<Self, Error> {
+        if inbound_bytes_per_min == Some(0) || outbound_bytes_per_min == Some(0) {
+            return Err(Error::InvalidConfig("bandwidth caps must not be zero"));
+        }
+        self.mixnet_options_tx.send_modify(|options| {
+            options.inbound_bytes_per_min = inbound_bytes_per_min;
+            options.outbound_bytes_per_min = outbound_bytes_per_min;
+        });
+        Ok(self)
+    }
+
+    /// Hold each outbound message back for a random delay of up to `max_jitter` before it's
+    /// written to the mixnet, and return self. The Nym client already pads messages to
+    /// whole sphinx packets and delays them at every mix node, but it writes them as soon
+    /// as it gets them, so an observer of our gateway connection can still match bursts of
+    /// traffic to application events; the jitter decorrelates the two. Messages stay in
+    /// order: the delay is drawn once a message is next in line, so it also slows down
+    /// bulk transfers, to about two messages per `max_jitter`. The jitter must be at most
+    /// 10 seconds.
+    pub fn with_send_jitter(self, max_jitter: Duration) -> Result<Self, Error> {
+        if max_jitter.is_zero() || max_jitter > MAX_SEND_JITTER {
+            return Err(Error::InvalidConfig(
+                "send jitter must be more than zero and at most 10 seconds",
+            ));
+        }
+        self.mixnet_options_tx
+            .send_modify(|options| options.send_jitter = Some(max_jitter));
+        Ok(self)
+    }
+
+    /// Set the watermarks of the backlog of inbound messages waiting to be handled, and
+    /// return self. If the swarm stops being polled, eg. during a long synchronous
+    /// operation, the transport stops reading from the mixnet once `high` messages are
+    /// waiting, leaving the rest with the Nym client, and resumes once it's down to `low`.
+    /// The default is 4096 and 2048.
+    pub fn with_inbound_watermarks(self, high: usize, low: usize) -> Result<Self, Error> {
+        check_inbound_watermarks(high, low)?;
+        self.mixnet_options_tx
+            .send_modify(|options| options.inbound_watermarks = Some((high, low)));
+        Ok(self)
+    }
+
+    /// Pass inbound messages on to the transport in batches of up to `max_messages`, and
+    /// return self. Under high inbound rates, eg. with gossip-heavy workloads, this wakes
+    /// the transport, and with it the swarm, once per batch rather than once per message.
+    /// No message is held back for longer than `max_delay`, which is the latency batching
+    /// may add. Inbound messages aren't batched by default.
+    pub fn with_inbound_batching(
+        self,
+        max_messages: usize,
+        max_delay: Duration,
+    ) -> Result<Self, Error> {
+        if max_messages == 0 || max_delay.is_zero() {
+            return Err(Error::InvalidConfig(
+                "inbound batches must hold messages and have a delay",
+            ));
+        }
+        self.mixnet_options_tx.send_modify(|options| {
+            options.inbound_batching = Some((max_messages, max_delay));
+        });
+        Ok(self)
+    }
+
+    /// Cap the memory held by the transport's buffers at `limit` bytes and return self:
+    /// partially received fragmented messages, data received on substreams but not read
+    /// yet, and data written to substreams but not written to the mixnet yet. Once they
+    /// hold 80% of it, substream writes and reading from the mixnet pause until the
+    /// application or the mixnet catches up, and new fragmented messages that don't fit in
+    /// what's left are dropped. Outbound latency probes and echoes are dropped from then
+    /// on too, and once the limit is reached, so is the data of low priority substreams.
+    /// Usage is available through [`NymTransport::memory_budget`]. There's no limit by
+    /// default.
+    pub fn with_memory_budget(self, limit: usize) -> Result<Self, Error> {
+        if limit == 0 {
+            return Err(Error::InvalidConfig("memory budget must not be zero"));
+        }
+        self.memory_budget.set_limit(Some(limit));
+        Ok(self)
+    }
+
+    /// Trip a circuit breaker when the mixnet appears to be down, and return self: after
+    /// too many messages in a row were refused by the Nym client or lost before the
+    /// gateway, or when nothing arrives for too long after sending. While it's open, dials
+    /// fail right away with [`Error::MixnetOutage`], messages lost before the gateway wait
+    /// to be sent again, and an echo request is sent to our own address at the probe
+    /// interval; the breaker closes once any message arrives. State changes are reported
+    /// through [`NymTransport::circuit_breaker`]. It's disabled by default.
+    pub fn with_circuit_breaker(self, config: CircuitBreakerConfig) -> Result<Self, Error> {
+        config.validate()?;
+        self.mixnet_options_tx
+            .send_modify(|options| options.circuit_breaker = Some(config));
+        Ok(self)
+    }
+
+    /// Probe the latency of every established connection at the given interval and
+    /// return self. The remote peer echoes the probe's timestamp along with its own, which
+    /// gives estimates of the one-way mixnet delays and the clock offset between the peers;
+    /// these are available through [`NymTransport::stats`]. The probes also rate the
+    /// [quality](TransportStats::quality) of each connection, see
+    /// [`NymTransport::subscribe_quality_updates`].
+    pub fn with_latency_probing(mut self, interval: Duration) -> Self {
+        let mut probe = interval_at(Instant::now() + interval, interval);
+        probe.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        self.latency_probe = Some(probe);
+        self
+    }
+
+    /// Report the traffic of every established connection over each interval of the given
+    /// length, as a [`ConnectionEvent::Stats`] through
+    /// [`MixnetConnection::connection_events`], and return self. Monitoring agents get the
+    /// deltas of each connection without polling [`NymTransport::stats`] and diffing
+    /// snapshots. The round-trip time is only known with
+    /// [latency probing](NymTransport::with_latency_probing).
+    pub fn with_connection_stats(mut self, interval: Duration) -> Self {
+        let mut connection_stats = interval_at(Instant::now() + interval, interval);
+        connection_stats.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        self.connection_stats = Some(connection_stats);
+        self
+    }
+
+    /// Send a keepalive on every connection at the given interval, and drop connections
+    /// whose remote peer leaves `max_missed` of them in a row unanswered, and return self.
+    /// Connections over the mixnet have no underlying TCP connection whose failure would
+    /// tell that the remote peer went away, so without keepalives a dead connection is only
+    /// noticed once the application gives up waiting on it. A dropped connection fails
+    /// with [`Error::KeepaliveTimeout`], which closes it in the swarm. Keepalives are
+    /// pings, which every peer answers, so the remote peer needn't enable them too.
+    pub fn with_keepalive(mut self, interval: Duration, max_missed: u32) -> Result<Self, Error> {
+        if max_missed == 0 {
+            return Err(Error::InvalidConfig(
+                "max missed keepalives must not be zero",
+            ));
+        }
+        let mut keepalive = interval_at(Instant::now() + interval, interval);
+        keepalive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        self.keepalive = Some(keepalive);
+        self.max_missed_keepalives = max_missed;
+        Ok(self)
+    }
+
+    /// Drop connections that haven't negotiated a protocol on any substream within the
+    /// given timeout after the handshake, along with any data buffered for them, and
+    /// return self. This evicts peers that complete the handshake but then stall, tying
+    /// up resources. Evictions are counted in [`TransportStats::upgrade_timeouts`].
+    pub fn with_upgrade_timeout(mut self, upgrade_timeout: Duration) -> Self {
+        // connections are checked a few times per timeout, so they're dropped soon after
+        // it expires
+        let period = (upgrade_timeout / 4).max(Duration::from_millis(10));
+        let mut check = interval_at(Instant::now() + period, period);
+        check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        self.upgrade_timeout = Some(upgrade_timeout);
+        self.upgrade_check = Some(check);
+        self
+    }
+
+    /// Verify inbound connection requests on the given number of worker tasks, with up to
+    /// `max_queued` requests waiting for a worker, and return self. A burst of handshakes
+    /// then doesn't hold up the messages of established connections; requests beyond the
+    /// queue are declined with [`DenialReason::RateLimited`]. There are 4 workers and up to
+    /// 256 queued requests by default.
+    pub fn with_handshake_workers(
+        mut self,
+        workers: usize,
+        max_queued: usize,
+    ) -> Result<Self, Error> {
+        check_handshake_workers(workers)?;
+        self.handshakes = HandshakePool::new(workers, max_queued);
+        Ok(self)
+    }
+
+    /// Handshake with at most `max_concurrent` peers at once when dialing, with up to
+    /// `max_queued` further dials waiting for one of them to finish, and return self. A
+    /// burst of dials then doesn't flood the Nym client's queues. Waiting dials go in the
+    /// order they were made, and their handshake timeout starts once it's their turn;
+    /// dials beyond the queue fail right away with [`Error::DialQueueFull`]. How many wait
+    /// is available through [`TransportStats::queued_dials`]. There are up to 64 dials in
+    /// flight and 256 waiting by default.
+    pub fn with_max_concurrent_dials(
+        mut self,
+        max_concurrent: usize,
+        max_queued: usize,
+    ) -> Result<Self, Error> {
+        check_max_concurrent_dials(max_concurrent)?;
+        self.dial_slots = DialSlots::new(
+            max_concurrent,
+            max_queued,
+            self.stats.queued_dials_counter(),
+        );
+        Ok(self)
+    }
+
+    /// Keep a connection to each of the [`PinnedPeers`], and return self. A pinned peer
+    /// without a live connection is dialed, and re-dialed with the given backoff until a
+    /// dial succeeds; the connection is then handed to the swarm as an incoming one, as a
+    /// transport can't report outbound connections it dialed by itself. A connection is
+    /// live until the swarm drops it. Each attempt is reported through
+    /// [`NymTransport::subscribe_pinned_peer_events`].
+    pub fn with_peer_pinning(mut self, backoff: RedialBackoff) -> Self {
+        let period = (backoff.initial / 2).max(Duration::from_millis(10));
+        let mut check = interval_at(Instant::now(), period);
+        check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        self.redial_backoff = backoff;
+        self.redial_check = Some(check);
+        self
+    }
+
+    /// Returns the set of pinned peers, which can be kept after the transport is moved into
+    /// a swarm; see [`NymTransport::with_peer_pinning`].
+    pub fn pinned_peers(&self) -> PinnedPeers {
+        self.pinned_peers.clone()
+    }
+
+    /// Returns a channel of events about re-dialing pinned peers. Only the latest
+    /// subscriber receives them.
+    pub fn subscribe_pinned_peer_events(&mut self) -> UnboundedReceiver<PinnedPeerEvent> {
+        let (events_tx, events_rx) = unbounded_channel();
+        self.pinned_events_tx = Some(events_tx);
+        events_rx
+    }
+
+    /// Returns an acceptor that takes the inbound connections the given match selects
+    /// before they're handed to the swarm, eg. to speak a custom protocol over the same Nym
+    /// address as libp2p. Acceptors are tried in the order they were created; once one is
+    /// dropped, the connections it would have taken go to the swarm again.
+    pub fn acceptor(&mut self, selects: AcceptorMatch) -> Acceptor {
+        let (upgrades_tx, upgrades_rx) = unbounded_channel();
+        self.acceptors.push((selects, upgrades_tx));
+        Acceptor::new(upgrades_rx)
+    }
+
+    /// Answer echo requests, at up to the given rate if any, and return self. Anyone can
+    /// then measure the latency to our Nym address with [`NymTransport::echo`] without
+    /// establishing a libp2p connection, eg. directories listing healthy nodes. The reply
+    /// goes to the address named in the request, so a rate limit keeps us from being used
+    /// to flood others.
+    pub fn with_echo_responder(mut self, max_replies_per_sec: Option<u32>) -> Self {
+        self.echo_responder = Some((RateLimiter::new(), max_replies_per_sec));
+        self
+    }
+
+    /// Sends an echo request to the Nym address, and returns a future of the latency
+    /// measured once the reply arrives, or `Error::EchoTimeout` if it doesn't within the
+    /// handshake timeout, eg. because the echo responder isn't enabled there. The transport
+    /// needs to be polled to receive the reply.
+    pub fn echo(
+        &mut self,
+        address: &Multiaddr,
+    ) -> Result<impl Future<Output = Result<EchoResult, Error>> + Send + 'static, Error> {
+        let (recipient, _) = multiaddress_to_nym_address(address.clone())?;
+        self.pending_echoes
+            .retain(|_, reply_tx| !reply_tx.is_closed());
+
+        let msg = EchoRequestMessage::new(self.self_address);
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.pending_echoes.insert(msg.nonce, reply_tx);
+        self.outbound_tx
+            .send(OutboundMessage::new(Message::EchoRequest(msg), recipient))
+            .map_err(Error::from)?;
+
+        let echo_timeout = self.config_rx.borrow().handshake_timeout;
+        Ok(async move {
+            match timeout(echo_timeout, reply_rx).await {
+                Ok(res) => res.map_err(|_| Error::RecvError),
+                Err(_) => Err(Error::EchoTimeout),
+            }
+        })
+    }
+
+    /// Check that our Nym address is reachable through the mixnet at the given interval,
+    /// and return self. On each tick, a connected peer (taking turns) is asked to send a
+    /// message to our address; the probe fails if it hasn't arrived by the next tick.
+    /// The result is available through [`TransportStats::reachability`], and a warning
+    /// is logged when our address stops being reachable.
+    pub fn with_reachability_probing(mut self, interval: Duration) -> Self {
+        let mut probe = interval_at(Instant::now() + interval, interval);
+        probe.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        self.reachability_probe = Some(probe);
+        self
+    }
+
+    /// Ask a connected peer to confirm that our Nym address is reachable, by sending a
+    /// message to it. The result is available through [`TransportStats::reachability`]
+    /// once the message arrives, or once the next probe is sent if it doesn't.
+    pub fn probe_reachability(&mut self) -> Result<(), Error> {
+        // the previous probe wasn't answered in time
+        if self.pending_reachability.take().is_some() {
+            self.record_reachability(false);
+        }
+
+        // the request carries our address, and anonymous connections don't reveal either
+        // peer's
+        let direct: Vec<_> = self
+            .connections
+            .iter()
+            .filter(|(_, handle)| handle.remote_recipient.route() == Route::Direct)
+            .collect();
+        if direct.is_empty() {
+            return Err(Error::NoConnectionForReachabilityRequest);
+        }
+        let index = self.reachability_probes_sent % direct.len();
+        self.reachability_probes_sent = self.reachability_probes_sent.wrapping_add(1);
+        let (id, handle) = direct[index];
+
+        let request = ReachabilityRequestMessage::new(id.clone(), self.self_address);
+        let nonce = request.nonce;
+        self.outbound_tx
+            .send(OutboundMessage::new(
+                Message::ReachabilityRequest(request),
+                handle.remote_recipient.get(),
+            ))
+            .map_err(Error::from)?;
+        self.pending_reachability = Some(nonce);
+        Ok(())
+    }
+
+    /// Set a filter which sees every inbound message before it touches any connection
+    /// state, and can drop or tag it, and return self.
+    /// Tagged messages are counted in [`TransportStats::filter_tags`].
+    pub fn with_inbound_filter<F: InboundFilter>(mut self, filter: F) -> Self {
+        self.inbound_filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Set a sink which records every inbound and outbound connection attempt, with the
+    /// remote peer, its outcome and timestamps, eg. a [`crate::audit::FileAuditSink`],
+    /// and return self.
+    pub fn with_audit_log<S: AuditSink>(mut self, sink: S) -> Self {
+        self.audit_log = Some(AuditLog::new(sink));
+        self
+    }
+
+    /// Record every wire message sent and received through the mixnet to a rotating
+    /// journal file, so protocol issues between peers can be diagnosed offline, and return
+    /// self. Read it back with [`JournalReader`](crate::journal::JournalReader). Set
+    /// [`JournalConfig::headers_only`] to leave application data out of the journal.
+    pub fn with_journal(self, config: JournalConfig) -> Result<Self, Error> {
+        let journal = Journal::open(config)?;
+        self.mixnet_options_tx
+            .send_modify(|options| options.journal = Some(journal));
+        Ok(self)
+    }
+
+    /// Returns a handle for writing to the mixnet through this transport's Nym client,
+    /// eg. to [broadcast](MixnetConnection::broadcast) to arbitrary Nym addresses.
+    pub fn mixnet_connection(&self) -> MixnetConnection {
+        self.mixnet_connection.clone()
+    }
+
+    /// Broadcasts the payload to the remote peers of all established connections,
+    /// serializing it only once. Peers receive it through [`NymTransport::subscribe_broadcasts`].
+    pub fn broadcast(&self, payload: Vec<u8>) -> Result<(), Error> {
+        // broadcasts go to Nym addresses, which anonymous connections don't reveal
+        let mut recipients: Vec<Recipient> = self
+            .connections
+            .values()
+            .filter(|handle| handle.remote_recipient.route() == Route::Direct)
+            .map(|handle| handle.remote_recipient.get())
+            .collect();
+        recipients.sort_by_key(|recipient| recipient.to_bytes());
+        recipients.dedup();
+        self.mixnet_connection.broadcast(recipients, payload)
+    }
+
+    /// Returns a channel of the payloads of broadcasts we receive. Only the latest
+    /// subscriber receives them; broadcasts received without a subscriber are dropped.
+    pub fn subscribe_broadcasts(&mut self) -> UnboundedReceiver<Vec<u8>> {
+        let (broadcast_tx, broadcast_rx) = unbounded_channel();
+        self.broadcast_tx = Some(broadcast_tx);
+        broadcast_rx
+    }
+
+    /// Sends the bytes to the Nym address as a datagram, outside of any connection, eg.
+    /// for connectionless fire-and-forget messaging alongside libp2p connections over the
+    /// same Nym client. Like UDP, datagrams aren't acknowledged, may be lost, and may
+    /// arrive out of order. The recipient's transport receives them through
+    /// [`NymTransport::subscribe_datagrams`]; peers from before datagrams existed drop them.
+    pub fn send_datagram(&self, recipient: Recipient, datagram: Vec<u8>) -> Result<(), Error> {
+        let message = OutboundMessage::datagram(recipient, datagram);
+        self.outbound_tx.send(message).map_err(Error::from)
+    }
+
+    /// Returns a channel of the datagrams we receive. Only the latest subscriber receives
+    /// them; datagrams received without a subscriber are dropped.
+    pub fn subscribe_datagrams(&mut self) -> UnboundedReceiver<Vec<u8>> {
+        let (datagram_tx, datagram_rx) = unbounded_channel();
+        self.datagram_tx = Some(datagram_tx);
+        datagram_rx
+    }
+
+    /// Returns a channel of the quality of connections, updated as latency probes are
+    /// answered and before each round of probes, if latency probing is enabled. Only the
+    /// latest subscriber receives them.
+    pub fn subscribe_quality_updates(&mut self) -> UnboundedReceiver<(PeerId, ConnectionQuality)> {
+        let (quality_tx, quality_rx) = unbounded_channel();
+        self.quality_tx = Some(quality_tx);
+        quality_rx
+    }
+
+    /// Returns a handle to the memory budget of the transport's buffers, which reports how
+    /// much memory they hold and can be kept after the transport is moved into a swarm.
+    pub fn memory_budget(&self) -> MemoryBudget {
+        self.memory_budget.clone()
+    }
+
+    /// Returns a handle to the transport's statistics, which can be kept after the
+    /// transport is moved into a swarm.
+    pub fn stats(&self) -> TransportStats {
+        self.stats.clone()
+    }
+
+    /// Returns our current Nym address.
+    pub fn nym_address(&self) -> Recipient {
+        self.self_address
+    }
+
+    /// Log what the transport does within the span and return self, eg. one naming the
+    /// instance, if the process hosts several transports; see
+    /// [`TransportRegistry`](crate::registry::TransportRegistry). By default that's the
+    /// span the transport was created in. The mixnet task always logs within that one.
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = span;
+        self
+    }
+
+    /// Shuts the transport down the next time it's polled: every connection is closed,
+    /// telling the remote peers, pending dials fail, the listener is closed, and, unless
+    /// the Nym client is shared with other transports, the mixnet task stops once it has
+    /// written what's been sent so far. New dials fail with [`Error::TransportShutDown`].
+    pub fn shutdown(&self) {
+        self.shutdown_handle().shutdown();
+    }
+
+    /// Returns a handle for shutting the transport down, which can be kept after the
+    /// transport is moved into a swarm.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::new(self.shutdown_tx.clone())
+    }
+
+    /// Returns true once the transport was shut down.
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down
+    }
+
+    /// shut_down_now closes every connection and pending dial, stops the mixnet task
+    /// unless it's shared, and returns the event closing the listener.
+    fn shut_down_now(&mut self) -> TransportEvent<Upgrade, Error> {
+        info!("shutting down");
+        self.shut_down = true;
+        for (id, handle) in std::mem::take(&mut self.connections) {
+            self.send_connection_close(&id, &handle, ShutdownReason::Shutdown);
+            self.stats.message_capture().unregister_connection(&id);
+            self.mixnet_connection.events.send(ConnectionEvent::Closed {
+                peer_id: handle.peer_id,
+                reason: CloseReason::Shutdown,
+            });
+        }
+        self.message_queues.clear();
+        self.compact_refs.clear();
+        self.pending_dials.clear();
+        self.parked.clear();
+        self.preconnects.clear();
+        if self.tenant.is_none() {
+            self.mixnet_options_tx
+                .send_modify(|options| options.shutdown = true);
+        }
+        TransportEvent::ListenerClosed {
+            listener_id: self.listener_id,
+            reason: Ok(()),
+        }
+    }
+
+    /// Returns a record of our current Nym address, signed with our libp2p key and valid
+    /// for `ttl`, for advertising the address to other peers, eg. over a DHT or gossip.
+    pub fn signed_address_record(&self, ttl: Duration) -> Result<SignedNymAddressRecord, Error> {
+        SignedNymAddressRecord::new(&self.keypair, self.self_address, ttl)
+    }
+
+    /// Returns what the mixnet backend knows about its connection to the mixnet: the
+    /// gateway it uses and, for in-process Nym clients, the estimated topology epoch and
+    /// client version. This is useful for correlating transport issues with conditions on
+    /// the mixnet side. It's updated when the backend reconnects or the address rotates.
+    pub fn mixnet_info(&self) -> MixnetInfo {
+        self.mixnet_info_rx.borrow().clone()
+    }
+
+    /// Returns a handle to the circuit breaker, which reports whether the mixnet appears to
+    /// be down and can be kept after the transport is moved into a swarm; see
+    /// [`NymTransport::with_circuit_breaker`].
+    pub fn circuit_breaker(&self) -> CircuitBreaker {
+        self.breaker.clone()
+    }
+
+    /// Returns a handle for making the transport fail on purpose, to test how the
+    /// application recovers. It can be kept after the transport is moved into a swarm.
+    #[cfg(feature = "testing")]
+    pub fn error_injector(&self) -> ErrorInjector {
+        self.injector.clone()
+    }
+
+    /// new_maybe_with_notify_inbound connects the backend the endpoint's scheme selects.
+    async fn new_maybe_with_notify_inbound(
+        endpoint: NymEndpoint,
+        keypair: Keypair,
+        notify_inbound_tx: Option<UnboundedSender<()>>,
+        timeout: Option<Duration>,
+        sender_workers: usize,
+    ) -> Result<Self, Error> {
+        match &endpoint {
+            NymEndpoint::Websocket(uri) => {
+                let backend = WebsocketBackend::connect(uri, sender_workers).await?;
+                Self::new_from_backend(backend, keypair, notify_inbound_tx, timeout, None)
+            }
+            #[cfg(feature = "sdk")]
+            NymEndpoint::Sdk { gateway } => {
+                let backend = match gateway {
+                    Some(gateway) => SdkBackend::connect_with_gateway(gateway.clone()).await?,
+                    None => SdkBackend::connect_new().await?,
+                };
+                Self::new_from_backend(backend, keypair, notify_inbound_tx, timeout, None)
+            }
+            #[cfg(not(feature = "sdk"))]
+            NymEndpoint::Sdk { .. } => Err(Error::InvalidEndpoint(format!(
+                "{endpoint}: sdk:// requires the sdk feature"
+            ))),
+            NymEndpoint::Mock(name) => {
+                let backend = MockMixnet::named(name).new_backend();
+                Self::new_from_backend(backend, keypair, notify_inbound_tx, timeout, None)
+            }
+        }
+    }
+
+    fn new_from_backend<B: MixnetBackend>(
+        backend: B,
+        keypair: Keypair,
+        notify_inbound_tx: Option<UnboundedSender<()>>,
+        timeout: Option<Duration>,
+        rotation: Option<AddressRotation<B>>,
+    ) -> Result<Self, Error> {
+        let channels = initialize_mixnet_with_rotation(backend, notify_inbound_tx, rotation);
+        Self::new_from_channels(channels, keypair, timeout, None)
+    }
+
+    /// new_from_channels returns a transport using the given mixnet task. If the task is
+    /// shared with other transports, `tenant` is its registration with the router.
+    pub(crate) fn new_from_channels(
+        channels: MixnetChannels,
+        keypair: Keypair,
+        timeout: Option<Duration>,
+        tenant: Option<TenantRegistration>,
+    ) -> Result<Self, Error> {
+        let MixnetChannels {
+            self_address,
+            inbound_rx,
+            inbound_backlog,
+            memory_budget,
+            message_capture,
+            gateway_outcomes,
+            reassembly_evictions,
+            unknown_message_types,
+            send_path_timings,
+            inbound_framings,
+            mixnet_traffic,
+            breaker,
+            outbound_tx,
+            broadcast_tx,
+            address_rx,
+            options_tx,
+            status_rx,
+            info_rx,
+            injector,
+        } = channels;
+        let listener_key = tenant.as_ref().map(|_| PeerId::from(keypair.public()));
+        let listen_addr = listen_multiaddress(self_address, listener_key)?;
+        let listener_id = ListenerId::new();
+
+        let (poll_tx, poll_rx) = unbounded_channel::<TransportEvent<Upgrade, Error>>();
+        let (preconnect_tx, preconnect_rx) = unbounded_channel();
+        let (closed_tx, closed_rx) = unbounded_channel();
+        let (dials_done_tx, dials_done_rx) = unbounded_channel();
+        let (shutdown_tx, shutdown_rx) = unbounded_channel();
+
+        poll_tx
+            .send(TransportEvent::NewAddress {
+                listener_id,
+                listen_addr: listen_addr.clone(),
+            })
+            .map_err(|_| Error::SendErrorTransportEvent)?;
+
+        let inbound_stream = UnboundedReceiverStream::new(inbound_rx);
+        options_tx.send_modify(|options| {
+            options.inbound_watermarks = Some((
+                DEFAULT_INBOUND_HIGH_WATERMARK,
+                DEFAULT_INBOUND_HIGH_WATERMARK / 2,
+            ))
+        });
+        let handshake_timeout =
+            timeout.unwrap_or_else(|| Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT_SECS));
+        let (config_handle, config_rx) = ConfigHandle::new(RuntimeConfig {
+            handshake_timeout,
+            ..Default::default()
+        });
+
+        let mixnet_connection =
+            MixnetConnection::new(outbound_tx.clone(), broadcast_tx, status_rx.clone());
+        let stats = TransportStats::default()
+            .with_message_capture(message_capture)
+            .with_gateway_outcomes(gateway_outcomes)
+            .with_reassembly_evictions(reassembly_evictions)
+            .with_unknown_message_types(unknown_message_types)
+            .with_send_path_timings(send_path_timings)
+            .with_inbound_framings(inbound_framings)
+            .with_mixnet_traffic(mixnet_traffic);
+        let dial_slots = DialSlots::new(
+            DEFAULT_MAX_CONCURRENT_DIALS,
+            DEFAULT_MAX_QUEUED_DIALS,
+            stats.queued_dials_counter(),
+        );
+
+        Ok(Self {
+            self_address,
+            listen_addr,
+            listener_id,
+            keypair,
+            connections: HashMap::new(),
+            address_epoch: 0,
+            pending_dials: HashMap::new(),
+            message_queues: HashMap::new(),
+            inbound_stream,
+            inbound_backlog,
+            memory_budget,
+            outbound_tx,
+            address_rx,
+            mixnet_options_tx: options_tx,
+            tenant,
+            mixnet_status_rx: status_rx,
+            mixnet_info_rx: info_rx,
+            breaker,
+            dial_queue_timeout: None,
+            default_reply_surbs: None,
+            max_message_size: None,
+            outbound_capacity: Arc::new(Semaphore::new(DEFAULT_OUTBOUND_CAPACITY)),
+            extensions: 0,
+            user_agent: None,
+            clock_skew_threshold: None,
+            clock_skew_correction: false,
+            compact_refs: HashMap::new(),
+            next_compact_ref: 0,
+            dial_options: DialOptionsHandle::default(),
+            preconnects: Vec::new(),
+            parked: HashMap::new(),
+            preconnect_rx,
+            preconnect_tx,
+            shutdown_rx,
+            shutdown_tx,
+            shut_down: false,
+            span: Span::current(),
+            mixnet_connection,
+            broadcast_tx: None,
+            datagram_tx: None,
+            quality_tx: None,
+            closed_rx,
+            closed_tx,
+            dials_done_rx,
+            dials_done_tx,
+            poll_rx,
+            poll_tx,
+            waker: None,
+            config_rx,
+            config_handle,
+            inbound_limiter: RateLimiter::new(),
+            handshakes: HandshakePool::new(
+                DEFAULT_HANDSHAKE_WORKERS,
+                DEFAULT_MAX_QUEUED_HANDSHAKES,
+            ),
+            dial_slots,
+            latency_probe: None,
+            connection_stats: None,
+            keepalive: None,
+            max_missed_keepalives: DEFAULT_KEEPALIVE_MAX_MISSED,
+            reachability_probe: None,
+            upgrade_timeout: None,
+            upgrade_check: None,
+            pinned_peers: PinnedPeers::default(),
+            redial_backoff: RedialBackoff::default(),
+            redial_check: None,
+            redials: HashMap::new(),
+            close_retry: None,
+            unacked_closes: HashMap::new(),
+            acked_closes: HashMap::new(),
+            paused: false,
+            pinned_events_tx: None,
+            acceptors: vec![],
+            pending_reachability: None,
+            reachability_probes_sent: 0,
+            echo_responder: None,
+            pending_echoes: HashMap::new(),
+            stats,
+            inbound_filter: None,
+            audit_log: None,
+            injector,
+        })
+    }
+
+    /// handle_address_event updates our address after a rotation or failover and returns the
+    /// corresponding TransportEvent.
+    fn handle_address_event(
+        &mut self,
+        event: AddressEvent,
+    ) -> Result<TransportEvent<Upgrade, Error>, Error> {
+        match event {
+            AddressEvent::New(address) => {
+                let listen_addr = self.listen_multiaddress(address)?;
+                self.send_address_updates(address)?;
+                self.self_address = address;
+                self.listen_addr = listen_addr.clone();
+                Ok(TransportEvent::NewAddress {
+                    listener_id: self.listener_id,
+                    listen_addr,
+                })
+            }
+            AddressEvent::Expired(address) => Ok(TransportEvent::AddressExpired {
+                listener_id: self.listener_id,
+                listen_addr: self.listen_multiaddress(address)?,
+            }),
+            AddressEvent::Changed { old, new } => {
+                let old_addr = self.listen_multiaddress(old)?;
+                let listen_addr = self.listen_multiaddress(new)?;
+                self.send_address_updates(new)?;
+                self.self_address = new;
+                self.listen_addr = listen_addr.clone();
+
+                // the old address expires right away; this is returned by the next poll
+                self.poll_tx
+                    .send(TransportEvent::AddressExpired {
+                        listener_id: self.listener_id,
+                        listen_addr: old_addr,
+                    })
+                    .map_err(|_| Error::SendErrorTransportEvent)?;
+                Ok(TransportEvent::NewAddress {
+                    listener_id: self.listener_id,
+                    listen_addr,
+                })
+            }
+        }
+    }
+
+    pub(crate) fn peer_id(&self) -> PeerId {
+        PeerId::from_public_key(&self.keypair.public())
+    }
+
+    /// listen_multiaddress returns the address we listen on at the given Nym address. If we
+    /// share the Nym client, it includes our listener key.
+    fn listen_multiaddress(&self, address: Recipient) -> Result<Multiaddr, Error> {
+        let listener_key = self.tenant.as_ref().map(|_| self.peer_id());
+        listen_multiaddress(address, listener_key)
+    }
+
+    fn handle_message_queue_on_connection_initiation(
+        &mut self,
+        id: &ConnectionId,
+    ) -> Result<(), Error> {
+        debug!("handle_message_queue_on_connection_initiation");
+        let Some(handle) = self.connections.get(id) else {
+            // this should not happen
+            return Err(Error::NoConnectionForTransportMessage);
+        };
+
+        match self.message_queues.get_mut(id) {
+            Some(queue) => {
+                // update expected nonce
+                queue.set_connection_message_received();
+
+                // push pending inbound some messages in this case
+                while let Some(msg) = queue.pop() {
+                    debug!(
+                        "popped queued message with nonce {} for connection",
+                        msg.nonce
+                    );
+                    handle.deliver(&msg.message)?;
+                }
+            }
+            None => {
+                // no queue exists for this connection, create one
+                let queue = MessageQueue::new();
+                self.message_queues.insert(id.clone(), queue);
+                let queue = self.message_queues.get_mut(id).unwrap();
+                queue.set_connection_message_received();
+            }
+        };
+
+        debug!("returning from handle_message_queue_on_connection_initiation");
+        Ok(())
+    }
+
+    // handle_connection_response resolves the pending connection corresponding to the response
+    // (if there is one) into a Connection.
+    fn handle_connection_response(&mut self, msg: &ConnectionMessage) -> Result<(), Error> {
+        if self.connections.contains_key(&msg.id) {
+            return Err(Error::ConnectionAlreadyEstablished);
+        }
+
+        if let Some(pending_conn) = self.pending_dials.remove(&msg.id) {
+            // resolve connection and put into pending_conn channel
+            // the listener only confirms extensions we offered, but don't trust it
+            let mut extensions = msg.extensions & self.extensions;
+            let remote_ref = match msg.connection_ref {
+                Some(reference) if extensions & extension::COMPACT_ID != 0 => Some(reference),
+                _ => {
+                    extensions &= !extension::COMPACT_ID;
+                    self.forget_compact_ref(&msg.id);
+                    None
+                }
+            };
+            let clock_offset = match msg.timestamps[..] {
+                [ping_sent_at, ping_received_at, pong_sent_at]
+                    if extensions & extension::CLOCK != 0 =>
+                {
+                    let sample = LatencySample {
+                        ping_sent_at,
+                        ping_received_at,
+                        pong_sent_at,
+                        pong_received_at: unix_micros(),
+                    };
+                    self.record_clock_offset(msg.peer_id, sample.clock_offset_micros())
+                }
+                _ => None,
+            };
+            let remote_user_agent = match &msg.user_agent {
+                Some(user_agent) if extensions & extension::USER_AGENT != 0 => {
+                    Some(user_agent.clone())
+                }
+                _ => None,
+            };
+            self.stats.record_user_agent(remote_user_agent.as_deref());
+            let (conn, handle) = self.create_connection_types(
+                msg.peer_id,
+                pending_conn.remote_recipient,
+                pending_conn.route,
+                msg.id.clone(),
+                extensions,
+                remote_ref,
+            );
+            let conn = conn
+                .with_clock_offset(clock_offset)
+                .with_remote_user_agent(remote_user_agent);
+
+            self.connections.insert(msg.id.clone(), handle);
+            self.handle_message_queue_on_connection_initiation(&msg.id)?;
+            debug!(
+                "established outbound connection with {}: {}",
+                Redacted(&msg.peer_id, self.redact_logs()),
+                conn.negotiated()
+            );
+
+            pending_conn
+                .connection_tx
+                .send(Ok(conn))
+                .map_err(|_| Error::ConnectionSendError)?;
+
+            if let Some(waker) = self.waker.take() {
+                waker.wake();
+            }
+
+            Ok(())
+        } else {
+            Err(Error::NoConnectionForResponse)
+        }
+    }
+
+    /// handle_connection_request handles an incoming connection request the handshake
+    /// workers verified, and completes the upgrade into a Connection. The connection
+    /// response is returned rather than sent, as the swarm may still deny the connection.
+    fn handle_connection_request(
+        &mut self,
+        msg: &ConnectionMessage,
+        verified: Result<(), Error>,
+    ) -> Result<(Connection, HandshakeReply), Error> {
+        match verified {
+            Err(Error::InboundConnectionRejected(reason)) => {
+                return self.deny_connection(msg, reason)
+            }
+            Err(e) => return Err(e),
+            Ok(()) => {}
+        }
+
+        // ensure we don't already have a conn with the same id
+        if self.connections.get(&msg.id).is_some() {
+            return Err(Error::ConnectionIDExists);
+        }
+        let (recipient, route) = self.reply_route(msg)?;
+
+        let max_per_sec = self.config_rx.borrow().max_inbound_connections_per_sec;
+        if !self.inbound_limiter.try_acquire(max_per_sec) {
+            return self.deny_connection(msg, DenialReason::RateLimited);
+        }
+
+        let received_at = unix_micros();
+        let mut extensions = msg.extensions & self.extensions;
+        let (clock_offset, timestamps) = match msg.timestamps.first() {
+            Some(&sent_at) if extensions & extension::CLOCK != 0 => {
+                // the time the request spent in the mixnet can't be told apart from the
+                // offset, so this understates it by that much; the dialer's estimate,
+                // from the round trip, is better
+                let offset =
+                    self.record_clock_offset(msg.peer_id, sent_at as i64 - received_at as i64);
+                (offset, vec![sent_at, received_at, unix_micros()])
+            }
+            _ => {
+                extensions &= !extension::CLOCK;
+                (None, vec![])
+            }
+        };
+        let connection_ref = match msg.connection_ref {
+            Some(_) if extensions & extension::COMPACT_ID != 0 => {
+                Some(self.assign_compact_ref(&msg.id))
+            }
+            _ => {
+                extensions &= !extension::COMPACT_ID;
+                None
+            }
+        };
+        // the dialer announced its user agent even if we don't announce ours
+        self.stats.record_user_agent(msg.user_agent.as_deref());
+        let (conn, handle) = self.create_connection_types(
+            msg.peer_id,
+            recipient,
+            route,
+            msg.id.clone(),
+            extensions,
+            connection_ref.and(msg.connection_ref),
+        );
+        let conn = conn
+            .with_handshake_payload(msg.handshake_payload.clone())
+            .with_clock_offset(clock_offset)
+            .with_remote_user_agent(msg.user_agent.clone());
+        self.connections.insert(msg.id.clone(), handle);
+        self.handle_message_queue_on_connection_initiation(&msg.id)?;
+
+        let resp = ConnectionMessage {
+            peer_id: self.peer_id(),
+            recipient: None,
+            target: None,
+            id: msg.id.clone(),
+            extensions,
+            handshake_payload: None,
+            timestamps,
+            connection_ref,
+            user_agent: self
+                .user_agent
+                .clone()
+                .filter(|_| extensions & extension::USER_AGENT != 0),
+            reply_tag: None,
+        };
+        let reply = HandshakeReply {
+            outbound_tx: self.outbound_tx.clone(),
+            recipient,
+            route,
+            response: resp,
+        };
+
+        debug!(
+            "established inbound connection with {}: {}",
+            Redacted(&msg.peer_id, self.redact_logs()),
+            conn.negotiated()
+        );
+        Ok((conn, reply))
+    }
+
+    /// record_clock_offset records the offset of a peer's clock estimated in a handshake,
+    /// and warns if it's over the threshold. It returns the offset to correct the
+    /// connection's message ages by, if they're corrected.
+    fn record_clock_offset(&self, peer_id: PeerId, offset_micros: i64) -> Option<i64> {
+        let threshold = self.clock_skew_threshold?;
+        self.stats.record_clock_offset(peer_id, offset_micros);
+        if offset_micros.unsigned_abs() > threshold.as_micros() as u64 {
+            warn!(
+                "clock of {} appears to be off by {}ms",
+                Redacted(&peer_id, self.redact_logs()),
+                offset_micros / 1000
+            );
+            self.stats.record_clock_skew();
+            self.mixnet_connection
+                .events
+                .send(ConnectionEvent::ClockSkewed {
+                    peer_id,
+                    offset_micros,
+                });
+        }
+        self.clock_skew_correction.then_some(offset_micros)
+    }
+
+    /// reply_route returns where to send the messages of the connection a request opens:
+    /// to the dialer's address, or as replies to its sender tag if it dialed anonymously,
+    /// in which case our own address stands in for the dialer's.
+    fn reply_route(&self, msg: &ConnectionMessage) -> Result<(Recipient, Route), Error> {
+        match (msg.recipient, msg.reply_tag) {
+            (Some(recipient), _) => Ok((recipient, Route::Direct)),
+            (None, Some(sender_tag)) => Ok((self.self_address, Route::Reply(sender_tag))),
+            (None, None) => Err(Error::NoneRecipientInConnectionRequest),
+        }
+    }
+
+    /// deny_connection tells the dialer why its connection request was declined, so its
+    /// dial fails with the reason instead of timing out.
+    fn deny_connection<T>(
+        &mut self,
+        msg: &ConnectionMessage,
+        reason: DenialReason,
+    ) -> Result<T, Error> {
+        let (recipient, route) = self.reply_route(msg)?;
+        let denied = ConnectionDeniedMessage {
+            id: msg.id.clone(),
+            reason,
+        };
+        self.outbound_tx
+            .send(
+                OutboundMessage::new(Message::ConnectionDenied(denied), recipient)
+                    .with_route(route),
+            )
+            .map_err(Error::from)?;
+
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+
+        Err(Error::InboundConnectionRejected(reason))
+    }
+
+    /// handle_connection_denied fails the pending dial the listener declined.
+    fn handle_connection_denied(&mut self, msg: ConnectionDeniedMessage) -> Result<(), Error> {
+        let Some(pending_conn) = self.pending_dials.remove(&msg.id) else {
+            return Err(Error::NoConnectionForResponse);
+        };
+        self.forget_compact_ref(&msg.id);
+
+        pending_conn
+            .connection_tx
+            .send(Err(Error::ConnectionDenied(msg.reason)))
+            .map_err(|_| Error::ConnectionSendError)
+    }
+
+    fn handle_transport_message(&mut self, msg: TransportMessage) -> Result<(), Error> {
+        let queue = match self.message_queues.get_mut(&msg.id) {
+            Some(queue) => queue,
+            None => {
+                // no queue exists for this connection, create one
+                let queue = MessageQueue::new();
+                self.message_queues.insert(msg.id.clone(), queue);
+                self.message_queues.get_mut(&msg.id).unwrap()
+            }
+        };
+
+        queue.print_nonces();
+
+        let nonce = msg.nonce;
+        let Some(msg) = queue.try_push(msg) else {
+            // don't push the message yet, it's been queued
+            debug!("message with nonce {} queued for connection", nonce);
+            return Ok(());
+        };
+
+        let Some(handle) = self.connections.get(&msg.id) else {
+            return Err(Error::NoConnectionForTransportMessage);
+        };
+
+        // send original message
+        debug!(
+            "sending original message with nonce {} for connection",
+            nonce
+        );
+        handle.deliver(&msg.message)?;
+
+        // try to pop queued messages and send them on inbound channel
+        while let Some(msg) = queue.pop() {
+            debug!(
+                "popped queued message with nonce {} for connection",
+                msg.nonce
+            );
+            handle.deliver(&msg.message)?;
+        }
+
+        if let Some(waker) = self.waker.clone().take() {
+            waker.wake();
+        }
+
+        Ok(())
+    }
+
+    fn create_connection_types(
+        &self,
+        remote_peer_id: PeerId,
+        recipient: Recipient,
+        route: Route,
+        id: ConnectionId,
+        extensions: u8,
+        remote_ref: Option<u64>,
+    ) -> (Connection, ConnectionHandle) {
+        let (inbound_tx, inbound_rx) = unbounded_channel::<SubstreamMessage>();
+        self.stats
+            .message_capture()
+            .register_connection(&id, remote_peer_id);
+        let (max_substreams, max_substream_opens_per_sec) = {
+            let config = self.config_rx.borrow();
+            (
+                config.max_substreams_per_connection,
+                config.max_substream_opens_per_sec,
+            )
+        };
+
+        // representation of a connection; this contains channels for applications to read/write to.
+        let conn = Connection::new(
+            remote_peer_id,
+            recipient,
+            id,
+            inbound_rx,
+            self.outbound_tx.clone(),
+        )
+        .with_stats(self.stats.clone())
+        .with_memory_budget(self.memory_budget.clone())
+        .with_max_message_size(self.max_message_size)
+        .with_outbound_capacity(self.outbound_capacity.clone())
+        .with_extensions(extensions)
+        .with_remote_connection_ref(remote_ref)
+        .with_route(route)
+        .with_substream_limits(max_substreams, max_substream_opens_per_sec)
+        .with_strict_message_types(self.mixnet_options_tx.borrow().strict_message_types)
+        .with_events(self.mixnet_connection.events.clone())
+        .with_close_notify(self.closed_tx.clone());
+
+        // inbound_tx is what we write to when receiving messages on the mixnet,
+        let handle = ConnectionHandle {
+            peer_id: remote_peer_id,
+            inbound_tx,
+            remote_recipient: conn.remote_recipient.clone(),
+            extensions,
+            remote_address_epoch: 0,
+            established_at: Instant::now(),
+            negotiated: conn.upgraded.clone(),
+            probes_sent: 0,
+            probes_answered: 0,
+            pending_probes: PendingPings::default(),
+            unanswered_keepalives: 0,
+            pending_keepalives: PendingPings::default(),
+            keepalive_expired: conn.keepalive_expired.clone(),
+            traffic: conn.traffic.clone(),
+            stats_reported_at: Instant::now(),
+            reported_retransmits: 0,
+        };
+        (conn, handle)
+    }
+
+    /// message_sender returns what we know about the sender of an inbound message,
+    /// from the connection it belongs to.
+    fn message_sender(&self, msg: &Message) -> MessageSender {
+        if let Message::ConnectionRequest(msg) = msg {
+            return MessageSender {
+                peer_id: Some(msg.peer_id),
+                address: msg.recipient,
+            };
+        }
+
+        let Some(id) = msg.connection_id() else {
+            return MessageSender::default();
+        };
+        if let Some(handle) = self.connections.get(id) {
+            // an anonymous dialer's address isn't known
+            let anonymous = matches!(handle.remote_recipient.route(), Route::Reply(_));
+            return MessageSender {
+                peer_id: Some(handle.peer_id),
+                address: (!anonymous).then(|| handle.remote_recipient.get()),
+            };
+        }
+        if let Some(pending_conn) = self.pending_dials.get(id) {
+            return MessageSender {
+                peer_id: None,
+                address: Some(pending_conn.remote_recipient),
+            };
+        }
+        MessageSender::default()
+    }
+
+    /// filter_inbound runs the inbound filter on the message, and returns false if it
+    /// should be dropped.
+    fn filter_inbound(&mut self, msg: &InboundMessage) -> bool {
+        if self.inbound_filter.is_none() {
+            return true;
+        }
+
+        let sender = self.message_sender(&msg.0);
+        let Some(filter) = self.inbound_filter.as_mut() else {
+            return true;
+        };
+        match filter.filter(&sender, msg) {
+            FilterAction::Accept => true,
+            FilterAction::Drop => {
+                debug!("inbound filter dropped {:?} message", msg.kind());
+                self.stats.record_filter_drop();
+                if msg.kind() == MessageKind::ConnectionRequest {
+                    self.stats.record_handshake(
+                        Some(HandshakeFailure::PolicyRejected),
+                        SystemTime::now(),
+                    );
+                    self.audit(
+                        ConnectionDirection::Inbound,
+                        sender.peer_id,
+                        sender.address,
+                        ConnectionOutcome::Filtered,
+                        SystemTime::now(),
+                    );
+                }
+                false
+            }
+            FilterAction::Tag(tag) => {
+                debug!("inbound filter tagged {:?} message: {}", msg.kind(), tag);
+                self.stats.record_filter_tag(tag);
+                true
+            }
+        }
+    }
+
+    /// handle_inbound handles an inbound message from the mixnet, received via self.inbound_stream.
     fn handle_inbound(&mut self, msg: Message) -> Result<InboundTransportEvent, Error> {
+        let redact = self.redact_logs();
         match msg {
             Message::ConnectionRequest(inner) => {
-                debug!("got inbound connection request {:?}", inner);
-                match self.handle_connection_request(&inner) {
-                    Ok(conn) => {
-                        let (connection_tx, connection_rx) =
-                            oneshot::channel::<(PeerId, Connection)>();
-                        let upgrade = Upgrade::new(connection_rx);
-                        connection_tx
-                            .send((inner.peer_id, conn))
-                            .map_err(|_| Error::ConnectionSendError)?;
-                        Ok(InboundTransportEvent::ConnectionRequest(upgrade))
+                debug!(
+                    "got inbound connection request {:?}",
+                    Redacted(&inner, redact)
+                );
+                self.queue_connection_request(inner)
+            }
+            Message::ConnectionResponse(msg) => {
+                debug!(
+                    "got inbound connection response {:?}",
+                    Redacted(&msg, redact)
+                );
+                self.handle_connection_response(&msg)
+                    .map(|_| InboundTransportEvent::ConnectionResponse)
+            }
+            Message::TransportMessage(msg) => {
+                debug!("got inbound TransportMessage: {:?}", Redacted(&msg, redact));
+                self.handle_transport_message(msg)
+                    .map(|_| InboundTransportEvent::TransportMessage)
+            }
+            Message::AddressUpdate(msg) => {
+                debug!("got inbound AddressUpdate: {:?}", Redacted(&msg, redact));
+                self.handle_address_update(msg)
+                    .map(|_| InboundTransportEvent::AddressUpdate)
+            }
+            Message::Ping(msg) => {
+                debug!("got inbound Ping: {:?}", Redacted(&msg, redact));
+                self.handle_ping(msg).map(|_| InboundTransportEvent::Probe)
+            }
+            Message::Pong(msg) => {
+                debug!("got inbound Pong: {:?}", Redacted(&msg, redact));
+                self.handle_pong(msg).map(|_| InboundTransportEvent::Probe)
+            }
+            Message::Broadcast(payload) => {
+                debug!("got inbound Broadcast of {} bytes", payload.len());
+                if let Some(broadcast_tx) = &self.broadcast_tx {
+                    // the subscriber might have gone away, which is fine
+                    broadcast_tx.send(payload).ok();
+                }
+                Ok(InboundTransportEvent::Broadcast)
+            }
+            Message::Datagram(payload) => {
+                debug!("got inbound Datagram of {} bytes", payload.len());
+                if let Some(datagram_tx) = &self.datagram_tx {
+                    // the subscriber might have gone away, which is fine
+                    datagram_tx.send(payload).ok();
+                }
+                Ok(InboundTransportEvent::Datagram)
+            }
+            Message::ReachabilityRequest(msg) => {
+                debug!(
+                    "got inbound ReachabilityRequest: {:?}",
+                    Redacted(&msg, redact)
+                );
+                self.handle_reachability_request(msg)
+                    .map(|_| InboundTransportEvent::Reachability)
+            }
+            Message::DialBack(msg) => {
+                debug!("got inbound DialBack: {:?}", msg);
+                self.handle_dial_back(msg);
+                Ok(InboundTransportEvent::Reachability)
+            }
+            Message::ConnectionDenied(msg) => {
+                debug!("got inbound ConnectionDenied: {:?}", msg);
+                self.handle_connection_denied(msg)
+                    .map(|_| InboundTransportEvent::ConnectionDenied)
+            }
+            Message::EchoRequest(msg) => {
+                debug!("got inbound EchoRequest: {:?}", Redacted(&msg, redact));
+                self.handle_echo_request(msg)
+                    .map(|_| InboundTransportEvent::Echo)
+            }
+            Message::EchoReply(msg) => {
+                debug!("got inbound EchoReply: {:?}", msg);
+                self.handle_echo_reply(msg);
+                Ok(InboundTransportEvent::Echo)
+            }
+            Message::ConnectionClose(msg) => {
+                debug!("got inbound ConnectionClose: {:?}", msg);
+                self.handle_connection_close(msg);
+                Ok(InboundTransportEvent::ConnectionClosed)
+            }
+            Message::CloseAck(msg) => {
+                debug!("got inbound CloseAck: {:?}", msg);
+                self.handle_close_ack(msg);
+                Ok(InboundTransportEvent::ConnectionClosed)
+            }
+            // resolve_compact unwraps those of our connections
+            Message::Compact(msg) => Err(Error::NoConnectionForCompactReference(msg.reference)),
+        }
+    }
+
+    /// queue_connection_request hands an inbound connection request to the handshake
+    /// workers, or declines it if too many are waiting for one already.
+    fn queue_connection_request(
+        &mut self,
+        msg: ConnectionMessage,
+    ) -> Result<InboundTransportEvent, Error> {
+        let started_at = SystemTime::now();
+        let config = self.config_rx.borrow().clone();
+        match self
+            .handshakes
+            .submit(msg, started_at, self.peer_id(), config)
+        {
+            Ok(()) => Ok(InboundTransportEvent::HandshakeQueued),
+            Err(msg) => {
+                debug!("too many inbound handshakes queued, declining the request");
+                self.finish_connection_request(VerifiedRequest {
+                    msg,
+                    started_at,
+                    result: Err(Error::InboundConnectionRejected(DenialReason::RateLimited)),
+                })
+                .map(|upgrade| {
+                    upgrade.map_or(
+                        InboundTransportEvent::HandedOff,
+                        InboundTransportEvent::ConnectionRequest,
+                    )
+                })
+            }
+        }
+    }
+
+    /// finish_connection_request establishes the connection of an inbound connection
+    /// request the handshake workers are done with, or declines it, and records the
+    /// outcome in the audit log. It returns the upgrade for the swarm, unless an acceptor
+    /// took it.
+    fn finish_connection_request(
+        &mut self,
+        verified: VerifiedRequest,
+    ) -> Result<Option<Upgrade>, Error> {
+        let VerifiedRequest {
+            msg,
+            started_at,
+            result,
+        } = verified;
+        let res = self.handle_connection_request(&msg, result);
+        self.stats.record_handshake(
+            res.as_ref().err().map(HandshakeFailure::from_error),
+            started_at,
+        );
+        let outcome = match &res {
+            Ok(_) => ConnectionOutcome::Established,
+            Err(e) => ConnectionOutcome::Failed(e.to_string()),
+        };
+        self.audit(
+            ConnectionDirection::Inbound,
+            Some(msg.peer_id),
+            msg.recipient,
+            outcome,
+            started_at,
+        );
+        let (conn, reply) = res?;
+        let upgraded = conn.upgraded.clone();
+        let (connection_tx, connection_rx) = oneshot::channel::<(PeerId, Connection)>();
+        connection_tx
+            .send((msg.peer_id, conn))
+            .map_err(|_| Error::ConnectionSendError)?;
+        let upgrade = Upgrade::new(connection_rx).with_reply(reply);
+        let upgrade = self.hand_off(&msg.peer_id, msg.handshake_payload.as_deref(), upgrade);
+        if upgrade.is_none() {
+            // the acceptor's protocol isn't negotiated like libp2p's, so the connection
+            // mustn't be taken for one that stalled while upgrading
+            upgraded.store(true, Ordering::Relaxed);
+        }
+        Ok(upgrade)
+    }
+
+    /// hand_off gives the upgrade of an inbound connection to the first acceptor that
+    /// selects it, or returns it if there's none, so it goes to the swarm. Acceptors that
+    /// were dropped are forgotten.
+    fn hand_off(
+        &mut self,
+        peer_id: &PeerId,
+        handshake_payload: Option<&[u8]>,
+        mut upgrade: Upgrade,
+    ) -> Option<Upgrade> {
+        let mut i = 0;
+        while i < self.acceptors.len() {
+            let (selects, upgrades_tx) = &self.acceptors[i];
+            if !selects.matches(peer_id, handshake_payload) {
+                i += 1;
+                continue;
+            }
+            match upgrades_tx.send(upgrade) {
+                Ok(()) => {
+                    debug!(
+                        "handed inbound connection from {} off to an acceptor",
+                        Redacted(peer_id, self.redact_logs())
+                    );
+                    return None;
+                }
+                Err(SendError(returned)) => {
+                    self.acceptors.remove(i);
+                    upgrade = returned;
+                }
+            }
+        }
+        Some(upgrade)
+    }
+
+    /// resolve_compact returns the message a Compact message wraps, with the ID of the
+    /// connection its reference is for. Messages with an unknown reference are returned as
+    /// they are.
+    fn resolve_compact(&self, msg: InboundMessage) -> InboundMessage {
+        match msg.0 {
+            Message::Compact(compact) => match self.compact_refs.get(&compact.reference) {
+                Some(id) => {
+                    let mut msg = *compact.message;
+                    msg.set_connection_id(id.clone());
+                    InboundMessage(msg)
+                }
+                None => InboundMessage(Message::Compact(compact)),
+            },
+            msg => InboundMessage(msg),
+        }
+    }
+
+    /// assign_compact_ref picks the reference the remote peer refers to the connection by.
+    /// References aren't reused, so that late messages of a closed connection aren't taken
+    /// for those of another one.
+    fn assign_compact_ref(&mut self, id: &ConnectionId) -> u64 {
+        let reference = self.next_compact_ref;
+        self.next_compact_ref += 1;
+        self.compact_refs.insert(reference, id.clone());
+        reference
+    }
+
+    /// forget_compact_ref forgets the reference to the connection, if it has one.
+    fn forget_compact_ref(&mut self, id: &ConnectionId) {
+        self.compact_refs.retain(|_, conn_id| conn_id != id);
+    }
+
+    /// forget_pending_dial forgets the pending connection of a dial that's done with,
+    /// unless the listener answered it already, ie. if the dial timed out or was dropped.
+    /// A response arriving later is ignored, like one for a connection we never dialed.
+    fn forget_pending_dial(&mut self, id: &ConnectionId) {
+        if self.pending_dials.remove(id).is_none() {
+            return;
+        }
+        debug!("forgetting the pending connection of an abandoned dial");
+        self.message_queues.remove(id);
+        self.forget_compact_ref(id);
+        if let Some(tenant) = &self.tenant {
+            tenant.unregister_connection(id);
+        }
+    }
+
+    /// drop_stalled_connections drops the connections that haven't finished upgrading
+    /// within the upgrade timeout, and the messages queued for them. The connection
+    /// itself fails once its handle is gone.
+    fn drop_stalled_connections(&mut self) {
+        let Some(upgrade_timeout) = self.upgrade_timeout else {
+            return;
+        };
+
+        let stalled: Vec<ConnectionId> = self
+            .connections
+            .iter()
+            .filter(|(id, handle)| {
+                !handle.negotiated.load(Ordering::Relaxed)
+                    && handle.established_at.elapsed() >= upgrade_timeout
+                    // preconnected connections aren't upgraded until they're dialed
+                    && !self.parked.values().any(|(_, conn)| conn.id == **id)
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in stalled {
+            let Some(handle) = self.connections.remove(&id) else {
+                continue;
+            };
+            self.message_queues.remove(&id);
+            self.forget_compact_ref(&id);
+            self.stats.message_capture().unregister_connection(&id);
+            self.stats.record_upgrade_timeout();
+            self.send_connection_close(&id, &handle, ShutdownReason::Idle);
+            self.mixnet_connection.events.send(ConnectionEvent::Closed {
+                peer_id: handle.peer_id,
+                reason: CloseReason::UpgradeTimeout,
+            });
+            info!(
+                "dropped connection with {} which didn't finish upgrading within {:?}",
+                Redacted(&handle.peer_id, self.redact_logs()),
+                upgrade_timeout
+            );
+        }
+    }
+
+    /// has_live_connection returns true if the swarm holds a connection to the peer.
+    fn has_live_connection(&self, peer_id: &PeerId) -> bool {
+        self.connections.iter().any(|(id, handle)| {
+            handle.peer_id == *peer_id
+                && !handle.inbound_tx.is_closed()
+                && !self.parked.values().any(|(_, conn)| conn.id == *id)
+        })
+    }
+
+    /// start_redials dials the pinned peers without a live connection whose backoff has
+    /// passed.
+    fn start_redials(&mut self) {
+        let pinned = self.pinned_peers.get();
+        self.redials.retain(|peer_id, redial| {
+            redial.dial.is_some() || pinned.iter().any(|(pinned, _)| pinned == peer_id)
+        });
+
+        let now = Instant::now();
+        for (peer_id, address) in pinned {
+            if let Some(redial) = self.redials.get(&peer_id) {
+                if redial.dial.is_some() || redial.next_attempt > now {
+                    continue;
+                }
+            }
+            if self.has_live_connection(&peer_id) {
+                self.redials.remove(&peer_id);
+                continue;
+            }
+
+            let dial = self.dial(address.clone());
+            let redial = self
+                .redials
+                .entry(peer_id)
+                .or_insert_with(|| Redial::new(address.clone()));
+            redial.address = address.clone();
+            redial.attempts += 1;
+            let attempt = redial.attempts;
+            let event = match dial {
+                Ok(dial) => {
+                    redial.dial = Some(dial);
+                    PinnedPeerEvent::Redialing {
+                        peer_id,
+                        address,
+                        attempt,
+                    }
+                }
+                Err(e) => {
+                    let retry_in = self.redial_backoff.delay(attempt);
+                    redial.next_attempt = now + retry_in;
+                    PinnedPeerEvent::RedialFailed {
+                        peer_id,
+                        attempt,
+                        error: e.to_string(),
+                        retry_in,
+                    }
+                }
+            };
+            self.send_pinned_peer_event(event);
+        }
+    }
+
+    /// poll_redials polls the dials to pinned peers, and returns the connection of the
+    /// first that succeeded as an incoming one.
+    fn poll_redials(&mut self, cx: &mut Context<'_>) -> Option<TransportEvent<Upgrade, Error>> {
+        let mut events = Vec::new();
+        let mut reconnected = None;
+        for (peer_id, redial) in self.redials.iter_mut() {
+            let Some(dial) = redial.dial.as_mut() else {
+                continue;
+            };
+            let Poll::Ready(res) = dial.as_mut().poll(cx) else {
+                continue;
+            };
+            redial.dial = None;
+            match res {
+                Ok(conn) => {
+                    reconnected = Some((*peer_id, conn));
+                    break;
+                }
+                Err(e) => {
+                    let retry_in = self.redial_backoff.delay(redial.attempts);
+                    redial.next_attempt = Instant::now() + retry_in;
+                    events.push(PinnedPeerEvent::RedialFailed {
+                        peer_id: *peer_id,
+                        attempt: redial.attempts,
+                        error: e.to_string(),
+                        retry_in,
+                    });
+                }
+            }
+        }
+        for event in events {
+            self.send_pinned_peer_event(event);
+        }
+
+        let (peer_id, conn) = reconnected?;
+        let redial = self.redials.remove(&peer_id)?;
+        info!(
+            "reconnected to pinned peer {}",
+            Redacted(&peer_id, self.redact_logs())
+        );
+        self.send_pinned_peer_event(PinnedPeerEvent::Reconnected {
+            peer_id,
+            attempt: redial.attempts,
+        });
+        let (connection_tx, connection_rx) = oneshot::channel();
+        connection_tx.send(conn).ok();
+        Some(TransportEvent::Incoming {
+            listener_id: self.listener_id,
+            upgrade: Upgrade::new(connection_rx),
+            local_addr: self.listen_addr.clone(),
+            send_back_addr: redial.address,
+        })
+    }
+
+    fn send_pinned_peer_event(&self, event: PinnedPeerEvent) {
+        debug!("{:?}", event);
+        if let Some(events_tx) = &self.pinned_events_tx {
+            events_tx.send(event).ok();
+        }
+    }
+
+    /// handle_connection_closed forgets a connection the application dropped.
+    fn handle_connection_closed(&mut self, id: &ConnectionId, reason: ShutdownReason) {
+        // connections dropped for not upgrading in time, or closed by the remote peer, are
+        // gone already
+        let Some(handle) = self.connections.remove(id) else {
+            return;
+        };
+        self.message_queues.remove(id);
+        self.forget_compact_ref(id);
+        self.stats.message_capture().unregister_connection(id);
+        debug!(
+            "connection with {} was closed: {}",
+            Redacted(&handle.peer_id, self.redact_logs()),
+            reason
+        );
+        self.send_connection_close(id, &handle, reason);
+        self.mixnet_connection.events.send(ConnectionEvent::Closed {
+            peer_id: handle.peer_id,
+            reason: CloseReason::Dropped,
+        });
+    }
+
+    /// send_connection_close tells the remote peer of a connection that we closed it, and
+    /// why, so it doesn't wait for messages that won't come. If the connection uses the
+    /// close ack extension, the close is sent again until it's acknowledged.
+    fn send_connection_close(
+        &mut self,
+        id: &ConnectionId,
+        handle: &ConnectionHandle,
+        reason: ShutdownReason,
+    ) {
+        let close = UnackedClose {
+            recipient: handle.remote_recipient.get(),
+            route: handle.remote_recipient.route(),
+            reason,
+            sent_at: Instant::now(),
+            retries: 0,
+        };
+        self.write_connection_close(id, &close);
+        if handle.extensions & extension::CLOSE_ACK != 0 {
+            self.unacked_closes.insert(id.clone(), close);
+        }
+    }
+
+    fn write_connection_close(&self, id: &ConnectionId, close: &UnackedClose) {
+        let msg = ConnectionCloseMessage {
+            id: id.clone(),
+            reason: close.reason,
+        };
+        let res = self.outbound_tx.send(
+            OutboundMessage::new(Message::ConnectionClose(msg), close.recipient)
+                .with_route(close.route),
+        );
+        if let Err(e) = res {
+            // the remote peer finds out once its messages go unanswered instead
+            debug!("failed to send connection close: {:?}", e);
+        }
+    }
+
+    /// retry_closes sends the closes that weren't acknowledged within
+    /// [`CLOSE_RETRY_INTERVAL`] again, and gives up on those sent too often already. It also
+    /// forgets the closes we acknowledged long enough ago.
+    fn retry_closes(&mut self) {
+        let now = Instant::now();
+        self.acked_closes.retain(|_, acked| acked.until > now);
+
+        let mut unacked_closes = std::mem::take(&mut self.unacked_closes);
+        unacked_closes.retain(|id, close| {
+            if close.sent_at + CLOSE_RETRY_INTERVAL > now {
+                return true;
+            }
+            if close.retries >= MAX_CLOSE_RETRIES {
+                debug!("giving up on the close of connection {:?}", id);
+                return false;
+            }
+            close.retries += 1;
+            close.sent_at = now;
+            self.write_connection_close(id, close);
+            true
+        });
+        self.unacked_closes = unacked_closes;
+    }
+
+    /// send_close_ack acknowledges a close on a connection using the close ack extension.
+    fn send_close_ack(&self, id: &ConnectionId, acked: &AckedClose) {
+        let ack = CloseAckMessage { id: id.clone() };
+        let res = self.outbound_tx.send(
+            OutboundMessage::new(Message::CloseAck(ack), acked.recipient).with_route(acked.route),
+        );
+        if let Err(e) = res {
+            // the remote peer sends the close again
+            debug!("failed to send close ack: {:?}", e);
+        }
+    }
+
+    /// handle_close_ack stops sending a close the remote peer acknowledged.
+    fn handle_close_ack(&mut self, msg: CloseAckMessage) {
+        if self.unacked_closes.remove(&msg.id).is_none() {
+            debug!("got CloseAck for unknown connection");
+        }
+    }
+
+    /// handle_connection_close drops a connection the remote peer closed. The application
+    /// finds out once it polls the connection.
+    fn handle_connection_close(&mut self, msg: ConnectionCloseMessage) {
+        // if we closed it at the same time, the remote peer is gone and won't acknowledge
+        // our close
+        self.unacked_closes.remove(&msg.id);
+        let Some(handle) = self.connections.remove(&msg.id) else {
+            match self.acked_closes.get(&msg.id) {
+                // our acknowledgement was lost
+                Some(acked) => self.send_close_ack(&msg.id, acked),
+                None => debug!("got ConnectionClose for unknown connection"),
+            }
+            return;
+        };
+        if handle.extensions & extension::CLOSE_ACK != 0 {
+            let acked = AckedClose {
+                recipient: handle.remote_recipient.get(),
+                route: handle.remote_recipient.route(),
+                until: Instant::now() + CLOSE_RETRY_INTERVAL * (MAX_CLOSE_RETRIES + 1),
+            };
+            self.send_close_ack(&msg.id, &acked);
+            self.acked_closes.insert(msg.id.clone(), acked);
+        }
+        self.message_queues.remove(&msg.id);
+        self.forget_compact_ref(&msg.id);
+        self.stats.message_capture().unregister_connection(&msg.id);
+        info!(
+            "connection with {} was closed by the remote peer: {}",
+            Redacted(&handle.peer_id, self.redact_logs()),
+            msg.reason
+        );
+        self.mixnet_connection.events.send(ConnectionEvent::Closed {
+            peer_id: handle.peer_id,
+            reason: CloseReason::Remote(msg.reason),
+        });
+    }
+
+    /// redact_logs returns true if addresses and message contents should be left out of
+    /// the logs.
+    fn redact_logs(&self) -> bool {
+        self.config_rx.borrow().redact_logs
+    }
+
+    /// audit records a connection attempt in the audit log, if there is one.
+    fn audit(
+        &self,
+        direction: ConnectionDirection,
+        peer_id: Option<PeerId>,
+        address: Option<Recipient>,
+        outcome: ConnectionOutcome,
+        started_at: SystemTime,
+    ) {
+        self.mixnet_connection
+            .events
+            .send(ConnectionEvent::from_outcome(direction, peer_id, &outcome));
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(direction, peer_id, address, outcome, started_at);
+        }
+    }
+
+    /// handle_ping echoes a latency probe back to the remote peer.
+    fn handle_ping(&mut self, msg: PingMessage) -> Result<(), Error> {
+        let received_at = unix_micros();
+        let Some(handle) = self.connections.get(&msg.id) else {
+            return Err(Error::NoConnectionForProbe);
+        };
+
+        let pong = PongMessage {
+            id: msg.id,
+            ping_sent_at: msg.sent_at,
+            ping_received_at: received_at,
+            sent_at: unix_micros(),
+        };
+        self.outbound_tx
+            .send(
+                OutboundMessage::new(Message::Pong(pong), handle.remote_recipient.get())
+                    .with_connection_ref(handle.remote_recipient.connection_ref())
+                    .with_route(handle.remote_recipient.route()),
+            )
+            .map_err(Error::from)
+    }
+
+    /// handle_pong records the latency measured by one of our probes.
+    fn handle_pong(&mut self, msg: PongMessage) -> Result<(), Error> {
+        let received_at = unix_micros();
+        let Some(handle) = self.connections.get_mut(&msg.id) else {
+            return Err(Error::NoConnectionForProbe);
+        };
+        // keepalives are pings too, but only latency probes count towards the loss
+        if handle.pending_probes.answer(msg.ping_sent_at) {
+            handle.probes_answered += 1;
+        } else if !handle.pending_keepalives.answer(msg.ping_sent_at) {
+            debug!("ignoring pong that doesn't answer any of our pings");
+            return Ok(());
+        }
+        handle.unanswered_keepalives = 0;
+
+        self.stats.record_latency(
+            handle.peer_id,
+            LatencySample {
+                ping_sent_at: msg.ping_sent_at,
+                ping_received_at: msg.ping_received_at,
+                pong_sent_at: msg.sent_at,
+                pong_received_at: received_at,
+            },
+        );
+        self.rate_connection(&msg.id);
+        Ok(())
+    }
+
+    /// handle_reachability_request sends a message to the address the remote peer of a
+    /// connection wants to check the reachability of.
+    fn handle_reachability_request(
+        &mut self,
+        msg: ReachabilityRequestMessage,
+    ) -> Result<(), Error> {
+        // only peers we're connected to can ask, so we can't be used to spam arbitrary
+        // addresses anonymously
+        if !self.connections.contains_key(&msg.id) {
+            return Err(Error::NoConnectionForReachabilityRequest);
+        }
+
+        self.outbound_tx
+            .send(OutboundMessage::new(
+                Message::DialBack(DialBackMessage { nonce: msg.nonce }),
+                msg.address,
+            ))
+            .map_err(Error::from)
+    }
+
+    /// handle_dial_back confirms our address is reachable, if the dial-back answers our
+    /// pending reachability probe.
+    fn handle_dial_back(&mut self, msg: DialBackMessage) {
+        if self.pending_reachability != Some(msg.nonce) {
+            debug!("ignoring unexpected dial-back");
+            return;
+        }
+        self.pending_reachability = None;
+        self.record_reachability(true);
+    }
+
+    /// handle_echo_request replies to an echo request, if the echo responder is enabled.
+    fn handle_echo_request(&mut self, msg: EchoRequestMessage) -> Result<(), Error> {
+        let received_at = unix_micros();
+        let Some((limiter, max_replies_per_sec)) = self.echo_responder.as_mut() else {
+            debug!("ignoring echo request; the echo responder isn't enabled");
+            return Ok(());
+        };
+        if !limiter.try_acquire(*max_replies_per_sec) {
+            debug!("ignoring echo request over the rate limit");
+            return Ok(());
+        }
+
+        let reply = EchoReplyMessage {
+            nonce: msg.nonce,
+            request_sent_at: msg.sent_at,
+            request_received_at: received_at,
+            sent_at: unix_micros(),
+        };
+        self.outbound_tx
+            .send(OutboundMessage::new(
+                Message::EchoReply(reply),
+                msg.reply_to,
+            ))
+            .map_err(Error::from)
+    }
+
+    /// handle_echo_reply resolves the echo request the reply is for.
+    fn handle_echo_reply(&mut self, msg: EchoReplyMessage) {
+        let received_at = unix_micros();
+        let Some(reply_tx) = self.pending_echoes.remove(&msg.nonce) else {
+            debug!("ignoring unexpected echo reply");
+            return;
+        };
+        let result = EchoResult::new(&LatencySample {
+            ping_sent_at: msg.request_sent_at,
+            ping_received_at: msg.request_received_at,
+            pong_sent_at: msg.sent_at,
+            pong_received_at: received_at,
+        });
+        // whoever sent the request might have stopped waiting, which is fine
+        reply_tx.send(result).ok();
+    }
+
+    fn record_reachability(&self, reachable: bool) {
+        if !self.stats.record_reachability(reachable) {
+            return;
+        }
+        let address = Redacted(&self.self_address, self.redact_logs());
+        if reachable {
+            info!("our Nym address {} is reachable", address);
+        } else {
+            warn!("our Nym address {} is not reachable", address);
+        }
+    }
+
+    /// rate_connection records the quality of the connection, and sends it to the
+    /// subscriber of quality updates, if any.
+    fn rate_connection(&self, id: &ConnectionId) {
+        let Some(handle) = self.connections.get(id) else {
+            return;
+        };
+        let (received, duplicates) = self
+            .message_queues
+            .get(id)
+            .map(|queue| (queue.received, queue.duplicates))
+            .unwrap_or_default();
+        let quality = ConnectionQuality::new(
+            self.stats
+                .latency(&handle.peer_id)
+                .map(|latency| latency.smoothed_rtt),
+            handle.probes_sent,
+            handle.probes_answered,
+            received,
+            duplicates,
+        );
+        self.stats.record_quality(handle.peer_id, quality.clone());
+        if let Some(quality_tx) = &self.quality_tx {
+            // the subscriber might have gone away, which is fine
+            quality_tx.send((handle.peer_id, quality)).ok();
+        }
+    }
+
+    /// report_connection_stats sends the traffic of every connection since its stats were
+    /// last reported to the subscribers of connection events.
+    fn report_connection_stats(&mut self) {
+        let now = Instant::now();
+        for (id, handle) in self.connections.iter_mut() {
+            let retransmits = self
+                .message_queues
+                .get(id)
+                .map(|queue| queue.duplicates)
+                .unwrap_or_default();
+            let stats = ConnectionStats {
+                interval: now - handle.stats_reported_at,
+                retransmits: retransmits - handle.reported_retransmits,
+                smoothed_rtt: self
+                    .stats
+                    .latency(&handle.peer_id)
+                    .map(|latency| latency.smoothed_rtt),
+                ..handle.traffic.take()
+            };
+            handle.stats_reported_at = now;
+            handle.reported_retransmits = retransmits;
+            self.mixnet_connection.events.send(ConnectionEvent::Stats {
+                peer_id: handle.peer_id,
+                stats,
+            });
+        }
+    }
+
+    /// send_pings sends a latency probe on every established connection.
+    fn send_pings(&mut self) {
+        // the previous probes have had a whole interval to be answered
+        let ids: Vec<ConnectionId> = self.connections.keys().cloned().collect();
+        for id in ids.iter() {
+            self.rate_connection(id);
+        }
+
+        for (id, handle) in self.connections.iter_mut() {
+            handle.probes_sent += 1;
+            let sent_at = send_ping(&self.outbound_tx, id, handle);
+            handle.pending_probes.push(sent_at);
+        }
+    }
+
+    /// send_keepalives drops the connections whose remote peer left too many keepalives
+    /// in a row unanswered, and sends a keepalive on the others.
+    fn send_keepalives(&mut self) {
+        let max_missed = self.max_missed_keepalives;
+        let expired: Vec<ConnectionId> = self
+            .connections
+            .iter()
+            .filter(|(_, handle)| handle.unanswered_keepalives >= max_missed)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            let Some(handle) = self.connections.remove(&id) else {
+                continue;
+            };
+            // the connection fails with a keepalive timeout once it sees its inbound
+            // channel closed
+            handle.keepalive_expired.store(true, Ordering::SeqCst);
+            self.message_queues.remove(&id);
+            self.forget_compact_ref(&id);
+            self.stats.message_capture().unregister_connection(&id);
+            self.send_connection_close(&id, &handle, ShutdownReason::Idle);
+            self.mixnet_connection.events.send(ConnectionEvent::Closed {
+                peer_id: handle.peer_id,
+                reason: CloseReason::KeepaliveTimeout,
+            });
+            warn!(
+                "dropped connection with {} which left {} keepalives unanswered",
+                Redacted(&handle.peer_id, self.redact_logs()),
+                max_missed
+            );
+        }
+
+        for (id, handle) in self.connections.iter_mut() {
+            handle.unanswered_keepalives += 1;
+            let sent_at = send_ping(&self.outbound_tx, id, handle);
+            handle.pending_keepalives.push(sent_at);
+        }
+    }
+
+    /// handle_address_update redirects a connection's traffic to the remote peer's new
+    /// Nym address.
+    fn handle_address_update(&mut self, msg: AddressUpdateMessage) -> Result<(), Error> {
+        let redact = self.redact_logs();
+        let Some(handle) = self.connections.get_mut(&msg.id) else {
+            return Err(Error::NoConnectionForAddressUpdate);
+        };
+
+        if !msg.verify(&handle.peer_id) {
+            self.mixnet_connection
+                .events
+                .send(ConnectionEvent::PeerMisbehaved {
+                    peer_id: handle.peer_id,
+                    reason: "invalid address update signature".to_string(),
+                });
+            return Err(Error::InvalidAddressUpdateSignature);
+        }
+
+        if msg.epoch <= handle.remote_address_epoch {
+            debug!("ignoring stale address update with epoch {}", msg.epoch);
+            return Ok(());
+        }
+
+        debug!(
+            "connection {:?} migrated to Nym address {}",
+            msg.id,
+            Redacted(&msg.recipient, redact)
+        );
+        handle.remote_address_epoch = msg.epoch;
+        handle.remote_recipient.set(msg.recipient);
+        Ok(())
+    }
+
+    /// send_address_updates tells the remote peer of every established connection about
+    /// our new Nym address, so the connections survive the address change.
+    fn send_address_updates(&mut self, address: Recipient) -> Result<(), Error> {
+        self.address_epoch += 1;
+        for (id, handle) in self.connections.iter() {
+            // our address is hidden from the listener of an anonymous dial
+            if let Route::Anonymous { .. } = handle.remote_recipient.route() {
+                continue;
+            }
+            let msg = AddressUpdateMessage::new_signed(
+                id.clone(),
+                self.address_epoch,
+                address,
+                &self.keypair,
+            )?;
+            self.outbound_tx
+                .send(
+                    OutboundMessage::new(
+                        Message::AddressUpdate(msg),
+                        handle.remote_recipient.get(),
+                    )
+                    .with_connection_ref(handle.remote_recipient.connection_ref())
+                    .with_route(handle.remote_recipient.route()),
+                )
+                .map_err(Error::from)?;
+        }
+        Ok(())
+    }
+}
+
+/// Upgrade represents a transport listener upgrade.
+/// Note: we immediately upgrade a connection request to a connection,
+/// so this only contains a channel for receiving that connection.
+pub struct Upgrade {
+    connection_tx: oneshot::Receiver<(PeerId, Connection)>,
+    /// set for inbound connection requests: the dialer gets the response once the swarm
+    /// polls the upgrade, ie. takes the connection, and a denial if it's dropped first,
+    /// eg. because of the swarm's connection limits
+    reply: Option<HandshakeReply>,
+}
+
+impl Upgrade {
+    fn new(connection_tx: oneshot::Receiver<(PeerId, Connection)>) -> Upgrade {
+        Upgrade {
+            connection_tx,
+            reply: None,
+        }
+    }
+
+    fn with_reply(mut self, reply: HandshakeReply) -> Self {
+        self.reply = Some(reply);
+        self
+    }
+}
+
+impl Future for Upgrade {
+    type Output = Result<(PeerId, Connection), Error>;
+
+    // poll checks if the upgrade has turned into a connection yet
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(reply) = self.reply.take() {
+            reply.accept();
+        }
+        self.connection_tx
+            .poll_unpin(cx)
+            .map_err(|_| Error::RecvError)
+    }
+}
+
+impl Drop for Upgrade {
+    fn drop(&mut self) {
+        if let Some(reply) = self.reply.take() {
+            debug!("inbound connection dropped before its upgrade was polled");
+            reply.deny(DenialReason::ConnectionLimit);
+        }
+    }
+}
+
+/// send_ping sends a ping on the connection, which the remote peer answers with a pong,
+/// and returns its send time, which the pong echoes.
+fn send_ping(outbound_tx: &OutboundSender, id: &ConnectionId, handle: &ConnectionHandle) -> u64 {
+    let sent_at = unix_micros();
+    let ping = PingMessage {
+        id: id.clone(),
+        sent_at,
+    };
+    if let Err(e) = outbound_tx.send(
+        OutboundMessage::new(Message::Ping(ping), handle.remote_recipient.get())
+            .with_connection_ref(handle.remote_recipient.connection_ref())
+            .with_route(handle.remote_recipient.route()),
+    ) {
+        debug!("failed to send ping: {:?}", e);
+    }
+    sent_at
+}
+
+/// UnackedClose is a close we sent on a connection using the close ack extension, kept
+/// until the remote peer acknowledges it.
+struct UnackedClose {
+    recipient: Recipient,
+    route: Route,
+    reason: ShutdownReason,
+    sent_at: Instant,
+    retries: u32,
+}
+
+/// AckedClose is a close we acknowledged, kept so the acknowledgement can be sent again if
+/// the remote peer resends the close.
+struct AckedClose {
+    recipient: Recipient,
+    route: Route,
+    until: Instant,
+}
+
+/// PendingDialGuard tells the transport once a dial is done with, so that it forgets the
+/// pending connection if the dial timed out or was dropped before the listener answered.
+struct PendingDialGuard {
+    id: ConnectionId,
+    dials_done_tx: UnboundedSender<ConnectionId>,
+}
+
+impl Drop for PendingDialGuard {
+    fn drop(&mut self) {
+        // the transport might be gone already, which is fine
+        self.dials_done_tx.send(self.id.clone()).ok();
+    }
+}
+
+/// HandshakeReply is the answer to an inbound connection request, sent once the swarm
+/// decides whether to take the connection.
+struct HandshakeReply {
+    outbound_tx: OutboundSender,
+    recipient: Recipient,
+    route: Route,
+    response: ConnectionMessage,
+}
+
+impl HandshakeReply {
+    /// accept sends the connection response, so the dial succeeds.
+    fn accept(mut self) {
+        // the last of the handshake's timestamps is when the response is sent
+        if let Some(sent_at) = self.response.timestamps.get_mut(2) {
+            *sent_at = unix_micros();
+        }
+        let resp = Message::ConnectionResponse(self.response);
+        if self
+            .outbound_tx
+            .send(OutboundMessage::new(resp, self.recipient).with_route(self.route))
+            .is_err()
+        {
+            debug!("failed to send connection response; the mixnet task stopped");
+        }
+    }
+
+    /// deny tells the dialer the connection was declined, so its dial fails right away.
+    fn deny(self, reason: DenialReason) {
+        let denied = ConnectionDeniedMessage {
+            id: self.response.id,
+            reason,
+        };
+        if self
+            .outbound_tx
+            .send(
+                OutboundMessage::new(Message::ConnectionDenied(denied), self.recipient)
+                    .with_route(self.route),
+            )
+            .is_err()
+        {
+            debug!("failed to send connection denial; the mixnet task stopped");
+        }
+    }
+}
+
+impl Transport for NymTransport {
+    type Output = (PeerId, Connection);
+    type Error = Error;
+    type ListenerUpgrade = Upgrade;
+    type Dial = Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
+
+    fn listen_on(&mut self, addr: Multiaddr) -> Result<ListenerId, TransportError<Self::Error>> {
+        // we're always listening on our Nym address; other kinds of addresses are left
+        // to any transport we're combined with.
+        // TODO: we should only allow listening on the multiaddress containing our Nym address
+        if !is_nym_multiaddress(&addr) {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        }
+        Ok(self.listener_id)
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        if self.listener_id != id {
+            return false;
+        }
+
+        // TODO: close channels?
+        self.poll_tx
+            .send(TransportEvent::ListenerClosed {
+                listener_id: id,
+                reason: Ok(()),
+            })
+            .expect("failed to send listener closed event");
+        true
+    }
+
+    fn dial(&mut self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        debug!("dialing {}", Redacted(&addr, self.redact_logs()));
+        if self.shut_down {
+            return Err(TransportError::Other(Error::TransportShutDown));
+        }
+
+        if !is_nym_multiaddress(&addr) {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        }
+
+        if let Some((peer_id, conn)) = self
+            .parked_address(&addr)
+            .and_then(|parked| self.parked.remove(&parked))
+        {
+            debug!(
+                "using preconnected connection to {}",
+                Redacted(&addr, self.redact_logs())
+            );
+            // the upgrade timeout starts now that the connection is used
+            if let Some(handle) = self.connections.get_mut(&conn.id) {
+                handle.established_at = Instant::now();
+            }
+            return Ok(async move { Ok((peer_id, conn)) }.boxed());
+        }
+
+        let id = ConnectionId::generate();
+        let started_at = SystemTime::now();
+        let options = self.dial_options.get(&addr).unwrap_or_default();
+
+        // create remote recipient address
+        let (recipient, target) = match multiaddress_to_nym_address(addr) {
+            Ok(res) => res,
+            Err(e) => {
+                self.audit(
+                    ConnectionDirection::Outbound,
+                    None,
+                    None,
+                    ConnectionOutcome::Failed(e.to_string()),
+                    started_at,
+                );
+                return Err(TransportError::Other(e));
+            }
+        };
+
+        if self.injector.take_dial_failure() {
+            return Ok(async { Err(Error::InjectedFailure("dial")) }.boxed());
+        }
+
+        // there's no point in waiting for the handshake to time out
+        if self.breaker.is_open() {
+            self.stats
+                .record_handshake(Some(HandshakeFailure::SendFailed), started_at);
+            self.stats.record_dial(false);
+            self.audit(
+                ConnectionDirection::Outbound,
+                None,
+                Some(recipient),
+                ConnectionOutcome::Failed(Error::MixnetOutage.to_string()),
+                started_at,
+            );
+            options.report(DialPhase::Failed);
+            return Err(TransportError::Other(Error::MixnetOutage));
+        }
+
+        let ticket = match self.dial_slots.join() {
+            Ok(ticket) => ticket,
+            Err(e) => {
+                warn!("declining dial: {}", e);
+                self.stats.record_dial(false);
+                self.audit(
+                    ConnectionDirection::Outbound,
+                    None,
+                    Some(recipient),
+                    ConnectionOutcome::Failed(e.to_string()),
+                    started_at,
+                );
+                options.report(DialPhase::Failed);
+                return Err(TransportError::Other(e));
+            }
+        };
+
+        // create pending conn structs and store
+        let (connection_tx, connection_rx) = oneshot::channel::<Result<Connection, Error>>();
+
+        let route = match options.reply_surbs.or(self.default_reply_surbs) {
+            Some(reply_surbs) => Route::Anonymous { reply_surbs },
+            None => Route::Direct,
+        };
+        let inner_pending_conn = PendingConnection::new(recipient, connection_tx).with_route(route);
+        self.pending_dials.insert(id.clone(), inner_pending_conn);
+        let pending_dial = PendingDialGuard {
+            id: id.clone(),
+            dials_done_tx: self.dials_done_tx.clone(),
+        };
+        if let Some(tenant) = &self.tenant {
+            tenant.register_connection(&id);
+        }
+        let connection_ref =
+            (self.extensions & extension::COMPACT_ID != 0).then(|| self.assign_compact_ref(&id));
+
+        let peer_id = self.peer_id();
+        let outbound_tx = self.outbound_tx.clone();
+        let mut mixnet_status_rx = self.mixnet_status_rx.clone();
+        let dial_queue_timeout = self.dial_queue_timeout;
+        let extensions = self.extensions;
+        let user_agent = self.user_agent.clone();
+
+        let mut waker = self.waker.clone();
+        let handshake_timeout = self.config_rx.borrow().handshake_timeout;
+        let audit_log = self.audit_log.clone();
+        let events = self.mixnet_connection.events.clone();
+        let stats = self.stats.clone();
+        options.report(DialPhase::Queued);
+        Ok(async move {
+            // dropped along with the dial, however it ends
+            let _pending_dial = pending_dial;
+            let res = async {
+                // held until the dial is done with
+                let _slot = ticket.wait().await;
+
+                // our address may change while the mixnet is reconnecting, eg. on failover
+                let self_address =
+                    wait_for_connected(&mut mixnet_status_rx, dial_queue_timeout).await?;
+
+                // put ConnectionRequest message into outbound message channel
+                // an anonymous dialer is replied to through the reply SURBs instead
+                let msg = ConnectionMessage {
+                    peer_id,
+                    recipient: (route == Route::Direct).then_some(self_address),
+                    target,
+                    id,
+                    extensions,
+                    handshake_payload: options.handshake_payload.clone(),
+                    timestamps: if extensions & extension::CLOCK != 0 {
+                        vec![unix_micros()]
+                    } else {
+                        vec![]
+                    },
+                    connection_ref,
+                    user_agent,
+                    reply_tag: None,
+                };
+                let (written_tx, written_rx) = oneshot::channel();
+                outbound_tx
+                    .send(
+                        OutboundMessage::new(Message::ConnectionRequest(msg), recipient)
+                            .with_priority(options.priority)
+                            .with_packet_size(options.packet_size)
+                            .with_route(route)
+                            .with_written_notifier(written_tx),
+                    )
+                    .map_err(Error::from)?;
+                options.report(DialPhase::SentRequest);
+
+                debug!("sent outbound ConnectionRequest");
+                if let Some(waker) = waker.take() {
+                    waker.wake();
+                };
+
+                let response = timeout(handshake_timeout, connection_rx);
+                tokio::pin!(response);
+                let res = tokio::select! {
+                    res = &mut response => res,
+                    _ = written_rx => {
+                        options.report(DialPhase::AwaitingResponse);
+                        response.await
+                    }
+                };
+                // the listener may have denied the connection
+                let conn = res???;
+                options.report(DialPhase::Upgrading);
+                let conn = conn.with_dial_options(&options);
+                Ok::<_, Error>((conn.peer_id, conn))
+            }
+            .await;
+            options.report(match &res {
+                Ok(_) => DialPhase::Established,
+                Err(_) => DialPhase::Failed,
+            });
+
+            stats.record_handshake(
+                res.as_ref().err().map(HandshakeFailure::from_error),
+                started_at,
+            );
+            stats.record_dial(res.is_ok());
+            let (peer_id, outcome) = match &res {
+                Ok((peer_id, _)) => (Some(*peer_id), ConnectionOutcome::Established),
+                Err(e) => (None, ConnectionOutcome::Failed(e.to_string())),
+            };
+            events.send(ConnectionEvent::from_outcome(
+                ConnectionDirection::Outbound,
+                peer_id,
+                &outcome,
+            ));
+            if let Some(audit_log) = audit_log {
+                audit_log.record(
+                    ConnectionDirection::Outbound,
+                    peer_id,
+                    Some(recipient),
+                    outcome,
+                    started_at,
+                );
+            }
+            res
+        }
+        .boxed())
+    }
+
+    // dial_as_listener currently just calls self.dial().
+    fn dial_as_listener(
+        &mut self,
+        addr: Multiaddr,
+    ) -> Result<Self::Dial, TransportError<Self::Error>> {
+        self.dial(addr)
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        let span = self.span.clone();
+        let _entered = span.enter();
+
+        if self.shut_down {
+            return Poll::Pending;
+        }
+        if let Poll::Ready(Some(())) = self.shutdown_rx.poll_recv(cx) {
+            return Poll::Ready(self.shut_down_now());
+        }
+        // connections come and go while handling the messages polled below and through the
+        // transport's methods, so the gauge lags behind by at most one poll
+        self.stats.set_active_connections(self.connections.len());
+
+        // new addresses + listener close events
+        if let Poll::Ready(Some(res)) = self.poll_rx.recv().boxed().poll_unpin(cx) {
+            return Poll::Ready(res);
+        }
+
+        // the timers still tick while paused, but nothing's done on them
+        let paused = self.update_paused();
+
+        // latency probes
+        let mut send_pings = false;
+        if let Some(probe) = self.latency_probe.as_mut() {
+            while probe.poll_tick(cx).is_ready() {
+                send_pings = !paused;
+            }
+        }
+        if send_pings {
+            self.send_pings();
+        }
+
+        // connection stats
+        let mut report_stats = false;
+        if let Some(connection_stats) = self.connection_stats.as_mut() {
+            while connection_stats.poll_tick(cx).is_ready() {
+                report_stats = !paused;
+            }
+        }
+        if report_stats {
+            self.report_connection_stats();
+        }
+
+        // keepalives
+        let mut send_keepalives = false;
+        if let Some(keepalive) = self.keepalive.as_mut() {
+            while keepalive.poll_tick(cx).is_ready() {
+                send_keepalives = !paused;
+            }
+        }
+        if send_keepalives {
+            self.send_keepalives();
+        }
+
+        // reachability probes
+        let mut probe_reachability = false;
+        if let Some(probe) = self.reachability_probe.as_mut() {
+            while probe.poll_tick(cx).is_ready() {
+                probe_reachability = !paused;
+            }
+        }
+        if probe_reachability {
+            if let Err(e) = self.probe_reachability() {
+                debug!("failed to probe reachability: {:?}", e);
+            }
+        }
+
+        // connections stalled while upgrading
+        let mut check_upgrades = false;
+        if let Some(check) = self.upgrade_check.as_mut() {
+            while check.poll_tick(cx).is_ready() {
+                check_upgrades = !paused;
+            }
+        }
+        if check_upgrades {
+            self.drop_stalled_connections();
+        }
+
+        // pinned peers without a connection
+        let mut check_pinned = false;
+        if let Some(check) = self.redial_check.as_mut() {
+            while check.poll_tick(cx).is_ready() {
+                check_pinned = !paused;
+            }
+        }
+        if check_pinned {
+            self.start_redials();
+        }
+        if let Some(event) = self.poll_redials(cx) {
+            return Poll::Ready(event);
+        }
+
+        // connections the application dropped
+        while let Poll::Ready(Some((id, reason))) = self.closed_rx.poll_recv(cx) {
+            self.handle_connection_closed(&id, reason);
+        }
+
+        // dials that are done with
+        while let Poll::Ready(Some(id)) = self.dials_done_rx.poll_recv(cx) {
+            self.forget_pending_dial(&id);
+        }
+
+        // closes the remote peer didn't acknowledge yet
+        let mut retry_closes = false;
+        if let Some(retry) = self.close_retry.as_mut() {
+            while retry.poll_tick(cx).is_ready() {
+                retry_closes = !paused;
+            }
+        }
+        if retry_closes {
+            self.retry_closes();
+        }
+
+        // address rotation events
+        while let Poll::Ready(Some(event)) = self.address_rx.poll_recv(cx) {
+            match self.handle_address_event(event) {
+                Ok(event) => return Poll::Ready(event),
+                Err(e) => debug!("failed to handle address event: {:?}", e),
+            }
+        }
+
+        // inbound connection requests the handshake workers are done with
+        while let Poll::Ready(Some(verified)) = self.handshakes.poll_verified(cx) {
+            let event = match self.finish_connection_request(verified) {
+                Ok(Some(upgrade)) => TransportEvent::Incoming {
+                    listener_id: self.listener_id,
+                    upgrade,
+                    local_addr: self.listen_addr.clone(),
+                    send_back_addr: self.listen_addr.clone(),
+                },
+                // an acceptor took it
+                Ok(None) => continue,
+                Err(error) => TransportEvent::ListenerError {
+                    listener_id: self.listener_id,
+                    error,
+                },
+            };
+            return Poll::Ready(event);
+        }
+
+        // check for and handle inbound messages
+        while let Poll::Ready(Some(msg)) = self.inbound_stream.poll_next_unpin(cx) {
+            self.inbound_backlog.pop();
+            let msg = self.resolve_compact(msg);
+            if !self.filter_inbound(&msg) {
+                continue;
+            }
+
+            match self.handle_inbound(msg.0) {
+                Ok(event) => match event {
+                    InboundTransportEvent::ConnectionRequest(upgrade) => {
+                        debug!("InboundTransportEvent::ConnectionRequest");
+                        return Poll::Ready(TransportEvent::Incoming {
+                            listener_id: self.listener_id,
+                            upgrade,
+                            local_addr: self.listen_addr.clone(),
+                            send_back_addr: self.listen_addr.clone(),
+                        });
+                    }
+                    InboundTransportEvent::HandshakeQueued => {
+                        debug!("InboundTransportEvent::HandshakeQueued");
+                    }
+                    InboundTransportEvent::HandedOff => {
+                        debug!("InboundTransportEvent::HandedOff");
+                    }
+                    InboundTransportEvent::ConnectionResponse => {
+                        debug!("InboundTransportEvent::ConnectionResponse");
+                    }
+                    InboundTransportEvent::TransportMessage => {
+                        debug!("InboundTransportEvent::TransportMessage");
+                    }
+                    InboundTransportEvent::AddressUpdate => {
+                        debug!("InboundTransportEvent::AddressUpdate");
+                    }
+                    InboundTransportEvent::Probe => {
+                        debug!("InboundTransportEvent::Probe");
+                    }
+                    InboundTransportEvent::Broadcast => {
+                        debug!("InboundTransportEvent::Broadcast");
+                    }
+                    InboundTransportEvent::Datagram => {
+                        debug!("InboundTransportEvent::Datagram");
+                    }
+                    InboundTransportEvent::Reachability => {
+                        debug!("InboundTransportEvent::Reachability");
+                    }
+                    InboundTransportEvent::ConnectionDenied => {
+                        debug!("InboundTransportEvent::ConnectionDenied");
+                    }
+                    InboundTransportEvent::ConnectionClosed => {
+                        debug!("InboundTransportEvent::ConnectionClosed");
+                    }
+                    InboundTransportEvent::Echo => {
+                        debug!("InboundTransportEvent::Echo");
+                    }
+                },
+                Err(e) => {
+                    return Poll::Ready(TransportEvent::ListenerError {
+                        listener_id: self.listener_id,
+                        error: e,
+                    });
+                }
+            };
+        }
+
+        self.poll_preconnects(cx);
+
+        self.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn address_translation(&self, _listen: &Multiaddr, _observed: &Multiaddr) -> Option<Multiaddr> {
+        None
+    }
+}
+
+pub(crate) fn nym_address_to_multiaddress(addr: Recipient) -> Result<Multiaddr, Error> {
+    Multiaddr::from_str(&format!("/nym/{}", addr)).map_err(Error::FailedToFormatMultiaddr)
+}
+
+/// listen_multiaddress returns the multiaddress of the Nym address, followed by the
+/// listener key if there is one.
+fn listen_multiaddress(addr: Recipient, listener_key: Option<PeerId>) -> Result<Multiaddr, Error> {
+    let multiaddr = nym_address_to_multiaddress(addr)?;
+    Ok(match listener_key {
+        Some(key) => multiaddr.with(Protocol::P2p(key.into())),
+        None => multiaddr,
+    })
+}
+
+/// multiaddress_to_nym_address returns the Nym address of the multiaddress, and the peer ID
+/// of its trailing /p2p/ component, if any, which is the listener key to dial.
+fn multiaddress_to_nym_address(multiaddr: Multiaddr) -> Result<(Recipient, Option<PeerId>), Error> {
+    let mut multiaddr = multiaddr;
+    let target = match multiaddr.iter().last() {
+        Some(Protocol::P2p(hash)) => {
+            Some(PeerId::from_multihash(hash).map_err(|_| Error::InvalidProtocolForMultiaddr)?)
+        }
+        _ => None,
+    };
+    if target.is_some() {
+        multiaddr.pop();
+    }
+    match multiaddr.pop() {
+        Some(Protocol::Nym(addr)) => Ok((
+            Recipient::from_str(&addr).map_err(Error::InvalidRecipientBytes)?,
+            target,
+        )),
+        _ => Err(Error::InvalidProtocolForMultiaddr),
+    }
+}
+
+/// is_nym_multiaddress returns true if the multiaddress ends in a /nym/ component,
+/// optionally followed by a /p2p/ one.
+pub(crate) fn is_nym_multiaddress(multiaddr: &Multiaddr) -> bool {
+    let protocols: Vec<_> = multiaddr.iter().collect();
+    matches!(
+        protocols.as_slice(),
+        [.., Protocol::Nym(_)] | [.., Protocol::Nym(_), Protocol::P2p(_)]
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::audit::{AuditRecord, ConnectionDirection, ConnectionOutcome};
+    use crate::backend::{FailoverBackend, MixnetBackend, MockMixnet, PacketSize};
+    use crate::breaker::{BreakerEvent, BreakerState, TripReason};
+    use crate::budget::BufferKind;
+    use crate::capture::JournalDirection;
+    use crate::config::{NymTransportConfig, RuntimeConfig};
+    use crate::connection::Connection;
+    use crate::dial::{DialPhase, DialProgress};
+    use crate::error::Error;
+    use crate::events::{CloseReason, ConnectionEvent, ShutdownReason};
+    use crate::filter::{FilterAction, InboundMessage, MessageKind, MessageSender};
+    use crate::fragment::FRAGMENT_HEADER_LEN;
+    use crate::framing::Framing;
+    use crate::listener::AcceptorMatch;
+    use crate::message::{
+        DenialReason, Message, MessagePriority, OutboundMessage, SubstreamId, SubstreamMessage,
+        SubstreamMessageType, TransportMessage,
+    };
+    use crate::mixnet::MixnetStatus;
+    use crate::pinned::{PinnedPeerEvent, RedialBackoff};
+    use crate::rotation::AddressRotation;
+    use crate::substream::Substream;
+    use crate::test_utils::{
+        assert_new_address_event, connect_mock_transports, create_nym_client, new_mock_transport,
+        new_mock_transports, new_mock_transports_on,
+    };
+    use crate::testing::VirtualTime;
+
+    use super::{nym_address_to_multiaddress, DialOptions, NymTransport};
+    use crate::DEFAULT_SENDER_WORKERS;
+    use futures::{
+        future::{self, poll_fn},
+        AsyncReadExt, AsyncWriteExt, FutureExt, StreamExt,
+    };
+    use libp2p::core::{
+        identity::Keypair,
+        multiaddr::Protocol,
+        transport::{Transport, TransportError, TransportEvent},
+        Multiaddr, PeerId, StreamMuxer,
+    };
+    use parking_lot::Mutex;
+    use std::{
+        convert::identity,
+        pin::Pin,
+        str::FromStr,
+        sync::{atomic::Ordering, Arc},
+        task::Poll,
+        time::Duration,
+    };
+    use testcontainers::clients;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+    use tokio::time::timeout;
+    use tracing::info;
+    use tracing_subscriber::EnvFilter;
+
+    impl Connection {
+        fn write(&self, msg: SubstreamMessage) -> Result<(), Error> {
+            let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
+            self.mixnet_outbound_tx
+                .send(OutboundMessage::new(
+                    Message::TransportMessage(TransportMessage {
+                        nonce,
+                        id: self.id.clone(),
+                        message: msg,
+                    }),
+                    self.remote_recipient.get(),
+                ))
+                .map_err(Error::from)?;
+            Ok(())
+        }
+    }
+
+    impl NymTransport {
+        async fn new_with_notify_inbound(
+            uri: &String,
+            notify_inbound_tx: UnboundedSender<()>,
+        ) -> Result<Self, Error> {
+            let local_key = Keypair::generate_ed25519();
+            Self::new_maybe_with_notify_inbound(
+                uri.parse()?,
+                local_key,
+                Some(notify_inbound_tx),
+                None,
+                DEFAULT_SENDER_WORKERS,
+            )
+            .await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transport_connection_mock_backend() {
+        let (mut dialer_transport, mut listener_transport) =
+            new_mock_transports(identity, identity).await;
+        let listener_multiaddr =
+            nym_address_to_multiaddress(listener_transport.self_address).unwrap();
+
+        let mut dial = tokio::spawn(dialer_transport.dial(listener_multiaddr).unwrap());
+
+        // the listener receives the connection request and sends the response
+        let upgrade = match poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)).await
+        {
+            TransportEvent::Incoming { upgrade, .. } => upgrade,
+            res => panic!("expected TransportEvent::Incoming, got {:?}", res),
+        };
+        let (dialer_peer_id, _listener_conn) = upgrade.await.unwrap();
+        assert_eq!(dialer_peer_id, dialer_transport.peer_id());
+
+        // the dialer transport needs to be polled to receive the response
+        let (listener_peer_id, _dialer_conn) = tokio::select! {
+            res = &mut dial => res.unwrap().unwrap(),
+            event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                panic!("unexpected transport event {:?}", event)
+            }
+        };
+        assert_eq!(listener_peer_id, listener_transport.peer_id());
+    }
+
+    #[tokio::test]
+    async fn test_transport_rejects_non_nym_multiaddr() {
+        let mut transport = new_mock_transport(&MockMixnet::new());
+        let tcp_addr = Multiaddr::from_str("/ip4/127.0.0.1/tcp/4001").unwrap();
+
+        assert!(matches!(
+            transport.dial(tcp_addr.clone()),
+            Err(TransportError::MultiaddrNotSupported(_))
+        ));
+        assert!(matches!(
+            transport.listen_on(tcp_addr),
+            Err(TransportError::MultiaddrNotSupported(_))
+        ));
+        assert!(transport.listen_on(transport.listen_addr.clone()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_transport_address_rotation() {
+        let mixnet = MockMixnet::new();
+        let rotation_mixnet = mixnet.clone();
+        let rotation = AddressRotation::new(
+            Duration::from_millis(50),
+            Duration::from_millis(50),
+            move || {
+                let mixnet = rotation_mixnet.clone();
+                async move { Ok(mixnet.new_backend()) }
+            },
+        );
+        let mut transport = NymTransport::new_with_address_rotation(
+            mixnet.new_backend(),
+            Keypair::generate_ed25519(),
+            rotation,
+        )
+        .unwrap();
+        let initial_addr = transport.listen_addr.clone();
+        assert_new_address_event(Pin::new(&mut transport)).await;
+
+        let rotated_addr = match poll_fn(|cx| Pin::new(&mut transport).as_mut().poll(cx)).await {
+            TransportEvent::NewAddress { listen_addr, .. } => listen_addr,
+            res => panic!("expected TransportEvent::NewAddress, got {:?}", res),
+        };
+        assert_ne!(rotated_addr, initial_addr);
+        assert_eq!(transport.listen_addr, rotated_addr);
+
+        match poll_fn(|cx| Pin::new(&mut transport).as_mut().poll(cx)).await {
+            TransportEvent::AddressExpired { listen_addr, .. } => {
+                assert_eq!(listen_addr, initial_addr)
+            }
+            res => panic!("expected TransportEvent::AddressExpired, got {:?}", res),
+        };
+    }
+
+    #[tokio::test]
+    async fn test_transport_gateway_failover() {
+        let mixnet = MockMixnet::new();
+        let gateways = (0..2)
+            .map(|_| {
+                let mixnet = mixnet.clone();
+                FailoverBackend::gateway(move || {
+                    let mixnet = mixnet.clone();
+                    async move { Ok(mixnet.new_backend()) }
+                })
+            })
+            .collect();
+        let backend = FailoverBackend::connect(gateways).await.unwrap();
+        let mut transport =
+            NymTransport::new_with_backend(backend, Keypair::generate_ed25519()).unwrap();
+        let initial_addr = transport.listen_addr.clone();
+        assert_new_address_event(Pin::new(&mut transport)).await;
+
+        // the gateway goes away
+        mixnet.disconnect(&transport.self_address);
+
+        let new_addr = match poll_fn(|cx| Pin::new(&mut transport).as_mut().poll(cx)).await {
+            TransportEvent::NewAddress { listen_addr, .. } => listen_addr,
+            res => panic!("expected TransportEvent::NewAddress, got {:?}", res),
+        };
+        assert_ne!(new_addr, initial_addr);
+        match poll_fn(|cx| Pin::new(&mut transport).as_mut().poll(cx)).await {
+            TransportEvent::AddressExpired { listen_addr, .. } => {
+                assert_eq!(listen_addr, initial_addr)
+            }
+            res => panic!("expected TransportEvent::AddressExpired, got {:?}", res),
+        };
+    }
+
+    #[tokio::test]
+    async fn test_transport_dial_queued_while_reconnecting() {
+        let mixnet = MockMixnet::new();
+        let gateways = (0..2)
+            .map(|i| {
+                let mixnet = mixnet.clone();
+                FailoverBackend::gateway(move || {
+                    let mixnet = mixnet.clone();
+                    async move {
+                        // the second gateway takes a while to connect to
+                        if i == 1 {
+                            tokio::time::sleep(Duration::from_millis(300)).await;
+                        }
+                        Ok(mixnet.new_backend())
+                    }
+                })
+            })
+            .collect();
+        let backend = FailoverBackend::connect(gateways).await.unwrap();
+        let mut dialer_transport =
+            NymTransport::new_with_backend(backend, Keypair::generate_ed25519())
+                .unwrap()
+                .with_dial_queuing(Duration::from_secs(2));
+        let mut listener_transport = new_mock_transport(&mixnet);
+        assert_new_address_event(Pin::new(&mut dialer_transport)).await;
+        assert_new_address_event(Pin::new(&mut listener_transport)).await;
+
+        // the gateway goes away; dial while failing over to the next one
+        mixnet.disconnect(&dialer_transport.self_address);
+        dialer_transport.mixnet_status_rx.changed().await.unwrap();
+        assert_eq!(
+            *dialer_transport.mixnet_status_rx.borrow(),
+            MixnetStatus::Reconnecting
+        );
+        let mut dial = tokio::spawn(
+            dialer_transport
+                .dial(listener_transport.listen_addr.clone())
+                .unwrap(),
+        );
+
+        let upgrade = match poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)).await
+        {
+            TransportEvent::Incoming { upgrade, .. } => upgrade,
+            res => panic!("expected TransportEvent::Incoming, got {:?}", res),
+        };
+        let (dialer_peer_id, _listener_conn) = upgrade.await.unwrap();
+        assert_eq!(dialer_peer_id, dialer_transport.peer_id());
+
+        // the dialer's address changes along the way
+        let (listener_peer_id, _dialer_conn) = loop {
+            tokio::select! {
+                res = &mut dial => break res.unwrap().unwrap(),
+                event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                    match event {
+                        TransportEvent::NewAddress { .. }
+                        | TransportEvent::AddressExpired { .. } => {}
+                        res => panic!("unexpected transport event {:?}", res),
+                    }
+                }
+            }
+        };
+        assert_eq!(listener_peer_id, listener_transport.peer_id());
+    }
+
+    #[tokio::test]
+    async fn test_transport_circuit_breaker() {
+        let mixnet = MockMixnet::new().with_send_outcomes();
+        let mut dialer_transport = new_mock_transport(&mixnet)
+            .with_circuit_breaker(CircuitBreakerConfig {
+                max_send_failures: 2,
+                max_inbound_silence: None,
+                probe_interval: Duration::from_millis(50),
+            })
+            .unwrap();
+        let listener_transport = new_mock_transport(&mixnet);
+        let breaker = dialer_transport.circuit_breaker();
+        let mut events_rx = breaker.subscribe();
+
+        // the message and its retransmission are lost, and so are the first two probes
+        mixnet.lose_before_gateway(4);
+        dialer_transport
+            .mixnet_connection()
+            .broadcast(vec![listener_transport.self_address], b"hello".to_vec())
+            .unwrap();
+        let event = timeout(Duration::from_secs(1), events_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event, BreakerEvent::Tripped(TripReason::SendFailures(2)));
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // dials fail right away
+        match dialer_transport.dial(listener_transport.listen_addr.clone()) {
+            Err(TransportError::Other(Error::MixnetOutage)) => {}
+            Err(e) => panic!("expected Error::MixnetOutage, got {:?}", e),
+            Ok(_) => panic!("expected Error::MixnetOutage, got a dial"),
+        }
+
+        // a probe gets through, and the paused retransmission is sent
+        let event = timeout(Duration::from_secs(1), events_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(event, BreakerEvent::Recovered { .. }));
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let stats = dialer_transport.stats().gateway();
+        assert_eq!(stats.retransmitted, 2);
+        assert_eq!(stats.handed_to_gateway, 1);
+    }
+
+    #[tokio::test]
+    async fn test_transport_connection_migrates_on_address_change() {
+        let mixnet = MockMixnet::new();
+        let gateways = (0..2)
+            .map(|_| {
+                let mixnet = mixnet.clone();
+                FailoverBackend::gateway(move || {
+                    let mixnet = mixnet.clone();
+                    async move { Ok(mixnet.new_backend()) }
+                })
+            })
+            .collect();
+        let backend = FailoverBackend::connect(gateways).await.unwrap();
+        let mut dialer_transport =
+            NymTransport::new_with_backend(backend, Keypair::generate_ed25519()).unwrap();
+        let mut listener_transport = new_mock_transport(&mixnet);
+        let listener_multiaddr =
+            nym_address_to_multiaddress(listener_transport.self_address).unwrap();
+        assert_new_address_event(Pin::new(&mut dialer_transport)).await;
+        assert_new_address_event(Pin::new(&mut listener_transport)).await;
+
+        let mut dial = tokio::spawn(dialer_transport.dial(listener_multiaddr).unwrap());
+        let upgrade = match poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)).await
+        {
+            TransportEvent::Incoming { upgrade, .. } => upgrade,
+            res => panic!("expected TransportEvent::Incoming, got {:?}", res),
+        };
+        let (_, listener_conn) = upgrade.await.unwrap();
+        tokio::select! {
+            res = &mut dial => res.unwrap().unwrap(),
+            event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                panic!("unexpected transport event {:?}", event)
+            }
+        };
+        let old_address = dialer_transport.self_address;
+        assert_eq!(listener_conn.remote_recipient.get(), old_address);
+
+        // the dialer's gateway goes away, so it fails over to a new address and tells
+        // the listener about it
+        mixnet.disconnect(&old_address);
+        match poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)).await {
+            TransportEvent::NewAddress { .. } => {}
+            res => panic!("expected TransportEvent::NewAddress, got {:?}", res),
+        };
+        let new_address = dialer_transport.self_address;
+        assert_ne!(new_address, old_address);
+
+        // the listener handles the update without emitting an event
+        let res = timeout(
+            Duration::from_millis(200),
+            poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)),
+        )
+        .await;
+        assert!(res.is_err(), "unexpected transport event");
+        assert_eq!(listener_conn.remote_recipient.get(), new_address);
+    }
+
+    #[tokio::test]
+    async fn test_transport_new_from_endpoint() {
+        let mut dialer_transport =
+            NymTransport::new("mock://test-endpoint", Keypair::generate_ed25519())
+                .await
+                .unwrap();
+        let endpoint: NymEndpoint = "mock://test-endpoint".parse().unwrap();
+        let mut listener_transport = NymTransport::new(endpoint, Keypair::generate_ed25519())
+            .await
+            .unwrap();
+        // transports using the same mock mixnet name reach each other
+        assert_new_address_event(Pin::new(&mut dialer_transport)).await;
+        assert_new_address_event(Pin::new(&mut listener_transport)).await;
+        connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+
+        assert!(matches!(
+            NymTransport::new("http://127.0.0.1:1977", Keypair::generate_ed25519()).await,
+            Err(Error::InvalidEndpoint(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_transport_mixnet_info() {
+        let transport = new_mock_transport(&MockMixnet::new());
+        let info = transport.mixnet_info();
+        assert_eq!(
+            info.gateway,
+            Some(transport.self_address.gateway().to_base58_string())
+        );
+        // the mock backend isn't a Nym client
+        assert_eq!(info.client_version, None);
+    }
+
+    #[tokio::test]
+    async fn test_transport_signed_address_record() {
+        let transport = new_mock_transport(&MockMixnet::new());
+        let record = transport
+            .signed_address_record(Duration::from_secs(60))
+            .unwrap();
+        record.verify().unwrap();
+        assert_eq!(record.peer_id(), transport.peer_id());
+        assert_eq!(record.multiaddr().unwrap(), transport.listen_addr);
+    }
+
+    #[tokio::test]
+    async fn test_transport_inbound_watermarks() {
+        let mixnet = MockMixnet::new();
+        let mut transport = new_mock_transport(&mixnet)
+            .with_inbound_watermarks(2, 1)
+            .unwrap();
+        let mut broadcast_rx = transport.subscribe_broadcasts();
+        let mut sender = mixnet.new_backend();
+        for i in 0..5u8 {
+            let msg = Message::Broadcast(vec![i]);
+            sender
+                .send(transport.self_address, msg.to_bytes())
+                .await
+                .unwrap();
+        }
+
+        // nothing polls the transport, so the mixnet task stops reading at the high watermark
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(transport.inbound_backlog.len(), 2);
+
+        // once the transport is polled again, the rest are read
+        let mut received = vec![];
+        while received.len() < 5 {
+            tokio::select! {
+                payload = broadcast_rx.recv() => received.push(payload.unwrap()),
+                event = poll_fn(|cx| Pin::new(&mut transport).poll(cx)) => assert!(
+                    matches!(event, TransportEvent::NewAddress { .. }),
+                    "unexpected transport event {:?}",
+                    event
+                ),
+            }
+        }
+        assert_eq!(received, (0..5u8).map(|i| vec![i]).collect::<Vec<_>>());
+        assert_eq!(transport.inbound_backlog.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_transport_with_config() {
+        let config = NymTransportConfig {
+            handshake_timeout_ms: Some(10_000),
+            packet_size: Some("extended8".to_string()),
+            inbound_high_watermark: Some(100),
+            memory_budget: Some(1 << 20),
+            echo_responder: true,
+            ..Default::default()
+        };
+        let transport = new_mock_transport(&MockMixnet::new())
+            .with_config(&config)
+            .unwrap();
+        assert_eq!(
+            transport.config_handle().config().handshake_timeout,
+            Duration::from_secs(10)
+        );
+        let options = transport.mixnet_options_tx.borrow().clone();
+        assert_eq!(options.packet_size, PacketSize::Extended8);
+        assert_eq!(options.inbound_watermarks, Some((100, 50)));
+        assert_eq!(transport.memory_budget().limit(), Some(1 << 20));
+        assert!(transport.echo_responder.is_some());
+        assert!(transport.latency_probe.is_none());
+
+        let config = NymTransportConfig {
+            latency_probe_interval_ms: Some(0),
+            ..Default::default()
+        };
+        assert!(new_mock_transport(&MockMixnet::new())
+            .with_config(&config)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transport_new_with_config() {
+        let config = NymTransportConfig::default()
+            .with_connect_timeout(Duration::from_secs(5))
+            .with_dial_timeout(Duration::from_secs(20))
+            .with_max_message_size(4)
+            .with_keepalive(Duration::from_secs(30), 5)
+            .with_anonymous_dials(8)
+            .with_inbound_watermarks(100, 10)
+            .unwrap()
+            .with_handshake_workers(2, 16)
+            .unwrap()
+            .with_max_concurrent_dials(8, 32)
+            .unwrap();
+        let transport = NymTransport::new_with_config(
+            "mock://test_transport_new_with_config",
+            Keypair::generate_ed25519(),
+            &config,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            transport.config_handle().config().handshake_timeout,
+            Duration::from_secs(20)
+        );
+        assert_eq!(transport.max_message_size, Some(4));
+        assert!(transport.keepalive.is_some());
+        assert_eq!(transport.max_missed_keepalives, 5);
+        assert_eq!(transport.default_reply_surbs, Some(8));
+        let options = transport.mixnet_options_tx.borrow().clone();
+        assert_eq!(options.inbound_watermarks, Some((100, 10)));
+
+        // a Nym client that accepts the connection but never answers the websocket handshake
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("ws://{}", listener.local_addr().unwrap());
+        let config = NymTransportConfig::default().with_connect_timeout(Duration::from_millis(50));
+        assert!(matches!(
+            NymTransport::new_with_config(endpoint, Keypair::generate_ed25519(), &config).await,
+            Err(Error::ConnectTimeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_transport_max_message_size() {
+        let (mut dialer_transport, mut listener_transport) = new_mock_transports(
+            |transport| transport.with_max_message_size(4).unwrap(),
+            identity,
+        )
+        .await;
+        let (mut dialer_conn, _listener_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+
+        // a longer write only writes the first 4 bytes
+        let mut substream = poll_fn(|cx| Pin::new(&mut dialer_conn).as_mut().poll_outbound(cx))
+            .await
+            .unwrap();
+        assert_eq!(substream.write(b"hello world").await.unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_transport_with_packet_size() {
+        let (mut dialer_transport, mut listener_transport) = new_mock_transports(
+            |transport| transport.with_packet_size(PacketSize::Extended16),
+            identity,
+        )
+        .await;
+        assert_eq!(
+            dialer_transport.mixnet_options_tx.borrow().packet_size,
+            PacketSize::Extended16
+        );
+        connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+    }
+
+    #[tokio::test]
+    async fn test_transport_max_message_packets() {
+        // the Nym client reports 100 bytes per packet
+        let mixnet = MockMixnet::new()
+            .with_send_outcomes()
+            .with_packet_payload_len(100);
+        let sender_transport = new_mock_transport(&mixnet)
+            .with_max_message_packets(2)
+            .unwrap();
+        let mut receiver_transport = new_mock_transport(&mixnet);
+        let mut broadcast_rx = receiver_transport.subscribe_broadcasts();
+
+        let payload: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        sender_transport
+            .mixnet_connection()
+            .broadcast(vec![receiver_transport.self_address], payload.clone())
+            .unwrap();
+        let received = loop {
+            tokio::select! {
+                payload = broadcast_rx.recv() => break payload.unwrap(),
+                _ = poll_fn(|cx| Pin::new(&mut receiver_transport).poll(cx)) => {}
+            }
+        };
+        assert_eq!(received, payload);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // fragments of up to 200 bytes, headers included
+        let handed = sender_transport.stats().gateway().handed_to_gateway;
+        assert!(handed > 1000 / 200, "{} fragments", handed);
+
+        new_mock_transport(&mixnet)
+            .with_packet_payload_len(FRAGMENT_HEADER_LEN)
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_transport_framing() {
+        let mixnet = MockMixnet::new().with_packet_payload_len(100);
+        let sender_transport = new_mock_transport(&mixnet)
+            .with_max_message_packets(2)
+            .unwrap()
+            .with_framing(Framing::V2);
+        let mut receiver_transport = new_mock_transport(&mixnet);
+        let mut broadcast_rx = receiver_transport.subscribe_broadcasts();
+
+        // v2 messages are read by a transport writing v1, fragmented or not
+        for len in [10, 1000] {
+            let payload: Vec<u8> = (0..len as u32).map(|i| i as u8).collect();
+            sender_transport
+                .mixnet_connection()
+                .broadcast(vec![receiver_transport.self_address], payload.clone())
+                .unwrap();
+            let received = loop {
+                tokio::select! {
+                    payload = broadcast_rx.recv() => break payload.unwrap(),
+                    _ = poll_fn(|cx| Pin::new(&mut receiver_transport).poll(cx)) => {}
+                }
+            };
+            assert_eq!(received, payload);
+        }
+        let stats = receiver_transport.stats();
+        assert!(stats.inbound_frames(Framing::V2) > 2);
+        assert_eq!(stats.inbound_frames(Framing::V1), 0);
+    }
+
+    #[tokio::test]
+    async fn test_transport_traffic_stats() {
+        let mixnet = MockMixnet::new().with_packet_payload_len(100);
+        let sender_transport = new_mock_transport(&mixnet)
+            .with_max_message_packets(2)
+            .unwrap();
+        let mut receiver_transport = new_mock_transport(&mixnet);
+        let mut broadcast_rx = receiver_transport.subscribe_broadcasts();
+
+        // a fragmented message counts once, on both sides
+        for len in [10, 1000] {
+            sender_transport
+                .mixnet_connection()
+                .broadcast(vec![receiver_transport.self_address], vec![0; len])
+                .unwrap();
+            loop {
+                tokio::select! {
+                    _ = broadcast_rx.recv() => break,
+                    _ = poll_fn(|cx| Pin::new(&mut receiver_transport).poll(cx)) => {}
+                }
+            }
+        }
+        let sent = sender_transport.stats().traffic();
+        let received = receiver_transport.stats().traffic();
+        assert_eq!(sent.messages_sent, 2);
+        assert!(sent.bytes_sent > 1010);
+        assert_eq!(received.messages_received, 2);
+        assert_eq!(received.bytes_received, sent.bytes_sent);
+        assert_eq!(received.reconnects, 0);
+
+        // dials and connections
+        let mut dialer_transport = new_mock_transport(&mixnet);
+        let mut listener_transport = new_mock_transport(&mixnet);
+        assert_new_address_event(Pin::new(&mut dialer_transport)).await;
+        assert_new_address_event(Pin::new(&mut listener_transport)).await;
+        let _conns = connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        // the gauge is updated on the next poll
+        for transport in [&mut dialer_transport, &mut listener_transport] {
+            let res = timeout(
+                Duration::from_millis(100),
+                poll_fn(|cx| Pin::new(&mut *transport).as_mut().poll(cx)),
+            )
+            .await;
+            assert!(res.is_err(), "unexpected transport event");
+            assert_eq!(transport.stats().active_connections(), 1);
+        }
+        let dials = dialer_transport.stats().dials();
+        assert_eq!(dials.succeeded, 1);
+        assert_eq!(dials.failed, 0);
+        assert_eq!(listener_transport.stats().dials().succeeded, 0);
+    }
+
+    #[tokio::test]
+    async fn test_transport_inbound_batching() {
+        let mixnet = MockMixnet::new();
+        let sender_transport = new_mock_transport(&mixnet);
+        let mut receiver_transport = new_mock_transport(&mixnet)
+            .with_inbound_batching(4, Duration::from_secs(10))
+            .unwrap();
+        let mut broadcasts = receiver_transport.subscribe_broadcasts();
+
+        // a full batch is passed on at once, long before the delay
+        for i in 0..4 {
+            sender_transport
+                .mixnet_connection()
+                .broadcast(vec![receiver_transport.self_address], vec![i])
+                .unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(broadcasts.try_recv().is_err());
+        poll_fn(|cx| {
+            while Pin::new(&mut receiver_transport)
+                .as_mut()
+                .poll(cx)
+                .is_ready()
+            {}
+            Poll::Ready(())
+        })
+        .await;
+        for i in 0..4 {
+            assert_eq!(broadcasts.try_recv().unwrap(), vec![i]);
+        }
+
+        new_mock_transport(&mixnet)
+            .with_inbound_batching(0, Duration::from_millis(5))
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_transport_pause_and_resume() {
+        let mut transport = new_mock_transport(&MockMixnet::new());
+        let handle = transport.lifecycle_handle();
+        assert!(!transport.update_paused());
+
+        transport.pause();
+        assert!(handle.is_paused());
+        assert!(transport.update_paused());
+        // pausing twice doesn't need resuming twice
+        handle.pause();
+        handle.resume();
+        assert!(!transport.lifecycle_handle().is_paused());
+        assert!(!transport.update_paused());
+    }
+
+    #[tokio::test]
+    async fn test_transport_reassembly_limits() {
+        let mixnet = MockMixnet::new().with_packet_payload_len(100);
+        let sender_transport = new_mock_transport(&mixnet)
+            .with_max_message_packets(2)
+            .unwrap();
+        let receiver_transport = new_mock_transport(&mixnet)
+            .with_reassembly_limits(Duration::from_millis(100), 1)
+            .unwrap();
+
+        // the first fragment of each message is lost, so neither can be completed
+        for _ in 0..2 {
+            mixnet.lose_before_gateway(1);
+            sender_transport
+                .mixnet_connection()
+                .broadcast(vec![receiver_transport.self_address], vec![1; 1000])
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        // the second message evicted the first, and then timed out without further fragments
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let reassembly = receiver_transport.stats().reassembly();
+        assert_eq!(reassembly.evicted, 1);
+        assert_eq!(reassembly.timed_out, 1);
+        assert_eq!(receiver_transport.memory_budget().used(), 0);
+
+        new_mock_transport(&mixnet)
+            .with_reassembly_limits(Duration::ZERO, 1)
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_transport_connection_negotiated_params() {
+        let (mut dialer_transport, mut listener_transport) =
+            new_mock_transports(identity, identity).await;
+        let (dialer_conn, listener_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+
+        assert_eq!(dialer_conn.negotiated(), listener_conn.negotiated());
+        assert_eq!(dialer_conn.negotiated().protocol_version, 1);
+        assert!(dialer_conn.negotiated().ordered_delivery);
+        assert!(!dialer_conn.negotiated().latency_extension);
+    }
+
+    #[tokio::test]
+    async fn test_transport_dial_options() {
+        let (mut dialer_transport, mut listener_transport) =
+            new_mock_transports(identity, identity).await;
+
+        let options = DialOptions::default()
+            .with_priority(MessagePriority::High)
+            .with_handshake_payload(b"hello".to_vec())
+            .unwrap();
+        dialer_transport
+            .dial_options_handle()
+            .set(listener_transport.listen_addr.clone(), options);
+        let (mut dialer_conn, listener_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+
+        assert_eq!(listener_conn.handshake_payload(), Some(&b"hello"[..]));
+        assert_eq!(dialer_conn.handshake_payload(), None);
+        let substream = poll_fn(|cx| Pin::new(&mut dialer_conn).as_mut().poll_outbound(cx))
+            .await
+            .unwrap();
+        assert_eq!(substream.priority(), MessagePriority::High);
+    }
+
+    #[tokio::test]
+    async fn test_transport_dial_progress() {
+        let (mut dialer_transport, mut listener_transport) =
+            new_mock_transports(identity, identity).await;
+        let (progress, mut phase_rx) = DialProgress::new();
+        let listener_multiaddr = listener_transport.listen_addr.clone();
+        dialer_transport.dial_options_handle().set(
+            listener_multiaddr.clone(),
+            DialOptions::default().with_progress(progress),
+        );
+
+        // the request reaches the Nym client before the listener does anything
+        let mut dial = tokio::spawn(dialer_transport.dial(listener_multiaddr).unwrap());
+        timeout(Duration::from_secs(1), async {
+            while *phase_rx.borrow() != DialPhase::AwaitingResponse {
+                phase_rx.changed().await.unwrap();
+            }
+        })
+        .await
+        .unwrap();
+
+        let upgrade = match poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)).await
+        {
+            TransportEvent::Incoming { upgrade, .. } => upgrade,
+            res => panic!("expected TransportEvent::Incoming, got {:?}", res),
+        };
+        upgrade.await.unwrap();
+        tokio::select! {
+            res = &mut dial => res.unwrap().unwrap(),
+            event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                panic!("unexpected transport event {:?}", event)
+            }
+        };
+        assert_eq!(*phase_rx.borrow(), DialPhase::Established);
+    }
+
+    #[tokio::test]
+    async fn test_transport_anonymous_dial() {
+        let (mut dialer_transport, mut listener_transport) =
+            new_mock_transports(identity, identity).await;
+        let dialer_budget = dialer_transport.memory_budget();
+        let listener_budget = listener_transport.memory_budget();
+
+        dialer_transport.dial_options_handle().set(
+            listener_transport.listen_addr.clone(),
+            DialOptions::default().with_anonymous(10),
+        );
+        let (mut dialer_conn, mut listener_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+
+        // the listener never learns the dialer's address, and replies to its sender tag
+        assert_eq!(
+            dialer_conn.remote_recipient.route(),
+            Route::Anonymous { reply_surbs: 10 }
+        );
+        assert!(matches!(
+            listener_conn.remote_recipient.route(),
+            Route::Reply(_)
+        ));
+        assert_ne!(
+            listener_conn.remote_recipient.get(),
+            dialer_transport.self_address
+        );
+
+        let mut dialer_substream =
+            poll_fn(|cx| Pin::new(&mut dialer_conn).as_mut().poll_outbound(cx))
+                .await
+                .unwrap();
+        dialer_substream.write_all(b"hello").await.unwrap();
+        timeout(Duration::from_secs(1), async {
+            while listener_budget.used_by(BufferKind::Substream) < 5 {
+                tokio::select! {
+                    event = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)) => {
+                        panic!("unexpected transport event {:?}", event)
+                    }
+                    _ = poll_fn(|cx| Pin::new(&mut listener_conn).as_mut().poll(cx)) => {}
+                    _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+        let mut listener_substream =
+            poll_fn(|cx| Pin::new(&mut listener_conn).as_mut().poll_inbound(cx))
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        let mut buf = [0u8; 5];
+        listener_substream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        listener_substream.write_all(b"hi").await.unwrap();
+        timeout(Duration::from_secs(1), async {
+            while dialer_budget.used_by(BufferKind::Substream) < 2 {
+                tokio::select! {
+                    event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                        panic!("unexpected transport event {:?}", event)
+                    }
+                    _ = poll_fn(|cx| Pin::new(&mut dialer_conn).as_mut().poll(cx)) => {}
+                    _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+        let mut buf = [0u8; 2];
+        dialer_substream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+    }
+
+    #[tokio::test]
+    async fn test_transport_acceptor() {
+        let (mut dialer_transport, mut listener_transport) =
+            new_mock_transports(identity, identity).await;
+        let mut acceptor =
+            listener_transport.acceptor(AcceptorMatch::HandshakePayloadPrefix(b"custom/".to_vec()));
+
+        // connections the acceptor selects never reach the swarm
+        let listener_multiaddr = listener_transport.listen_addr.clone();
+        let options = DialOptions::default()
+            .with_handshake_payload(b"custom/1.0".to_vec())
+            .unwrap();
+        dialer_transport
+            .dial_options_handle()
+            .set(listener_multiaddr.clone(), options);
+        let mut dial = tokio::spawn(dialer_transport.dial(listener_multiaddr).unwrap());
+        let upgrade = tokio::select! {
+            upgrade = acceptor.accept() => upgrade.unwrap(),
+            event = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)) => {
+                panic!("unexpected transport event {:?}", event)
+            }
+        };
+        let (peer_id, listener_conn) = upgrade.await.unwrap();
+        assert_eq!(peer_id, dialer_transport.peer_id());
+        assert_eq!(listener_conn.handshake_payload(), Some(&b"custom/1.0"[..]));
+        tokio::select! {
+            res = &mut dial => { res.unwrap().unwrap(); }
+            event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                panic!("unexpected transport event {:?}", event)
+            }
+        };
+
+        // the rest go to the swarm, as do all of them once the acceptor is dropped
+        let options = dialer_transport
+            .dial_options_handle()
+            .remove(&listener_transport.listen_addr)
+            .unwrap();
+        connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        drop(acceptor);
+        dialer_transport
+            .dial_options_handle()
+            .set(listener_transport.listen_addr.clone(), options);
+        connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        assert!(listener_transport.acceptors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_transport_preconnect() {
+        let (mut dialer_transport, mut listener_transport) =
+            new_mock_transports(identity, identity).await;
+
+        let listener_multiaddr = listener_transport.listen_addr.clone();
+        dialer_transport
+            .preconnect_handle()
+            .preconnect(listener_multiaddr.clone())
+            .unwrap();
+        // the dialer transport needs to be polled to start preconnecting
+        let upgrade = tokio::select! {
+            event = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)) => {
+                match event {
+                    TransportEvent::Incoming { upgrade, .. } => upgrade,
+                    res => panic!("expected TransportEvent::Incoming, got {:?}", res),
+                }
+            }
+            event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                panic!("unexpected transport event {:?}", event)
+            }
+        };
+        let (_, listener_conn) = upgrade.await.unwrap();
+
+        // the dialer transport parks the connection once it handles the response
+        timeout(Duration::from_secs(5), async {
+            while !dialer_transport.is_preconnected(&listener_multiaddr) {
+                tokio::select! {
+                    event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                        panic!("unexpected transport event {:?}", event)
+                    }
+                    _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        // the dial returns the parked connection without another handshake
+        let with_peer_id =
+            listener_multiaddr.with(Protocol::P2p(listener_transport.peer_id().into()));
+        let (peer_id, dialer_conn) = dialer_transport
+            .dial(with_peer_id.clone())
+            .unwrap()
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer_id, listener_transport.peer_id());
+        assert!(dialer_conn.id == listener_conn.id);
+        assert!(!dialer_transport.is_preconnected(&with_peer_id));
+    }
+
+    #[tokio::test]
+    async fn test_transport_peer_pinning() {
+        let (mut dialer_transport, mut listener_transport) = new_mock_transports(
+            |transport| {
+                transport.with_peer_pinning(RedialBackoff {
+                    initial: Duration::from_millis(50),
+                    max: Duration::from_secs(1),
+                })
+            },
+            identity,
+        )
+        .await;
+
+        let mut events_rx = dialer_transport.subscribe_pinned_peer_events();
+        let listener_peer_id = listener_transport.peer_id();
+        let listener_multiaddr = listener_transport.listen_addr.clone();
+        dialer_transport
+            .pinned_peers()
+            .pin(listener_peer_id, listener_multiaddr.clone());
+
+        // the peer is dialed right away, and again once the connection is dropped
+        for _ in 0..2 {
+            let mut listener_conn = None;
+            let mut dialer_conn = None;
+            timeout(Duration::from_secs(5), async {
+                while listener_conn.is_none() || dialer_conn.is_none() {
+                    tokio::select! {
+                        event = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)) => {
+                            match event {
+                                TransportEvent::Incoming { upgrade, .. } => {
+                                    listener_conn = Some(upgrade.await.unwrap());
+                                }
+                                res => panic!("expected TransportEvent::Incoming, got {:?}", res),
+                            }
+                        }
+                        event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                            match event {
+                                TransportEvent::Incoming { upgrade, send_back_addr, .. } => {
+                                    assert_eq!(send_back_addr, listener_multiaddr);
+                                    dialer_conn = Some(upgrade.await.unwrap());
+                                }
+                                res => panic!("expected TransportEvent::Incoming, got {:?}", res),
+                            }
+                        }
+                    }
+                }
+            })
+            .await
+            .unwrap();
+
+            let (peer_id, dialer_conn) = dialer_conn.unwrap();
+            assert_eq!(peer_id, listener_peer_id);
+            assert_eq!(
+                events_rx.recv().await,
+                Some(PinnedPeerEvent::Redialing {
+                    peer_id,
+                    address: listener_multiaddr.clone(),
+                    attempt: 1,
+                })
+            );
+            assert_eq!(
+                events_rx.recv().await,
+                Some(PinnedPeerEvent::Reconnected {
+                    peer_id,
+                    attempt: 1,
+                })
+            );
+            drop(dialer_conn);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transport_latency_extension_negotiation() {
+        let (mut dialer_transport, mut listener_transport) =
+            new_mock_transports(|transport| transport.with_latency_extension(), identity).await;
+
+        // only offered by the dialer
+        let (dialer_conn, listener_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        assert!(!dialer_conn.negotiated().latency_extension);
+        assert!(!listener_conn.negotiated().latency_extension);
+
+        let mut listener_transport = listener_transport.with_latency_extension();
+        let (dialer_conn, listener_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        assert!(dialer_conn.negotiated().latency_extension);
+        assert!(listener_conn.negotiated().latency_extension);
+    }
+
+    #[tokio::test]
+    async fn test_transport_user_agent() {
+        let (mut dialer_transport, mut listener_transport) = new_mock_transports(
+            |transport| transport.with_custom_user_agent("app/2.0").unwrap(),
+            identity,
+        )
+        .await;
+
+        // the listener learns the dialer's user agent without announcing its own
+        let (dialer_conn, listener_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        assert_eq!(dialer_conn.negotiated().remote_user_agent, None);
+        assert_eq!(
+            listener_conn.negotiated().remote_user_agent.as_deref(),
+            Some("app/2.0")
+        );
+
+        let mut listener_transport = listener_transport.with_user_agent();
+        let (dialer_conn, _listener_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        assert_eq!(
+            dialer_conn.negotiated().remote_user_agent.as_deref(),
+            Some(crate::USER_AGENT)
+        );
+        let user_agents = dialer_transport.stats.user_agents();
+        assert_eq!(user_agents[crate::stats::UNKNOWN_USER_AGENT], 1);
+        assert_eq!(user_agents[crate::USER_AGENT], 1);
+        assert_eq!(listener_transport.stats.user_agents()["app/2.0"], 2);
+
+        assert!(matches!(
+            new_mock_transport(&mixnet).with_custom_user_agent(""),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_transport_compact_connection_ids() {
+        let (mut dialer_transport, mut listener_transport) = new_mock_transports(
+            |transport| transport.with_compact_connection_ids(),
+            |transport| transport.with_compact_connection_ids(),
+        )
+        .await;
+        let budget = listener_transport.memory_budget();
+
+        let (mut dialer_conn, mut listener_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        assert!(dialer_conn.negotiated().compact_connection_ids);
+        assert!(listener_conn.negotiated().compact_connection_ids);
+        assert_eq!(dialer_transport.compact_refs.len(), 1);
+        assert_eq!(listener_transport.compact_refs.len(), 1);
+
+        // the substream's messages only carry the listener's reference to the connection
+        let mut dialer_substream =
+            poll_fn(|cx| Pin::new(&mut dialer_conn).as_mut().poll_outbound(cx))
+                .await
+                .unwrap();
+        dialer_substream.write_all(b"hello").await.unwrap();
+        timeout(Duration::from_secs(1), async {
+            while budget.used_by(BufferKind::Substream) < 5 {
+                tokio::select! {
+                    event = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)) => {
+                        panic!("unexpected transport event {:?}", event)
                     }
-                    Err(e) => Err(e),
+                    _ = poll_fn(|cx| Pin::new(&mut listener_conn).as_mut().poll(cx)) => {}
+                    _ = tokio::time::sleep(Duration::from_millis(10)) => {}
                 }
             }
-            Message::ConnectionResponse(msg) => {
-                debug!("got inbound connection response {:?}", msg);
-                self.handle_connection_response(&msg)
-                    .map(|_| InboundTransportEvent::ConnectionResponse)
+        })
+        .await
+        .unwrap();
+        let mut listener_substream =
+            poll_fn(|cx| Pin::new(&mut listener_conn).as_mut().poll_inbound(cx))
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        let mut buf = [0u8; 5];
+        listener_substream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // the reference is forgotten along with the connection
+        drop(listener_substream);
+        drop(listener_conn);
+        timeout(Duration::from_secs(1), async {
+            while !listener_transport.compact_refs.is_empty() {
+                tokio::select! {
+                    event = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)) => {
+                        panic!("unexpected transport event {:?}", event)
+                    }
+                    _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+                }
             }
-            Message::TransportMessage(msg) => {
-                debug!("got inbound TransportMessage: {:?}", msg);
-                self.handle_transport_message(msg)
-                    .map(|_| InboundTransportEvent::TransportMessage)
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transport_substream_limits() {
+        let (mut dialer_transport, mut listener_transport) =
+            new_mock_transports(identity, |transport| {
+                transport
+                    .with_runtime_config(RuntimeConfig {
+                        max_substreams_per_connection: Some(1),
+                        ..Default::default()
+                    })
+                    .unwrap()
+            })
+            .await;
+        let listener_stats = listener_transport.stats();
+
+        let (mut dialer_conn, mut listener_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        let mut first = poll_fn(|cx| Pin::new(&mut dialer_conn).as_mut().poll_outbound(cx))
+            .await
+            .unwrap();
+        let mut second = poll_fn(|cx| Pin::new(&mut dialer_conn).as_mut().poll_outbound(cx))
+            .await
+            .unwrap();
+        first.write_all(b"hello").await.unwrap();
+
+        // the listener accepts the first substream and resets the second
+        timeout(Duration::from_secs(1), async {
+            while listener_stats.substream_resets() == 0 {
+                tokio::select! {
+                    event = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)) => {
+                        panic!("unexpected transport event {:?}", event)
+                    }
+                    _ = poll_fn(|cx| Pin::new(&mut listener_conn).as_mut().poll(cx)) => {}
+                    _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+        let mut listener_substream =
+            poll_fn(|cx| Pin::new(&mut listener_conn).as_mut().poll_inbound(cx))
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        assert!(
+            poll_fn(|cx| Pin::new(&mut listener_conn).as_mut().poll_inbound(cx))
+                .now_or_never()
+                .is_none()
+        );
+
+        // the dialer's second substream fails once the reset arrives
+        let mut buf = [0u8; 5];
+        let err = timeout(Duration::from_secs(1), async {
+            loop {
+                if let Some(res) = second.read(&mut buf).now_or_never() {
+                    break res.unwrap_err();
+                }
+                tokio::select! {
+                    event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                        panic!("unexpected transport event {:?}", event)
+                    }
+                    _ = poll_fn(|cx| Pin::new(&mut dialer_conn).as_mut().poll(cx)) => {}
+                    _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+        assert!(err.to_string().contains("too many streams"));
+
+        // the first substream is unaffected
+        listener_substream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_transport_clock_skew_detection() {
+        // the listener's estimate includes the time the request took, which is over zero
+        let (mut dialer_transport, mut listener_transport) = new_mock_transports(
+            |transport| transport.with_clock_skew_detection(Duration::from_secs(60)),
+            |transport| transport.with_clock_skew_detection(Duration::ZERO),
+        )
+        .await;
+        let dialer_stats = dialer_transport.stats();
+        let listener_stats = listener_transport.stats();
+        let mut listener_events = listener_transport.mixnet_connection().connection_events();
+
+        let (_dialer_conn, _listener_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        let dialer_offset = dialer_stats
+            .clock_offset(&listener_transport.peer_id())
+            .unwrap();
+        assert!(dialer_offset.abs() < 1_000_000);
+        assert_eq!(dialer_stats.clock_skews(), 0);
+        let listener_offset = listener_stats
+            .clock_offset(&dialer_transport.peer_id())
+            .unwrap();
+        assert!(listener_offset < 0);
+        assert_eq!(listener_stats.clock_skews(), 1);
+        assert_eq!(
+            listener_events.next().await,
+            Some(ConnectionEvent::ClockSkewed {
+                peer_id: dialer_transport.peer_id(),
+                offset_micros: listener_offset,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transport_echo() {
+        let (mut requester_transport, mut responder_transport) = new_mock_transports(
+            |transport| {
+                transport
+                    .with_runtime_config(RuntimeConfig {
+                        handshake_timeout: Duration::from_millis(200),
+                        ..Default::default()
+                    })
+                    .unwrap()
+            },
+            identity,
+        )
+        .await;
+        let responder_multiaddr = responder_transport.listen_addr.clone();
+
+        // both sides need to be polled for the request and reply to be handled; no
+        // connection is established
+        let echo = requester_transport.echo(&responder_multiaddr).unwrap();
+        let res = tokio::select! {
+            res = echo => res,
+            event = poll_fn(|cx| Pin::new(&mut requester_transport).as_mut().poll(cx)) => {
+                panic!("unexpected transport event {:?}", event)
+            }
+            event = poll_fn(|cx| Pin::new(&mut responder_transport).as_mut().poll(cx)) => {
+                panic!("unexpected transport event {:?}", event)
+            }
+        };
+        assert!(matches!(res, Err(Error::EchoTimeout)));
+
+        let mut responder_transport = responder_transport.with_echo_responder(None);
+        let echo = requester_transport.echo(&responder_multiaddr).unwrap();
+        let result = tokio::select! {
+            res = echo => res.unwrap(),
+            event = poll_fn(|cx| Pin::new(&mut requester_transport).as_mut().poll(cx)) => {
+                panic!("unexpected transport event {:?}", event)
+            }
+            event = poll_fn(|cx| Pin::new(&mut responder_transport).as_mut().poll(cx)) => {
+                panic!("unexpected transport event {:?}", event)
+            }
+        };
+        assert!(result.rtt < Duration::from_millis(200));
+        assert!(requester_transport.connections.is_empty());
+        assert!(responder_transport.connections.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_transport_connection_events() {
+        let (mut dialer_transport, mut listener_transport) =
+            new_mock_transports(identity, identity).await;
+        let mut dialer_events = dialer_transport.mixnet_connection().connection_events();
+        let mut listener_events = listener_transport.mixnet_connection().connection_events();
+
+        let (mut dialer_conn, mut listener_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        assert_eq!(
+            dialer_events.next().await,
+            Some(ConnectionEvent::Opened {
+                peer_id: listener_transport.peer_id(),
+                direction: ConnectionDirection::Outbound,
+            })
+        );
+        assert_eq!(
+            listener_events.next().await,
+            Some(ConnectionEvent::Opened {
+                peer_id: dialer_transport.peer_id(),
+                direction: ConnectionDirection::Inbound,
+            })
+        );
+
+        // the transport needs to be polled to notice the connection was dropped
+        dialer_conn.set_close_reason(ShutdownReason::Policy);
+        drop(dialer_conn);
+        let event = tokio::select! {
+            event = dialer_events.next() => event,
+            event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                panic!("unexpected transport event {:?}", event)
             }
+        };
+        assert_eq!(
+            event,
+            Some(ConnectionEvent::Closed {
+                peer_id: listener_transport.peer_id(),
+                reason: CloseReason::Dropped,
+            })
+        );
+        assert!(dialer_transport.connections.is_empty());
+
+        // the listener is told why, and drops its end
+        let event = tokio::select! {
+            event = listener_events.next() => event,
+            event = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)) => {
+                panic!("unexpected transport event {:?}", event)
+            }
+        };
+        assert_eq!(
+            event,
+            Some(ConnectionEvent::Closed {
+                peer_id: dialer_transport.peer_id(),
+                reason: CloseReason::Remote(ShutdownReason::Policy),
+            })
+        );
+        assert!(listener_transport.connections.is_empty());
+        assert!(matches!(
+            poll_fn(|cx| Pin::new(&mut listener_conn).poll(cx)).await,
+            Err(Error::ConnectionDropped)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_transport_close_acks() {
+        let mixnet = MockMixnet::new();
+        let (mut dialer_transport, mut listener_transport) = new_mock_transports_on(
+            &mixnet,
+            |transport| transport.with_close_acks(),
+            |transport| transport.with_close_acks(),
+        )
+        .await;
+        let mut dialer_events = dialer_transport.mixnet_connection().connection_events();
+        let mut listener_events = listener_transport.mixnet_connection().connection_events();
+        let (dialer_conn, _listener_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        dialer_events.next().await.unwrap();
+        listener_events.next().await.unwrap();
+
+        // the first close is lost, and stays unacknowledged
+        mixnet.lose_before_gateway(1);
+        drop(dialer_conn);
+        let event = tokio::select! {
+            event = dialer_events.next() => event,
+            event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                panic!("unexpected transport event {:?}", event)
+            }
+        };
+        assert!(matches!(event, Some(ConnectionEvent::Closed { .. })));
+        assert_eq!(dialer_transport.unacked_closes.len(), 1);
+
+        // once it's sent again, the listener drops its end and acknowledges it
+        for close in dialer_transport.unacked_closes.values_mut() {
+            close.sent_at -= CLOSE_RETRY_INTERVAL;
         }
+        dialer_transport.retry_closes();
+        let close = dialer_transport.unacked_closes.values().next().unwrap();
+        assert_eq!(close.retries, 1);
+        let event = tokio::select! {
+            event = listener_events.next() => event,
+            event = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)) => {
+                panic!("unexpected transport event {:?}", event)
+            }
+        };
+        assert!(matches!(event, Some(ConnectionEvent::Closed { .. })));
+        assert_eq!(listener_transport.acked_closes.len(), 1);
+
+        timeout(
+            Duration::from_secs(5),
+            poll_fn(|cx| {
+                let _ = Pin::new(&mut dialer_transport).as_mut().poll(cx);
+                if dialer_transport.unacked_closes.is_empty() {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            }),
+        )
+        .await
+        .unwrap();
     }
-}
 
-/// Upgrade represents a transport listener upgrade.
-/// Note: we immediately upgrade a connection request to a connection,
-/// so this only contains a channel for receiving that connection.
-pub struct Upgrade {
-    connection_tx: oneshot::Receiver<(PeerId, Connection)>,
-}
+    #[tokio::test]
+    async fn test_transport_keepalives_are_not_probes() {
+        // latency probing is off, and the keepalives are sent by hand rather than on the
+        // interval
+        let (mut dialer_transport, mut listener_transport) = new_mock_transports(
+            |transport| {
+                transport
+                    .with_keepalive(Duration::from_secs(3600), 100)
+                    .unwrap()
+            },
+            identity,
+        )
+        .await;
+        let _conns = connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
 
-impl Upgrade {
-    fn new(connection_tx: oneshot::Receiver<(PeerId, Connection)>) -> Upgrade {
-        Upgrade { connection_tx }
+        for _ in 0..3 {
+            dialer_transport.send_keepalives();
+            let handle = dialer_transport.connections.values().next().unwrap();
+            assert_eq!(handle.unanswered_keepalives, 1);
+
+            // the listener's answer resets the miss counter
+            timeout(
+                Duration::from_secs(1),
+                poll_fn(|cx| {
+                    assert!(Pin::new(&mut listener_transport).poll(cx).is_pending());
+                    assert!(Pin::new(&mut dialer_transport).poll(cx).is_pending());
+                    let handle = dialer_transport.connections.values().next().unwrap();
+                    if handle.unanswered_keepalives == 0 {
+                        Poll::Ready(())
+                    } else {
+                        Poll::Pending
+                    }
+                }),
+            )
+            .await
+            .unwrap();
+        }
+
+        // the answered keepalives don't count as probes
+        let handle = dialer_transport.connections.values().next().unwrap();
+        assert_eq!(handle.probes_sent, 0);
+        assert_eq!(handle.probes_answered, 0);
+        assert!(dialer_transport
+            .stats()
+            .quality(&listener_transport.peer_id())
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_transport_keepalive() {
+        let (mut dialer_transport, mut listener_transport) = new_mock_transports(
+            |transport| {
+                transport
+                    .with_keepalive(Duration::from_millis(100), 2)
+                    .unwrap()
+            },
+            identity,
+        )
+        .await;
+        let mut dialer_events = dialer_transport.mixnet_connection().connection_events();
+        let (mut dialer_conn, _listener_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        dialer_events.next().await.unwrap();
+
+        // the listener answers the keepalives, so the connection stays up
+        let res = timeout(Duration::from_millis(500), async {
+            loop {
+                tokio::select! {
+                    event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                        panic!("unexpected transport event {:?}", event)
+                    }
+                    event = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)) => {
+                        panic!("unexpected transport event {:?}", event)
+                    }
+                    res = poll_fn(|cx| Pin::new(&mut dialer_conn).as_mut().poll(cx)) => break res,
+                }
+            }
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(dialer_transport.connections.len(), 1);
+
+        // once it stops, the connection fails after two unanswered keepalives
+        let res = timeout(Duration::from_secs(2), async {
+            loop {
+                tokio::select! {
+                    event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                        panic!("unexpected transport event {:?}", event)
+                    }
+                    res = poll_fn(|cx| Pin::new(&mut dialer_conn).as_mut().poll(cx)) => break res,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(matches!(res, Err(Error::KeepaliveTimeout)));
+        assert!(dialer_transport.connections.is_empty());
+        assert_eq!(
+            dialer_events.next().await,
+            Some(ConnectionEvent::Closed {
+                peer_id: listener_transport.peer_id(),
+                reason: CloseReason::KeepaliveTimeout,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transport_memory_budget() {
+        let mixnet = MockMixnet::new();
+        let (mut dialer_transport, mut listener_transport) =
+            new_mock_transports_on(&mixnet, identity, |transport| {
+                transport.with_memory_budget(1000).unwrap()
+            })
+            .await;
+        assert!(new_mock_transport(&mixnet).with_memory_budget(0).is_err());
+        let budget = listener_transport.memory_budget();
+        assert_eq!(budget.limit(), Some(1000));
+
+        let (mut dialer_conn, mut listener_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        let mut dialer_substream =
+            poll_fn(|cx| Pin::new(&mut dialer_conn).as_mut().poll_outbound(cx))
+                .await
+                .unwrap();
+        dialer_substream.write_all(&[1u8; 900]).await.unwrap();
+
+        // the data counts towards the budget until the application reads it
+        timeout(Duration::from_secs(1), async {
+            while budget.used_by(BufferKind::Substream) < 900 {
+                tokio::select! {
+                    event = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)) => {
+                        panic!("unexpected transport event {:?}", event)
+                    }
+                    _ = poll_fn(|cx| Pin::new(&mut listener_conn).as_mut().poll(cx)) => {}
+                    _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(budget.is_under_pressure());
+
+        // which holds back writes in the meantime
+        let mut listener_substream =
+            poll_fn(|cx| Pin::new(&mut listener_conn).as_mut().poll_inbound(cx))
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        assert!(listener_substream.write(b"hi").now_or_never().is_none());
+
+        let mut buf = [0u8; 900];
+        listener_substream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(budget.used_by(BufferKind::Substream), 0);
+        assert!(!budget.is_under_pressure());
+        listener_substream.write_all(b"hi").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transport_message_capture() {
+        let (mut dialer_transport, mut listener_transport) =
+            new_mock_transports(identity, identity).await;
+        let dialer_stats = dialer_transport.stats();
+        let listener_stats = listener_transport.stats();
+        dialer_stats.set_message_capture(Some(10));
+        listener_stats.set_message_capture(Some(10));
+
+        let (mut dialer_conn, _listener_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        let listener_peer_id = listener_transport.peer_id();
+        let dialer_peer_id = dialer_transport.peer_id();
+        let _substream = poll_fn(|cx| Pin::new(&mut dialer_conn).as_mut().poll_outbound(cx))
+            .await
+            .unwrap();
+
+        // the substream open request is captured on both ends, without polling
+        let open_request = timeout(Duration::from_secs(1), async {
+            loop {
+                let inbound = listener_stats
+                    .captured_messages(&dialer_peer_id)
+                    .into_iter()
+                    .find(|message| message.direction == JournalDirection::Inbound);
+                if let Some(message) = inbound {
+                    return message;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(open_request.kind, MessageKind::Transport);
+        assert!(dialer_stats
+            .captured_messages(&listener_peer_id)
+            .iter()
+            .any(|message| message.direction == JournalDirection::Outbound
+                && message.bytes == open_request.bytes));
+
+        dialer_stats.set_message_capture(None);
+        assert!(dialer_stats.captured_messages(&listener_peer_id).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_transport_broadcast() {
+        let (mut dialer_transport, mut listener_transport) =
+            new_mock_transports(identity, identity).await;
+        let _conns = connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+
+        let mut broadcast_rx = listener_transport.subscribe_broadcasts();
+        dialer_transport.broadcast(b"hello".to_vec()).unwrap();
+
+        // the listener transport needs to be polled to receive the broadcast
+        let payload = tokio::select! {
+            payload = broadcast_rx.recv() => payload.unwrap(),
+            event = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)) => {
+                panic!("unexpected transport event {:?}", event)
+            }
+        };
+        assert_eq!(payload, b"hello".to_vec());
+    }
+
+    #[tokio::test]
+    async fn test_transport_datagrams() {
+        let mixnet = MockMixnet::new();
+        let sender_transport = new_mock_transport(&mixnet);
+        let mut receiver_transport = new_mock_transport(&mixnet);
+        assert_new_address_event(Pin::new(&mut receiver_transport)).await;
+        let mut datagram_rx = receiver_transport.subscribe_datagrams();
+        let mut broadcast_rx = receiver_transport.subscribe_broadcasts();
+
+        // datagrams don't need a connection, and aren't mistaken for broadcasts
+        let recipient = receiver_transport.self_address;
+        for datagram in [b"one".to_vec(), b"two".to_vec()] {
+            sender_transport.send_datagram(recipient, datagram).unwrap();
+        }
+        let mut received = vec![];
+        while received.len() < 2 {
+            tokio::select! {
+                datagram = datagram_rx.recv() => received.push(datagram.unwrap()),
+                event = poll_fn(|cx| Pin::new(&mut receiver_transport).as_mut().poll(cx)) => {
+                    panic!("unexpected transport event {:?}", event)
+                }
+            }
+        }
+        assert_eq!(received, vec![b"one".to_vec(), b"two".to_vec()]);
+        assert!(broadcast_rx.try_recv().is_err());
+        assert!(receiver_transport.connections.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_transport_error_injector() {
+        let (mut dialer_transport, mut listener_transport) = new_mock_transports(
+            |transport| transport.with_dial_queuing(Duration::from_secs(1)),
+            identity,
+        )
+        .await;
+        let injector = dialer_transport.injector.clone();
+
+        injector.fail_next_dial();
+        let dial = dialer_transport
+            .dial(listener_transport.listen_addr.clone())
+            .unwrap();
+        assert!(matches!(dial.await, Err(Error::InjectedFailure(_))));
+
+        // the transport reconnects, and dials work again
+        injector.drop_mixnet_connection();
+        // give the mixnet task a moment to reconnect, so the handshake isn't lost with
+        // the old connection
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let _conns = connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+
+        // a delayed broadcast arrives after one sent later
+        let mut broadcast_rx = listener_transport.subscribe_broadcasts();
+        listener_transport
+            .injector
+            .delay_inbound(1, Duration::from_millis(200));
+        dialer_transport.broadcast(b"first".to_vec()).unwrap();
+        dialer_transport.broadcast(b"second".to_vec()).unwrap();
+        for expected in [&b"second"[..], &b"first"[..]] {
+            let payload = tokio::select! {
+                payload = broadcast_rx.recv() => payload.unwrap(),
+                event = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)) => {
+                    panic!("unexpected transport event {:?}", event)
+                }
+            };
+            assert_eq!(payload, expected.to_vec());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transport_upgrade_timeout() {
+        let (mut dialer_transport, mut listener_transport) =
+            new_mock_transports(identity, |transport| {
+                transport.with_upgrade_timeout(Duration::from_secs(30))
+            })
+            .await;
+        let stats = listener_transport.stats();
+
+        // the dialer's ends are kept, or the dialer would close the connections
+        let (_stalled_dialer_conn, mut stalled_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        let (_upgraded_dialer_conn, upgraded_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        // as if a protocol had been negotiated on one of its substreams
+        upgraded_conn.upgraded.store(true, Ordering::Relaxed);
+
+        // the timeout passes on the paused clock, without waiting for it
+        let _time = VirtualTime::start();
+        timeout(
+            Duration::from_secs(90),
+            poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(stats.upgrade_timeouts(), 1);
+        assert_eq!(listener_transport.connections.len(), 1);
+        assert!(matches!(
+            poll_fn(|cx| Pin::new(&mut stalled_conn).poll(cx)).await,
+            Err(Error::ConnectionDropped)
+        ));
     }
-}
 
-impl Future for Upgrade {
-    type Output = Result<(PeerId, Connection), Error>;
+    #[tokio::test]
+    async fn test_transport_inbound_filter() {
+        let mixnet = MockMixnet::new();
+        let dialer_keypair = Keypair::generate_ed25519();
+        let dialer_peer_id = PeerId::from_public_key(&dialer_keypair.public());
+        let mut dialer_transport =
+            NymTransport::new_with_backend(mixnet.new_backend(), dialer_keypair).unwrap();
+        let mut listener_transport = new_mock_transport(&mixnet).with_inbound_filter(
+            move |sender: &MessageSender, msg: &InboundMessage| match msg.kind() {
+                MessageKind::ConnectionRequest if sender.peer_id == Some(dialer_peer_id) => {
+                    FilterAction::Tag("known-peer")
+                }
+                MessageKind::Broadcast => FilterAction::Drop,
+                _ => FilterAction::Accept,
+            },
+        );
+        let stats = listener_transport.stats();
+        assert_new_address_event(Pin::new(&mut dialer_transport)).await;
+        assert_new_address_event(Pin::new(&mut listener_transport)).await;
 
-    // poll checks if the upgrade has turned into a connection yet
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        self.connection_tx
-            .poll_unpin(cx)
-            .map_err(|_| Error::RecvError)
+        let _conns = connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        assert_eq!(stats.filter_tags().get("known-peer"), Some(&1));
+
+        let mut broadcast_rx = listener_transport.subscribe_broadcasts();
+        dialer_transport.broadcast(b"spam".to_vec()).unwrap();
+        let res = timeout(
+            Duration::from_millis(200),
+            poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)),
+        )
+        .await;
+        assert!(res.is_err(), "unexpected transport event");
+        assert!(broadcast_rx.try_recv().is_err());
+        assert_eq!(stats.filter_drops(), 1);
     }
-}
 
-impl Transport for NymTransport {
-    type Output = (PeerId, Connection);
-    type Error = Error;
-    type ListenerUpgrade = Upgrade;
-    type Dial = Pin<Box<dyn Future<Output = Result<Self::Output, Self::Error>> + Send>>;
+    #[tokio::test]
+    async fn test_transport_audit_log() {
+        let records = Arc::new(Mutex::new(vec![]));
+        let dialer_records = records.clone();
+        let listener_records = records.clone();
+        let (mut dialer_transport, mut listener_transport) = new_mock_transports(
+            |transport| {
+                transport.with_audit_log(move |record: &AuditRecord| {
+                    dialer_records.lock().push(record.clone());
+                    Ok(())
+                })
+            },
+            |transport| {
+                transport.with_audit_log(move |record: &AuditRecord| {
+                    listener_records.lock().push(record.clone());
+                    Ok(())
+                })
+            },
+        )
+        .await;
+        let _conns = connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
 
-    fn listen_on(&mut self, _: Multiaddr) -> Result<ListenerId, TransportError<Self::Error>> {
-        // we should only allow listening on the multiaddress containing our Nym address
-        Ok(self.listener_id)
+        // the listener handles the request before the dialer gets the response
+        let records = records.lock();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, ConnectionDirection::Inbound);
+        assert_eq!(records[0].peer_id, Some(dialer_transport.peer_id()));
+        assert_eq!(records[0].address, Some(dialer_transport.self_address));
+        assert_eq!(records[0].outcome, ConnectionOutcome::Established);
+        assert_eq!(records[1].direction, ConnectionDirection::Outbound);
+        assert_eq!(records[1].peer_id, Some(listener_transport.peer_id()));
+        assert_eq!(records[1].address, Some(listener_transport.self_address));
+        assert_eq!(records[1].outcome, ConnectionOutcome::Established);
+        assert!(records[1].started_at <= records[1].finished_at);
     }
 
-    fn remove_listener(&mut self, id: ListenerId) -> bool {
-        if self.listener_id != id {
-            return false;
-        }
+    #[tokio::test]
+    async fn test_transport_update_config_deny_list() {
+        let (mut dialer_transport, mut listener_transport) = new_mock_transports(
+            |transport| transport.with_timeout(Duration::from_millis(200)),
+            identity,
+        )
+        .await;
 
-        // TODO: close channels?
-        self.poll_tx
-            .send(TransportEvent::ListenerClosed {
-                listener_id: id,
-                reason: Ok(()),
+        // deny the dialer while the listener is running
+        let config_handle = listener_transport.config_handle();
+        let dialer_peer_id = dialer_transport.peer_id();
+        config_handle
+            .modify(|config| {
+                config.deny_list.insert(dialer_peer_id);
             })
-            .expect("failed to send listener closed event");
-        true
-    }
+            .unwrap();
 
-    fn dial(&mut self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
-        debug!("dialing {}", addr);
+        let dial = dialer_transport
+            .dial(listener_transport.listen_addr.clone())
+            .unwrap();
+        match poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)).await {
+            TransportEvent::ListenerError {
+                error: Error::InboundConnectionRejected(DenialReason::NotAllowed),
+                ..
+            } => {}
+            res => panic!("expected TransportEvent::ListenerError, got {:?}", res),
+        }
 
-        let id = ConnectionId::generate();
+        // the dialer is told why, instead of timing out
+        let res = tokio::select! {
+            res = dial => res,
+            event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                panic!("unexpected dialer event {:?}", event)
+            }
+        };
+        assert!(matches!(
+            res,
+            Err(Error::ConnectionDenied(DenialReason::NotAllowed))
+        ));
 
-        // create remote recipient address
-        let recipient = multiaddress_to_nym_address(addr).map_err(TransportError::Other)?;
+        // allowed again
+        config_handle
+            .modify(|config| config.deny_list.clear())
+            .unwrap();
+        let _conns = connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
 
-        // create pending conn structs and store
-        let (connection_tx, connection_rx) = oneshot::channel::<Connection>();
+        // both ends count the denied handshake and the established one
+        for transport in [&dialer_transport, &listener_transport] {
+            let handshakes = transport.stats().handshakes();
+            assert_eq!(handshakes.failures(HandshakeFailure::PolicyRejected), 1);
+            assert_eq!(handshakes.failures(HandshakeFailure::TimedOut), 0);
+            assert_eq!(handshakes.established.count, 1);
+        }
+    }
 
-        let inner_pending_conn = PendingConnection::new(recipient, connection_tx);
-        self.pending_dials.insert(id.clone(), inner_pending_conn);
+    #[tokio::test]
+    async fn test_transport_upgrade_dropped_by_swarm() {
+        let (mut dialer_transport, mut listener_transport) = new_mock_transports(
+            |transport| transport.with_timeout(Duration::from_secs(5)),
+            identity,
+        )
+        .await;
 
-        // put ConnectionRequest message into outbound message channel
-        let msg = ConnectionMessage {
-            peer_id: self.peer_id(),
-            recipient: Some(self.self_address),
-            id,
+        let dial = dialer_transport
+            .dial(listener_transport.listen_addr.clone())
+            .unwrap();
+        let upgrade = match poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)).await
+        {
+            TransportEvent::Incoming { upgrade, .. } => upgrade,
+            res => panic!("expected TransportEvent::Incoming, got {:?}", res),
         };
 
-        let outbound_tx = self.outbound_tx.clone();
+        // the swarm denies the pending connection, eg. because of its connection limits,
+        // so it drops the upgrade without polling it
+        drop(upgrade);
+        let res = timeout(Duration::from_secs(1), async {
+            tokio::select! {
+                res = dial => res,
+                event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                    panic!("unexpected dialer event {:?}", event)
+                }
+            }
+        })
+        .await
+        .expect("the dial should fail before its timeout");
+        assert!(matches!(
+            res,
+            Err(Error::ConnectionDenied(DenialReason::ConnectionLimit))
+        ));
 
-        let mut waker = self.waker.clone();
-        let handshake_timeout = self.handshake_timeout;
-        Ok(async move {
-            outbound_tx
-                .send(OutboundMessage {
-                    message: Message::ConnectionRequest(msg),
-                    recipient,
-                })
-                .map_err(|e| Error::OutboundSendError(e.to_string()))?;
+        // an upgrade the swarm polls is accepted
+        let _conns = connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+    }
 
-            debug!("sent outbound ConnectionRequest");
-            if let Some(waker) = waker.take() {
-                waker.wake();
-            };
+    #[tokio::test]
+    async fn test_transport_handshake_queue_limit() {
+        let mixnet = MockMixnet::new();
+        let mut listener_transport = new_mock_transport(&mixnet)
+            .with_handshake_workers(1, 0)
+            .unwrap();
+        assert_new_address_event(Pin::new(&mut listener_transport)).await;
+        let mut dials = vec![];
+        for _ in 0..2 {
+            let mut dialer_transport = new_mock_transport(&mixnet);
+            assert_new_address_event(Pin::new(&mut dialer_transport)).await;
+            let dial = dialer_transport
+                .dial(listener_transport.listen_addr.clone())
+                .unwrap();
+            dials.push((dialer_transport, tokio::spawn(dial)));
+        }
 
-            let conn = timeout(handshake_timeout, connection_rx).await??;
-            Ok((conn.peer_id, conn))
+        // both requests arrive before the listener is polled, and only one fits in the pool
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut incoming = 0;
+        let mut rejected = 0;
+        for _ in 0..2 {
+            match poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)).await {
+                TransportEvent::Incoming { .. } => incoming += 1,
+                TransportEvent::ListenerError {
+                    error: Error::InboundConnectionRejected(DenialReason::RateLimited),
+                    ..
+                } => rejected += 1,
+                event => panic!("unexpected transport event {:?}", event),
+            }
         }
-        .boxed())
+        assert_eq!((incoming, rejected), (1, 1));
+        assert_eq!(listener_transport.handshakes.in_flight(), 0);
     }
 
-    // dial_as_listener currently just calls self.dial().
-    fn dial_as_listener(
-        &mut self,
-        addr: Multiaddr,
-    ) -> Result<Self::Dial, TransportError<Self::Error>> {
-        self.dial(addr)
-    }
+    #[tokio::test]
+    async fn test_transport_reachability_probing() {
+        let (mut dialer_transport, mut listener_transport) = new_mock_transports(
+            |transport| transport.with_reachability_probing(Duration::from_millis(100)),
+            identity,
+        )
+        .await;
+        let stats = dialer_transport.stats();
 
-    fn poll(
-        mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
-    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
-        // new addresses + listener close events
-        if let Poll::Ready(Some(res)) = self.poll_rx.recv().boxed().poll_unpin(cx) {
-            return Poll::Ready(res);
-        }
+        // nobody to ask yet
+        assert!(matches!(
+            dialer_transport.probe_reachability(),
+            Err(Error::NoConnectionForReachabilityRequest)
+        ));
+        let _conns = connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
 
-        // check for and handle inbound messages
-        while let Poll::Ready(Some(msg)) = self.inbound_stream.poll_next_unpin(cx) {
-            match self.handle_inbound(msg.0) {
-                Ok(event) => match event {
-                    InboundTransportEvent::ConnectionRequest(upgrade) => {
-                        debug!("InboundTransportEvent::ConnectionRequest");
-                        return Poll::Ready(TransportEvent::Incoming {
-                            listener_id: self.listener_id,
-                            upgrade,
-                            local_addr: self.listen_addr.clone(),
-                            send_back_addr: self.listen_addr.clone(),
-                        });
-                    }
-                    InboundTransportEvent::ConnectionResponse => {
-                        debug!("InboundTransportEvent::ConnectionResponse");
-                    }
-                    InboundTransportEvent::TransportMessage => {
-                        debug!("InboundTransportEvent::TransportMessage");
-                    }
-                },
-                Err(e) => {
-                    return Poll::Ready(TransportEvent::ListenerError {
-                        listener_id: self.listener_id,
-                        error: e,
-                    });
-                }
-            };
-        }
+        let res = timeout(
+            Duration::from_millis(250),
+            future::join(
+                poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)),
+                poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)),
+            ),
+        )
+        .await;
+        assert!(res.is_err(), "unexpected transport event");
+        let reachability = stats.reachability().unwrap();
+        assert!(reachability.reachable);
+        assert_eq!(reachability.consecutive_failures, 0);
 
-        self.waker = Some(cx.waker().clone());
-        Poll::Pending
+        // a probe that isn't answered before the next one is sent fails
+        dialer_transport.probe_reachability().unwrap();
+        dialer_transport.probe_reachability().unwrap();
+        assert!(!stats.reachability().unwrap().reachable);
     }
 
-    fn address_translation(&self, _listen: &Multiaddr, _observed: &Multiaddr) -> Option<Multiaddr> {
-        None
-    }
-}
+    #[tokio::test]
+    async fn test_transport_latency_probing() {
+        let (mut dialer_transport, mut listener_transport) = new_mock_transports(
+            |transport| transport.with_latency_probing(Duration::from_millis(50)),
+            identity,
+        )
+        .await;
+        let _conns = connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
 
-fn nym_address_to_multiaddress(addr: Recipient) -> Result<Multiaddr, Error> {
-    Multiaddr::from_str(&format!("/nym/{}", addr)).map_err(Error::FailedToFormatMultiaddr)
-}
+        let stats = dialer_transport.stats();
+        let listener_peer_id = listener_transport.peer_id();
+        assert!(stats.latency(&listener_peer_id).is_none());
 
-fn multiaddress_to_nym_address(multiaddr: Multiaddr) -> Result<Recipient, Error> {
-    let mut multiaddr = multiaddr;
-    match multiaddr.pop().unwrap() {
-        Protocol::Nym(addr) => Recipient::from_str(&addr).map_err(Error::InvalidRecipientBytes),
-        _ => Err(Error::InvalidProtocolForMultiaddr),
+        // both sides need to be polled for the ping and pong to be handled
+        let res = timeout(
+            Duration::from_millis(300),
+            future::join(
+                poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)),
+                poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)),
+            ),
+        )
+        .await;
+        assert!(res.is_err(), "unexpected transport event");
+
+        let latency = stats.latency(&listener_peer_id).unwrap();
+        assert!(latency.samples >= 1);
+        assert!(latency.rtt < Duration::from_millis(300));
     }
-}
 
-#[cfg(test)]
-mod test {
-    use crate::connection::Connection;
-    use crate::error::Error;
-    use crate::message::{
-        Message, OutboundMessage, SubstreamId, SubstreamMessage, SubstreamMessageType,
-        TransportMessage,
-    };
-    use crate::substream::Substream;
-    use crate::test_utils::create_nym_client;
+    #[tokio::test]
+    async fn test_transport_connection_quality() {
+        let (mut dialer_transport, mut listener_transport) = new_mock_transports(
+            |transport| transport.with_latency_probing(Duration::from_millis(50)),
+            identity,
+        )
+        .await;
+        let _conns = connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
 
-    use super::{nym_address_to_multiaddress, NymTransport};
-    use futures::{future::poll_fn, AsyncReadExt, AsyncWriteExt, FutureExt};
-    use libp2p::core::{
-        identity::Keypair,
-        transport::{Transport, TransportEvent},
-        Multiaddr, StreamMuxer,
-    };
-    use std::{pin::Pin, str::FromStr, sync::atomic::Ordering};
-    use testcontainers::clients;
-    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
-    use tracing::info;
-    use tracing_subscriber::EnvFilter;
+        let stats = dialer_transport.stats();
+        let mut quality_rx = dialer_transport.subscribe_quality_updates();
+        let listener_peer_id = listener_transport.peer_id();
+        assert!(stats.quality(&listener_peer_id).is_none());
 
-    impl Connection {
-        fn write(&self, msg: SubstreamMessage) -> Result<(), Error> {
-            let nonce = self.message_nonce.fetch_add(1, Ordering::SeqCst);
-            self.mixnet_outbound_tx
-                .send(OutboundMessage {
-                    recipient: self.remote_recipient,
-                    message: Message::TransportMessage(TransportMessage {
-                        nonce,
-                        id: self.id.clone(),
-                        message: msg,
-                    }),
-                })
-                .map_err(|e| Error::OutboundSendError(e.to_string()))?;
-            Ok(())
-        }
-    }
+        let res = timeout(
+            Duration::from_millis(300),
+            future::join(
+                poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)),
+                poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)),
+            ),
+        )
+        .await;
+        assert!(res.is_err(), "unexpected transport event");
 
-    impl NymTransport {
-        async fn new_with_notify_inbound(
-            uri: &String,
-            notify_inbound_tx: UnboundedSender<()>,
-        ) -> Result<Self, Error> {
-            let local_key = Keypair::generate_ed25519();
-            Self::new_maybe_with_notify_inbound(uri, local_key, Some(notify_inbound_tx), None).await
-        }
+        // the answered probes rate the connection
+        let (peer_id, _) = quality_rx.try_recv().unwrap();
+        assert_eq!(peer_id, listener_peer_id);
+        let quality = stats.quality(&listener_peer_id).unwrap();
+        assert!(quality.probes_answered >= 1);
+        assert_eq!(quality.retransmits, 0);
+        assert!(quality.score > 0.0);
     }
 
     #[tokio::test]
@@ -606,7 +5918,7 @@ mod test {
             .is_none());
         listener_notify_inbound_rx.recv().await.unwrap();
 
-        // should receive the connection request from the mixnet and send the connection response
+        // should receive the connection request from the mixnet
         let res = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)).await;
         let mut upgrade = match res {
             TransportEvent::Incoming {
@@ -622,6 +5934,12 @@ mod test {
             }
             _ => panic!("expected TransportEvent::Incoming, got {:?}", res),
         };
+
+        // the response is sent once the upgrade is polled, ie. the swarm takes the connection
+        let (_, mut listener_conn) = poll_fn(|cx| Pin::new(&mut upgrade).as_mut().poll_unpin(cx))
+            .now_or_never()
+            .expect("the upgrade should be ready")
+            .expect("the upgrade should not error");
         dialer_notify_inbound_rx.recv().await.unwrap();
 
         // should receive the connection response from the mixnet
@@ -632,11 +5950,7 @@ mod test {
         );
         info!("waiting for connections...");
 
-        // should be able to resolve the connections now
-        let (_, mut listener_conn) = poll_fn(|cx| Pin::new(&mut upgrade).as_mut().poll_unpin(cx))
-            .now_or_never()
-            .expect("the upgrade should be ready")
-            .expect("the upgrade should not error");
+        // should be able to resolve the dial now
         let (_, mut dialer_conn) = poll_fn(|cx| Pin::new(&mut dial).as_mut().poll_unpin(cx))
             .now_or_never()
             .expect("the upgrade should be ready")
@@ -670,19 +5984,6 @@ mod test {
         .await;
     }
 
-    async fn assert_new_address_event(mut transport: Pin<&mut NymTransport>) {
-        match poll_fn(|cx| transport.as_mut().poll(cx)).await {
-            TransportEvent::NewAddress {
-                listener_id,
-                listen_addr,
-            } => {
-                assert_eq!(listener_id, transport.listener_id);
-                assert_eq!(listen_addr, transport.listen_addr);
-            }
-            _ => panic!("expected TransportEvent::NewAddress"),
-        }
-    }
-
     async fn send_and_receive_over_conns(
         msg: Vec<u8>,
         conn1: &mut Connection,
@@ -744,7 +6045,7 @@ mod test {
             .is_none());
         listener_notify_inbound_rx.recv().await.unwrap();
 
-        // should receive the connection request from the mixnet and send the connection response
+        // should receive the connection request from the mixnet
         let res = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)).await;
         let mut upgrade = match res {
             TransportEvent::Incoming {
@@ -760,6 +6061,12 @@ mod test {
             }
             _ => panic!("expected TransportEvent::Incoming, got {:?}", res),
         };
+
+        // the response is sent once the upgrade is polled, ie. the swarm takes the connection
+        let (_, mut listener_conn) = poll_fn(|cx| Pin::new(&mut upgrade).as_mut().poll_unpin(cx))
+            .now_or_never()
+            .expect("the upgrade should be ready")
+            .expect("the upgrade should not error");
         dialer_notify_inbound_rx.recv().await.unwrap();
 
         // should receive the connection response from the mixnet
@@ -770,11 +6077,7 @@ mod test {
         );
         info!("waiting for connections...");
 
-        // should be able to resolve the connections now
-        let (_, mut listener_conn) = poll_fn(|cx| Pin::new(&mut upgrade).as_mut().poll_unpin(cx))
-            .now_or_never()
-            .unwrap()
-            .unwrap();
+        // should be able to resolve the dial now
         let (_, mut dialer_conn) = poll_fn(|cx| Pin::new(&mut dial).as_mut().poll_unpin(cx))
             .now_or_never()
             .unwrap()
@@ -855,6 +6158,188 @@ mod test {
         listener_substream.close().await.unwrap_err();
     }
 
+    #[tokio::test]
+    async fn test_transport_abandoned_dials() {
+        let mixnet = MockMixnet::new();
+        let mut dialer_transport = new_mock_transport(&mixnet)
+            .with_compact_connection_ids()
+            .with_timeout(Duration::from_millis(200));
+        // never polled, so it never answers
+        let silent_transport = new_mock_transport(&mixnet);
+        assert_new_address_event(Pin::new(&mut dialer_transport)).await;
+
+        // a dial that times out fails with a distinct error
+        let dial = dialer_transport
+            .dial(silent_transport.listen_addr.clone())
+            .unwrap();
+        assert_eq!(dialer_transport.pending_dials.len(), 1);
+        let res = tokio::select! {
+            res = dial => res,
+            event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                panic!("unexpected transport event {:?}", event)
+            }
+        };
+        assert!(matches!(res, Err(Error::DialTimeout(_))));
+
+        // so does dropping a dial before it's answered; either way, the transport forgets
+        // the pending connection the next time it's polled
+        let dial = dialer_transport
+            .dial(silent_transport.listen_addr.clone())
+            .unwrap();
+        assert!(!dialer_transport.pending_dials.is_empty());
+        drop(dial);
+        let res = timeout(
+            Duration::from_millis(100),
+            poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)),
+        )
+        .await;
+        assert!(res.is_err(), "unexpected transport event");
+        assert!(dialer_transport.pending_dials.is_empty());
+        assert!(dialer_transport.compact_refs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_transport_max_concurrent_dials() {
+        let mixnet = MockMixnet::new();
+        let mut dialer_transport = new_mock_transport(&mixnet)
+            .with_max_concurrent_dials(1, 2)
+            .unwrap();
+        // never polled, so it never answers
+        let silent_transport = new_mock_transport(&mixnet);
+        let address = silent_transport.listen_addr.clone();
+
+        let first = dialer_transport.dial(address.clone()).unwrap();
+        let (progress, mut second_phase_rx) = DialProgress::new();
+        dialer_transport.dial_options_handle().set(
+            address.clone(),
+            DialOptions::default().with_progress(progress),
+        );
+        let mut second = dialer_transport.dial(address.clone()).unwrap();
+        let (progress, third_phase_rx) = DialProgress::new();
+        dialer_transport.dial_options_handle().set(
+            address.clone(),
+            DialOptions::default().with_progress(progress),
+        );
+        let mut third = dialer_transport.dial(address.clone()).unwrap();
+        let stats = dialer_transport.stats();
+        assert_eq!(stats.queued_dials(), 2);
+        assert!(matches!(
+            dialer_transport.dial(address.clone()),
+            Err(TransportError::Other(Error::DialQueueFull))
+        ));
+        assert_eq!(stats.dials().failed, 1);
+
+        // the waiting dials go in the order they were made once the first is done with,
+        // even if the later one is polled first
+        assert!((&mut third).now_or_never().is_none());
+        assert!((&mut second).now_or_never().is_none());
+        drop(first);
+        timeout(Duration::from_secs(1), async {
+            while *second_phase_rx.borrow() != DialPhase::AwaitingResponse {
+                tokio::select! {
+                    res = &mut second => panic!("the dial wasn't answered, got {:?}", res.err()),
+                    res = second_phase_rx.changed() => res.unwrap(),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!((&mut third).now_or_never().is_none());
+        assert_eq!(*third_phase_rx.borrow(), DialPhase::Queued);
+        assert_eq!(stats.queued_dials(), 1);
+
+        drop(second);
+        let res = timeout(Duration::from_millis(100), third).await;
+        assert!(res.is_err(), "the dial wasn't answered");
+        assert_eq!(*third_phase_rx.borrow(), DialPhase::AwaitingResponse);
+        assert_eq!(stats.queued_dials(), 0);
+
+        assert!(new_mock_transport(&mixnet)
+            .with_max_concurrent_dials(0, 1)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_transport_connection_stats() {
+        let (mut dialer_transport, mut listener_transport) = new_mock_transports(
+            |transport| transport.with_connection_stats(Duration::from_millis(200)),
+            |transport| transport.with_connection_stats(Duration::from_millis(200)),
+        )
+        .await;
+        let (mut dialer_conn, mut listener_conn) =
+            connect_mock_transports(&mut dialer_transport, &mut listener_transport).await;
+        let mut dialer_events = dialer_transport.mixnet_connection().connection_events();
+        let mut listener_events = listener_transport.mixnet_connection().connection_events();
+
+        let mut dialer_substream =
+            poll_fn(|cx| Pin::new(&mut dialer_conn).as_mut().poll_outbound(cx))
+                .await
+                .unwrap();
+        dialer_substream.write_all(b"hello").await.unwrap();
+
+        // the first interval with traffic has the data, and only once
+        let stats = timeout(Duration::from_secs(5), async {
+            loop {
+                tokio::select! {
+                    event = listener_events.next() => match event {
+                        Some(ConnectionEvent::Stats { peer_id, stats }) => {
+                            assert_eq!(peer_id, dialer_transport.peer_id());
+                            if stats.messages_received > 0 {
+                                break stats;
+                            }
+                        }
+                        event => panic!("unexpected connection event {:?}", event),
+                    },
+                    event = poll_fn(|cx| Pin::new(&mut listener_transport).as_mut().poll(cx)) => {
+                        panic!("unexpected transport event {:?}", event)
+                    }
+                    _ = poll_fn(|cx| Pin::new(&mut listener_conn).as_mut().poll(cx)) => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(stats.messages_received, 1);
+        assert_eq!(stats.bytes_received, 5);
+        assert_eq!(stats.retransmits, 0);
+        assert!(stats.interval > Duration::ZERO);
+
+        let stats = timeout(Duration::from_secs(5), async {
+            loop {
+                tokio::select! {
+                    Some(event) = dialer_events.next() => match event {
+                        ConnectionEvent::Stats { stats, .. } => break stats,
+                        event => panic!("unexpected connection event {:?}", event),
+                    },
+                    event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                        panic!("unexpected transport event {:?}", event)
+                    }
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(stats.messages_sent, 1);
+        assert_eq!(stats.bytes_sent, 5);
+        assert_eq!(stats.smoothed_rtt, None);
+
+        let event = timeout(Duration::from_secs(5), async {
+            tokio::select! {
+                event = dialer_events.next() => event,
+                event = poll_fn(|cx| Pin::new(&mut dialer_transport).as_mut().poll(cx)) => {
+                    panic!("unexpected transport event {:?}", event)
+                }
+            }
+        })
+        .await
+        .unwrap();
+        let Some(ConnectionEvent::Stats { stats, .. }) = event else {
+            panic!("unexpected connection event {:?}", event);
+        };
+        assert_eq!(stats.messages_sent, 0);
+        assert_eq!(stats.bytes_sent, 0);
+    }
+
     async fn send_and_receive_substream_message(
         data: Vec<u8>,
         mut sender_substream: Pin<&mut Substream>,
@@ -0,0 +1,60 @@
+use futures::{stream, SinkExt, StreamExt};
+use rust_libp2p_nym::backend::MockMixnet;
+use rust_libp2p_nym::mixnet::{connect_with_backend, open_with_backend, OutboundMessage};
+use rust_libp2p_nym::substream::MessagePriority;
+use std::time::Duration;
+use tokio::time::{timeout, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[tokio::test]
+async fn test_outbound_sink_datagrams() {
+    let mixnet = MockMixnet::new();
+    let (_, _, mut sink) = connect_with_backend(mixnet.new_backend(), 2);
+    let (recipient, mut inbound, _) = connect_with_backend(mixnet.new_backend(), 2);
+
+    // more messages than may be in flight at once, so the sink has to wait for some
+    let messages = (0..8u8).map(|i| {
+        Ok(OutboundMessage::datagram(recipient, vec![i])
+            .with_priority(MessagePriority::High)
+            .with_deadline(Instant::now() + TIMEOUT, None))
+    });
+    timeout(TIMEOUT, sink.send_all(&mut stream::iter(messages)))
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut received = vec![];
+    while received.len() < 8 {
+        let message = timeout(TIMEOUT, inbound.next()).await.unwrap().unwrap();
+        received.push(message.datagram_payload().unwrap().to_vec());
+    }
+    received.sort();
+    assert_eq!(received, (0..8u8).map(|i| vec![i]).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn test_mixnet_connection_sink_datagrams() {
+    let mixnet = MockMixnet::new();
+    let (connection, _) = open_with_backend(mixnet.new_backend());
+    let (recipient, mut inbound, _) = connect_with_backend(mixnet.new_backend(), 2);
+
+    let mut sink = connection.clone().with_max_in_flight(2);
+    let messages = (0..8u8).map(|i| Ok(OutboundMessage::datagram(recipient, vec![i])));
+    timeout(TIMEOUT, sink.send_all(&mut stream::iter(messages)))
+        .await
+        .unwrap()
+        .unwrap();
+    // send doesn't wait for the mixnet
+    connection
+        .send(OutboundMessage::datagram(recipient, vec![8]))
+        .unwrap();
+
+    let mut received = vec![];
+    while received.len() < 9 {
+        let message = timeout(TIMEOUT, inbound.next()).await.unwrap().unwrap();
+        received.push(message.datagram_payload().unwrap().to_vec());
+    }
+    received.sort();
+    assert_eq!(received, (0..9u8).map(|i| vec![i]).collect::<Vec<_>>());
+}